@@ -6,7 +6,10 @@ use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Read;
 
+use arch::numa::numa_distance;
+use arch::numa::NumaMemoryRange;
 use arch::CpuSet;
+use arch::NumaNode;
 use arch::SERIAL_ADDR;
 use cros_fdt::Error;
 use cros_fdt::FdtWriter;
@@ -16,6 +19,7 @@ use devices::bat::GOLDFISHBAT_MMIO_LEN;
 use devices::pl030::PL030_AMBA_ID;
 use devices::PciAddress;
 use devices::PciInterruptPin;
+use devices::FW_CFG_MMIO_SIZE;
 use hypervisor::PsciVersion;
 use hypervisor::PSCI_0_2;
 use hypervisor::PSCI_1_0;
@@ -59,10 +63,28 @@ const IRQ_TYPE_EDGE_RISING: u32 = 0x00000001;
 const IRQ_TYPE_LEVEL_HIGH: u32 = 0x00000004;
 const IRQ_TYPE_LEVEL_LOW: u32 = 0x00000008;
 
-fn create_memory_node(fdt: &mut FdtWriter, guest_mem: &GuestMemory) -> Result<()> {
+fn create_memory_node(
+    fdt: &mut FdtWriter,
+    guest_mem: &GuestMemory,
+    numa_ranges: &[NumaMemoryRange],
+) -> Result<()> {
+    // With a NUMA topology, each node gets its own memory nodes tagged with its ID.
+    if !numa_ranges.is_empty() {
+        for range in numa_ranges {
+            let memory_node = fdt.begin_node(&format!("memory@{:x}", range.start.offset()))?;
+            fdt.property_string("device_type", "memory")?;
+            fdt.property_array_u64("reg", &[range.start.offset(), range.size])?;
+            fdt.property_u32("numa-node-id", range.node)?;
+            fdt.end_node(memory_node)?;
+        }
+        return Ok(());
+    }
+
     let mut mem_reg_prop = Vec::new();
+    let hotplug_base = guest_mem.hotplug_range().map(|(base, _)| base);
     for region in guest_mem.guest_memory_regions() {
-        if region.0.offset() == AARCH64_PROTECTED_VM_FW_START {
+        // The hotplug range is reported by its device rather than as RAM.
+        if region.0.offset() == AARCH64_PROTECTED_VM_FW_START || Some(region.0) == hotplug_base {
             continue;
         }
         mem_reg_prop.push(region.0.offset());
@@ -77,6 +99,24 @@ fn create_memory_node(fdt: &mut FdtWriter, guest_mem: &GuestMemory) -> Result<()
     Ok(())
 }
 
+fn create_distance_map_node(fdt: &mut FdtWriter, numa_nodes: &[NumaNode]) -> Result<()> {
+    let mut distance_matrix = Vec::new();
+    for (from, from_node) in numa_nodes.iter().enumerate() {
+        for (to, to_node) in numa_nodes.iter().enumerate() {
+            distance_matrix.push(from_node.node);
+            distance_matrix.push(to_node.node);
+            distance_matrix.push(numa_distance(numa_nodes, from, to).into());
+        }
+    }
+
+    let distance_map_node = fdt.begin_node("distance-map")?;
+    fdt.property_string("compatible", "numa-distance-map-v1")?;
+    fdt.property_array_u32("distance-matrix", &distance_matrix)?;
+    fdt.end_node(distance_map_node)?;
+
+    Ok(())
+}
+
 fn create_resv_memory_node(fdt: &mut FdtWriter, resv_size: Option<u64>) -> Result<Option<u32>> {
     if let Some(resv_size) = resv_size {
         let resv_memory_node = fdt.begin_node("reserved-memory")?;
@@ -103,6 +143,7 @@ fn create_cpu_nodes(
     num_cpus: u32,
     cpu_clusters: Vec<CpuSet>,
     cpu_capacity: BTreeMap<usize, u32>,
+    numa_nodes: &[NumaNode],
 ) -> Result<()> {
     let cpus_node = fdt.begin_node("cpus")?;
     fdt.property_u32("#address-cells", 0x1)?;
@@ -123,6 +164,13 @@ fn create_cpu_nodes(
             fdt.property_u32("capacity-dmips-mhz", *capacity)?;
         }
 
+        if let Some(node) = numa_nodes
+            .iter()
+            .find(|node| node.cpus.contains(&(cpu_id as usize)))
+        {
+            fdt.property_u32("numa-node-id", node.node)?;
+        }
+
         fdt.end_node(cpu_node)?;
     }
 
@@ -510,6 +558,17 @@ fn create_vmwdt_node(fdt: &mut FdtWriter, vmwdt_cfg: VmWdtConfig) -> Result<()>
     Ok(())
 }
 
+fn create_fw_cfg_node(fdt: &mut FdtWriter, fw_cfg_base: u64) -> Result<()> {
+    let fw_cfg_name = format!("fw-cfg@{:x}", fw_cfg_base);
+    let reg = [fw_cfg_base, FW_CFG_MMIO_SIZE];
+    let fw_cfg_node = fdt.begin_node(&fw_cfg_name)?;
+    fdt.property_string("compatible", "qemu,fw-cfg-mmio")?;
+    fdt.property_array_u64("reg", &reg)?;
+    fdt.property_null("dma-coherent")?;
+    fdt.end_node(fw_cfg_node)?;
+    Ok(())
+}
+
 /// Creates a flattened device tree containing all of the parameters for the
 /// kernel and loads it into the guest memory at the specified offset.
 ///
@@ -531,6 +590,7 @@ fn create_vmwdt_node(fdt: &mut FdtWriter, vmwdt_cfg: VmWdtConfig) -> Result<()>
 /// * `bat_irq` - The battery irq number
 /// * `swiotlb` - Reserve a memory pool for DMA
 /// * `vmwdt_cfg` - The virtual watchdog configuration
+/// * `fw_cfg_base` - The fw_cfg device base address, if present
 pub fn create_fdt(
     fdt_max_size: usize,
     guest_mem: &GuestMemory,
@@ -540,6 +600,8 @@ pub fn create_fdt(
     num_cpus: u32,
     cpu_clusters: Vec<CpuSet>,
     cpu_capacity: BTreeMap<usize, u32>,
+    numa_nodes: &[NumaNode],
+    numa_ranges: &[NumaMemoryRange],
     fdt_address: GuestAddress,
     cmdline: &str,
     image: (GuestAddress, usize),
@@ -551,6 +613,7 @@ pub fn create_fdt(
     swiotlb: Option<u64>,
     bat_mmio_base_and_irq: Option<(u64, u32)>,
     vmwdt_cfg: VmWdtConfig,
+    fw_cfg_base: Option<u64>,
 ) -> Result<()> {
     let mut fdt = FdtWriter::new(&[]);
 
@@ -565,9 +628,12 @@ pub fn create_fdt(
     }
     create_chosen_node(&mut fdt, cmdline, initrd)?;
     create_config_node(&mut fdt, image)?;
-    create_memory_node(&mut fdt, guest_mem)?;
+    create_memory_node(&mut fdt, guest_mem, numa_ranges)?;
     let dma_pool_phandle = create_resv_memory_node(&mut fdt, swiotlb)?;
-    create_cpu_nodes(&mut fdt, num_cpus, cpu_clusters, cpu_capacity)?;
+    create_cpu_nodes(&mut fdt, num_cpus, cpu_clusters, cpu_capacity, numa_nodes)?;
+    if !numa_nodes.is_empty() {
+        create_distance_map_node(&mut fdt, numa_nodes)?;
+    }
     create_gic_node(&mut fdt, is_gicv3, num_cpus as u64)?;
     create_timer_node(&mut fdt, num_cpus)?;
     if use_pmu {
@@ -581,6 +647,9 @@ pub fn create_fdt(
         create_battery_node(&mut fdt, bat_mmio_base, bat_irq)?;
     }
     create_vmwdt_node(&mut fdt, vmwdt_cfg)?;
+    if let Some(fw_cfg_base) = fw_cfg_base {
+        create_fw_cfg_node(&mut fdt, fw_cfg_base)?;
+    }
     // End giant node
     fdt.end_node(root_node)?;
 
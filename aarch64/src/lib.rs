@@ -7,11 +7,14 @@
 #![cfg(any(target_arch = "arm", target_arch = "aarch64"))]
 
 use std::collections::BTreeMap;
+use std::fs::File;
 use std::io;
+use std::io::Read;
 use std::sync::mpsc;
 use std::sync::Arc;
 
 use arch::get_serial_cmdline;
+use arch::numa::NumaMemoryRange;
 use arch::GetSerialCmdlineError;
 use arch::MsrConfig;
 use arch::MsrExitHandlerError;
@@ -28,6 +31,8 @@ use devices::vmwdt::VMWDT_DEFAULT_TIMEOUT_SEC;
 use devices::Bus;
 use devices::BusDeviceObj;
 use devices::BusError;
+use devices::FwCfg;
+use devices::FwCfgInterface;
 use devices::IrqChip;
 use devices::IrqChipAArch64;
 use devices::IrqEventSource;
@@ -42,6 +47,8 @@ use gdbstub::arch::Arch;
 use gdbstub_arch::aarch64::AArch64 as GdbArch;
 use hypervisor::CpuConfigAArch64;
 use hypervisor::DeviceKind;
+#[cfg(all(target_arch = "aarch64", feature = "gdb"))]
+use hypervisor::GuestDebug;
 use hypervisor::Hypervisor;
 use hypervisor::HypervisorCap;
 use hypervisor::ProtectionType;
@@ -171,6 +178,11 @@ const AARCH64_VMWDT_ADDR: u64 = 0x3000;
 // The virtual watchdog device gets one 4k page
 const AARCH64_VMWDT_SIZE: u64 = 0x1000;
 
+// Place the fw_cfg device at page 4
+const AARCH64_FW_CFG_ADDR: u64 = 0x4000;
+// The fw_cfg device gets one 4k page
+const AARCH64_FW_CFG_SIZE: u64 = 0x1000;
+
 // PCI MMIO configuration region base address.
 const AARCH64_PCI_CFG_BASE: u64 = 0x10000;
 // PCI MMIO configuration region size.
@@ -206,6 +218,8 @@ pub enum Error {
     CreateEvent(base::Error),
     #[error("FDT could not be created: {0}")]
     CreateFdt(cros_fdt::Error),
+    #[error("failed to add fw_cfg file: {0}")]
+    CreateFwCfgFile(devices::FwCfgError),
     #[error("failed to create GIC: {0}")]
     CreateGICFailure(base::Error),
     #[error("failed to create a PCI root hub: {0}")]
@@ -220,24 +234,30 @@ pub enum Error {
     CreateVcpu(base::Error),
     #[error("vm created wrong kind of vcpu")]
     DowncastVcpu,
-    #[error("failed to enable singlestep execution: {0}")]
-    EnableSinglestep(base::Error),
     #[error("failed to finalize IRQ chip: {0}")]
     FinalizeIrqChip(base::Error),
     #[error("failed to get HW breakpoint count: {0}")]
     GetMaxHwBreakPoint(base::Error),
+    #[error("failed to get HW watchpoint count: {0}")]
+    GetMaxHwWatchPoint(base::Error),
     #[error("failed to get PSCI version: {0}")]
     GetPsciVersion(base::Error),
     #[error("failed to get serial cmdline: {0}")]
     GetSerialCmdline(GetSerialCmdlineError),
+    #[error("failed to get the hit watchpoint: {0}")]
+    GetWatchpointHit(base::Error),
     #[error("failed to initialize arm pvtime: {0}")]
     InitPvtimeError(base::Error),
     #[error("initrd could not be loaded: {0}")]
     InitrdLoadFailure(arch::LoadImageError),
+    #[error("failed to inject a breakpoint exception: {0}")]
+    InjectBreakpoint(base::Error),
     #[error("kernel could not be loaded: {0}")]
     KernelLoadFailure(kernel_loader::Error),
     #[error("error loading Kernel from Elf image: {0}")]
     LoadElfKernel(kernel_loader::Error),
+    #[error("error loading fw_cfg file {0}: {1}")]
+    LoadFwCfgFile(String, io::Error),
     #[error("failed to map arm pvtime memory: {0}")]
     MapPvtimeError(base::Error),
     #[error("failed to protect vm: {0}")]
@@ -252,6 +272,8 @@ pub enum Error {
     ReadReg(base::Error),
     #[error("error reading CPU registers: {0}")]
     ReadRegs(base::Error),
+    #[error("error registering fw_cfg device: {0}")]
+    RegisterFwCfg(BusError),
     #[error("failed to register irq fd: {0}")]
     RegisterIrqfd(base::Error),
     #[error("error registering PCI bus: {0}")]
@@ -260,8 +282,8 @@ pub enum Error {
     RegisterVsock(arch::DeviceRegistrationError),
     #[error("failed to set device attr: {0}")]
     SetDeviceAttr(base::Error),
-    #[error("failed to set a hardware breakpoint: {0}")]
-    SetHwBreakpoint(base::Error),
+    #[error("failed to set up guest debugging: {0}")]
+    SetGuestDebug(base::Error),
     #[error("failed to set register: {0}")]
     SetReg(base::Error),
     #[error("failed to set up guest memory: {0}")]
@@ -320,7 +342,22 @@ impl arch::LinuxArch for AArch64 {
             ));
         }
 
-        Ok(memory_regions)
+        let numa_ranges = Self::numa_memory_layout(components)?;
+        if numa_ranges.is_empty() {
+            Ok(memory_regions)
+        } else {
+            Ok(arch::numa::split_memory_regions(
+                &memory_regions,
+                &numa_ranges,
+            ))
+        }
+    }
+
+    fn numa_memory_layout(
+        components: &VmComponents,
+    ) -> std::result::Result<Vec<NumaMemoryRange>, Self::Error> {
+        let ram = [(GuestAddress(AARCH64_PHYS_MEM_START), components.memory_size)];
+        Ok(arch::numa::numa_memory_ranges(&ram, &components.numa_nodes))
     }
 
     fn get_system_allocator_config<V: Vm>(vm: &V) -> SystemAllocatorConfig {
@@ -350,6 +387,7 @@ impl arch::LinuxArch for AArch64 {
     {
         let has_bios = matches!(components.vm_image, VmImage::Bios(_));
         let mem = vm.get_memory().clone();
+        let numa_ranges = Self::numa_memory_layout(&components)?;
 
         // separate out image loading from other setup to get a specific error for
         // image loading
@@ -377,7 +415,7 @@ impl arch::LinuxArch for AArch64 {
                         .map_err(Error::KernelLoadFailure)?
                 };
                 let kernel_end = loaded_kernel.address_range.end;
-                initrd = match components.initrd_image {
+                initrd = match components.initrd_image.take() {
                     Some(initrd_file) => {
                         let mut initrd_file = initrd_file;
                         let initrd_addr =
@@ -556,6 +594,30 @@ impl arch::LinuxArch for AArch64 {
             .insert(pci_bus, AARCH64_PCI_CFG_BASE, AARCH64_PCI_CFG_SIZE)
             .map_err(Error::RegisterPci)?;
 
+        let fw_cfg = if components.fw_cfg_kernel_image.is_some()
+            || !components.fw_cfg_parameters.is_empty()
+        {
+            // The initrd is only handed to the firmware alongside a kernel; a directly booted
+            // kernel has already had it loaded above.
+            let kernel_image = components.fw_cfg_kernel_image.take();
+            let initrd_image = if kernel_image.is_some() {
+                components.initrd_image.take()
+            } else {
+                None
+            };
+            Some(Self::setup_fw_cfg_device(
+                &mmio_bus,
+                &mem,
+                vcpu_count,
+                components.memory_size,
+                &components.fw_cfg_parameters,
+                kernel_image,
+                initrd_image,
+            )?)
+        } else {
+            None
+        };
+
         let mut cmdline = Self::get_base_linux_cmdline();
         get_serial_cmdline(&mut cmdline, serial_parameters, "mmio")
             .map_err(Error::GetSerialCmdline)?;
@@ -568,6 +630,10 @@ impl arch::LinuxArch for AArch64 {
                 .map_err(Error::Cmdline)?;
         }
 
+        if let Some(fw_cfg) = &fw_cfg {
+            fw_cfg.lock().set_cmdline(cmdline.as_str());
+        }
+
         let psci_version = vcpus[0].get_psci_version().map_err(Error::GetPsciVersion)?;
 
         let pci_cfg = fdt::PciConfigRegion {
@@ -629,6 +695,8 @@ impl arch::LinuxArch for AArch64 {
             vcpu_count as u32,
             components.cpu_clusters,
             components.cpu_capacity,
+            &components.numa_nodes,
+            &numa_ranges,
             fdt_offset,
             cmdline.as_str(),
             (payload.entry(), payload.size() as usize),
@@ -640,6 +708,7 @@ impl arch::LinuxArch for AArch64 {
             components.swiotlb,
             bat_mmio_base_and_irq,
             vmwdt_cfg,
+            fw_cfg.as_ref().map(|_| AARCH64_FW_CFG_ADDR),
         )
         .map_err(Error::CreateFdt)?;
 
@@ -754,20 +823,25 @@ impl<T: VcpuAArch64> arch::GdbOps<T> for AArch64 {
         vcpu.set_gdb_register(reg_id, data).map_err(Error::WriteReg)
     }
 
-    fn enable_singlestep(vcpu: &T) -> Result<()> {
-        const SINGLE_STEP: bool = true;
-        vcpu.set_guest_debug(&[], SINGLE_STEP)
-            .map_err(Error::EnableSinglestep)
-    }
-
     fn get_max_hw_breakpoints(vcpu: &T) -> Result<usize> {
         vcpu.get_max_hw_bps().map_err(Error::GetMaxHwBreakPoint)
     }
 
-    fn set_hw_breakpoints(vcpu: &T, breakpoints: &[GuestAddress]) -> Result<()> {
-        const SINGLE_STEP: bool = false;
-        vcpu.set_guest_debug(breakpoints, SINGLE_STEP)
-            .map_err(Error::SetHwBreakpoint)
+    fn get_max_hw_watchpoints(vcpu: &T) -> Result<usize> {
+        vcpu.get_max_hw_wps().map_err(Error::GetMaxHwWatchPoint)
+    }
+
+    fn set_guest_debug(vcpu: &T, debug: &GuestDebug) -> Result<()> {
+        vcpu.set_guest_debug(debug).map_err(Error::SetGuestDebug)
+    }
+
+    fn get_watchpoint_hit(vcpu: &T, debug: &GuestDebug) -> Result<Option<usize>> {
+        vcpu.get_debug_exit_watchpoint(debug)
+            .map_err(Error::GetWatchpointHit)
+    }
+
+    fn inject_breakpoint(vcpu: &T) -> Result<()> {
+        vcpu.inject_breakpoint().map_err(Error::InjectBreakpoint)
     }
 }
 
@@ -853,6 +927,68 @@ impl AArch64 {
         Ok(())
     }
 
+    /// Sets up the fw_cfg device, which exposes the files given by the user as well as the kernel
+    /// and initrd to be loaded by the firmware.
+    ///
+    /// # Arguments
+    ///
+    /// * `bus` - the MMIO bus object
+    /// * `mem` - the guest memory used by the DMA interface
+    /// * `vcpu_count` - the number of virtual CPUs
+    /// * `memory_size` - the size of guest RAM
+    /// * `fw_cfg_parameters` - the files exposed by the device
+    /// * `kernel_image` - the kernel handed to the firmware, if any
+    /// * `initrd_image` - the initrd handed to the firmware, if any
+    fn setup_fw_cfg_device(
+        bus: &Bus,
+        mem: &GuestMemory,
+        vcpu_count: usize,
+        memory_size: u64,
+        fw_cfg_parameters: &[devices::FwCfgParameters],
+        kernel_image: Option<File>,
+        initrd_image: Option<File>,
+    ) -> Result<Arc<Mutex<FwCfg>>> {
+        let mut fw_cfg = FwCfg::new(
+            mem.clone(),
+            FwCfgInterface::Mmio {
+                base: AARCH64_FW_CFG_ADDR,
+            },
+        );
+        fw_cfg.set_ram_size(memory_size);
+        fw_cfg.set_cpu_count(vcpu_count as u16);
+
+        for param in fw_cfg_parameters {
+            let data = param
+                .data()
+                .map_err(|e| Error::LoadFwCfgFile(param.name.clone(), e))?;
+            fw_cfg
+                .add_file(&param.name, data)
+                .map_err(Error::CreateFwCfgFile)?;
+        }
+
+        if let Some(mut kernel_image) = kernel_image {
+            // arm64 Images have no separate setup code; the whole image is the kernel.
+            let mut kernel = Vec::new();
+            kernel_image
+                .read_to_end(&mut kernel)
+                .map_err(|e| Error::LoadFwCfgFile("kernel".to_string(), e))?;
+            fw_cfg.set_kernel(Vec::new(), kernel);
+
+            if let Some(mut initrd_image) = initrd_image {
+                let mut initrd = Vec::new();
+                initrd_image
+                    .read_to_end(&mut initrd)
+                    .map_err(|e| Error::LoadFwCfgFile("initrd".to_string(), e))?;
+                fw_cfg.set_initrd(initrd);
+            }
+        }
+
+        let fw_cfg = Arc::new(Mutex::new(fw_cfg));
+        bus.insert(fw_cfg.clone(), AARCH64_FW_CFG_ADDR, AARCH64_FW_CFG_SIZE)
+            .map_err(Error::RegisterFwCfg)?;
+        Ok(fw_cfg)
+    }
+
     /// Get ARM-specific features for vcpu with index `vcpu_id`.
     ///
     /// # Arguments
@@ -955,6 +1091,7 @@ mod tests {
             address_range: AddressRange::from_start_and_size(0x8080_0000, 0x1000).unwrap(),
             size: 0x1000,
             entry: GuestAddress(0x8080_0000),
+            pvh_entry: None,
         });
         let fdt_address = GuestAddress(0x1234);
         let prot = ProtectionType::Unprotected;
@@ -992,6 +1129,7 @@ mod tests {
             address_range: AddressRange::from_start_and_size(0x8080_0000, 0x1000).unwrap(),
             size: 0x1000,
             entry: GuestAddress(0x8080_0000),
+            pvh_entry: None,
         });
         let fdt_address = GuestAddress(0x1234);
         let prot = ProtectionType::Protected;
@@ -5,6 +5,7 @@
 //! Virtual machine architecture support code.
 
 pub mod android;
+pub mod numa;
 pub mod pstore;
 pub mod serial;
 
@@ -39,6 +40,7 @@ use devices::BusDevice;
 use devices::BusDeviceObj;
 use devices::BusError;
 use devices::BusResumeDevice;
+use devices::FwCfgParameters;
 use devices::HotPlugBus;
 use devices::IrqChip;
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
@@ -71,6 +73,8 @@ use gdbstub_arch::x86::X86_64_SSE as GdbArch;
 use hypervisor::CpuConfigAArch64 as CpuConfigArch;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use hypervisor::CpuConfigX86_64 as CpuConfigArch;
+#[cfg(all(any(target_arch = "x86_64", target_arch = "aarch64"), feature = "gdb"))]
+use hypervisor::GuestDebug;
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
 use hypervisor::Hypervisor as HypervisorArch;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -91,6 +95,7 @@ use hypervisor::VmAArch64 as VmArch;
 use hypervisor::VmX86_64 as VmArch;
 #[cfg(unix)]
 use minijail::Minijail;
+use numa::NumaMemoryRange;
 use remain::sorted;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use resources::AddressRange;
@@ -126,6 +131,24 @@ pub struct Pstore {
     pub size: u32,
 }
 
+/// Guest NUMA node, made of a set of vCPUs and a share of the guest memory.
+#[derive(Clone, Debug, Deserialize, Serialize, FromKeyValues, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct NumaNode {
+    /// ID of the node in the guest.
+    pub node: u32,
+    /// vCPUs belonging to the node.
+    pub cpus: CpuSet,
+    /// Amount of guest memory belonging to the node in MiB.
+    pub mem: u64,
+    /// Host NUMA node to allocate the memory of the node from.
+    #[serde(default)]
+    pub host_node: Option<u32>,
+    /// Distances from this node to every node, indexed by node ID.
+    #[serde(default)]
+    pub distances: Vec<u8>,
+}
+
 /// Set of CPU cores.
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct CpuSet(Vec<usize>);
@@ -318,6 +341,9 @@ pub struct VmComponents {
     pub extra_kernel_params: Vec<String>,
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub force_s2idle: bool,
+    /// A kernel loaded by the firmware through the fw_cfg device when booting a BIOS.
+    pub fw_cfg_kernel_image: Option<File>,
+    pub fw_cfg_parameters: Vec<FwCfgParameters>,
     #[cfg(all(any(target_arch = "x86_64", target_arch = "aarch64"), feature = "gdb"))]
     pub gdb: Option<(u32, Tube)>, // port and control tube.
     pub host_cpu_topology: bool,
@@ -329,6 +355,7 @@ pub struct VmComponents {
     pub no_i8042: bool,
     pub no_rtc: bool,
     pub no_smt: bool,
+    pub numa_nodes: Vec<NumaNode>,
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub oem_strings: Vec<String>,
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -401,6 +428,17 @@ pub trait LinuxArch {
         components: &VmComponents,
     ) -> std::result::Result<Vec<(GuestAddress, u64)>, Self::Error>;
 
+    /// Returns the ranges of guest RAM belonging to each of the NUMA nodes of `components`, or an
+    /// empty Vec if the guest has no NUMA topology. The regions returned by `guest_memory_layout`
+    /// never cross the boundary between two of these ranges.
+    ///
+    /// # Arguments
+    ///
+    /// * `components` - Parts used to determine the memory layout.
+    fn numa_memory_layout(
+        components: &VmComponents,
+    ) -> std::result::Result<Vec<NumaMemoryRange>, Self::Error>;
+
     /// Gets the configuration for a new `SystemAllocator` that fits the given `Vm`'s memory layout.
     ///
     /// This is the per-architecture template for constructing the `SystemAllocator`. Platform
@@ -520,14 +558,20 @@ pub trait GdbOps<T: VcpuArch> {
         data: &[u8],
     ) -> Result<(), Self::Error>;
 
-    /// Make the next vCPU's run single-step.
-    fn enable_singlestep(vcpu: &T) -> Result<(), Self::Error>;
-
     /// Get maximum number of hardware breakpoints.
     fn get_max_hw_breakpoints(vcpu: &T) -> Result<usize, Self::Error>;
 
-    /// Set hardware breakpoints at the given addresses.
-    fn set_hw_breakpoints(vcpu: &T, breakpoints: &[GuestAddress]) -> Result<(), Self::Error>;
+    /// Get maximum number of hardware watchpoints.
+    fn get_max_hw_watchpoints(vcpu: &T) -> Result<usize, Self::Error>;
+
+    /// Set up breakpoints, watchpoints and single-stepping for the next vCPU's runs.
+    fn set_guest_debug(vcpu: &T, debug: &GuestDebug) -> Result<(), Self::Error>;
+
+    /// Get the index in `debug.watchpoints` of the watchpoint that stopped the vCPU, if any.
+    fn get_watchpoint_hit(vcpu: &T, debug: &GuestDebug) -> Result<Option<usize>, Self::Error>;
+
+    /// Passes the breakpoint exception that stopped the vCPU on to the guest.
+    fn inject_breakpoint(vcpu: &T) -> Result<(), Self::Error>;
 }
 
 /// Errors for device manager.
@@ -675,6 +719,7 @@ pub fn configure_pci_device<V: VmArch, Vcpu: VcpuArch>(
 
     let mut keep_rds = device.keep_rds();
     syslog::push_descriptors(&mut keep_rds);
+    cros_tracing::push_descriptors(&mut keep_rds);
 
     device
         .register_device_capabilities()
@@ -756,6 +801,7 @@ pub fn generate_virtio_mmio_bus(
 
         let mut keep_rds = device.keep_rds();
         syslog::push_descriptors(&mut keep_rds);
+        cros_tracing::push_descriptors(&mut keep_rds);
 
         let irq_num = resources
             .allocate_irq()
@@ -1063,6 +1109,7 @@ pub fn generate_pci_root(
 
         let mut keep_rds = device.keep_rds();
         syslog::push_descriptors(&mut keep_rds);
+        cros_tracing::push_descriptors(&mut keep_rds);
         keep_rds.append(&mut vm.get_memory().as_raw_descriptors());
 
         let ranges = io_ranges.remove(&dev_idx).unwrap_or_default();
@@ -1331,6 +1378,37 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn parse_numa_node() {
+        let res: NumaNode = from_key_values("node=1,cpus=[2-3],mem=1024").unwrap();
+        assert_eq!(
+            res,
+            NumaNode {
+                node: 1,
+                cpus: CpuSet::new([2, 3]),
+                mem: 1024,
+                host_node: None,
+                distances: Vec::new(),
+            }
+        );
+
+        let res: NumaNode =
+            from_key_values("node=0,cpus=[0,1],mem=512,host-node=1,distances=[10,21]").unwrap();
+        assert_eq!(
+            res,
+            NumaNode {
+                node: 0,
+                cpus: CpuSet::new([0, 1]),
+                mem: 512,
+                host_node: Some(1),
+                distances: vec![10, 21],
+            }
+        );
+
+        let res = from_key_values::<NumaNode>("node=0,cpus=[0]");
+        assert!(res.is_err());
+    }
+
     #[test]
     fn deserialize_cpuset_serde_kv() {
         let res: CpuSet = from_key_values("[0,4,7]").unwrap();
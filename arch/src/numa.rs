@@ -0,0 +1,198 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Helpers to lay out guest NUMA nodes in the guest physical address space.
+
+use vm_memory::GuestAddress;
+
+use crate::NumaNode;
+
+/// Distance reported between a node and itself.
+pub const LOCAL_DISTANCE: u8 = 10;
+/// Distance reported between two different nodes when none was configured.
+pub const REMOTE_DISTANCE: u8 = 20;
+
+/// Range of guest RAM belonging to a NUMA node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NumaMemoryRange {
+    /// Guest ID of the node.
+    pub node: u32,
+    /// Guest physical address of the start of the range.
+    pub start: GuestAddress,
+    /// Size of the range in bytes.
+    pub size: u64,
+}
+
+/// Distributes the guest RAM regions `ram` between `nodes` in address order: the first node gets
+/// the lowest `nodes[0].mem` MiB of RAM, the second node the following ones, and so on.
+///
+/// A node whose memory spans a hole of the address space (e.g. the 32-bit PCI MMIO gap on x86)
+/// gets one range on each side of the hole. `ram` must be sorted by address.
+pub fn numa_memory_ranges(ram: &[(GuestAddress, u64)], nodes: &[NumaNode]) -> Vec<NumaMemoryRange> {
+    let mut ranges = Vec::new();
+    let mut regions = ram.iter().copied();
+    let mut current = regions.next();
+
+    for node in nodes {
+        let mut remaining = node.mem << 20;
+        while remaining > 0 {
+            let (start, size) = match current {
+                Some(region) => region,
+                None => return ranges,
+            };
+            let len = remaining.min(size);
+            ranges.push(NumaMemoryRange {
+                node: node.node,
+                start,
+                size: len,
+            });
+            remaining -= len;
+            current = if len == size {
+                regions.next()
+            } else {
+                Some((start.unchecked_add(len), size - len))
+            };
+        }
+    }
+
+    ranges
+}
+
+/// Splits the memory regions `regions` so that no region crosses the boundary between two of the
+/// NUMA ranges `numa_ranges`, which lets each node be backed (and bound on the host) separately.
+pub fn split_memory_regions(
+    regions: &[(GuestAddress, u64)],
+    numa_ranges: &[NumaMemoryRange],
+) -> Vec<(GuestAddress, u64)> {
+    let mut split = Vec::new();
+    for &(start, size) in regions {
+        let end = start.unchecked_add(size);
+        let mut cuts: Vec<GuestAddress> = numa_ranges
+            .iter()
+            .map(|range| range.start)
+            .filter(|&addr| addr > start && addr < end)
+            .collect();
+        cuts.sort();
+        cuts.push(end);
+
+        let mut piece_start = start;
+        for cut in cuts {
+            split.push((piece_start, cut.offset_from(piece_start)));
+            piece_start = cut;
+        }
+    }
+    split
+}
+
+/// Returns the distance from node `from` to node `to` reported to the guest, as found in the
+/// ACPI SLIT or the device tree distance map.
+pub fn numa_distance(nodes: &[NumaNode], from: usize, to: usize) -> u8 {
+    match nodes[from].distances.get(to) {
+        Some(&distance) => distance,
+        None if from == to => LOCAL_DISTANCE,
+        None => REMOTE_DISTANCE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CpuSet;
+
+    const MB: u64 = 1 << 20;
+
+    fn node(node: u32, mem: u64) -> NumaNode {
+        NumaNode {
+            node,
+            cpus: CpuSet::new([node as usize]),
+            mem,
+            host_node: None,
+            distances: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn ranges_single_region() {
+        let ram = [(GuestAddress(0), 1024 * MB)];
+        let ranges = numa_memory_ranges(&ram, &[node(0, 256), node(1, 768)]);
+        assert_eq!(
+            ranges,
+            vec![
+                NumaMemoryRange {
+                    node: 0,
+                    start: GuestAddress(0),
+                    size: 256 * MB,
+                },
+                NumaMemoryRange {
+                    node: 1,
+                    start: GuestAddress(256 * MB),
+                    size: 768 * MB,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn ranges_across_hole() {
+        let ram = [
+            (GuestAddress(0), 3072 * MB),
+            (GuestAddress(4096 * MB), 1024 * MB),
+        ];
+        let ranges = numa_memory_ranges(&ram, &[node(0, 2048), node(1, 2048)]);
+        assert_eq!(
+            ranges,
+            vec![
+                NumaMemoryRange {
+                    node: 0,
+                    start: GuestAddress(0),
+                    size: 2048 * MB,
+                },
+                NumaMemoryRange {
+                    node: 1,
+                    start: GuestAddress(2048 * MB),
+                    size: 1024 * MB,
+                },
+                NumaMemoryRange {
+                    node: 1,
+                    start: GuestAddress(4096 * MB),
+                    size: 1024 * MB,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn split_regions_at_node_boundaries() {
+        let regions = [
+            (GuestAddress(0), 3072 * MB),
+            (GuestAddress(4096 * MB - 0x1000), 0x1000),
+            (GuestAddress(4096 * MB), 1024 * MB),
+        ];
+        let ram = [
+            (GuestAddress(0), 3072 * MB),
+            (GuestAddress(4096 * MB), 1024 * MB),
+        ];
+        let ranges = numa_memory_ranges(&ram, &[node(0, 1024), node(1, 3072)]);
+        assert_eq!(
+            split_memory_regions(&regions, &ranges),
+            vec![
+                (GuestAddress(0), 1024 * MB),
+                (GuestAddress(1024 * MB), 2048 * MB),
+                (GuestAddress(4096 * MB - 0x1000), 0x1000),
+                (GuestAddress(4096 * MB), 1024 * MB),
+            ]
+        );
+    }
+
+    #[test]
+    fn default_distances() {
+        let mut nodes = vec![node(0, 256), node(1, 256)];
+        assert_eq!(numa_distance(&nodes, 0, 0), LOCAL_DISTANCE);
+        assert_eq!(numa_distance(&nodes, 0, 1), REMOTE_DISTANCE);
+
+        nodes[1].distances = vec![31, 10];
+        assert_eq!(numa_distance(&nodes, 1, 0), 31);
+        assert_eq!(numa_distance(&nodes, 0, 1), REMOTE_DISTANCE);
+    }
+}
@@ -247,6 +247,7 @@ mod tests {
                 stdin: true,
                 out_timestamp: false,
                 debugcon_port: 0,
+                name: None,
             },
         );
 
@@ -277,6 +278,7 @@ mod tests {
                 stdin: true,
                 out_timestamp: false,
                 debugcon_port: 0,
+                name: None,
             },
         );
 
@@ -294,6 +296,7 @@ mod tests {
                 stdin: false,
                 out_timestamp: false,
                 debugcon_port: 0,
+                name: None,
             },
         );
 
@@ -325,6 +328,7 @@ mod tests {
                 stdin: true,
                 out_timestamp: false,
                 debugcon_port: 0,
+                name: None,
             },
         );
 
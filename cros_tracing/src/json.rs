@@ -0,0 +1,302 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Writes trace events to a file in the Chrome trace-event JSON format.
+//!
+//! Each event is appended to the file as one line of a JSON array that is never closed, which both
+//! `chrome://tracing` and the Perfetto UI accept. This keeps the trace readable if crosvm dies, and
+//! lets jailed device processes append to the same file without any coordination: the file is
+//! opened with `O_APPEND` and every event is written with a single `write`.
+//!
+//! Jailed processes live in their own pid namespace, where `getpid()` is meaningless to the trace
+//! viewer and the same for every device. Events are therefore tagged with the host pid of the
+//! process, which a forked process learns from its parent through `ForkedProcess`, and each
+//! process is named in the trace with a label chosen before the fork.
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+
+use base::AsRawDescriptor;
+use base::RawDescriptor;
+use once_cell::sync::OnceCell;
+use serde_json::json;
+use serde_json::Value;
+
+static OUTPUT: OnceCell<File> = OnceCell::new();
+// Host pid of this process, or 0 if `getpid()` can be used as is.
+static HOST_PID: AtomicU32 = AtomicU32::new(0);
+
+/// Records a complete ("X") event covering the time between its creation and its drop.
+#[macro_export]
+macro_rules! trace_event {
+    ($category:ident, $name:expr) => {
+        if $crate::enabled() {
+            Some($crate::TraceEvent::new(
+                stringify!($category),
+                ($name).to_string(),
+            ))
+        } else {
+            None
+        }
+    };
+}
+
+/// Records the start of a duration on the current thread, ended by `trace_event_end!`.
+#[macro_export]
+macro_rules! trace_event_begin {
+    ($category:ident, $name:expr) => {
+        if $crate::enabled() {
+            $crate::trace_begin(stringify!($category), &($name).to_string());
+        }
+    };
+}
+
+/// Records the end of the last duration started on the current thread.
+#[macro_export]
+macro_rules! trace_event_end {
+    ($category:ident) => {
+        if $crate::enabled() {
+            $crate::trace_end(stringify!($category));
+        }
+    };
+}
+
+/// Does nothing; tracing only starts once an output is given to `init_with_output`.
+pub fn init() {}
+
+/// Starts writing trace events to `path`, replacing anything it contained.
+///
+/// This must be called before any device process is forked, and the descriptor returned through
+/// `push_descriptors` must be kept open in the jails so that those processes can trace too.
+pub fn init_with_output(path: &Path) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .custom_flags(libc::O_APPEND)
+        .open(path)?;
+    file.write_all(b"[\n")?;
+    OUTPUT.set(file).map_err(|_| {
+        io::Error::new(io::ErrorKind::AlreadyExists, "trace output was already set")
+    })?;
+    set_process(base::getpid() as u32, "crosvm");
+    Ok(())
+}
+
+/// Returns true if trace events are being recorded.
+pub fn enabled() -> bool {
+    OUTPUT.get().is_some()
+}
+
+/// Pushes the descriptor of the trace output, if any, into `keep_rds`.
+pub fn push_descriptors(keep_rds: &mut Vec<RawDescriptor>) {
+    if let Some(file) = OUTPUT.get() {
+        keep_rds.push(file.as_raw_descriptor());
+    }
+}
+
+/// Tags the events of this process with the host pid `pid` and names it `label` in the trace.
+pub fn set_process(pid: u32, label: &str) {
+    HOST_PID.store(pid, Ordering::Relaxed);
+    write_event(json!({
+        "name": "process_name",
+        "ph": "M",
+        "args": { "name": label },
+    }));
+}
+
+/// Passes its host pid to a process about to be forked, possibly into a new pid namespace.
+///
+/// Create this before forking and keep the descriptors from `push_descriptors` open in the jail,
+/// then call `parent` with the pid returned by the fork in the parent and `child` in the child.
+pub struct ForkedProcess {
+    label: String,
+    read_pipe: File,
+    write_pipe: File,
+}
+
+impl ForkedProcess {
+    /// Returns `None` if tracing is disabled, or if the pid can't be passed to the child, in which
+    /// case it is traced with its namespaced pid.
+    pub fn new(label: &str) -> Option<ForkedProcess> {
+        if !enabled() {
+            return None;
+        }
+        let (read_pipe, write_pipe) = base::pipe(true).ok()?;
+        Some(ForkedProcess {
+            label: label.to_string(),
+            read_pipe,
+            write_pipe,
+        })
+    }
+
+    pub fn push_descriptors(&self, keep_rds: &mut Vec<RawDescriptor>) {
+        keep_rds.push(self.read_pipe.as_raw_descriptor());
+        keep_rds.push(self.write_pipe.as_raw_descriptor());
+    }
+
+    /// Sends `pid`, the host pid of the forked process, to that process.
+    pub fn parent(self, pid: i32) {
+        let mut write_pipe = self.write_pipe;
+        let _ = write_pipe.write_all(&(pid as u32).to_ne_bytes());
+    }
+
+    /// Waits for the host pid of this process from the parent and records it with the label.
+    pub fn child(self) {
+        // Drop the write end first so that the read fails instead of hanging if the parent never
+        // sends the pid.
+        std::mem::drop(self.write_pipe);
+        let mut read_pipe = self.read_pipe;
+        let mut pid = [0u8; 4];
+        if read_pipe.read_exact(&mut pid).is_ok() {
+            set_process(u32::from_ne_bytes(pid), &self.label);
+        }
+    }
+}
+
+// Returns the time in microseconds on the monotonic clock, which is shared by all the processes.
+fn timestamp_us() -> f64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Safe because `ts` is a valid timespec that the kernel only writes to.
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as f64 * 1_000_000.0 + ts.tv_nsec as f64 / 1_000.0
+}
+
+fn write_event(mut event: Value) {
+    let mut file = match OUTPUT.get() {
+        Some(file) => file,
+        None => return,
+    };
+    // Thread ids may also be namespaced, but they only need to be unique within the process.
+    event["pid"] = match HOST_PID.load(Ordering::Relaxed) {
+        0 => base::getpid().into(),
+        pid => pid.into(),
+    };
+    event["tid"] = base::gettid().into();
+    let mut line = event.to_string();
+    line.push_str(",\n");
+    // Tracing must not get in the way of the VM, so errors are ignored.
+    let _ = file.write_all(line.as_bytes());
+}
+
+/// An event that is recorded with its duration when dropped. Created by `trace_event!`.
+pub struct TraceEvent {
+    category: &'static str,
+    name: String,
+    start_us: f64,
+}
+
+impl TraceEvent {
+    pub fn new(category: &'static str, name: String) -> TraceEvent {
+        TraceEvent {
+            category,
+            name,
+            start_us: timestamp_us(),
+        }
+    }
+}
+
+impl Drop for TraceEvent {
+    fn drop(&mut self) {
+        let end_us = timestamp_us();
+        write_event(json!({
+            "name": self.name,
+            "cat": self.category,
+            "ph": "X",
+            "ts": self.start_us,
+            "dur": end_us - self.start_us,
+        }));
+    }
+}
+
+#[doc(hidden)]
+pub fn trace_begin(category: &'static str, name: &str) {
+    write_event(json!({
+        "name": name,
+        "cat": category,
+        "ph": "B",
+        "ts": timestamp_us(),
+    }));
+}
+
+#[doc(hidden)]
+pub fn trace_end(category: &'static str) {
+    write_event(json!({
+        "cat": category,
+        "ph": "E",
+        "ts": timestamp_us(),
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn write_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.json");
+
+        // Nothing is recorded before the output is set.
+        assert!(trace_event!(test, "ignored").is_none());
+        init_with_output(&path).unwrap();
+        assert!(init_with_output(&path).is_err());
+
+        {
+            let _event = trace_event!(test, format!("complete {}", 1));
+        }
+        trace_event_begin!(test, "duration");
+        trace_event_end!(test);
+
+        // A forked process is traced with the host pid sent by its parent. Forking duplicates the
+        // pipe descriptors, which is done by hand here.
+        let child = ForkedProcess::new("device").unwrap();
+        let parent = ForkedProcess {
+            label: child.label.clone(),
+            read_pipe: child.read_pipe.try_clone().unwrap(),
+            write_pipe: child.write_pipe.try_clone().unwrap(),
+        };
+        parent.parent(4321);
+        child.child();
+        trace_event_begin!(test, "in device");
+
+        // Closing the array is left to the reader.
+        let mut contents = fs::read_to_string(&path).unwrap();
+        contents.truncate(contents.trim_end().trim_end_matches(',').len());
+        contents.push(']');
+        let events: Vec<Value> = serde_json::from_str(&contents).unwrap();
+
+        assert_eq!(events.len(), 5);
+        assert_eq!(events[0]["name"], "complete 1");
+        assert_eq!(events[0]["cat"], "test");
+        assert_eq!(events[0]["ph"], "X");
+        assert!(events[0]["dur"].as_f64().unwrap() >= 0.0);
+        assert_eq!(events[1]["name"], "duration");
+        assert_eq!(events[1]["ph"], "B");
+        assert_eq!(events[2]["ph"], "E");
+        assert!(events[2]["ts"].as_f64() >= events[1]["ts"].as_f64());
+        for event in &events[..3] {
+            assert_eq!(event["pid"], base::getpid());
+            assert_eq!(event["tid"], base::gettid());
+        }
+        assert_eq!(events[3]["name"], "process_name");
+        assert_eq!(events[3]["ph"], "M");
+        assert_eq!(events[3]["args"]["name"], "device");
+        assert_eq!(events[3]["pid"], 4321);
+        assert_eq!(events[4]["name"], "in device");
+        assert_eq!(events[4]["pid"], 4321);
+    }
+}
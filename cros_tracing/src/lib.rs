@@ -2,7 +2,18 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-/// A crate that provides noop tracing.
+//! Tracing of crosvm events.
+//!
+//! By default every tracing macro compiles to nothing. With the `trace_json` feature, events are
+//! written to the file given to `init_with_output()` in the Chrome trace-event JSON format, which
+//! can be loaded into `chrome://tracing` or the Perfetto UI.
+
+#[cfg(all(unix, feature = "trace_json"))]
+mod json;
+#[cfg(not(all(unix, feature = "trace_json")))]
 mod noop;
 
+#[cfg(all(unix, feature = "trace_json"))]
+pub use json::*;
+#[cfg(not(all(unix, feature = "trace_json")))]
 pub use noop::*;
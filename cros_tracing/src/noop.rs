@@ -21,3 +21,25 @@ macro_rules! trace_event_end {
 }
 
 pub fn init() {}
+
+pub fn push_descriptors(_keep_rds: &mut Vec<base::RawDescriptor>) {}
+
+pub fn enabled() -> bool {
+    false
+}
+
+pub fn set_process(_pid: u32, _label: &str) {}
+
+pub struct ForkedProcess;
+
+impl ForkedProcess {
+    pub fn new(_label: &str) -> Option<ForkedProcess> {
+        None
+    }
+
+    pub fn push_descriptors(&self, _keep_rds: &mut Vec<base::RawDescriptor>) {}
+
+    pub fn parent(self, _pid: i32) {}
+
+    pub fn child(self) {}
+}
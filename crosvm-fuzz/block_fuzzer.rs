@@ -98,6 +98,7 @@ fuzz_target!(|bytes| {
         None,
         None,
         None,
+        Default::default(),
     )
     .unwrap();
 
@@ -12,6 +12,7 @@ use devices::virtio::DescriptorChain;
 use devices::virtio::Queue;
 use rand::Rng;
 use rand::RngCore;
+use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
 use vm_memory::GuestAddress;
 use vm_memory::GuestMemory;
 
@@ -54,22 +55,38 @@ struct virtq_used {
     avail_event: u16,
 }
 
+// In a packed virtqueue, the available and used rings are replaced by the driver and device event
+// suppression structures. The descriptor ring entries have the same size as virtq_desc.
+#[repr(C, packed)]
+struct pvirtq_event_suppress {
+    desc: u16,
+    flags: u16,
+}
+
 fuzz_target!(|data: &[u8]| {
     let mut q = Queue::new(MAX_QUEUE_SIZE);
     let mut rng = FuzzRng::new(data);
+    let packed: bool = rng.gen();
+    if packed {
+        q.ack_features(1 << VIRTIO_F_RING_PACKED);
+    }
     q.set_size(rng.gen());
     q.set_ready(true);
 
     // For each of {desc_table,avail_ring,used_ring} generate a random address that includes enough
     // space to hold the relevant struct with the largest possible queue size.
+    let (avail_size, used_size) = if packed {
+        (
+            size_of::<pvirtq_event_suppress>(),
+            size_of::<pvirtq_event_suppress>(),
+        )
+    } else {
+        (size_of::<virtq_avail>(), size_of::<virtq_used>())
+    };
     let max_table_size = MAX_QUEUE_SIZE as u64 * size_of::<virtq_desc>() as u64;
     q.set_desc_table(GuestAddress(rng.gen_range(0..MEM_SIZE - max_table_size)));
-    q.set_avail_ring(GuestAddress(
-        rng.gen_range(0..MEM_SIZE - size_of::<virtq_avail>() as u64),
-    ));
-    q.set_used_ring(GuestAddress(
-        rng.gen_range(0..MEM_SIZE - size_of::<virtq_used>() as u64),
-    ));
+    q.set_avail_ring(GuestAddress(rng.gen_range(0..MEM_SIZE - avail_size as u64)));
+    q.set_used_ring(GuestAddress(rng.gen_range(0..MEM_SIZE - used_size as u64)));
 
     GUEST_MEM.with(|mem| {
         if !q.is_valid(mem) {
@@ -89,16 +106,26 @@ fuzz_target!(|data: &[u8]| {
         rng.fill_bytes(&mut buf[..]);
         mem.write_all_at_addr(&buf[..], q.desc_table()).unwrap();
 
-        // Fill in the available ring. See the definition of virtq_avail above for the source of
+        // Fill in the available ring, or the driver event suppression structure of a packed queue.
+        // See the definitions of virtq_avail and pvirtq_event_suppress above for the source of
         // these numbers.
-        let avail_size = 4 + (queue_size * 2) + 2;
+        let avail_size = if packed {
+            size_of::<pvirtq_event_suppress>()
+        } else {
+            4 + (queue_size * 2) + 2
+        };
         buf.resize(avail_size, 0);
         rng.fill_bytes(&mut buf[..]);
         mem.write_all_at_addr(&buf[..], q.avail_ring()).unwrap();
 
-        // Fill in the used ring. See the definition of virtq_used above for the source of
-        // these numbers.
-        let used_size = 4 + (queue_size * size_of::<virtq_used_elem>()) + 2;
+        // Fill in the used ring, or the device event suppression structure of a packed queue. See
+        // the definitions of virtq_used and pvirtq_event_suppress above for the source of these
+        // numbers.
+        let used_size = if packed {
+            size_of::<pvirtq_event_suppress>()
+        } else {
+            4 + (queue_size * size_of::<virtq_used_elem>()) + 2
+        };
         buf.resize(used_size, 0);
         rng.fill_bytes(&mut buf[..]);
         mem.write_all_at_addr(&buf[..], q.used_ring()).unwrap();
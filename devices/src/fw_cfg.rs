@@ -0,0 +1,587 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Firmware configuration device compatible with [QEMU's fw_cfg], which lets the firmware and the
+//! guest read named blobs provided by the host.
+//!
+//! Items are selected by writing their key to the selector register, and read either one byte at
+//! a time from the data register, or through the DMA interface by writing the guest address of a
+//! `FwCfgDmaAccess` descriptor to the DMA register. On x86 the device uses I/O ports and is
+//! described by ACPI, on ARM it is memory-mapped and described by the device tree.
+//!
+//! Besides the named files listed in the file directory, the device exposes the well-known items
+//! that firmware such as OVMF and SeaBIOS use to load a kernel, initrd and command line given to
+//! the VMM. Writes to items are not supported.
+//!
+//! [QEMU's fw_cfg]: https://www.qemu.org/docs/master/specs/fw_cfg.html
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use acpi_tables::aml;
+use acpi_tables::aml::Aml;
+use base::error;
+use data_model::DataInit;
+use remain::sorted;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+
+use crate::pci::CrosvmDeviceId;
+use crate::BusAccessInfo;
+use crate::BusDevice;
+use crate::DeviceId;
+use crate::Suspendable;
+
+/// Base of the I/O ports of the device, as expected by firmware on x86.
+pub const FW_CFG_IO_BASE: u64 = 0x510;
+/// Size of the I/O port range of the device.
+pub const FW_CFG_IO_SIZE: u64 = 0xc;
+/// Size of the registers of the memory-mapped device.
+pub const FW_CFG_MMIO_SIZE: u64 = 0x18;
+
+/// Maximum length of the name of a file, including the terminating null byte.
+pub const FW_CFG_MAX_FILE_NAME: usize = 56;
+
+// Well-known item keys.
+const FW_CFG_SIGNATURE: u16 = 0x00;
+const FW_CFG_ID: u16 = 0x01;
+const FW_CFG_RAM_SIZE: u16 = 0x03;
+const FW_CFG_NB_CPUS: u16 = 0x05;
+const FW_CFG_KERNEL_SIZE: u16 = 0x08;
+const FW_CFG_INITRD_SIZE: u16 = 0x0b;
+const FW_CFG_MAX_CPUS: u16 = 0x0f;
+const FW_CFG_KERNEL_DATA: u16 = 0x11;
+const FW_CFG_INITRD_DATA: u16 = 0x12;
+const FW_CFG_CMDLINE_SIZE: u16 = 0x14;
+const FW_CFG_CMDLINE_DATA: u16 = 0x15;
+const FW_CFG_SETUP_SIZE: u16 = 0x17;
+const FW_CFG_SETUP_DATA: u16 = 0x18;
+const FW_CFG_FILE_DIR: u16 = 0x19;
+const FW_CFG_FILE_FIRST: u16 = 0x20;
+// Keys with bit 14 set select the item for writing, which is not supported.
+const FW_CFG_ENTRY_MASK: u16 = 0x3fff;
+
+// Feature bits of the FW_CFG_ID item.
+const FW_CFG_VERSION: u32 = 0x01;
+const FW_CFG_VERSION_DMA: u32 = 0x02;
+
+// Value read from the DMA register, which lets firmware detect the DMA interface.
+const FW_CFG_DMA_SIGNATURE: [u8; 8] = *b"QEMU CFG";
+
+// Control bits of the DMA access descriptor.
+const FW_CFG_DMA_CTL_ERROR: u32 = 0x01;
+const FW_CFG_DMA_CTL_READ: u32 = 0x02;
+const FW_CFG_DMA_CTL_SKIP: u32 = 0x04;
+const FW_CFG_DMA_CTL_SELECT: u32 = 0x08;
+const FW_CFG_DMA_CTL_WRITE: u32 = 0x10;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum FwCfgError {
+    #[error("fw_cfg file {0} is specified more than once")]
+    DuplicateFile(String),
+    #[error("fw_cfg file name {0} is empty or too long")]
+    InvalidFileName(String),
+    #[error("too many fw_cfg files")]
+    TooManyFiles,
+}
+
+pub type Result<T> = std::result::Result<T, FwCfgError>;
+
+/// A named file exposed by the fw_cfg device, with either the content of a host file or a string.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FwCfgParameters {
+    pub name: String,
+    pub path: Option<PathBuf>,
+    pub string: Option<String>,
+}
+
+impl FwCfgParameters {
+    /// Returns the content of the file.
+    pub fn data(&self) -> io::Result<Vec<u8>> {
+        match (&self.path, &self.string) {
+            (Some(path), None) => fs::read(path),
+            (None, Some(string)) => Ok(string.as_bytes().to_vec()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "exactly one of path or string must be given",
+            )),
+        }
+    }
+}
+
+/// How the registers of the device are accessed by the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FwCfgInterface {
+    /// I/O ports, with a little-endian selector register at offset 0 and the data register at
+    /// offset 1.
+    Io { base: u16 },
+    /// Memory-mapped registers, with the data register at offset 0 and a big-endian selector
+    /// register at offset 8.
+    Mmio { base: u64 },
+}
+
+impl FwCfgInterface {
+    fn selector_offset(&self) -> u64 {
+        match self {
+            FwCfgInterface::Io { .. } => 0,
+            FwCfgInterface::Mmio { .. } => 8,
+        }
+    }
+
+    fn data_offset(&self) -> u64 {
+        match self {
+            FwCfgInterface::Io { .. } => 1,
+            FwCfgInterface::Mmio { .. } => 0,
+        }
+    }
+
+    fn dma_offset(&self) -> u64 {
+        match self {
+            FwCfgInterface::Io { .. } => 4,
+            FwCfgInterface::Mmio { .. } => 16,
+        }
+    }
+}
+
+/// Descriptor of a DMA transfer, read from guest memory. All the fields are big-endian.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct FwCfgDmaAccess {
+    control: u32,
+    length: u32,
+    address: u64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for FwCfgDmaAccess {}
+
+struct FwCfgFile {
+    name: String,
+    key: u16,
+}
+
+pub struct FwCfg {
+    mem: GuestMemory,
+    interface: FwCfgInterface,
+    items: BTreeMap<u16, Vec<u8>>,
+    files: Vec<FwCfgFile>,
+    selector: u16,
+    offset: usize,
+    dma_address: u64,
+}
+
+impl FwCfg {
+    /// Creates the device, accessed through `interface`, doing DMA transfers to `mem`.
+    pub fn new(mem: GuestMemory, interface: FwCfgInterface) -> FwCfg {
+        let mut items = BTreeMap::new();
+        items.insert(FW_CFG_SIGNATURE, b"QEMU".to_vec());
+        items.insert(
+            FW_CFG_ID,
+            (FW_CFG_VERSION | FW_CFG_VERSION_DMA).to_le_bytes().to_vec(),
+        );
+        items.insert(FW_CFG_FILE_DIR, 0u32.to_be_bytes().to_vec());
+        FwCfg {
+            mem,
+            interface,
+            items,
+            files: Vec::new(),
+            selector: 0,
+            offset: 0,
+            dma_address: 0,
+        }
+    }
+
+    /// Sets the size of the guest memory, in bytes.
+    pub fn set_ram_size(&mut self, size: u64) {
+        self.items
+            .insert(FW_CFG_RAM_SIZE, size.to_le_bytes().to_vec());
+    }
+
+    /// Sets the number of vCPUs of the guest.
+    pub fn set_cpu_count(&mut self, count: u16) {
+        self.items
+            .insert(FW_CFG_NB_CPUS, count.to_le_bytes().to_vec());
+        self.items
+            .insert(FW_CFG_MAX_CPUS, count.to_le_bytes().to_vec());
+    }
+
+    /// Sets the kernel loaded by the firmware. On x86, `setup` is the real-mode part of the
+    /// bzImage and `kernel` is the rest of it, other architectures have no setup part.
+    pub fn set_kernel(&mut self, setup: Vec<u8>, kernel: Vec<u8>) {
+        self.set_blob(FW_CFG_SETUP_SIZE, FW_CFG_SETUP_DATA, setup);
+        self.set_blob(FW_CFG_KERNEL_SIZE, FW_CFG_KERNEL_DATA, kernel);
+    }
+
+    /// Sets the initrd loaded by the firmware with the kernel.
+    pub fn set_initrd(&mut self, initrd: Vec<u8>) {
+        self.set_blob(FW_CFG_INITRD_SIZE, FW_CFG_INITRD_DATA, initrd);
+    }
+
+    /// Sets the command line of the kernel loaded by the firmware.
+    pub fn set_cmdline(&mut self, cmdline: &str) {
+        let mut data = cmdline.as_bytes().to_vec();
+        data.push(0);
+        self.set_blob(FW_CFG_CMDLINE_SIZE, FW_CFG_CMDLINE_DATA, data);
+    }
+
+    fn set_blob(&mut self, size_key: u16, data_key: u16, data: Vec<u8>) {
+        self.items
+            .insert(size_key, (data.len() as u32).to_le_bytes().to_vec());
+        self.items.insert(data_key, data);
+    }
+
+    /// Adds a file named `name` to the file directory.
+    pub fn add_file(&mut self, name: &str, data: Vec<u8>) -> Result<()> {
+        if name.is_empty() || name.len() >= FW_CFG_MAX_FILE_NAME {
+            return Err(FwCfgError::InvalidFileName(name.to_string()));
+        }
+        if self.files.iter().any(|f| f.name == name) {
+            return Err(FwCfgError::DuplicateFile(name.to_string()));
+        }
+        let key = FW_CFG_FILE_FIRST + self.files.len() as u16;
+        if key > FW_CFG_ENTRY_MASK {
+            return Err(FwCfgError::TooManyFiles);
+        }
+        self.items.insert(key, data);
+        self.files.push(FwCfgFile {
+            name: name.to_string(),
+            key,
+        });
+        self.update_file_dir();
+        Ok(())
+    }
+
+    fn update_file_dir(&mut self) {
+        // The directory is sorted by name, as firmware may do binary searches in it.
+        let mut files: Vec<&FwCfgFile> = self.files.iter().collect();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut dir = (files.len() as u32).to_be_bytes().to_vec();
+        for file in files {
+            let size = self.items.get(&file.key).map_or(0, |data| data.len());
+            dir.extend_from_slice(&(size as u32).to_be_bytes());
+            dir.extend_from_slice(&file.key.to_be_bytes());
+            dir.extend_from_slice(&[0, 0]);
+            let mut name = [0u8; FW_CFG_MAX_FILE_NAME];
+            name[..file.name.len()].copy_from_slice(file.name.as_bytes());
+            dir.extend_from_slice(&name);
+        }
+        self.items.insert(FW_CFG_FILE_DIR, dir);
+    }
+
+    fn select(&mut self, key: u16) {
+        self.selector = key;
+        self.offset = 0;
+    }
+
+    fn selected_item(&self) -> &[u8] {
+        self.items
+            .get(&(self.selector & FW_CFG_ENTRY_MASK))
+            .map_or(&[], |data| data.as_slice())
+    }
+
+    fn read_data(&mut self, data: &mut [u8]) {
+        let item = self.selected_item();
+        let start = self.offset.min(item.len());
+        let len = data.len().min(item.len() - start);
+        data[..len].copy_from_slice(&item[start..start + len]);
+        data[len..].fill(0);
+        self.offset += data.len();
+    }
+
+    // Copies `length` bytes of the selected item to guest memory at `address`, with zeroes past the
+    // end of the item. The guest chooses `length`, so nothing proportional to it is allocated.
+    fn read_data_to_guest(&mut self, address: GuestAddress, length: usize) -> Result<(), ()> {
+        let item = self.selected_item();
+        let start = self.offset.min(item.len());
+        let len = length.min(item.len() - start);
+        self.offset = self.offset.saturating_add(length);
+        self.mem
+            .write_all_at_addr(&item[start..start + len], address)
+            .map_err(|e| error!("fw_cfg: failed to write DMA data: {}", e))?;
+
+        let zeroes = [0u8; 4096];
+        let mut written = len;
+        while written < length {
+            let chunk = (length - written).min(zeroes.len());
+            let chunk_address = address
+                .checked_add(written as u64)
+                .ok_or_else(|| error!("fw_cfg: DMA data at {} overflows", address))?;
+            self.mem
+                .write_all_at_addr(&zeroes[..chunk], chunk_address)
+                .map_err(|e| error!("fw_cfg: failed to write DMA data: {}", e))?;
+            written += chunk;
+        }
+        Ok(())
+    }
+
+    fn write_dma_address(&mut self, offset: u64, data: &[u8]) {
+        match (offset, data.len()) {
+            (0, 8) => {
+                self.dma_address = u64::from_be_bytes(data.try_into().unwrap());
+                self.do_dma();
+            }
+            (0, 4) => {
+                let high = u32::from_be_bytes(data.try_into().unwrap());
+                self.dma_address = (u64::from(high) << 32) | (self.dma_address & 0xffff_ffff);
+            }
+            // Writing the low half of the address starts the transfer.
+            (4, 4) => {
+                let low = u32::from_be_bytes(data.try_into().unwrap());
+                self.dma_address = (self.dma_address & !0xffff_ffff) | u64::from(low);
+                self.do_dma();
+            }
+            _ => error!(
+                "fw_cfg: invalid DMA register write of {} bytes at {}",
+                data.len(),
+                offset
+            ),
+        }
+    }
+
+    fn do_dma(&mut self) {
+        let access_address = GuestAddress(self.dma_address);
+        // The address is cleared once the transfer is done.
+        self.dma_address = 0;
+        let access: FwCfgDmaAccess = match self.mem.read_obj_from_addr(access_address) {
+            Ok(access) => access,
+            Err(e) => {
+                error!("fw_cfg: failed to read DMA access: {}", e);
+                return;
+            }
+        };
+        let control = u32::from_be(access.control);
+        let length = u32::from_be(access.length) as usize;
+        let address = GuestAddress(u64::from_be(access.address));
+
+        if control & FW_CFG_DMA_CTL_SELECT != 0 {
+            self.select((control >> 16) as u16);
+        }
+
+        let result = if control & FW_CFG_DMA_CTL_READ != 0 {
+            self.read_data_to_guest(address, length)
+        } else if control & FW_CFG_DMA_CTL_WRITE != 0 {
+            error!("fw_cfg: writes are not supported");
+            Err(())
+        } else {
+            if control & FW_CFG_DMA_CTL_SKIP != 0 {
+                self.offset = self.offset.saturating_add(length);
+            }
+            Ok(())
+        };
+
+        let status = match result {
+            Ok(()) => 0,
+            Err(()) => FW_CFG_DMA_CTL_ERROR,
+        };
+        if let Err(e) = self.mem.write_obj_at_addr(status.to_be(), access_address) {
+            error!("fw_cfg: failed to complete DMA access: {}", e);
+        }
+    }
+}
+
+impl BusDevice for FwCfg {
+    fn device_id(&self) -> DeviceId {
+        CrosvmDeviceId::FwCfg.into()
+    }
+
+    fn debug_label(&self) -> String {
+        "fw_cfg".to_owned()
+    }
+
+    fn read(&mut self, info: BusAccessInfo, data: &mut [u8]) {
+        let dma_offset = self.interface.dma_offset();
+        if info.offset == self.interface.data_offset() {
+            self.read_data(data);
+        } else if info.offset >= dma_offset && info.offset < dma_offset + 8 {
+            let start = (info.offset - dma_offset) as usize;
+            let end = (start + data.len()).min(FW_CFG_DMA_SIGNATURE.len());
+            data.fill(0);
+            data[..end - start].copy_from_slice(&FW_CFG_DMA_SIGNATURE[start..end]);
+        } else {
+            data.fill(0);
+        }
+    }
+
+    fn write(&mut self, info: BusAccessInfo, data: &[u8]) {
+        let dma_offset = self.interface.dma_offset();
+        if info.offset == self.interface.selector_offset() && data.len() == 2 {
+            let key = match self.interface {
+                FwCfgInterface::Io { .. } => u16::from_le_bytes([data[0], data[1]]),
+                FwCfgInterface::Mmio { .. } => u16::from_be_bytes([data[0], data[1]]),
+            };
+            self.select(key);
+        } else if info.offset >= dma_offset && info.offset < dma_offset + 8 {
+            self.write_dma_address(info.offset - dma_offset, data);
+        }
+    }
+}
+
+impl Suspendable for FwCfg {}
+
+impl Aml for FwCfg {
+    fn to_aml_bytes(&self, bytes: &mut Vec<u8>) {
+        let resource: Box<dyn Aml> = match self.interface {
+            FwCfgInterface::Io { base } => {
+                Box::new(aml::IO::new(base, base, 1, FW_CFG_IO_SIZE as u8))
+            }
+            FwCfgInterface::Mmio { base } => Box::new(aml::Memory32Fixed::new(
+                true,
+                base as u32,
+                FW_CFG_MMIO_SIZE as u32,
+            )),
+        };
+        aml::Device::new(
+            "_SB_.FWCF".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &"QEMU0002"),
+                &aml::Name::new("_STA".into(), &0xbu8),
+                &aml::Name::new(
+                    "_CRS".into(),
+                    &aml::ResourceTemplate::new(vec![resource.as_ref()]),
+                ),
+            ],
+        )
+        .to_aml_bytes(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IO: FwCfgInterface = FwCfgInterface::Io {
+        base: FW_CFG_IO_BASE as u16,
+    };
+
+    fn access(offset: u64) -> BusAccessInfo {
+        BusAccessInfo {
+            offset,
+            address: FW_CFG_IO_BASE + offset,
+            id: 0,
+        }
+    }
+
+    fn new_fw_cfg() -> FwCfg {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        FwCfg::new(mem, IO)
+    }
+
+    fn read_item(fw_cfg: &mut FwCfg, key: u16, len: usize) -> Vec<u8> {
+        fw_cfg.write(access(0), &key.to_le_bytes());
+        let mut data = vec![0u8; len];
+        for byte in data.iter_mut() {
+            let mut buf = [0u8];
+            fw_cfg.read(access(1), &mut buf);
+            *byte = buf[0];
+        }
+        data
+    }
+
+    #[test]
+    fn signature() {
+        let mut fw_cfg = new_fw_cfg();
+        assert_eq!(read_item(&mut fw_cfg, FW_CFG_SIGNATURE, 4), b"QEMU");
+        assert_eq!(read_item(&mut fw_cfg, FW_CFG_ID, 4), [3, 0, 0, 0]);
+
+        let mut dma = [0u8; 8];
+        fw_cfg.read(access(4), &mut dma);
+        assert_eq!(&dma, b"QEMU CFG");
+    }
+
+    #[test]
+    fn file_dir() {
+        let mut fw_cfg = new_fw_cfg();
+        fw_cfg.add_file("opt/foo", b"foo".to_vec()).unwrap();
+        fw_cfg.add_file("etc/bar", b"barbar".to_vec()).unwrap();
+        assert!(fw_cfg.add_file("opt/foo", Vec::new()).is_err());
+
+        let dir = read_item(&mut fw_cfg, FW_CFG_FILE_DIR, 4 + 2 * 64);
+        assert_eq!(&dir[0..4], &2u32.to_be_bytes());
+        // Entries are sorted by name.
+        assert_eq!(&dir[4..8], &6u32.to_be_bytes());
+        assert_eq!(&dir[8..10], &(FW_CFG_FILE_FIRST + 1).to_be_bytes());
+        assert_eq!(&dir[12..19], b"etc/bar");
+        assert_eq!(&dir[68..72], &3u32.to_be_bytes());
+        assert_eq!(&dir[72..74], &FW_CFG_FILE_FIRST.to_be_bytes());
+        assert_eq!(&dir[76..83], b"opt/foo");
+
+        assert_eq!(read_item(&mut fw_cfg, FW_CFG_FILE_FIRST, 5), b"foo\0\0");
+    }
+
+    #[test]
+    fn dma_read() {
+        let mut fw_cfg = new_fw_cfg();
+        fw_cfg.set_cmdline("console=ttyS0");
+
+        let access_addr = GuestAddress(0x1000);
+        let data_addr = GuestAddress(0x2000);
+        let descriptor = FwCfgDmaAccess {
+            control: ((u32::from(FW_CFG_CMDLINE_DATA) << 16)
+                | FW_CFG_DMA_CTL_SELECT
+                | FW_CFG_DMA_CTL_READ)
+                .to_be(),
+            length: 14u32.to_be(),
+            address: data_addr.offset().to_be(),
+        };
+        fw_cfg
+            .mem
+            .write_obj_at_addr(descriptor, access_addr)
+            .unwrap();
+        fw_cfg.write(access(4), &0u32.to_be_bytes());
+        fw_cfg.write(access(8), &(access_addr.offset() as u32).to_be_bytes());
+
+        let control: u32 = fw_cfg.mem.read_obj_from_addr(access_addr).unwrap();
+        assert_eq!(control, 0);
+        let mut data = [0u8; 14];
+        fw_cfg.mem.read_exact_at_addr(&mut data, data_addr).unwrap();
+        assert_eq!(&data, b"console=ttyS0\0");
+    }
+
+    #[test]
+    fn dma_read_past_item_end() {
+        let mut fw_cfg = new_fw_cfg();
+        fw_cfg.set_cmdline("console=ttyS0");
+
+        let access_addr = GuestAddress(0x1000);
+        let data_addr = GuestAddress(0x2000);
+        fw_cfg
+            .mem
+            .write_all_at_addr(&[0xff; 0x3000], data_addr)
+            .unwrap();
+        let mut do_dma = |length: u32| {
+            let descriptor = FwCfgDmaAccess {
+                control: ((u32::from(FW_CFG_CMDLINE_DATA) << 16)
+                    | FW_CFG_DMA_CTL_SELECT
+                    | FW_CFG_DMA_CTL_READ)
+                    .to_be(),
+                length: length.to_be(),
+                address: data_addr.offset().to_be(),
+            };
+            fw_cfg
+                .mem
+                .write_obj_at_addr(descriptor, access_addr)
+                .unwrap();
+            fw_cfg.write(access(4), &0u32.to_be_bytes());
+            fw_cfg.write(access(8), &(access_addr.offset() as u32).to_be_bytes());
+            u32::from_be(fw_cfg.mem.read_obj_from_addr(access_addr).unwrap())
+        };
+
+        // The data past the end of the item is zeroed, over several chunks.
+        assert_eq!(do_dma(0x3000), 0);
+        let mut data = vec![0u8; 0x3000];
+        fw_cfg.mem.read_exact_at_addr(&mut data, data_addr).unwrap();
+        assert_eq!(&data[..14], b"console=ttyS0\0");
+        assert!(data[14..].iter().all(|&b| b == 0));
+
+        // A length beyond guest memory fails without allocating it.
+        assert_eq!(do_dma(u32::MAX), FW_CFG_DMA_CTL_ERROR);
+    }
+}
@@ -13,9 +13,12 @@ use hypervisor::IrqRoute;
 use hypervisor::IrqSource;
 use hypervisor::IrqSourceChip;
 use hypervisor::LapicState;
+use hypervisor::MPState;
 use hypervisor::PicSelect;
 use hypervisor::PicState;
 use hypervisor::PitState;
+use serde::Deserialize;
+use serde::Serialize;
 
 use crate::IrqChip;
 
@@ -58,6 +61,56 @@ pub trait IrqChipX86_64: IrqChip {
 
     /// Returns true if the PIT uses port 0x61 for the PC speaker, false if 0x61 is unused.
     fn pit_uses_speaker_port(&self) -> bool;
+
+    /// Captures the state of the PICs, IOAPIC, PIT and the local APIC and MP state of the first
+    /// `num_vcpus` VCPUs.
+    fn snapshot(&self, num_vcpus: usize) -> Result<IrqChipSnapshot> {
+        let mut vcpus = Vec::with_capacity(num_vcpus);
+        for vcpu_id in 0..num_vcpus {
+            vcpus.push(VcpuIrqSnapshot {
+                lapic: self.get_lapic_state(vcpu_id)?,
+                mp_state: self.as_irq_chip().get_mp_state(vcpu_id)?,
+            });
+        }
+        Ok(IrqChipSnapshot {
+            pic_primary: self.get_pic_state(PicSelect::Primary)?,
+            pic_secondary: self.get_pic_state(PicSelect::Secondary)?,
+            ioapic: self.get_ioapic_state()?,
+            pit: self.get_pit()?,
+            vcpus,
+        })
+    }
+
+    /// Restores state previously captured by `snapshot`.
+    fn restore(&mut self, snapshot: &IrqChipSnapshot) -> Result<()> {
+        self.set_pic_state(PicSelect::Primary, &snapshot.pic_primary)?;
+        self.set_pic_state(PicSelect::Secondary, &snapshot.pic_secondary)?;
+        self.set_ioapic_state(&snapshot.ioapic)?;
+        self.set_pit(&snapshot.pit)?;
+        for (vcpu_id, vcpu) in snapshot.vcpus.iter().enumerate() {
+            self.set_lapic_state(vcpu_id, &vcpu.lapic)?;
+            self.as_irq_chip_mut()
+                .set_mp_state(vcpu_id, &vcpu.mp_state)?;
+        }
+        Ok(())
+    }
+}
+
+/// Per-VCPU interrupt controller state saved in an `IrqChipSnapshot`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VcpuIrqSnapshot {
+    pub lapic: LapicState,
+    pub mp_state: MPState,
+}
+
+/// State of the x86 interrupt controllers, as captured by `IrqChipX86_64::snapshot`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IrqChipSnapshot {
+    pub pic_primary: PicState,
+    pub pic_secondary: PicState,
+    pub ioapic: IoapicState,
+    pub pit: PitState,
+    pub vcpus: Vec<VcpuIrqSnapshot>,
 }
 
 /// A container for x86 IrqRoutes, grouped by GSI.
@@ -18,6 +18,7 @@ mod debugcon;
 pub mod direct_io;
 #[cfg(feature = "direct")]
 pub mod direct_irq;
+mod fw_cfg;
 mod i8042;
 mod irq_event;
 pub mod irqchip;
@@ -29,6 +30,8 @@ pub mod serial_device;
 #[cfg(feature = "tpm")]
 mod software_tpm;
 mod suspendable;
+#[cfg(all(unix, feature = "swtpm"))]
+mod swtpm;
 mod sys;
 pub mod virtio;
 #[cfg(all(feature = "vtpm", target_arch = "x86_64"))]
@@ -87,6 +90,13 @@ pub use self::direct_io::DirectMmio;
 pub use self::direct_irq::DirectIrq;
 #[cfg(feature = "direct")]
 pub use self::direct_irq::DirectIrqError;
+pub use self::fw_cfg::FwCfg;
+pub use self::fw_cfg::FwCfgError;
+pub use self::fw_cfg::FwCfgInterface;
+pub use self::fw_cfg::FwCfgParameters;
+pub use self::fw_cfg::FW_CFG_IO_BASE;
+pub use self::fw_cfg::FW_CFG_IO_SIZE;
+pub use self::fw_cfg::FW_CFG_MMIO_SIZE;
 pub use self::i8042::I8042Device;
 pub use self::irq_event::IrqEdgeEvent;
 pub use self::irq_event::IrqLevelEvent;
@@ -127,6 +137,10 @@ pub use self::serial_device::SerialType;
 pub use self::software_tpm::SoftwareTpm;
 pub use self::suspendable::DeviceState;
 pub use self::suspendable::Suspendable;
+#[cfg(all(unix, feature = "swtpm"))]
+pub use self::swtpm::Swtpm;
+#[cfg(all(unix, feature = "swtpm"))]
+pub use self::swtpm::TpmVersion;
 pub use self::virtio::VirtioMmioDevice;
 pub use self::virtio::VirtioPciDevice;
 #[cfg(all(feature = "vtpm", target_arch = "x86_64"))]
@@ -375,19 +389,21 @@ async fn handle_command_tube(
                                 continue 'listener;
                             }
                         }
-                        for bus in buses {
-                            wake_devices(bus);
-                        }
-
                         if let Err(e) = serde_json::to_writer(&mut file, &devices_vec) {
                             error!("failed to write serialized device to snapshot");
+                            for bus in &buses {
+                                wake_devices(bus);
+                            }
                             if let Err(e) = command_tube
                                 .send(SnapshotControlResult::Failed(e.to_string()))
                                 .await
                             {
                                 return Err(anyhow!("Failed to send response: {}", e));
                             }
+                            continue 'listener;
                         }
+                        // Devices stay asleep until `WakeDevices` so that the rest of the VM state
+                        // can be captured consistently with them.
                         if let Err(e) = command_tube.send(SnapshotControlResult::Ok).await {
                             return Err(anyhow!("Failed to send response: {}", e));
                         }
@@ -399,12 +415,12 @@ async fn handle_command_tube(
                             Ok(file) => file,
                             Err(e) => {
                                 error!(
-                                    "failed to open {} for writing snapshot: {}",
+                                    "failed to open {} for reading snapshot: {}",
                                     path.as_path().display(),
                                     e
                                 );
                                 if let Err(e) = command_tube
-                                    .send(SnapshotControlResult::Failed(e.to_string()))
+                                    .send(RestoreControlResult::Failed(e.to_string()))
                                     .await
                                 {
                                     return Err(anyhow!("Failed to send response: {}", e));
@@ -417,6 +433,12 @@ async fn handle_command_tube(
                         let deserialized_list: Vec<SerializedDevice> = match res {
                             Err(e) => {
                                 error!("failed to deserialize devices list: {}", e);
+                                if let Err(e) = command_tube
+                                    .send(RestoreControlResult::Failed(e.to_string()))
+                                    .await
+                                {
+                                    return Err(anyhow!("Failed to send response: {}", e));
+                                }
                                 continue;
                             }
                             Ok(list) => list,
@@ -429,8 +451,11 @@ async fn handle_command_tube(
                         let buses = [&io_bus, &mmio_bus];
                         for bus in &buses {
                             if let Err(e) = sleep_devices(bus) {
+                                for bus in &buses {
+                                    wake_devices(bus);
+                                }
                                 if let Err(e) = command_tube
-                                    .send(SnapshotControlResult::Failed(e.to_string()))
+                                    .send(RestoreControlResult::Failed(e.to_string()))
                                     .await
                                 {
                                     return Err(anyhow!("Failed to send response: {}", e));
@@ -456,9 +481,6 @@ async fn handle_command_tube(
                                 continue 'listener;
                             }
                         }
-                        for bus in buses {
-                            wake_devices(bus);
-                        }
                         for (key, _) in devices_map.iter().filter(|(_, v)| !v.is_empty()) {
                             info!("Device with device_id: {} did was not restored due to an error or the device might be missing.", key);
                         }
@@ -466,6 +488,11 @@ async fn handle_command_tube(
                             return Err(anyhow!("Failed to send response: {}", e));
                         }
                     }
+                    DeviceControlCommand::WakeDevices => {
+                        for bus in [&io_bus, &mmio_bus] {
+                            wake_devices(bus);
+                        }
+                    }
                 };
             }
             Err(e) => {
@@ -47,6 +47,7 @@ pub use self::coiommu::CoIommuUnpinPolicy;
 pub use self::msi::MsiConfig;
 pub use self::msix::MsixCap;
 pub use self::msix::MsixConfig;
+pub use self::msix::MsixConfigSnapshot;
 pub use self::msix::MsixStatus;
 pub use self::pci_address::Error as PciAddressError;
 pub use self::pci_address::PciAddress;
@@ -58,6 +59,7 @@ pub use self::pci_configuration::PciCapability;
 pub use self::pci_configuration::PciCapabilityID;
 pub use self::pci_configuration::PciClassCode;
 pub use self::pci_configuration::PciConfiguration;
+pub use self::pci_configuration::PciConfigurationSnapshot;
 pub use self::pci_configuration::PciDisplaySubclass;
 pub use self::pci_configuration::PciHeaderType;
 pub use self::pci_configuration::PciProgrammingInterface;
@@ -131,6 +133,7 @@ pub enum CrosvmDeviceId {
     VmWatchdog = 17,
     Pflash = 18,
     VirtioMmio = 19,
+    FwCfg = 20,
 }
 
 impl TryFrom<u16> for CrosvmDeviceId {
@@ -157,6 +160,7 @@ impl TryFrom<u16> for CrosvmDeviceId {
             17 => Ok(CrosvmDeviceId::VmWatchdog),
             18 => Ok(CrosvmDeviceId::Pflash),
             19 => Ok(CrosvmDeviceId::VirtioMmio),
+            20 => Ok(CrosvmDeviceId::FwCfg),
             _ => Err(base::Error::new(EINVAL)),
         }
     }
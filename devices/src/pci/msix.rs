@@ -4,6 +4,8 @@
 
 use std::convert::TryInto;
 
+use anyhow::bail;
+use anyhow::Context;
 use base::error;
 use base::AsRawDescriptor;
 use base::Error as SysError;
@@ -14,6 +16,8 @@ use base::TubeError;
 use bit_field::*;
 use data_model::DataInit;
 use remain::sorted;
+use serde::Deserialize;
+use serde::Serialize;
 use thiserror::Error;
 use vm_control::VmIrqRequest;
 use vm_control::VmIrqResponse;
@@ -29,7 +33,7 @@ const FUNCTION_MASK_BIT: u16 = 0x4000;
 const MSIX_ENABLE_BIT: u16 = 0x8000;
 const MSIX_TABLE_ENTRY_MASK_BIT: u32 = 0x1;
 
-#[derive(Clone, Default)]
+#[derive(Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
 struct MsixTableEntry {
     msg_addr_lo: u32,
     msg_addr_hi: u32,
@@ -61,6 +65,15 @@ pub struct MsixConfig {
     device_name: String,
 }
 
+/// Serializable state of a `MsixConfig`, as captured by `MsixConfig::snapshot`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MsixConfigSnapshot {
+    table_entries: Vec<MsixTableEntry>,
+    pba_entries: Vec<u64>,
+    masked: bool,
+    enabled: bool,
+}
+
 #[sorted]
 #[derive(Error, Debug)]
 enum MsixError {
@@ -147,6 +160,41 @@ impl MsixConfig {
         self.enabled
     }
 
+    /// Saves the MSI-X table, the PBA and the Message Control bits programmed by the guest.
+    pub fn snapshot(&self) -> MsixConfigSnapshot {
+        MsixConfigSnapshot {
+            table_entries: self.table_entries.clone(),
+            pba_entries: self.pba_entries.clone(),
+            masked: self.masked,
+            enabled: self.enabled,
+        }
+    }
+
+    /// Loads the state saved by `snapshot` and, if MSI-X was enabled, sets up the routes of the
+    /// unmasked vectors again.
+    pub fn restore(&mut self, snapshot: &MsixConfigSnapshot) -> anyhow::Result<()> {
+        if snapshot.table_entries.len() != self.table_entries.len()
+            || snapshot.pba_entries.len() != self.pba_entries.len()
+        {
+            bail!(
+                "snapshot has {} MSI-X vectors but the device has {}",
+                snapshot.table_entries.len(),
+                self.table_entries.len()
+            );
+        }
+        self.destroy();
+        self.irq_vec
+            .resize_with(self.msix_num.into(), || None::<IrqfdGsi>);
+        self.table_entries = snapshot.table_entries.clone();
+        self.pba_entries = snapshot.pba_entries.clone();
+        self.masked = snapshot.masked;
+        self.enabled = snapshot.enabled;
+        if self.enabled {
+            self.msix_enable_all().context("failed to enable MSI-X")?;
+        }
+        Ok(())
+    }
+
     /// Read the MSI-X Capability Structure.
     /// The top 2 bits in Message Control word are emulated and all other
     /// bits are read only.
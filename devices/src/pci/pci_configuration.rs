@@ -251,6 +251,15 @@ pub struct PciConfiguration {
     last_capability: Option<(usize, usize)>,
 }
 
+/// Serializable state of a `PciConfiguration`, as captured by `PciConfiguration::snapshot`.
+///
+/// The layout of the configuration space (BARs, capabilities and writable bits) is set up by the
+/// device when it is created, so only the register values are saved.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PciConfigurationSnapshot {
+    registers: Vec<u32>,
+}
+
 /// See pci_regs.h in kernel
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PciBarRegionType {
@@ -322,6 +331,8 @@ pub enum Error {
     CapabilityLengthInvalid(usize),
     #[error("capability of size {0} doesn't fit")]
     CapabilitySpaceFull(usize),
+    #[error("snapshot has an invalid number of registers: {0}")]
+    SnapshotRegisterCount(usize),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -626,6 +637,24 @@ impl PciConfiguration {
             | u32::from(line);
     }
 
+    /// Saves the values of the registers, including the BAR addresses and the command register
+    /// programmed by the guest.
+    pub fn snapshot(&self) -> PciConfigurationSnapshot {
+        PciConfigurationSnapshot {
+            registers: self.registers.to_vec(),
+        }
+    }
+
+    /// Loads register values previously saved by `snapshot` into a configuration space with the
+    /// same layout.
+    pub fn restore(&mut self, snapshot: &PciConfigurationSnapshot) -> Result<()> {
+        if snapshot.registers.len() != NUM_CONFIGURATION_REGISTERS {
+            return Err(Error::SnapshotRegisterCount(snapshot.registers.len()));
+        }
+        self.registers.copy_from_slice(&snapshot.registers);
+        Ok(())
+    }
+
     /// Adds the capability `cap_data` to the list of capabilities.
     /// `cap_data` should include the two-byte PCI capability header (type, next),
     /// but not populate it. Correct values will be generated automatically based
@@ -1279,4 +1308,51 @@ mod tests {
         assert_eq!(cfg.read_reg(ROM_BAR_REG), 0x12345000);
         assert_eq!(cfg.writable_bits[ROM_BAR_REG], 0xFFFFF801);
     }
+
+    #[test]
+    fn snapshot_restore() {
+        let new_cfg = || {
+            let mut cfg = PciConfiguration::new(
+                0x1234,
+                0x5678,
+                PciClassCode::MultimediaController,
+                &PciMultimediaSubclass::AudioController,
+                None,
+                PciHeaderType::Device,
+                0xABCD,
+                0x2468,
+                0,
+            );
+            cfg.add_pci_bar(
+                PciBarConfiguration::new(
+                    0,
+                    0x1000,
+                    PciBarRegionType::Memory32BitRegion,
+                    PciBarPrefetchable::NotPrefetchable,
+                )
+                .set_address(0x10000),
+            )
+            .expect("add_pci_bar failed");
+            cfg
+        };
+
+        let mut cfg = new_cfg();
+        cfg.write_reg(COMMAND_REG, 0, &0x6u16.to_le_bytes());
+        cfg.write_reg(BAR0_REG, 0, &0x20000u32.to_le_bytes());
+        let snapshot = cfg.snapshot();
+
+        let mut restored = new_cfg();
+        restored.restore(&snapshot).expect("restore failed");
+        assert_eq!(restored.read_reg(COMMAND_REG) & 0xffff, 0x6);
+        assert_eq!(restored.get_bar_addr(0), 0x20000);
+        assert_eq!(restored.snapshot(), snapshot);
+
+        let truncated = PciConfigurationSnapshot {
+            registers: vec![0; 4],
+        };
+        assert_eq!(
+            restored.restore(&truncated),
+            Err(Error::SnapshotRegisterCount(4))
+        );
+    }
 }
@@ -183,6 +183,10 @@ impl ProxyDevice {
         let (child_tube, parent_tube) = Tube::pair().map_err(Error::Tube)?;
 
         keep_rds.push(child_tube.as_raw_descriptor());
+        let trace_process = cros_tracing::ForkedProcess::new(&debug_label);
+        if let Some(trace_process) = &trace_process {
+            trace_process.push_descriptors(&mut keep_rds);
+        }
 
         // Deduplicate the FDs since minijail expects this.
         keep_rds.sort_unstable();
@@ -205,6 +209,10 @@ impl ProxyDevice {
                 // Preserve TZ for `chrono::Local` (b/257987535).
                 std::env::set_var("TZ", tz);
 
+                if let Some(trace_process) = trace_process {
+                    trace_process.child();
+                }
+
                 device.on_sandboxed();
                 child_proc(child_tube, &mut device);
 
@@ -225,6 +233,10 @@ impl ProxyDevice {
             p => p,
         };
 
+        if let Some(trace_process) = trace_process {
+            trace_process.parent(pid);
+        }
+
         parent_tube
             .set_send_timeout(Some(Duration::from_millis(SOCKET_TIMEOUT_MS)))
             .map_err(Error::Tube)?;
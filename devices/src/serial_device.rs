@@ -151,6 +151,7 @@ pub struct SerialParameters {
     pub out_timestamp: bool,
     #[serde(default = "serial_parameters_default_debugcon_port")]
     pub debugcon_port: u16,
+    pub name: Option<String>,
 }
 
 impl SerialParameters {
@@ -260,6 +261,7 @@ mod tests {
                 stdin: false,
                 out_timestamp: false,
                 debugcon_port: 0x402,
+                name: None,
             }
         );
 
@@ -347,8 +349,12 @@ mod tests {
         let params = from_serial_arg("debugcon_port=1026").unwrap();
         assert_eq!(params.debugcon_port, 1026);
 
+        // name parameter
+        let params = from_serial_arg("name=org.qemu.guest_agent.0").unwrap();
+        assert_eq!(params.name, Some("org.qemu.guest_agent.0".to_string()));
+
         // all together
-        let params = from_serial_arg("type=stdout,path=/some/path,hardware=virtio-console,num=5,earlycon,console,stdin,input=/some/input,out_timestamp,debugcon_port=12").unwrap();
+        let params = from_serial_arg("type=stdout,path=/some/path,hardware=virtio-console,num=5,earlycon,console,stdin,input=/some/input,out_timestamp,debugcon_port=12,name=port").unwrap();
         assert_eq!(
             params,
             SerialParameters {
@@ -362,6 +368,7 @@ mod tests {
                 stdin: true,
                 out_timestamp: true,
                 debugcon_port: 12,
+                name: Some("port".to_string()),
             }
         );
 
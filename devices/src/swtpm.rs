@@ -0,0 +1,617 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! swtpm backend, sending the TPM commands to an swtpm process that keeps the state of the TPM.
+//!
+//! crosvm connects to the control channel of `swtpm socket --ctrl type=unixio,path=PATH`, and hands
+//! swtpm one end of a socket pair to use as its data channel. The control messages are described in
+//! `tpm_ioctl.h` of swtpm; all their fields are big endian.
+
+use std::cell::RefCell;
+use std::io;
+use std::io::IoSlice;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use anyhow::bail;
+use anyhow::Context;
+use base::error;
+use base::AsRawDescriptor;
+use base::RawDescriptor;
+use base::ScmSocket;
+use remain::sorted;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+
+use super::virtio::TpmBackend;
+
+// Control commands.
+const CMD_GET_CAPABILITY: u32 = 1;
+const CMD_INIT: u32 = 2;
+const CMD_SHUTDOWN: u32 = 3;
+const CMD_GET_STATEBLOB: u32 = 12;
+const CMD_SET_STATEBLOB: u32 = 13;
+const CMD_STOP: u32 = 14;
+const CMD_SET_DATAFD: u32 = 16;
+const CMD_GET_INFO: u32 = 18;
+
+// Capabilities returned by `CMD_GET_CAPABILITY`, one per command.
+const PTM_CAP_INIT: u64 = 1 << 0;
+const PTM_CAP_SHUTDOWN: u64 = 1 << 1;
+const PTM_CAP_GET_STATEBLOB: u64 = 1 << 8;
+const PTM_CAP_SET_STATEBLOB: u64 = 1 << 9;
+const PTM_CAP_STOP: u64 = 1 << 10;
+const PTM_CAP_SET_DATAFD: u64 = 1 << 12;
+const PTM_CAP_GET_INFO: u64 = 1 << 14;
+
+const REQUIRED_CAPS: u64 = PTM_CAP_INIT | PTM_CAP_SHUTDOWN | PTM_CAP_SET_DATAFD;
+const SNAPSHOT_CAPS: u64 = PTM_CAP_STOP | PTM_CAP_GET_STATEBLOB | PTM_CAP_SET_STATEBLOB;
+
+// Flag of `CMD_INIT` deleting the volatile state once it has been loaded.
+const PTM_INIT_FLAG_DELETE_VOLATILE: u32 = 1 << 0;
+
+// Types of the state blobs.
+const PTM_BLOB_TYPE_PERMANENT: u32 = 1;
+const PTM_BLOB_TYPE_VOLATILE: u32 = 2;
+const PTM_BLOB_TYPE_SAVESTATE: u32 = 3;
+
+// Flag of `CMD_GET_INFO` returning the TPM specification the TPM implements.
+const SWTPM_INFO_TPMSPECIFICATION: u64 = 1 << 0;
+
+// Responses start with a 2-byte tag and the 4-byte size of the whole response.
+const TPM_RESPONSE_HEADER_SIZE: usize = 6;
+// Responses larger than the buffer of the virtio-tpm device are rejected by the device anyway.
+const TPM_MAX_RESPONSE_SIZE: usize = 4096;
+
+// The response of TPM_RC_FAILURE
+const TPM2_FAILURE_RESPONSE: &[u8] = &[
+    0x80, 0x01, // TPM_ST_NO_SESSIONS
+    0x00, 0x00, 0x00, 0x0A, // Header Size = 10
+    0x00, 0x00, 0x01, 0x01, // TPM_RC_FAILURE
+];
+
+// The response of TPM_FAIL
+const TPM12_FAILURE_RESPONSE: &[u8] = &[
+    0x00, 0xC4, // TPM_TAG_RSP_COMMAND
+    0x00, 0x00, 0x00, 0x0A, // Header Size = 10
+    0x00, 0x00, 0x00, 0x09, // TPM_FAIL
+];
+
+/// Version of the TPM emulated by swtpm, which is selected by its `--tpm2` option.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TpmVersion {
+    /// TPM 1.2
+    Tpm12,
+    /// TPM 2.0
+    Tpm2,
+}
+
+impl Default for TpmVersion {
+    fn default() -> Self {
+        TpmVersion::Tpm2
+    }
+}
+
+impl TpmVersion {
+    /// Returns the family of the TPM specification, as reported by swtpm.
+    fn family(self) -> &'static str {
+        match self {
+            TpmVersion::Tpm12 => "1.2",
+            TpmVersion::Tpm2 => "2.0",
+        }
+    }
+
+    fn failure_response(self) -> &'static [u8] {
+        match self {
+            TpmVersion::Tpm12 => TPM12_FAILURE_RESPONSE,
+            TpmVersion::Tpm2 => TPM2_FAILURE_RESPONSE,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StateBlob {
+    // Whether the blob is encrypted with the key of swtpm.
+    flags: u32,
+    data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SwtpmSnapshot {
+    permanent: StateBlob,
+    volatile: StateBlob,
+    savestate: StateBlob,
+}
+
+/// Backend sending the TPM commands to swtpm.
+pub struct Swtpm {
+    ctrl: UnixStream,
+    // Data channel, set up when the TPM is started. The TPM is started by the process running the
+    // device rather than by `new`, so that the copy dropped by the parent of a sandboxed device
+    // does not shut it down. Snapshots, which only borrow the backend, may start it too.
+    data: RefCell<Option<UnixStream>>,
+    version: TpmVersion,
+    caps: u64,
+    response: Vec<u8>,
+}
+
+impl Swtpm {
+    /// Connects to the control channel of swtpm at `ctrl_path`, and checks that swtpm emulates a
+    /// TPM of `version`.
+    pub fn new<P: AsRef<Path>>(ctrl_path: P, version: TpmVersion) -> anyhow::Result<Self> {
+        let ctrl_path = ctrl_path.as_ref();
+        let ctrl = UnixStream::connect(ctrl_path).with_context(|| {
+            format!(
+                "failed to connect to the swtpm control channel at {}",
+                ctrl_path.display()
+            )
+        })?;
+        let mut swtpm = Swtpm {
+            ctrl,
+            data: RefCell::new(None),
+            version,
+            caps: 0,
+            response: Vec::new(),
+        };
+
+        swtpm.caps = swtpm
+            .get_capability()
+            .context("failed to get the capabilities of swtpm")?;
+        let missing_caps = REQUIRED_CAPS & !swtpm.caps;
+        if missing_caps != 0 {
+            bail!("swtpm lacks required capabilities {:#x}", missing_caps);
+        }
+
+        // Versions of swtpm without `CMD_GET_INFO` are trusted to emulate the right TPM.
+        if swtpm.caps & PTM_CAP_GET_INFO != 0 {
+            let family = swtpm.tpm_family()?;
+            if family != version.family() {
+                bail!(
+                    "swtpm emulates a TPM {} instead of a TPM {}",
+                    family,
+                    version.family()
+                );
+            }
+        }
+
+        Ok(swtpm)
+    }
+
+    fn send_ctrl(&self, cmd: u32, payload: &[u8]) -> Result<()> {
+        // swtpm reads a control message with a single read, so it is sent in one piece.
+        let mut msg = cmd.to_be_bytes().to_vec();
+        msg.extend_from_slice(payload);
+        (&self.ctrl).write_all(&msg).map_err(Error::Io)
+    }
+
+    fn read_ctrl(&self, buf: &mut [u8]) -> Result<()> {
+        (&self.ctrl).read_exact(buf).map_err(Error::Io)
+    }
+
+    fn read_u32(&self) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        self.read_ctrl(&mut buf)?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    /// Reads the result at the start of the response to `cmd`.
+    fn read_result(&self, cmd: u32) -> Result<()> {
+        match self.read_u32()? {
+            0 => Ok(()),
+            result => Err(Error::Command { cmd, result }),
+        }
+    }
+
+    fn get_capability(&self) -> Result<u64> {
+        self.send_ctrl(CMD_GET_CAPABILITY, &[])?;
+        let mut buf = [0u8; 8];
+        self.read_ctrl(&mut buf)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    /// Returns the family of the TPM specification implemented by swtpm, such as "2.0".
+    fn tpm_family(&self) -> anyhow::Result<String> {
+        let mut payload = SWTPM_INFO_TPMSPECIFICATION.to_be_bytes().to_vec();
+        // Offset in the returned string, and padding.
+        payload.extend_from_slice(&[0u8; 8]);
+        self.send_ctrl(CMD_GET_INFO, &payload)?;
+        self.read_result(CMD_GET_INFO)?;
+        let _total_length = self.read_u32()?;
+        let mut info = vec![0u8; self.read_u32()? as usize];
+        self.read_ctrl(&mut info)?;
+
+        let info: serde_json::Value =
+            serde_json::from_slice(&info).context("failed to parse the swtpm info")?;
+        info["TPMSpecification"]["family"]
+            .as_str()
+            .map(str::to_owned)
+            .context("swtpm info has no TPM family")
+    }
+
+    fn init(&self, flags: u32) -> Result<()> {
+        self.send_ctrl(CMD_INIT, &flags.to_be_bytes())?;
+        self.read_result(CMD_INIT)
+    }
+
+    fn stop(&self) -> Result<()> {
+        self.send_ctrl(CMD_STOP, &[])?;
+        self.read_result(CMD_STOP)
+    }
+
+    fn shutdown(&self) -> Result<()> {
+        self.send_ctrl(CMD_SHUTDOWN, &[])?;
+        self.read_result(CMD_SHUTDOWN)
+    }
+
+    /// Hands the data channel to swtpm and starts the TPM, unless it was already started.
+    fn start(&self) -> Result<()> {
+        if self.data.borrow().is_some() {
+            return Ok(());
+        }
+
+        let (data, swtpm_data) = UnixStream::pair().map_err(Error::Io)?;
+        self.ctrl
+            .send_with_fd(
+                &[IoSlice::new(&CMD_SET_DATAFD.to_be_bytes())],
+                swtpm_data.as_raw_descriptor(),
+            )
+            .map_err(|e| Error::Io(e.into()))?;
+        self.read_result(CMD_SET_DATAFD)?;
+        self.init(0)?;
+
+        *self.data.borrow_mut() = Some(data);
+        Ok(())
+    }
+
+    fn get_state_blob(&self, blob_type: u32) -> Result<StateBlob> {
+        let mut payload = Vec::with_capacity(12);
+        // No flags, so that swtpm does not decrypt the blob, and offset 0.
+        payload.extend_from_slice(&0u32.to_be_bytes());
+        payload.extend_from_slice(&blob_type.to_be_bytes());
+        payload.extend_from_slice(&0u32.to_be_bytes());
+        self.send_ctrl(CMD_GET_STATEBLOB, &payload)?;
+        self.read_result(CMD_GET_STATEBLOB)?;
+        let flags = self.read_u32()?;
+        // On a socket, the whole blob follows the header.
+        let _total_length = self.read_u32()?;
+        let mut data = vec![0u8; self.read_u32()? as usize];
+        self.read_ctrl(&mut data)?;
+        Ok(StateBlob { flags, data })
+    }
+
+    fn set_state_blob(&self, blob_type: u32, blob: &StateBlob) -> Result<()> {
+        let mut payload = Vec::with_capacity(12 + blob.data.len());
+        payload.extend_from_slice(&blob.flags.to_be_bytes());
+        payload.extend_from_slice(&blob_type.to_be_bytes());
+        payload.extend_from_slice(&(blob.data.len() as u32).to_be_bytes());
+        payload.extend_from_slice(&blob.data);
+        self.send_ctrl(CMD_SET_STATEBLOB, &payload)?;
+        self.read_result(CMD_SET_STATEBLOB)
+    }
+
+    /// Loads the state blobs of `snapshot` into the stopped TPM and starts it again.
+    fn set_state(&self, snapshot: &SwtpmSnapshot) -> Result<()> {
+        for (blob_type, blob) in [
+            (PTM_BLOB_TYPE_PERMANENT, &snapshot.permanent),
+            (PTM_BLOB_TYPE_VOLATILE, &snapshot.volatile),
+            (PTM_BLOB_TYPE_SAVESTATE, &snapshot.savestate),
+        ] {
+            if !blob.data.is_empty() {
+                self.set_state_blob(blob_type, blob)?;
+            }
+        }
+        // The volatile state was only stored to be loaded by this initialization.
+        self.init(PTM_INIT_FLAG_DELETE_VOLATILE)
+    }
+
+    fn try_execute_command(&mut self, command: &[u8]) -> Result<()> {
+        self.start()?;
+        let data = self.data.get_mut().as_mut().expect("swtpm was started");
+        data.write_all(command).map_err(Error::Io)?;
+
+        let mut header = [0u8; TPM_RESPONSE_HEADER_SIZE];
+        data.read_exact(&mut header).map_err(Error::Io)?;
+        let size = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize;
+        if !(TPM_RESPONSE_HEADER_SIZE..=TPM_MAX_RESPONSE_SIZE).contains(&size) {
+            return Err(Error::ResponseSize(size));
+        }
+
+        self.response.clear();
+        self.response.extend_from_slice(&header);
+        self.response.resize(size, 0);
+        data.read_exact(&mut self.response[TPM_RESPONSE_HEADER_SIZE..])
+            .map_err(Error::Io)
+    }
+}
+
+impl TpmBackend for Swtpm {
+    fn execute_command<'a>(&'a mut self, command: &[u8]) -> &'a [u8] {
+        match self.try_execute_command(command) {
+            Ok(()) => &self.response,
+            Err(e) => {
+                error!("swtpm failed to execute a command: {}", e);
+                self.version.failure_response()
+            }
+        }
+    }
+
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut rds = vec![self.ctrl.as_raw_descriptor()];
+        if let Some(data) = &*self.data.borrow() {
+            rds.push(data.as_raw_descriptor());
+        }
+        rds
+    }
+
+    fn snapshot(&self) -> anyhow::Result<String> {
+        if self.caps & SNAPSHOT_CAPS != SNAPSHOT_CAPS {
+            bail!("swtpm does not support saving its state");
+        }
+
+        // The guest may not have sent any command yet, and the state is saved from a started TPM.
+        self.start().context("failed to start the TPM")?;
+        self.stop().context("failed to stop the TPM")?;
+        let snapshot = SwtpmSnapshot {
+            permanent: self.get_state_blob(PTM_BLOB_TYPE_PERMANENT)?,
+            volatile: self.get_state_blob(PTM_BLOB_TYPE_VOLATILE)?,
+            savestate: self.get_state_blob(PTM_BLOB_TYPE_SAVESTATE)?,
+        };
+        // Stopping the TPM dropped its volatile state, which is loaded back from the snapshot.
+        self.set_state(&snapshot)
+            .context("failed to restart the TPM")?;
+        serde_json::to_string(&snapshot).context("failed to serialize the swtpm snapshot")
+    }
+
+    fn restore(&mut self, data: &str) -> anyhow::Result<()> {
+        if self.caps & SNAPSHOT_CAPS != SNAPSHOT_CAPS {
+            bail!("swtpm does not support restoring its state");
+        }
+        let snapshot: SwtpmSnapshot =
+            serde_json::from_str(data).context("failed to deserialize the swtpm snapshot")?;
+
+        self.start().context("failed to start the TPM")?;
+        self.stop().context("failed to stop the TPM")?;
+        self.set_state(&snapshot)
+            .context("failed to load the state of the TPM")?;
+        Ok(())
+    }
+}
+
+impl Drop for Swtpm {
+    fn drop(&mut self) {
+        // Lets swtpm store its state and exit.
+        if self.data.get_mut().is_some() {
+            if let Err(e) = self.shutdown() {
+                error!("failed to shut down swtpm: {}", e);
+            }
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[sorted]
+#[derive(Error, Debug)]
+enum Error {
+    #[error("swtpm control command {cmd} failed with {result:#x}")]
+    Command { cmd: u32, result: u32 },
+    #[error("swtpm socket I/O failed: {0}")]
+    Io(io::Error),
+    #[error("swtpm response has an invalid size of {0} bytes")]
+    ResponseSize(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::thread;
+
+    use super::*;
+
+    const TPM2_COMMAND: [u8; 10] = [0x80, 0x01, 0x00, 0x00, 0x00, 0x0A, 0x00, 0x00, 0x01, 0x7B];
+
+    // Answers the control messages like swtpm emulating a TPM 2.0 whose state blobs start as
+    // `blobs`, and echoes the commands sent on the data channel. Returns the control commands
+    // received and the state blobs once the control channel is closed.
+    fn fake_swtpm(
+        ctrl: UnixStream,
+        mut blobs: BTreeMap<u32, (u32, Vec<u8>)>,
+    ) -> (Vec<u32>, BTreeMap<u32, (u32, Vec<u8>)>) {
+        let read_u32 = || {
+            let mut buf = [0u8; 4];
+            (&ctrl).read_exact(&mut buf).unwrap();
+            u32::from_be_bytes(buf)
+        };
+        let write_u32s = |values: &[u32]| {
+            for value in values {
+                (&ctrl).write_all(&value.to_be_bytes()).unwrap();
+            }
+        };
+
+        let mut cmds = Vec::new();
+        loop {
+            let mut cmd = [0u8; 4];
+            let (len, fd) = ctrl.recv_with_fd(io::IoSliceMut::new(&mut cmd)).unwrap();
+            if len == 0 {
+                return (cmds, blobs);
+            }
+            let cmd = u32::from_be_bytes(cmd);
+            cmds.push(cmd);
+            match cmd {
+                CMD_GET_CAPABILITY => {
+                    let caps = REQUIRED_CAPS | SNAPSHOT_CAPS | PTM_CAP_GET_INFO;
+                    (&ctrl).write_all(&caps.to_be_bytes()).unwrap();
+                }
+                CMD_GET_INFO => {
+                    let mut payload = [0u8; 16];
+                    (&ctrl).read_exact(&mut payload).unwrap();
+                    let info = br#"{"TPMSpecification":{"family":"2.0","level":0,"revision":164}}"#;
+                    write_u32s(&[0, info.len() as u32, info.len() as u32]);
+                    (&ctrl).write_all(info).unwrap();
+                }
+                CMD_SET_DATAFD => {
+                    let data = UnixStream::from(base::SafeDescriptor::from(fd.unwrap()));
+                    thread::spawn(move || {
+                        let mut buf = [0u8; TPM_MAX_RESPONSE_SIZE];
+                        loop {
+                            match (&data).read(&mut buf) {
+                                Ok(0) | Err(_) => break,
+                                Ok(len) => (&data).write_all(&buf[..len]).unwrap(),
+                            }
+                        }
+                    });
+                    write_u32s(&[0]);
+                }
+                CMD_INIT => {
+                    if read_u32() & PTM_INIT_FLAG_DELETE_VOLATILE != 0 {
+                        blobs.remove(&PTM_BLOB_TYPE_VOLATILE);
+                    }
+                    write_u32s(&[0]);
+                }
+                CMD_SHUTDOWN | CMD_STOP => write_u32s(&[0]),
+                CMD_GET_STATEBLOB => {
+                    assert_eq!(read_u32(), 0, "unexpected state blob flags");
+                    let blob_type = read_u32();
+                    assert_eq!(read_u32(), 0, "unexpected state blob offset");
+                    let (flags, data) = blobs.get(&blob_type).cloned().unwrap_or_default();
+                    write_u32s(&[0, flags, data.len() as u32, data.len() as u32]);
+                    (&ctrl).write_all(&data).unwrap();
+                }
+                CMD_SET_STATEBLOB => {
+                    let flags = read_u32();
+                    let blob_type = read_u32();
+                    let mut data = vec![0u8; read_u32() as usize];
+                    (&ctrl).read_exact(&mut data).unwrap();
+                    blobs.insert(blob_type, (flags, data));
+                    write_u32s(&[0]);
+                }
+                cmd => panic!("unexpected swtpm control command {}", cmd),
+            }
+        }
+    }
+
+    fn connect_fake_swtpm(
+        blobs: BTreeMap<u32, (u32, Vec<u8>)>,
+    ) -> (
+        Swtpm,
+        thread::JoinHandle<(Vec<u32>, BTreeMap<u32, (u32, Vec<u8>)>)>,
+    ) {
+        let (ctrl, swtpm_ctrl) = UnixStream::pair().unwrap();
+        let swtpm_thread = thread::spawn(move || fake_swtpm(swtpm_ctrl, blobs));
+
+        let mut swtpm = Swtpm {
+            ctrl,
+            data: RefCell::new(None),
+            version: TpmVersion::Tpm2,
+            caps: 0,
+            response: Vec::new(),
+        };
+        swtpm.caps = swtpm.get_capability().unwrap();
+        assert_eq!(swtpm.tpm_family().unwrap(), "2.0");
+        (swtpm, swtpm_thread)
+    }
+
+    #[test]
+    fn execute_command() {
+        let (mut swtpm, swtpm_thread) = connect_fake_swtpm(BTreeMap::new());
+
+        assert_eq!(swtpm.execute_command(&TPM2_COMMAND), &TPM2_COMMAND);
+
+        drop(swtpm);
+        let (cmds, _) = swtpm_thread.join().unwrap();
+        assert_eq!(
+            cmds,
+            [
+                CMD_GET_CAPABILITY,
+                CMD_GET_INFO,
+                CMD_SET_DATAFD,
+                CMD_INIT,
+                CMD_SHUTDOWN
+            ]
+        );
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let blobs = BTreeMap::from([
+            (PTM_BLOB_TYPE_PERMANENT, (1, b"permanent".to_vec())),
+            (PTM_BLOB_TYPE_VOLATILE, (1, b"volatile".to_vec())),
+        ]);
+        let (swtpm, swtpm_thread) = connect_fake_swtpm(blobs);
+
+        // The guest did not send any command, so the TPM is started to save its state.
+        let data = swtpm.snapshot().unwrap();
+        let snapshot: SwtpmSnapshot = serde_json::from_str(&data).unwrap();
+        assert_eq!(snapshot.permanent.flags, 1);
+        assert_eq!(snapshot.permanent.data, b"permanent");
+        assert_eq!(snapshot.volatile.data, b"volatile");
+        assert!(snapshot.savestate.data.is_empty());
+
+        drop(swtpm);
+        let (cmds, _) = swtpm_thread.join().unwrap();
+        assert_eq!(
+            cmds,
+            [
+                CMD_GET_CAPABILITY,
+                CMD_GET_INFO,
+                CMD_SET_DATAFD,
+                CMD_INIT,
+                CMD_STOP,
+                CMD_GET_STATEBLOB,
+                CMD_GET_STATEBLOB,
+                CMD_GET_STATEBLOB,
+                CMD_SET_STATEBLOB,
+                CMD_SET_STATEBLOB,
+                CMD_INIT,
+                CMD_SHUTDOWN
+            ]
+        );
+
+        let (mut swtpm, swtpm_thread) = connect_fake_swtpm(BTreeMap::new());
+        swtpm.restore(&data).unwrap();
+        assert_eq!(swtpm.execute_command(&TPM2_COMMAND), &TPM2_COMMAND);
+
+        drop(swtpm);
+        let (cmds, restored_blobs) = swtpm_thread.join().unwrap();
+        assert_eq!(
+            cmds,
+            [
+                CMD_GET_CAPABILITY,
+                CMD_GET_INFO,
+                CMD_SET_DATAFD,
+                CMD_INIT,
+                CMD_STOP,
+                CMD_SET_STATEBLOB,
+                CMD_SET_STATEBLOB,
+                CMD_INIT,
+                CMD_SHUTDOWN
+            ]
+        );
+        // The volatile state is only loaded once, by the initialization that follows it.
+        assert_eq!(
+            restored_blobs,
+            BTreeMap::from([(PTM_BLOB_TYPE_PERMANENT, (1, b"permanent".to_vec()))])
+        );
+    }
+
+    #[test]
+    fn failure_without_swtpm() {
+        let (ctrl, swtpm_ctrl) = UnixStream::pair().unwrap();
+        drop(swtpm_ctrl);
+
+        let mut swtpm = Swtpm {
+            ctrl,
+            data: RefCell::new(None),
+            version: TpmVersion::Tpm12,
+            caps: 0,
+            response: Vec::new(),
+        };
+        let command = [0x00, 0xC1, 0x00, 0x00, 0x00, 0x0A, 0x00, 0x00, 0x00, 0x46];
+        assert_eq!(swtpm.execute_command(&command), TPM12_FAILURE_RESPONSE);
+    }
+}
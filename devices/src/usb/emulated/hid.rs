@@ -0,0 +1,623 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! USB HID keyboard and tablet, driven by the input events of a socket.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::sync::Arc;
+
+use anyhow::Context;
+use base::error;
+use base::warn;
+use base::AsRawDescriptor;
+use base::RawDescriptor;
+use data_model::DataInit;
+use linux_input_sys::virtio_input_event;
+use linux_input_sys::ABS_X;
+use linux_input_sys::ABS_Y;
+use linux_input_sys::BTN_TOUCH;
+use linux_input_sys::EV_ABS;
+use linux_input_sys::EV_KEY;
+use linux_input_sys::EV_REL;
+use linux_input_sys::EV_SYN;
+use linux_input_sys::SYN_REPORT;
+use sync::Mutex;
+use usb_util::ControlRequestDataPhaseTransferDirection;
+use usb_util::ControlRequestRecipient;
+use usb_util::ControlRequestType;
+use usb_util::DescriptorType;
+use usb_util::DeviceDescriptor;
+use usb_util::EndpointDescriptor;
+use usb_util::InterfaceDescriptor;
+use usb_util::StandardControlRequest;
+use usb_util::TransferStatus;
+use usb_util::UsbRequestSetup;
+use vm_control::UsbHidDevice;
+
+use super::usb_device::complete_in_transfer;
+use super::usb_device::push_descriptor;
+use super::usb_device::transfer_buffer;
+use super::usb_device::PendingTransfers;
+use super::usb_device::UsbFunction;
+use super::usb_device::STRING_MANUFACTURER;
+use super::usb_device::STRING_PRODUCT;
+use super::usb_device::STRING_SERIAL_NUMBER;
+use super::usb_device::VENDOR_ID;
+use crate::usb::host_backend::error::Error;
+use crate::usb::host_backend::error::Result;
+use crate::usb::xhci::xhci_transfer::TransferDirection;
+use crate::usb::xhci::xhci_transfer::XhciTransfer;
+use crate::utils::AsyncJobQueue;
+use crate::utils::EventHandler;
+use crate::utils::FailHandle;
+use crate::virtio::EventSource;
+use crate::virtio::SocketEventSource;
+
+const PRODUCT_ID_KEYBOARD: u16 = 0x0101;
+const PRODUCT_ID_TABLET: u16 = 0x0102;
+
+const INTERFACE_CLASS_HID: u8 = 0x03;
+const INTERFACE_SUBCLASS_NONE: u8 = 0x00;
+const INTERFACE_SUBCLASS_BOOT: u8 = 0x01;
+const INTERFACE_PROTOCOL_NONE: u8 = 0x00;
+const INTERFACE_PROTOCOL_KEYBOARD: u8 = 0x01;
+
+const INTERRUPT_IN_ENDPOINT: u8 = 0x81;
+const INTERRUPT_MAX_PACKET_SIZE: u16 = 8;
+const ENDPOINT_ATTRIBUTES_INTERRUPT: u8 = 0x03;
+// Polling interval of the interrupt endpoint, in frames of 1 ms.
+const INTERRUPT_INTERVAL: u8 = 10;
+
+const DESCRIPTOR_TYPE_HID: u8 = 0x21;
+const DESCRIPTOR_TYPE_REPORT: u8 = 0x22;
+// Version 1.11 of the HID specification.
+const HID_VERSION: u16 = 0x0111;
+
+// Class-specific requests.
+const GET_REPORT: u8 = 0x01;
+const GET_IDLE: u8 = 0x02;
+const GET_PROTOCOL: u8 = 0x03;
+const SET_REPORT: u8 = 0x09;
+const SET_IDLE: u8 = 0x0a;
+const SET_PROTOCOL: u8 = 0x0b;
+
+// Reports waiting for the guest to poll the device. Older reports are dropped when the guest
+// falls behind.
+const MAX_QUEUED_REPORTS: usize = 32;
+
+// Button codes of the input events that are not exported by `linux_input_sys`.
+const BTN_LEFT: u16 = 0x110;
+const BTN_RIGHT: u16 = 0x111;
+const BTN_MIDDLE: u16 = 0x112;
+const REL_WHEEL: u16 = 0x08;
+
+// Largest coordinate reported by the tablet.
+const TABLET_MAX_COORDINATE: u64 = 0x7fff;
+
+// Report descriptor of a keyboard using the boot protocol, from appendix B.1 of the HID
+// specification.
+const KEYBOARD_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xa1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0xe0, //   Usage Minimum (224)
+    0x29, 0xe7, //   Usage Maximum (231)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x01, //   Input (Constant)
+    0x95, 0x05, //   Report Count (5)
+    0x75, 0x01, //   Report Size (1)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (1)
+    0x29, 0x05, //   Usage Maximum (5)
+    0x91, 0x02, //   Output (Data, Variable, Absolute)
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x03, //   Report Size (3)
+    0x91, 0x01, //   Output (Constant)
+    0x95, 0x06, //   Report Count (6)
+    0x75, 0x08, //   Report Size (8)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x65, //   Logical Maximum (101)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0x65, //   Usage Maximum (101)
+    0x81, 0x00, //   Input (Data, Array)
+    0xc0, //       End Collection
+];
+
+// Report descriptor of a tablet with three buttons, absolute coordinates and a wheel.
+const TABLET_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x02, // Usage (Mouse)
+    0xa1, 0x01, // Collection (Application)
+    0x09, 0x01, //   Usage (Pointer)
+    0xa1, 0x00, //   Collection (Physical)
+    0x05, 0x09, //     Usage Page (Button)
+    0x19, 0x01, //     Usage Minimum (1)
+    0x29, 0x03, //     Usage Maximum (3)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x95, 0x03, //     Report Count (3)
+    0x75, 0x01, //     Report Size (1)
+    0x81, 0x02, //     Input (Data, Variable, Absolute)
+    0x95, 0x01, //     Report Count (1)
+    0x75, 0x05, //     Report Size (5)
+    0x81, 0x01, //     Input (Constant)
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x26, 0xff, 0x7f, // Logical Maximum (32767)
+    0x35, 0x00, //     Physical Minimum (0)
+    0x46, 0xff, 0x7f, // Physical Maximum (32767)
+    0x75, 0x10, //     Report Size (16)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x02, //     Input (Data, Variable, Absolute)
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x38, //     Usage (Wheel)
+    0x15, 0x81, //     Logical Minimum (-127)
+    0x25, 0x7f, //     Logical Maximum (127)
+    0x35, 0x00, //     Physical Minimum (0)
+    0x45, 0x00, //     Physical Maximum (0)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x01, //     Report Count (1)
+    0x81, 0x06, //     Input (Data, Variable, Relative)
+    0xc0, //         End Collection
+    0xc0, //       End Collection
+];
+
+// Input event codes of the modifier keys, in the order of their bits in the keyboard report:
+// left control, shift, alt and meta, then the same keys on the right.
+const MODIFIER_KEYS: [u16; 8] = [29, 42, 56, 125, 97, 54, 100, 126];
+
+// First usage of `KEY_USAGES`.
+const FIRST_KEY_USAGE: u8 = 0x04;
+// Input event codes of the keyboard usages starting at `FIRST_KEY_USAGE`, as mapped by the Linux
+// HID driver.
+const KEY_USAGES: [u16; 98] = [
+    30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38, // 0x04
+    50, 49, 24, 25, 16, 19, 31, 20, 22, 47, 17, 45, 21, 44, 2, 3, // 0x10
+    4, 5, 6, 7, 8, 9, 10, 11, 28, 1, 14, 15, 57, 12, 13, 26, // 0x20
+    27, 43, 43, 39, 40, 41, 51, 52, 53, 58, 59, 60, 61, 62, 63, 64, // 0x30
+    65, 66, 67, 68, 87, 88, 99, 70, 119, 110, 102, 104, 111, 107, 109, 106, // 0x40
+    105, 108, 103, 69, 98, 55, 74, 78, 96, 79, 80, 81, 75, 76, 77, 71, // 0x50
+    72, 73, 82, 83, 86, 127, // 0x60
+];
+
+// Number of keys, besides the modifiers, reported at once by the keyboard.
+const MAX_PRESSED_KEYS: usize = 6;
+// Usage reported in all key slots when too many keys are pressed.
+const KEY_ERROR_ROLL_OVER: u8 = 0x01;
+
+fn key_usage(code: u16) -> Option<u8> {
+    KEY_USAGES
+        .iter()
+        .position(|&c| c == code)
+        .map(|i| FIRST_KEY_USAGE + i as u8)
+}
+
+struct HidState {
+    device: UsbHidDevice,
+    // Keyboard state.
+    modifiers: u8,
+    pressed_keys: Vec<u8>,
+    // Tablet state.
+    buttons: u8,
+    x: u32,
+    y: u32,
+    wheel: i32,
+    protocol: u8,
+    idle: u8,
+    reports: VecDeque<Vec<u8>>,
+}
+
+impl HidState {
+    fn new(device: UsbHidDevice) -> HidState {
+        HidState {
+            device,
+            modifiers: 0,
+            pressed_keys: Vec::new(),
+            buttons: 0,
+            x: 0,
+            y: 0,
+            wheel: 0,
+            // The report protocol is the default.
+            protocol: 1,
+            idle: 0,
+            reports: VecDeque::new(),
+        }
+    }
+
+    fn report(&self) -> Vec<u8> {
+        match self.device {
+            UsbHidDevice::Keyboard => {
+                let mut report = vec![0u8; 2 + MAX_PRESSED_KEYS];
+                report[0] = self.modifiers;
+                if self.pressed_keys.len() > MAX_PRESSED_KEYS {
+                    report[2..].fill(KEY_ERROR_ROLL_OVER);
+                } else {
+                    report[2..2 + self.pressed_keys.len()].copy_from_slice(&self.pressed_keys);
+                }
+                report
+            }
+            UsbHidDevice::Tablet { width, height } => {
+                let scale = |value: u32, size: u32| {
+                    let max = u64::from(size.max(2) - 1);
+                    (u64::from(value).min(max) * TABLET_MAX_COORDINATE / max) as u16
+                };
+                let mut report = Vec::with_capacity(6);
+                report.push(self.buttons);
+                report.extend_from_slice(&scale(self.x, width).to_le_bytes());
+                report.extend_from_slice(&scale(self.y, height).to_le_bytes());
+                report.push(self.wheel.clamp(-127, 127) as i8 as u8);
+                report
+            }
+        }
+    }
+
+    fn set_button(&mut self, bit: u8, pressed: bool) {
+        if pressed {
+            self.buttons |= 1 << bit;
+        } else {
+            self.buttons &= !(1 << bit);
+        }
+    }
+
+    fn handle_key(&mut self, code: u16, value: i32) {
+        match self.device {
+            UsbHidDevice::Keyboard => {
+                if let Some(bit) = MODIFIER_KEYS.iter().position(|&c| c == code) {
+                    if value != 0 {
+                        self.modifiers |= 1 << bit;
+                    } else {
+                        self.modifiers &= !(1 << bit);
+                    }
+                } else if let Some(usage) = key_usage(code) {
+                    // A value of 2 is an autorepeat of a key that is already pressed.
+                    if value != 0 {
+                        if !self.pressed_keys.contains(&usage) {
+                            self.pressed_keys.push(usage);
+                        }
+                    } else {
+                        self.pressed_keys.retain(|&u| u != usage);
+                    }
+                }
+            }
+            UsbHidDevice::Tablet { .. } => match code {
+                BTN_LEFT | BTN_TOUCH => self.set_button(0, value != 0),
+                BTN_RIGHT => self.set_button(1, value != 0),
+                BTN_MIDDLE => self.set_button(2, value != 0),
+                _ => {}
+            },
+        }
+    }
+
+    fn handle_event(&mut self, event: &virtio_input_event) {
+        let value = event.value.to_native();
+        match (event.type_.to_native(), event.code.to_native()) {
+            (EV_KEY, code) => self.handle_key(code, value),
+            (EV_ABS, ABS_X) => self.x = value.max(0) as u32,
+            (EV_ABS, ABS_Y) => self.y = value.max(0) as u32,
+            (EV_REL, REL_WHEEL) => self.wheel = self.wheel.saturating_add(value),
+            (EV_SYN, SYN_REPORT) => {
+                if self.reports.len() == MAX_QUEUED_REPORTS {
+                    warn!("dropping HID report, the guest is not polling the device");
+                    self.reports.pop_front();
+                }
+                self.reports.push_back(self.report());
+                // The wheel is relative to the previous report.
+                self.wheel = 0;
+            }
+            _ => {}
+        }
+    }
+}
+
+struct HidInner {
+    state: Mutex<HidState>,
+    pending_in: PendingTransfers,
+}
+
+impl HidInner {
+    // Completes the pending IN transfers with the queued reports.
+    fn send_reports(&self) -> Result<()> {
+        let mut state = self.state.lock();
+        while !state.reports.is_empty() {
+            let transfer = match self.pending_in.pop() {
+                Some(transfer) => transfer,
+                None => break,
+            };
+            let buffer = match transfer_buffer(&transfer)? {
+                Some(buffer) => buffer,
+                None => continue,
+            };
+            let report = state.reports.pop_front().unwrap();
+            if complete_in_transfer(transfer, &buffer, &report)?.is_none() {
+                state.reports.push_front(report);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A USB HID keyboard or tablet. The input events are received by the `HidEventHandler` returned
+/// along with the device.
+pub struct Hid {
+    inner: Arc<HidInner>,
+}
+
+impl Hid {
+    /// Create a HID device of type `device` that reports the input events read from `source`.
+    /// The returned handler must be registered on an event loop for the readability of `source`.
+    pub fn new(
+        device: UsbHidDevice,
+        source: File,
+        fail_handle: Arc<dyn FailHandle>,
+        job_queue: Arc<AsyncJobQueue>,
+    ) -> (Hid, Arc<HidEventHandler>) {
+        let inner = Arc::new(HidInner {
+            state: Mutex::new(HidState::new(device)),
+            pending_in: PendingTransfers::new(fail_handle, job_queue),
+        });
+        let handler = Arc::new(HidEventHandler {
+            source: Mutex::new(SocketEventSource::new(source)),
+            inner: inner.clone(),
+        });
+        (Hid { inner }, handler)
+    }
+
+    fn report_descriptor(&self) -> &'static [u8] {
+        match self.inner.state.lock().device {
+            UsbHidDevice::Keyboard => KEYBOARD_REPORT_DESCRIPTOR,
+            UsbHidDevice::Tablet { .. } => TABLET_REPORT_DESCRIPTOR,
+        }
+    }
+
+    fn hid_descriptor(&self) -> Vec<u8> {
+        let report_descriptor_length = self.report_descriptor().len() as u16;
+        let mut content = Vec::new();
+        content.extend_from_slice(&HID_VERSION.to_le_bytes());
+        // Country code: not localized.
+        content.push(0);
+        // A single report descriptor follows.
+        content.push(1);
+        content.push(DESCRIPTOR_TYPE_REPORT);
+        content.extend_from_slice(&report_descriptor_length.to_le_bytes());
+        let mut data = Vec::new();
+        push_descriptor(&mut data, DESCRIPTOR_TYPE_HID, &content);
+        data
+    }
+}
+
+impl UsbFunction for Hid {
+    fn device_descriptor(&self) -> DeviceDescriptor {
+        let product_id = match self.inner.state.lock().device {
+            UsbHidDevice::Keyboard => PRODUCT_ID_KEYBOARD,
+            UsbHidDevice::Tablet { .. } => PRODUCT_ID_TABLET,
+        };
+        DeviceDescriptor {
+            bcdUSB: 0x0200,
+            // The class is defined by the interface.
+            bDeviceClass: 0,
+            bDeviceSubClass: 0,
+            bDeviceProtocol: 0,
+            bMaxPacketSize0: 64,
+            idVendor: VENDOR_ID,
+            idProduct: product_id,
+            bcdDevice: 0x0100,
+            iManufacturer: STRING_MANUFACTURER,
+            iProduct: STRING_PRODUCT,
+            iSerialNumber: STRING_SERIAL_NUMBER,
+            bNumConfigurations: 1,
+        }
+    }
+
+    fn interface_descriptors(&self) -> Vec<u8> {
+        let (subclass, protocol) = match self.inner.state.lock().device {
+            UsbHidDevice::Keyboard => (INTERFACE_SUBCLASS_BOOT, INTERFACE_PROTOCOL_KEYBOARD),
+            UsbHidDevice::Tablet { .. } => (INTERFACE_SUBCLASS_NONE, INTERFACE_PROTOCOL_NONE),
+        };
+        let interface = InterfaceDescriptor {
+            bInterfaceNumber: 0,
+            bAlternateSetting: 0,
+            bNumEndpoints: 1,
+            bInterfaceClass: INTERFACE_CLASS_HID,
+            bInterfaceSubClass: subclass,
+            bInterfaceProtocol: protocol,
+            iInterface: 0,
+        };
+        let endpoint = EndpointDescriptor {
+            bEndpointAddress: INTERRUPT_IN_ENDPOINT,
+            bmAttributes: ENDPOINT_ATTRIBUTES_INTERRUPT,
+            wMaxPacketSize: INTERRUPT_MAX_PACKET_SIZE,
+            bInterval: INTERRUPT_INTERVAL,
+        };
+        let mut data = Vec::new();
+        push_descriptor(
+            &mut data,
+            DescriptorType::Interface as u8,
+            interface.as_slice(),
+        );
+        // The HID descriptor goes between the interface and its endpoints.
+        data.extend_from_slice(&self.hid_descriptor());
+        push_descriptor(
+            &mut data,
+            DescriptorType::Endpoint as u8,
+            endpoint.as_slice(),
+        );
+        data
+    }
+
+    fn num_interfaces(&self) -> u8 {
+        1
+    }
+
+    fn product(&self) -> &str {
+        match self.inner.state.lock().device {
+            UsbHidDevice::Keyboard => "crosvm keyboard",
+            UsbHidDevice::Tablet { .. } => "crosvm tablet",
+        }
+    }
+
+    fn serial_number(&self) -> &str {
+        "1"
+    }
+
+    fn control_request(&mut self, setup: &UsbRequestSetup, _data: &[u8]) -> Option<Vec<u8>> {
+        if setup.get_recipient() != ControlRequestRecipient::Interface || setup.index != 0 {
+            return None;
+        }
+        match setup.get_type() {
+            ControlRequestType::Standard => {
+                if setup.get_standard_request() != Some(StandardControlRequest::GetDescriptor) {
+                    return None;
+                }
+                match (setup.value >> 8) as u8 {
+                    DESCRIPTOR_TYPE_HID => Some(self.hid_descriptor()),
+                    DESCRIPTOR_TYPE_REPORT => Some(self.report_descriptor().to_vec()),
+                    _ => None,
+                }
+            }
+            ControlRequestType::Class => {
+                let mut state = self.inner.state.lock();
+                match setup.request {
+                    GET_REPORT => Some(state.report()),
+                    GET_IDLE => Some(vec![state.idle]),
+                    GET_PROTOCOL => Some(vec![state.protocol]),
+                    // The LEDs of the keyboard are not emulated.
+                    SET_REPORT
+                        if setup.get_direction()
+                            == ControlRequestDataPhaseTransferDirection::HostToDevice =>
+                    {
+                        Some(Vec::new())
+                    }
+                    // Reports are only sent on input events, whatever the idle rate.
+                    SET_IDLE => {
+                        state.idle = (setup.value >> 8) as u8;
+                        Some(Vec::new())
+                    }
+                    // The boot and report protocols share the same reports.
+                    SET_PROTOCOL => {
+                        state.protocol = setup.value as u8;
+                        Some(Vec::new())
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn submit_transfer(&mut self, transfer: XhciTransfer) -> Result<()> {
+        let endpoint = transfer.get_endpoint_number();
+        match transfer.get_transfer_dir() {
+            TransferDirection::In if endpoint == INTERRUPT_IN_ENDPOINT & 0x0f => {
+                self.inner.pending_in.push(transfer)?;
+                self.inner.send_reports()
+            }
+            _ => {
+                error!("transfer to unknown HID endpoint {}", endpoint);
+                transfer
+                    .on_transfer_complete(&TransferStatus::Error, 0)
+                    .map_err(Error::TransferComplete)
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        let mut state = self.inner.state.lock();
+        state.reports.clear();
+        state.protocol = 1;
+        state.idle = 0;
+    }
+}
+
+/// Reads the input events of a `Hid` device from its socket.
+pub struct HidEventHandler {
+    source: Mutex<SocketEventSource<File>>,
+    inner: Arc<HidInner>,
+}
+
+impl EventHandler for HidEventHandler {
+    fn on_event(&self) -> anyhow::Result<()> {
+        let mut source = self.source.lock();
+        source
+            .receive_events()
+            .context("failed to read HID input events")?;
+        {
+            let mut state = self.inner.state.lock();
+            while let Some(event) = source.pop_available_event() {
+                state.handle_event(&event);
+            }
+        }
+        self.inner
+            .send_reports()
+            .context("failed to send HID reports")
+    }
+}
+
+impl AsRawDescriptor for HidEventHandler {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.source.lock().as_raw_descriptor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data_model::Le16;
+    use data_model::SLe32;
+
+    use super::*;
+
+    fn event(type_: u16, code: u16, value: i32) -> virtio_input_event {
+        virtio_input_event {
+            type_: Le16::from(type_),
+            code: Le16::from(code),
+            value: SLe32::from(value),
+        }
+    }
+
+    #[test]
+    fn keyboard_report() {
+        let mut state = HidState::new(UsbHidDevice::Keyboard);
+        // Left shift and "a".
+        state.handle_event(&event(EV_KEY, 42, 1));
+        state.handle_event(&event(EV_KEY, 30, 1));
+        state.handle_event(&event(EV_SYN, SYN_REPORT, 0));
+        state.handle_event(&event(EV_KEY, 30, 0));
+        state.handle_event(&event(EV_SYN, SYN_REPORT, 0));
+        assert_eq!(
+            state.reports.pop_front().unwrap(),
+            vec![0x02, 0, 0x04, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            state.reports.pop_front().unwrap(),
+            vec![0x02, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn tablet_report() {
+        let mut state = HidState::new(UsbHidDevice::Tablet {
+            width: 1281,
+            height: 1025,
+        });
+        state.handle_event(&event(EV_ABS, ABS_X, 1280));
+        state.handle_event(&event(EV_ABS, ABS_Y, 512));
+        state.handle_event(&event(EV_KEY, BTN_LEFT, 1));
+        state.handle_event(&event(EV_REL, REL_WHEEL, -1));
+        state.handle_event(&event(EV_SYN, SYN_REPORT, 0));
+        assert_eq!(
+            state.reports.pop_front().unwrap(),
+            vec![0x01, 0xff, 0x7f, 0xff, 0x3f, 0xff]
+        );
+    }
+}
@@ -0,0 +1,419 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! USB mass storage device using the bulk-only transport, which carries the SCSI commands of a
+//! disk image.
+
+use std::cmp::min;
+use std::mem;
+use std::sync::Arc;
+
+use base::error;
+use data_model::DataInit;
+use data_model::Le32;
+use usb_util::ControlRequestType;
+use usb_util::DescriptorType;
+use usb_util::DeviceDescriptor;
+use usb_util::EndpointDescriptor;
+use usb_util::InterfaceDescriptor;
+use usb_util::TransferStatus;
+use usb_util::UsbRequestSetup;
+
+use super::usb_device::complete_in_transfer;
+use super::usb_device::push_descriptor;
+use super::usb_device::transfer_buffer;
+use super::usb_device::PendingTransfers;
+use super::usb_device::UsbFunction;
+use super::usb_device::STRING_MANUFACTURER;
+use super::usb_device::STRING_PRODUCT;
+use super::usb_device::STRING_SERIAL_NUMBER;
+use super::usb_device::VENDOR_ID;
+use crate::usb::host_backend::error::Error;
+use crate::usb::host_backend::error::Result;
+use crate::usb::xhci::xhci_transfer::TransferDirection;
+use crate::usb::xhci::xhci_transfer::XhciTransfer;
+use crate::utils::AsyncJobQueue;
+use crate::utils::FailHandle;
+use crate::virtio::scsi::BufferReader;
+use crate::virtio::scsi::BufferWriter;
+use crate::virtio::scsi::LogicalUnit;
+use crate::virtio::scsi::Sense;
+
+const PRODUCT_ID: u16 = 0x0104;
+
+const INTERFACE_CLASS_MASS_STORAGE: u8 = 0x08;
+const INTERFACE_SUBCLASS_SCSI: u8 = 0x06;
+const INTERFACE_PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+const BULK_IN_ENDPOINT: u8 = 0x81;
+const BULK_OUT_ENDPOINT: u8 = 0x02;
+const BULK_MAX_PACKET_SIZE: u16 = 64;
+const ENDPOINT_ATTRIBUTES_BULK: u8 = 0x02;
+
+// Class-specific requests of the bulk-only transport.
+const BULK_ONLY_MASS_STORAGE_RESET: u8 = 0xff;
+const GET_MAX_LUN: u8 = 0xfe;
+
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CSW_SIGNATURE: u32 = 0x53425355;
+// Direction bit of the CBW flags: the data is sent from the device to the host.
+const CBW_FLAG_DATA_IN: u8 = 0x80;
+
+const CSW_STATUS_PASSED: u8 = 0x00;
+const CSW_STATUS_FAILED: u8 = 0x01;
+
+// Largest amount of data of a command that is buffered. Commands transferring more data fail.
+const MAX_DATA_LENGTH: usize = 32 << 20;
+
+// The sense data of a failed command is fetched with REQUEST SENSE, which is answered by the
+// transport rather than the logical unit.
+const REQUEST_SENSE: u8 = 0x03;
+
+/// Command block wrapper, sent by the host on the bulk OUT endpoint to start a command.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct CommandBlockWrapper {
+    signature: Le32,
+    tag: Le32,
+    data_transfer_length: Le32,
+    flags: u8,
+    lun: u8,
+    cb_length: u8,
+    cb: [u8; 16],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for CommandBlockWrapper {}
+
+/// Command status wrapper, returned on the bulk IN endpoint when a command completes.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct CommandStatusWrapper {
+    signature: Le32,
+    tag: Le32,
+    data_residue: Le32,
+    status: u8,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for CommandStatusWrapper {}
+
+enum BulkOnlyState {
+    /// Waiting for a command block wrapper.
+    Command,
+    /// Receiving `length` bytes of data for the command, of which `received` bytes were received.
+    DataOut {
+        cbw: CommandBlockWrapper,
+        data: Vec<u8>,
+        received: usize,
+        length: usize,
+    },
+    /// Returning the data of the command, of which `sent` bytes were sent. The host expects up to
+    /// `length` bytes.
+    DataIn {
+        data: Vec<u8>,
+        sent: usize,
+        length: usize,
+    },
+    /// Returning the command status wrapper.
+    Status,
+}
+
+/// A USB mass storage device with a single logical unit.
+pub struct MassStorage {
+    lun: LogicalUnit,
+    state: BulkOnlyState,
+    csw: CommandStatusWrapper,
+    sense: Sense,
+    pending_in: PendingTransfers,
+}
+
+impl MassStorage {
+    /// Create a mass storage device exposing the disk of `lun`.
+    pub fn new(
+        lun: LogicalUnit,
+        fail_handle: Arc<dyn FailHandle>,
+        job_queue: Arc<AsyncJobQueue>,
+    ) -> MassStorage {
+        MassStorage {
+            lun,
+            state: BulkOnlyState::Command,
+            csw: CommandStatusWrapper::default(),
+            sense: Sense::NONE,
+            pending_in: PendingTransfers::new(fail_handle, job_queue),
+        }
+    }
+
+    // Executes the command of `cbw` with the data sent by the host, and returns the data to send
+    // back to the host along with the status of the command.
+    fn execute_command(&mut self, cbw: &CommandBlockWrapper, data: Vec<u8>) -> (Vec<u8>, u8) {
+        let length = if cbw.flags & CBW_FLAG_DATA_IN != 0 {
+            u32::from(cbw.data_transfer_length) as usize
+        } else {
+            0
+        };
+        // The whole command block is passed on, as the logical unit expects the CDB to be padded.
+        let cdb = &cbw.cb;
+        if cbw.lun == 0 && cdb[0] == REQUEST_SENSE {
+            let sense = mem::replace(&mut self.sense, Sense::NONE).to_fixed();
+            let len = min(min(sense.len(), cdb[4] as usize), length);
+            return (sense[..len].to_vec(), CSW_STATUS_PASSED);
+        }
+
+        let mut writer = BufferWriter::new(min(length, MAX_DATA_LENGTH));
+        let result = if cbw.lun == 0 {
+            self.lun
+                .execute(cdb, &mut BufferReader::new(data), &mut writer)
+        } else {
+            Err(Sense::LUN_NOT_SUPPORTED)
+        };
+        let status = match result {
+            Ok(()) => CSW_STATUS_PASSED,
+            Err(sense) => {
+                self.sense = sense;
+                CSW_STATUS_FAILED
+            }
+        };
+        (writer.into_inner(), status)
+    }
+
+    fn complete_command(&mut self, cbw: &CommandBlockWrapper, data: Vec<u8>) {
+        let (data, status) = self.execute_command(cbw, data);
+        let length = u32::from(cbw.data_transfer_length) as usize;
+        let data_in = cbw.flags & CBW_FLAG_DATA_IN != 0;
+        // The data sent by the host is always consumed entirely.
+        let residue = if data_in { length - data.len() } else { 0 };
+        self.csw = CommandStatusWrapper {
+            signature: Le32::from(CSW_SIGNATURE),
+            tag: cbw.tag,
+            data_residue: Le32::from(residue as u32),
+            status,
+        };
+        self.state = if data_in && length > 0 {
+            BulkOnlyState::DataIn {
+                data,
+                sent: 0,
+                length,
+            }
+        } else {
+            BulkOnlyState::Status
+        };
+    }
+
+    fn handle_command_block(&mut self, data: &[u8]) {
+        let cbw = match CommandBlockWrapper::from_slice(data) {
+            Some(cbw)
+                if u32::from(cbw.signature) == CBW_SIGNATURE
+                    && (1..=cbw.cb.len()).contains(&(cbw.cb_length as usize)) =>
+            {
+                *cbw
+            }
+            _ => {
+                error!("invalid command block wrapper of {} bytes", data.len());
+                return;
+            }
+        };
+        let length = u32::from(cbw.data_transfer_length) as usize;
+        if cbw.flags & CBW_FLAG_DATA_IN == 0 && length > 0 {
+            self.state = BulkOnlyState::DataOut {
+                cbw,
+                data: Vec::new(),
+                received: 0,
+                length,
+            };
+        } else {
+            self.complete_command(&cbw, Vec::new());
+        }
+    }
+
+    fn handle_out_transfer(&mut self, transfer: XhciTransfer) -> Result<()> {
+        let buffer = match transfer_buffer(&transfer)? {
+            Some(buffer) => buffer,
+            None => return Ok(()),
+        };
+        let mut data = vec![0u8; buffer.len().map_err(Error::BufferLen)?];
+        buffer.read(&mut data).map_err(Error::ReadBuffer)?;
+        transfer
+            .on_transfer_complete(&TransferStatus::Completed, data.len() as u32)
+            .map_err(Error::TransferComplete)?;
+
+        match mem::replace(&mut self.state, BulkOnlyState::Command) {
+            BulkOnlyState::Command => self.handle_command_block(&data),
+            BulkOnlyState::DataOut {
+                cbw,
+                data: mut buffered,
+                received,
+                length,
+            } => {
+                let received = received + data.len();
+                let len = min(data.len(), MAX_DATA_LENGTH - buffered.len());
+                buffered.extend_from_slice(&data[..len]);
+                if received >= length {
+                    buffered.truncate(length);
+                    self.complete_command(&cbw, buffered);
+                } else {
+                    self.state = BulkOnlyState::DataOut {
+                        cbw,
+                        data: buffered,
+                        received,
+                        length,
+                    };
+                }
+            }
+            state => {
+                error!("unexpected data on the bulk OUT endpoint");
+                self.state = state;
+            }
+        }
+        self.send_pending_in()
+    }
+
+    // Completes the pending IN transfers with the data or the status of the current command.
+    fn send_pending_in(&mut self) -> Result<()> {
+        loop {
+            if !matches!(
+                self.state,
+                BulkOnlyState::DataIn { .. } | BulkOnlyState::Status
+            ) {
+                return Ok(());
+            }
+            let transfer = match self.pending_in.pop() {
+                Some(transfer) => transfer,
+                None => return Ok(()),
+            };
+            let buffer = match transfer_buffer(&transfer)? {
+                Some(buffer) => buffer,
+                None => continue,
+            };
+            match mem::replace(&mut self.state, BulkOnlyState::Command) {
+                BulkOnlyState::DataIn { data, sent, length } => {
+                    let buffer_len = buffer.len().map_err(Error::BufferLen)?;
+                    self.state = match complete_in_transfer(transfer, &buffer, &data[sent..])? {
+                        // The data stage ends with a short packet when the command returns less
+                        // data than the host asked for.
+                        Some(written) if written < buffer_len || sent + written >= length => {
+                            BulkOnlyState::Status
+                        }
+                        Some(written) => BulkOnlyState::DataIn {
+                            data,
+                            sent: sent + written,
+                            length,
+                        },
+                        None => BulkOnlyState::DataIn { data, sent, length },
+                    };
+                }
+                BulkOnlyState::Status => {
+                    self.state = match complete_in_transfer(transfer, &buffer, self.csw.as_slice())?
+                    {
+                        Some(_) => BulkOnlyState::Command,
+                        None => BulkOnlyState::Status,
+                    };
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+impl UsbFunction for MassStorage {
+    fn device_descriptor(&self) -> DeviceDescriptor {
+        DeviceDescriptor {
+            bcdUSB: 0x0200,
+            // The class is defined by the interface.
+            bDeviceClass: 0,
+            bDeviceSubClass: 0,
+            bDeviceProtocol: 0,
+            bMaxPacketSize0: 64,
+            idVendor: VENDOR_ID,
+            idProduct: PRODUCT_ID,
+            bcdDevice: 0x0100,
+            iManufacturer: STRING_MANUFACTURER,
+            iProduct: STRING_PRODUCT,
+            iSerialNumber: STRING_SERIAL_NUMBER,
+            bNumConfigurations: 1,
+        }
+    }
+
+    fn interface_descriptors(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        let interface = InterfaceDescriptor {
+            bInterfaceNumber: 0,
+            bAlternateSetting: 0,
+            bNumEndpoints: 2,
+            bInterfaceClass: INTERFACE_CLASS_MASS_STORAGE,
+            bInterfaceSubClass: INTERFACE_SUBCLASS_SCSI,
+            bInterfaceProtocol: INTERFACE_PROTOCOL_BULK_ONLY,
+            iInterface: 0,
+        };
+        push_descriptor(
+            &mut data,
+            DescriptorType::Interface as u8,
+            interface.as_slice(),
+        );
+        for address in [BULK_IN_ENDPOINT, BULK_OUT_ENDPOINT] {
+            let endpoint = EndpointDescriptor {
+                bEndpointAddress: address,
+                bmAttributes: ENDPOINT_ATTRIBUTES_BULK,
+                wMaxPacketSize: BULK_MAX_PACKET_SIZE,
+                bInterval: 0,
+            };
+            push_descriptor(
+                &mut data,
+                DescriptorType::Endpoint as u8,
+                endpoint.as_slice(),
+            );
+        }
+        data
+    }
+
+    fn num_interfaces(&self) -> u8 {
+        1
+    }
+
+    fn product(&self) -> &str {
+        "crosvm mass storage"
+    }
+
+    fn serial_number(&self) -> &str {
+        "1"
+    }
+
+    fn control_request(&mut self, setup: &UsbRequestSetup, _data: &[u8]) -> Option<Vec<u8>> {
+        if setup.get_type() != ControlRequestType::Class {
+            return None;
+        }
+        match setup.request {
+            GET_MAX_LUN => Some(vec![0]),
+            BULK_ONLY_MASS_STORAGE_RESET => {
+                self.state = BulkOnlyState::Command;
+                Some(Vec::new())
+            }
+            _ => None,
+        }
+    }
+
+    fn submit_transfer(&mut self, transfer: XhciTransfer) -> Result<()> {
+        let endpoint = transfer.get_endpoint_number();
+        match transfer.get_transfer_dir() {
+            TransferDirection::Out if endpoint == BULK_OUT_ENDPOINT & 0x0f => {
+                self.handle_out_transfer(transfer)
+            }
+            TransferDirection::In if endpoint == BULK_IN_ENDPOINT & 0x0f => {
+                self.pending_in.push(transfer)?;
+                self.send_pending_in()
+            }
+            _ => {
+                error!("transfer to unknown mass storage endpoint {}", endpoint);
+                transfer
+                    .on_transfer_complete(&TransferStatus::Error, 0)
+                    .map_err(Error::TransferComplete)
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = BulkOnlyState::Command;
+        self.sense = Sense::NONE;
+    }
+}
@@ -0,0 +1,9 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! USB devices emulated by crosvm, attached to the xHCI controller next to the host devices.
+
+pub mod hid;
+pub mod mass_storage;
+pub mod usb_device;
@@ -0,0 +1,516 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::mem;
+use std::mem::size_of;
+use std::sync::Arc;
+use std::sync::Weak;
+
+use base::error;
+use data_model::DataInit;
+use sync::Mutex;
+use usb_util::ConfigDescriptor;
+use usb_util::ControlRequestDataPhaseTransferDirection;
+use usb_util::ControlRequestRecipient;
+use usb_util::DescriptorHeader;
+use usb_util::DescriptorType;
+use usb_util::DeviceDescriptor;
+use usb_util::StandardControlRequest;
+use usb_util::TransferStatus;
+use usb_util::UsbRequestSetup;
+
+use crate::usb::host_backend::error::Error;
+use crate::usb::host_backend::error::Result;
+use crate::usb::host_backend::host_device::ControlEndpointState;
+use crate::usb::xhci::scatter_gather_buffer::ScatterGatherBuffer;
+use crate::usb::xhci::xhci_backend_device::BackendType;
+use crate::usb::xhci::xhci_backend_device::UsbDeviceAddress;
+use crate::usb::xhci::xhci_backend_device::XhciBackendDevice;
+use crate::usb::xhci::xhci_transfer::XhciTransfer;
+use crate::usb::xhci::xhci_transfer::XhciTransferState;
+use crate::usb::xhci::xhci_transfer::XhciTransferType;
+use crate::utils::AsyncJobQueue;
+use crate::utils::FailHandle;
+
+/// Vendor ID of the emulated devices (Linux Foundation).
+pub const VENDOR_ID: u16 = 0x1d6b;
+
+/// Index of the manufacturer string descriptor.
+pub const STRING_MANUFACTURER: u8 = 1;
+/// Index of the product string descriptor.
+pub const STRING_PRODUCT: u8 = 2;
+/// Index of the serial number string descriptor.
+pub const STRING_SERIAL_NUMBER: u8 = 3;
+
+const MANUFACTURER: &str = "crosvm";
+
+const DESCRIPTOR_TYPE_STRING: u8 = 0x03;
+// Language of the string descriptors: English (United States).
+const LANGUAGE_ID_EN_US: u16 = 0x0409;
+
+// Value of the only configuration of the emulated devices.
+const CONFIGURATION_VALUE: u8 = 1;
+
+/// The class-specific part of an emulated USB device. `EmulatedDevice` answers the standard
+/// requests to the device and passes everything else to its function.
+pub trait UsbFunction: Send {
+    /// Returns the device descriptor.
+    fn device_descriptor(&self) -> DeviceDescriptor;
+    /// Returns the interface, class-specific and endpoint descriptors of the configuration.
+    fn interface_descriptors(&self) -> Vec<u8>;
+    /// Returns the number of interfaces of the configuration.
+    fn num_interfaces(&self) -> u8;
+    /// Returns the product name reported in the string descriptors.
+    fn product(&self) -> &str;
+    /// Returns the serial number reported in the string descriptors.
+    fn serial_number(&self) -> &str;
+    /// Handles a control request that is not a standard request to the device. `data` holds the
+    /// data stage of requests from the host. Returns the data stage of requests to the host, or
+    /// `None` if the request is not supported.
+    fn control_request(&mut self, setup: &UsbRequestSetup, data: &[u8]) -> Option<Vec<u8>>;
+    /// Handles a transfer on one of the data endpoints.
+    fn submit_transfer(&mut self, transfer: XhciTransfer) -> Result<()>;
+    /// Resets the function to its state after the device is configured.
+    fn reset(&mut self);
+}
+
+/// Appends a descriptor of `descriptor_type` with the content `data` to `buf`.
+pub fn push_descriptor(buf: &mut Vec<u8>, descriptor_type: u8, data: &[u8]) {
+    let header = DescriptorHeader {
+        bLength: (size_of::<DescriptorHeader>() + data.len()) as u8,
+        bDescriptorType: descriptor_type,
+    };
+    buf.extend_from_slice(header.as_slice());
+    buf.extend_from_slice(data);
+}
+
+/// A USB device emulated by crosvm, made of the standard device logic and a `UsbFunction`.
+pub struct EmulatedDevice<F: UsbFunction> {
+    function: F,
+    ctl_ep_state: ControlEndpointState,
+    control_request_setup: UsbRequestSetup,
+    executed: bool,
+    configuration: u8,
+}
+
+impl<F: UsbFunction> EmulatedDevice<F> {
+    /// Create a new emulated device exposing `function`.
+    pub fn new(function: F) -> EmulatedDevice<F> {
+        EmulatedDevice {
+            function,
+            ctl_ep_state: ControlEndpointState::SetupStage,
+            control_request_setup: UsbRequestSetup::new(0, 0, 0, 0, 0),
+            executed: false,
+            configuration: 0,
+        }
+    }
+
+    fn config_descriptor(&self) -> Vec<u8> {
+        let interfaces = self.function.interface_descriptors();
+        let total_length =
+            size_of::<DescriptorHeader>() + size_of::<ConfigDescriptor>() + interfaces.len();
+        let config = ConfigDescriptor {
+            wTotalLength: total_length as u16,
+            bNumInterfaces: self.function.num_interfaces(),
+            bConfigurationValue: CONFIGURATION_VALUE,
+            iConfiguration: 0,
+            // Bus powered. Bit 7 is reserved and must be set.
+            bmAttributes: 0x80,
+            // In units of 2 mA.
+            bMaxPower: 50,
+        };
+        let mut data = Vec::with_capacity(total_length);
+        push_descriptor(
+            &mut data,
+            DescriptorType::Configuration as u8,
+            config.as_slice(),
+        );
+        data.extend_from_slice(&interfaces);
+        data
+    }
+
+    fn string_descriptor(&self, index: u8) -> Option<Vec<u8>> {
+        let string = match index {
+            0 => {
+                let mut data = Vec::new();
+                push_descriptor(
+                    &mut data,
+                    DESCRIPTOR_TYPE_STRING,
+                    &LANGUAGE_ID_EN_US.to_le_bytes(),
+                );
+                return Some(data);
+            }
+            STRING_MANUFACTURER => MANUFACTURER,
+            STRING_PRODUCT => self.function.product(),
+            STRING_SERIAL_NUMBER => self.function.serial_number(),
+            _ => return None,
+        };
+        let utf16: Vec<u8> = string
+            .encode_utf16()
+            .flat_map(|c| c.to_le_bytes())
+            .collect();
+        let mut data = Vec::new();
+        push_descriptor(&mut data, DESCRIPTOR_TYPE_STRING, &utf16);
+        Some(data)
+    }
+
+    fn get_descriptor(&self) -> Option<Vec<u8>> {
+        let descriptor_type = (self.control_request_setup.value >> 8) as u8;
+        let descriptor_index = self.control_request_setup.value as u8;
+        if descriptor_type == DescriptorType::Device as u8 {
+            let mut data = Vec::new();
+            push_descriptor(
+                &mut data,
+                descriptor_type,
+                self.function.device_descriptor().as_slice(),
+            );
+            Some(data)
+        } else if descriptor_type == DescriptorType::Configuration as u8 {
+            Some(self.config_descriptor())
+        } else if descriptor_type == DESCRIPTOR_TYPE_STRING {
+            self.string_descriptor(descriptor_index)
+        } else {
+            usb_debug!("unsupported descriptor type {}", descriptor_type);
+            None
+        }
+    }
+
+    // Executes the current control request. Returns the data stage of requests to the host, or
+    // `None` if the request is not supported.
+    fn execute_control_request(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        let setup = self.control_request_setup;
+        let standard_request = match setup.get_standard_request() {
+            Some(req) => req,
+            None => return self.function.control_request(&setup, data),
+        };
+        match (standard_request, setup.get_recipient()) {
+            (StandardControlRequest::GetDescriptor, ControlRequestRecipient::Device) => {
+                self.get_descriptor()
+            }
+            // The address is handled by the xHCI controller, see `set_address`.
+            (StandardControlRequest::SetAddress, ControlRequestRecipient::Device) => {
+                Some(Vec::new())
+            }
+            (StandardControlRequest::GetConfiguration, ControlRequestRecipient::Device) => {
+                Some(vec![self.configuration])
+            }
+            (StandardControlRequest::SetConfiguration, ControlRequestRecipient::Device) => {
+                let configuration = setup.value as u8;
+                if configuration != 0 && configuration != CONFIGURATION_VALUE {
+                    return None;
+                }
+                usb_debug!("emulated device set config {}", configuration);
+                self.configuration = configuration;
+                self.function.reset();
+                Some(Vec::new())
+            }
+            // The interfaces only have the default alternate setting.
+            (StandardControlRequest::GetInterface, ControlRequestRecipient::Interface) => {
+                Some(vec![0])
+            }
+            (StandardControlRequest::SetInterface, ControlRequestRecipient::Interface) => {
+                if setup.value == 0 {
+                    Some(Vec::new())
+                } else {
+                    None
+                }
+            }
+            // Not self powered, no remote wakeup and no halted endpoint.
+            (StandardControlRequest::GetStatus, _) => Some(vec![0, 0]),
+            (StandardControlRequest::ClearFeature, _) | (StandardControlRequest::SetFeature, _) => {
+                Some(Vec::new())
+            }
+            _ => self.function.control_request(&setup, data),
+        }
+    }
+
+    fn execute_control_transfer(
+        &mut self,
+        xhci_transfer: XhciTransfer,
+        buffer: Option<ScatterGatherBuffer>,
+    ) -> Result<()> {
+        let direction = self.control_request_setup.get_direction();
+        let mut data = Vec::new();
+        if direction == ControlRequestDataPhaseTransferDirection::HostToDevice {
+            if let Some(buffer) = &buffer {
+                data.resize(self.control_request_setup.length as usize, 0);
+                let len = buffer.read(&mut data).map_err(Error::ReadBuffer)?;
+                data.truncate(len);
+            }
+        }
+
+        let (status, bytes_transferred) = match self.execute_control_request(&data) {
+            Some(reply) => {
+                if direction == ControlRequestDataPhaseTransferDirection::DeviceToHost {
+                    let len = min(reply.len(), self.control_request_setup.length as usize);
+                    let written = match &buffer {
+                        Some(buffer) => buffer.write(&reply[..len]).map_err(Error::WriteBuffer)?,
+                        None => 0,
+                    };
+                    (TransferStatus::Completed, written)
+                } else {
+                    (TransferStatus::Completed, data.len())
+                }
+            }
+            None => {
+                usb_debug!(
+                    "unsupported control request {:?}",
+                    self.control_request_setup
+                );
+                (TransferStatus::Error, 0)
+            }
+        };
+        xhci_transfer
+            .on_transfer_complete(&status, bytes_transferred as u32)
+            .map_err(Error::TransferComplete)
+    }
+
+    fn handle_control_transfer(&mut self, xhci_transfer: XhciTransfer) -> Result<()> {
+        let transfer_type = xhci_transfer
+            .get_transfer_type()
+            .map_err(Error::GetXhciTransferType)?;
+        match transfer_type {
+            XhciTransferType::SetupStage(setup) => {
+                if self.ctl_ep_state != ControlEndpointState::SetupStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                usb_debug!("emulated device setup stage: {:?}", setup);
+                self.control_request_setup = setup;
+                xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete)?;
+                self.ctl_ep_state = ControlEndpointState::DataStage;
+            }
+            XhciTransferType::DataStage(buffer) => {
+                if self.ctl_ep_state != ControlEndpointState::DataStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                self.execute_control_transfer(xhci_transfer, Some(buffer))?;
+                self.executed = true;
+                self.ctl_ep_state = ControlEndpointState::StatusStage;
+            }
+            XhciTransferType::StatusStage => {
+                if self.ctl_ep_state == ControlEndpointState::SetupStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                if self.executed {
+                    xhci_transfer
+                        .on_transfer_complete(&TransferStatus::Completed, 0)
+                        .map_err(Error::TransferComplete)?;
+                } else {
+                    self.execute_control_transfer(xhci_transfer, None)?;
+                }
+                self.executed = false;
+                self.ctl_ep_state = ControlEndpointState::SetupStage;
+            }
+            _ => {
+                error!(
+                    "Non control {} transfer sent to control endpoint.",
+                    transfer_type,
+                );
+                xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<F: UsbFunction> XhciBackendDevice for EmulatedDevice<F> {
+    fn get_backend_type(&self) -> BackendType {
+        BackendType::Usb2
+    }
+
+    fn get_vid(&self) -> u16 {
+        self.function.device_descriptor().idVendor
+    }
+
+    fn get_pid(&self) -> u16 {
+        self.function.device_descriptor().idProduct
+    }
+
+    fn submit_transfer(&mut self, transfer: XhciTransfer) -> Result<()> {
+        if transfer.get_endpoint_number() == 0 {
+            return self.handle_control_transfer(transfer);
+        }
+        if self.configuration == 0 {
+            error!("transfer to an emulated device that is not configured");
+            return transfer
+                .on_transfer_complete(&TransferStatus::Error, 0)
+                .map_err(Error::TransferComplete);
+        }
+        self.function.submit_transfer(transfer)
+    }
+
+    fn set_address(&mut self, _address: UsbDeviceAddress) {
+        usb_debug!("emulated device set address {}", _address);
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        usb_debug!("resetting emulated device");
+        self.ctl_ep_state = ControlEndpointState::SetupStage;
+        self.executed = false;
+        self.configuration = 0;
+        self.function.reset();
+        Ok(())
+    }
+}
+
+/// Returns the buffer of a transfer on a bulk or interrupt endpoint, or completes the transfer and
+/// returns `None` if it has no buffer.
+pub fn transfer_buffer(transfer: &XhciTransfer) -> Result<Option<ScatterGatherBuffer>> {
+    match transfer
+        .get_transfer_type()
+        .map_err(Error::GetXhciTransferType)?
+    {
+        XhciTransferType::Normal(buffer) => Ok(Some(buffer)),
+        XhciTransferType::Noop => {
+            transfer
+                .on_transfer_complete(&TransferStatus::Completed, 0)
+                .map_err(Error::TransferComplete)?;
+            Ok(None)
+        }
+        _ => {
+            error!("unhandled xhci transfer type by emulated device");
+            transfer
+                .on_transfer_complete(&TransferStatus::Error, 0)
+                .map_err(Error::TransferComplete)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Writes `data` to the buffer of the IN `transfer` and completes it. Returns the number of bytes
+/// written, or `None` if the guest cancelled the transfer in the meantime.
+pub fn complete_in_transfer(
+    transfer: XhciTransfer,
+    buffer: &ScatterGatherBuffer,
+    data: &[u8],
+) -> Result<Option<usize>> {
+    let cancelled = {
+        let mut state = transfer.state().lock();
+        let cancelled = matches!(
+            *state,
+            XhciTransferState::Cancelling | XhciTransferState::Cancelled
+        );
+        *state = if cancelled {
+            XhciTransferState::Cancelled
+        } else {
+            XhciTransferState::Completed
+        };
+        cancelled
+    };
+    if cancelled {
+        transfer
+            .on_transfer_complete(&TransferStatus::Cancelled, 0)
+            .map_err(Error::TransferComplete)?;
+        return Ok(None);
+    }
+    let written = buffer.write(data).map_err(Error::WriteBuffer)?;
+    transfer
+        .on_transfer_complete(&TransferStatus::Completed, written as u32)
+        .map_err(Error::TransferComplete)?;
+    Ok(Some(written))
+}
+
+/// IN transfers waiting for an emulated device to have data to return.
+pub struct PendingTransfers {
+    transfers: Arc<Mutex<VecDeque<XhciTransfer>>>,
+    fail_handle: Arc<dyn FailHandle>,
+    job_queue: Arc<AsyncJobQueue>,
+}
+
+impl PendingTransfers {
+    pub fn new(fail_handle: Arc<dyn FailHandle>, job_queue: Arc<AsyncJobQueue>) -> Self {
+        PendingTransfers {
+            transfers: Arc::new(Mutex::new(VecDeque::new())),
+            fail_handle,
+            job_queue,
+        }
+    }
+
+    /// Keeps `transfer` until it is taken with `pop`, or until the guest cancels it.
+    pub fn push(&self, transfer: XhciTransfer) -> Result<()> {
+        let mut transfers = self.transfers.lock();
+        let transfer_state = transfer.state().clone();
+        let mut state = transfer_state.lock();
+        match mem::replace(&mut *state, XhciTransferState::Cancelled) {
+            XhciTransferState::Created => {
+                let weak_transfers = Arc::downgrade(&self.transfers);
+                let fail_handle = self.fail_handle.clone();
+                let job_queue = self.job_queue.clone();
+                // The transfer is cancelled with the locks of the transfer manager held, so
+                // completing it is left to the job queue.
+                let cancel_callback = Box::new(move || {
+                    let job = move || {
+                        if let Err(e) = complete_cancelled(&weak_transfers) {
+                            error!("failed to complete cancelled transfers: {}", e);
+                            fail_handle.fail();
+                        }
+                    };
+                    if let Err(e) = job_queue.queue_job(job) {
+                        error!("failed to queue transfer cancellation: {}", e);
+                    }
+                });
+                *state = XhciTransferState::Submitted { cancel_callback };
+            }
+            XhciTransferState::Cancelled => {
+                drop(state);
+                drop(transfers);
+                return transfer
+                    .on_transfer_complete(&TransferStatus::Cancelled, 0)
+                    .map_err(Error::TransferComplete);
+            }
+            _ => {
+                error!("xhci trasfer state is invalid");
+                return Err(Error::BadXhciTransferState);
+            }
+        }
+        drop(state);
+        transfers.push_back(transfer);
+        Ok(())
+    }
+
+    /// Takes the oldest pending transfer.
+    pub fn pop(&self) -> Option<XhciTransfer> {
+        self.transfers.lock().pop_front()
+    }
+
+    /// Puts back a transfer taken with `pop` that could not be completed.
+    pub fn push_front(&self, transfer: XhciTransfer) {
+        self.transfers.lock().push_front(transfer);
+    }
+}
+
+// Completes the pending transfers that were cancelled.
+fn complete_cancelled(transfers: &Weak<Mutex<VecDeque<XhciTransfer>>>) -> Result<()> {
+    let transfers = match transfers.upgrade() {
+        Some(transfers) => transfers,
+        // The device is already gone along with its transfers.
+        None => return Ok(()),
+    };
+    let mut locked = transfers.lock();
+    let (cancelled, pending): (VecDeque<_>, VecDeque<_>) =
+        locked.drain(..).partition(|t: &XhciTransfer| {
+            matches!(
+                *t.state().lock(),
+                XhciTransferState::Cancelling | XhciTransferState::Cancelled
+            )
+        });
+    *locked = pending;
+    drop(locked);
+    for transfer in cancelled {
+        *transfer.state().lock() = XhciTransferState::Cancelled;
+        transfer
+            .on_transfer_complete(&TransferStatus::Cancelled, 0)
+            .map_err(Error::TransferComplete)?;
+    }
+    Ok(())
+}
@@ -5,6 +5,7 @@
 use std::collections::HashMap;
 use std::fs::File;
 use std::mem;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -19,21 +20,28 @@ use usb_util::Device;
 use vm_control::UsbControlAttachedDevice;
 use vm_control::UsbControlCommand;
 use vm_control::UsbControlResult;
+use vm_control::UsbHidDevice;
 use vm_control::USB_CONTROL_MAX_PORTS;
 
 use super::error::*;
 use super::host_device::HostDevice;
+use crate::usb::emulated::hid::Hid;
+use crate::usb::emulated::hid::HidEventHandler;
+use crate::usb::emulated::mass_storage::MassStorage;
+use crate::usb::emulated::usb_device::EmulatedDevice;
 use crate::usb::xhci::usb_hub::UsbHub;
+use crate::usb::xhci::xhci_backend_device::XhciBackendDevice;
 use crate::usb::xhci::xhci_backend_device_provider::XhciBackendDeviceProvider;
 use crate::utils::AsyncJobQueue;
 use crate::utils::EventHandler;
 use crate::utils::EventLoop;
 use crate::utils::FailHandle;
+use crate::virtio::scsi::LogicalUnit;
 
 const SOCKET_TIMEOUT_MS: u64 = 2000;
 
 /// Host backend device provider is a xhci backend device provider that would provide pass through
-/// devices.
+/// devices, as well as the devices emulated by crosvm.
 pub enum HostBackendDeviceProvider {
     // The provider is created but not yet started.
     Created { control_tube: Mutex<Tube> },
@@ -133,12 +141,18 @@ pub struct ProviderInner {
     usb_hub: Arc<UsbHub>,
 
     // Map of USB hub port number to per-device context.
-    devices: Mutex<HashMap<u8, HostDeviceContext>>,
+    devices: Mutex<HashMap<u8, DeviceContext>>,
 }
 
-struct HostDeviceContext {
-    event_handler: Arc<dyn EventHandler>,
-    device: Arc<Mutex<Device>>,
+enum DeviceContext {
+    Host {
+        event_handler: Arc<dyn EventHandler>,
+        device: Arc<Mutex<Device>>,
+    },
+    Hid {
+        event_handler: Arc<HidEventHandler>,
+    },
+    MassStorage,
 }
 
 impl ProviderInner {
@@ -185,7 +199,7 @@ impl ProviderInner {
             return UsbControlResult::FailedToOpenDevice;
         }
 
-        let device_ctx = HostDeviceContext {
+        let device_ctx = DeviceContext::Host {
             event_handler,
             device: arc_mutex_device.clone(),
         };
@@ -208,7 +222,62 @@ impl ProviderInner {
             }
         };
 
-        let port = self.usb_hub.connect_backend(host_device);
+        self.connect_device(host_device, device_ctx)
+    }
+
+    /// Create an emulated mass storage device exposing the disk image `file`.
+    fn handle_attach_mass_storage(&self, file: File, read_only: bool) -> UsbControlResult {
+        let disk = match disk::create_disk_file(file, true, disk::MAX_NESTING_DEPTH, Path::new(""))
+        {
+            Ok(disk) => disk,
+            Err(e) => {
+                error!("failed to open disk image for USB mass storage: {}", e);
+                return UsbControlResult::FailedToOpenDevice;
+            }
+        };
+        let lun = match LogicalUnit::new(disk, read_only, true, 512) {
+            Ok(lun) => lun,
+            Err(e) => {
+                error!("failed to create USB mass storage logical unit: {}", e);
+                return UsbControlResult::FailedToInitHostDevice;
+            }
+        };
+        let device = EmulatedDevice::new(MassStorage::new(
+            lun,
+            self.fail_handle.clone(),
+            self.job_queue.clone(),
+        ));
+        self.connect_device(Box::new(device), DeviceContext::MassStorage)
+    }
+
+    /// Create an emulated HID device reporting the input events read from `source`.
+    fn handle_attach_hid_device(&self, device: UsbHidDevice, source: File) -> UsbControlResult {
+        let (hid, event_handler) = Hid::new(
+            device,
+            source,
+            self.fail_handle.clone(),
+            self.job_queue.clone(),
+        );
+        let handler: Arc<dyn EventHandler> = event_handler.clone();
+        if let Err(e) =
+            self.event_loop
+                .add_event(&*event_handler, EventType::Read, Arc::downgrade(&handler))
+        {
+            error!("failed to add HID input source to event handler: {}", e);
+            return UsbControlResult::FailedToOpenDevice;
+        }
+        self.connect_device(
+            Box::new(EmulatedDevice::new(hid)),
+            DeviceContext::Hid { event_handler },
+        )
+    }
+
+    fn connect_device(
+        &self,
+        device: Box<dyn XhciBackendDevice>,
+        device_ctx: DeviceContext,
+    ) -> UsbControlResult {
+        let port = self.usb_hub.connect_backend(device);
         match port {
             Ok(port) => {
                 self.devices.lock().insert(port, device_ctx);
@@ -216,25 +285,43 @@ impl ProviderInner {
             }
             Err(e) => {
                 error!("failed to connect device to hub: {}", e);
+                if let DeviceContext::Hid { event_handler } = device_ctx {
+                    self.remove_hid_event_handler(&event_handler);
+                }
                 UsbControlResult::NoAvailablePort
             }
         }
     }
 
+    fn remove_hid_event_handler(&self, event_handler: &HidEventHandler) {
+        if let Err(e) = self.event_loop.remove_event_for_descriptor(event_handler) {
+            error!("failed to remove HID input source from event loop: {}", e);
+        }
+    }
+
     fn handle_detach_device(&self, port: u8) -> UsbControlResult {
         match self.usb_hub.disconnect_port(port) {
             Ok(()) => {
-                if let Some(device_ctx) = self.devices.lock().remove(&port) {
-                    let _ = device_ctx.event_handler.on_event();
-                    let device = device_ctx.device.lock();
-                    let descriptor = device.fd();
-
-                    if let Err(e) = self.event_loop.remove_event_for_descriptor(&*descriptor) {
-                        error!(
-                            "failed to remove poll change handler from event loop: {}",
-                            e
-                        );
+                match self.devices.lock().remove(&port) {
+                    Some(DeviceContext::Host {
+                        event_handler,
+                        device,
+                    }) => {
+                        let _ = event_handler.on_event();
+                        let device = device.lock();
+                        let descriptor = device.fd();
+
+                        if let Err(e) = self.event_loop.remove_event_for_descriptor(&*descriptor) {
+                            error!(
+                                "failed to remove poll change handler from event loop: {}",
+                                e
+                            );
+                        }
                     }
+                    Some(DeviceContext::Hid { event_handler }) => {
+                        self.remove_hid_event_handler(&event_handler);
+                    }
+                    Some(DeviceContext::MassStorage) | None => {}
                 }
                 UsbControlResult::Ok { port }
             }
@@ -273,6 +360,12 @@ impl ProviderInner {
             UsbControlCommand::AttachDevice { file } => self.handle_attach_device(file),
             UsbControlCommand::DetachDevice { port } => self.handle_detach_device(port),
             UsbControlCommand::ListDevice { ports } => self.handle_list_devices(ports),
+            UsbControlCommand::AttachMassStorage { file, read_only } => {
+                self.handle_attach_mass_storage(file, read_only)
+            }
+            UsbControlCommand::AttachHidDevice { device, source } => {
+                self.handle_attach_hid_device(device, source)
+            }
         };
         tube.send(&result).map_err(Error::WriteControlTube)?;
         Ok(())
@@ -4,5 +4,6 @@
 
 #[macro_use]
 mod log;
+pub mod emulated;
 pub mod host_backend;
 pub mod xhci;
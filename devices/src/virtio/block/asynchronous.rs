@@ -14,8 +14,12 @@ use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
+use std::time::Instant;
 use std::u32;
 
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
 use base::error;
 use base::info;
 use base::warn;
@@ -42,18 +46,24 @@ use data_model::Le32;
 use data_model::Le64;
 use disk::AsyncDisk;
 use disk::DiskFile;
+use futures::channel::mpsc;
 use futures::pin_mut;
 use futures::stream::FuturesUnordered;
 use futures::stream::StreamExt;
 use remain::sorted;
+use serde::Deserialize;
+use serde::Serialize;
 use sync::Mutex;
 use thiserror::Error as ThisError;
 use vm_control::DiskControlCommand;
 use vm_control::DiskControlResult;
+use vm_control::DiskThrottleConfig;
 use vm_memory::GuestMemory;
 
 use crate::virtio::async_utils;
 use crate::virtio::block::sys::*;
+use crate::virtio::block::throttle::Throttle;
+use crate::virtio::block::throttle::ThrottleDirection;
 use crate::virtio::copy_config;
 use crate::virtio::device_constants::block::virtio_blk_config;
 use crate::virtio::device_constants::block::virtio_blk_discard_write_zeroes;
@@ -81,6 +91,7 @@ use crate::virtio::DescriptorError;
 use crate::virtio::DeviceType;
 use crate::virtio::Interrupt;
 use crate::virtio::Queue;
+use crate::virtio::QueueSnapshot;
 use crate::virtio::Reader;
 use crate::virtio::SignalableInterrupt;
 use crate::virtio::VirtioDevice;
@@ -204,6 +215,8 @@ pub struct DiskState {
     pub read_only: bool,
     pub sparse: bool,
     pub id: Option<BlockId>,
+    /// Shared with the `BlockAsync` so that changes to the I/O limits outlive the worker.
+    pub throttle: Arc<Mutex<Throttle>>,
 }
 
 impl DiskState {
@@ -214,6 +227,7 @@ impl DiskState {
         read_only: bool,
         sparse: bool,
         id: Option<BlockId>,
+        throttle: Arc<Mutex<Throttle>>,
     ) -> DiskState {
         DiskState {
             disk_image,
@@ -221,6 +235,7 @@ impl DiskState {
             read_only,
             sparse,
             id,
+            throttle,
         }
     }
 }
@@ -269,6 +284,51 @@ async fn process_one_request(
     Ok(available_bytes)
 }
 
+// Returns the direction and the amount of data of the request in `avail_desc`, or `None` if the
+// request is not subject to I/O limits. Malformed requests are left for `execute_request` to
+// reject.
+fn throttled_request(
+    mem: &GuestMemory,
+    avail_desc: &DescriptorChain,
+) -> Option<(ThrottleDirection, u64)> {
+    let mut reader = Reader::new(mem.clone(), avail_desc.clone()).ok()?;
+    let req_header: virtio_blk_req_header = reader.read_obj().ok()?;
+    match req_header.req_type.to_native() {
+        VIRTIO_BLK_T_IN => {
+            let writer = Writer::new(mem.clone(), avail_desc.clone()).ok()?;
+            // The last byte of the buffer is the status, not data.
+            let data_len = writer.available_bytes().saturating_sub(1);
+            Some((ThrottleDirection::Read, data_len as u64))
+        }
+        VIRTIO_BLK_T_OUT => Some((ThrottleDirection::Write, reader.available_bytes() as u64)),
+        VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => Some((ThrottleDirection::Write, 0)),
+        _ => None,
+    }
+}
+
+// Holds back the request in `avail_desc` for as long as the I/O limits of the disk require.
+async fn throttle_request(
+    ex: &Executor,
+    mem: &GuestMemory,
+    avail_desc: &DescriptorChain,
+    disk_state: &Rc<AsyncMutex<DiskState>>,
+) {
+    let throttle = Arc::clone(&disk_state.read_lock().await.throttle);
+    if !throttle.lock().is_enabled() {
+        return;
+    }
+    let (direction, bytes) = match throttled_request(mem, avail_desc) {
+        Some(request) => request,
+        None => return,
+    };
+    let delay = throttle.lock().delay(direction, bytes, Instant::now());
+    if !delay.is_zero() {
+        if let Err(e) = TimerAsync::sleep(ex, delay).await {
+            error!("failed to wait for the disk I/O limits: {}", e);
+        }
+    }
+}
+
 /// Process one descriptor chain asynchronously.
 pub async fn process_one_chain<I: SignalableInterrupt>(
     queue: Rc<RefCell<Queue>>,
@@ -299,6 +359,8 @@ pub async fn process_one_chain<I: SignalableInterrupt>(
 // There is one async task running `handle_queue` per virtio queue in use.
 // Receives messages from the guest and queues a task to complete the operations with the async
 // executor.
+//
+// Each request in flight holds a clone of `inflight`, if any, until it has been completed.
 pub async fn handle_queue<I: SignalableInterrupt + 'static>(
     ex: Executor,
     mem: GuestMemory,
@@ -308,6 +370,7 @@ pub async fn handle_queue<I: SignalableInterrupt + 'static>(
     interrupt: I,
     flush_timer: Rc<RefCell<TimerAsync>>,
     flush_timer_armed: Rc<RefCell<bool>>,
+    inflight: Option<mpsc::Sender<()>>,
 ) {
     loop {
         if let Err(e) = evt.next_val().await {
@@ -321,8 +384,12 @@ pub async fn handle_queue<I: SignalableInterrupt + 'static>(
             let interrupt = interrupt.clone();
             let flush_timer = Rc::clone(&flush_timer);
             let flush_timer_armed = Rc::clone(&flush_timer_armed);
+            let task_ex = ex.clone();
+            let inflight = inflight.clone();
 
             ex.spawn_local(async move {
+                let _inflight = inflight;
+                throttle_request(&task_ex, &mem, &descriptor_chain, &disk_state).await;
                 process_one_chain(
                     queue,
                     descriptor_chain,
@@ -374,18 +441,24 @@ async fn handle_command_tube(
     loop {
         match command_tube.next().await {
             Ok(command) => {
+                // Only a resize changes the config space the guest sees.
+                let resized = matches!(command, DiskControlCommand::Resize { .. });
                 let resp = match command {
                     DiskControlCommand::Resize { new_size } => {
                         resize(Rc::clone(&disk_state), new_size).await
                     }
+                    DiskControlCommand::SetThrottle { config } => {
+                        set_throttle(Rc::clone(&disk_state), config).await
+                    }
+                    command => snapshot_command(Rc::clone(&disk_state), command).await,
                 };
+                let config_changed = matches!(resp, DiskControlResult::Ok) && resized;
 
-                let resp_clone = resp.clone();
                 command_tube
-                    .send(resp_clone)
+                    .send(resp)
                     .await
                     .map_err(ExecuteError::SendingResponse)?;
-                if let DiskControlResult::Ok = resp {
+                if config_changed {
                     match &signal {
                         ConfigChangeSignal::Interrupt(interrupt) => {
                             interrupt.signal_config_changed();
@@ -441,6 +514,64 @@ async fn resize(disk_state: Rc<AsyncMutex<DiskState>>, new_size: u64) -> DiskCon
     DiskControlResult::Ok
 }
 
+async fn set_throttle(
+    disk_state: Rc<AsyncMutex<DiskState>>,
+    config: DiskThrottleConfig,
+) -> DiskControlResult {
+    info!("Setting block device I/O limits to {:?}", config);
+    // The buckets start out full, so the new limits apply from now on.
+    *disk_state.read_lock().await.throttle.lock() = Throttle::new(config);
+    DiskControlResult::Ok
+}
+
+async fn snapshot_command(
+    disk_state: Rc<AsyncMutex<DiskState>>,
+    command: DiskControlCommand,
+) -> DiskControlResult {
+    // Hold exclusive access to the state so no request is in flight while the snapshots change.
+    let mut disk_state = disk_state.lock().await;
+
+    if disk_state.read_only && !matches!(command, DiskControlCommand::ListSnapshots) {
+        error!("Attempted to modify the snapshots of a read-only block device");
+        return DiskControlResult::Err(SysError::new(libc::EROFS));
+    }
+
+    let disk_image = &mut disk_state.disk_image;
+    let result = match &command {
+        DiskControlCommand::CreateSnapshot { name } => {
+            info!("Creating disk snapshot {}", name);
+            disk_image
+                .create_snapshot(name)
+                .map(|_| DiskControlResult::Ok)
+        }
+        DiskControlCommand::ListSnapshots => disk_image
+            .list_snapshots()
+            .map(DiskControlResult::Snapshots),
+        DiskControlCommand::ApplySnapshot { snapshot } => {
+            info!("Applying disk snapshot {}", snapshot);
+            disk_image
+                .apply_snapshot(snapshot)
+                .map(|_| DiskControlResult::Ok)
+        }
+        DiskControlCommand::DeleteSnapshot { snapshot } => {
+            info!("Deleting disk snapshot {}", snapshot);
+            disk_image
+                .delete_snapshot(snapshot)
+                .map(|_| DiskControlResult::Ok)
+        }
+        _ => unreachable!("not a snapshot command: {}", command),
+    };
+
+    result.unwrap_or_else(|e| {
+        error!("{} failed: {}", command, e);
+        let errno = match e {
+            disk::Error::SnapshotsNotSupported => libc::ENOTSUP,
+            _ => libc::EIO,
+        };
+        DiskControlResult::Err(SysError::new(errno))
+    })
+}
+
 /// Periodically flushes the disk when the given timer fires.
 pub async fn flush_disk(
     disk_state: Rc<AsyncMutex<DiskState>>,
@@ -467,6 +598,31 @@ pub async fn flush_disk(
     }
 }
 
+// Waits for the requests that are still in flight on `queues` to complete, then flushes the disk
+// and hands the queues back. Called once the worker tasks have stopped so that no descriptor is
+// left popped from a queue without being added to its used ring.
+async fn quiesce(
+    disk_state: &Rc<AsyncMutex<DiskState>>,
+    queues: Vec<Rc<RefCell<Queue>>>,
+    mut inflight: mpsc::Receiver<()>,
+) -> Vec<Queue> {
+    // Nothing is ever sent on the channel: it is closed once every in-flight request has been
+    // completed and has dropped its sender.
+    while inflight.next().await.is_some() {}
+
+    if let Err(e) = disk_state.read_lock().await.disk_image.fsync().await {
+        error!("failed to flush a disk: {}", e);
+    }
+
+    queues
+        .into_iter()
+        .map(|q| match Rc::try_unwrap(q) {
+            Ok(q) => q.into_inner(),
+            Err(_) => panic!("too many refs to a queue"),
+        })
+        .collect()
+}
+
 // The main worker thread. Initialized the asynchronous worker tasks and passes them to the executor
 // to be processed.
 //
@@ -476,12 +632,13 @@ pub async fn flush_disk(
 fn run_worker(
     ex: Executor,
     interrupt: Interrupt,
-    queues: Vec<Queue>,
+    queues: &[Rc<RefCell<Queue>>],
     mem: GuestMemory,
     disk_state: &Rc<AsyncMutex<DiskState>>,
     control_tube: &Option<AsyncTube>,
     queue_evts: Vec<Event>,
     kill_evt: Event,
+    inflight: mpsc::Sender<()>,
 ) -> Result<(), String> {
     if queues.len() != queue_evts.len() {
         return Err("Number of queues and events must match.".to_string());
@@ -514,8 +671,7 @@ fn run_worker(
     ));
 
     let queue_handlers = queues
-        .into_iter()
-        .map(|q| Rc::new(RefCell::new(q)))
+        .iter()
         .zip(
             queue_evts
                 .into_iter()
@@ -526,11 +682,12 @@ fn run_worker(
                 ex.clone(),
                 mem.clone(),
                 Rc::clone(disk_state),
-                Rc::clone(&queue),
+                Rc::clone(queue),
                 event,
                 interrupt.clone(),
                 Rc::clone(&flush_timer),
                 Rc::clone(&flush_timer_armed),
+                Some(inflight.clone()),
             )
         })
         .collect::<FuturesUnordered<_>>()
@@ -576,8 +733,31 @@ pub struct BlockAsync {
     pub(crate) control_tube: Option<Tube>,
     pub(crate) queue_sizes: Vec<u16>,
     pub(crate) executor_kind: ExecutorKind,
+    pub(crate) throttle: Arc<Mutex<Throttle>>,
+    acked_features: u64,
     kill_evt: Option<Event>,
-    worker_thread: Option<thread::JoinHandle<(Box<dyn DiskFile>, Option<Tube>)>>,
+    worker_thread: Option<thread::JoinHandle<WorkerReturn>>,
+    // Resources handed over by `activate`, kept so that `wake` can restart the worker.
+    worker_resources: Option<WorkerResources>,
+    // Queues taken back from the worker by `sleep`, or loaded by `restore`.
+    sleeping_queues: Option<Vec<Queue>>,
+}
+
+// Resources handed back by the worker thread when it exits.
+type WorkerReturn = (Box<dyn DiskFile>, Option<Tube>, Vec<Queue>);
+
+struct WorkerResources {
+    mem: GuestMemory,
+    interrupt: Interrupt,
+    queue_evts: Vec<Event>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BlockAsyncSnapshot {
+    acked_features: u64,
+    disk_size: u64,
+    // `None` if the device was not activated.
+    queues: Option<Vec<QueueSnapshot>>,
 }
 
 impl BlockAsync {
@@ -593,6 +773,7 @@ impl BlockAsync {
         queue_size: Option<u16>,
         executor_kind: Option<ExecutorKind>,
         num_queues: Option<u16>,
+        throttle: DiskThrottleConfig,
     ) -> SysResult<BlockAsync> {
         if block_size % SECTOR_SIZE as u32 != 0 {
             error!(
@@ -638,13 +819,146 @@ impl BlockAsync {
             block_size,
             id,
             queue_sizes,
+            acked_features: 0,
             kill_evt: None,
             worker_thread: None,
+            worker_resources: None,
+            sleeping_queues: None,
             control_tube,
             executor_kind,
+            throttle: Arc::new(Mutex::new(Throttle::new(throttle))),
         })
     }
 
+    /// Spawns the worker thread that processes `queues`.
+    fn start_worker(&mut self, queues: Vec<Queue>) {
+        let resources = match &self.worker_resources {
+            Some(r) => r,
+            None => {
+                error!("{}: no resources to start the worker", self.debug_label());
+                return;
+            }
+        };
+        let mem = resources.mem.clone();
+        let interrupt = resources.interrupt.clone();
+        let queue_evts = match resources
+            .queue_evts
+            .iter()
+            .map(|e| e.try_clone())
+            .collect::<SysResult<Vec<Event>>>()
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed to clone queue events: {}", e);
+                return;
+            }
+        };
+
+        let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed creating kill Event pair: {}", e);
+                return;
+            }
+        };
+        self.kill_evt = Some(self_kill_evt);
+
+        let read_only = self.read_only;
+        let sparse = self.sparse;
+        let disk_size = self.disk_size.clone();
+        let id = self.id;
+        let executor_kind = self.executor_kind;
+        let throttle = Arc::clone(&self.throttle);
+        if let Some(disk_image) = self.disk_image.take() {
+            let control_tube = self.control_tube.take();
+            let worker_result =
+                thread::Builder::new()
+                    .name("virtio_blk".to_string())
+                    .spawn(move || {
+                        let ex = Executor::with_executor_kind(executor_kind)
+                            .expect("Failed to create an executor");
+
+                        let async_control = control_tube
+                            .map(|c| AsyncTube::new(&ex, c).expect("failed to create async tube"));
+                        let async_image = match disk_image.to_async_disk(&ex) {
+                            Ok(d) => d,
+                            Err(e) => panic!("Failed to create async disk {}", e),
+                        };
+                        let disk_state = Rc::new(AsyncMutex::new(DiskState {
+                            disk_image: async_image,
+                            disk_size,
+                            read_only,
+                            sparse,
+                            id,
+                            throttle,
+                        }));
+                        let queues: Vec<_> = queues
+                            .into_iter()
+                            .map(|q| Rc::new(RefCell::new(q)))
+                            .collect();
+                        let (inflight_tx, inflight_rx) = mpsc::channel(0);
+                        if let Err(err_string) = run_worker(
+                            ex.clone(),
+                            interrupt,
+                            &queues,
+                            mem,
+                            &disk_state,
+                            &async_control,
+                            queue_evts,
+                            kill_evt,
+                            inflight_tx,
+                        ) {
+                            error!("{}", err_string);
+                        }
+
+                        let queues = match ex.run_until(quiesce(&disk_state, queues, inflight_rx)) {
+                            Ok(q) => q,
+                            Err(e) => panic!("failed to complete in-flight requests: {}", e),
+                        };
+
+                        let disk_state = match Rc::try_unwrap(disk_state) {
+                            Ok(d) => d.into_inner(),
+                            Err(_) => panic!("too many refs to the disk"),
+                        };
+                        (
+                            disk_state.disk_image.into_inner(),
+                            async_control.map(|c| c.into()),
+                            queues,
+                        )
+                    });
+
+            self.worker_thread = match worker_result {
+                Err(e) => {
+                    error!("failed to spawn virtio_blk worker: {}", e);
+                    return;
+                }
+                Ok(join_handle) => Some(join_handle),
+            }
+        }
+    }
+
+    /// Stops the worker thread and takes back its resources. Returns the queues it was
+    /// processing, or `None` if no worker was running.
+    fn stop_worker(&mut self) -> anyhow::Result<Option<Vec<Queue>>> {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            kill_evt
+                .signal()
+                .context("failed to notify the kill event")?;
+        }
+
+        match self.worker_thread.take() {
+            Some(worker_thread) => match worker_thread.join() {
+                Ok((disk_image, control_tube, queues)) => {
+                    self.disk_image = Some(disk_image);
+                    self.control_tube = control_tube;
+                    Ok(Some(queues))
+                }
+                Err(_) => Err(anyhow!("failed to get back resources")),
+            },
+            None => Ok(None),
+        }
+    }
+
     /// Returns the feature flags given the specified attributes.
     fn build_avail_features(
         base_features: u64,
@@ -913,6 +1227,14 @@ impl VirtioDevice for BlockAsync {
         copy_config(data, 0, config_space.as_slice(), offset);
     }
 
+    fn ack_features(&mut self, mut value: u64) {
+        if value & !self.avail_features != 0 {
+            warn!("virtio_blk got unknown feature ack {:x}", value);
+            value &= self.avail_features;
+        }
+        self.acked_features |= value;
+    }
+
     fn activate(
         &mut self,
         mem: GuestMemory,
@@ -920,102 +1242,127 @@ impl VirtioDevice for BlockAsync {
         queues: Vec<Queue>,
         queue_evts: Vec<Event>,
     ) {
-        let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
-            Ok(v) => v,
-            Err(e) => {
-                error!("failed creating kill Event pair: {}", e);
+        if let Some(restored_queues) = &self.sleeping_queues {
+            if restored_queues.len() != queue_evts.len() {
+                error!(
+                    "virtio_blk restored {} queues but was activated with {}",
+                    restored_queues.len(),
+                    queue_evts.len()
+                );
                 return;
             }
-        };
-        self.kill_evt = Some(self_kill_evt);
+        }
 
-        let read_only = self.read_only;
-        let sparse = self.sparse;
-        let disk_size = self.disk_size.clone();
-        let id = self.id.take();
-        let executor_kind = self.executor_kind;
-        if let Some(disk_image) = self.disk_image.take() {
-            let control_tube = self.control_tube.take();
-            let worker_result =
-                thread::Builder::new()
-                    .name("virtio_blk".to_string())
-                    .spawn(move || {
-                        let ex = Executor::with_executor_kind(executor_kind)
-                            .expect("Failed to create an executor");
+        self.worker_resources = Some(WorkerResources {
+            mem,
+            interrupt,
+            queue_evts,
+        });
+        // A device restored from a snapshot before being activated carries on from the restored
+        // queues once it is woken up.
+        if self.sleeping_queues.is_none() {
+            self.start_worker(queues);
+        }
+    }
 
-                        let async_control = control_tube
-                            .map(|c| AsyncTube::new(&ex, c).expect("failed to create async tube"));
-                        let async_image = match disk_image.to_async_disk(&ex) {
-                            Ok(d) => d,
-                            Err(e) => panic!("Failed to create async disk {}", e),
-                        };
-                        let disk_state = Rc::new(AsyncMutex::new(DiskState {
-                            disk_image: async_image,
-                            disk_size,
-                            read_only,
-                            sparse,
-                            id,
-                        }));
-                        if let Err(err_string) = run_worker(
-                            ex,
-                            interrupt,
-                            queues,
-                            mem,
-                            &disk_state,
-                            &async_control,
-                            queue_evts,
-                            kill_evt,
-                        ) {
-                            error!("{}", err_string);
-                        }
+    fn reset(&mut self) -> bool {
+        self.acked_features = 0;
+        self.worker_resources = None;
+        if self.sleeping_queues.take().is_some() {
+            return true;
+        }
+        match self.stop_worker() {
+            Ok(queues) => queues.is_some(),
+            Err(e) => {
+                error!("{}: {:#}", self.debug_label(), e);
+                false
+            }
+        }
+    }
+}
 
-                        let disk_state = match Rc::try_unwrap(disk_state) {
-                            Ok(d) => d.into_inner(),
-                            Err(_) => panic!("too many refs to the disk"),
-                        };
-                        (
-                            disk_state.disk_image.into_inner(),
-                            async_control.map(|c| c.into()),
-                        )
-                    });
+impl Suspendable for BlockAsync {
+    fn sleep(&mut self) -> anyhow::Result<()> {
+        if let Some(queues) = self.stop_worker()? {
+            self.sleeping_queues = Some(queues);
+        }
+        Ok(())
+    }
 
-            self.worker_thread = match worker_result {
-                Err(e) => {
-                    error!("failed to spawn virtio_blk worker: {}", e);
-                    return;
-                }
-                Ok(join_handle) => Some(join_handle),
-            }
+    fn wake(&mut self) -> anyhow::Result<()> {
+        if let Some(queues) = self.sleeping_queues.take() {
+            self.start_worker(queues);
         }
+        Ok(())
     }
 
-    fn reset(&mut self) -> bool {
-        if let Some(kill_evt) = self.kill_evt.take() {
-            if kill_evt.signal().is_err() {
-                error!("{}: failed to notify the kill event", self.debug_label());
-                return false;
-            }
+    fn snapshot(&self) -> anyhow::Result<String> {
+        if self.worker_thread.is_some() {
+            bail!("virtio_blk must be asleep to be snapshotted");
         }
+        serde_json::to_string(&BlockAsyncSnapshot {
+            acked_features: self.acked_features,
+            disk_size: self.disk_size.load(Ordering::Acquire),
+            queues: self
+                .sleeping_queues
+                .as_ref()
+                .map(|queues| queues.iter().map(Queue::snapshot).collect()),
+        })
+        .context("failed to serialize virtio_blk snapshot")
+    }
 
-        if let Some(worker_thread) = self.worker_thread.take() {
-            match worker_thread.join() {
-                Err(_) => {
-                    error!("{}: failed to get back resources", self.debug_label());
-                    return false;
+    fn restore(&mut self, data: &str) -> anyhow::Result<()> {
+        if self.worker_thread.is_some() {
+            bail!("virtio_blk must be asleep to be restored");
+        }
+        let snapshot: BlockAsyncSnapshot =
+            serde_json::from_str(data).context("failed to deserialize virtio_blk snapshot")?;
+
+        let disk_image = self
+            .disk_image
+            .as_ref()
+            .context("virtio_blk has no disk image")?;
+        let disk_size = disk_image.get_len().context("failed to get disk size")?;
+        if disk_size != snapshot.disk_size {
+            bail!(
+                "disk size {} does not match the snapshot's {}",
+                disk_size,
+                snapshot.disk_size
+            );
+        }
+        if let Some(queues) = &snapshot.queues {
+            match &self.worker_resources {
+                Some(resources) => {
+                    if queues.len() != resources.queue_evts.len() {
+                        bail!(
+                            "snapshot has {} queues but virtio_blk was activated with {}",
+                            queues.len(),
+                            resources.queue_evts.len()
+                        );
+                    }
                 }
-                Ok((disk_image, control_tube)) => {
-                    self.disk_image = Some(disk_image);
-                    self.control_tube = control_tube;
-                    return true;
+                // The number of queues is checked again when the device is activated.
+                None => {
+                    if queues.len() > self.queue_sizes.len() {
+                        bail!(
+                            "snapshot has {} queues but virtio_blk has {}",
+                            queues.len(),
+                            self.queue_sizes.len()
+                        );
+                    }
                 }
             }
         }
-        false
+
+        self.acked_features = snapshot.acked_features;
+        self.disk_size.store(snapshot.disk_size, Ordering::Release);
+        self.sleeping_queues = snapshot
+            .queues
+            .map(|queues| queues.iter().map(Queue::restore).collect());
+        Ok(())
     }
 }
 
-impl Suspendable for BlockAsync {}
-
 #[cfg(test)]
 mod tests {
     use std::fs::File;
@@ -1034,6 +1381,8 @@ mod tests {
     use crate::virtio::base_features;
     use crate::virtio::descriptor_utils::create_descriptor_chain;
     use crate::virtio::descriptor_utils::DescriptorType;
+    use crate::virtio::VIRTIO_MSI_NO_VECTOR;
+    use crate::IrqLevelEvent;
 
     #[test]
     fn read_size() {
@@ -1055,6 +1404,7 @@ mod tests {
             None,
             None,
             None,
+            Default::default(),
         )
         .unwrap();
         let mut num_sectors = [0u8; 4];
@@ -1087,6 +1437,7 @@ mod tests {
             None,
             None,
             None,
+            Default::default(),
         )
         .unwrap();
         let mut blk_size = [0u8; 4];
@@ -1116,12 +1467,14 @@ mod tests {
                 None,
                 None,
                 None,
+                Default::default(),
             )
             .unwrap();
             // writable device should set VIRTIO_BLK_F_FLUSH + VIRTIO_BLK_F_DISCARD
             // + VIRTIO_BLK_F_WRITE_ZEROES + VIRTIO_F_VERSION_1 + VIRTIO_BLK_F_BLK_SIZE
             // + VIRTIO_BLK_F_SEG_MAX + VIRTIO_BLK_F_MQ + VIRTIO_RING_F_EVENT_IDX
-            assert_eq!(0x120007244, b.features());
+            // + VIRTIO_F_RING_PACKED
+            assert_eq!(0x520007244, b.features());
         }
 
         // read-write block device, non-sparse
@@ -1139,12 +1492,13 @@ mod tests {
                 None,
                 None,
                 None,
+                Default::default(),
             )
             .unwrap();
             // writable device should set VIRTIO_F_FLUSH + VIRTIO_BLK_F_RO
             // + VIRTIO_F_VERSION_1 + VIRTIO_BLK_F_BLK_SIZE + VIRTIO_BLK_F_SEG_MAX
-            // + VIRTIO_BLK_F_MQ + VIRTIO_RING_F_EVENT_IDX
-            assert_eq!(0x120005244, b.features());
+            // + VIRTIO_BLK_F_MQ + VIRTIO_RING_F_EVENT_IDX + VIRTIO_F_RING_PACKED
+            assert_eq!(0x520005244, b.features());
         }
 
         // read-only block device
@@ -1162,12 +1516,13 @@ mod tests {
                 None,
                 None,
                 None,
+                Default::default(),
             )
             .unwrap();
             // read-only device should set VIRTIO_BLK_F_RO
             // + VIRTIO_F_VERSION_1 + VIRTIO_BLK_F_BLK_SIZE + VIRTIO_BLK_F_SEG_MAX
-            // + VIRTIO_BLK_F_MQ + VIRTIO_RING_F_EVENT_IDX
-            assert_eq!(0x120001064, b.features());
+            // + VIRTIO_BLK_F_MQ + VIRTIO_RING_F_EVENT_IDX + VIRTIO_F_RING_PACKED
+            assert_eq!(0x520001064, b.features());
         }
     }
 
@@ -1191,6 +1546,7 @@ mod tests {
             None,
             None,
             None,
+            Default::default(),
         )
         .unwrap();
         assert_eq!(
@@ -1211,6 +1567,7 @@ mod tests {
             Some(128),
             None,
             Some(1),
+            Default::default(),
         )
         .unwrap();
         assert_eq!([128; 1], b.queue_max_sizes());
@@ -1276,6 +1633,7 @@ mod tests {
             read_only: false,
             sparse: true,
             id: None,
+            throttle: Arc::new(Mutex::new(Throttle::default())),
         }));
 
         let fut = process_one_request(avail_desc, disk_state, flush_timer, flush_timer_armed, &mem);
@@ -1345,6 +1703,7 @@ mod tests {
             read_only: false,
             sparse: true,
             id: None,
+            throttle: Arc::new(Mutex::new(Throttle::default())),
         }));
 
         let fut = process_one_request(avail_desc, disk_state, flush_timer, flush_timer_armed, &mem);
@@ -1416,6 +1775,7 @@ mod tests {
             read_only: false,
             sparse: true,
             id: Some(*id),
+            throttle: Arc::new(Mutex::new(Throttle::default())),
         }));
 
         let fut = process_one_request(avail_desc, disk_state, flush_timer, flush_timer_armed, &mem);
@@ -1432,4 +1792,98 @@ mod tests {
         let returned_id = mem.read_obj_from_addr::<[u8; 20]>(id_offset).unwrap();
         assert_eq!(returned_id, *id);
     }
+
+    #[test]
+    fn sleep_snapshot_restore_wake() {
+        let tempdir = TempDir::new().unwrap();
+        let mut path = tempdir.path().to_owned();
+        path.push("disk_image");
+        let f = File::create(&path).unwrap();
+        f.set_len(0x1000).unwrap();
+
+        let features = base_features(ProtectionType::Unprotected);
+        let mut b = BlockAsync::new(
+            features,
+            Box::new(f),
+            false,
+            false,
+            512,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Default::default(),
+        )
+        .unwrap();
+        // Snapshotting a device that was never activated only records its features.
+        b.sleep().unwrap();
+        let inactive = b.snapshot().unwrap();
+
+        let mem = GuestMemory::new(&[(GuestAddress(0u64), 4 * 1024 * 1024)])
+            .expect("Creating guest memory failed.");
+        let mut q = Queue::new(DEFAULT_QUEUE_SIZE);
+        q.set_desc_table(GuestAddress(0x1000));
+        q.set_avail_ring(GuestAddress(0x2000));
+        q.set_used_ring(GuestAddress(0x3000));
+        q.set_ready(true);
+        b.ack_features(1 << VIRTIO_BLK_F_FLUSH);
+        b.activate(
+            mem,
+            Interrupt::new(IrqLevelEvent::new().unwrap(), None, VIRTIO_MSI_NO_VECTOR),
+            vec![q],
+            vec![Event::new().unwrap()],
+        );
+
+        assert!(b.snapshot().is_err(), "snapshot of a running device");
+        b.sleep().unwrap();
+        b.sleep().unwrap();
+        let active = b.snapshot().unwrap();
+        let state: BlockAsyncSnapshot = serde_json::from_str(&active).unwrap();
+        assert_eq!(state.acked_features, 1 << VIRTIO_BLK_F_FLUSH);
+        assert_eq!(state.disk_size, 0x1000);
+        assert_eq!(state.queues.map(|q| q.len()), Some(1));
+
+        b.restore(&inactive).unwrap();
+        assert_eq!(b.snapshot().unwrap(), inactive);
+        b.restore(&active).unwrap();
+        assert_eq!(b.snapshot().unwrap(), active);
+        b.wake().unwrap();
+        assert!(b.reset());
+
+        // A device restored before being activated keeps the restored queues until it is woken.
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut restored = BlockAsync::new(
+            features,
+            Box::new(f),
+            false,
+            false,
+            512,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Default::default(),
+        )
+        .unwrap();
+        restored.sleep().unwrap();
+        restored.restore(&active).unwrap();
+        let mem = GuestMemory::new(&[(GuestAddress(0u64), 4 * 1024 * 1024)])
+            .expect("Creating guest memory failed.");
+        restored.activate(
+            mem,
+            Interrupt::new(IrqLevelEvent::new().unwrap(), None, VIRTIO_MSI_NO_VECTOR),
+            vec![Queue::new(DEFAULT_QUEUE_SIZE)],
+            vec![Event::new().unwrap()],
+        );
+        assert_eq!(restored.snapshot().unwrap(), active);
+        restored.wake().unwrap();
+        assert!(restored.snapshot().is_err(), "snapshot of a running device");
+        assert!(restored.reset());
+    }
 }
@@ -10,6 +10,7 @@ use cros_async::ExecutorKind;
 use serde::Deserialize;
 use serde::Deserializer;
 use serde::Serialize;
+use vm_control::DiskThrottleConfig;
 
 fn block_option_sparse_default() -> bool {
     true
@@ -80,6 +81,9 @@ pub struct DiskOption {
     /// precedence over the async executor kind specified by the subcommand's option.
     /// If None, the default or the specified by the subcommand's option would be used.
     pub async_executor: Option<ExecutorKind>,
+    #[serde(default)]
+    /// Limits on the rate of I/O the guest can issue to this disk.
+    pub throttle: DiskThrottleConfig,
 }
 
 #[cfg(test)]
@@ -119,6 +123,7 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
             }
         );
 
@@ -137,6 +142,7 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
             }
         );
 
@@ -155,6 +161,7 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
             }
         );
 
@@ -173,6 +180,7 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
             }
         );
 
@@ -191,6 +199,7 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
             }
         );
         let params = from_block_arg("/some/path.img,sparse=false").unwrap();
@@ -207,6 +216,7 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
             }
         );
 
@@ -225,6 +235,7 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
             }
         );
 
@@ -243,6 +254,7 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
             }
         );
 
@@ -261,6 +273,7 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
             }
         );
 
@@ -279,6 +292,7 @@ mod tests {
                 async_executor: None,
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
+                throttle: Default::default(),
             }
         );
 
@@ -298,6 +312,7 @@ mod tests {
                     id: None,
                     io_concurrency: NonZeroU32::new(4).unwrap(),
                     async_executor: None,
+                    throttle: Default::default(),
                 }
             );
         }
@@ -317,6 +332,7 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
             }
         );
         let err = from_block_arg("/some/path.img,id=DISK_ID_IS_WAY_TOO_LONG").unwrap_err();
@@ -348,13 +364,40 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: Some(ex_kind),
+                throttle: Default::default(),
+            }
+        );
+
+        // throttle
+        let params =
+            from_block_arg("/some/path.img,throttle=[read-iops=100,write-bps=1048576]").unwrap();
+        assert_eq!(
+            params,
+            DiskOption {
+                path: "/some/path.img".into(),
+                read_only: false,
+                root: false,
+                sparse: true,
+                direct: false,
+                block_size: 512,
+                id: None,
+                #[cfg(windows)]
+                io_concurrency: NonZeroU32::new(1).unwrap(),
+                async_executor: None,
+                throttle: DiskThrottleConfig {
+                    read_iops: 100,
+                    write_bps: 1048576,
+                    ..Default::default()
+                },
             }
         );
+        let err = from_block_arg("/some/path.img,throttle=[iops=100]").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::SerdeError(_)));
 
         // All together
         let params = from_block_arg(&format!(
             "/some/path.img,block_size=256,ro,root,sparse=false,id=DISK_LABEL\
-            ,direct,async-executor={ex_kind_opt}"
+            ,direct,async-executor={ex_kind_opt},throttle=[write-iops=50,write-iops-burst=200]"
         ))
         .unwrap();
         assert_eq!(
@@ -370,6 +413,11 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: Some(ex_kind),
+                throttle: DiskThrottleConfig {
+                    write_iops: 50,
+                    write_iops_burst: 200,
+                    ..Default::default()
+                },
             }
         );
     }
@@ -5,6 +5,7 @@
 pub mod asynchronous;
 pub mod block;
 pub(crate) mod sys;
+pub mod throttle;
 
 pub use asynchronous::BlockAsync;
 pub use asynchronous::DiskState;
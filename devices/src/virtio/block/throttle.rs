@@ -0,0 +1,186 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Rate limiting of the requests a block device accepts from the guest.
+
+use std::time::Duration;
+use std::time::Instant;
+
+use vm_control::DiskThrottleConfig;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// A token bucket that refills at `rate` tokens per second, up to `capacity` tokens.
+///
+/// Taking more tokens than are available puts the bucket in debt instead of failing, and the
+/// caller is told how long to wait for the debt to be repaid. That way a request larger than the
+/// whole bucket is still let through, just late.
+struct TokenBucket {
+    rate: u64,
+    capacity: u64,
+    // Available tokens in billionths of a token, so that refills are exact. Negative while in debt.
+    level: i128,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, burst: u64, now: Instant) -> TokenBucket {
+        let capacity = if burst == 0 { rate } else { burst };
+        TokenBucket {
+            rate,
+            capacity,
+            level: capacity as i128 * NANOS_PER_SEC as i128,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_nanos();
+        let missing = (self.capacity as i128 * NANOS_PER_SEC as i128 - self.level).max(0) as u128;
+        self.level += (elapsed * self.rate as u128).min(missing) as i128;
+        self.last_refill = now;
+    }
+
+    // Takes `tokens` from the bucket and returns how long the caller has to wait before going
+    // ahead.
+    fn take(&mut self, tokens: u64, now: Instant) -> Duration {
+        self.refill(now);
+        self.level -= tokens as i128 * NANOS_PER_SEC as i128;
+        if self.level >= 0 {
+            return Duration::ZERO;
+        }
+        let wait_nanos = (-self.level as u128 + self.rate as u128 - 1) / self.rate as u128;
+        Duration::from_nanos(wait_nanos.min(u64::MAX as u128) as u64)
+    }
+}
+
+/// The direction of a throttled request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThrottleDirection {
+    Read,
+    Write,
+}
+
+/// Enforces the limits of a `DiskThrottleConfig`. The default has no limits.
+#[derive(Default)]
+pub struct Throttle {
+    config: DiskThrottleConfig,
+    read_iops: Option<TokenBucket>,
+    write_iops: Option<TokenBucket>,
+    read_bps: Option<TokenBucket>,
+    write_bps: Option<TokenBucket>,
+}
+
+impl Throttle {
+    /// Creates a `Throttle` enforcing `config`, with every bucket starting out full.
+    pub fn new(config: DiskThrottleConfig) -> Throttle {
+        let now = Instant::now();
+        let bucket = |rate, burst| (rate != 0).then(|| TokenBucket::new(rate, burst, now));
+        Throttle {
+            config,
+            read_iops: bucket(config.read_iops, config.read_iops_burst),
+            write_iops: bucket(config.write_iops, config.write_iops_burst),
+            read_bps: bucket(config.read_bps, config.read_bps_burst),
+            write_bps: bucket(config.write_bps, config.write_bps_burst),
+        }
+    }
+
+    /// Returns true if any limit is set.
+    pub fn is_enabled(&self) -> bool {
+        self.config != DiskThrottleConfig::default()
+    }
+
+    /// Accounts for a request transferring `bytes` in `direction` and returns how long it has to
+    /// be held back to stay within the limits.
+    pub fn delay(&mut self, direction: ThrottleDirection, bytes: u64, now: Instant) -> Duration {
+        let (iops, bps) = match direction {
+            ThrottleDirection::Read => (&mut self.read_iops, &mut self.read_bps),
+            ThrottleDirection::Write => (&mut self.write_iops, &mut self.write_bps),
+        };
+        let iops_delay = iops.as_mut().map_or(Duration::ZERO, |b| b.take(1, now));
+        let bps_delay = bps.as_mut().map_or(Duration::ZERO, |b| b.take(bytes, now));
+        iops_delay.max(bps_delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlimited() {
+        let mut throttle = Throttle::new(DiskThrottleConfig::default());
+        assert!(!throttle.is_enabled());
+        let now = Instant::now();
+        for _ in 0..1000 {
+            assert_eq!(
+                throttle.delay(ThrottleDirection::Write, 1 << 20, now),
+                Duration::ZERO
+            );
+        }
+    }
+
+    #[test]
+    fn iops_burst_then_rate() {
+        let mut throttle = Throttle::new(DiskThrottleConfig {
+            read_iops: 10,
+            read_iops_burst: 5,
+            ..Default::default()
+        });
+        assert!(throttle.is_enabled());
+        let now = Instant::now();
+        for _ in 0..5 {
+            assert_eq!(
+                throttle.delay(ThrottleDirection::Read, 0, now),
+                Duration::ZERO
+            );
+        }
+        // The bucket is empty, so each further request waits for its own tenth of a second.
+        assert_eq!(
+            throttle.delay(ThrottleDirection::Read, 0, now),
+            Duration::from_millis(100)
+        );
+        assert_eq!(
+            throttle.delay(ThrottleDirection::Read, 0, now),
+            Duration::from_millis(200)
+        );
+        // Writes are limited separately.
+        assert_eq!(
+            throttle.delay(ThrottleDirection::Write, 0, now),
+            Duration::ZERO
+        );
+
+        // Waiting repays the debt.
+        let later = now + Duration::from_millis(200);
+        assert_eq!(
+            throttle.delay(ThrottleDirection::Read, 0, later),
+            Duration::from_millis(100)
+        );
+    }
+
+    #[test]
+    fn bps_default_burst() {
+        let mut throttle = Throttle::new(DiskThrottleConfig {
+            write_bps: 1 << 20,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        // One second's worth may be written at once.
+        assert_eq!(
+            throttle.delay(ThrottleDirection::Write, 1 << 20, now),
+            Duration::ZERO
+        );
+        // A request bigger than the bucket goes ahead once the bytes have been paid for.
+        assert_eq!(
+            throttle.delay(ThrottleDirection::Write, 2 << 20, now),
+            Duration::from_secs(2)
+        );
+        // The bucket never holds more than the burst, however long it is left alone.
+        let much_later = now + Duration::from_secs(3600);
+        assert_eq!(
+            throttle.delay(ThrottleDirection::Write, 2 << 20, much_later),
+            Duration::from_secs(1)
+        );
+    }
+}
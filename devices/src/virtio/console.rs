@@ -7,6 +7,8 @@
 
 #[cfg(unix)]
 pub mod asynchronous;
+#[cfg(unix)]
+pub mod multiport;
 mod sys;
 
 use std::collections::VecDeque;
@@ -47,8 +49,8 @@ use crate::Suspendable;
 
 pub(crate) const QUEUE_SIZE: u16 = 256;
 
-// For now, just implement port 0 (receiveq and transmitq).
-// If VIRTIO_CONSOLE_F_MULTIPORT is implemented, more queues will be needed.
+// Only port 0 (receiveq and transmitq) is implemented here. VIRTIO_CONSOLE_F_MULTIPORT is
+// implemented by [[multiport::MultiportConsole]].
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE];
 
 #[sorted]
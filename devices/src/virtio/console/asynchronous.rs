@@ -48,7 +48,7 @@ use crate::Suspendable;
 
 /// Wrapper that makes any `SerialInput` usable as an async source by providing an implementation of
 /// `IntoAsync`.
-struct AsyncSerialInput(Box<dyn SerialInput>);
+pub(super) struct AsyncSerialInput(pub(super) Box<dyn SerialInput>);
 impl AsRawDescriptor for AsyncSerialInput {
     fn as_raw_descriptor(&self) -> RawDescriptor {
         self.0.get_read_notifier().as_raw_descriptor()
@@ -56,7 +56,7 @@ impl AsRawDescriptor for AsyncSerialInput {
 }
 impl IntoAsync for AsyncSerialInput {}
 
-async fn run_tx_queue<I: SignalableInterrupt>(
+pub(super) async fn run_tx_queue<I: SignalableInterrupt>(
     mut queue: virtio::Queue,
     mem: GuestMemory,
     doorbell: I,
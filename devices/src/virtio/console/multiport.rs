@@ -0,0 +1,1028 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Virtio console device implementing `VIRTIO_CONSOLE_F_MULTIPORT`, which exposes several named
+//! ports to the guest (`/dev/virtio-ports/<name>` on Linux), each backed by its own serial input
+//! and output.
+//!
+//! The host side of a port is closed while its input is at the end of its stream, and reopened
+//! once the input has new data, which is polled for every `HOST_REOPEN_INTERVAL` and whenever the
+//! guest opens the port. The input of an open port is only read while the guest has the port open.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io;
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Context;
+use base::error;
+use base::info;
+use base::warn;
+use base::Event;
+use base::FileSync;
+use base::RawDescriptor;
+use cros_async::select2;
+use cros_async::EventAsync;
+use cros_async::Executor;
+use cros_async::IoSourceExt;
+use cros_async::SelectResult;
+use cros_async::TimerAsync;
+use data_model::DataInit;
+use data_model::Le16;
+use data_model::Le32;
+use futures::channel::mpsc;
+use futures::future::join_all;
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use futures::StreamExt;
+use hypervisor::ProtectionType;
+use vm_memory::GuestMemory;
+
+use super::asynchronous::run_tx_queue;
+use super::asynchronous::AsyncSerialInput;
+use super::handle_input;
+use super::QUEUE_SIZE;
+use crate::serial_device::SerialInput;
+use crate::virtio::async_utils;
+use crate::virtio::base_features;
+use crate::virtio::copy_config;
+use crate::virtio::virtio_console_config;
+use crate::virtio::ConsoleError;
+use crate::virtio::DeviceType;
+use crate::virtio::Interrupt;
+use crate::virtio::Queue;
+use crate::virtio::Reader;
+use crate::virtio::SignalableInterrupt;
+use crate::virtio::VirtioDevice;
+use crate::virtio::Writer;
+use crate::SerialDevice;
+use crate::Suspendable;
+
+const VIRTIO_CONSOLE_F_MULTIPORT: u32 = 1;
+
+// Events of the control messages.
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+// Interval at which the input of a port closed by the host is read again to see if it got reopened.
+const HOST_REOPEN_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_console_control {
+    id: Le32,
+    event: Le16,
+    value: Le16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_console_control {}
+
+/// Returns a control message for the guest, followed by `data`.
+fn control_message(id: usize, event: u16, value: u16, data: &[u8]) -> Vec<u8> {
+    let control = virtio_console_control {
+        id: (id as u32).into(),
+        event: event.into(),
+        value: value.into(),
+    };
+    [control.as_slice(), data].concat()
+}
+
+/// One port of a `MultiportConsole`, created from the `SerialParameters` of the port.
+pub struct ConsolePort {
+    input: Option<Box<dyn SerialInput>>,
+    output: Box<dyn io::Write + Send>,
+}
+
+impl SerialDevice for ConsolePort {
+    fn new(
+        _protection_type: ProtectionType,
+        _evt: Event,
+        input: Option<Box<dyn SerialInput>>,
+        output: Option<Box<dyn io::Write + Send>>,
+        _sync: Option<Box<dyn FileSync + Send>>,
+        _out_timestamp: bool,
+        _keep_rds: Vec<RawDescriptor>,
+    ) -> ConsolePort {
+        ConsolePort {
+            input,
+            output: output.unwrap_or_else(|| Box::new(io::sink())),
+        }
+    }
+}
+
+/// State of the ports shared by the tasks of the worker.
+struct PortStates<'a> {
+    names: &'a [String],
+    /// Whether the host side of each port is open.
+    host_connected: Vec<Cell<bool>>,
+    /// Channels notifying the receive task of each port when the guest opens or closes it.
+    guest_connected: Vec<mpsc::UnboundedSender<bool>>,
+    /// Channel of the control messages to send to the guest.
+    control: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl PortStates<'_> {
+    fn send_control(&self, id: usize, event: u16, value: u16, data: &[u8]) {
+        // The receiver only goes away with the worker.
+        let _ = self
+            .control
+            .unbounded_send(control_message(id, event, value, data));
+    }
+
+    /// Opens or closes the host side of port `id` and tells the guest.
+    fn set_host_connected(&self, id: usize, connected: bool) {
+        info!(
+            "console: host {} port {}",
+            if connected { "opened" } else { "closed" },
+            self.names[id]
+        );
+        self.host_connected[id].set(connected);
+        self.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, u16::from(connected), &[]);
+    }
+
+    /// Handles a control message received from the guest.
+    fn handle_control(&self, control: virtio_console_control) {
+        let id = control.id.to_native() as usize;
+        let value = control.value.to_native();
+        match control.event.to_native() {
+            VIRTIO_CONSOLE_DEVICE_READY => {
+                if value != 1 {
+                    error!("console: guest failed to initialize the device");
+                    return;
+                }
+                for id in 0..self.names.len() {
+                    self.send_control(id, VIRTIO_CONSOLE_DEVICE_ADD, 1, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY => {
+                let name = match self.names.get(id) {
+                    Some(name) => name,
+                    None => {
+                        error!("console: guest sent a message for invalid port {}", id);
+                        return;
+                    }
+                };
+                if value != 1 {
+                    error!("console: guest failed to add port {}", name);
+                    return;
+                }
+                self.send_control(id, VIRTIO_CONSOLE_PORT_NAME, 1, name.as_bytes());
+                if self.host_connected[id].get() {
+                    self.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                let guest_connected = match self.guest_connected.get(id) {
+                    Some(guest_connected) => guest_connected,
+                    None => {
+                        error!("console: guest sent a message for invalid port {}", id);
+                        return;
+                    }
+                };
+                info!(
+                    "console: guest {} port {}",
+                    if value == 1 { "opened" } else { "closed" },
+                    self.names[id]
+                );
+                let _ = guest_connected.unbounded_send(value == 1);
+            }
+            event => warn!("console: unhandled control event {}", event),
+        }
+    }
+}
+
+/// Processes the control messages of the guest.
+async fn run_control_tx<I: SignalableInterrupt>(
+    mut queue: Queue,
+    mem: GuestMemory,
+    doorbell: I,
+    kick_evt: EventAsync,
+    ports: &PortStates<'_>,
+) {
+    loop {
+        if let Err(e) = kick_evt.next_val().await {
+            error!("Failed to read kick event for control tx queue: {}", e);
+            break;
+        }
+
+        let mut needs_interrupt = false;
+        while let Some(desc) = queue.pop(&mem) {
+            let desc_index = desc.index;
+            match Reader::new(mem.clone(), desc) {
+                Ok(mut reader) => match reader.read_obj::<virtio_console_control>() {
+                    Ok(control) => ports.handle_control(control),
+                    Err(e) => error!("console: failed to read control message: {}", e),
+                },
+                Err(e) => error!("console: failed to create reader: {}", e),
+            }
+            queue.add_used(&mem, desc_index, 0);
+            needs_interrupt = true;
+        }
+
+        if needs_interrupt {
+            queue.trigger_interrupt(&mem, &doorbell);
+        }
+    }
+}
+
+/// Sends the control messages received from `messages` to the guest.
+async fn run_control_rx<I: SignalableInterrupt>(
+    mut queue: Queue,
+    mem: GuestMemory,
+    doorbell: I,
+    kick_evt: EventAsync,
+    mut messages: mpsc::UnboundedReceiver<Vec<u8>>,
+) {
+    while let Some(message) = messages.next().await {
+        let desc = loop {
+            match queue.pop(&mem) {
+                Some(desc) => break desc,
+                None => {
+                    // Wait until a descriptor becomes available.
+                    if let Err(e) = kick_evt.next_val().await {
+                        error!("Failed to read kick event for control rx queue: {}", e);
+                        return;
+                    }
+                }
+            }
+        };
+
+        let desc_index = desc.index;
+        let bytes_written = match Writer::new(mem.clone(), desc) {
+            Ok(mut writer) => match writer.write_all(&message) {
+                Ok(()) => writer.bytes_written() as u32,
+                Err(e) => {
+                    error!("console: failed to write control message: {}", e);
+                    0
+                }
+            },
+            Err(e) => {
+                error!("console: failed to create Writer: {}", e);
+                0
+            }
+        };
+        queue.add_used(&mem, desc_index, bytes_written);
+        queue.trigger_interrupt(&mem, &doorbell);
+    }
+}
+
+/// Forwards the input of port `id` to the guest while the guest has the port open.
+///
+/// The host side of the port is closed when the input reaches the end of its stream. The input is
+/// then read again every `HOST_REOPEN_INTERVAL`, or as soon as the guest opens the port, and the
+/// host side is reopened once it has new data, e.g. when another writer opens a FIFO.
+#[allow(clippy::too_many_arguments)]
+async fn run_port_rx<I: SignalableInterrupt>(
+    ex: &Executor,
+    id: usize,
+    mut queue: Queue,
+    mem: GuestMemory,
+    doorbell: I,
+    kick_evt: EventAsync,
+    input: &dyn IoSourceExt<AsyncSerialInput>,
+    mut guest_events: mpsc::UnboundedReceiver<bool>,
+    ports: Option<&PortStates<'_>>,
+) {
+    // Without the control queues, the port is always open.
+    let mut guest_connected = ports.is_none();
+    let mut host_connected = true;
+    let mut in_buffer = VecDeque::<u8>::new();
+    let mut rx_buf = vec![0u8; 4096];
+
+    loop {
+        while let Ok(Some(connected)) = guest_events.try_next() {
+            guest_connected = connected;
+        }
+
+        // The input of a port closed by the host is read regardless of the guest to find out when
+        // it gets reopened.
+        if in_buffer.is_empty() && (guest_connected || !host_connected) {
+            match input.read_to_vec(None, rx_buf).await {
+                // Input source has closed.
+                Ok((0, v)) => {
+                    rx_buf = v;
+                    if host_connected {
+                        host_connected = false;
+                        if let Some(ports) = ports {
+                            ports.set_host_connected(id, false);
+                        }
+                    }
+                    // Try again later, or as soon as the guest opens the port.
+                    match select2(
+                        TimerAsync::sleep(ex, HOST_REOPEN_INTERVAL).boxed_local(),
+                        guest_events.next(),
+                    )
+                    .await
+                    {
+                        (SelectResult::Finished(Err(e)), _) => {
+                            error!("Failed to wait for console input: {}", e);
+                            return;
+                        }
+                        (_, SelectResult::Finished(Some(connected))) => guest_connected = connected,
+                        (_, SelectResult::Finished(None)) => return,
+                        _ => {}
+                    }
+                }
+                Ok((size, v)) => {
+                    in_buffer.extend(&v[0..size]);
+                    rx_buf = v;
+                    if !host_connected {
+                        host_connected = true;
+                        if let Some(ports) = ports {
+                            ports.set_host_connected(id, true);
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to read console input: {}", e);
+                    if host_connected {
+                        if let Some(ports) = ports {
+                            ports.set_host_connected(id, false);
+                        }
+                    }
+                    return;
+                }
+            }
+            // The guest may have closed or opened the port while waiting for the input.
+            continue;
+        }
+
+        if !guest_connected {
+            // Leave the input in the host stream until the guest opens the port.
+            match guest_events.next().await {
+                Some(connected) => guest_connected = connected,
+                None => return,
+            }
+            continue;
+        }
+
+        match handle_input(&mem, &doorbell, &mut in_buffer, &mut queue) {
+            Ok(()) => {}
+            Err(ConsoleError::RxDescriptorsExhausted) => {
+                // Wait until a descriptor becomes available and try again.
+                if let Err(e) = kick_evt.next_val().await {
+                    error!("Failed to read kick event for rx queue: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Runs the queues of the device until `kill_evt` is signaled.
+///
+/// The queues are, in order, those of port 0, the control queues if `multiport` is true, which is
+/// when the driver acked `VIRTIO_CONSOLE_F_MULTIPORT`, and those of the other ports.
+#[allow(clippy::too_many_arguments)]
+fn run_worker(
+    ex: &Executor,
+    mem: GuestMemory,
+    interrupt: Interrupt,
+    queues: Vec<Queue>,
+    queue_evts: Vec<Event>,
+    multiport: bool,
+    names: &[String],
+    ports: &mut [ConsolePort],
+    kill_evt: Event,
+) -> anyhow::Result<()> {
+    if multiport && queues.len() < 4 {
+        anyhow::bail!("the control queues of the multiport console are missing");
+    }
+    let mut queues = queues
+        .into_iter()
+        .zip(queue_evts)
+        .map(|(queue, evt)| {
+            Ok((
+                queue,
+                EventAsync::new(evt, ex).context("failed to create EventAsync for kick_evt")?,
+            ))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?
+        .into_iter();
+
+    let mut port_queues = Vec::new();
+    port_queues.extend(queues.next().zip(queues.next()));
+    let control_queues = if multiport {
+        queues.next().zip(queues.next())
+    } else {
+        None
+    };
+    while let Some(port) = queues.next().zip(queues.next()) {
+        port_queues.push(port);
+    }
+    port_queues.truncate(ports.len());
+
+    let mut inputs = Vec::new();
+    for port in ports[..port_queues.len()].iter_mut() {
+        let input = match port.input.take() {
+            Some(input) => Some(
+                ex.async_from(AsyncSerialInput(input))
+                    .context("failed to create async input")?,
+            ),
+            None => None,
+        };
+        inputs.push(input);
+    }
+
+    let (control_sender, control_receiver) = mpsc::unbounded();
+    let mut guest_events = Vec::new();
+    let mut guest_connected = Vec::new();
+    for _ in 0..port_queues.len() {
+        let (sender, receiver) = mpsc::unbounded();
+        guest_connected.push(sender);
+        guest_events.push(receiver);
+    }
+    let port_states = PortStates {
+        names: &names[..port_queues.len()],
+        host_connected: (0..port_queues.len()).map(|_| Cell::new(true)).collect(),
+        guest_connected,
+        control: control_sender,
+    };
+    let states = control_queues.as_ref().map(|_| &port_states);
+
+    let mut tasks: Vec<LocalBoxFuture<'_, ()>> = Vec::new();
+    if let Some(((rx_queue, rx_evt), (tx_queue, tx_evt))) = control_queues {
+        tasks.push(
+            run_control_rx(
+                rx_queue,
+                mem.clone(),
+                interrupt.clone(),
+                rx_evt,
+                control_receiver,
+            )
+            .boxed_local(),
+        );
+        tasks.push(
+            run_control_tx(
+                tx_queue,
+                mem.clone(),
+                interrupt.clone(),
+                tx_evt,
+                &port_states,
+            )
+            .boxed_local(),
+        );
+    }
+    for (id, ((((rx_queue, rx_evt), (tx_queue, tx_evt)), port), (input, events))) in port_queues
+        .into_iter()
+        .zip(ports.iter_mut())
+        .zip(inputs.iter().zip(guest_events))
+        .enumerate()
+    {
+        if let Some(input) = input {
+            tasks.push(
+                run_port_rx(
+                    ex,
+                    id,
+                    rx_queue,
+                    mem.clone(),
+                    interrupt.clone(),
+                    rx_evt,
+                    input.as_ref(),
+                    events,
+                    states,
+                )
+                .boxed_local(),
+            );
+        }
+        tasks.push(
+            run_tx_queue(
+                tx_queue,
+                mem.clone(),
+                interrupt.clone(),
+                tx_evt,
+                &mut port.output,
+            )
+            .boxed_local(),
+        );
+    }
+
+    // Run until the kill event is signaled and cancel all tasks.
+    let result = match ex.run_until(select2(
+        join_all(tasks),
+        async_utils::await_and_exit(ex, kill_evt).boxed_local(),
+    )) {
+        Ok((_, SelectResult::Finished(res))) => res,
+        Ok((_, SelectResult::Pending(_))) => Ok(()),
+        Err(e) => Err(e).context("failed to run the console worker"),
+    };
+
+    for (port, input) in ports.iter_mut().zip(inputs) {
+        port.input = input.map(|input| input.into_source().0);
+    }
+    result
+}
+
+/// Virtio console device with several named ports.
+pub struct MultiportConsole {
+    base_features: u64,
+    acked_features: u64,
+    names: Vec<String>,
+    // `None` while the worker thread owns the ports.
+    ports: Option<Vec<ConsolePort>>,
+    queue_sizes: Vec<u16>,
+    keep_rds: Vec<RawDescriptor>,
+    kill_evt: Option<Event>,
+    worker_thread: Option<thread::JoinHandle<Vec<ConsolePort>>>,
+}
+
+impl MultiportConsole {
+    /// Creates a console exposing `ports` to the guest under their name, the first port having ID
+    /// 0.
+    pub fn new(
+        protection_type: ProtectionType,
+        ports: Vec<(String, ConsolePort)>,
+        keep_rds: Vec<RawDescriptor>,
+    ) -> MultiportConsole {
+        // One queue pair per port, plus the control queue pair.
+        let queue_sizes = vec![QUEUE_SIZE; 2 * (ports.len() + 1)];
+        let (names, ports) = ports.into_iter().unzip();
+        MultiportConsole {
+            base_features: base_features(protection_type) | 1 << VIRTIO_CONSOLE_F_MULTIPORT,
+            acked_features: 0,
+            names,
+            ports: Some(ports),
+            queue_sizes,
+            keep_rds,
+            kill_evt: None,
+            worker_thread: None,
+        }
+    }
+}
+
+impl Drop for MultiportConsole {
+    fn drop(&mut self) {
+        let _ = self.reset();
+    }
+}
+
+impl VirtioDevice for MultiportConsole {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        self.keep_rds.clone()
+    }
+
+    fn features(&self) -> u64 {
+        self.base_features
+    }
+
+    fn ack_features(&mut self, mut value: u64) {
+        if value & !self.base_features != 0 {
+            warn!("virtio_console got unknown feature ack {:x}", value);
+            value &= self.base_features;
+        }
+        self.acked_features |= value;
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Console
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = virtio_console_config {
+            max_nr_ports: (self.names.len() as u32).into(),
+            ..Default::default()
+        };
+        copy_config(data, 0, config.as_slice(), offset);
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        queues: Vec<Queue>,
+        queue_evts: Vec<Event>,
+    ) {
+        if queues.len() < 2 || queue_evts.len() < 2 {
+            return;
+        }
+
+        // Reset the device if it was already running.
+        if self.worker_thread.is_some() {
+            self.reset();
+        }
+
+        let mut ports = match self.ports.take() {
+            Some(ports) => ports,
+            None => {
+                warn!("device is broken and cannot be activated");
+                return;
+            }
+        };
+
+        let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed creating kill Event pair: {}", e);
+                self.ports = Some(ports);
+                return;
+            }
+        };
+
+        let multiport = self.acked_features & (1 << VIRTIO_CONSOLE_F_MULTIPORT) != 0;
+        let names = self.names.clone();
+        let worker_result = thread::Builder::new()
+            .name("v_console".to_string())
+            .spawn(move || {
+                let ex = Executor::new().expect("failed to create an executor");
+                if let Err(e) = run_worker(
+                    &ex, mem, interrupt, queues, queue_evts, multiport, &names, &mut ports,
+                    kill_evt,
+                ) {
+                    error!("virtio_console worker failed: {:#}", e);
+                }
+                ports
+            });
+
+        match worker_result {
+            Err(e) => error!("failed to spawn virtio_console worker: {}", e),
+            Ok(join_handle) => {
+                self.kill_evt = Some(self_kill_evt);
+                self.worker_thread = Some(join_handle);
+            }
+        }
+    }
+
+    fn reset(&mut self) -> bool {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            if let Err(e) = kill_evt.signal() {
+                error!(
+                    "{}: failed to notify the kill event: {}",
+                    self.debug_label(),
+                    e
+                );
+                return false;
+            }
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            match worker_thread.join() {
+                Ok(ports) => {
+                    self.ports = Some(ports);
+                    return true;
+                }
+                Err(_) => {
+                    error!("{}: worker thread has panicked", self.debug_label());
+                    return false;
+                }
+            }
+        }
+        self.ports.is_some()
+    }
+}
+
+impl Suspendable for MultiportConsole {}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::fs::OpenOptions;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::path::Path;
+    use std::time::Instant;
+
+    use base::IrqLevelEvent;
+    use data_model::Le64;
+    use tempfile::tempdir;
+    use vm_memory::GuestAddress;
+
+    use super::*;
+    use crate::virtio::Desc;
+
+    const TEST_QUEUE_SIZE: u16 = 16;
+    const TEST_BUFFER_SIZE: u32 = 64;
+    const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+
+    /// Split virtqueue `index` of the device, driven by the test as the guest driver would. Its
+    /// rings are in page `index` of the guest memory and its buffers in page `0x10 + index`.
+    struct GuestQueue {
+        index: u64,
+        next_avail: u16,
+        next_used: u16,
+    }
+
+    impl GuestQueue {
+        fn new(index: u64) -> GuestQueue {
+            GuestQueue {
+                index,
+                next_avail: 0,
+                next_used: 0,
+            }
+        }
+
+        fn rings(&self) -> u64 {
+            self.index * 0x1000
+        }
+
+        fn buffer_addr(&self, desc_index: u16) -> GuestAddress {
+            GuestAddress((0x10 + self.index) * 0x1000 + u64::from(desc_index) * 0x40)
+        }
+
+        fn queue(&self) -> Queue {
+            let mut queue = Queue::new(TEST_QUEUE_SIZE);
+            queue.set_desc_table(GuestAddress(self.rings()));
+            queue.set_avail_ring(GuestAddress(self.rings() + 0x200));
+            queue.set_used_ring(GuestAddress(self.rings() + 0x400));
+            queue.set_ready(true);
+            queue
+        }
+
+        /// Makes a buffer holding `data` available to the device, or a buffer the device can write
+        /// to if `data` is empty.
+        fn add_buffer(&mut self, mem: &GuestMemory, data: &[u8]) {
+            let desc_index = self.next_avail % TEST_QUEUE_SIZE;
+            let addr = self.buffer_addr(desc_index);
+            mem.write_all_at_addr(data, addr).unwrap();
+            let desc = Desc {
+                addr: Le64::from(addr.offset()),
+                len: Le32::from(if data.is_empty() {
+                    TEST_BUFFER_SIZE
+                } else {
+                    data.len() as u32
+                }),
+                flags: Le16::from(if data.is_empty() {
+                    VIRTQ_DESC_F_WRITE
+                } else {
+                    0
+                }),
+                next: Le16::from(0),
+            };
+            mem.write_obj_at_addr(
+                desc,
+                GuestAddress(self.rings() + u64::from(desc_index) * 16),
+            )
+            .unwrap();
+            mem.write_obj_at_addr(
+                Le16::from(desc_index),
+                GuestAddress(self.rings() + 0x204 + u64::from(desc_index) * 2),
+            )
+            .unwrap();
+            self.next_avail = self.next_avail.wrapping_add(1);
+            mem.write_obj_at_addr(
+                Le16::from(self.next_avail),
+                GuestAddress(self.rings() + 0x202),
+            )
+            .unwrap();
+        }
+
+        /// Waits for the device to use the next buffer and returns the data it wrote to it.
+        fn next_used(&mut self, mem: &GuestMemory) -> Vec<u8> {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                let used_idx: Le16 = mem
+                    .read_obj_from_addr(GuestAddress(self.rings() + 0x402))
+                    .unwrap();
+                if used_idx.to_native() != self.next_used {
+                    break;
+                }
+                assert!(Instant::now() < deadline, "queue {} not used", self.index);
+                thread::sleep(Duration::from_millis(10));
+            }
+            let elem = GuestAddress(
+                self.rings() + 0x404 + u64::from(self.next_used % TEST_QUEUE_SIZE) * 8,
+            );
+            let id: Le32 = mem.read_obj_from_addr(elem).unwrap();
+            let len: Le32 = mem.read_obj_from_addr(elem.unchecked_add(4)).unwrap();
+            self.next_used = self.next_used.wrapping_add(1);
+            let mut data = vec![0; len.to_native() as usize];
+            mem.read_exact_at_addr(&mut data, self.buffer_addr(id.to_native() as u16))
+                .unwrap();
+            data
+        }
+    }
+
+    /// Guest side of an activated `MultiportConsole`.
+    struct TestGuest {
+        mem: GuestMemory,
+        queues: Vec<GuestQueue>,
+        kick_evts: Vec<Event>,
+    }
+
+    impl TestGuest {
+        /// Activates `console` with `num_queues` queues, after acking `features`.
+        fn activate(console: &mut MultiportConsole, features: u64, num_queues: u64) -> TestGuest {
+            let mem = GuestMemory::new(&[(GuestAddress(0), 0x20000)]).unwrap();
+            let queues: Vec<GuestQueue> = (0..num_queues).map(GuestQueue::new).collect();
+            let kick_evts: Vec<Event> = queues.iter().map(|_| Event::new().unwrap()).collect();
+            console.ack_features(features);
+            console.activate(
+                mem.clone(),
+                Interrupt::new(IrqLevelEvent::new().unwrap(), None, 0),
+                queues.iter().map(GuestQueue::queue).collect(),
+                kick_evts.iter().map(|e| e.try_clone().unwrap()).collect(),
+            );
+            TestGuest {
+                mem,
+                queues,
+                kick_evts,
+            }
+        }
+
+        /// Gives `count` empty buffers to the device on queue `index`.
+        fn add_rx_buffers(&mut self, index: usize, count: usize) {
+            for _ in 0..count {
+                self.queues[index].add_buffer(&self.mem, &[]);
+            }
+            self.kick_evts[index].signal().unwrap();
+        }
+
+        /// Sends a control message to the device and waits for it to be processed.
+        fn send_control(&mut self, id: usize, event: u16, value: u16) {
+            self.queues[3].add_buffer(&self.mem, &control_message(id, event, value, &[]));
+            self.kick_evts[3].signal().unwrap();
+            self.queues[3].next_used(&self.mem);
+        }
+
+        fn next_control(&mut self) -> Vec<u8> {
+            self.queues[2].next_used(&self.mem)
+        }
+
+        fn next_rx(&mut self, index: usize) -> Vec<u8> {
+            self.queues[index].next_used(&self.mem)
+        }
+    }
+
+    fn mkfifo(path: &Path) {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        // Safe because `path` is a valid C string and the return value is checked.
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+    }
+
+    fn open_fifo_writer(path: &Path) -> File {
+        OpenOptions::new().write(true).open(path).unwrap()
+    }
+
+    #[test]
+    fn worker_port_open_close() {
+        let dir = tempdir().unwrap();
+        let fifo = dir.path().join("agent.in");
+        mkfifo(&fifo);
+        let input = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&fifo)
+            .unwrap();
+        let mut writer = open_fifo_writer(&fifo);
+
+        let mut console = MultiportConsole::new(
+            ProtectionType::Unprotected,
+            vec![
+                (
+                    "console".to_string(),
+                    ConsolePort {
+                        input: None,
+                        output: Box::new(io::sink()),
+                    },
+                ),
+                (
+                    "agent".to_string(),
+                    ConsolePort {
+                        input: Some(Box::new(input)),
+                        output: Box::new(io::sink()),
+                    },
+                ),
+            ],
+            Vec::new(),
+        );
+        let features = console.features();
+        let mut guest = TestGuest::activate(&mut console, features, 6);
+        guest.add_rx_buffers(2, 8);
+        guest.add_rx_buffers(4, 4);
+
+        guest.send_control(0, VIRTIO_CONSOLE_DEVICE_READY, 1);
+        assert_eq!(
+            guest.next_control(),
+            control_message(0, VIRTIO_CONSOLE_DEVICE_ADD, 1, &[])
+        );
+        assert_eq!(
+            guest.next_control(),
+            control_message(1, VIRTIO_CONSOLE_DEVICE_ADD, 1, &[])
+        );
+        guest.send_control(1, VIRTIO_CONSOLE_PORT_READY, 1);
+        assert_eq!(
+            guest.next_control(),
+            control_message(1, VIRTIO_CONSOLE_PORT_NAME, 1, b"agent")
+        );
+        assert_eq!(
+            guest.next_control(),
+            control_message(1, VIRTIO_CONSOLE_PORT_OPEN, 1, &[])
+        );
+
+        // The input is forwarded once the guest opens the port.
+        writer.write_all(b"hello").unwrap();
+        guest.send_control(1, VIRTIO_CONSOLE_PORT_OPEN, 1);
+        assert_eq!(guest.next_rx(4), b"hello");
+
+        // The host closes the port at the end of the input, and reopens it with the next writer.
+        drop(writer);
+        assert_eq!(
+            guest.next_control(),
+            control_message(1, VIRTIO_CONSOLE_PORT_OPEN, 0, &[])
+        );
+        let mut writer = open_fifo_writer(&fifo);
+        writer.write_all(b"again").unwrap();
+        assert_eq!(
+            guest.next_control(),
+            control_message(1, VIRTIO_CONSOLE_PORT_OPEN, 1, &[])
+        );
+        assert_eq!(guest.next_rx(4), b"again");
+
+        assert!(console.reset());
+    }
+
+    #[test]
+    fn worker_without_multiport() {
+        let (input, mut writer) = base::pipe(true).unwrap();
+        let mut console = MultiportConsole::new(
+            ProtectionType::Unprotected,
+            vec![(
+                "console".to_string(),
+                ConsolePort {
+                    input: Some(Box::new(input)),
+                    output: Box::new(io::sink()),
+                },
+            )],
+            Vec::new(),
+        );
+        // A driver that doesn't ack `VIRTIO_CONSOLE_F_MULTIPORT` only sets up the queues of port 0,
+        // which is always open.
+        let features = console.features() & !(1 << VIRTIO_CONSOLE_F_MULTIPORT);
+        let mut guest = TestGuest::activate(&mut console, features, 2);
+        guest.add_rx_buffers(0, 1);
+        writer.write_all(b"hello").unwrap();
+        assert_eq!(guest.next_rx(0), b"hello");
+
+        assert!(console.reset());
+    }
+
+    fn control_messages(receiver: &mut mpsc::UnboundedReceiver<Vec<u8>>) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        while let Ok(Some(message)) = receiver.try_next() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn guest_control(id: u32, event: u16, value: u16) -> virtio_console_control {
+        virtio_console_control {
+            id: id.into(),
+            event: event.into(),
+            value: value.into(),
+        }
+    }
+
+    #[test]
+    fn control_port_setup() {
+        let names = vec!["org.qemu.guest_agent.0".to_string(), "log".to_string()];
+        let (control, mut control_receiver) = mpsc::unbounded();
+        let (guest_connected, mut guest_events) = mpsc::unbounded();
+        let ports = PortStates {
+            names: &names,
+            host_connected: vec![Cell::new(true), Cell::new(false)],
+            guest_connected: vec![guest_connected, mpsc::unbounded().0],
+            control,
+        };
+
+        ports.handle_control(guest_control(0, VIRTIO_CONSOLE_DEVICE_READY, 1));
+        assert_eq!(
+            control_messages(&mut control_receiver),
+            vec![
+                control_message(0, VIRTIO_CONSOLE_DEVICE_ADD, 1, &[]),
+                control_message(1, VIRTIO_CONSOLE_DEVICE_ADD, 1, &[]),
+            ]
+        );
+
+        // Only the port whose host side is connected gets opened.
+        ports.handle_control(guest_control(0, VIRTIO_CONSOLE_PORT_READY, 1));
+        ports.handle_control(guest_control(1, VIRTIO_CONSOLE_PORT_READY, 1));
+        assert_eq!(
+            control_messages(&mut control_receiver),
+            vec![
+                control_message(0, VIRTIO_CONSOLE_PORT_NAME, 1, b"org.qemu.guest_agent.0"),
+                control_message(0, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]),
+                control_message(1, VIRTIO_CONSOLE_PORT_NAME, 1, b"log"),
+            ]
+        );
+
+        ports.handle_control(guest_control(0, VIRTIO_CONSOLE_PORT_OPEN, 1));
+        ports.handle_control(guest_control(0, VIRTIO_CONSOLE_PORT_OPEN, 0));
+        assert_eq!(guest_events.try_next().unwrap(), Some(true));
+        assert_eq!(guest_events.try_next().unwrap(), Some(false));
+
+        // Messages for ports that don't exist are ignored.
+        ports.handle_control(guest_control(2, VIRTIO_CONSOLE_PORT_READY, 1));
+        assert!(control_messages(&mut control_receiver).is_empty());
+    }
+}
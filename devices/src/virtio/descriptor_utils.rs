@@ -742,6 +742,18 @@ impl Writer {
     }
 }
 
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // Devices may write to the buffer through the raw regions as well as through the `Writer`
+        // methods, so mark the whole buffer dirty once the device is done with it, for live
+        // migration.
+        for region in &self.regions.regions.regions {
+            self.mem
+                .mark_dirty(GuestAddress(region.offset as u64), region.len as u64);
+        }
+    }
+}
+
 impl io::Write for Writer {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let mut rem = buf;
@@ -35,6 +35,10 @@ use base::VmEventType;
 use base::WaitContext;
 use data_model::*;
 pub use gpu_display::EventDevice;
+#[cfg(unix)]
+pub use gpu_display::VncAddress;
+#[cfg(unix)]
+pub use gpu_display::VncListener;
 use gpu_display::*;
 pub use parameters::GpuParameters;
 use rutabaga_gfx::*;
@@ -1037,6 +1041,9 @@ pub enum DisplayBackend {
     #[cfg(unix)]
     /// Open a connection to the X server at the given display if given.
     X(Option<String>),
+    #[cfg(unix)]
+    /// Serve the display to the VNC clients connecting on the given listener.
+    Vnc(Arc<VncListener>),
     /// Emulate a display without actually displaying it.
     Stub,
     #[cfg(windows)]
@@ -1054,6 +1061,11 @@ impl DisplayBackend {
             DisplayBackend::Wayland(path) => GpuDisplay::open_wayland(path.as_ref()),
             #[cfg(unix)]
             DisplayBackend::X(display) => GpuDisplay::open_x(display.as_ref()),
+            #[cfg(unix)]
+            DisplayBackend::Vnc(listener) => listener
+                .try_clone()
+                .map_err(GpuDisplayError::IoError)
+                .and_then(GpuDisplay::open_vnc),
             DisplayBackend::Stub => GpuDisplay::open_stub(),
             #[cfg(windows)]
             DisplayBackend::WinApi(display_properties) => match wndproc_thread.take() {
@@ -1311,6 +1323,13 @@ impl VirtioDevice for Gpu {
 
         keep_rds.push(self.exit_evt_wrtube.as_raw_descriptor());
 
+        #[cfg(unix)]
+        for display_backend in &self.display_backends {
+            if let DisplayBackend::Vnc(listener) = display_backend {
+                keep_rds.push(listener.as_raw_descriptor());
+            }
+        }
+
         #[cfg(unix)]
         if let Some(gpu_control_tube) = &self.gpu_control_tube {
             keep_rds.push(gpu_control_tube.as_raw_descriptor());
@@ -1331,6 +1350,11 @@ impl VirtioDevice for Gpu {
         QUEUE_SIZES
     }
 
+    fn accesses_memory_through_queues(&self) -> bool {
+        // The backing of resources is given by address in the commands.
+        false
+    }
+
     fn features(&self) -> u64 {
         let mut virtio_gpu_features = 1 << VIRTIO_GPU_F_EDID;
 
@@ -31,8 +31,8 @@ use vm_memory::GuestMemory;
 
 use self::constants::*;
 use self::event_source::EvdevEventSource;
-use self::event_source::EventSource;
-use self::event_source::SocketEventSource;
+pub use self::event_source::EventSource;
+pub use self::event_source::SocketEventSource;
 use super::copy_config;
 use super::DescriptorChain;
 use super::DescriptorError;
@@ -0,0 +1,586 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Implements the virtio-mem device, which lets the guest plug and unplug blocks of a hotpluggable
+//! memory region at the request of the host.
+
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+use std::thread;
+
+use base::error;
+use base::AsRawDescriptor;
+use base::Event;
+use base::MemoryMapping;
+use base::MemoryMappingUnix;
+use base::RawDescriptor;
+use base::Tube;
+use cros_async::select4;
+use cros_async::AsyncTube;
+use cros_async::EventAsync;
+use cros_async::Executor;
+use data_model::DataInit;
+use data_model::Le16;
+use data_model::Le64;
+use futures::pin_mut;
+use remain::sorted;
+use sync::Mutex;
+use thiserror::Error;
+use vm_control::VirtioMemControlCommand;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+
+use super::async_utils;
+use super::copy_config;
+use super::DescriptorChain;
+use super::DescriptorError;
+use super::DeviceType;
+use super::Interrupt;
+use super::Queue;
+use super::Reader;
+use super::SignalableInterrupt;
+use super::VirtioDevice;
+use super::Writer;
+use crate::Suspendable;
+
+const QUEUE_SIZE: u16 = 128;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+
+/// Size of the blocks the guest plugs and unplugs.
+pub const VIRTIO_MEM_BLOCK_SIZE: u64 = 2 << 20;
+/// Alignment of the virtio-mem region in the guest physical address space. Linux adds the region
+/// in memory sections, which are 128 MiB on x86_64 and arm64 with 4k pages.
+pub const VIRTIO_MEM_REGION_ALIGN: u64 = 128 << 20;
+
+const VIRTIO_MEM_REQ_PLUG: u16 = 0;
+const VIRTIO_MEM_REQ_UNPLUG: u16 = 1;
+const VIRTIO_MEM_REQ_UNPLUG_ALL: u16 = 2;
+const VIRTIO_MEM_REQ_STATE: u16 = 3;
+
+const VIRTIO_MEM_RESP_ACK: u16 = 0;
+const VIRTIO_MEM_RESP_NACK: u16 = 1;
+const VIRTIO_MEM_RESP_ERROR: u16 = 3;
+
+const VIRTIO_MEM_STATE_PLUGGED: u16 = 0;
+const VIRTIO_MEM_STATE_UNPLUGGED: u16 = 1;
+const VIRTIO_MEM_STATE_MIXED: u16 = 2;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_mem_config {
+    block_size: Le64,
+    node_id: Le16,
+    padding: [u8; 6],
+    addr: Le64,
+    region_size: Le64,
+    usable_region_size: Le64,
+    plugged_size: Le64,
+    requested_size: Le64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_mem_config {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_mem_req {
+    type_: Le16,
+    padding: [u8; 6],
+    addr: Le64,
+    nb_blocks: Le16,
+    padding_1: [u8; 6],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_mem_req {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_mem_resp {
+    type_: Le16,
+    padding: [u8; 6],
+    state: Le16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_mem_resp {}
+
+#[sorted]
+#[derive(Error, Debug)]
+enum Error {
+    /// Invalid virtio descriptor chain.
+    #[error("virtio descriptor error: {0}")]
+    Descriptor(DescriptorError),
+    /// Failed to read from virtqueue.
+    #[error("failed to read from virtqueue: {0}")]
+    ReadQueue(io::Error),
+    /// Failed to write to virtqueue.
+    #[error("failed to write to virtqueue: {0}")]
+    WriteQueue(io::Error),
+}
+
+type Result<T> = ::std::result::Result<T, Error>;
+
+// VirtioMemState is shared by the worker and device thread.
+struct VirtioMemState {
+    addr: GuestAddress,
+    // One entry per block of the region, set when the block is plugged.
+    plugged: Vec<bool>,
+    requested_size: u64,
+}
+
+impl VirtioMemState {
+    fn plugged_size(&self) -> u64 {
+        self.plugged.iter().filter(|p| **p).count() as u64 * VIRTIO_MEM_BLOCK_SIZE
+    }
+
+    // Returns the blocks covered by a request, or `None` if they are not all in the region.
+    fn blocks(&self, addr: u64, nb_blocks: u16) -> Option<Range<usize>> {
+        let offset = addr.checked_sub(self.addr.offset())?;
+        if nb_blocks == 0 || offset % VIRTIO_MEM_BLOCK_SIZE != 0 {
+            return None;
+        }
+        let first = usize::try_from(offset / VIRTIO_MEM_BLOCK_SIZE).ok()?;
+        let end = first.checked_add(nb_blocks as usize)?;
+        if end > self.plugged.len() {
+            return None;
+        }
+        Some(first..end)
+    }
+}
+
+// Releases the host memory backing `blocks`, so that unplugged memory reads back as zeroes.
+fn discard_blocks(mapping: &MemoryMapping, blocks: Range<usize>) -> bool {
+    let offset = blocks.start * VIRTIO_MEM_BLOCK_SIZE as usize;
+    let count = blocks.len() * VIRTIO_MEM_BLOCK_SIZE as usize;
+    if let Err(e) = mapping.remove_range(offset, count) {
+        error!("failed to discard unplugged memory: {}", e);
+        return false;
+    }
+    true
+}
+
+fn execute_request(
+    request: virtio_mem_req,
+    state: &Mutex<VirtioMemState>,
+    mapping: &MemoryMapping,
+) -> virtio_mem_resp {
+    let mut state = state.lock();
+    let addr = request.addr.to_native();
+    let nb_blocks = request.nb_blocks.to_native();
+    let mut response = virtio_mem_resp::default();
+
+    let type_ = match request.type_.to_native() {
+        VIRTIO_MEM_REQ_PLUG => match state.blocks(addr, nb_blocks) {
+            Some(blocks) if state.plugged[blocks.clone()].iter().all(|p| !p) => {
+                if state.plugged_size() + blocks.len() as u64 * VIRTIO_MEM_BLOCK_SIZE
+                    > state.requested_size
+                {
+                    VIRTIO_MEM_RESP_NACK
+                } else {
+                    state.plugged[blocks].fill(true);
+                    VIRTIO_MEM_RESP_ACK
+                }
+            }
+            _ => VIRTIO_MEM_RESP_ERROR,
+        },
+        VIRTIO_MEM_REQ_UNPLUG => match state.blocks(addr, nb_blocks) {
+            Some(blocks) if state.plugged[blocks.clone()].iter().all(|p| *p) => {
+                if discard_blocks(mapping, blocks.clone()) {
+                    state.plugged[blocks].fill(false);
+                    VIRTIO_MEM_RESP_ACK
+                } else {
+                    VIRTIO_MEM_RESP_ERROR
+                }
+            }
+            _ => VIRTIO_MEM_RESP_ERROR,
+        },
+        VIRTIO_MEM_REQ_UNPLUG_ALL => {
+            let blocks = 0..state.plugged.len();
+            if discard_blocks(mapping, blocks.clone()) {
+                state.plugged[blocks].fill(false);
+                VIRTIO_MEM_RESP_ACK
+            } else {
+                VIRTIO_MEM_RESP_ERROR
+            }
+        }
+        VIRTIO_MEM_REQ_STATE => match state.blocks(addr, nb_blocks) {
+            Some(blocks) => {
+                let plugged = &state.plugged[blocks];
+                let block_state = if plugged.iter().all(|p| *p) {
+                    VIRTIO_MEM_STATE_PLUGGED
+                } else if plugged.iter().all(|p| !p) {
+                    VIRTIO_MEM_STATE_UNPLUGGED
+                } else {
+                    VIRTIO_MEM_STATE_MIXED
+                };
+                response.state = block_state.into();
+                VIRTIO_MEM_RESP_ACK
+            }
+            None => VIRTIO_MEM_RESP_ERROR,
+        },
+        t => {
+            error!("unknown request type: {}", t);
+            VIRTIO_MEM_RESP_ERROR
+        }
+    };
+    response.type_ = type_.into();
+    response
+}
+
+fn handle_request(
+    mem: &GuestMemory,
+    avail_desc: DescriptorChain,
+    state: &Mutex<VirtioMemState>,
+    mapping: &MemoryMapping,
+) -> Result<usize> {
+    let mut reader = Reader::new(mem.clone(), avail_desc.clone()).map_err(Error::Descriptor)?;
+    let mut writer = Writer::new(mem.clone(), avail_desc).map_err(Error::Descriptor)?;
+
+    let response = reader
+        .read_obj()
+        .map(|request| execute_request(request, state, mapping))
+        .map_err(Error::ReadQueue)?;
+
+    writer.write_obj(response).map_err(Error::WriteQueue)?;
+
+    Ok(writer.bytes_written())
+}
+
+async fn handle_queue(
+    mem: &GuestMemory,
+    mut queue: Queue,
+    mut queue_event: EventAsync,
+    interrupt: Interrupt,
+    state: &Mutex<VirtioMemState>,
+    mapping: &MemoryMapping,
+) {
+    loop {
+        let avail_desc = match queue.next_async(mem, &mut queue_event).await {
+            Err(e) => {
+                error!("Failed to read descriptor {}", e);
+                return;
+            }
+            Ok(d) => d,
+        };
+        let index = avail_desc.index;
+        let written = match handle_request(mem, avail_desc, state, mapping) {
+            Ok(n) => n,
+            Err(e) => {
+                error!("virtio-mem: failed to handle request: {}", e);
+                0
+            }
+        };
+        queue.add_used(mem, index, written as u32);
+        queue.trigger_interrupt(mem, &interrupt);
+    }
+}
+
+async fn handle_command_tube(
+    command_tube: &AsyncTube,
+    interrupt: Interrupt,
+    state: &Mutex<VirtioMemState>,
+) {
+    loop {
+        match command_tube.next().await {
+            Ok(VirtioMemControlCommand::Resize { requested_size }) => {
+                state.lock().requested_size = requested_size;
+                interrupt.signal_config_changed();
+            }
+            Err(e) => {
+                error!("failed to receive virtio-mem command: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+fn run_worker(
+    queue_evt: Event,
+    queue: Queue,
+    command_tube: Tube,
+    interrupt: Interrupt,
+    kill_evt: Event,
+    mem: GuestMemory,
+    state: Arc<Mutex<VirtioMemState>>,
+    mapping: Arc<MemoryMapping>,
+) -> Tube {
+    let ex = Executor::new().unwrap();
+
+    let queue_evt = EventAsync::new(queue_evt, &ex).expect("failed to set up the queue event");
+    let command_tube =
+        AsyncTube::new(&ex, command_tube).expect("failed to set up the command tube");
+
+    {
+        // Process requests from the virtio queue.
+        let queue_fut = handle_queue(&mem, queue, queue_evt, interrupt.clone(), &state, &mapping);
+        pin_mut!(queue_fut);
+
+        // Process resize requests from the host.
+        let command = handle_command_tube(&command_tube, interrupt.clone(), &state);
+        pin_mut!(command);
+
+        // Process any requests to resample the irq value.
+        let resample = async_utils::handle_irq_resample(&ex, interrupt);
+        pin_mut!(resample);
+
+        // Exit if the kill event is triggered.
+        let kill = async_utils::await_and_exit(&ex, kill_evt);
+        pin_mut!(kill);
+
+        if let Err(e) = ex.run_until(select4(queue_fut, command, resample, kill)) {
+            error!("error happened in executor: {}", e);
+        }
+    }
+
+    command_tube.into()
+}
+
+/// Virtio device exposing a hotpluggable memory region, of which the guest plugs as many blocks as
+/// the host requests.
+pub struct VirtioMem {
+    kill_event: Option<Event>,
+    worker_thread: Option<thread::JoinHandle<Tube>>,
+    base_features: u64,
+    region_size: u64,
+    state: Arc<Mutex<VirtioMemState>>,
+    // Host mapping of the memory backing the region, used to discard unplugged blocks.
+    mapping: Arc<MemoryMapping>,
+    command_tube: Option<Tube>,
+}
+
+impl VirtioMem {
+    /// Create a new virtio-mem device for the region starting at `addr`.
+    ///
+    /// `mapping` is the host mapping of the memory backing the region, whose size is the size of
+    /// the region. `requested_size` is the amount of memory the guest is initially asked to plug,
+    /// and `command_tube` receives the `VirtioMemControlCommand`s that change it.
+    pub fn new(
+        base_features: u64,
+        addr: GuestAddress,
+        mapping: MemoryMapping,
+        requested_size: u64,
+        command_tube: Tube,
+    ) -> VirtioMem {
+        let region_size = mapping.size() as u64;
+        let num_blocks = (region_size / VIRTIO_MEM_BLOCK_SIZE) as usize;
+
+        VirtioMem {
+            kill_event: None,
+            worker_thread: None,
+            base_features,
+            region_size,
+            state: Arc::new(Mutex::new(VirtioMemState {
+                addr,
+                plugged: vec![false; num_blocks],
+                requested_size,
+            })),
+            mapping: Arc::new(mapping),
+            command_tube: Some(command_tube),
+        }
+    }
+}
+
+impl Drop for VirtioMem {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_event.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.signal();
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let _ = worker_thread.join();
+        }
+    }
+}
+
+impl VirtioDevice for VirtioMem {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = Vec::new();
+        if let Some(command_tube) = &self.command_tube {
+            keep_rds.push(command_tube.as_raw_descriptor());
+        }
+        keep_rds
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Mem
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.base_features
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let state = self.state.lock();
+        let config = virtio_mem_config {
+            block_size: VIRTIO_MEM_BLOCK_SIZE.into(),
+            addr: state.addr.offset().into(),
+            region_size: self.region_size.into(),
+            usable_region_size: self.region_size.into(),
+            plugged_size: state.plugged_size().into(),
+            requested_size: state.requested_size.into(),
+            ..Default::default()
+        };
+        copy_config(data, 0, config.as_slice(), offset);
+    }
+
+    fn activate(
+        &mut self,
+        memory: GuestMemory,
+        interrupt: Interrupt,
+        mut queues: Vec<Queue>,
+        mut queue_events: Vec<Event>,
+    ) {
+        if queues.len() != 1 || queue_events.len() != 1 {
+            return;
+        }
+
+        let queue = queues.remove(0);
+        let queue_event = queue_events.remove(0);
+
+        let command_tube = match self.command_tube.take() {
+            Some(tube) => tube,
+            None => {
+                error!("virtio-mem command tube is missing");
+                return;
+            }
+        };
+
+        let (self_kill_event, kill_event) = match Event::new().and_then(|e| Ok((e.try_clone()?, e)))
+        {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed creating kill Event pair: {}", e);
+                return;
+            }
+        };
+        self.kill_event = Some(self_kill_event);
+
+        let state = self.state.clone();
+        let mapping = self.mapping.clone();
+        let worker_result = thread::Builder::new()
+            .name("v_mem".to_string())
+            .spawn(move || {
+                run_worker(
+                    queue_event,
+                    queue,
+                    command_tube,
+                    interrupt,
+                    kill_event,
+                    memory,
+                    state,
+                    mapping,
+                )
+            });
+
+        match worker_result {
+            Err(e) => {
+                error!("failed to spawn virtio_mem worker: {}", e);
+            }
+            Ok(join_handle) => {
+                self.worker_thread = Some(join_handle);
+            }
+        }
+    }
+
+    fn reset(&mut self) -> bool {
+        if let Some(kill_evt) = self.kill_event.take() {
+            if kill_evt.signal().is_err() {
+                error!("{}: failed to notify the kill event", self.debug_label());
+                return false;
+            }
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            match worker_thread.join() {
+                Err(_) => {
+                    error!("{}: failed to get back resources", self.debug_label());
+                    return false;
+                }
+                Ok(command_tube) => {
+                    // The plugged blocks are kept: the guest unplugs everything when it
+                    // initializes the device again.
+                    self.command_tube = Some(command_tube);
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+impl Suspendable for VirtioMem {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::SharedMemory;
+
+    const REGION_ADDR: u64 = 0x1_0000_0000;
+
+    fn test_state(requested_size: u64) -> (Mutex<VirtioMemState>, MemoryMapping) {
+        let size = 8 * VIRTIO_MEM_BLOCK_SIZE;
+        let shm = SharedMemory::new("virtio_mem_test", size).unwrap();
+        let mapping = base::MemoryMappingBuilder::new(size as usize)
+            .from_shared_memory(&shm)
+            .build()
+            .unwrap();
+        let state = Mutex::new(VirtioMemState {
+            addr: GuestAddress(REGION_ADDR),
+            plugged: vec![false; 8],
+            requested_size,
+        });
+        (state, mapping)
+    }
+
+    fn request(type_: u16, block: u64, nb_blocks: u16) -> virtio_mem_req {
+        virtio_mem_req {
+            type_: type_.into(),
+            addr: (REGION_ADDR + block * VIRTIO_MEM_BLOCK_SIZE).into(),
+            nb_blocks: nb_blocks.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn plug_up_to_requested_size() {
+        let (state, mapping) = test_state(4 * VIRTIO_MEM_BLOCK_SIZE);
+
+        let resp = execute_request(request(VIRTIO_MEM_REQ_PLUG, 0, 3), &state, &mapping);
+        assert_eq!(resp.type_.to_native(), VIRTIO_MEM_RESP_ACK);
+        let resp = execute_request(request(VIRTIO_MEM_REQ_PLUG, 3, 2), &state, &mapping);
+        assert_eq!(resp.type_.to_native(), VIRTIO_MEM_RESP_NACK);
+        let resp = execute_request(request(VIRTIO_MEM_REQ_PLUG, 2, 1), &state, &mapping);
+        assert_eq!(resp.type_.to_native(), VIRTIO_MEM_RESP_ERROR);
+        let resp = execute_request(request(VIRTIO_MEM_REQ_PLUG, 7, 2), &state, &mapping);
+        assert_eq!(resp.type_.to_native(), VIRTIO_MEM_RESP_ERROR);
+        assert_eq!(state.lock().plugged_size(), 3 * VIRTIO_MEM_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn unplug_and_state() {
+        let (state, mapping) = test_state(8 * VIRTIO_MEM_BLOCK_SIZE);
+
+        execute_request(request(VIRTIO_MEM_REQ_PLUG, 0, 4), &state, &mapping);
+        let resp = execute_request(request(VIRTIO_MEM_REQ_UNPLUG, 2, 2), &state, &mapping);
+        assert_eq!(resp.type_.to_native(), VIRTIO_MEM_RESP_ACK);
+
+        let resp = execute_request(request(VIRTIO_MEM_REQ_STATE, 0, 2), &state, &mapping);
+        assert_eq!(resp.state.to_native(), VIRTIO_MEM_STATE_PLUGGED);
+        let resp = execute_request(request(VIRTIO_MEM_REQ_STATE, 1, 2), &state, &mapping);
+        assert_eq!(resp.state.to_native(), VIRTIO_MEM_STATE_MIXED);
+        let resp = execute_request(request(VIRTIO_MEM_REQ_STATE, 2, 6), &state, &mapping);
+        assert_eq!(resp.state.to_native(), VIRTIO_MEM_STATE_UNPLUGGED);
+
+        let resp = execute_request(request(VIRTIO_MEM_REQ_UNPLUG_ALL, 0, 0), &state, &mapping);
+        assert_eq!(resp.type_.to_native(), VIRTIO_MEM_RESP_ACK);
+        assert_eq!(state.lock().plugged_size(), 0);
+    }
+}
@@ -16,7 +16,7 @@ mod iommu;
 mod queue;
 mod rng;
 mod sys;
-#[cfg(any(feature = "tpm", feature = "vtpm"))]
+#[cfg(any(feature = "tpm", feature = "vtpm", feature = "swtpm"))]
 mod tpm;
 #[cfg(any(feature = "video-decoder", feature = "video-encoder"))]
 mod video;
@@ -24,12 +24,14 @@ mod virtio_device;
 mod virtio_mmio_device;
 mod virtio_pci_common_config;
 mod virtio_pci_device;
+mod vsock;
 
 pub mod block;
 pub mod console;
 #[cfg(feature = "gpu")]
 pub mod gpu;
 pub mod resource_bridge;
+pub mod scsi;
 #[cfg(feature = "audio")]
 pub mod snd;
 pub mod vhost;
@@ -47,15 +49,17 @@ pub use self::interrupt::*;
 pub use self::iommu::*;
 pub use self::queue::*;
 pub use self::rng::*;
-#[cfg(any(feature = "tpm", feature = "vtpm"))]
+#[cfg(any(feature = "tpm", feature = "vtpm", feature = "swtpm"))]
 pub use self::tpm::*;
 #[cfg(any(feature = "video-decoder", feature = "video-encoder"))]
 pub use self::video::*;
 pub use self::virtio_device::*;
 pub use self::virtio_mmio_device::*;
 pub use self::virtio_pci_device::*;
+pub use self::vsock::*;
 cfg_if::cfg_if! {
     if #[cfg(unix)] {
+        mod mem;
         mod p9;
         mod pmem;
 
@@ -64,6 +68,7 @@ cfg_if::cfg_if! {
         pub mod net;
 
         pub use self::iommu::sys::unix::vfio_wrapper;
+        pub use self::mem::*;
         pub use self::net::*;
         pub use self::p9::*;
         pub use self::pmem::*;
@@ -72,8 +77,6 @@ cfg_if::cfg_if! {
         pub use self::wl::*;
 
     } else if #[cfg(windows)] {
-        mod vsock;
-
         #[cfg(feature = "slirp")]
         pub mod net;
 
@@ -81,7 +84,6 @@ cfg_if::cfg_if! {
         pub use self::net::*;
         #[cfg(feature = "slirp")]
         pub use self::sys::windows::NetExt;
-        pub use self::vsock::*;
     } else {
         compile_error!("Unsupported platform");
     }
@@ -91,6 +93,7 @@ use std::convert::TryFrom;
 
 use hypervisor::ProtectionType;
 use virtio_sys::virtio_config::VIRTIO_F_ACCESS_PLATFORM;
+use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
 use virtio_sys::virtio_config::VIRTIO_F_VERSION_1;
 use virtio_sys::virtio_ids;
 use virtio_sys::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
@@ -123,6 +126,7 @@ pub enum DeviceType {
     Sound = virtio_ids::VIRTIO_ID_SOUND,
     Fs = virtio_ids::VIRTIO_ID_FS,
     Pmem = virtio_ids::VIRTIO_ID_PMEM,
+    Mem = virtio_ids::VIRTIO_ID_MEM,
     Mac80211HwSim = virtio_ids::VIRTIO_ID_MAC80211_HWSIM,
     VideoEnc = virtio_ids::VIRTIO_ID_VIDEO_ENCODER,
     VideoDec = virtio_ids::VIRTIO_ID_VIDEO_DECODER,
@@ -154,6 +158,7 @@ impl std::fmt::Display for DeviceType {
             DeviceType::Sound => write!(f, "snd"),
             DeviceType::Fs => write!(f, "fs"),
             DeviceType::Pmem => write!(f, "pmem"),
+            DeviceType::Mem => write!(f, "mem"),
             DeviceType::Wl => write!(f, "wl"),
             DeviceType::Tpm => write!(f, "tpm"),
             DeviceType::VideoDec => write!(f, "video-decoder"),
@@ -189,7 +194,8 @@ pub fn copy_config(dst: &mut [u8], dst_offset: u64, src: &[u8], src_offset: u64)
 
 /// Returns the set of reserved base features common to all virtio devices.
 pub fn base_features(protection_type: ProtectionType) -> u64 {
-    let mut features: u64 = 1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_RING_F_EVENT_IDX;
+    let mut features: u64 =
+        1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_RING_F_EVENT_IDX | 1 << VIRTIO_F_RING_PACKED;
 
     if protection_type != ProtectionType::Unprotected {
         features |= 1 << VIRTIO_F_ACCESS_PLATFORM;
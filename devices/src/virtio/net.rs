@@ -24,11 +24,15 @@ use base::WaitContext;
 use data_model::DataInit;
 use data_model::Le16;
 use data_model::Le64;
+#[cfg(all(unix, feature = "slirp"))]
+use net_util::slirp::HostFwd;
 use net_util::Error as TapError;
 use net_util::MacAddress;
 use net_util::TapT;
 use remain::sorted;
 use serde::Deserialize;
+#[cfg(all(unix, feature = "slirp"))]
+use serde::Deserializer;
 use serde::Serialize;
 use thiserror::Error as ThisError;
 use virtio_sys::virtio_net;
@@ -154,6 +158,32 @@ pub enum NetParametersMode {
         netmask: Ipv4Addr,
         mac: MacAddress,
     },
+    /// User-mode networking through libslirp, e.g. `slirp,hostfwd=tcp:8080-:80`.
+    #[cfg(all(unix, feature = "slirp"))]
+    #[serde(rename_all = "kebab-case")]
+    Slirp {
+        slirp: bool,
+        #[serde(default, deserialize_with = "deserialize_hostfwds")]
+        hostfwd: Vec<HostFwd>,
+    },
+}
+
+/// Accepts either a single host forward or a `[..]` list of them.
+#[cfg(all(unix, feature = "slirp"))]
+fn deserialize_hostfwds<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<HostFwd>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(HostFwd),
+        Many(Vec<HostFwd>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(host_forward) => vec![host_forward],
+        OneOrMany::Many(host_forwards) => host_forwards,
+    })
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
@@ -907,4 +937,50 @@ mod tests {
         // invalid parameter
         assert!(from_net_arg("tap-name=tap,foomatic=true").is_err());
     }
+
+    #[cfg(all(unix, feature = "slirp"))]
+    #[test]
+    fn params_from_key_values_slirp() {
+        let params = from_net_arg("slirp").unwrap();
+        assert_eq!(
+            params,
+            NetParameters {
+                mode: NetParametersMode::Slirp {
+                    slirp: true,
+                    hostfwd: Vec::new(),
+                }
+            }
+        );
+
+        let params = from_net_arg("slirp,hostfwd=tcp:8080-:80").unwrap();
+        assert_eq!(
+            params,
+            NetParameters {
+                mode: NetParametersMode::Slirp {
+                    slirp: true,
+                    hostfwd: vec![HostFwd::from_str("tcp:8080-:80").unwrap()],
+                }
+            }
+        );
+
+        let params =
+            from_net_arg("slirp,hostfwd=[tcp:8080-:80,udp:127.0.0.1:5353-10.0.2.15:53]").unwrap();
+        assert_eq!(
+            params,
+            NetParameters {
+                mode: NetParametersMode::Slirp {
+                    slirp: true,
+                    hostfwd: vec![
+                        HostFwd::from_str("tcp:8080-:80").unwrap(),
+                        HostFwd::from_str("udp:127.0.0.1:5353-10.0.2.15:53").unwrap(),
+                    ],
+                }
+            }
+        );
+
+        // invalid forward
+        assert!(from_net_arg("slirp,hostfwd=tcp:8080").is_err());
+        // slirp cannot be combined with a tap
+        assert!(from_net_arg("slirp,tap-name=tap").is_err());
+    }
 }
@@ -2,6 +2,8 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+mod inflight;
+
 use std::cmp::min;
 use std::num::Wrapping;
 use std::sync::atomic::fence;
@@ -20,9 +22,12 @@ use data_model::DataInit;
 use data_model::Le16;
 use data_model::Le32;
 use data_model::Le64;
+use serde::Deserialize;
+use serde::Serialize;
 use smallvec::smallvec;
 use smallvec::SmallVec;
 use sync::Mutex;
+use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
 use virtio_sys::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::GuestAddress;
 use vm_memory::GuestMemory;
@@ -35,15 +40,26 @@ use crate::virtio::memory_mapper::MemRegion;
 use crate::virtio::memory_util::read_obj_from_addr_wrapper;
 use crate::virtio::memory_util::write_obj_at_addr_wrapper;
 
+pub use self::inflight::inflight_region_size;
+pub use self::inflight::InflightRegion;
+
 const VIRTQ_DESC_F_NEXT: u16 = 0x1;
 const VIRTQ_DESC_F_WRITE: u16 = 0x2;
 #[allow(dead_code)]
 const VIRTQ_DESC_F_INDIRECT: u16 = 0x4;
 
+const VIRTQ_DESC_F_AVAIL: u16 = 0x80;
+const VIRTQ_DESC_F_USED: u16 = 0x8000;
+
 const VIRTQ_USED_F_NO_NOTIFY: u16 = 0x1;
 #[allow(dead_code)]
 const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 0x1;
 
+// Values of the `flags` field of the packed ring event suppression structures.
+const RING_EVENT_FLAGS_ENABLE: u16 = 0x0;
+const RING_EVENT_FLAGS_DISABLE: u16 = 0x1;
+const RING_EVENT_FLAGS_DESC: u16 = 0x2;
+
 /// An iterator over a single descriptor chain.  Not to be confused with AvailIter,
 /// which iterates over the descriptor chain heads in a queue.
 pub struct DescIter {
@@ -113,6 +129,10 @@ pub struct DescriptorChain {
     /// The exported iommu region of the current descriptor. Present iff
     /// iommu is present.
     exported_region: Option<ExportedRegion>,
+
+    /// Whether the descriptor was read from a packed descriptor ring. In that case `index` is the
+    /// buffer ID of the chain and `next` is the position of the following descriptor in the ring.
+    packed: bool,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -126,6 +146,18 @@ pub struct Desc {
 // Safe because it only has data and has no implicit padding.
 unsafe impl DataInit for Desc {}
 
+/// A descriptor of a packed descriptor ring.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct PackedDesc {
+    pub addr: Le64,
+    pub len: Le32,
+    pub id: Le16,
+    pub flags: Le16,
+}
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for PackedDesc {}
+
 impl DescriptorChain {
     pub(crate) fn checked_new(
         mem: &GuestMemory,
@@ -146,6 +178,73 @@ impl DescriptorChain {
         let desc: Desc = read_obj_from_addr_wrapper(mem, &exported_desc_table, desc_head)
             .with_context(|| format!("failed to read desc {:x}", desc_head.offset()))?;
 
+        DescriptorChain::from_desc(
+            mem,
+            desc_table,
+            queue_size,
+            index,
+            desc,
+            required_flags,
+            iommu,
+            exported_desc_table,
+            false,
+        )
+    }
+
+    /// Reads the descriptor at position `ring_index` of a packed descriptor ring. The buffer ID of
+    /// a packed chain is only stored in its last descriptor, so the caller passes it in as `id`.
+    pub(crate) fn checked_new_packed(
+        mem: &GuestMemory,
+        desc_table: GuestAddress,
+        queue_size: u16,
+        ring_index: u16,
+        id: u16,
+        required_flags: u16,
+        iommu: Option<Arc<Mutex<IpcMemoryMapper>>>,
+        exported_desc_table: Option<ExportedRegion>,
+    ) -> Result<DescriptorChain> {
+        if ring_index >= queue_size {
+            bail!("index ({}) >= queue_size ({})", ring_index, queue_size);
+        }
+
+        let desc_addr = desc_table
+            .checked_add((ring_index as u64) * 16)
+            .context("integer overflow")?;
+        let desc: PackedDesc = read_obj_from_addr_wrapper(mem, &exported_desc_table, desc_addr)
+            .with_context(|| format!("failed to read desc {:x}", desc_addr.offset()))?;
+
+        // The descriptors of a packed chain are consecutive in the ring.
+        let desc = Desc {
+            addr: desc.addr,
+            len: desc.len,
+            flags: desc.flags,
+            next: Le16::from((ring_index + 1) % queue_size),
+        };
+
+        DescriptorChain::from_desc(
+            mem,
+            desc_table,
+            queue_size,
+            id,
+            desc,
+            required_flags,
+            iommu,
+            exported_desc_table,
+            true,
+        )
+    }
+
+    fn from_desc(
+        mem: &GuestMemory,
+        desc_table: GuestAddress,
+        queue_size: u16,
+        index: u16,
+        desc: Desc,
+        required_flags: u16,
+        iommu: Option<Arc<Mutex<IpcMemoryMapper>>>,
+        exported_desc_table: Option<ExportedRegion>,
+        packed: bool,
+    ) -> Result<DescriptorChain> {
         let addr = GuestAddress(desc.addr.into());
         let len = desc.len.to_native();
         let (regions, exported_region) = if let Some(iommu) = &iommu {
@@ -195,6 +294,7 @@ impl DescriptorChain {
             regions,
             exported_region,
             exported_desc_table,
+            packed,
         };
 
         if chain.is_valid() && chain.flags & required_flags == required_flags {
@@ -253,15 +353,29 @@ impl DescriptorChain {
             // Once we see a write-only descriptor, all subsequent descriptors must be write-only.
             let required_flags = self.flags & VIRTQ_DESC_F_WRITE;
             let iommu = self.iommu.as_ref().map(Arc::clone);
-            match DescriptorChain::checked_new(
-                &self.mem,
-                self.desc_table,
-                self.queue_size,
-                self.next,
-                required_flags,
-                iommu,
-                self.exported_desc_table.clone(),
-            ) {
+            let next = if self.packed {
+                DescriptorChain::checked_new_packed(
+                    &self.mem,
+                    self.desc_table,
+                    self.queue_size,
+                    self.next,
+                    self.index,
+                    required_flags,
+                    iommu,
+                    self.exported_desc_table.clone(),
+                )
+            } else {
+                DescriptorChain::checked_new(
+                    &self.mem,
+                    self.desc_table,
+                    self.queue_size,
+                    self.next,
+                    required_flags,
+                    iommu,
+                    self.exported_desc_table.clone(),
+                )
+            };
+            match next {
                 Ok(mut c) => {
                     c.ttl = self.ttl - 1;
                     Some(c)
@@ -322,24 +436,41 @@ pub struct Queue {
     /// Guest physical address of the descriptor table
     desc_table: GuestAddress,
 
-    /// Guest physical address of the available ring
+    /// Guest physical address of the available ring, or of the driver event suppression
+    /// structure of a packed ring
     avail_ring: GuestAddress,
 
-    /// Guest physical address of the used ring
+    /// Guest physical address of the used ring, or of the device event suppression structure of a
+    /// packed ring
     used_ring: GuestAddress,
 
+    // In a packed ring, these are positions in the descriptor ring.
     pub next_avail: Wrapping<u16>,
     pub next_used: Wrapping<u16>,
 
+    // Wrap counters of a packed ring, flipped each time `next_avail` and `next_used` wrap around
+    // the end of the descriptor ring.
+    pub avail_wrap_counter: bool,
+    pub used_wrap_counter: bool,
+
     // Device feature bits accepted by the driver
     features: u64,
     last_used: Wrapping<u16>,
+    // Value of `used_wrap_counter` when `last_used` was recorded, for packed rings.
+    last_used_wrap_counter: bool,
 
     // Count of notification disables. Users of the queue can disable guest notification while
     // processing requests. This is the count of how many are in flight(could be several contexts
     // handling requests in parallel). When this count is zero, notifications are re-enabled.
     notification_disable_count: usize,
 
+    // Buffer ID and number of descriptors of the packed chain returned by the last `peek`.
+    peeked_packed_chain: Option<(u16, u16)>,
+
+    // Number of descriptors of each packed chain popped but not yet used, indexed by buffer ID.
+    // The device must skip as many ring entries when it adds the buffer to the used ring.
+    packed_chain_lens: Vec<u16>,
+
     iommu: Option<Arc<Mutex<IpcMemoryMapper>>>,
 
     // When |iommu| is present, |desc_table| and the rings are IOVAs rather than real
@@ -348,6 +479,34 @@ pub struct Queue {
     exported_desc_table: Option<ExportedRegion>,
     exported_avail_ring: Option<ExportedRegion>,
     exported_used_ring: Option<ExportedRegion>,
+
+    // Inflight I/O tracking region set by `set_inflight`, along with the order in which the next
+    // descriptor chain will be popped.
+    inflight: Option<InflightRegion>,
+    inflight_counter: u64,
+
+    // Heads of the chains found inflight by `set_inflight`, which are popped again before the
+    // available ring is looked at. The last one is the first to be popped.
+    resubmit: Vec<u16>,
+}
+
+/// Serializable state of a `Queue`, as captured by `Queue::snapshot`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueSnapshot {
+    max_size: u16,
+    size: u16,
+    ready: bool,
+    vector: u16,
+    desc_table: GuestAddress,
+    avail_ring: GuestAddress,
+    used_ring: GuestAddress,
+    next_avail: u16,
+    next_used: u16,
+    avail_wrap_counter: bool,
+    used_wrap_counter: bool,
+    features: u64,
+    last_used: u16,
+    last_used_wrap_counter: bool,
 }
 
 macro_rules! accessors {
@@ -380,13 +539,21 @@ impl Queue {
             used_ring: GuestAddress(0),
             next_avail: Wrapping(0),
             next_used: Wrapping(0),
+            avail_wrap_counter: true,
+            used_wrap_counter: true,
             features: 0,
             last_used: Wrapping(0),
+            last_used_wrap_counter: true,
             notification_disable_count: 0,
+            peeked_packed_chain: None,
+            packed_chain_lens: Vec::new(),
             iommu: None,
             exported_desc_table: None,
             exported_avail_ring: None,
             exported_used_ring: None,
+            inflight: None,
+            inflight_counter: 0,
+            resubmit: Vec::new(),
         }
     }
 
@@ -414,11 +581,19 @@ impl Queue {
         self.used_ring = GuestAddress(0);
         self.next_avail = Wrapping(0);
         self.next_used = Wrapping(0);
+        self.avail_wrap_counter = true;
+        self.used_wrap_counter = true;
         self.features = 0;
         self.last_used = Wrapping(0);
+        self.last_used_wrap_counter = true;
+        self.peeked_packed_chain = None;
+        self.packed_chain_lens.clear();
         self.exported_desc_table = None;
         self.exported_avail_ring = None;
         self.exported_used_ring = None;
+        self.inflight = None;
+        self.inflight_counter = 0;
+        self.resubmit.clear();
     }
 
     /// Reset queue's counters.
@@ -427,7 +602,17 @@ impl Queue {
     pub fn reset_counters(&mut self) {
         self.next_avail = Wrapping(0);
         self.next_used = Wrapping(0);
+        self.avail_wrap_counter = true;
+        self.used_wrap_counter = true;
         self.last_used = Wrapping(0);
+        self.last_used_wrap_counter = true;
+        self.peeked_packed_chain = None;
+        self.packed_chain_lens.iter_mut().for_each(|len| *len = 0);
+    }
+
+    /// Returns whether the driver negotiated the packed virtqueue layout.
+    pub fn is_packed(&self) -> bool {
+        self.features & (1u64 << VIRTIO_F_RING_PACKED) != 0
     }
 
     pub fn is_valid(&mut self, mem: &GuestMemory) -> bool {
@@ -444,6 +629,13 @@ impl Queue {
 
     fn ring_sizes(&self) -> Vec<(GuestAddress, usize)> {
         let queue_size = self.actual_size() as usize;
+        if self.is_packed() {
+            return vec![
+                (self.desc_table, 16 * queue_size),
+                (self.avail_ring, 4),
+                (self.used_ring, 4),
+            ];
+        }
         vec![
             (self.desc_table, 16 * queue_size),
             (self.avail_ring, 6 + 2 * queue_size),
@@ -488,7 +680,11 @@ impl Queue {
     }
 
     fn validate(&mut self, mem: &GuestMemory) {
-        if self.size > self.max_size || self.size == 0 || (self.size & (self.size - 1)) != 0 {
+        // Only split queues are required to have a power of 2 size.
+        if self.size > self.max_size
+            || self.size == 0
+            || (!self.is_packed() && (self.size & (self.size - 1)) != 0)
+        {
             error!("virtio queue with invalid size: {}", self.size);
             return;
         }
@@ -514,6 +710,9 @@ impl Queue {
                 }
             }
         }
+        if self.is_packed() {
+            self.packed_chain_lens = vec![0; self.actual_size() as usize];
+        }
         self.validated = true;
     }
 
@@ -596,6 +795,17 @@ impl Queue {
             .unwrap();
     }
 
+    // Get the `idx` field in the used ring.
+    fn get_used_index(&self, mem: &GuestMemory) -> Wrapping<u16> {
+        fence(Ordering::SeqCst);
+
+        let used_index_addr = self.used_ring.unchecked_add(2);
+        let used_index: u16 =
+            read_obj_from_addr_wrapper(mem, &self.exported_used_ring, used_index_addr).unwrap();
+
+        Wrapping(used_index)
+    }
+
     // Set a single-bit flag in the used ring.
     //
     // Changes the bit specified by the mask in `flag` to `value`.
@@ -620,23 +830,28 @@ impl Queue {
             return None;
         }
 
-        let avail_index = self.get_avail_index(mem);
-        if self.next_avail == avail_index {
-            return None;
+        if self.is_packed() {
+            return self.peek_packed(mem);
         }
 
-        // This fence ensures that subsequent reads from the descriptor do not
-        // get reordered and happen only after fetching the available_index and
-        // checking that there is a slot available.
-        fence(Ordering::SeqCst);
-
         let queue_size = self.actual_size();
-        let desc_idx_addr_offset = 4 + (u64::from(self.next_avail.0 % queue_size) * 2);
-        let desc_idx_addr = self.avail_ring.checked_add(desc_idx_addr_offset)?;
+        let descriptor_index = match self.resubmit.last() {
+            Some(&head) => head,
+            None => {
+                let avail_index = self.get_avail_index(mem);
+                if self.next_avail == avail_index {
+                    return None;
+                }
 
-        // This index is checked below in checked_new.
-        let descriptor_index: u16 =
-            read_obj_from_addr_wrapper(mem, &self.exported_avail_ring, desc_idx_addr).unwrap();
+                // This fence ensures that subsequent reads from the descriptor do not
+                // get reordered and happen only after fetching the available_index and
+                // checking that there is a slot available.
+                fence(Ordering::SeqCst);
+
+                // This index is checked below in checked_new.
+                self.get_avail_entry(mem, self.next_avail)?
+            }
+        };
 
         let iommu = self.iommu.as_ref().map(Arc::clone);
         DescriptorChain::checked_new(
@@ -655,9 +870,111 @@ impl Queue {
         .ok()
     }
 
+    // Get the head of the descriptor chain at position `index` of the available ring.
+    fn get_avail_entry(&self, mem: &GuestMemory, index: Wrapping<u16>) -> Option<u16> {
+        let desc_idx_addr_offset = 4 + (u64::from(index.0 % self.actual_size()) * 2);
+        let desc_idx_addr = self.avail_ring.checked_add(desc_idx_addr_offset)?;
+        Some(read_obj_from_addr_wrapper(mem, &self.exported_avail_ring, desc_idx_addr).unwrap())
+    }
+
+    // Read the descriptor at position `ring_index` of the packed descriptor ring.
+    fn read_packed_desc(&self, mem: &GuestMemory, ring_index: u16) -> PackedDesc {
+        let desc_addr = self.desc_table.unchecked_add(u64::from(ring_index) * 16);
+        // This can't fail as `ring_index` is within the validated descriptor ring.
+        read_obj_from_addr_wrapper(mem, &self.exported_desc_table, desc_addr).unwrap()
+    }
+
+    fn peek_packed(&mut self, mem: &GuestMemory) -> Option<DescriptorChain> {
+        fence(Ordering::SeqCst);
+
+        let queue_size = self.actual_size();
+        let head = self.read_packed_desc(mem, self.next_avail.0);
+
+        // The driver makes a descriptor available by setting its AVAIL flag to the driver's wrap
+        // counter and its USED flag to the inverse of it.
+        let flags = head.flags.to_native();
+        if (flags & VIRTQ_DESC_F_AVAIL != 0) != self.avail_wrap_counter
+            || (flags & VIRTQ_DESC_F_USED != 0) == self.avail_wrap_counter
+        {
+            return None;
+        }
+
+        // This fence ensures that the rest of the chain is read only after checking that the head
+        // descriptor has been made available.
+        fence(Ordering::SeqCst);
+
+        // The buffer ID is only valid in the last descriptor of the chain, so walk the chain to
+        // find it along with the number of ring entries the chain occupies.
+        let mut desc = head;
+        let mut ring_index = self.next_avail.0;
+        let mut chain_len = 1;
+        while desc.flags.to_native() & VIRTQ_DESC_F_NEXT != 0 {
+            if chain_len == queue_size {
+                error!("packed descriptor chain is longer than the queue");
+                return None;
+            }
+            ring_index = (ring_index + 1) % queue_size;
+            desc = self.read_packed_desc(mem, ring_index);
+            chain_len += 1;
+        }
+
+        let id = desc.id.to_native();
+        if id >= queue_size {
+            error!("packed descriptor chain has invalid buffer id {}", id);
+            return None;
+        }
+
+        let iommu = self.iommu.as_ref().map(Arc::clone);
+        let chain = DescriptorChain::checked_new_packed(
+            mem,
+            self.desc_table,
+            queue_size,
+            self.next_avail.0,
+            id,
+            0,
+            iommu,
+            self.exported_desc_table.clone(),
+        )
+        .map_err(|e| {
+            error!("{:#}", e);
+            e
+        })
+        .ok()?;
+
+        self.peeked_packed_chain = Some((id, chain_len));
+        Some(chain)
+    }
+
     /// Remove the first available descriptor chain from the queue.
     /// This function should only be called immediately following `peek`.
     pub fn pop_peeked(&mut self, mem: &GuestMemory) {
+        if self.is_packed() {
+            if let Some((id, chain_len)) = self.peeked_packed_chain.take() {
+                self.packed_chain_lens[id as usize] = chain_len;
+                self.next_avail += Wrapping(chain_len);
+                if self.next_avail.0 >= self.actual_size() {
+                    self.next_avail -= Wrapping(self.actual_size());
+                    self.avail_wrap_counter = !self.avail_wrap_counter;
+                }
+            }
+            return;
+        }
+
+        // Chains being resubmitted are already counted in `next_avail` and marked inflight.
+        if self.resubmit.pop().is_some() {
+            return;
+        }
+
+        if let Some(inflight) = &self.inflight {
+            if let Some(head) = self
+                .get_avail_entry(mem, self.next_avail)
+                .filter(|&head| head < self.actual_size())
+            {
+                inflight.set_popped(head, self.inflight_counter);
+                self.inflight_counter += 1;
+            }
+        }
+
         self.next_avail += Wrapping(1);
         if self.features & ((1u64) << VIRTIO_RING_F_EVENT_IDX) != 0 {
             self.set_avail_event(mem, self.next_avail);
@@ -704,6 +1021,15 @@ impl Queue {
             return;
         }
 
+        if self.is_packed() {
+            self.add_used_packed(mem, desc_index, len);
+            return;
+        }
+
+        if let Some(inflight) = &self.inflight {
+            inflight.set_last_batch_head(desc_index);
+        }
+
         let used_ring = self.used_ring;
         let next_used = (self.next_used.0 % self.actual_size()) as usize;
         let used_elem = used_ring.unchecked_add((4 + next_used * 8) as u64);
@@ -721,6 +1047,66 @@ impl Queue {
 
         self.next_used += Wrapping(1);
         self.set_used_index(mem, self.next_used);
+
+        if let Some(inflight) = &self.inflight {
+            inflight.set_used(desc_index);
+            fence(Ordering::SeqCst);
+            inflight.set_used_idx(self.next_used.0);
+        }
+    }
+
+    // Write a used descriptor for buffer `id` at `next_used` in the packed descriptor ring.
+    fn add_used_packed(&mut self, mem: &GuestMemory, id: u16, len: u32) {
+        let chain_len = match self.packed_chain_lens.get_mut(id as usize) {
+            Some(chain_len) if *chain_len != 0 => std::mem::replace(chain_len, 0),
+            _ => {
+                error!("attempted to add unknown buffer to used ring: {}", id);
+                return;
+            }
+        };
+
+        let desc_addr = self
+            .desc_table
+            .unchecked_add(u64::from(self.next_used.0) * 16);
+        let mut flags = 0;
+        if len > 0 {
+            flags |= VIRTQ_DESC_F_WRITE;
+        }
+        if self.used_wrap_counter {
+            flags |= VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED;
+        }
+
+        // These writes can't fail as we are guaranteed to be within the descriptor ring.
+        write_obj_at_addr_wrapper(
+            mem,
+            &self.exported_desc_table,
+            Le32::from(len),
+            desc_addr.unchecked_add(8),
+        )
+        .unwrap();
+        write_obj_at_addr_wrapper(
+            mem,
+            &self.exported_desc_table,
+            Le16::from(id),
+            desc_addr.unchecked_add(12),
+        )
+        .unwrap();
+
+        // The flags hand the descriptor back to the driver, so they must be written last.
+        fence(Ordering::SeqCst);
+        write_obj_at_addr_wrapper(
+            mem,
+            &self.exported_desc_table,
+            Le16::from(flags),
+            desc_addr.unchecked_add(14),
+        )
+        .unwrap();
+
+        self.next_used += Wrapping(chain_len);
+        if self.next_used.0 >= self.actual_size() {
+            self.next_used -= Wrapping(self.actual_size());
+            self.used_wrap_counter = !self.used_wrap_counter;
+        }
     }
 
     /// Enable / Disable guest notify device that requests are available on
@@ -732,9 +1118,23 @@ impl Queue {
             self.notification_disable_count += 1;
         }
 
-        // We should only set VIRTQ_USED_F_NO_NOTIFY when the VIRTIO_RING_F_EVENT_IDX feature has
-        // not been negotiated.
-        if self.features & ((1u64) << VIRTIO_RING_F_EVENT_IDX) == 0 {
+        if self.is_packed() {
+            let flags = if self.notification_disable_count > 0 {
+                RING_EVENT_FLAGS_DISABLE
+            } else {
+                RING_EVENT_FLAGS_ENABLE
+            };
+            fence(Ordering::SeqCst);
+            write_obj_at_addr_wrapper(
+                mem,
+                &self.exported_used_ring,
+                Le16::from(flags),
+                self.used_ring.unchecked_add(2),
+            )
+            .unwrap();
+        } else if self.features & ((1u64) << VIRTIO_RING_F_EVENT_IDX) == 0 {
+            // We should only set VIRTQ_USED_F_NO_NOTIFY when the VIRTIO_RING_F_EVENT_IDX feature
+            // has not been negotiated.
             self.set_used_flag(
                 mem,
                 VIRTQ_USED_F_NO_NOTIFY,
@@ -865,6 +1265,9 @@ impl Queue {
     ///                              └──────────────────────────────────┘
     /// ```
     fn queue_wants_interrupt(&self, mem: &GuestMemory) -> bool {
+        if self.is_packed() {
+            return self.packed_queue_wants_interrupt(mem);
+        }
         if self.features & ((1u64) << VIRTIO_RING_F_EVENT_IDX) != 0 {
             let used_event = self.get_used_event(mem);
             self.next_used - used_event - Wrapping(1) < self.next_used - self.last_used
@@ -873,6 +1276,52 @@ impl Queue {
         }
     }
 
+    // Packed ring counterpart of `queue_wants_interrupt`, driven by the driver event suppression
+    // structure. With `VIRTIO_RING_F_EVENT_IDX`, the driver may ask to be notified once the device
+    // has used the descriptor at a given ring position and wrap counter.
+    //
+    // Ring positions only range up to the ring size, which doesn't divide 2^16 in general, so
+    // unlike for split rings the check can't rely on u16 wrapping. Instead, positions are turned
+    // into signed indices relative to the start of the current lap of `next_used`, using their wrap
+    // counter.
+    fn packed_queue_wants_interrupt(&self, mem: &GuestMemory) -> bool {
+        fence(Ordering::SeqCst);
+
+        let off_wrap: u16 =
+            read_obj_from_addr_wrapper(mem, &self.exported_avail_ring, self.avail_ring).unwrap();
+        let flags: u16 = read_obj_from_addr_wrapper(
+            mem,
+            &self.exported_avail_ring,
+            self.avail_ring.unchecked_add(2),
+        )
+        .unwrap();
+
+        match flags & 0x3 {
+            RING_EVENT_FLAGS_DISABLE => false,
+            RING_EVENT_FLAGS_DESC if self.features & ((1u64) << VIRTIO_RING_F_EVENT_IDX) != 0 => {
+                let size = i32::from(self.actual_size());
+                let lap_index = |pos: u16, wrap_counter: bool| {
+                    if wrap_counter == self.used_wrap_counter {
+                        i32::from(pos)
+                    } else {
+                        i32::from(pos) - size
+                    }
+                };
+                let next_used = i32::from(self.next_used.0);
+                // The driver only ever asks about the current or the previous lap.
+                let used_event = lap_index(off_wrap & 0x7fff, off_wrap & 0x8000 != 0);
+                let mut last_used = lap_index(self.last_used.0, self.last_used_wrap_counter);
+                if last_used > next_used {
+                    // The last interrupt was two laps ago.
+                    last_used -= 2 * size;
+                }
+                // Interrupt if the descriptor at `used_event` was used since the last interrupt.
+                last_used <= used_event && used_event < next_used
+            }
+            _ => true,
+        }
+    }
+
     /// inject interrupt into guest on this queue
     /// return true: interrupt is injected into guest for this queue
     ///        false: interrupt isn't injected
@@ -883,6 +1332,7 @@ impl Queue {
     ) -> bool {
         if self.queue_wants_interrupt(mem) {
             self.last_used = self.next_used;
+            self.last_used_wrap_counter = self.used_wrap_counter;
             interrupt.signal_used_queue(self.vector);
             true
         } else {
@@ -898,12 +1348,117 @@ impl Queue {
     pub fn set_iommu(&mut self, iommu: Arc<Mutex<IpcMemoryMapper>>) {
         self.iommu = Some(iommu);
     }
+
+    /// Records the descriptor chains popped from this ready split queue and not yet added to the
+    /// used ring in `inflight`, so that they are not lost if the device process restarts.
+    ///
+    /// If `inflight` was already used by a previous device process, the ring positions are
+    /// restored from it and the chains that process left inflight are popped again, in the order
+    /// in which they were first popped, before any new chain.
+    pub fn set_inflight(&mut self, mem: &GuestMemory, inflight: InflightRegion) -> Result<()> {
+        if !self.is_valid(mem) {
+            bail!("queue is not valid");
+        }
+        if self.is_packed() {
+            bail!("inflight tracking of packed queues is not supported");
+        }
+        let queue_size = self.actual_size();
+        if inflight.len() < inflight_region_size(queue_size) {
+            bail!(
+                "inflight region is too small for {} descriptors",
+                queue_size
+            );
+        }
+
+        let used_idx = self.get_used_index(mem);
+        if !inflight.is_initialized() {
+            inflight.initialize(queue_size, used_idx.0);
+        } else {
+            if inflight.desc_num() != queue_size {
+                bail!(
+                    "inflight region tracks {} descriptors instead of {}",
+                    inflight.desc_num(),
+                    queue_size
+                );
+            }
+            // The device stopped after updating the used ring but before clearing the last
+            // chain it added to it.
+            if inflight.used_idx() != used_idx.0 {
+                let head = inflight.last_batch_head();
+                if head < queue_size {
+                    inflight.set_used(head);
+                }
+                inflight.set_used_idx(used_idx.0);
+            }
+        }
+
+        let mut heads: Vec<(u64, u16)> = (0..queue_size)
+            .filter_map(|head| inflight.inflight(head).map(|counter| (counter, head)))
+            .collect();
+        heads.sort_unstable();
+
+        self.next_used = used_idx;
+        self.last_used = used_idx;
+        self.next_avail = used_idx + Wrapping(heads.len() as u16);
+        self.inflight_counter = heads.last().map_or(0, |(counter, _)| counter + 1);
+        self.resubmit = heads.into_iter().rev().map(|(_, head)| head).collect();
+        self.inflight = Some(inflight);
+        Ok(())
+    }
+
+    /// Returns whether chains left inflight by a previous device process are waiting to be popped
+    /// again. The guest will not notify the device about them.
+    pub fn has_resubmit(&self) -> bool {
+        !self.resubmit.is_empty()
+    }
+
+    /// Captures the configuration and ring positions of the queue. Requests popped from the queue
+    /// but not yet added to the used ring are not part of the snapshot, so the device must have
+    /// completed them beforehand.
+    pub fn snapshot(&self) -> QueueSnapshot {
+        QueueSnapshot {
+            max_size: self.max_size,
+            size: self.size,
+            ready: self.ready,
+            vector: self.vector,
+            desc_table: self.desc_table,
+            avail_ring: self.avail_ring,
+            used_ring: self.used_ring,
+            next_avail: self.next_avail.0,
+            next_used: self.next_used.0,
+            avail_wrap_counter: self.avail_wrap_counter,
+            used_wrap_counter: self.used_wrap_counter,
+            features: self.features,
+            last_used: self.last_used.0,
+            last_used_wrap_counter: self.last_used_wrap_counter,
+        }
+    }
+
+    /// Constructs a queue from state previously captured by `snapshot`.
+    pub fn restore(snapshot: &QueueSnapshot) -> Queue {
+        let mut queue = Queue::new(snapshot.max_size);
+        queue.size = snapshot.size;
+        queue.ready = snapshot.ready;
+        queue.vector = snapshot.vector;
+        queue.desc_table = snapshot.desc_table;
+        queue.avail_ring = snapshot.avail_ring;
+        queue.used_ring = snapshot.used_ring;
+        queue.next_avail = Wrapping(snapshot.next_avail);
+        queue.next_used = Wrapping(snapshot.next_used);
+        queue.avail_wrap_counter = snapshot.avail_wrap_counter;
+        queue.used_wrap_counter = snapshot.used_wrap_counter;
+        queue.features = snapshot.features;
+        queue.last_used = Wrapping(snapshot.last_used);
+        queue.last_used_wrap_counter = snapshot.last_used_wrap_counter;
+        queue
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::convert::TryInto;
 
+    use base::MemoryMappingBuilder;
     use memoffset::offset_of;
 
     use super::super::Interrupt;
@@ -1158,4 +1713,278 @@ mod tests {
         // should inject interrupt again.
         assert_eq!(queue.trigger_interrupt(&mem, &interrupt), true);
     }
+
+    fn setup_packed_vq(queue: &mut Queue, mem: &GuestMemory) {
+        let desc = PackedDesc {
+            addr: Le64::from(0u64),
+            len: Le32::from(0u32),
+            id: Le16::from(0u16),
+            flags: Le16::from(0u16),
+        };
+        for i in 0..QUEUE_SIZE as u64 {
+            let _ = mem.write_obj_at_addr(desc, GuestAddress(DESC_OFFSET + i * 16));
+        }
+        let _ = mem.write_obj_at_addr(0u32, GuestAddress(AVAIL_OFFSET));
+        let _ = mem.write_obj_at_addr(0u32, GuestAddress(USED_OFFSET));
+
+        queue.desc_table = GuestAddress(DESC_OFFSET);
+        queue.avail_ring = GuestAddress(AVAIL_OFFSET);
+        queue.used_ring = GuestAddress(USED_OFFSET);
+        queue.ack_features((1u64) << VIRTIO_F_RING_PACKED);
+        queue.set_ready(true);
+    }
+
+    // Makes a chain of `lens.len()` descriptors with buffer ID `id` available at `ring_index`, the
+    // way a driver whose wrap counter is `wrap` would.
+    fn add_packed_chain(mem: &GuestMemory, ring_index: u16, id: u16, lens: &[u32], wrap: bool) {
+        let avail_flags = if wrap {
+            VIRTQ_DESC_F_AVAIL
+        } else {
+            VIRTQ_DESC_F_USED
+        };
+        for (i, len) in lens.iter().enumerate() {
+            let mut flags = avail_flags | VIRTQ_DESC_F_WRITE;
+            if i + 1 < lens.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            let desc = PackedDesc {
+                addr: Le64::from(BUFFER_OFFSET + i as u64 * BUFFER_LEN as u64),
+                len: Le32::from(*len),
+                id: Le16::from(id),
+                flags: Le16::from(flags),
+            };
+            let pos = (ring_index as u64 + i as u64) % QUEUE_SIZE as u64;
+            let _ = mem.write_obj_at_addr(desc, GuestAddress(DESC_OFFSET + pos * 16));
+        }
+    }
+
+    fn read_packed_desc(mem: &GuestMemory, ring_index: u16) -> PackedDesc {
+        mem.read_obj_from_addr(GuestAddress(DESC_OFFSET + ring_index as u64 * 16))
+            .unwrap()
+    }
+
+    #[test]
+    fn packed_queue_pop_add_used() {
+        let mut queue = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        let mem = GuestMemory::new(&[(GuestAddress(0), GUEST_MEMORY_SIZE)]).unwrap();
+        setup_packed_vq(&mut queue, &mem);
+
+        assert!(queue.pop(&mem).is_none());
+
+        add_packed_chain(&mem, 0, 7, &[0x10, 0x20, 0x30], true);
+        let chain = queue.pop(&mem).expect("no chain available");
+        assert_eq!(chain.index, 7);
+        let lens: Vec<u32> = chain.into_iter().map(|d| d.len).collect();
+        assert_eq!(lens, vec![0x10, 0x20, 0x30]);
+        assert_eq!(queue.next_avail, Wrapping(3));
+        assert!(queue.pop(&mem).is_none());
+
+        queue.add_used(&mem, 7, 0x60);
+        let used = read_packed_desc(&mem, 0);
+        assert_eq!(used.id.to_native(), 7);
+        assert_eq!(used.len.to_native(), 0x60);
+        assert_eq!(
+            used.flags.to_native(),
+            VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED | VIRTQ_DESC_F_WRITE
+        );
+        assert_eq!(queue.next_used, Wrapping(3));
+
+        // Buffers that were not popped can't be used.
+        queue.add_used(&mem, 7, 0x60);
+        assert_eq!(queue.next_used, Wrapping(3));
+    }
+
+    #[test]
+    fn packed_queue_wrap() {
+        let mut queue = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        let mem = GuestMemory::new(&[(GuestAddress(0), GUEST_MEMORY_SIZE)]).unwrap();
+        setup_packed_vq(&mut queue, &mem);
+
+        // Fill all but the last two ring entries and hand them back.
+        let first_lap = QUEUE_SIZE as u16 - 2;
+        for i in 0..first_lap {
+            add_packed_chain(&mem, i, i, &[BUFFER_LEN], true);
+            let chain = queue.pop(&mem).unwrap();
+            queue.add_used(&mem, chain.index, 0);
+        }
+
+        // A chain spanning the end of the ring uses both wrap counter values.
+        add_packed_chain(&mem, first_lap, 1, &[1, 2, 3], true);
+        let mut desc = read_packed_desc(&mem, 0);
+        desc.flags = Le16::from(VIRTQ_DESC_F_USED | VIRTQ_DESC_F_WRITE);
+        let _ = mem.write_obj_at_addr(desc, GuestAddress(DESC_OFFSET));
+        let chain = queue.pop(&mem).unwrap();
+        assert_eq!(chain.into_iter().count(), 3);
+        assert_eq!(queue.next_avail, Wrapping(1));
+        assert!(!queue.avail_wrap_counter);
+
+        queue.add_used(&mem, 1, 6);
+        assert_eq!(queue.next_used, Wrapping(1));
+        assert!(!queue.used_wrap_counter);
+        // The used descriptor was written with the wrap counter of the first lap.
+        assert_eq!(
+            read_packed_desc(&mem, first_lap).flags.to_native(),
+            VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED | VIRTQ_DESC_F_WRITE
+        );
+
+        // Descriptors of the first lap are no longer available in the second one.
+        assert!(queue.pop(&mem).is_none());
+        add_packed_chain(&mem, 1, 2, &[BUFFER_LEN], false);
+        let chain = queue.pop(&mem).unwrap();
+        assert_eq!(chain.index, 2);
+        queue.add_used(&mem, 2, 0);
+        assert_eq!(read_packed_desc(&mem, 1).flags.to_native(), 0);
+    }
+
+    #[test]
+    fn packed_queue_event_suppression() {
+        let mut queue = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        let mem = GuestMemory::new(&[(GuestAddress(0), GUEST_MEMORY_SIZE)]).unwrap();
+        setup_packed_vq(&mut queue, &mem);
+        queue.ack_features((1u64) << VIRTIO_RING_F_EVENT_IDX);
+        let interrupt = Interrupt::new(IrqLevelEvent::new().unwrap(), None, 10);
+
+        for i in 0..4 {
+            add_packed_chain(&mem, i, i, &[BUFFER_LEN], true);
+            let chain = queue.pop(&mem).unwrap();
+            queue.add_used(&mem, chain.index, 0);
+        }
+
+        // Interrupts are enabled by default.
+        assert!(queue.trigger_interrupt(&mem, &interrupt));
+
+        let _ = mem.write_obj_at_addr(RING_EVENT_FLAGS_DISABLE, GuestAddress(AVAIL_OFFSET + 2));
+        add_packed_chain(&mem, 4, 4, &[BUFFER_LEN], true);
+        let chain = queue.pop(&mem).unwrap();
+        queue.add_used(&mem, chain.index, 0);
+        assert!(!queue.trigger_interrupt(&mem, &interrupt));
+
+        // Ask for an interrupt once the descriptor at position 6 of the first lap is used.
+        let _ = mem.write_obj_at_addr(6u16 | 0x8000, GuestAddress(AVAIL_OFFSET));
+        let _ = mem.write_obj_at_addr(RING_EVENT_FLAGS_DESC, GuestAddress(AVAIL_OFFSET + 2));
+        add_packed_chain(&mem, 5, 5, &[BUFFER_LEN], true);
+        let chain = queue.pop(&mem).unwrap();
+        queue.add_used(&mem, chain.index, 0);
+        assert!(!queue.trigger_interrupt(&mem, &interrupt));
+        add_packed_chain(&mem, 6, 6, &[BUFFER_LEN], true);
+        let chain = queue.pop(&mem).unwrap();
+        queue.add_used(&mem, chain.index, 0);
+        assert!(queue.trigger_interrupt(&mem, &interrupt));
+
+        // The device tells the driver when it doesn't need to be notified.
+        queue.set_notify(&mem, false);
+        let flags: u16 = mem
+            .read_obj_from_addr(GuestAddress(USED_OFFSET + 2))
+            .unwrap();
+        assert_eq!(flags, RING_EVENT_FLAGS_DISABLE);
+        queue.set_notify(&mem, true);
+        let flags: u16 = mem
+            .read_obj_from_addr(GuestAddress(USED_OFFSET + 2))
+            .unwrap();
+        assert_eq!(flags, RING_EVENT_FLAGS_ENABLE);
+    }
+
+    #[test]
+    fn packed_queue_event_suppression_wrap() {
+        let mut queue = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        let mem = GuestMemory::new(&[(GuestAddress(0), GUEST_MEMORY_SIZE)]).unwrap();
+        setup_packed_vq(&mut queue, &mem);
+        queue.ack_features((1u64) << VIRTIO_RING_F_EVENT_IDX);
+        let interrupt = Interrupt::new(IrqLevelEvent::new().unwrap(), None, 10);
+        let use_desc = |queue: &mut Queue, ring_index: u16, wrap: bool| {
+            add_packed_chain(&mem, ring_index, ring_index, &[BUFFER_LEN], wrap);
+            let chain = queue.pop(&mem).unwrap();
+            queue.add_used(&mem, chain.index, 0);
+        };
+
+        // Interrupt the driver right before the end of the first lap.
+        let last_lap_index = QUEUE_SIZE as u16 - 2;
+        for i in 0..last_lap_index {
+            use_desc(&mut queue, i, true);
+        }
+        assert!(queue.trigger_interrupt(&mem, &interrupt));
+
+        // An event that was already reached during the first lap doesn't trigger an interrupt
+        // once `next_used` has wrapped around.
+        let _ = mem.write_obj_at_addr(2u16 | 0x8000, GuestAddress(AVAIL_OFFSET));
+        let _ = mem.write_obj_at_addr(RING_EVENT_FLAGS_DESC, GuestAddress(AVAIL_OFFSET + 2));
+        use_desc(&mut queue, last_lap_index, true);
+        use_desc(&mut queue, last_lap_index + 1, true);
+        use_desc(&mut queue, 0, false);
+        assert!(!queue.used_wrap_counter);
+        assert!(!queue.trigger_interrupt(&mem, &interrupt));
+
+        // An event at the start of the second lap triggers one as soon as it is reached.
+        let _ = mem.write_obj_at_addr(1u16, GuestAddress(AVAIL_OFFSET));
+        assert!(!queue.trigger_interrupt(&mem, &interrupt));
+        use_desc(&mut queue, 1, false);
+        assert!(queue.trigger_interrupt(&mem, &interrupt));
+
+        // An event at the end of the first lap, set before the interrupt above, is already past.
+        let _ = mem.write_obj_at_addr((last_lap_index + 1) | 0x8000, GuestAddress(AVAIL_OFFSET));
+        use_desc(&mut queue, 2, false);
+        assert!(!queue.trigger_interrupt(&mem, &interrupt));
+    }
+
+    #[test]
+    fn queue_snapshot_restore() {
+        let mut queue = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        let memory_start_addr = GuestAddress(0x0);
+        let mem = GuestMemory::new(&[(memory_start_addr, GUEST_MEMORY_SIZE)]).unwrap();
+        setup_vq(&mut queue, &mem);
+        queue.set_ready(true);
+        for _ in 0..3 {
+            queue.add_used(&mem, 0x0, BUFFER_LEN);
+        }
+        queue.next_avail = Wrapping(5);
+
+        let snapshot = queue.snapshot();
+        let restored = Queue::restore(&snapshot);
+        assert_eq!(restored.snapshot(), snapshot);
+        assert!(restored.ready());
+        assert_eq!(restored.desc_table(), GuestAddress(DESC_OFFSET));
+        assert_eq!(restored.avail_ring(), GuestAddress(AVAIL_OFFSET));
+        assert_eq!(restored.used_ring(), GuestAddress(USED_OFFSET));
+        assert_eq!(restored.next_avail, Wrapping(5));
+        assert_eq!(restored.next_used, Wrapping(3));
+    }
+
+    #[test]
+    fn queue_inflight_resubmit() {
+        let mut queue = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        let memory_start_addr = GuestAddress(0x0);
+        let mem = GuestMemory::new(&[(memory_start_addr, GUEST_MEMORY_SIZE)]).unwrap();
+        setup_vq(&mut queue, &mem);
+        queue.set_ready(true);
+        for head in 0..3u16 {
+            let _ = mem.write_obj_at_addr(head, GuestAddress(AVAIL_OFFSET + 4 + 2 * head as u64));
+        }
+        let _ = mem.write_obj_at_addr(3u16, GuestAddress(AVAIL_OFFSET + 2));
+
+        let mapping = Arc::new(
+            MemoryMappingBuilder::new(inflight_region_size(QUEUE_SIZE as u16))
+                .build()
+                .unwrap(),
+        );
+        queue
+            .set_inflight(&mem, InflightRegion::new(mapping.clone(), 0))
+            .unwrap();
+        let heads: Vec<u16> = queue.iter(&mem).map(|chain| chain.index).collect();
+        assert_eq!(heads, vec![0, 1, 2]);
+        queue.add_used(&mem, 1, 0);
+
+        // A restarted device pops the chains that were not used again, in the same order.
+        let mut restarted = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        restarted.set_desc_table(GuestAddress(DESC_OFFSET));
+        restarted.set_avail_ring(GuestAddress(AVAIL_OFFSET));
+        restarted.set_used_ring(GuestAddress(USED_OFFSET));
+        restarted.set_ready(true);
+        restarted
+            .set_inflight(&mem, InflightRegion::new(mapping, 0))
+            .unwrap();
+        assert_eq!(restarted.next_used, Wrapping(1));
+        assert_eq!(restarted.next_avail, Wrapping(3));
+        let heads: Vec<u16> = restarted.iter(&mem).map(|chain| chain.index).collect();
+        assert_eq!(heads, vec![0, 2]);
+    }
 }
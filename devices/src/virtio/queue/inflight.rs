@@ -0,0 +1,134 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Inflight I/O tracking of split virtqueues, following the layout of the shared buffer described
+//! in the "Inflight I/O tracking" section of the vhost-user specification.
+//!
+//! The buffer outlives the device process, so a restarted device can find out which descriptor
+//! chains it had popped from the queue without adding them to the used ring, and process them
+//! again.
+
+use std::sync::Arc;
+
+use base::MappedRegion;
+use base::MemoryMapping;
+use data_model::DataInit;
+
+/// Version of the layout written in the header of an initialized region.
+const INFLIGHT_VERSION: u16 = 1;
+
+// Offsets of the fields of the region header.
+const VERSION_OFFSET: usize = 8;
+const DESC_NUM_OFFSET: usize = 10;
+const LAST_BATCH_HEAD_OFFSET: usize = 12;
+const USED_IDX_OFFSET: usize = 14;
+const HEADER_SIZE: usize = 16;
+
+// Size and offsets of the fields of the descriptor entries following the header.
+const DESC_SIZE: usize = 16;
+const DESC_INFLIGHT_OFFSET: usize = 0;
+const DESC_COUNTER_OFFSET: usize = 8;
+
+/// Returns the size of the region tracking a queue of `queue_size` descriptors.
+pub fn inflight_region_size(queue_size: u16) -> usize {
+    let size = HEADER_SIZE + DESC_SIZE * queue_size as usize;
+    // Regions are aligned to a cache line.
+    (size + 63) & !63
+}
+
+/// The region of an inflight I/O tracking buffer that belongs to one queue.
+#[derive(Clone)]
+pub struct InflightRegion {
+    mapping: Arc<MemoryMapping>,
+    offset: usize,
+}
+
+impl InflightRegion {
+    /// Creates the region starting at `offset` in `mapping`, which must be large enough to hold
+    /// `inflight_region_size(queue_size)` bytes from there.
+    pub fn new(mapping: Arc<MemoryMapping>, offset: usize) -> Self {
+        InflightRegion { mapping, offset }
+    }
+
+    // The offsets are checked against the mapping size in `Queue::set_inflight`, so the accesses
+    // below can't fail.
+    fn read<T: DataInit>(&self, offset: usize) -> T {
+        self.mapping
+            .read_obj_volatile(self.offset + offset)
+            .unwrap()
+    }
+
+    fn write<T: DataInit>(&self, offset: usize, val: T) {
+        self.mapping
+            .write_obj_volatile(val, self.offset + offset)
+            .unwrap()
+    }
+
+    /// Returns the number of bytes available in the mapping for this region.
+    pub(super) fn len(&self) -> usize {
+        self.mapping.size().saturating_sub(self.offset)
+    }
+
+    /// Returns whether the region has been set up by a previous call to `initialize`, as opposed
+    /// to being freshly allocated by the frontend.
+    pub(super) fn is_initialized(&self) -> bool {
+        self.read::<u16>(VERSION_OFFSET) == INFLIGHT_VERSION
+    }
+
+    /// Sets up a freshly allocated region for a queue of `desc_num` descriptors, of which the
+    /// driver has been handed back `used_idx` chains.
+    pub(super) fn initialize(&self, desc_num: u16, used_idx: u16) {
+        self.write(DESC_NUM_OFFSET, desc_num);
+        self.write(LAST_BATCH_HEAD_OFFSET, 0u16);
+        self.write(USED_IDX_OFFSET, used_idx);
+        self.write(VERSION_OFFSET, INFLIGHT_VERSION);
+    }
+
+    /// Returns the number of descriptors of the tracked queue.
+    pub(super) fn desc_num(&self) -> u16 {
+        self.read(DESC_NUM_OFFSET)
+    }
+
+    /// Returns the head of the last chain being added to the used ring.
+    pub(super) fn last_batch_head(&self) -> u16 {
+        self.read(LAST_BATCH_HEAD_OFFSET)
+    }
+
+    pub(super) fn set_last_batch_head(&self, head: u16) {
+        self.write(LAST_BATCH_HEAD_OFFSET, head);
+    }
+
+    /// Returns the index of the used ring once all the chains passed to `set_used` so far have
+    /// been added to it.
+    pub(super) fn used_idx(&self) -> u16 {
+        self.read(USED_IDX_OFFSET)
+    }
+
+    pub(super) fn set_used_idx(&self, used_idx: u16) {
+        self.write(USED_IDX_OFFSET, used_idx);
+    }
+
+    /// Returns whether the chain starting at descriptor `head` is inflight, along with the value
+    /// of the counter when it was popped.
+    pub(super) fn inflight(&self, head: u16) -> Option<u64> {
+        let desc = HEADER_SIZE + DESC_SIZE * head as usize;
+        if self.read::<u8>(desc + DESC_INFLIGHT_OFFSET) == 0 {
+            return None;
+        }
+        Some(self.read(desc + DESC_COUNTER_OFFSET))
+    }
+
+    /// Records that the chain starting at descriptor `head` has been popped, in order `counter`.
+    pub(super) fn set_popped(&self, head: u16, counter: u64) {
+        let desc = HEADER_SIZE + DESC_SIZE * head as usize;
+        self.write(desc + DESC_COUNTER_OFFSET, counter);
+        self.write(desc + DESC_INFLIGHT_OFFSET, 1u8);
+    }
+
+    /// Records that the chain starting at descriptor `head` has been added to the used ring.
+    pub(super) fn set_used(&self, head: u16) {
+        let desc = HEADER_SIZE + DESC_SIZE * head as usize;
+        self.write(desc + DESC_INFLIGHT_OFFSET, 0u8);
+    }
+}
@@ -0,0 +1,587 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Emulation of the SCSI commands a guest sends to a direct access block device.
+
+use std::cmp::min;
+use std::convert::TryInto;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use base::error;
+use data_model::VolatileSlice;
+use disk::DiskFile;
+
+use crate::virtio::Reader;
+use crate::virtio::Writer;
+
+// Operation codes of the supported commands.
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1a;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const UNMAP: u8 = 0x42;
+const MODE_SENSE_10: u8 = 0x5a;
+const READ_16: u8 = 0x88;
+const WRITE_16: u8 = 0x8a;
+const SYNCHRONIZE_CACHE_16: u8 = 0x91;
+const SERVICE_ACTION_IN_16: u8 = 0x9e;
+pub const REPORT_LUNS: u8 = 0xa0;
+
+// Service action of SERVICE ACTION IN(16).
+const READ_CAPACITY_16: u8 = 0x10;
+
+/// Status of a command that completed successfully.
+pub const GOOD: u8 = 0x00;
+/// Status of a command that failed, described by the sense data of the response.
+pub const CHECK_CONDITION: u8 = 0x02;
+
+// Sense keys.
+const NO_SENSE: u8 = 0x00;
+const MEDIUM_ERROR: u8 = 0x03;
+const HARDWARE_ERROR: u8 = 0x04;
+const ILLEGAL_REQUEST: u8 = 0x05;
+const DATA_PROTECT: u8 = 0x07;
+
+// Vital product data pages returned by INQUIRY.
+const VPD_SUPPORTED_PAGES: u8 = 0x00;
+const VPD_BLOCK_LIMITS: u8 = 0xb0;
+const VPD_LOGICAL_BLOCK_PROVISIONING: u8 = 0xb2;
+
+// Mode pages returned by MODE SENSE.
+const MODE_PAGE_CACHING: u8 = 0x08;
+const MODE_PAGE_ALL: u8 = 0x3f;
+
+// Maximum number of block descriptors in an UNMAP parameter list.
+const MAX_UNMAP_DESCRIPTORS: u32 = 256;
+
+/// Sense data describing why a command failed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Sense {
+    key: u8,
+    asc: u8,
+    ascq: u8,
+}
+
+impl Sense {
+    pub const NONE: Sense = Sense::new(NO_SENSE, 0x00, 0x00);
+    const WRITE_ERROR: Sense = Sense::new(MEDIUM_ERROR, 0x0c, 0x00);
+    const UNRECOVERED_READ_ERROR: Sense = Sense::new(MEDIUM_ERROR, 0x11, 0x00);
+    const INTERNAL_TARGET_FAILURE: Sense = Sense::new(HARDWARE_ERROR, 0x44, 0x00);
+    const PARAMETER_LIST_LENGTH_ERROR: Sense = Sense::new(ILLEGAL_REQUEST, 0x1a, 0x00);
+    const INVALID_OPCODE: Sense = Sense::new(ILLEGAL_REQUEST, 0x20, 0x00);
+    const LBA_OUT_OF_RANGE: Sense = Sense::new(ILLEGAL_REQUEST, 0x21, 0x00);
+    const INVALID_FIELD_IN_CDB: Sense = Sense::new(ILLEGAL_REQUEST, 0x24, 0x00);
+    pub const LUN_NOT_SUPPORTED: Sense = Sense::new(ILLEGAL_REQUEST, 0x25, 0x00);
+    const INVALID_FIELD_IN_PARAMETER_LIST: Sense = Sense::new(ILLEGAL_REQUEST, 0x26, 0x00);
+    const WRITE_PROTECTED: Sense = Sense::new(DATA_PROTECT, 0x27, 0x00);
+
+    const fn new(key: u8, asc: u8, ascq: u8) -> Sense {
+        Sense { key, asc, ascq }
+    }
+
+    /// Returns the sense data in fixed format.
+    pub fn to_fixed(self) -> [u8; 18] {
+        let mut sense = [0u8; 18];
+        // Current error, fixed format.
+        sense[0] = 0x70;
+        sense[2] = self.key;
+        // Additional sense length.
+        sense[7] = 10;
+        sense[12] = self.asc;
+        sense[13] = self.ascq;
+        sense
+    }
+}
+
+type Result<T> = std::result::Result<T, Sense>;
+
+/// Source of the data sent with a command.
+pub trait DataReader: Read {
+    /// Returns the number of bytes left to read.
+    fn available_bytes(&self) -> usize;
+    /// Reads `count` bytes and writes them to `disk` at `offset`.
+    fn read_to_disk(
+        &mut self,
+        disk: &mut dyn DiskFile,
+        count: usize,
+        offset: u64,
+    ) -> io::Result<()>;
+}
+
+/// Destination of the data returned by a command.
+pub trait DataWriter: Write {
+    /// Returns the number of bytes that can still be written.
+    fn available_bytes(&self) -> usize;
+    /// Reads `count` bytes from `disk` at `offset` and writes them.
+    fn write_from_disk(
+        &mut self,
+        disk: &mut dyn DiskFile,
+        count: usize,
+        offset: u64,
+    ) -> io::Result<()>;
+}
+
+impl DataReader for Reader {
+    fn available_bytes(&self) -> usize {
+        Reader::available_bytes(self)
+    }
+
+    fn read_to_disk(
+        &mut self,
+        disk: &mut dyn DiskFile,
+        count: usize,
+        offset: u64,
+    ) -> io::Result<()> {
+        self.read_exact_to_at(disk, count, offset)
+    }
+}
+
+impl DataWriter for Writer {
+    fn available_bytes(&self) -> usize {
+        Writer::available_bytes(self)
+    }
+
+    fn write_from_disk(
+        &mut self,
+        disk: &mut dyn DiskFile,
+        count: usize,
+        offset: u64,
+    ) -> io::Result<()> {
+        self.write_all_from_at(disk, count, offset)
+    }
+}
+
+/// A `DataReader` over data that was received before the command is executed.
+pub struct BufferReader {
+    data: Vec<u8>,
+    offset: usize,
+}
+
+impl BufferReader {
+    pub fn new(data: Vec<u8>) -> BufferReader {
+        BufferReader { data, offset: 0 }
+    }
+}
+
+impl Read for BufferReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = min(buf.len(), self.data.len() - self.offset);
+        buf[..len].copy_from_slice(&self.data[self.offset..self.offset + len]);
+        self.offset += len;
+        Ok(len)
+    }
+}
+
+impl DataReader for BufferReader {
+    fn available_bytes(&self) -> usize {
+        self.data.len() - self.offset
+    }
+
+    fn read_to_disk(
+        &mut self,
+        disk: &mut dyn DiskFile,
+        count: usize,
+        offset: u64,
+    ) -> io::Result<()> {
+        if count > DataReader::available_bytes(self) {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        let data = &mut self.data[self.offset..self.offset + count];
+        disk.write_all_at_volatile(VolatileSlice::new(data), offset)?;
+        self.offset += count;
+        Ok(())
+    }
+}
+
+/// A `DataWriter` that collects the data returned by a command, up to `capacity` bytes.
+pub struct BufferWriter {
+    data: Vec<u8>,
+    capacity: usize,
+}
+
+impl BufferWriter {
+    pub fn new(capacity: usize) -> BufferWriter {
+        BufferWriter {
+            data: Vec::new(),
+            capacity,
+        }
+    }
+
+    /// Returns the data written so far.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Write for BufferWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = min(buf.len(), self.capacity - self.data.len());
+        self.data.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl DataWriter for BufferWriter {
+    fn available_bytes(&self) -> usize {
+        self.capacity - self.data.len()
+    }
+
+    fn write_from_disk(
+        &mut self,
+        disk: &mut dyn DiskFile,
+        count: usize,
+        offset: u64,
+    ) -> io::Result<()> {
+        if count > DataWriter::available_bytes(self) {
+            return Err(io::Error::from(io::ErrorKind::WriteZero));
+        }
+        let start = self.data.len();
+        self.data.resize(start + count, 0);
+        disk.read_exact_at_volatile(VolatileSlice::new(&mut self.data[start..]), offset)
+    }
+}
+
+fn be16(b: &[u8]) -> u16 {
+    u16::from_be_bytes(b[..2].try_into().unwrap())
+}
+
+fn be32(b: &[u8]) -> u32 {
+    u32::from_be_bytes(b[..4].try_into().unwrap())
+}
+
+fn be64(b: &[u8]) -> u64 {
+    u64::from_be_bytes(b[..8].try_into().unwrap())
+}
+
+// Returns `data` to the guest, truncated to the allocation length of the command.
+fn write_data(writer: &mut dyn DataWriter, data: &[u8], allocation_length: usize) -> Result<()> {
+    let len = min(min(data.len(), allocation_length), writer.available_bytes());
+    writer.write_all(&data[..len]).map_err(|e| {
+        error!("failed to write SCSI data: {}", e);
+        Sense::INTERNAL_TARGET_FAILURE
+    })
+}
+
+/// Answers REPORT LUNS with the LUNs `0..num_luns`, whichever LUN the command was sent to.
+pub fn report_luns(cdb: &[u8], num_luns: usize, writer: &mut dyn DataWriter) -> Result<()> {
+    let allocation_length = be32(&cdb[6..]) as usize;
+    let mut data = vec![0u8; 8 + 8 * num_luns];
+    data[0..4].copy_from_slice(&((8 * num_luns) as u32).to_be_bytes());
+    for lun in 0..num_luns {
+        // Peripheral device addressing, which covers LUNs up to 255.
+        data[8 + 8 * lun + 1] = lun as u8;
+    }
+    write_data(writer, &data, allocation_length)
+}
+
+/// Answers a command sent to a LUN that does not exist. INQUIRY reports that no device is there,
+/// and the other commands fail.
+pub fn execute_missing_lun(cdb: &[u8], writer: &mut dyn DataWriter) -> Result<()> {
+    match cdb[0] {
+        INQUIRY if cdb[1] & 0x01 == 0 => {
+            let mut data = [0u8; 36];
+            // Peripheral qualifier 3: not capable of supporting a device at this LUN.
+            data[0] = 0x7f;
+            data[4] = 31;
+            write_data(writer, &data, be16(&cdb[3..]) as usize)
+        }
+        REQUEST_SENSE => write_data(
+            writer,
+            &Sense::LUN_NOT_SUPPORTED.to_fixed(),
+            cdb[4] as usize,
+        ),
+        _ => Err(Sense::LUN_NOT_SUPPORTED),
+    }
+}
+
+/// A direct access block device backed by a disk image.
+pub struct LogicalUnit {
+    disk: Box<dyn DiskFile>,
+    read_only: bool,
+    sparse: bool,
+    block_size: u32,
+    num_blocks: u64,
+}
+
+impl LogicalUnit {
+    /// Create a logical unit exposing `disk` in blocks of `block_size` bytes. `sparse` allows the
+    /// guest to discard blocks with UNMAP.
+    pub fn new(
+        disk: Box<dyn DiskFile>,
+        read_only: bool,
+        sparse: bool,
+        block_size: u32,
+    ) -> io::Result<LogicalUnit> {
+        if block_size == 0 || block_size % 512 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid block size {}", block_size),
+            ));
+        }
+        let num_blocks = disk.get_len()? / u64::from(block_size);
+        Ok(LogicalUnit {
+            disk,
+            read_only,
+            sparse,
+            block_size,
+            num_blocks,
+        })
+    }
+
+    /// Returns the disk image backing the logical unit.
+    pub fn disk(&self) -> &dyn DiskFile {
+        &*self.disk
+    }
+
+    fn supports_unmap(&self) -> bool {
+        self.sparse && !self.read_only
+    }
+
+    /// Executes the command in `cdb`. The data sent with the command is read from `reader` and the
+    /// data returned by the command is written to `writer`.
+    pub fn execute(
+        &mut self,
+        cdb: &[u8],
+        reader: &mut dyn DataReader,
+        writer: &mut dyn DataWriter,
+    ) -> Result<()> {
+        match cdb[0] {
+            TEST_UNIT_READY => Ok(()),
+            // Errors are reported with the response of the failed command, so there is never a
+            // pending sense.
+            REQUEST_SENSE => write_data(writer, &Sense::NONE.to_fixed(), cdb[4] as usize),
+            INQUIRY => self.inquiry(cdb, writer),
+            MODE_SENSE_6 | MODE_SENSE_10 => self.mode_sense(cdb, writer),
+            READ_CAPACITY_10 => {
+                let mut data = [0u8; 8];
+                let last_lba = min(self.num_blocks.saturating_sub(1), u32::MAX.into()) as u32;
+                data[0..4].copy_from_slice(&last_lba.to_be_bytes());
+                data[4..8].copy_from_slice(&self.block_size.to_be_bytes());
+                write_data(writer, &data, data.len())
+            }
+            SERVICE_ACTION_IN_16 if cdb[1] & 0x1f == READ_CAPACITY_16 => {
+                let mut data = [0u8; 32];
+                data[0..8].copy_from_slice(&self.num_blocks.saturating_sub(1).to_be_bytes());
+                data[8..12].copy_from_slice(&self.block_size.to_be_bytes());
+                if self.supports_unmap() {
+                    // LBPME: logical block provisioning management enabled.
+                    data[14] = 0x80;
+                }
+                write_data(writer, &data, be32(&cdb[10..]) as usize)
+            }
+            READ_10 => self.read(be32(&cdb[2..]).into(), be16(&cdb[7..]).into(), writer),
+            READ_16 => self.read(be64(&cdb[2..]), be32(&cdb[10..]), writer),
+            WRITE_10 => self.write(
+                be32(&cdb[2..]).into(),
+                be16(&cdb[7..]).into(),
+                cdb[1] & 0x08 != 0,
+                reader,
+            ),
+            WRITE_16 => self.write(
+                be64(&cdb[2..]),
+                be32(&cdb[10..]),
+                cdb[1] & 0x08 != 0,
+                reader,
+            ),
+            SYNCHRONIZE_CACHE_10 | SYNCHRONIZE_CACHE_16 => self.flush(),
+            UNMAP if self.supports_unmap() => self.unmap(cdb, reader),
+            UNMAP if self.read_only => Err(Sense::WRITE_PROTECTED),
+            opcode => {
+                error!("unsupported SCSI command {:#x}", opcode);
+                Err(Sense::INVALID_OPCODE)
+            }
+        }
+    }
+
+    fn inquiry(&self, cdb: &[u8], writer: &mut dyn DataWriter) -> Result<()> {
+        let allocation_length = be16(&cdb[3..]) as usize;
+        let evpd = cdb[1] & 0x01 != 0;
+        let page = cdb[2];
+
+        if !evpd {
+            if page != 0 {
+                return Err(Sense::INVALID_FIELD_IN_CDB);
+            }
+            let mut data = [0u8; 36];
+            // Peripheral device type 0: direct access block device.
+            data[0] = 0x00;
+            // Version: SPC-4.
+            data[2] = 0x06;
+            // Response data format 2.
+            data[3] = 0x02;
+            data[4] = (data.len() - 5) as u8;
+            // CMDQUE: the device supports command queuing.
+            data[7] = 0x02;
+            data[8..16].copy_from_slice(b"CROSVM  ");
+            data[16..32].copy_from_slice(b"CROSVM HARDDISK ");
+            data[32..36].copy_from_slice(b"0.1 ");
+            return write_data(writer, &data, allocation_length);
+        }
+
+        let mut data = vec![0u8, page, 0, 0];
+        match page {
+            VPD_SUPPORTED_PAGES => {
+                data.extend_from_slice(&[
+                    VPD_SUPPORTED_PAGES,
+                    VPD_BLOCK_LIMITS,
+                    VPD_LOGICAL_BLOCK_PROVISIONING,
+                ]);
+            }
+            VPD_BLOCK_LIMITS => {
+                let mut limits = [0u8; 60];
+                if self.supports_unmap() {
+                    // Maximum unmap LBA count and maximum unmap block descriptor count.
+                    limits[16..20].copy_from_slice(&u32::MAX.to_be_bytes());
+                    limits[20..24].copy_from_slice(&MAX_UNMAP_DESCRIPTORS.to_be_bytes());
+                }
+                data.extend_from_slice(&limits);
+            }
+            VPD_LOGICAL_BLOCK_PROVISIONING => {
+                let mut provisioning = [0u8; 4];
+                if self.supports_unmap() {
+                    // LBPU: UNMAP is supported.
+                    provisioning[1] = 0x80;
+                    // Provisioning type 2: thin provisioned.
+                    provisioning[2] = 0x02;
+                }
+                data.extend_from_slice(&provisioning);
+            }
+            _ => return Err(Sense::INVALID_FIELD_IN_CDB),
+        }
+        let page_length = (data.len() - 4) as u16;
+        data[2..4].copy_from_slice(&page_length.to_be_bytes());
+        write_data(writer, &data, allocation_length)
+    }
+
+    fn mode_sense(&self, cdb: &[u8], writer: &mut dyn DataWriter) -> Result<()> {
+        let page_code = cdb[2] & 0x3f;
+        // Page control 1 asks for the changeable values, and none can be changed.
+        let changeable = cdb[2] >> 6 == 1;
+        let (header_len, allocation_length) = if cdb[0] == MODE_SENSE_6 {
+            (4, cdb[4] as usize)
+        } else {
+            (8, be16(&cdb[7..]) as usize)
+        };
+
+        let mut data = vec![0u8; header_len];
+        match page_code {
+            MODE_PAGE_CACHING | MODE_PAGE_ALL => {
+                let mut page = [0u8; 20];
+                page[0] = MODE_PAGE_CACHING;
+                page[1] = (page.len() - 2) as u8;
+                if !changeable {
+                    // WCE: writes are cached until SYNCHRONIZE CACHE.
+                    page[2] = 0x04;
+                }
+                data.extend_from_slice(&page);
+            }
+            _ => return Err(Sense::INVALID_FIELD_IN_CDB),
+        }
+
+        // The device-specific parameter holds the write protect bit.
+        let device_specific = if self.read_only { 0x80 } else { 0x00 };
+        if cdb[0] == MODE_SENSE_6 {
+            data[0] = (data.len() - 1) as u8;
+            data[2] = device_specific;
+        } else {
+            let mode_data_length = (data.len() - 2) as u16;
+            data[0..2].copy_from_slice(&mode_data_length.to_be_bytes());
+            data[3] = device_specific;
+        }
+        write_data(writer, &data, allocation_length)
+    }
+
+    // Returns the byte offset and length of `num_blocks` blocks starting at `lba`.
+    fn byte_range(&self, lba: u64, num_blocks: u32) -> Result<(u64, usize)> {
+        match lba.checked_add(num_blocks.into()) {
+            Some(end) if end <= self.num_blocks => Ok((
+                lba * u64::from(self.block_size),
+                num_blocks as usize * self.block_size as usize,
+            )),
+            _ => Err(Sense::LBA_OUT_OF_RANGE),
+        }
+    }
+
+    fn read(&mut self, lba: u64, num_blocks: u32, writer: &mut dyn DataWriter) -> Result<()> {
+        let (offset, len) = self.byte_range(lba, num_blocks)?;
+        writer
+            .write_from_disk(&mut *self.disk, len, offset)
+            .map_err(|e| {
+                error!("failed to read from SCSI disk: {}", e);
+                Sense::UNRECOVERED_READ_ERROR
+            })
+    }
+
+    fn write(
+        &mut self,
+        lba: u64,
+        num_blocks: u32,
+        fua: bool,
+        reader: &mut dyn DataReader,
+    ) -> Result<()> {
+        if self.read_only {
+            return Err(Sense::WRITE_PROTECTED);
+        }
+        let (offset, len) = self.byte_range(lba, num_blocks)?;
+        reader
+            .read_to_disk(&mut *self.disk, len, offset)
+            .map_err(|e| {
+                error!("failed to write to SCSI disk: {}", e);
+                Sense::WRITE_ERROR
+            })?;
+        // Force unit access: the data must be on the disk before the command completes.
+        if fua {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.disk.fsync().map_err(|e| {
+            error!("failed to flush SCSI disk: {}", e);
+            Sense::WRITE_ERROR
+        })
+    }
+
+    fn unmap(&mut self, cdb: &[u8], reader: &mut dyn DataReader) -> Result<()> {
+        let parameter_list_length = be16(&cdb[7..]) as usize;
+        if parameter_list_length == 0 {
+            return Ok(());
+        }
+        if parameter_list_length < 8 || parameter_list_length > reader.available_bytes() {
+            return Err(Sense::PARAMETER_LIST_LENGTH_ERROR);
+        }
+
+        let mut parameters = vec![0u8; parameter_list_length];
+        reader
+            .read_exact(&mut parameters)
+            .map_err(|_| Sense::PARAMETER_LIST_LENGTH_ERROR)?;
+        let descriptors_length = be16(&parameters[2..]) as usize;
+        let descriptors = parameters
+            .get(8..8 + descriptors_length)
+            .ok_or(Sense::PARAMETER_LIST_LENGTH_ERROR)?;
+        if descriptors.len() / 16 > MAX_UNMAP_DESCRIPTORS as usize {
+            return Err(Sense::INVALID_FIELD_IN_PARAMETER_LIST);
+        }
+
+        for descriptor in descriptors.chunks_exact(16) {
+            let (offset, len) = self.byte_range(be64(descriptor), be32(&descriptor[8..]))?;
+            self.disk.punch_hole(offset, len as u64).map_err(|e| {
+                error!("failed to unmap SCSI disk blocks: {}", e);
+                Sense::WRITE_ERROR
+            })?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,610 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::io;
+use std::mem::size_of;
+use std::thread;
+
+use base::error;
+use base::Event;
+use base::RawDescriptor;
+use cros_async::select4;
+use cros_async::EventAsync;
+use cros_async::Executor;
+use data_model::DataInit;
+use data_model::Le16;
+use data_model::Le32;
+use data_model::Le64;
+use futures::pin_mut;
+use remain::sorted;
+use thiserror::Error;
+use vm_memory::GuestMemory;
+
+use super::commands;
+use super::commands::LogicalUnit;
+use super::commands::Sense;
+use crate::virtio::async_utils;
+use crate::virtio::copy_config;
+use crate::virtio::DescriptorChain;
+use crate::virtio::DescriptorError;
+use crate::virtio::DeviceType;
+use crate::virtio::Interrupt;
+use crate::virtio::Queue;
+use crate::virtio::Reader;
+use crate::virtio::VirtioDevice;
+use crate::virtio::Writer;
+use crate::Suspendable;
+
+// The control, event and request queues.
+const QUEUE_SIZE: u16 = 128;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE];
+
+const CDB_SIZE: usize = 32;
+const SENSE_SIZE: usize = 96;
+
+/// Maximum number of LUNs of a controller. They are all on target 0.
+pub const MAX_LUNS: usize = 256;
+
+const VIRTIO_SCSI_T_TMF: u32 = 0;
+const VIRTIO_SCSI_T_AN_QUERY: u32 = 1;
+const VIRTIO_SCSI_T_AN_SUBSCRIBE: u32 = 2;
+
+const VIRTIO_SCSI_S_OK: u8 = 0;
+const VIRTIO_SCSI_S_FUNCTION_COMPLETE: u8 = 0;
+const VIRTIO_SCSI_S_BAD_TARGET: u8 = 3;
+const VIRTIO_SCSI_S_FUNCTION_REJECTED: u8 = 11;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_scsi_config {
+    num_queues: Le32,
+    seg_max: Le32,
+    max_sectors: Le32,
+    cmd_per_lun: Le32,
+    event_info_size: Le32,
+    sense_size: Le32,
+    cdb_size: Le32,
+    max_channel: Le16,
+    max_target: Le16,
+    max_lun: Le32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_scsi_config {}
+
+#[derive(Copy, Clone, Default)]
+#[repr(C, packed)]
+struct virtio_scsi_cmd_req {
+    lun: [u8; 8],
+    tag: Le64,
+    task_attr: u8,
+    prio: u8,
+    crn: u8,
+    cdb: [u8; CDB_SIZE],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_scsi_cmd_req {}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct virtio_scsi_cmd_resp {
+    sense_len: Le32,
+    resid: Le32,
+    status_qualifier: Le16,
+    status: u8,
+    response: u8,
+    sense: [u8; SENSE_SIZE],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_scsi_cmd_resp {}
+
+impl virtio_scsi_cmd_resp {
+    fn new(response: u8) -> virtio_scsi_cmd_resp {
+        virtio_scsi_cmd_resp {
+            sense_len: 0.into(),
+            resid: 0.into(),
+            status_qualifier: 0.into(),
+            status: commands::GOOD,
+            response,
+            sense: [0u8; SENSE_SIZE],
+        }
+    }
+}
+
+#[sorted]
+#[derive(Error, Debug)]
+enum Error {
+    /// Invalid virtio descriptor chain.
+    #[error("virtio descriptor error: {0}")]
+    Descriptor(DescriptorError),
+    /// Failed to read from virtqueue.
+    #[error("failed to read from virtqueue: {0}")]
+    ReadQueue(io::Error),
+    /// Failed to write to virtqueue.
+    #[error("failed to write to virtqueue: {0}")]
+    WriteQueue(io::Error),
+}
+
+type Result<T> = ::std::result::Result<T, Error>;
+
+// Returns the index of the LUN addressed by a request, or `None` if it is not on target 0.
+fn parse_lun(lun: &[u8; 8]) -> Option<usize> {
+    if lun[0] != 1 || lun[1] != 0 {
+        return None;
+    }
+    Some(((lun[2] as usize & 0x3f) << 8) | lun[3] as usize)
+}
+
+fn execute_command(
+    req: &virtio_scsi_cmd_req,
+    luns: &mut [LogicalUnit],
+    reader: &mut Reader,
+    writer: &mut Writer,
+) -> virtio_scsi_cmd_resp {
+    let lun = match parse_lun(&req.lun) {
+        Some(lun) => lun,
+        None => return virtio_scsi_cmd_resp::new(VIRTIO_SCSI_S_BAD_TARGET),
+    };
+
+    let num_luns = luns.len();
+    let cdb = &req.cdb;
+    let result = match luns.get_mut(lun) {
+        _ if cdb[0] == commands::REPORT_LUNS => commands::report_luns(cdb, num_luns, writer),
+        Some(lun) => lun.execute(cdb, reader, writer),
+        None => commands::execute_missing_lun(cdb, writer),
+    };
+
+    let mut resp = virtio_scsi_cmd_resp::new(VIRTIO_SCSI_S_OK);
+    if let Err(sense) = result {
+        let sense = Sense::to_fixed(sense);
+        resp.status = commands::CHECK_CONDITION;
+        resp.sense_len = (sense.len() as u32).into();
+        resp.sense[..sense.len()].copy_from_slice(&sense);
+    }
+    resp
+}
+
+fn process_request(
+    mem: &GuestMemory,
+    avail_desc: DescriptorChain,
+    luns: &mut [LogicalUnit],
+) -> Result<usize> {
+    let mut reader = Reader::new(mem.clone(), avail_desc.clone()).map_err(Error::Descriptor)?;
+    let mut writer = Writer::new(mem.clone(), avail_desc).map_err(Error::Descriptor)?;
+
+    let req: virtio_scsi_cmd_req = reader.read_obj().map_err(Error::ReadQueue)?;
+    // The data returned by the command follows the response.
+    let mut data_writer = writer.split_at(size_of::<virtio_scsi_cmd_resp>());
+
+    let mut resp = execute_command(&req, luns, &mut reader, &mut data_writer);
+    // A command either sends or receives data, so only one of them is left over.
+    let resid = reader.available_bytes() + data_writer.available_bytes();
+    resp.resid = (resid as u32).into();
+
+    writer.write_obj(resp).map_err(Error::WriteQueue)?;
+    Ok(writer.bytes_written() + data_writer.bytes_written())
+}
+
+fn process_control_request(mem: &GuestMemory, avail_desc: DescriptorChain) -> Result<usize> {
+    let mut reader = Reader::new(mem.clone(), avail_desc.clone()).map_err(Error::Descriptor)?;
+    let mut writer = Writer::new(mem.clone(), avail_desc).map_err(Error::Descriptor)?;
+
+    let type_: Le32 = reader.read_obj().map_err(Error::ReadQueue)?;
+    match type_.to_native() {
+        VIRTIO_SCSI_T_TMF => {
+            // Commands complete before the next one is read, so there is never a task to abort or
+            // a LUN to reset.
+            writer
+                .write_obj(VIRTIO_SCSI_S_FUNCTION_COMPLETE)
+                .map_err(Error::WriteQueue)?;
+        }
+        VIRTIO_SCSI_T_AN_QUERY | VIRTIO_SCSI_T_AN_SUBSCRIBE => {
+            // No asynchronous notification is supported.
+            writer.write_obj(Le32::from(0)).map_err(Error::WriteQueue)?;
+            writer
+                .write_obj(VIRTIO_SCSI_S_OK)
+                .map_err(Error::WriteQueue)?;
+        }
+        t => {
+            error!("unknown virtio-scsi control request type: {}", t);
+            writer
+                .write_obj(VIRTIO_SCSI_S_FUNCTION_REJECTED)
+                .map_err(Error::WriteQueue)?;
+        }
+    }
+    Ok(writer.bytes_written())
+}
+
+async fn handle_request_queue(
+    mem: &GuestMemory,
+    mut queue: Queue,
+    mut queue_event: EventAsync,
+    interrupt: Interrupt,
+    luns: &mut [LogicalUnit],
+) {
+    loop {
+        let avail_desc = match queue.next_async(mem, &mut queue_event).await {
+            Err(e) => {
+                error!("Failed to read descriptor {}", e);
+                return;
+            }
+            Ok(d) => d,
+        };
+        let index = avail_desc.index;
+        let written = match process_request(mem, avail_desc, luns) {
+            Ok(n) => n,
+            Err(e) => {
+                error!("virtio-scsi: failed to handle request: {}", e);
+                0
+            }
+        };
+        queue.add_used(mem, index, written as u32);
+        queue.trigger_interrupt(mem, &interrupt);
+    }
+}
+
+async fn handle_control_queue(
+    mem: &GuestMemory,
+    mut queue: Queue,
+    mut queue_event: EventAsync,
+    interrupt: Interrupt,
+) {
+    loop {
+        let avail_desc = match queue.next_async(mem, &mut queue_event).await {
+            Err(e) => {
+                error!("Failed to read descriptor {}", e);
+                return;
+            }
+            Ok(d) => d,
+        };
+        let index = avail_desc.index;
+        let written = match process_control_request(mem, avail_desc) {
+            Ok(n) => n,
+            Err(e) => {
+                error!("virtio-scsi: failed to handle control request: {}", e);
+                0
+            }
+        };
+        queue.add_used(mem, index, written as u32);
+        queue.trigger_interrupt(mem, &interrupt);
+    }
+}
+
+fn run_worker(
+    mut queues: Vec<Queue>,
+    mut queue_evts: Vec<Event>,
+    interrupt: Interrupt,
+    kill_evt: Event,
+    mem: GuestMemory,
+    mut luns: Vec<LogicalUnit>,
+) -> Vec<LogicalUnit> {
+    let ex = Executor::new().unwrap();
+
+    // The event queue is left alone: no event is ever reported.
+    let request_queue = queues.remove(2);
+    let request_evt = EventAsync::new(queue_evts.remove(2), &ex)
+        .expect("failed to set up the request queue event");
+    let control_queue = queues.remove(0);
+    let control_evt = EventAsync::new(queue_evts.remove(0), &ex)
+        .expect("failed to set up the control queue event");
+
+    {
+        let request = handle_request_queue(
+            &mem,
+            request_queue,
+            request_evt,
+            interrupt.clone(),
+            &mut luns,
+        );
+        pin_mut!(request);
+
+        let control = handle_control_queue(&mem, control_queue, control_evt, interrupt.clone());
+        pin_mut!(control);
+
+        // Process any requests to resample the irq value.
+        let resample = async_utils::handle_irq_resample(&ex, interrupt);
+        pin_mut!(resample);
+
+        // Exit if the kill event is triggered.
+        let kill = async_utils::await_and_exit(&ex, kill_evt);
+        pin_mut!(kill);
+
+        if let Err(e) = ex.run_until(select4(request, control, resample, kill)) {
+            error!("error happened in executor: {}", e);
+        }
+    }
+
+    luns
+}
+
+/// Virtio SCSI controller exposing disk images as the LUNs of a single target.
+pub struct Scsi {
+    kill_evt: Option<Event>,
+    worker_thread: Option<thread::JoinHandle<Vec<LogicalUnit>>>,
+    base_features: u64,
+    num_luns: usize,
+    luns: Option<Vec<LogicalUnit>>,
+}
+
+impl Scsi {
+    /// Create a new virtio-scsi controller with `luns` as LUNs 0, 1, ...
+    pub fn new(base_features: u64, luns: Vec<LogicalUnit>) -> Scsi {
+        Scsi {
+            kill_evt: None,
+            worker_thread: None,
+            base_features,
+            num_luns: luns.len(),
+            luns: Some(luns),
+        }
+    }
+}
+
+impl Drop for Scsi {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.signal();
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let _ = worker_thread.join();
+        }
+    }
+}
+
+impl VirtioDevice for Scsi {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = Vec::new();
+        if let Some(luns) = &self.luns {
+            for lun in luns {
+                keep_rds.extend(lun.disk().as_raw_descriptors());
+            }
+        }
+        keep_rds
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Scsi
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.base_features
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = virtio_scsi_config {
+            num_queues: 1.into(),
+            // The request header and response each consume a descriptor.
+            seg_max: (u32::from(QUEUE_SIZE) - 2).into(),
+            max_sectors: 0xffff.into(),
+            cmd_per_lun: u32::from(QUEUE_SIZE).into(),
+            event_info_size: 0.into(),
+            sense_size: (SENSE_SIZE as u32).into(),
+            cdb_size: (CDB_SIZE as u32).into(),
+            max_channel: 0.into(),
+            max_target: 0.into(),
+            max_lun: (self.num_luns.saturating_sub(1) as u32).into(),
+        };
+        copy_config(data, 0, config.as_slice(), offset);
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        queues: Vec<Queue>,
+        queue_evts: Vec<Event>,
+    ) {
+        if queues.len() != QUEUE_SIZES.len() || queue_evts.len() != QUEUE_SIZES.len() {
+            return;
+        }
+
+        let luns = match self.luns.take() {
+            Some(luns) => luns,
+            None => {
+                error!("virtio-scsi LUNs are missing");
+                return;
+            }
+        };
+
+        let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed creating kill Event pair: {}", e);
+                return;
+            }
+        };
+        self.kill_evt = Some(self_kill_evt);
+
+        let worker_result = thread::Builder::new()
+            .name("v_scsi".to_string())
+            .spawn(move || run_worker(queues, queue_evts, interrupt, kill_evt, mem, luns));
+
+        match worker_result {
+            Err(e) => {
+                error!("failed to spawn virtio_scsi worker: {}", e);
+            }
+            Ok(join_handle) => {
+                self.worker_thread = Some(join_handle);
+            }
+        }
+    }
+
+    fn reset(&mut self) -> bool {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            if kill_evt.signal().is_err() {
+                error!("{}: failed to notify the kill event", self.debug_label());
+                return false;
+            }
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            match worker_thread.join() {
+                Err(_) => {
+                    error!("{}: failed to get back resources", self.debug_label());
+                    return false;
+                }
+                Ok(luns) => {
+                    self.luns = Some(luns);
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+impl Suspendable for Scsi {}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use tempfile::tempfile;
+    use vm_memory::GuestAddress;
+
+    use super::*;
+    use crate::virtio::descriptor_utils::create_descriptor_chain;
+    use crate::virtio::descriptor_utils::DescriptorType;
+
+    const REQ_ADDR: u64 = 0x1000;
+    const RESP_ADDR: u64 = REQ_ADDR + size_of::<virtio_scsi_cmd_req>() as u64;
+
+    fn test_lun(read_only: bool) -> LogicalUnit {
+        let f: File = tempfile().unwrap();
+        f.set_len(0x10000).unwrap();
+        LogicalUnit::new(Box::new(f), read_only, true, 512).unwrap()
+    }
+
+    fn cmd_req(lun: u8, cdb: &[u8]) -> virtio_scsi_cmd_req {
+        let mut req = virtio_scsi_cmd_req {
+            lun: [1, 0, 0x40, lun, 0, 0, 0, 0],
+            ..Default::default()
+        };
+        req.cdb[..cdb.len()].copy_from_slice(cdb);
+        req
+    }
+
+    // Sends `req` with `data_out` and room for `data_in_len` bytes of data, and returns the
+    // response and the data returned by the command.
+    fn run_request(
+        luns: &mut [LogicalUnit],
+        req: virtio_scsi_cmd_req,
+        data_out: &[u8],
+        data_in_len: u32,
+    ) -> (virtio_scsi_cmd_resp, Vec<u8>) {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x20000)]).unwrap();
+        mem.write_obj_at_addr(req, GuestAddress(REQ_ADDR)).unwrap();
+
+        let mut descriptors = vec![(
+            DescriptorType::Readable,
+            size_of::<virtio_scsi_cmd_req>() as u32,
+        )];
+        let resp_addr = if data_out.is_empty() {
+            RESP_ADDR
+        } else {
+            mem.write_all_at_addr(data_out, GuestAddress(RESP_ADDR))
+                .unwrap();
+            descriptors.push((DescriptorType::Readable, data_out.len() as u32));
+            RESP_ADDR + data_out.len() as u64
+        };
+        descriptors.push((
+            DescriptorType::Writable,
+            size_of::<virtio_scsi_cmd_resp>() as u32,
+        ));
+        if data_in_len > 0 {
+            descriptors.push((DescriptorType::Writable, data_in_len));
+        }
+        let avail_desc = create_descriptor_chain(
+            &mem,
+            GuestAddress(0x100),
+            GuestAddress(REQ_ADDR),
+            descriptors,
+            0,
+        )
+        .unwrap();
+
+        process_request(&mem, avail_desc, luns).unwrap();
+
+        let resp: virtio_scsi_cmd_resp = mem.read_obj_from_addr(GuestAddress(resp_addr)).unwrap();
+        let mut data = vec![0u8; data_in_len as usize];
+        let data_addr = resp_addr + size_of::<virtio_scsi_cmd_resp>() as u64;
+        mem.read_exact_at_addr(&mut data, GuestAddress(data_addr))
+            .unwrap();
+        (resp, data)
+    }
+
+    #[test]
+    fn inquiry_and_capacity() {
+        let mut luns = vec![test_lun(false)];
+
+        let (resp, data) = run_request(&mut luns, cmd_req(0, &[0x12, 0, 0, 0, 36, 0]), &[], 36);
+        assert_eq!(resp.response, VIRTIO_SCSI_S_OK);
+        assert_eq!(resp.status, commands::GOOD);
+        assert_eq!(data[0], 0x00);
+        assert_eq!(&data[16..32], b"CROSVM HARDDISK ");
+
+        let (resp, data) = run_request(&mut luns, cmd_req(0, &[0x25]), &[], 8);
+        assert_eq!(resp.status, commands::GOOD);
+        assert_eq!(data, [0, 0, 0, 0x7f, 0, 0, 2, 0]);
+
+        // A LUN that does not exist on the target.
+        let (resp, data) = run_request(&mut luns, cmd_req(1, &[0x12, 0, 0, 0, 36, 0]), &[], 36);
+        assert_eq!(resp.status, commands::GOOD);
+        assert_eq!(data[0], 0x7f);
+
+        let mut req = cmd_req(0, &[0x00]);
+        req.lun[1] = 1;
+        let (resp, _) = run_request(&mut luns, req, &[], 0);
+        assert_eq!(resp.response, VIRTIO_SCSI_S_BAD_TARGET);
+    }
+
+    #[test]
+    fn write_and_read() {
+        let mut luns = vec![test_lun(false), test_lun(true)];
+
+        // WRITE(10) of one block at LBA 2.
+        let data_out = [0xa5u8; 512];
+        let write = [0x2a, 0, 0, 0, 0, 2, 0, 0, 1, 0];
+        let (resp, _) = run_request(&mut luns, cmd_req(0, &write), &data_out, 0);
+        assert_eq!(resp.status, commands::GOOD);
+
+        // READ(16) of the same block.
+        let read = [0x88, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0];
+        let (resp, data) = run_request(&mut luns, cmd_req(0, &read), &[], 512);
+        assert_eq!(resp.status, commands::GOOD);
+        assert_eq!(resp.resid.to_native(), 0);
+        assert_eq!(data, data_out);
+
+        // Past the end of the disk.
+        let read = [0x28, 0, 0, 0, 0, 0x7f, 0, 0, 2, 0];
+        let (resp, _) = run_request(&mut luns, cmd_req(0, &read), &[], 1024);
+        assert_eq!(resp.status, commands::CHECK_CONDITION);
+        assert_eq!(resp.sense[12], 0x21);
+
+        // The second LUN is read-only.
+        let (resp, _) = run_request(&mut luns, cmd_req(1, &write), &data_out, 0);
+        assert_eq!(resp.status, commands::CHECK_CONDITION);
+        assert_eq!(resp.sense[2], 0x07);
+    }
+
+    #[test]
+    fn report_luns() {
+        let mut luns = vec![test_lun(false), test_lun(false)];
+
+        let report_luns = [0xa0, 0, 0, 0, 0, 0, 0, 0, 0, 64, 0, 0];
+        let (resp, data) = run_request(&mut luns, cmd_req(0, &report_luns), &[], 64);
+        assert_eq!(resp.status, commands::GOOD);
+        assert_eq!(&data[0..4], &[0, 0, 0, 16]);
+        assert_eq!(&data[8..10], &[0, 0]);
+        assert_eq!(&data[16..18], &[0, 1]);
+        assert_eq!(resp.resid.to_native(), 64 - 24);
+    }
+}
@@ -0,0 +1,17 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Implements a virtio-scsi controller whose LUNs are backed by disk images.
+
+mod commands;
+mod device;
+
+pub use commands::BufferReader;
+pub use commands::BufferWriter;
+pub use commands::DataReader;
+pub use commands::DataWriter;
+pub use commands::LogicalUnit;
+pub use commands::Sense;
+pub use device::Scsi;
+pub use device::MAX_LUNS;
@@ -3,6 +3,8 @@
 // found in the LICENSE file.
 
 use std::num::ParseIntError;
+#[cfg(unix)]
+use std::path::PathBuf;
 use std::str::ParseBoolError;
 
 #[cfg(all(unix, feature = "audio_cras"))]
@@ -67,6 +69,10 @@ pub struct Parameters {
     pub client_type: CrasClientType,
     #[cfg(all(unix, feature = "audio_cras"))]
     pub socket_type: CrasSocketType,
+    #[cfg(all(unix, feature = "audio_alsa"))]
+    pub alsa_device: String,
+    #[cfg(unix)]
+    pub wav_dir: Option<PathBuf>,
 }
 
 impl Default for Parameters {
@@ -82,6 +88,10 @@ impl Default for Parameters {
             client_type: CrasClientType::CRAS_CLIENT_TYPE_CROSVM,
             #[cfg(all(unix, feature = "audio_cras"))]
             socket_type: CrasSocketType::Unified,
+            #[cfg(all(unix, feature = "audio_alsa"))]
+            alsa_device: "default".to_string(),
+            #[cfg(unix)]
+            wav_dir: None,
         }
     }
 }
@@ -227,4 +237,35 @@ mod tests {
             CrasSocketType::Unified,
         );
     }
+
+    #[test]
+    #[cfg(unix)]
+    fn file_parameters_fromstr() {
+        let params: Parameters =
+            serde_keyvalue::from_key_values("backend=file,capture=true,wav_dir=/tmp/snd")
+                .expect("parse should have succeded");
+        assert_eq!(
+            params.backend,
+            StreamSourceBackend::Sys(SysStreamSourceBackend::FILE)
+        );
+        assert!(params.capture);
+        assert_eq!(params.wav_dir, Some(PathBuf::from("/tmp/snd")));
+    }
+
+    #[test]
+    #[cfg(all(unix, feature = "audio_alsa"))]
+    fn alsa_parameters_fromstr() {
+        let params: Parameters =
+            serde_keyvalue::from_key_values("backend=alsa").expect("parse should have succeded");
+        assert_eq!(
+            params.backend,
+            StreamSourceBackend::Sys(SysStreamSourceBackend::ALSA)
+        );
+        assert_eq!(params.alsa_device, "default");
+
+        let params: Parameters =
+            serde_keyvalue::from_key_values("backend=alsa,alsa_device=\"hw:0,0\"")
+                .expect("parse should have succeded");
+        assert_eq!(params.alsa_device, "hw:0,0");
+    }
 }
@@ -2,6 +2,10 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+#[cfg(feature = "audio_alsa")]
+mod alsa_backend;
+mod file_backend;
+
 use audio_streams::StreamSourceGenerator;
 use base::set_rt_prio_limit;
 use base::set_rt_round_robin;
@@ -9,6 +13,9 @@ use base::warn;
 #[cfg(feature = "audio_cras")]
 use libcras::CrasStreamSourceGenerator;
 
+#[cfg(feature = "audio_alsa")]
+use self::alsa_backend::AlsaStreamSourceGenerator;
+use self::file_backend::FileStreamSourceGenerator;
 use crate::virtio::snd::common_backend::SndData;
 use crate::virtio::snd::parameters::Error;
 use crate::virtio::snd::parameters::Parameters;
@@ -17,8 +24,11 @@ const AUDIO_THREAD_RTPRIO: u16 = 10; // Matches other cros audio clients.
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StreamSourceBackend {
+    #[cfg(feature = "audio_alsa")]
+    ALSA,
     #[cfg(feature = "audio_cras")]
     CRAS,
+    FILE,
 }
 
 impl TryFrom<&str> for StreamSourceBackend {
@@ -26,13 +36,31 @@ impl TryFrom<&str> for StreamSourceBackend {
 
     fn try_from(s: &str) -> Result<Self, Self::Error> {
         match s {
+            #[cfg(feature = "audio_alsa")]
+            "alsa" => Ok(StreamSourceBackend::ALSA),
             #[cfg(feature = "audio_cras")]
             "cras" => Ok(StreamSourceBackend::CRAS),
+            "file" => Ok(StreamSourceBackend::FILE),
             _ => Err(Error::InvalidBackend),
         }
     }
 }
 
+#[cfg(feature = "audio_alsa")]
+pub(crate) fn create_alsa_stream_source_generators(
+    params: &Parameters,
+    snd_data: &SndData,
+) -> Vec<Box<dyn StreamSourceGenerator>> {
+    let mut generators: Vec<Box<dyn StreamSourceGenerator>> = Vec::new();
+    generators.resize_with(snd_data.pcm_info_len(), || {
+        Box::new(AlsaStreamSourceGenerator::new(
+            params.alsa_device.clone(),
+            params.capture,
+        ))
+    });
+    generators
+}
+
 #[cfg(feature = "audio_cras")]
 pub(crate) fn create_cras_stream_source_generators(
     params: &Parameters,
@@ -49,15 +77,34 @@ pub(crate) fn create_cras_stream_source_generators(
     generators
 }
 
-#[allow(unused_variables)]
+/// Creates a generator for each PCM stream, which uses the file `streamN.wav` in `wav_dir`, `N`
+/// being the ID of the stream.
+pub(crate) fn create_file_stream_source_generators(
+    params: &Parameters,
+    snd_data: &SndData,
+) -> Vec<Box<dyn StreamSourceGenerator>> {
+    let wav_dir = params.wav_dir.clone().unwrap_or_default();
+    (0..snd_data.pcm_info_len())
+        .map(|stream_id| {
+            Box::new(FileStreamSourceGenerator::new(
+                wav_dir.join(format!("stream{}.wav", stream_id)),
+                params.capture,
+            )) as Box<dyn StreamSourceGenerator>
+        })
+        .collect()
+}
+
 pub(crate) fn create_stream_source_generators(
     backend: StreamSourceBackend,
     params: &Parameters,
     snd_data: &SndData,
 ) -> Vec<Box<dyn StreamSourceGenerator>> {
     match backend {
+        #[cfg(feature = "audio_alsa")]
+        StreamSourceBackend::ALSA => create_alsa_stream_source_generators(params, snd_data),
         #[cfg(feature = "audio_cras")]
         StreamSourceBackend::CRAS => create_cras_stream_source_generators(params, snd_data),
+        StreamSourceBackend::FILE => create_file_stream_source_generators(params, snd_data),
     }
 }
 
@@ -0,0 +1,473 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Backend of virtio-snd that plays and captures the guest audio through an ALSA PCM device of the
+//! host.
+
+use std::time::Duration;
+
+use alsa::pcm::Access;
+use alsa::pcm::Format;
+use alsa::pcm::Frames;
+use alsa::pcm::HwParams;
+use alsa::pcm::State;
+use alsa::pcm::PCM;
+use alsa::Direction;
+use alsa::ValueOr;
+use async_trait::async_trait;
+use audio_streams::capture::AsyncCaptureBuffer;
+use audio_streams::capture::AsyncCaptureBufferStream;
+use audio_streams::capture::CaptureBuffer;
+use audio_streams::capture::CaptureBufferStream;
+use audio_streams::capture::NoopCaptureStream;
+use audio_streams::AsyncBufferCommit;
+use audio_streams::AsyncPlaybackBuffer;
+use audio_streams::AsyncPlaybackBufferStream;
+use audio_streams::AudioStreamsExecutor;
+use audio_streams::BoxError;
+use audio_streams::BufferCommit;
+use audio_streams::NoopStreamControl;
+use audio_streams::PlaybackBuffer;
+use audio_streams::PlaybackBufferStream;
+use audio_streams::SampleFormat;
+use audio_streams::StreamControl;
+use audio_streams::StreamEffect;
+use audio_streams::StreamSource;
+use audio_streams::StreamSourceGenerator;
+use base::error;
+
+// Number of buffers of the stream that the ALSA ring buffer holds.
+const BUFFERS_PER_RING: Frames = 4;
+
+fn alsa_format(format: SampleFormat) -> Format {
+    match format {
+        SampleFormat::U8 => Format::U8,
+        SampleFormat::S16LE => Format::S16LE,
+        SampleFormat::S24LE => Format::S24LE,
+        SampleFormat::S32LE => Format::S32LE,
+    }
+}
+
+/// Opens the PCM `device` for a stream exchanging buffers of `buffer_size` frames.
+fn open_pcm(
+    device: &str,
+    direction: Direction,
+    num_channels: usize,
+    format: SampleFormat,
+    frame_rate: u32,
+    buffer_size: usize,
+) -> alsa::Result<PCM> {
+    let pcm = PCM::new(device, direction, false)?;
+    {
+        let hwp = HwParams::any(&pcm)?;
+        hwp.set_access(Access::RWInterleaved)?;
+        hwp.set_format(alsa_format(format))?;
+        hwp.set_channels(num_channels as u32)?;
+        hwp.set_rate(frame_rate, ValueOr::Nearest)?;
+        let period_size = hwp.set_period_size_near(buffer_size as Frames, ValueOr::Nearest)?;
+        hwp.set_buffer_size_near(period_size * BUFFERS_PER_RING)?;
+        pcm.hw_params(&hwp)?;
+    }
+    pcm.prepare()?;
+    Ok(pcm)
+}
+
+/// Waits until `frames` frames can be written to or read from `pcm` without blocking.
+async fn wait_for_frames(
+    pcm: &PCM,
+    direction: Direction,
+    frames: usize,
+    frame_rate: u32,
+    ex: &dyn AudioStreamsExecutor,
+) -> Result<(), BoxError> {
+    loop {
+        // A capture stream only fills its ring buffer once started, which is also needed after
+        // recovering from an overrun. Playback streams start on the first write.
+        if direction == Direction::Capture && pcm.state() == State::Prepared {
+            pcm.start()?;
+        }
+        let avail = match pcm.avail_update() {
+            Ok(avail) => avail as usize,
+            Err(e) => {
+                pcm.try_recover(e, true)?;
+                continue;
+            }
+        };
+        if avail >= frames {
+            return Ok(());
+        }
+        let missing = (frames - avail) as u64;
+        ex.delay(Duration::from_nanos(
+            missing * 1_000_000_000 / u64::from(frame_rate),
+        ))
+        .await?;
+    }
+}
+
+/// Records the number of frames of the last buffer of a stream, which are only written to the
+/// device when the next buffer is requested, since the commit can't access the samples.
+#[derive(Default)]
+struct AlsaBufferCommit {
+    frames: usize,
+}
+
+impl BufferCommit for AlsaBufferCommit {
+    fn commit(&mut self, nframes: usize) {
+        self.frames = nframes;
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncBufferCommit for AlsaBufferCommit {
+    async fn commit(&mut self, nframes: usize) {
+        self.frames = nframes;
+    }
+}
+
+struct AlsaPlaybackStream {
+    pcm: PCM,
+    frame_rate: u32,
+    frame_size: usize,
+    buffer: Vec<u8>,
+    commit: AlsaBufferCommit,
+}
+
+impl AlsaPlaybackStream {
+    fn new(
+        device: &str,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+    ) -> alsa::Result<Self> {
+        let pcm = open_pcm(
+            device,
+            Direction::Playback,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+        )?;
+        let frame_size = num_channels * format.sample_bytes();
+        Ok(AlsaPlaybackStream {
+            pcm,
+            frame_rate,
+            frame_size,
+            buffer: vec![0; buffer_size * frame_size],
+            commit: AlsaBufferCommit::default(),
+        })
+    }
+
+    /// Writes the samples of the last committed buffer to the device, recovering from underruns.
+    fn write_committed(&mut self) -> alsa::Result<()> {
+        let frames = std::mem::take(&mut self.commit.frames);
+        let mut samples = &self.buffer[..frames * self.frame_size];
+        while !samples.is_empty() {
+            match self.pcm.io_bytes().writei(samples) {
+                Ok(written) => samples = &samples[written * self.frame_size..],
+                Err(e) => self.pcm.try_recover(e, true)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AlsaPlaybackStream {
+    fn drop(&mut self) {
+        if let Err(e) = self.write_committed() {
+            error!("failed to write the last playback buffer: {}", e);
+        }
+        if self.pcm.state() == State::Running {
+            if let Err(e) = self.pcm.drain() {
+                error!("failed to drain the playback stream: {}", e);
+            }
+        }
+    }
+}
+
+impl PlaybackBufferStream for AlsaPlaybackStream {
+    fn next_playback_buffer<'b, 's: 'b>(&'s mut self) -> Result<PlaybackBuffer<'b>, BoxError> {
+        // Writes block while the ring buffer is full, which paces the stream.
+        self.write_committed()?;
+        Ok(PlaybackBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.commit,
+        )?)
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncPlaybackBufferStream for AlsaPlaybackStream {
+    async fn next_playback_buffer<'a>(
+        &'a mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<AsyncPlaybackBuffer<'a>, BoxError> {
+        self.write_committed()?;
+        wait_for_frames(
+            &self.pcm,
+            Direction::Playback,
+            self.buffer.len() / self.frame_size,
+            self.frame_rate,
+            ex,
+        )
+        .await?;
+        Ok(AsyncPlaybackBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.commit,
+        )?)
+    }
+}
+
+struct AlsaCaptureStream {
+    pcm: PCM,
+    frame_rate: u32,
+    frame_size: usize,
+    buffer: Vec<u8>,
+    commit: AlsaBufferCommit,
+}
+
+impl AlsaCaptureStream {
+    fn new(
+        device: &str,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+    ) -> alsa::Result<Self> {
+        let pcm = open_pcm(
+            device,
+            Direction::Capture,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+        )?;
+        let frame_size = num_channels * format.sample_bytes();
+        Ok(AlsaCaptureStream {
+            pcm,
+            frame_rate,
+            frame_size,
+            buffer: vec![0; buffer_size * frame_size],
+            commit: AlsaBufferCommit::default(),
+        })
+    }
+
+    /// Fills the buffer with samples of the device, recovering from overruns.
+    fn fill_buffer(&mut self) -> alsa::Result<()> {
+        let mut offset = 0;
+        while offset < self.buffer.len() {
+            match self.pcm.io_bytes().readi(&mut self.buffer[offset..]) {
+                Ok(read) => offset += read * self.frame_size,
+                Err(e) => self.pcm.try_recover(e, true)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CaptureBufferStream for AlsaCaptureStream {
+    fn next_capture_buffer<'b, 's: 'b>(&'s mut self) -> Result<CaptureBuffer<'b>, BoxError> {
+        // Reads block until the device has captured enough frames, which paces the stream.
+        self.fill_buffer()?;
+        Ok(CaptureBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.commit,
+        )?)
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncCaptureBufferStream for AlsaCaptureStream {
+    async fn next_capture_buffer<'a>(
+        &'a mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<AsyncCaptureBuffer<'a>, BoxError> {
+        wait_for_frames(
+            &self.pcm,
+            Direction::Capture,
+            self.buffer.len() / self.frame_size,
+            self.frame_rate,
+            ex,
+        )
+        .await?;
+        self.fill_buffer()?;
+        Ok(AsyncCaptureBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.commit,
+        )?)
+    }
+}
+
+/// Source of streams on an ALSA PCM device.
+pub struct AlsaStreamSource {
+    device: String,
+    capture: bool,
+}
+
+impl StreamSource for AlsaStreamSource {
+    #[allow(clippy::type_complexity)]
+    fn new_playback_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn PlaybackBufferStream>), BoxError> {
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(AlsaPlaybackStream::new(
+                &self.device,
+                num_channels,
+                format,
+                frame_rate,
+                buffer_size,
+            )?),
+        ))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_playback_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn AsyncPlaybackBufferStream>), BoxError> {
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(AlsaPlaybackStream::new(
+                &self.device,
+                num_channels,
+                format,
+                frame_rate,
+                buffer_size,
+            )?),
+        ))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_capture_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn CaptureBufferStream>), BoxError> {
+        let stream: Box<dyn CaptureBufferStream> = if self.capture {
+            Box::new(AlsaCaptureStream::new(
+                &self.device,
+                num_channels,
+                format,
+                frame_rate,
+                buffer_size,
+            )?)
+        } else {
+            Box::new(NoopCaptureStream::new(
+                num_channels,
+                format,
+                frame_rate,
+                buffer_size,
+            ))
+        };
+        Ok((Box::new(NoopStreamControl::new()), stream))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_capture_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn AsyncCaptureBufferStream>), BoxError> {
+        let stream: Box<dyn AsyncCaptureBufferStream> = if self.capture {
+            Box::new(AlsaCaptureStream::new(
+                &self.device,
+                num_channels,
+                format,
+                frame_rate,
+                buffer_size,
+            )?)
+        } else {
+            Box::new(NoopCaptureStream::new(
+                num_channels,
+                format,
+                frame_rate,
+                buffer_size,
+            ))
+        };
+        Ok((Box::new(NoopStreamControl::new()), stream))
+    }
+}
+
+/// `AlsaStreamSourceGenerator` is a struct that implements [`StreamSourceGenerator`]
+/// to generate [`AlsaStreamSource`].
+pub struct AlsaStreamSourceGenerator {
+    device: String,
+    capture: bool,
+}
+
+impl AlsaStreamSourceGenerator {
+    /// Creates a generator for streams on the PCM `device`, such as `default` or `hw:0,0`. Capture
+    /// streams are silent unless `capture` is true.
+    pub fn new(device: String, capture: bool) -> Self {
+        AlsaStreamSourceGenerator { device, capture }
+    }
+}
+
+impl StreamSourceGenerator for AlsaStreamSourceGenerator {
+    fn generate(&self) -> Result<Box<dyn StreamSource>, BoxError> {
+        Ok(Box::new(AlsaStreamSource {
+            device: self.device.clone(),
+            capture: self.capture,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The `null` PCM of alsa-lib accepts any hardware parameters and discards the samples.
+    const NULL_DEVICE: &str = "null";
+
+    #[test]
+    fn open_pcm_sets_hw_params() {
+        for (direction, num_channels, format, frame_rate, buffer_size) in [
+            (Direction::Playback, 2, SampleFormat::S16LE, 48000, 480),
+            (Direction::Playback, 1, SampleFormat::U8, 8000, 256),
+            (Direction::Capture, 4, SampleFormat::S32LE, 44100, 1024),
+            (Direction::Capture, 2, SampleFormat::S24LE, 96000, 960),
+        ] {
+            let pcm = open_pcm(
+                NULL_DEVICE,
+                direction,
+                num_channels,
+                format,
+                frame_rate,
+                buffer_size,
+            )
+            .expect("failed to open pcm");
+            let hwp = pcm.hw_params_current().unwrap();
+            assert_eq!(hwp.get_access().unwrap(), Access::RWInterleaved);
+            assert_eq!(hwp.get_format().unwrap(), alsa_format(format));
+            assert_eq!(hwp.get_channels().unwrap(), num_channels as u32);
+            assert_eq!(hwp.get_rate().unwrap(), frame_rate);
+            assert_eq!(hwp.get_period_size().unwrap(), buffer_size as Frames);
+            assert_eq!(
+                hwp.get_buffer_size().unwrap(),
+                buffer_size as Frames * BUFFERS_PER_RING
+            );
+            assert_eq!(pcm.state(), State::Prepared);
+        }
+    }
+}
@@ -0,0 +1,711 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Backend of virtio-snd that writes playback streams to WAV files and reads capture streams from
+//! WAV files, so that the guest audio is deterministic.
+//!
+//! PCM stream `N` uses the file `streamN.wav` in the directory given to the backend. The file of a
+//! playback stream is overwritten by the first stream of the VM, and later streams with the same
+//! format are appended to it. Capture streams read their file from the start, and get silence once
+//! the samples of the file are exhausted. Buffers are paced in real time, like the null backend.
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use audio_streams::capture::AsyncCaptureBuffer;
+use audio_streams::capture::AsyncCaptureBufferStream;
+use audio_streams::capture::CaptureBuffer;
+use audio_streams::capture::CaptureBufferStream;
+use audio_streams::capture::NoopCaptureStream;
+use audio_streams::AsyncBufferCommit;
+use audio_streams::AsyncPlaybackBuffer;
+use audio_streams::AsyncPlaybackBufferStream;
+use audio_streams::AudioStreamsExecutor;
+use audio_streams::BoxError;
+use audio_streams::BufferCommit;
+use audio_streams::NoopStreamControl;
+use audio_streams::PlaybackBuffer;
+use audio_streams::PlaybackBufferStream;
+use audio_streams::SampleFormat;
+use audio_streams::StreamControl;
+use audio_streams::StreamEffect;
+use audio_streams::StreamSource;
+use audio_streams::StreamSourceGenerator;
+use base::error;
+use remain::sorted;
+use thiserror::Error as ThisError;
+
+// Size of the header written to the WAV files of playback streams.
+const WAV_HEADER_SIZE: u64 = 44;
+const WAVE_FORMAT_PCM: u16 = 1;
+
+#[sorted]
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("{0} has the format {1:?} but the stream needs {2:?}")]
+    FormatMismatch(PathBuf, WavFormat, WavFormat),
+    #[error("failed to open {0}: {1}")]
+    Open(PathBuf, io::Error),
+    #[error("failed to read {0}: {1}")]
+    Read(PathBuf, io::Error),
+    #[error("invalid WAV file {0}: {1}")]
+    ReadHeader(PathBuf, io::Error),
+    #[error("failed to write {0}: {1}")]
+    Write(PathBuf, io::Error),
+}
+
+/// Format of the samples of a WAV file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WavFormat {
+    num_channels: u16,
+    frame_rate: u32,
+    bits_per_sample: u16,
+}
+
+impl WavFormat {
+    fn new(num_channels: usize, format: SampleFormat, frame_rate: u32) -> Self {
+        let bits_per_sample = match format {
+            SampleFormat::U8 => 8,
+            SampleFormat::S16LE => 16,
+            // Samples are packed in 3 bytes in WAV files, unlike in the audio streams.
+            SampleFormat::S24LE => 24,
+            SampleFormat::S32LE => 32,
+        };
+        WavFormat {
+            num_channels: num_channels as u16,
+            frame_rate,
+            bits_per_sample,
+        }
+    }
+
+    /// Returns the size in bytes of a frame in a WAV file.
+    fn block_align(&self) -> u16 {
+        self.num_channels * self.bits_per_sample / 8
+    }
+
+    fn header(&self, data_len: u32) -> [u8; WAV_HEADER_SIZE as usize] {
+        let mut header = [0u8; WAV_HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(b"RIFF");
+        header[4..8].copy_from_slice(&(data_len.saturating_add(36)).to_le_bytes());
+        header[8..12].copy_from_slice(b"WAVE");
+        header[12..16].copy_from_slice(b"fmt ");
+        header[16..20].copy_from_slice(&16u32.to_le_bytes());
+        header[20..22].copy_from_slice(&WAVE_FORMAT_PCM.to_le_bytes());
+        header[22..24].copy_from_slice(&self.num_channels.to_le_bytes());
+        header[24..28].copy_from_slice(&self.frame_rate.to_le_bytes());
+        let byte_rate = self.frame_rate * u32::from(self.block_align());
+        header[28..32].copy_from_slice(&byte_rate.to_le_bytes());
+        header[32..34].copy_from_slice(&self.block_align().to_le_bytes());
+        header[34..36].copy_from_slice(&self.bits_per_sample.to_le_bytes());
+        header[36..40].copy_from_slice(b"data");
+        header[40..44].copy_from_slice(&data_len.to_le_bytes());
+        header
+    }
+}
+
+fn invalid_wav(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads the header of the WAV file `file`, and returns its format along with the offset and the
+/// length of its samples.
+fn read_wav_header(file: &File) -> io::Result<(WavFormat, u64, u64)> {
+    let mut riff = [0u8; 12];
+    file.read_exact_at(&mut riff, 0)?;
+    if &riff[0..4] != b"RIFF" || &riff[8..12] != b"WAVE" {
+        return Err(invalid_wav("not a RIFF WAVE file"));
+    }
+
+    let mut format = None;
+    let mut offset = riff.len() as u64;
+    loop {
+        let mut chunk = [0u8; 8];
+        file.read_exact_at(&mut chunk, offset)?;
+        let len = u64::from(u32::from_le_bytes(chunk[4..8].try_into().unwrap()));
+        offset += chunk.len() as u64;
+        match &chunk[0..4] {
+            b"fmt " => {
+                if len < 16 {
+                    return Err(invalid_wav("fmt chunk too short"));
+                }
+                let mut fmt = [0u8; 16];
+                file.read_exact_at(&mut fmt, offset)?;
+                if u16::from_le_bytes([fmt[0], fmt[1]]) != WAVE_FORMAT_PCM {
+                    return Err(invalid_wav("samples are not PCM"));
+                }
+                format = Some(WavFormat {
+                    num_channels: u16::from_le_bytes([fmt[2], fmt[3]]),
+                    frame_rate: u32::from_le_bytes(fmt[4..8].try_into().unwrap()),
+                    bits_per_sample: u16::from_le_bytes([fmt[14], fmt[15]]),
+                });
+            }
+            b"data" => {
+                let format = format.ok_or_else(|| invalid_wav("data chunk before fmt chunk"))?;
+                // The length is not updated by some writers, so don't read past the file.
+                let len = len.min(file.metadata()?.len().saturating_sub(offset));
+                return Ok((format, offset, len));
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even length.
+        offset += len + (len & 1);
+    }
+}
+
+/// Returns the value of a silent sample in `format`.
+fn silence(format: SampleFormat) -> u8 {
+    match format {
+        SampleFormat::U8 => 0x80,
+        _ => 0,
+    }
+}
+
+/// Paces the buffers of a stream in real time, as an audio device would consume or produce them.
+struct Pacer {
+    interval: Duration,
+    next_frame: Duration,
+    start_time: Option<Instant>,
+}
+
+impl Pacer {
+    fn new(frame_rate: u32, buffer_size: usize) -> Self {
+        let interval =
+            Duration::from_nanos(buffer_size as u64 * 1_000_000_000 / u64::from(frame_rate));
+        Pacer {
+            interval,
+            next_frame: interval,
+            start_time: None,
+        }
+    }
+
+    /// Returns how long to wait before the next buffer is due.
+    fn next_delay(&mut self) -> Option<Duration> {
+        match self.start_time {
+            Some(start_time) => {
+                let elapsed = start_time.elapsed();
+                let delay = self.next_frame.checked_sub(elapsed);
+                self.next_frame += self.interval;
+                delay
+            }
+            None => {
+                self.start_time = Some(Instant::now());
+                self.next_frame = self.interval;
+                None
+            }
+        }
+    }
+}
+
+/// Records the number of frames of the last buffer of a stream, which are only processed when
+/// the next buffer is requested, since the commit can't access the samples.
+#[derive(Default)]
+struct FileBufferCommit {
+    frames: usize,
+}
+
+impl BufferCommit for FileBufferCommit {
+    fn commit(&mut self, nframes: usize) {
+        self.frames = nframes;
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncBufferCommit for FileBufferCommit {
+    async fn commit(&mut self, nframes: usize) {
+        self.frames = nframes;
+    }
+}
+
+/// Stream that appends the samples played by the guest to a WAV file.
+struct FilePlaybackStream {
+    path: PathBuf,
+    file: File,
+    format: SampleFormat,
+    frame_size: usize,
+    buffer: Vec<u8>,
+    // Samples converted to the format of the file.
+    file_buffer: Vec<u8>,
+    data_offset: u64,
+    data_len: u64,
+    pacer: Pacer,
+    commit: FileBufferCommit,
+}
+
+impl FilePlaybackStream {
+    fn new(
+        path: PathBuf,
+        append: bool,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+    ) -> Result<Self, Error> {
+        let wav_format = WavFormat::new(num_channels, format, frame_rate);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .map_err(|e| Error::Open(path.clone(), e))?;
+
+        let (data_offset, data_len) = match read_wav_header(&file) {
+            Ok((f, data_offset, data_len)) if append && f == wav_format => (data_offset, data_len),
+            _ => {
+                file.set_len(0)
+                    .and_then(|_| file.write_all_at(&wav_format.header(0), 0))
+                    .map_err(|e| Error::Write(path.clone(), e))?;
+                (WAV_HEADER_SIZE, 0)
+            }
+        };
+
+        let frame_size = num_channels * format.sample_bytes();
+        Ok(FilePlaybackStream {
+            path,
+            file,
+            format,
+            frame_size,
+            buffer: vec![0; buffer_size * frame_size],
+            file_buffer: Vec::new(),
+            data_offset,
+            data_len,
+            pacer: Pacer::new(frame_rate, buffer_size),
+            commit: FileBufferCommit::default(),
+        })
+    }
+
+    /// Writes the samples of the last committed buffer to the file, and updates the lengths in
+    /// the header so that the file is valid at any time.
+    fn write_committed(&mut self) -> Result<(), Error> {
+        let frames = std::mem::take(&mut self.commit.frames);
+        if frames == 0 {
+            return Ok(());
+        }
+        let samples = &self.buffer[..frames * self.frame_size];
+        let data = match self.format {
+            SampleFormat::S24LE => {
+                self.file_buffer.clear();
+                for sample in samples.chunks_exact(4) {
+                    self.file_buffer.extend_from_slice(&sample[..3]);
+                }
+                &self.file_buffer[..]
+            }
+            _ => samples,
+        };
+
+        let data_len = self.data_len + data.len() as u64;
+        let riff_len = u32::try_from(self.data_offset + data_len - 8).unwrap_or(u32::MAX);
+        let data_chunk_len = u32::try_from(data_len).unwrap_or(u32::MAX);
+        self.file
+            .write_all_at(data, self.data_offset + self.data_len)
+            .and_then(|_| self.file.write_all_at(&riff_len.to_le_bytes(), 4))
+            .and_then(|_| {
+                self.file
+                    .write_all_at(&data_chunk_len.to_le_bytes(), self.data_offset - 4)
+            })
+            .map_err(|e| Error::Write(self.path.clone(), e))?;
+        self.data_len = data_len;
+        Ok(())
+    }
+}
+
+impl Drop for FilePlaybackStream {
+    fn drop(&mut self) {
+        if let Err(e) = self.write_committed() {
+            error!("failed to write the last playback buffer: {}", e);
+        }
+    }
+}
+
+impl PlaybackBufferStream for FilePlaybackStream {
+    fn next_playback_buffer<'b, 's: 'b>(&'s mut self) -> Result<PlaybackBuffer<'b>, BoxError> {
+        self.write_committed()?;
+        if let Some(delay) = self.pacer.next_delay() {
+            thread::sleep(delay);
+        }
+        Ok(PlaybackBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.commit,
+        )?)
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncPlaybackBufferStream for FilePlaybackStream {
+    async fn next_playback_buffer<'a>(
+        &'a mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<AsyncPlaybackBuffer<'a>, BoxError> {
+        self.write_committed()?;
+        if let Some(delay) = self.pacer.next_delay() {
+            ex.delay(delay).await?;
+        }
+        Ok(AsyncPlaybackBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.commit,
+        )?)
+    }
+}
+
+/// Stream that gives the samples of a WAV file to the guest.
+struct FileCaptureStream {
+    path: PathBuf,
+    file: File,
+    format: SampleFormat,
+    frame_size: usize,
+    buffer: Vec<u8>,
+    // Samples read from the file, before conversion to the format of the stream.
+    file_buffer: Vec<u8>,
+    file_frame_size: usize,
+    data_offset: u64,
+    remaining: u64,
+    pacer: Pacer,
+    commit: FileBufferCommit,
+}
+
+impl FileCaptureStream {
+    fn new(
+        path: PathBuf,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+    ) -> Result<Self, Error> {
+        let wav_format = WavFormat::new(num_channels, format, frame_rate);
+        let file = File::open(&path).map_err(|e| Error::Open(path.clone(), e))?;
+        let (file_format, data_offset, data_len) =
+            read_wav_header(&file).map_err(|e| Error::ReadHeader(path.clone(), e))?;
+        if file_format != wav_format {
+            return Err(Error::FormatMismatch(path, file_format, wav_format));
+        }
+
+        let frame_size = num_channels * format.sample_bytes();
+        Ok(FileCaptureStream {
+            path,
+            file,
+            format,
+            frame_size,
+            buffer: vec![0; buffer_size * frame_size],
+            file_buffer: Vec::new(),
+            file_frame_size: usize::from(wav_format.block_align()),
+            data_offset,
+            remaining: data_len,
+            pacer: Pacer::new(frame_rate, buffer_size),
+            commit: FileBufferCommit::default(),
+        })
+    }
+
+    /// Fills the buffer with the next samples of the file, followed by silence at the end of the
+    /// file.
+    fn fill_buffer(&mut self) -> Result<(), Error> {
+        let frames = self.buffer.len() / self.frame_size;
+        let len = self.remaining.min((frames * self.file_frame_size) as u64) as usize;
+        self.file_buffer.resize(len, 0);
+        self.file
+            .read_exact_at(&mut self.file_buffer, self.data_offset)
+            .map_err(|e| Error::Read(self.path.clone(), e))?;
+        self.data_offset += len as u64;
+        self.remaining -= len as u64;
+
+        let filled = match self.format {
+            SampleFormat::S24LE => {
+                for (sample, packed) in self
+                    .buffer
+                    .chunks_exact_mut(4)
+                    .zip(self.file_buffer.chunks_exact(3))
+                {
+                    sample[..3].copy_from_slice(packed);
+                    // Sign-extend the 24-bit sample.
+                    sample[3] = if packed[2] & 0x80 != 0 { 0xff } else { 0 };
+                }
+                len / 3 * 4
+            }
+            _ => {
+                self.buffer[..len].copy_from_slice(&self.file_buffer);
+                len
+            }
+        };
+        self.buffer[filled..].fill(silence(self.format));
+        Ok(())
+    }
+}
+
+impl CaptureBufferStream for FileCaptureStream {
+    fn next_capture_buffer<'b, 's: 'b>(&'s mut self) -> Result<CaptureBuffer<'b>, BoxError> {
+        if let Some(delay) = self.pacer.next_delay() {
+            thread::sleep(delay);
+        }
+        self.fill_buffer()?;
+        Ok(CaptureBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.commit,
+        )?)
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncCaptureBufferStream for FileCaptureStream {
+    async fn next_capture_buffer<'a>(
+        &'a mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<AsyncCaptureBuffer<'a>, BoxError> {
+        if let Some(delay) = self.pacer.next_delay() {
+            ex.delay(delay).await?;
+        }
+        self.fill_buffer()?;
+        Ok(AsyncCaptureBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.commit,
+        )?)
+    }
+}
+
+/// Source of the streams of one PCM stream of the file backend.
+pub struct FileStreamSource {
+    path: PathBuf,
+    capture: bool,
+    written: Arc<AtomicBool>,
+}
+
+impl FileStreamSource {
+    fn new_playback(
+        &self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+    ) -> Result<FilePlaybackStream, Error> {
+        // The first playback stream of the VM replaces the file of a previous run.
+        let append = self.written.swap(true, Ordering::SeqCst);
+        FilePlaybackStream::new(
+            self.path.clone(),
+            append,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+        )
+    }
+}
+
+impl StreamSource for FileStreamSource {
+    #[allow(clippy::type_complexity)]
+    fn new_playback_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn PlaybackBufferStream>), BoxError> {
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(self.new_playback(num_channels, format, frame_rate, buffer_size)?),
+        ))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_playback_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn AsyncPlaybackBufferStream>), BoxError> {
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(self.new_playback(num_channels, format, frame_rate, buffer_size)?),
+        ))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_capture_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn CaptureBufferStream>), BoxError> {
+        let stream: Box<dyn CaptureBufferStream> = if self.capture {
+            Box::new(FileCaptureStream::new(
+                self.path.clone(),
+                num_channels,
+                format,
+                frame_rate,
+                buffer_size,
+            )?)
+        } else {
+            Box::new(NoopCaptureStream::new(
+                num_channels,
+                format,
+                frame_rate,
+                buffer_size,
+            ))
+        };
+        Ok((Box::new(NoopStreamControl::new()), stream))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_capture_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn AsyncCaptureBufferStream>), BoxError> {
+        let stream: Box<dyn AsyncCaptureBufferStream> = if self.capture {
+            Box::new(FileCaptureStream::new(
+                self.path.clone(),
+                num_channels,
+                format,
+                frame_rate,
+                buffer_size,
+            )?)
+        } else {
+            Box::new(NoopCaptureStream::new(
+                num_channels,
+                format,
+                frame_rate,
+                buffer_size,
+            ))
+        };
+        Ok((Box::new(NoopStreamControl::new()), stream))
+    }
+}
+
+/// `FileStreamSourceGenerator` generates the [`FileStreamSource`]s of a PCM stream, which all use
+/// the same file.
+pub struct FileStreamSourceGenerator {
+    path: PathBuf,
+    capture: bool,
+    // Whether a playback stream has written to the file since the VM started.
+    written: Arc<AtomicBool>,
+}
+
+impl FileStreamSourceGenerator {
+    /// Creates a generator for the streams of the file `path`. Capture streams are silent unless
+    /// `capture` is true.
+    pub fn new(path: PathBuf, capture: bool) -> Self {
+        FileStreamSourceGenerator {
+            path,
+            capture,
+            written: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl StreamSourceGenerator for FileStreamSourceGenerator {
+    fn generate(&self) -> Result<Box<dyn StreamSource>, BoxError> {
+        Ok(Box::new(FileStreamSource {
+            path: self.path.clone(),
+            capture: self.capture,
+            written: self.written.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::io::Write;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn play(generator: &FileStreamSourceGenerator, format: SampleFormat, samples: &[u8]) {
+        let (_, mut stream) = generator
+            .generate()
+            .unwrap()
+            .new_playback_stream(1, format, 48000, 4)
+            .expect("failed to create playback stream");
+        for chunk in samples.chunks(4 * format.sample_bytes()) {
+            let mut buffer = stream.next_playback_buffer().unwrap();
+            buffer.write_all(chunk).unwrap();
+            buffer.commit();
+        }
+    }
+
+    fn capture(generator: &FileStreamSourceGenerator, format: SampleFormat, len: usize) -> Vec<u8> {
+        let (_, mut stream) = generator
+            .generate()
+            .unwrap()
+            .new_capture_stream(1, format, 48000, 4, &[])
+            .expect("failed to create capture stream");
+        let mut samples = Vec::new();
+        while samples.len() < len {
+            let mut buffer = stream.next_capture_buffer().unwrap();
+            buffer.read_to_end(&mut samples).unwrap();
+            buffer.commit();
+        }
+        samples
+    }
+
+    #[test]
+    fn playback_appends_to_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("stream0.wav");
+        std::fs::write(&path, b"file of a previous run").unwrap();
+
+        let generator = FileStreamSourceGenerator::new(path.clone(), false);
+        let samples: Vec<u8> = (0..24).collect();
+        play(&generator, SampleFormat::S16LE, &samples[..16]);
+        play(&generator, SampleFormat::S16LE, &samples[16..]);
+
+        let file = std::fs::read(&path).unwrap();
+        assert_eq!(
+            file[..WAV_HEADER_SIZE as usize],
+            WavFormat::new(1, SampleFormat::S16LE, 48000).header(24)
+        );
+        assert_eq!(file[WAV_HEADER_SIZE as usize..], samples);
+
+        // A stream with another format restarts the file.
+        play(&generator, SampleFormat::U8, &samples[..4]);
+        let file = std::fs::read(&path).unwrap();
+        assert_eq!(file.len(), WAV_HEADER_SIZE as usize + 4);
+    }
+
+    #[test]
+    fn capture_reads_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("stream0.wav");
+        let generator = FileStreamSourceGenerator::new(path.clone(), true);
+
+        // 24-bit samples are packed in the file and sign-extended in the stream.
+        let samples = [0x01, 0x02, 0x03, 0x00, 0xfd, 0xfe, 0xff, 0xff];
+        play(&generator, SampleFormat::S24LE, &samples);
+        let file = std::fs::read(&path).unwrap();
+        assert_eq!(
+            file[WAV_HEADER_SIZE as usize..],
+            [0x01, 0x02, 0x03, 0xfd, 0xfe, 0xff]
+        );
+
+        let captured = capture(&generator, SampleFormat::S24LE, 32);
+        assert_eq!(captured[..8], samples);
+        // The end of the file is followed by silence.
+        assert!(captured[8..].iter().all(|&b| b == 0));
+
+        generator
+            .generate()
+            .unwrap()
+            .new_capture_stream(2, SampleFormat::S24LE, 48000, 4, &[])
+            .expect_err("capture with another format should have failed");
+    }
+}
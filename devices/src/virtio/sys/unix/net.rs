@@ -11,8 +11,12 @@ use base::EventType;
 use base::ReadNotifier;
 use base::WaitContext;
 use net_util::TapT;
+#[cfg(feature = "slirp")]
+use virtio_sys::virtio_net;
 use vm_memory::GuestMemory;
 
+#[cfg(feature = "slirp")]
+use super::super::super::net::Net;
 use super::super::super::net::NetError;
 use super::super::super::net::Token;
 use super::super::super::net::Worker;
@@ -160,3 +164,22 @@ where
         )
     }
 }
+
+#[cfg(feature = "slirp")]
+impl Net<net_util::Slirp> {
+    /// Creates a new virtio network device from a pseudo-TAP device, provided by Slirp. The
+    /// libslirp loop at the other end of `slirp` is started by the caller.
+    pub fn new_slirp(base_features: u64, slirp: net_util::Slirp) -> Result<Self, NetError> {
+        let mut net = Net::from(base_features, slirp, 1)?;
+
+        // libslirp only deals in complete, checksummed frames, so don't offer any offloads.
+        net.avail_features &= !(1 << virtio_net::VIRTIO_NET_F_GUEST_CSUM
+            | 1 << virtio_net::VIRTIO_NET_F_CSUM
+            | 1 << virtio_net::VIRTIO_NET_F_CTRL_GUEST_OFFLOADS
+            | 1 << virtio_net::VIRTIO_NET_F_GUEST_TSO4
+            | 1 << virtio_net::VIRTIO_NET_F_GUEST_UFO
+            | 1 << virtio_net::VIRTIO_NET_F_HOST_TSO4
+            | 1 << virtio_net::VIRTIO_NET_F_HOST_UFO);
+        Ok(net)
+    }
+}
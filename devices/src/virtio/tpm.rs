@@ -8,12 +8,17 @@ use std::io::Write;
 use std::ops::BitOrAssign;
 use std::thread;
 
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
 use base::error;
 use base::Event;
 use base::EventToken;
 use base::RawDescriptor;
 use base::WaitContext;
 use remain::sorted;
+use serde::Deserialize;
+use serde::Serialize;
 use thiserror::Error;
 use vm_memory::GuestMemory;
 
@@ -22,6 +27,7 @@ use super::DescriptorError;
 use super::DeviceType;
 use super::Interrupt;
 use super::Queue;
+use super::QueueSnapshot;
 use super::Reader;
 use super::SignalableInterrupt;
 use super::VirtioDevice;
@@ -50,6 +56,21 @@ struct Worker {
 
 pub trait TpmBackend: Send {
     fn execute_command<'a>(&'a mut self, command: &[u8]) -> &'a [u8];
+
+    /// Returns the descriptors the backend needs to keep when the device is sandboxed.
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        Vec::new()
+    }
+
+    /// Saves the state of the TPM, to be given back to `restore`.
+    fn snapshot(&self) -> anyhow::Result<String> {
+        Err(anyhow!("TPM backend does not support snapshots"))
+    }
+
+    /// Restores the state of the TPM saved by `snapshot`.
+    fn restore(&mut self, _data: &str) -> anyhow::Result<()> {
+        Err(anyhow!("TPM backend does not support snapshots"))
+    }
 }
 
 impl Worker {
@@ -108,7 +129,14 @@ impl Worker {
         needs_interrupt
     }
 
-    fn run(mut self) {
+    /// Processes the queue until the kill event is signaled, and hands back the backend and the
+    /// queue.
+    fn run(mut self) -> (Box<dyn TpmBackend>, Queue) {
+        self.run_until_killed();
+        (self.backend, self.queue)
+    }
+
+    fn run_until_killed(&mut self) {
         #[derive(EventToken, Debug)]
         enum Token {
             // A request is ready on the queue.
@@ -172,8 +200,25 @@ impl Worker {
 pub struct Tpm {
     backend: Option<Box<dyn TpmBackend>>,
     kill_evt: Option<Event>,
-    worker_thread: Option<thread::JoinHandle<()>>,
+    worker_thread: Option<thread::JoinHandle<(Box<dyn TpmBackend>, Queue)>>,
     features: u64,
+    // Resources handed over by `activate`, kept so that `wake` can restart the worker.
+    worker_resources: Option<WorkerResources>,
+    // Queue taken back from the worker by `sleep`.
+    sleeping_queue: Option<Queue>,
+}
+
+struct WorkerResources {
+    mem: GuestMemory,
+    interrupt: Interrupt,
+    queue_evt: Event,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TpmSnapshot {
+    backend: String,
+    // `None` if the device was not activated.
+    queue: Option<QueueSnapshot>,
 }
 
 impl Tpm {
@@ -183,6 +228,89 @@ impl Tpm {
             kill_evt: None,
             worker_thread: None,
             features: base_features,
+            worker_resources: None,
+            sleeping_queue: None,
+        }
+    }
+
+    /// Spawns the worker thread that processes `queue`.
+    fn start_worker(&mut self, queue: Queue) {
+        let resources = match &self.worker_resources {
+            Some(r) => r,
+            None => {
+                error!("vtpm has no resources to start the worker");
+                return;
+            }
+        };
+        let mem = resources.mem.clone();
+        let interrupt = resources.interrupt.clone();
+        let queue_evt = match resources.queue_evt.try_clone() {
+            Ok(e) => e,
+            Err(e) => {
+                error!("vtpm failed to clone queue Event: {}", e);
+                return;
+            }
+        };
+
+        let backend = match self.backend.take() {
+            Some(backend) => backend,
+            None => {
+                error!("no backend in vtpm");
+                return;
+            }
+        };
+
+        let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("vtpm failed to create kill Event pair: {}", err);
+                self.backend = Some(backend);
+                return;
+            }
+        };
+        self.kill_evt = Some(self_kill_evt);
+
+        let worker = Worker {
+            interrupt,
+            queue,
+            mem,
+            queue_evt,
+            kill_evt,
+            backend,
+        };
+
+        let worker_result = thread::Builder::new()
+            .name("v_tpm".to_string())
+            .spawn(|| worker.run());
+
+        match worker_result {
+            Err(e) => {
+                error!("vtpm failed to spawn virtio_tpm worker: {}", e);
+            }
+            Ok(join_handle) => {
+                self.worker_thread = Some(join_handle);
+            }
+        }
+    }
+
+    /// Stops the worker thread and takes back the backend. Returns the queue it was processing,
+    /// or `None` if no worker was running.
+    fn stop_worker(&mut self) -> anyhow::Result<Option<Queue>> {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            kill_evt
+                .signal()
+                .context("failed to notify the kill event")?;
+        }
+
+        match self.worker_thread.take() {
+            Some(worker_thread) => match worker_thread.join() {
+                Ok((backend, queue)) => {
+                    self.backend = Some(backend);
+                    Ok(Some(queue))
+                }
+                Err(_) => Err(anyhow!("failed to get back the vtpm backend")),
+            },
+            None => Ok(None),
         }
     }
 }
@@ -201,7 +329,10 @@ impl Drop for Tpm {
 
 impl VirtioDevice for Tpm {
     fn keep_rds(&self) -> Vec<RawDescriptor> {
-        Vec::new()
+        self.backend
+            .as_ref()
+            .map(|backend| backend.keep_rds())
+            .unwrap_or_default()
     }
 
     fn device_type(&self) -> DeviceType {
@@ -229,48 +360,63 @@ impl VirtioDevice for Tpm {
         let queue = queues.remove(0);
         let queue_evt = queue_evts.remove(0);
 
-        let backend = match self.backend.take() {
-            Some(backend) => backend,
-            None => {
-                error!("no backend in vtpm");
-                return;
-            }
-        };
-
-        let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
-            Ok(v) => v,
-            Err(err) => {
-                error!("vtpm failed to create kill Event pair: {}", err);
-                return;
-            }
-        };
-        self.kill_evt = Some(self_kill_evt);
-
-        let worker = Worker {
-            interrupt,
-            queue,
+        self.worker_resources = Some(WorkerResources {
             mem,
+            interrupt,
             queue_evt,
-            kill_evt,
-            backend,
-        };
+        });
+        self.start_worker(queue);
+    }
+}
 
-        let worker_result = thread::Builder::new()
-            .name("v_tpm".to_string())
-            .spawn(|| worker.run());
+impl Suspendable for Tpm {
+    fn sleep(&mut self) -> anyhow::Result<()> {
+        if let Some(queue) = self.stop_worker()? {
+            self.sleeping_queue = Some(queue);
+        }
+        Ok(())
+    }
 
-        match worker_result {
-            Err(e) => {
-                error!("vtpm failed to spawn virtio_tpm worker: {}", e);
-            }
-            Ok(join_handle) => {
-                self.worker_thread = Some(join_handle);
-            }
+    fn wake(&mut self) -> anyhow::Result<()> {
+        if let Some(queue) = self.sleeping_queue.take() {
+            self.start_worker(queue);
         }
+        Ok(())
     }
-}
 
-impl Suspendable for Tpm {}
+    fn snapshot(&self) -> anyhow::Result<String> {
+        if self.worker_thread.is_some() {
+            bail!("virtio_tpm must be asleep to be snapshotted");
+        }
+        let backend = self.backend.as_ref().context("virtio_tpm has no backend")?;
+        serde_json::to_string(&TpmSnapshot {
+            backend: backend
+                .snapshot()
+                .context("failed to snapshot the TPM backend")?,
+            queue: self.sleeping_queue.as_ref().map(Queue::snapshot),
+        })
+        .context("failed to serialize virtio_tpm snapshot")
+    }
+
+    fn restore(&mut self, data: &str) -> anyhow::Result<()> {
+        if self.worker_thread.is_some() {
+            bail!("virtio_tpm must be asleep to be restored");
+        }
+        let snapshot: TpmSnapshot =
+            serde_json::from_str(data).context("failed to deserialize virtio_tpm snapshot")?;
+        if snapshot.queue.is_some() && self.worker_resources.is_none() {
+            bail!("virtio_tpm must be activated to restore its queue");
+        }
+
+        self.backend
+            .as_mut()
+            .context("virtio_tpm has no backend")?
+            .restore(&snapshot.backend)
+            .context("failed to restore the TPM backend")?;
+        self.sleeping_queue = snapshot.queue.as_ref().map(Queue::restore);
+        Ok(())
+    }
+}
 
 #[derive(PartialEq)]
 enum NeedsInterrupt {
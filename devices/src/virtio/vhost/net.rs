@@ -16,6 +16,7 @@ use base::Tube;
 use net_util::MacAddress;
 use net_util::TapT;
 use vhost::NetT as VhostNetT;
+use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
 use virtio_sys::virtio_net;
 use vm_memory::GuestMemory;
 
@@ -83,7 +84,8 @@ where
         tap.enable().map_err(Error::TapEnable)?;
         let vhost_net_handle = U::new(vhost_net_device_path).map_err(Error::VhostOpen)?;
 
-        let avail_features = base_features
+        // The vhost kernel driver only implements split virtqueues.
+        let avail_features = base_features & !(1 << VIRTIO_F_RING_PACKED)
             | 1 << virtio_net::VIRTIO_NET_F_GUEST_CSUM
             | 1 << virtio_net::VIRTIO_NET_F_CSUM
             | 1 << virtio_net::VIRTIO_NET_F_GUEST_TSO4
@@ -176,6 +178,12 @@ where
         QUEUE_SIZES
     }
 
+    fn accesses_memory_through_queues(&self) -> bool {
+        // The queues are accessed by the vhost kernel driver, which is given guest physical
+        // addresses and knows nothing of the virtio-iommu.
+        false
+    }
+
     fn features(&self) -> u64 {
         self.avail_features
     }
@@ -428,6 +436,12 @@ pub mod tests {
         assert_eq!(net.features(), expected_features);
     }
 
+    #[test]
+    fn not_behind_iommu() {
+        let net = create_net_common();
+        assert!(!net.accesses_memory_through_queues());
+    }
+
     #[test]
     fn ack_features() {
         let mut net = create_net_common();
@@ -86,6 +86,7 @@ impl VhostUserDevice for BlockAsync {
             self.read_only,
             self.sparse,
             self.id,
+            Arc::clone(&self.throttle),
         )));
 
         let timer = Timer::new().context("Failed to create a timer")?;
@@ -175,6 +176,7 @@ impl VhostUserBackend for BlockBackend {
 
     fn protocol_features(&self) -> VhostUserProtocolFeatures {
         VhostUserProtocolFeatures::CONFIG
+            | VhostUserProtocolFeatures::INFLIGHT_SHMFD
             | VhostUserProtocolFeatures::MQ
             | VhostUserProtocolFeatures::SLAVE_REQ
     }
@@ -237,6 +239,7 @@ impl VhostUserBackend for BlockBackend {
                     doorbell,
                     timer,
                     timer_armed,
+                    None,
                 ),
                 registration,
             ))
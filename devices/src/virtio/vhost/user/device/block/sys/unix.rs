@@ -52,6 +52,7 @@ pub fn start_device(opts: Options) -> anyhow::Result<()> {
         block_size: 512,
         id: None,
         async_executor: None,
+        throttle: Default::default(),
     };
 
     let block = Box::new(BlockAsync::new(
@@ -65,6 +66,7 @@ pub fn start_device(opts: Options) -> anyhow::Result<()> {
         None,
         None,
         None,
+        disk.throttle,
     )?)
     .into_backend(&ex)?;
 
@@ -81,6 +81,7 @@ pub fn start_device(opts: Options) -> anyhow::Result<()> {
         None,
         None,
         None,
+        disk_option.throttle,
     )?)
     .into_backend(&ex)?;
 
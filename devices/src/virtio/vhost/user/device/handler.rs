@@ -64,10 +64,13 @@ use base::error;
 use base::Event;
 use base::FromRawDescriptor;
 use base::IntoRawDescriptor;
+use base::MemoryMapping;
+use base::MemoryMappingBuilder;
 use base::Protection;
 use base::SafeDescriptor;
 use base::SharedMemory;
 use sys::Doorbell;
+use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
 use vm_control::VmMemorySource;
 use vm_memory::GuestAddress;
 use vm_memory::GuestMemory;
@@ -94,6 +97,8 @@ use vmm_vhost::Slave;
 use vmm_vhost::VhostUserMasterReqHandler;
 use vmm_vhost::VhostUserSlaveReqHandlerMut;
 
+use crate::virtio::inflight_region_size;
+use crate::virtio::InflightRegion;
 use crate::virtio::Queue;
 use crate::virtio::SharedMemoryMapper;
 use crate::virtio::SharedMemoryRegion;
@@ -244,6 +249,13 @@ impl Vring {
     }
 }
 
+/// Inflight I/O tracking buffer shared with the frontend, which keeps it across backend restarts.
+struct InflightBuffer {
+    mapping: Arc<MemoryMapping>,
+    /// Size of the region of each queue in `mapping`.
+    queue_region_size: usize,
+}
+
 /// Trait for defining vhost-user ops that are platform-dependent.
 pub trait VhostUserPlatformOps {
     /// Returns the protocol implemented by these platform ops.
@@ -370,6 +382,7 @@ where
     owned: bool,
     vmm_maps: Option<Vec<MappingInfo>>,
     mem: Option<GuestMemory>,
+    inflight: Option<InflightBuffer>,
     backend: Box<dyn VhostUserBackend>,
     ops: O,
 }
@@ -397,6 +410,7 @@ where
             owned: false,
             vmm_maps: None,
             mem: None,
+            inflight: None,
             backend,
             ops,
         }
@@ -423,7 +437,9 @@ impl<O: VhostUserPlatformOps> VhostUserSlaveReqHandlerMut for DeviceRequestHandl
     }
 
     fn get_features(&mut self) -> VhostResult<u64> {
-        let features = self.backend.features();
+        // The negotiated features are not passed on to the vrings, so they can only be split
+        // virtqueues.
+        let features = self.backend.features() & !(1 << VIRTIO_F_RING_PACKED);
         Ok(features)
     }
 
@@ -570,13 +586,35 @@ impl<O: VhostUserPlatformOps> VhostUserSlaveReqHandlerMut for DeviceRequestHandl
         let vring = &mut self.vrings[index as usize];
         vring.queue.set_ready(true);
 
-        let queue = vring.queue.clone();
-        let doorbell = vring.doorbell.clone().ok_or(VhostError::InvalidOperation)?;
         let mem = self
             .mem
             .as_ref()
             .cloned()
             .ok_or(VhostError::InvalidOperation)?;
+        if let Some(inflight) = &self.inflight {
+            let region = InflightRegion::new(
+                inflight.mapping.clone(),
+                index as usize * inflight.queue_region_size,
+            );
+            if let Err(e) = vring.queue.set_inflight(&mem, region) {
+                error!(
+                    "failed to set up inflight tracking of queue {}: {:#}",
+                    index, e
+                );
+                return Err(VhostError::SlaveInternalError);
+            }
+            // The guest already notified the previous device process about the chains to
+            // resubmit, so kick the queue to get them processed.
+            if vring.queue.has_resubmit() {
+                if let Err(e) = kick_evt.signal() {
+                    error!("failed to kick queue {}: {}", index, e);
+                    return Err(VhostError::SlaveInternalError);
+                }
+            }
+        }
+
+        let queue = vring.queue.clone();
+        let doorbell = vring.doorbell.clone().ok_or(VhostError::InvalidOperation)?;
 
         if let Err(e) = self
             .backend
@@ -655,13 +693,69 @@ impl<O: VhostUserPlatformOps> VhostUserSlaveReqHandlerMut for DeviceRequestHandl
 
     fn get_inflight_fd(
         &mut self,
-        _inflight: &VhostUserInflight,
+        inflight: &VhostUserInflight,
     ) -> VhostResult<(VhostUserInflight, File)> {
-        unimplemented!("get_inflight_fd");
+        if inflight.num_queues == 0 || inflight.num_queues as usize > self.vrings.len() {
+            return Err(VhostError::InvalidParam);
+        }
+
+        // The buffer is zeroed, which tells `Queue::set_inflight` that no chain is inflight yet.
+        let queue_region_size = inflight_region_size(inflight.queue_size);
+        let mmap_size = queue_region_size * inflight.num_queues as usize;
+        let shm = SharedMemory::new("vhost_user_inflight", mmap_size as u64).map_err(|e| {
+            error!("failed to allocate inflight buffer: {}", e);
+            VhostError::SlaveInternalError
+        })?;
+        let mapping = MemoryMappingBuilder::new(mmap_size)
+            .from_shared_memory(&shm)
+            .build()
+            .map_err(|e| {
+                error!("failed to map inflight buffer: {}", e);
+                VhostError::SlaveInternalError
+            })?;
+        self.inflight = Some(InflightBuffer {
+            mapping: Arc::new(mapping),
+            queue_region_size,
+        });
+
+        let reply = VhostUserInflight::new(
+            mmap_size as u64,
+            0,
+            inflight.num_queues,
+            inflight.queue_size,
+        );
+        // Safe because we own the descriptor.
+        let file =
+            unsafe { File::from_raw_descriptor(SafeDescriptor::from(shm).into_raw_descriptor()) };
+        Ok((reply, file))
     }
 
-    fn set_inflight_fd(&mut self, _inflight: &VhostUserInflight, _file: File) -> VhostResult<()> {
-        unimplemented!("set_inflight_fd");
+    fn set_inflight_fd(&mut self, inflight: &VhostUserInflight, file: File) -> VhostResult<()> {
+        if inflight.num_queues == 0 || inflight.num_queues as usize > self.vrings.len() {
+            return Err(VhostError::InvalidParam);
+        }
+        let queue_region_size = inflight.mmap_size as usize / inflight.num_queues as usize;
+        if queue_region_size < inflight_region_size(inflight.queue_size) {
+            error!(
+                "inflight buffer of {} bytes is too small for {} queues of size {}",
+                inflight.mmap_size, inflight.num_queues, inflight.queue_size
+            );
+            return Err(VhostError::InvalidParam);
+        }
+
+        let mapping = MemoryMappingBuilder::new(inflight.mmap_size as usize)
+            .from_file(&file)
+            .offset(inflight.mmap_offset)
+            .build()
+            .map_err(|e| {
+                error!("failed to map inflight buffer: {}", e);
+                VhostError::InvalidParam
+            })?;
+        self.inflight = Some(InflightBuffer {
+            mapping: Arc::new(mapping),
+            queue_region_size,
+        });
+        Ok(())
     }
 
     fn get_max_mem_slots(&mut self) -> VhostResult<u64> {
@@ -832,9 +926,13 @@ mod tests {
     use std::sync::mpsc::channel;
     #[cfg(unix)]
     use std::sync::Barrier;
+    #[cfg(unix)]
+    use std::time::Duration;
 
     use anyhow::anyhow;
     use anyhow::bail;
+    #[cfg(unix)]
+    use base::EventWaitResult;
     use data_model::DataInit;
     #[cfg(unix)]
     use tempfile::Builder;
@@ -862,6 +960,7 @@ mod tests {
         avail_features: u64,
         acked_features: u64,
         acked_protocol_features: VhostUserProtocolFeatures,
+        started_queues: Arc<std::sync::Mutex<Vec<(Queue, GuestMemory, Event)>>>,
     }
 
     impl FakeBackend {
@@ -873,6 +972,7 @@ mod tests {
                 avail_features: VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits(),
                 acked_features: 0,
                 acked_protocol_features: VhostUserProtocolFeatures::empty(),
+                started_queues: Default::default(),
             }
         }
     }
@@ -933,11 +1033,15 @@ mod tests {
         fn start_queue(
             &mut self,
             _idx: usize,
-            _queue: Queue,
-            _mem: GuestMemory,
+            queue: Queue,
+            mem: GuestMemory,
             _doorbell: Doorbell,
-            _kick_evt: Event,
+            kick_evt: Event,
         ) -> anyhow::Result<()> {
+            self.started_queues
+                .lock()
+                .unwrap()
+                .push((queue, mem, kick_evt));
             Ok(())
         }
 
@@ -1057,6 +1161,120 @@ mod tests {
         }
     }
 
+    // Sets up the memory table and the first vring of `handler` the way a VMM using `inflight`
+    // does.
+    #[cfg(unix)]
+    fn start_inflight_vring(
+        handler: &mut DeviceRequestHandler<VhostUserRegularOps>,
+        mem: &GuestMemory,
+        inflight: &VhostUserInflight,
+        inflight_file: &File,
+    ) {
+        let region = VhostUserMemoryRegion::new(0, mem.memory_size(), 0, 0);
+        let shm = SafeDescriptor::try_from(mem.shm_region(GuestAddress(0)).unwrap()).unwrap();
+        handler.set_mem_table(&[region], vec![shm.into()]).unwrap();
+        handler
+            .set_inflight_fd(inflight, inflight_file.try_clone().unwrap())
+            .unwrap();
+        handler
+            .set_vring_num(0, inflight.queue_size.into())
+            .unwrap();
+        handler
+            .set_vring_addr(0, VhostUserVringAddrFlags::empty(), 0, 0x400, 0x200, 0)
+            .unwrap();
+        handler.set_vring_base(0, 0).unwrap();
+        let call_evt = Event::new().unwrap();
+        handler
+            .set_vring_call(0, Some(SafeDescriptor::from(call_evt).into()))
+            .unwrap();
+        let kick_evt = Event::new().unwrap();
+        handler
+            .set_vring_kick(0, Some(SafeDescriptor::from(kick_evt).into()))
+            .unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_inflight_resubmit_after_reconnect() {
+        const QUEUE_SIZE: u16 = 0x10;
+
+        // Three chains are available in the split queue laid out at 0x0 (descriptors), 0x200
+        // (available ring) and 0x400 (used ring).
+        let mem = GuestMemory::new(&[(GuestAddress(0x0), 0x10000)]).unwrap();
+        for head in 0..3u16 {
+            mem.write_obj_at_addr(head, GuestAddress(0x204 + 2 * head as u64))
+                .unwrap();
+        }
+        mem.write_obj_at_addr(3u16, GuestAddress(0x202)).unwrap();
+
+        let backend = FakeBackend::new();
+        let started_queues = backend.started_queues.clone();
+        let mut handler = DeviceRequestHandler::new(Box::new(backend));
+        let (inflight, inflight_file) = handler
+            .get_inflight_fd(&VhostUserInflight::new(0, 0, 1, QUEUE_SIZE))
+            .unwrap();
+        start_inflight_vring(&mut handler, &mem, &inflight, &inflight_file);
+        {
+            let mut started = started_queues.lock().unwrap();
+            let (queue, mem, kick_evt) = &mut started[0];
+            // Nothing was left inflight by a previous device process.
+            assert_eq!(
+                kick_evt.wait_timeout(Duration::ZERO).unwrap(),
+                EventWaitResult::TimedOut
+            );
+            let heads: Vec<u16> = queue.iter(mem).map(|chain| chain.index).collect();
+            assert_eq!(heads, vec![0, 1, 2]);
+            queue.add_used(mem, 1, 0);
+        }
+        // The device process exits with chains 0 and 2 inflight.
+        drop(handler);
+        started_queues.lock().unwrap().clear();
+
+        let backend = FakeBackend::new();
+        let started_queues = backend.started_queues.clone();
+        let mut handler = DeviceRequestHandler::new(Box::new(backend));
+        start_inflight_vring(&mut handler, &mem, &inflight, &inflight_file);
+        let mut started = started_queues.lock().unwrap();
+        let (queue, mem, kick_evt) = &mut started[0];
+        // The restarted device is kicked without the guest notifying it again, and completes the
+        // chains left inflight.
+        assert_eq!(
+            kick_evt.wait_timeout(Duration::ZERO).unwrap(),
+            EventWaitResult::Signaled
+        );
+        let heads: Vec<u16> = queue.iter(mem).map(|chain| chain.index).collect();
+        assert_eq!(heads, vec![0, 2]);
+        queue.add_used(mem, 0, 0);
+        queue.add_used(mem, 2, 0);
+        let used_idx: u16 = mem.read_obj_from_addr(GuestAddress(0x402)).unwrap();
+        assert_eq!(used_idx, 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_inflight_fd_invalid_queues() {
+        let mut handler = DeviceRequestHandler::new(Box::new(FakeBackend::new()));
+        assert!(matches!(
+            handler.get_inflight_fd(&VhostUserInflight::new(0, 0, 0, 0x10)),
+            Err(VhostError::InvalidParam)
+        ));
+
+        let (inflight, inflight_file) = handler
+            .get_inflight_fd(&VhostUserInflight::new(0, 0, 2, 0x10))
+            .unwrap();
+        let no_queues = VhostUserInflight::new(inflight.mmap_size, 0, 0, 0x10);
+        assert!(matches!(
+            handler.set_inflight_fd(&no_queues, inflight_file.try_clone().unwrap()),
+            Err(VhostError::InvalidParam)
+        ));
+        // The buffer only has room for two queues.
+        let too_many_queues = VhostUserInflight::new(inflight.mmap_size, 0, 3, 0x10);
+        assert!(matches!(
+            handler.set_inflight_fd(&too_many_queues, inflight_file),
+            Err(VhostError::InvalidParam)
+        ));
+    }
+
     pub(super) fn vmm_handler_send_requests(vmm_handler: &mut VhostUserHandler, queues_num: usize) {
         println!("read_config");
         let mut buf = vec![0; std::mem::size_of::<FakeConfig>()];
@@ -140,7 +140,7 @@ where
     }
 
     fn protocol_features(&self) -> VhostUserProtocolFeatures {
-        VhostUserProtocolFeatures::CONFIG
+        VhostUserProtocolFeatures::CONFIG | VhostUserProtocolFeatures::INFLIGHT_SHMFD
     }
 
     fn ack_protocol_features(&mut self, features: u64) -> anyhow::Result<()> {
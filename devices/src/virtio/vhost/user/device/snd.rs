@@ -289,7 +289,9 @@ pub struct Options {
     /// comma separated key=value pairs for setting up cras snd devices.
     /// Possible key values:
     /// capture - Enable audio capture. Default to false.
-    /// backend - Which backend to use for vhost-snd (null|cras).
+    /// backend - Which backend to use for vhost-snd (null|file|alsa|cras).
+    /// wav_dir - Directory of the WAV files of the file backend.
+    /// alsa_device - PCM device of the alsa backend. Default to default.
     /// client_type - Set specific client type for cras backend.
     /// socket_type - Set socket type for cras backend.
     /// num_output_devices - Set number of output PCM devices.
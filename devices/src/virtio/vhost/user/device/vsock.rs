@@ -28,6 +28,7 @@ use data_model::Le64;
 use hypervisor::ProtectionType;
 use vhost::Vhost;
 use vhost::Vsock;
+use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
 use vm_memory::GuestMemory;
 use vmm_vhost::connection::vfio::Listener as VfioListener;
 use vmm_vhost::connection::Endpoint;
@@ -141,7 +142,9 @@ impl<H: VhostUserPlatformOps> VhostUserSlaveReqHandlerMut for VsockBackend<H> {
     }
 
     fn get_features(&mut self) -> Result<u64> {
-        let features = base_features(ProtectionType::Unprotected)
+        // The rings are handed to the vhost kernel driver, which only implements split
+        // virtqueues.
+        let features = base_features(ProtectionType::Unprotected) & !(1 << VIRTIO_F_RING_PACKED)
             | self.features
             | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
         Ok(features)
@@ -48,6 +48,7 @@ use libc::MSG_PEEK;
 use resources::Alloc;
 use sync::Mutex;
 use uuid::Uuid;
+use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
 use vm_control::MemSlot;
 use vm_control::VmMemoryDestination;
 use vm_control::VmMemoryRequest;
@@ -1390,6 +1391,10 @@ impl VirtioVhostUser {
             .checked_next_power_of_two()
             .expect("Sibling too large");
 
+        // The rx and tx queues are driven by the vvu driver of the device backend in the guest,
+        // which only implements split virtqueues.
+        let base_features = base_features & !(1 << VIRTIO_F_RING_PACKED);
+
         Ok(VirtioVhostUser {
             base_features: base_features | 1 << VIRTIO_F_ACCESS_PLATFORM,
             device_bar_size,
@@ -37,6 +37,7 @@ impl VhostUserVirtioDevice {
             | 1 << VIRTIO_BLK_F_WRITE_ZEROES;
 
         let allow_protocol_features = VhostUserProtocolFeatures::CONFIG
+            | VhostUserProtocolFeatures::INFLIGHT_SHMFD
             | VhostUserProtocolFeatures::MQ
             | VhostUserProtocolFeatures::SLAVE_REQ;
 
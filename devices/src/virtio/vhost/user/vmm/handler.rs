@@ -5,6 +5,9 @@
 mod sys;
 mod worker;
 
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
 
@@ -15,10 +18,12 @@ use base::Event;
 use base::Protection;
 use base::SafeDescriptor;
 use rutabaga_gfx::DeviceId;
+use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
 use vm_control::VmMemorySource;
 use vm_memory::GuestMemory;
 use vmm_vhost::message::VhostUserConfigFlags;
 use vmm_vhost::message::VhostUserGpuMapMsg;
+use vmm_vhost::message::VhostUserInflight;
 use vmm_vhost::message::VhostUserProtocolFeatures;
 use vmm_vhost::message::VhostUserShmemMapMsg;
 use vmm_vhost::message::VhostUserShmemUnmapMsg;
@@ -49,6 +54,21 @@ fn set_features(vu: &mut SocketMaster, avail_features: u64, ack_features: u64) -
     Ok(features)
 }
 
+/// A vring set up by `VhostUserHandler::activate`, kept to set it up again on a new backend.
+#[cfg_attr(windows, allow(dead_code))]
+struct ActiveVring {
+    queue: Queue,
+    queue_evt: Event,
+    irqfd: Event,
+}
+
+/// Where to find the backend again after it restarts.
+#[cfg_attr(windows, allow(dead_code))]
+struct ReconnectInfo {
+    path: PathBuf,
+    max_queue_num: u64,
+}
+
 pub struct VhostUserHandler {
     vu: SocketMaster,
     pub avail_features: u64,
@@ -57,6 +77,13 @@ pub struct VhostUserHandler {
     backend_req_handler: Option<BackendReqHandler>,
     // Shared memory region info. IPC result from backend is saved with outer Option.
     shmem_region: Option<Option<SharedMemoryRegion>>,
+    // Inflight I/O tracking buffer allocated by the backend, if `INFLIGHT_SHMFD` is negotiated.
+    inflight: Option<(VhostUserInflight, File)>,
+    // Guest memory and vrings of the activated device.
+    mem: Option<GuestMemory>,
+    active_vrings: Vec<ActiveVring>,
+    // Set if the device can be reconnected to a restarted backend.
+    reconnect_info: Option<ReconnectInfo>,
     // On Windows, we need a backend pid to support backend requests.
     #[cfg(windows)]
     backend_pid: Option<u32>,
@@ -111,6 +138,10 @@ impl VhostUserHandler {
             protocol_features,
             backend_req_handler,
             shmem_region: None,
+            inflight: None,
+            mem: None,
+            active_vrings: Vec::new(),
+            reconnect_info: None,
             #[cfg(windows)]
             backend_pid,
         })
@@ -205,6 +236,25 @@ impl VhostUserHandler {
         queue: &Queue,
         queue_evt: &Event,
         irqfd: &Event,
+    ) -> Result<()> {
+        // For packed virtqueues, bit 15 of the base holds the wrap counter, which starts at 1.
+        let base = if self.acked_features & (1 << VIRTIO_F_RING_PACKED) != 0 {
+            1 << 15
+        } else {
+            0
+        };
+        self.start_vring(mem, queue_index, queue, queue_evt, irqfd, base)
+    }
+
+    // Sets up and enables the vring of `queue`, starting at ring position `base`.
+    fn start_vring(
+        &mut self,
+        mem: &GuestMemory,
+        queue_index: usize,
+        queue: &Queue,
+        queue_evt: &Event,
+        irqfd: &Event,
+        base: u16,
     ) -> Result<()> {
         self.vu
             .set_vring_num(queue_index, queue.actual_size())
@@ -230,7 +280,7 @@ impl VhostUserHandler {
             .map_err(Error::SetVringAddr)?;
 
         self.vu
-            .set_vring_base(queue_index, 0)
+            .set_vring_base(queue_index, base)
             .map_err(Error::SetVringBase)?;
 
         self.vu
@@ -246,16 +296,45 @@ impl VhostUserHandler {
         Ok(())
     }
 
+    // Allocates the inflight I/O tracking buffer of `queues` in the backend.
+    fn set_up_inflight(&mut self, queues: &[Queue]) -> Result<()> {
+        let queue_size = queues.iter().map(Queue::actual_size).max().unwrap_or(0);
+        let (inflight, file) = self
+            .vu
+            .get_inflight_fd(&VhostUserInflight::new(
+                0,
+                0,
+                queues.len() as u16,
+                queue_size,
+            ))
+            .map_err(Error::GetInflightFd)?;
+        self.vu
+            .set_inflight_fd(&inflight, file.as_raw_descriptor())
+            .map_err(Error::SetInflightFd)?;
+        self.inflight = Some((inflight, file));
+        Ok(())
+    }
+
     /// Activates vrings.
+    ///
+    /// If the backend supports inflight I/O tracking and can be reached again on its socket, the
+    /// worker reconnects to it when it goes away and restores the state of the device on it.
     pub fn activate(
-        &mut self,
+        handler: &Arc<Mutex<Self>>,
         mem: GuestMemory,
         interrupt: Interrupt,
         queues: Vec<Queue>,
         queue_evts: Vec<Event>,
         label: &str,
     ) -> Result<(thread::JoinHandle<()>, Event)> {
-        self.set_mem_table(&mem)?;
+        let mut this = handler.lock().unwrap();
+        if this
+            .protocol_features
+            .contains(VhostUserProtocolFeatures::INFLIGHT_SHMFD)
+        {
+            this.set_up_inflight(&queues)?;
+        }
+        this.set_mem_table(&mem)?;
 
         let msix_config_opt = interrupt
             .get_msix_config()
@@ -263,21 +342,33 @@ impl VhostUserHandler {
             .ok_or(Error::MsixConfigUnavailable)?;
         let msix_config = msix_config_opt.lock();
 
+        this.active_vrings.clear();
         for (queue_index, queue) in queues.iter().enumerate() {
             let queue_evt = &queue_evts[queue_index];
             let irqfd = msix_config
                 .get_irqfd(queue.vector() as usize)
                 .unwrap_or_else(|| interrupt.get_interrupt_evt());
-            self.activate_vring(&mem, queue_index, queue, queue_evt, irqfd)?;
+            this.activate_vring(&mem, queue_index, queue, queue_evt, irqfd)?;
+            this.active_vrings.push(ActiveVring {
+                queue: queue.clone(),
+                queue_evt: queue_evt.try_clone().map_err(Error::CreateEvent)?,
+                irqfd: irqfd.try_clone().map_err(Error::CreateEvent)?,
+            });
         }
 
         drop(msix_config);
+        this.mem = Some(mem.clone());
+        let reconnect_handler = if this.inflight.is_some() && this.reconnect_info.is_some() {
+            Some(handler.clone())
+        } else {
+            None
+        };
 
         let label = format!("vhost_user_virtio_{}", label);
         let kill_evt = Event::new().map_err(Error::CreateEvent)?;
         let self_kill_evt = kill_evt.try_clone().map_err(Error::CreateEvent)?;
 
-        let backend_req_handler = self.backend_req_handler.take();
+        let backend_req_handler = this.backend_req_handler.take();
         if let Some(handler) = &backend_req_handler {
             // Using unwrap here to get the mutex protected value
             handler
@@ -295,6 +386,7 @@ impl VhostUserHandler {
                     mem,
                     kill_evt,
                     backend_req_handler,
+                    reconnect_handler,
                 };
 
                 if let Err(e) = worker.run(interrupt) {
@@ -315,9 +407,50 @@ impl VhostUserHandler {
                 .get_vring_base(queue_index)
                 .map_err(Error::GetVringBase)?;
         }
+        // The buffer of inflight chains is only valid until the device is reset.
+        self.inflight = None;
+        self.mem = None;
+        self.active_vrings.clear();
         Ok(())
     }
 
+    // Sets up the inflight buffer, memory table and vrings of the activated device on a new
+    // backend. The vrings start at the position of their used ring, from which the backend
+    // resumes the chains left inflight by the previous one.
+    #[cfg_attr(windows, allow(dead_code))]
+    fn restore_vrings(&mut self) -> Result<()> {
+        if let Some((inflight, file)) = &self.inflight {
+            self.vu
+                .set_inflight_fd(inflight, file.as_raw_descriptor())
+                .map_err(Error::SetInflightFd)?;
+        }
+        let mem = match self.mem.clone() {
+            Some(mem) => mem,
+            None => return Ok(()),
+        };
+        self.set_mem_table(&mem)?;
+
+        let active_vrings = std::mem::take(&mut self.active_vrings);
+        let result = active_vrings
+            .iter()
+            .enumerate()
+            .try_for_each(|(queue_index, vring)| {
+                let used_index = mem
+                    .read_obj_from_addr::<u16>(vring.queue.used_ring().unchecked_add(2))
+                    .map_err(Error::GetUsedIndex)?;
+                self.start_vring(
+                    &mem,
+                    queue_index,
+                    &vring.queue,
+                    &vring.queue_evt,
+                    &vring.irqfd,
+                    used_index,
+                )
+            });
+        self.active_vrings = active_vrings;
+        result
+    }
+
     pub fn get_shared_memory_region(&mut self) -> Result<Option<SharedMemoryRegion>> {
         if !self
             .protocol_features
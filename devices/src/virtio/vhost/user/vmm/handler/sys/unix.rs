@@ -2,26 +2,38 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::io;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 
 use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
+use base::error;
 use base::info;
 use base::AsRawDescriptor;
+use base::Event;
+use base::EventWaitResult;
 use base::SafeDescriptor;
 use cros_async::AsyncWrapper;
 use cros_async::Executor;
+use cros_async::TimerAsync;
 use vmm_vhost::connection::socket::Endpoint as SocketEndpoint;
 use vmm_vhost::message::MasterReq;
 use vmm_vhost::message::VhostUserProtocolFeatures;
+use vmm_vhost::message::VhostUserVirtioFeatures;
 use vmm_vhost::Error as VhostError;
 use vmm_vhost::Master;
 use vmm_vhost::MasterReqHandler;
+use vmm_vhost::VhostBackend;
+use vmm_vhost::VhostUserMaster;
 
 use crate::virtio::vhost::user::vmm::handler::BackendReqHandler;
 use crate::virtio::vhost::user::vmm::handler::BackendReqHandlerImpl;
+use crate::virtio::vhost::user::vmm::handler::ReconnectInfo;
 use crate::virtio::vhost::user::vmm::handler::VhostUserHandler;
 use crate::virtio::vhost::user::vmm::Connection;
 use crate::virtio::vhost::user::vmm::Error;
@@ -30,6 +42,12 @@ use crate::virtio::vhost::user::vmm::Result as VhostResult;
 pub(in crate::virtio::vhost::user::vmm::handler) type SocketMaster =
     Master<SocketEndpoint<MasterReq>>;
 
+/// Interval between two attempts to connect to a backend that went away.
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Interval between two checks of whether a reply from the backend has been read.
+const REPLY_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 impl VhostUserHandler {
     /// Creates a `VhostUserHandler` instance attached to the provided
     /// connection with features and protocol features initialized.
@@ -40,12 +58,154 @@ impl VhostUserHandler {
         init_features: u64,
         allow_protocol_features: VhostUserProtocolFeatures,
     ) -> VhostResult<Self> {
-        Self::new(
+        // A backend listening on a named socket can be found there again after it restarts.
+        let reconnect_info = connection
+            .peer_addr()
+            .ok()
+            .and_then(|addr| addr.as_pathname().map(Path::to_path_buf))
+            .map(|path| ReconnectInfo {
+                path,
+                max_queue_num,
+            });
+        let mut handler = Self::new(
             SocketMaster::from_stream(connection, max_queue_num),
             allow_features,
             init_features,
             allow_protocol_features,
-        )
+        )?;
+        handler.reconnect_info = reconnect_info;
+        Ok(handler)
+    }
+
+    // Negotiates the features of the device again with a new backend connected through
+    // `connection`, and restores the state of the device on it. The backend requests are handled
+    // by `backend`, which was used for the previous backend.
+    fn restore(
+        &mut self,
+        connection: UnixStream,
+        backend: Option<Arc<Mutex<BackendReqHandlerImpl>>>,
+    ) -> VhostResult<()> {
+        let max_queue_num = self
+            .reconnect_info
+            .as_ref()
+            .map_or(0, |info| info.max_queue_num);
+        let mut vu = SocketMaster::from_stream(connection, max_queue_num);
+        vu.set_owner().map_err(Error::SetOwner)?;
+
+        let avail_features = vu.get_features().map_err(Error::GetFeatures)?;
+        if self.acked_features & !avail_features != 0 {
+            return Err(Error::ReconnectFeatures);
+        }
+        vu.set_features(self.acked_features)
+            .map_err(Error::SetFeatures)?;
+
+        if self.acked_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() != 0 {
+            let avail_protocol_features = vu
+                .get_protocol_features()
+                .map_err(Error::GetProtocolFeatures)?;
+            if !avail_protocol_features.contains(self.protocol_features) {
+                return Err(Error::ReconnectFeatures);
+            }
+            vu.set_protocol_features(self.protocol_features)
+                .map_err(Error::SetProtocolFeatures)?;
+        }
+
+        if let Some(backend) = backend {
+            let mut handler =
+                MasterReqHandler::with_stream(backend).map_err(Error::CreateBackendReqHandler)?;
+            vu.set_slave_request_fd(&handler.take_tx_descriptor())
+                .map_err(Error::SetDeviceRequestChannel)?;
+            self.backend_req_handler = Some(handler);
+        }
+
+        self.vu = vu;
+        self.restore_vrings()
+    }
+}
+
+/// Waits until the backend of `handler` closes its connection, or forever if `handler` is `None`.
+pub async fn wait_for_disconnect(
+    handler: Option<&Mutex<VhostUserHandler>>,
+    ex: &Executor,
+) -> Result<()> {
+    let handler = match handler {
+        Some(h) => h,
+        None => std::future::pending().await,
+    };
+
+    let socket = SafeDescriptor::try_from(&handler.lock().unwrap().vu as &dyn AsRawDescriptor)
+        .context("failed to get safe descriptor for the vhost-user socket")?;
+    let fd = socket.as_raw_descriptor();
+    let socket_source = ex
+        .async_from(AsyncWrapper::new(socket))
+        .context("failed to create an async source")?;
+
+    loop {
+        socket_source
+            .wait_readable()
+            .await
+            .context("failed to wait for the vhost-user socket to become readable")?;
+
+        // The replies of the backend also make the socket readable, so peek at the data to tell
+        // them apart from the end of the stream.
+        let mut byte = 0u8;
+        // Safe because `fd` is valid as long as `socket_source` is, and the buffer is one byte.
+        let ret = unsafe {
+            libc::recv(
+                fd,
+                &mut byte as *mut u8 as *mut libc::c_void,
+                1,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        if ret > 0 {
+            // Give the device time to read the reply.
+            TimerAsync::sleep(ex, REPLY_POLL_INTERVAL)
+                .await
+                .context("failed to sleep")?;
+        } else if ret == 0 || io::Error::last_os_error().kind() != io::ErrorKind::WouldBlock {
+            return Ok(());
+        }
+    }
+}
+
+/// Waits for a new backend to listen on the socket of the backend of `handler` that went away,
+/// and restores the state of the device on it. The backend requests are handled by `backend`,
+/// as they were for the previous backend.
+///
+/// Returns `false` if `kill_evt` is signaled before a backend could be reconnected.
+pub fn reconnect(
+    handler: &Mutex<VhostUserHandler>,
+    backend: Option<Arc<Mutex<BackendReqHandlerImpl>>>,
+    kill_evt: &Event,
+) -> Result<bool> {
+    let path = match &handler.lock().unwrap().reconnect_info {
+        Some(info) => info.path.clone(),
+        None => bail!("the backend can't be reconnected"),
+    };
+    info!(
+        "vhost-user backend went away, waiting for it on {}",
+        path.display()
+    );
+
+    loop {
+        // The socket can't be connected until the new backend listens on it.
+        if let Ok(connection) = UnixStream::connect(&path) {
+            match handler.lock().unwrap().restore(connection, backend.clone()) {
+                Ok(()) => {
+                    info!("vhost-user backend reconnected on {}", path.display());
+                    return Ok(true);
+                }
+                Err(e) => error!("failed to restore the device on the backend: {}", e),
+            }
+        }
+        match kill_evt
+            .wait_timeout(RECONNECT_INTERVAL)
+            .context("failed to wait for kill_evt")?
+        {
+            EventWaitResult::Signaled => return Ok(false),
+            EventWaitResult::TimedOut => {}
+        }
     }
 }
 
@@ -5,10 +5,12 @@
 use std::sync::Arc;
 use std::sync::Mutex;
 
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use base::info;
 use base::CloseNotifier;
+use base::Event;
 use base::ReadNotifier;
 use base::Tube;
 use cros_async::EventAsync;
@@ -104,3 +106,20 @@ pub async fn run_backend_request_handler(
         }
     }
 }
+
+/// Waits forever, as the backends can't be reconnected on Windows.
+pub async fn wait_for_disconnect(
+    _handler: Option<&Mutex<VhostUserHandler>>,
+    _ex: &Executor,
+) -> Result<()> {
+    std::future::pending().await
+}
+
+/// The backends can't be reconnected on Windows.
+pub fn reconnect(
+    _handler: &Mutex<VhostUserHandler>,
+    _backend: Option<Arc<Mutex<BackendReqHandlerImpl>>>,
+    _kill_evt: &Event,
+) -> Result<bool> {
+    bail!("vhost-user backends can't be reconnected on Windows")
+}
@@ -2,16 +2,22 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::sync::Arc;
+use std::sync::Mutex;
+
 use base::Event;
-use cros_async::select3;
+use cros_async::select4;
 use cros_async::Executor;
 use cros_async::SelectResult;
 use futures::pin_mut;
 use vm_memory::GuestMemory;
 
 use crate::virtio::async_utils;
+use crate::virtio::vhost::user::vmm::handler::sys::reconnect;
 use crate::virtio::vhost::user::vmm::handler::sys::run_backend_request_handler;
+use crate::virtio::vhost::user::vmm::handler::sys::wait_for_disconnect;
 use crate::virtio::vhost::user::vmm::handler::BackendReqHandler;
+use crate::virtio::vhost::user::vmm::handler::VhostUserHandler;
 use crate::virtio::Interrupt;
 use crate::virtio::Queue;
 
@@ -20,6 +26,9 @@ pub struct Worker {
     pub mem: GuestMemory,
     pub kill_evt: Event,
     pub backend_req_handler: Option<BackendReqHandler>,
+    // Handler of the device, which is restored on a new backend when the current one goes away.
+    // `None` if the device can't be reconnected.
+    pub reconnect_handler: Option<Arc<Mutex<VhostUserHandler>>>,
 }
 
 impl Worker {
@@ -34,20 +43,52 @@ impl Worker {
         let kill = async_utils::await_and_exit(&ex, kill_evt);
         pin_mut!(kill);
 
-        let req_handler = run_backend_request_handler(self.backend_req_handler.take(), &ex);
-        pin_mut!(req_handler);
+        loop {
+            let backend = self.backend_req_handler.as_ref().map(|h| h.backend());
+            let req_handler = run_backend_request_handler(self.backend_req_handler.take(), &ex);
+            pin_mut!(req_handler);
+
+            let disconnect = wait_for_disconnect(self.reconnect_handler.as_deref(), &ex);
+            pin_mut!(disconnect);
 
-        match ex.run_until(select3(resample, kill, req_handler)) {
-            Ok((resample_res, _, backend_result)) => {
-                if let SelectResult::Finished(Err(e)) = resample_res {
-                    return Err(format!("failed to resample a irq value: {:?}", e));
+            match ex.run_until(select4(
+                resample.as_mut(),
+                kill.as_mut(),
+                req_handler,
+                disconnect,
+            )) {
+                Ok((resample_res, kill_res, backend_result, disconnect_res)) => {
+                    if let SelectResult::Finished(Err(e)) = resample_res {
+                        return Err(format!("failed to resample a irq value: {:?}", e));
+                    }
+                    if let SelectResult::Finished(Err(e)) = backend_result {
+                        return Err(format!("backend request failure: {:#}", e));
+                    }
+                    if let SelectResult::Finished(Err(e)) = disconnect_res {
+                        return Err(format!("failed to watch the backend: {:#}", e));
+                    }
+                    // The backend request handler also finishes when the backend goes away.
+                    if matches!(kill_res, SelectResult::Finished(_))
+                        || (matches!(disconnect_res, SelectResult::Pending(_))
+                            && matches!(backend_result, SelectResult::Pending(_)))
+                    {
+                        return Ok(());
+                    }
                 }
-                if let SelectResult::Finished(Err(e)) = backend_result {
-                    return Err(format!("backend request failure: {:#}", e));
+                Err(e) => return Err(e.to_string()),
+            }
+
+            let handler = match &self.reconnect_handler {
+                Some(handler) => handler,
+                None => return Ok(()),
+            };
+            match reconnect(handler, backend, &self.kill_evt) {
+                Ok(true) => {
+                    self.backend_req_handler = handler.lock().unwrap().backend_req_handler.take();
                 }
-                Ok(())
+                Ok(false) => return Ok(()),
+                Err(e) => return Err(format!("failed to reconnect the backend: {:#}", e)),
             }
-            Err(e) => Err(e.to_string()),
         }
     }
 }
@@ -69,6 +69,9 @@ pub enum Error {
     /// Failed to get features.
     #[error("failed to get features: {0}")]
     GetFeatures(VhostError),
+    /// Failed to get the inflight I/O tracking buffer.
+    #[error("failed to get inflight buffer: {0}")]
+    GetInflightFd(VhostError),
     /// Failed to get host address.
     #[error("failed to get host address: {0}")]
     GetHostAddress(GuestMemoryError),
@@ -78,6 +81,9 @@ pub enum Error {
     /// Failed to get number of queues.
     #[error("failed to get number of queues: {0}")]
     GetQueueNum(VhostError),
+    /// Failed to read the index of a used ring.
+    #[error("failed to read the used ring index: {0}")]
+    GetUsedIndex(GuestMemoryError),
     /// Failed to get vring base offset.
     #[error("failed to get vring base offset: {0}")]
     GetVringBase(VhostError),
@@ -95,6 +101,9 @@ pub enum Error {
     MsixIrqfdUnavailable,
     #[error("protocol feature is not negotiated: {0:?}")]
     ProtocolFeatureNotNegoiated(VhostUserProtocolFeatures),
+    /// A new backend does not support the features negotiated with the previous one.
+    #[error("reconnected backend lacks negotiated features")]
+    ReconnectFeatures,
     /// Failed to reset owner.
     #[error("failed to reset owner: {0}")]
     ResetOwner(VhostError),
@@ -107,6 +116,9 @@ pub enum Error {
     /// Failed to set features.
     #[error("failed to set features: {0}")]
     SetFeatures(VhostError),
+    /// Failed to set the inflight I/O tracking buffer.
+    #[error("failed to set inflight buffer: {0}")]
+    SetInflightFd(VhostError),
     /// Failed to set memory map regions.
     #[error("failed to set memory map regions: {0}")]
     SetMemTable(VhostError),
@@ -34,8 +34,9 @@ impl VhostUserVirtioDevice {
             | 1 << virtio_net::VIRTIO_NET_F_MQ
             | 1 << virtio_net::VIRTIO_NET_F_MTU;
 
-        let allow_protocol_features =
-            VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::CONFIG;
+        let allow_protocol_features = VhostUserProtocolFeatures::MQ
+            | VhostUserProtocolFeatures::CONFIG
+            | VhostUserProtocolFeatures::INFLIGHT_SHMFD;
 
         VhostUserVirtioDevice::new(
             connection,
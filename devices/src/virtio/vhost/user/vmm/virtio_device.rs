@@ -4,7 +4,8 @@
 
 //! VirtioDevice implementation for the VMM side of a vhost-user connection.
 
-use std::cell::RefCell;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::thread;
 
 use base::error;
@@ -30,7 +31,7 @@ pub struct VhostUserVirtioDevice {
     device_type: DeviceType,
     kill_evt: Option<Event>,
     worker_thread: Option<thread::JoinHandle<()>>,
-    handler: RefCell<VhostUserHandler>,
+    handler: Arc<Mutex<VhostUserHandler>>,
     queue_sizes: Vec<u16>,
     cfg: Option<Vec<u8>>,
     expose_shmem_descriptors_with_viommu: bool,
@@ -111,7 +112,7 @@ impl VhostUserVirtioDevice {
             device_type,
             kill_evt: None,
             worker_thread: None,
-            handler: RefCell::new(handler),
+            handler: Arc::new(Mutex::new(handler)),
             queue_sizes,
             cfg: cfg.map(|cfg| cfg.to_vec()),
             expose_shmem_descriptors_with_viommu,
@@ -132,12 +133,18 @@ impl VirtioDevice for VhostUserVirtioDevice {
         &self.queue_sizes
     }
 
+    fn accesses_memory_through_queues(&self) -> bool {
+        // The queues are accessed by the backend, which would need IOTLB messages to translate
+        // them.
+        false
+    }
+
     fn features(&self) -> u64 {
-        self.handler.borrow().avail_features
+        self.handler.lock().unwrap().avail_features
     }
 
     fn ack_features(&mut self, features: u64) {
-        if let Err(e) = self.handler.borrow_mut().ack_features(features) {
+        if let Err(e) = self.handler.lock().unwrap().ack_features(features) {
             error!("failed to enable features 0x{:x}: {}", features, e);
         }
     }
@@ -145,13 +152,13 @@ impl VirtioDevice for VhostUserVirtioDevice {
     fn read_config(&self, offset: u64, data: &mut [u8]) {
         if let Some(cfg) = &self.cfg {
             copy_config(data, 0, cfg, offset);
-        } else if let Err(e) = self.handler.borrow_mut().read_config(offset, data) {
+        } else if let Err(e) = self.handler.lock().unwrap().read_config(offset, data) {
             error!("failed to read config: {}", e);
         }
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        if let Err(e) = self.handler.borrow_mut().write_config(offset, data) {
+        if let Err(e) = self.handler.lock().unwrap().write_config(offset, data) {
             error!("failed to write config: {}", e);
         }
     }
@@ -163,7 +170,8 @@ impl VirtioDevice for VhostUserVirtioDevice {
         queues: Vec<Queue>,
         queue_evts: Vec<Event>,
     ) {
-        match self.handler.borrow_mut().activate(
+        match VhostUserHandler::activate(
+            &self.handler,
             mem,
             interrupt,
             queues,
@@ -181,7 +189,7 @@ impl VirtioDevice for VhostUserVirtioDevice {
     }
 
     fn reset(&mut self) -> bool {
-        if let Err(e) = self.handler.borrow_mut().reset(self.queue_sizes.len()) {
+        if let Err(e) = self.handler.lock().unwrap().reset(self.queue_sizes.len()) {
             error!("Failed to reset device: {}", e);
             false
         } else {
@@ -190,7 +198,7 @@ impl VirtioDevice for VhostUserVirtioDevice {
     }
 
     fn get_shared_memory_region(&self) -> Option<SharedMemoryRegion> {
-        match self.handler.borrow_mut().get_shared_memory_region() {
+        match self.handler.lock().unwrap().get_shared_memory_region() {
             Ok(r) => r,
             Err(e) => {
                 error!("Failed to get shared memory regions {}", e);
@@ -200,7 +208,12 @@ impl VirtioDevice for VhostUserVirtioDevice {
     }
 
     fn set_shared_memory_mapper(&mut self, mapper: Box<dyn SharedMemoryMapper>) {
-        if let Err(e) = self.handler.borrow_mut().set_shared_memory_mapper(mapper) {
+        if let Err(e) = self
+            .handler
+            .lock()
+            .unwrap()
+            .set_shared_memory_mapper(mapper)
+        {
             error!("Error setting shared memory mapper {}", e);
         }
     }
@@ -19,6 +19,7 @@ use data_model::Le64;
 use serde::Deserialize;
 use vhost::Vhost;
 use vhost::Vsock as VhostVsockHandle;
+use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
 use vm_memory::GuestMemory;
 
 use super::worker::Worker;
@@ -78,7 +79,8 @@ impl Vsock {
         let kill_evt = Event::new().map_err(Error::CreateKillEvent)?;
         let handle = VhostVsockHandle::new(device_file);
 
-        let avail_features = base_features;
+        // The vhost kernel driver only implements split virtqueues.
+        let avail_features = base_features & !(1 << VIRTIO_F_RING_PACKED);
 
         let mut interrupts = Vec::new();
         for _ in 0..NUM_QUEUES {
@@ -154,6 +156,12 @@ impl VirtioDevice for Vsock {
         QUEUE_SIZES
     }
 
+    fn accesses_memory_through_queues(&self) -> bool {
+        // The queues are accessed by the vhost kernel driver, which is given guest physical
+        // addresses and knows nothing of the virtio-iommu.
+        false
+    }
+
     fn features(&self) -> u64 {
         self.avail_features
     }
@@ -333,6 +341,12 @@ mod tests {
         assert_eq!(features, vsock.features());
     }
 
+    #[test]
+    fn not_behind_iommu() {
+        let vsock = Vsock::new_for_testing(5, 0);
+        assert!(!vsock.accesses_memory_through_queues());
+    }
+
     fn from_vsock_arg(options: &str) -> Result<VhostVsockConfig, ParseError> {
         from_key_values(options)
     }
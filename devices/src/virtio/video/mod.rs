@@ -191,6 +191,11 @@ impl VirtioDevice for VideoDevice {
         QUEUE_SIZES
     }
 
+    fn accesses_memory_through_queues(&self) -> bool {
+        // Guest pages of resources are given by address in the commands.
+        false
+    }
+
     fn features(&self) -> u64 {
         self.base_features | backend_supported_virtio_features(self.backend)
     }
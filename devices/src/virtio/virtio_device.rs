@@ -84,6 +84,13 @@ pub trait VirtioDevice: Send + Suspendable {
         false
     }
 
+    /// Whether the only guest addresses this device gets from the driver are those of its queues
+    /// and descriptors, in which case the transport can put it behind a virtio-iommu by
+    /// translating them.
+    fn accesses_memory_through_queues(&self) -> bool {
+        true
+    }
+
     /// The set of feature bits that this device supports in addition to the base features.
     fn features(&self) -> u64 {
         0
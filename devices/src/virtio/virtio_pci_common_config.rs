@@ -5,6 +5,8 @@
 use std::convert::TryInto;
 
 use base::warn;
+use serde::Deserialize;
+use serde::Serialize;
 use vm_memory::GuestAddress;
 
 use super::*;
@@ -31,6 +33,7 @@ use super::*;
 /// le64 queue_desc;                // read-write
 /// le64 queue_avail;               // read-write
 /// le64 queue_used;                // read-write
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct VirtioPciCommonConfig {
     pub driver_status: u8,
     pub config_generation: u8,
@@ -38,6 +41,9 @@ pub struct VirtioPciCommonConfig {
     pub driver_feature_select: u32,
     pub queue_select: u16,
     pub msix_config: u16,
+    /// Features offered by the transport on behalf of the device, which are hidden from the
+    /// device when the driver acknowledges them.
+    pub transport_features: u64,
 }
 
 impl VirtioPciCommonConfig {
@@ -153,7 +159,8 @@ impl VirtioPciCommonConfig {
                 // Only 64 bits of features (2 pages) are defined for now, so limit
                 // device_feature_select to avoid shifting by 64 or more bits.
                 if self.device_feature_select < 2 {
-                    (device.features() >> (self.device_feature_select * 32)) as u32
+                    ((device.features() | self.transport_features)
+                        >> (self.device_feature_select * 32)) as u32
                 } else {
                     0
                 }
@@ -187,7 +194,7 @@ impl VirtioPciCommonConfig {
             0x0c => {
                 if self.driver_feature_select < 2 {
                     let features: u64 = (value as u64) << (self.driver_feature_select * 32);
-                    device.ack_features(features);
+                    device.ack_features(features & !self.transport_features);
                     for queue in queues.iter_mut() {
                         queue.ack_features(features);
                     }
@@ -286,6 +293,7 @@ mod tests {
             driver_feature_select: 0x0,
             queue_select: 0xff,
             msix_config: 0x00,
+            transport_features: 0,
         };
 
         let dev = &mut DummyDevice(DeviceType::Rng) as &mut dyn VirtioDevice;
@@ -326,4 +334,31 @@ mod tests {
         assert_eq!(read_back[0], 0xaa);
         assert_eq!(read_back[1], 0x55);
     }
+
+    #[test]
+    fn transport_features() {
+        let mut regs = VirtioPciCommonConfig {
+            driver_status: 0,
+            config_generation: 0,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            queue_select: 0,
+            msix_config: 0,
+            transport_features: 1 << 33,
+        };
+
+        let dev = &mut DummyDevice(DeviceType::Rng) as &mut dyn VirtioDevice;
+        let mut queues = Vec::new();
+
+        // The features of the transport are offered along with those of the device.
+        let mut read_back = [0u8; 4];
+        regs.read(0x04, &mut read_back, &mut queues, dev);
+        assert_eq!(u32::from_le_bytes(read_back), DUMMY_FEATURES as u32);
+        regs.write(0x00, &[1, 0, 0, 0], &mut queues, dev);
+        regs.read(0x04, &mut read_back, &mut queues, dev);
+        assert_eq!(
+            u32::from_le_bytes(read_back),
+            (DUMMY_FEATURES >> 32) as u32 | 1 << 1
+        );
+    }
 }
@@ -25,6 +25,8 @@ use libc::ERANGE;
 use resources::Alloc;
 use resources::AllocOptions;
 use resources::SystemAllocator;
+use serde::Deserialize;
+use serde::Serialize;
 use sync::Mutex;
 use virtio_sys::virtio_config::VIRTIO_CONFIG_S_ACKNOWLEDGE;
 use virtio_sys::virtio_config::VIRTIO_CONFIG_S_DRIVER;
@@ -44,6 +46,7 @@ use super::*;
 use crate::pci::BarRange;
 use crate::pci::MsixCap;
 use crate::pci::MsixConfig;
+use crate::pci::MsixConfigSnapshot;
 use crate::pci::PciAddress;
 use crate::pci::PciBarConfiguration;
 use crate::pci::PciBarIndex;
@@ -53,6 +56,7 @@ use crate::pci::PciCapability;
 use crate::pci::PciCapabilityID;
 use crate::pci::PciClassCode;
 use crate::pci::PciConfiguration;
+use crate::pci::PciConfigurationSnapshot;
 use crate::pci::PciDevice;
 use crate::pci::PciDeviceError;
 use crate::pci::PciDisplaySubclass;
@@ -291,12 +295,27 @@ pub struct VirtioPciDevice {
     common_config: VirtioPciCommonConfig,
 
     iommu: Option<Arc<Mutex<IpcMemoryMapper>>>,
+    // Whether the transport translates the queues of the device with `iommu`, on behalf of a
+    // device that doesn't support it itself.
+    access_platform: bool,
 
     // A tube that is present if the device has shared memory regions, and
     // is used to map/unmap files into the shared memory region.
     shared_memory_tube: Option<Tube>,
 }
 
+#[derive(Serialize, Deserialize)]
+struct VirtioPciDeviceSnapshot {
+    config_regs: PciConfigurationSnapshot,
+    inner_device: String,
+    device_activated: bool,
+    common_config: VirtioPciCommonConfig,
+    // Queues as configured by the driver. The progress of the queues of an activated device is
+    // part of the snapshot of the device itself.
+    queues: Vec<QueueSnapshot>,
+    msix_config: MsixConfigSnapshot,
+}
+
 impl VirtioPciDevice {
     /// Constructs a new PCI transport for the given virtio device.
     pub fn new(
@@ -380,12 +399,29 @@ impl VirtioPciDevice {
                 driver_feature_select: 0,
                 queue_select: 0,
                 msix_config: VIRTIO_MSI_NO_VECTOR,
+                transport_features: 0,
             },
             iommu: None,
+            access_platform: false,
             shared_memory_tube,
         })
     }
 
+    /// Puts the device behind the virtio-iommu even though it accesses guest memory by guest
+    /// physical address: `VIRTIO_F_ACCESS_PLATFORM` is offered to the driver on its behalf, and
+    /// the descriptors of its queues are translated by the transport.
+    ///
+    /// Does nothing for devices that translate addresses themselves, or that get guest addresses
+    /// from their driver in other ways than descriptors.
+    pub fn set_access_platform(&mut self) {
+        if self.device.supports_iommu() || !self.device.accesses_memory_through_queues() {
+            return;
+        }
+        self.access_platform = true;
+        self.common_config.transport_features =
+            (1 << VIRTIO_F_ACCESS_PLATFORM) & !self.device.features();
+    }
+
     fn is_driver_ready(&self) -> bool {
         let ready_bits = (VIRTIO_CONFIG_S_ACKNOWLEDGE
             | VIRTIO_CONFIG_S_DRIVER
@@ -503,7 +539,7 @@ impl VirtioPciDevice {
         match self.clone_queue_evts() {
             Ok(queue_evts) => {
                 // Use ready queues and their events.
-                let (queues, queue_evts) = self
+                let (mut queues, queue_evts): (Vec<Queue>, Vec<Event>) = self
                     .queues
                     .clone()
                     .into_iter()
@@ -511,6 +547,14 @@ impl VirtioPciDevice {
                     .filter(|(q, _)| q.ready())
                     .unzip();
 
+                if self.access_platform {
+                    for queue in queues.iter_mut() {
+                        queue.export_memory(&mem).with_context(|| {
+                            format!("{} failed to export queue memory", self.debug_label())
+                        })?;
+                    }
+                }
+
                 if let Some(iommu) = &self.iommu {
                     self.device.set_iommu(iommu);
                 }
@@ -532,7 +576,7 @@ impl VirtioPciDevice {
 
 impl PciDevice for VirtioPciDevice {
     fn supports_iommu(&self) -> bool {
-        self.device.supports_iommu()
+        self.access_platform || self.device.supports_iommu()
     }
 
     fn debug_label(&self) -> String {
@@ -944,11 +988,81 @@ impl Suspendable for VirtioPciDevice {
     }
 
     fn snapshot(&self) -> anyhow::Result<String> {
-        self.device.snapshot()
+        serde_json::to_string(&VirtioPciDeviceSnapshot {
+            config_regs: self.config_regs.snapshot(),
+            inner_device: self.device.snapshot()?,
+            device_activated: self.device_activated,
+            common_config: self.common_config.clone(),
+            queues: self.queues.iter().map(Queue::snapshot).collect(),
+            msix_config: self.msix_config.lock().snapshot(),
+        })
+        .with_context(|| format!("failed to serialize {} snapshot", self.debug_label()))
     }
 
     fn restore(&mut self, data: &str) -> anyhow::Result<()> {
-        self.device.restore(data)
+        let snapshot: VirtioPciDeviceSnapshot = serde_json::from_str(data)
+            .with_context(|| format!("failed to deserialize {} snapshot", self.debug_label()))?;
+        if snapshot.queues.len() != self.queues.len() {
+            bail!(
+                "snapshot has {} queues but {} has {}",
+                snapshot.queues.len(),
+                self.debug_label(),
+                self.queues.len()
+            );
+        }
+
+        // The BARs are mapped on the buses and the notification ioevents are registered when the
+        // VM is created, so they have to be at the same place as when the snapshot was taken.
+        let bar_addrs: Vec<(PciBarIndex, u64)> = self
+            .config_regs
+            .get_bars()
+            .map(|bar| (bar.bar_index(), bar.address()))
+            .collect();
+        let current_config_regs = self.config_regs.snapshot();
+        self.config_regs
+            .restore(&snapshot.config_regs)
+            .context("failed to restore the PCI configuration space")?;
+        for (bar_index, addr) in bar_addrs {
+            let restored_addr = self.config_regs.get_bar_addr(bar_index);
+            if restored_addr != addr {
+                // Leave the device as it was rather than with a configuration space that doesn't
+                // match its mappings.
+                self.config_regs
+                    .restore(&current_config_regs)
+                    .context("failed to roll back the PCI configuration space")?;
+                bail!(
+                    "{} BAR {} is at {:#x} in the snapshot instead of {:#x}",
+                    self.debug_label(),
+                    bar_index,
+                    restored_addr,
+                    addr
+                );
+            }
+        }
+
+        if self.device_activated {
+            // Start over from the state of a device that the driver hasn't set up.
+            self.device.reset();
+            self.device_activated = false;
+        }
+
+        self.msix_config
+            .lock()
+            .restore(&snapshot.msix_config)
+            .context("failed to restore MSI-X")?;
+        self.common_config = snapshot.common_config;
+        self.queues = snapshot.queues.iter().map(Queue::restore).collect();
+        self.device.restore(&snapshot.inner_device)?;
+
+        if snapshot.device_activated {
+            if let Some(iommu) = &self.iommu {
+                for q in &mut self.queues {
+                    q.set_iommu(Arc::clone(iommu));
+                }
+            }
+            self.activate()?;
+        }
+        Ok(())
     }
 }
 
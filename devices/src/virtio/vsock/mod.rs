@@ -4,15 +4,14 @@
 
 //! This module implements the virtio vsock device.
 //!
-//! Currently, this is only implemented for Windows.
-//! For Linux, please use the vhost-vsock device, which delegates the vsock
-//! implementation to the kernel.
+//! On Windows, guest connections are forwarded to named pipes. On Linux, this is a "hybrid" vsock
+//! device that forwards guest connections to unix domain sockets on the host, which is useful when
+//! the host kernel does not provide vhost-vsock. When vhost-vsock is available, the vhost device
+//! delegates the vsock implementation to the kernel instead.
 
-#![cfg(windows)]
+mod protocol;
+mod sys;
 
-pub mod protocol;
-pub mod vsock;
-
-pub(crate) use protocol::*;
-pub use vsock::Vsock;
-pub use vsock::VsockError;
+pub use protocol::*;
+pub use sys::Vsock;
+pub use sys::VsockError;
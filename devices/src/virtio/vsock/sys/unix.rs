@@ -0,0 +1,1239 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Hybrid vsock device that forwards guest vsock streams to unix domain sockets on the host.
+//!
+//! The protocol on the host side follows the one used by Firecracker and cloud-hypervisor:
+//!
+//! * A guest connecting to host port `P` is connected to the unix socket at `<uds_path>_<P>`.
+//! * A host process connects to the unix socket at `<uds_path>` and writes `CONNECT <P>\n` to
+//!   request a connection to guest port `P`. Once the guest accepts the connection, the device
+//!   replies with `OK <host_port>\n`, where `host_port` is the source port the guest sees, and the
+//!   stream carries the connection data from then on.
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::mem::size_of;
+use std::net::Shutdown;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+
+use base::error;
+use base::info;
+use base::warn;
+use base::AsRawDescriptor;
+use base::Event;
+use base::EventToken;
+use base::EventType;
+use base::RawDescriptor;
+use base::WaitContext;
+use data_model::DataInit;
+use data_model::Le32;
+use data_model::Le64;
+use remain::sorted;
+use thiserror::Error as ThisError;
+use vm_memory::GuestMemory;
+
+use crate::virtio::copy_config;
+use crate::virtio::virtio_vsock_config;
+use crate::virtio::virtio_vsock_hdr;
+use crate::virtio::vsock_op;
+use crate::virtio::DeviceType;
+use crate::virtio::Interrupt;
+use crate::virtio::Queue;
+use crate::virtio::Reader;
+use crate::virtio::SignalableInterrupt;
+use crate::virtio::VirtioDevice;
+use crate::virtio::Writer;
+use crate::virtio::TYPE_STREAM_SOCKET;
+use crate::Suspendable;
+
+const QUEUE_SIZE: u16 = 256;
+const NUM_QUEUES: usize = 3;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+
+// The well-known CID of the host.
+const HOST_CID: u64 = 2;
+
+// Receive buffer space advertised to the guest for each connection.
+const CONNECTION_BUF_ALLOC: u32 = 256 * 1024;
+
+// Maximum payload of a single packet sent to the guest.
+const MAX_PACKET_PAYLOAD: usize = 4096;
+
+// Maximum number of packets waiting for rx buffers from the guest. While the backlog is full, the
+// device stops reading from host sockets and processing the tx queue.
+const MAX_RX_BACKLOG: usize = 256;
+
+// Maximum length of the `CONNECT <port>\n` line sent by host-initiated connections.
+const MAX_CONNECT_LINE: usize = 32;
+
+// Local ports assigned to host-initiated connections start here to stay clear of the ports that
+// guest services usually listen on.
+const FIRST_HOST_PORT: u32 = 1 << 30;
+
+// Flags of the VIRTIO_VSOCK_OP_SHUTDOWN operation.
+const VIRTIO_VSOCK_SHUTDOWN_RCV: u32 = 1;
+const VIRTIO_VSOCK_SHUTDOWN_SEND: u32 = 2;
+
+#[sorted]
+#[derive(ThisError, Debug)]
+pub enum VsockError {
+    #[error("failed to bind vsock socket {0}: {1}")]
+    BindSocket(PathBuf, io::Error),
+    #[error("failed to clone vsock listener: {0}")]
+    CloneListener(io::Error),
+    #[error("failed to create kill event: {0}")]
+    CreateKillEvent(base::Error),
+    #[error("failed to create wait context: {0}")]
+    CreateWaitContext(base::Error),
+    #[error("failed to remove stale vsock socket {0}: {1}")]
+    RemoveSocket(PathBuf, io::Error),
+    #[error("failed to set vsock socket nonblocking: {0}")]
+    SetNonBlocking(io::Error),
+    #[error("vsock socket path {0} exists and is not a socket")]
+    SocketPathInUse(PathBuf),
+    #[error("failed to spawn vsock worker thread: {0}")]
+    SpawnThread(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, VsockError>;
+
+/// Identifies a connection by its host (local) and guest (peer) ports.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+struct PortPair {
+    host: u32,
+    guest: u32,
+}
+
+struct Connection {
+    id: u32,
+    stream: UnixStream,
+    /// Set once both sides have agreed on the connection.
+    established: bool,
+    /// Events currently registered with the wait context.
+    events: EventType,
+    /// The host socket reported a hangup and has been removed from the wait context.
+    hungup: bool,
+    /// The host side will not send any more data.
+    host_eof: bool,
+    /// Shutdown flags received from the guest.
+    guest_shutdown: u32,
+    /// The write side of the host socket has been shut down.
+    host_write_shutdown: bool,
+    /// Guest data that could not be written to the host socket yet.
+    pending_write: Vec<u8>,
+    /// Bytes of guest data forwarded to the host socket.
+    fwd_cnt: u32,
+    /// `fwd_cnt` last reported to the guest.
+    last_fwd_cnt_sent: u32,
+    /// Bytes of host data sent to the guest.
+    rx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+}
+
+impl Connection {
+    fn new(id: u32, stream: UnixStream) -> Connection {
+        Connection {
+            id,
+            stream,
+            established: false,
+            events: EventType::None,
+            hungup: false,
+            host_eof: false,
+            guest_shutdown: 0,
+            host_write_shutdown: false,
+            pending_write: Vec::new(),
+            fwd_cnt: 0,
+            last_fwd_cnt_sent: 0,
+            rx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+        }
+    }
+
+    /// Number of bytes the guest is currently able to receive on this connection.
+    fn peer_credit(&self) -> usize {
+        let in_flight = self.rx_cnt.wrapping_sub(self.peer_fwd_cnt);
+        self.peer_buf_alloc.saturating_sub(in_flight) as usize
+    }
+
+    fn can_read(&self) -> bool {
+        self.established
+            && !self.host_eof
+            && self.guest_shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV == 0
+            && self.peer_credit() > 0
+    }
+}
+
+/// A host-initiated connection that has not sent its `CONNECT` line yet.
+struct PendingConnection {
+    stream: UnixStream,
+    line: Vec<u8>,
+}
+
+#[derive(EventToken)]
+enum Token {
+    RxQueue,
+    TxQueue,
+    EventQueue,
+    InterruptResample,
+    Kill,
+    Listener,
+    Pending { id: u32 },
+    Connection { id: u32 },
+}
+
+struct Worker {
+    mem: GuestMemory,
+    interrupt: Interrupt,
+    rx_queue: Queue,
+    tx_queue: Queue,
+    guest_cid: u64,
+    uds_path: PathBuf,
+    listener: UnixListener,
+    wait_ctx: WaitContext<Token>,
+    connections: HashMap<PortPair, Connection>,
+    connection_ports: HashMap<u32, PortPair>,
+    pending: HashMap<u32, PendingConnection>,
+    next_id: u32,
+    next_host_port: u32,
+    rx_backlog: VecDeque<(virtio_vsock_hdr, Vec<u8>)>,
+}
+
+impl Worker {
+    fn alloc_id(&mut self) -> u32 {
+        loop {
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+            if !self.pending.contains_key(&id) && !self.connection_ports.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    fn alloc_host_port(&mut self) -> u32 {
+        loop {
+            let port = self.next_host_port;
+            self.next_host_port = port.checked_add(1).unwrap_or(FIRST_HOST_PORT);
+            if !self.connections.keys().any(|p| p.host == port) {
+                return port;
+            }
+        }
+    }
+
+    /// Queues a packet for the guest on the connection identified by `ports`.
+    fn send_to_guest(&mut self, ports: PortPair, op: u16, flags: u32, data: Vec<u8>) {
+        let (buf_alloc, fwd_cnt) = match self.connections.get_mut(&ports) {
+            Some(conn) => {
+                conn.last_fwd_cnt_sent = conn.fwd_cnt;
+                if op == vsock_op::VIRTIO_VSOCK_OP_RW {
+                    conn.rx_cnt = conn.rx_cnt.wrapping_add(data.len() as u32);
+                }
+                (CONNECTION_BUF_ALLOC, conn.fwd_cnt)
+            }
+            None => (0, 0),
+        };
+        let hdr = virtio_vsock_hdr {
+            src_cid: Le64::from(HOST_CID),
+            dst_cid: Le64::from(self.guest_cid),
+            src_port: Le32::from(ports.host),
+            dst_port: Le32::from(ports.guest),
+            len: Le32::from(data.len() as u32),
+            r#type: TYPE_STREAM_SOCKET.into(),
+            op: op.into(),
+            flags: Le32::from(flags),
+            buf_alloc: Le32::from(buf_alloc),
+            fwd_cnt: Le32::from(fwd_cnt),
+        };
+        self.rx_backlog.push_back((hdr, data));
+    }
+
+    fn send_reset(&mut self, ports: PortPair) {
+        self.send_to_guest(ports, vsock_op::VIRTIO_VSOCK_OP_RST, 0, Vec::new());
+    }
+
+    fn remove_connection(&mut self, ports: PortPair) {
+        if let Some(conn) = self.connections.remove(&ports) {
+            self.connection_ports.remove(&conn.id);
+            if !conn.hungup {
+                if let Err(e) = self.wait_ctx.delete(&conn.stream) {
+                    error!("vsock: failed to remove connection from WaitContext: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Tears down the connection on both sides.
+    fn reset_connection(&mut self, ports: PortPair) {
+        self.remove_connection(ports);
+        self.send_reset(ports);
+    }
+
+    /// Writes the data to the guest rx queue until it runs out of buffers.
+    fn process_rx_backlog(&mut self) {
+        let mut needs_interrupt = false;
+        while !self.rx_backlog.is_empty() {
+            let avail_desc = match self.rx_queue.pop(&self.mem) {
+                Some(d) => d,
+                None => break,
+            };
+            let index = avail_desc.index;
+            let (hdr, data) = self.rx_backlog.pop_front().unwrap();
+
+            let written = match Writer::new(self.mem.clone(), avail_desc) {
+                Ok(mut writer) => {
+                    if writer.available_bytes() < size_of::<virtio_vsock_hdr>() + data.len() {
+                        error!("vsock: rx buffer too small for packet, dropping it");
+                        0
+                    } else if let Err(e) =
+                        writer.write_obj(hdr).and_then(|_| writer.write_all(&data))
+                    {
+                        error!("vsock: failed to write packet to the guest: {}", e);
+                        0
+                    } else {
+                        writer.bytes_written()
+                    }
+                }
+                Err(e) => {
+                    error!("vsock: failed to create Writer: {}", e);
+                    0
+                }
+            };
+            self.rx_queue.add_used(&self.mem, index, written as u32);
+            needs_interrupt = true;
+        }
+        if needs_interrupt {
+            self.rx_queue.trigger_interrupt(&self.mem, &self.interrupt);
+        }
+    }
+
+    /// Handles packets sent by the guest. Returns false if processing stopped because the rx
+    /// backlog is full.
+    fn process_tx_queue(&mut self) -> bool {
+        let mut needs_interrupt = false;
+        let mut complete = true;
+        loop {
+            if self.rx_backlog.len() >= MAX_RX_BACKLOG {
+                complete = false;
+                break;
+            }
+            let avail_desc = match self.tx_queue.pop(&self.mem) {
+                Some(d) => d,
+                None => break,
+            };
+            let index = avail_desc.index;
+            match Reader::new(self.mem.clone(), avail_desc) {
+                Ok(mut reader) => {
+                    while reader.available_bytes() >= size_of::<virtio_vsock_hdr>() {
+                        if let Err(e) = self.process_tx_packet(&mut reader) {
+                            error!("vsock: failed to read packet from the guest: {}", e);
+                            break;
+                        }
+                    }
+                }
+                Err(e) => error!("vsock: failed to create Reader: {}", e),
+            }
+            self.tx_queue.add_used(&self.mem, index, 0);
+            needs_interrupt = true;
+        }
+        if needs_interrupt {
+            self.tx_queue.trigger_interrupt(&self.mem, &self.interrupt);
+        }
+        complete
+    }
+
+    fn process_tx_packet(&mut self, reader: &mut Reader) -> io::Result<()> {
+        let hdr: virtio_vsock_hdr = reader.read_obj()?;
+        let ports = PortPair {
+            host: hdr.dst_port.to_native(),
+            guest: hdr.src_port.to_native(),
+        };
+        let op = hdr.op.to_native();
+
+        // The payload must fit in the descriptor chain and in the receive buffer space the device
+        // advertised. Check it before allocating memory for it.
+        let len = hdr.len.to_native() as usize;
+        if len > reader.available_bytes() || len > CONNECTION_BUF_ALLOC as usize {
+            warn!(
+                "vsock: dropping packet with invalid length {} from the guest",
+                len
+            );
+            // The rest of the chain can't be parsed anymore.
+            reader.consume(reader.available_bytes());
+            if op != vsock_op::VIRTIO_VSOCK_OP_RST {
+                self.reset_connection(ports);
+            }
+            return Ok(());
+        }
+        let mut data = vec![0; len];
+        reader.read_exact(&mut data)?;
+        if hdr.src_cid.to_native() != self.guest_cid
+            || hdr.dst_cid.to_native() != HOST_CID
+            || hdr.r#type.to_native() != TYPE_STREAM_SOCKET
+        {
+            warn!(
+                "vsock: dropping unsupported packet from cid {} to cid {}",
+                hdr.src_cid.to_native(),
+                hdr.dst_cid.to_native()
+            );
+            if op != vsock_op::VIRTIO_VSOCK_OP_RST {
+                self.send_reset(ports);
+            }
+            return Ok(());
+        }
+
+        // Every packet carries the current receive credit of the guest.
+        if let Some(conn) = self.connections.get_mut(&ports) {
+            conn.peer_buf_alloc = hdr.buf_alloc.to_native();
+            conn.peer_fwd_cnt = hdr.fwd_cnt.to_native();
+        }
+
+        match op {
+            vsock_op::VIRTIO_VSOCK_OP_REQUEST => self.connect_to_host(ports, &hdr),
+            vsock_op::VIRTIO_VSOCK_OP_RESPONSE => self.handle_response(ports),
+            vsock_op::VIRTIO_VSOCK_OP_RST => self.remove_connection(ports),
+            vsock_op::VIRTIO_VSOCK_OP_SHUTDOWN => {
+                match self.connections.get_mut(&ports) {
+                    Some(conn) => conn.guest_shutdown |= hdr.flags.to_native(),
+                    None => self.send_reset(ports),
+                }
+                self.finish_shutdown(ports);
+            }
+            vsock_op::VIRTIO_VSOCK_OP_RW => self.forward_to_host(ports, data),
+            vsock_op::VIRTIO_VSOCK_OP_CREDIT_UPDATE => {
+                if !self.connections.contains_key(&ports) {
+                    self.send_reset(ports);
+                }
+            }
+            vsock_op::VIRTIO_VSOCK_OP_CREDIT_REQUEST => {
+                if self.connections.contains_key(&ports) {
+                    self.send_to_guest(
+                        ports,
+                        vsock_op::VIRTIO_VSOCK_OP_CREDIT_UPDATE,
+                        0,
+                        Vec::new(),
+                    );
+                } else {
+                    self.send_reset(ports);
+                }
+            }
+            _ => {
+                warn!("vsock: unknown operation {} from the guest", op);
+                self.reset_connection(ports);
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a connection request from the guest by connecting to `<uds_path>_<port>`.
+    fn connect_to_host(&mut self, ports: PortPair, hdr: &virtio_vsock_hdr) {
+        if self.connections.contains_key(&ports) {
+            warn!("vsock: guest reused the ports of connection {:?}", ports);
+            self.reset_connection(ports);
+            return;
+        }
+
+        let mut path = OsString::from(self.uds_path.as_os_str());
+        path.push(format!("_{}", ports.host));
+        let stream = match UnixStream::connect(&path).and_then(|s| {
+            s.set_nonblocking(true)?;
+            Ok(s)
+        }) {
+            Ok(s) => s,
+            Err(e) => {
+                info!(
+                    "vsock: failed to connect to {}: {}",
+                    Path::new(&path).display(),
+                    e
+                );
+                self.send_reset(ports);
+                return;
+            }
+        };
+
+        let id = self.alloc_id();
+        if let Err(e) =
+            self.wait_ctx
+                .add_for_event(&stream, EventType::Read, Token::Connection { id })
+        {
+            error!("vsock: failed to add connection to WaitContext: {}", e);
+            self.send_reset(ports);
+            return;
+        }
+        let mut conn = Connection::new(id, stream);
+        conn.established = true;
+        conn.events = EventType::Read;
+        conn.peer_buf_alloc = hdr.buf_alloc.to_native();
+        conn.peer_fwd_cnt = hdr.fwd_cnt.to_native();
+        self.connections.insert(ports, conn);
+        self.connection_ports.insert(id, ports);
+        self.send_to_guest(ports, vsock_op::VIRTIO_VSOCK_OP_RESPONSE, 0, Vec::new());
+    }
+
+    /// Handles the guest accepting a host-initiated connection.
+    fn handle_response(&mut self, ports: PortPair) {
+        let conn = match self.connections.get_mut(&ports) {
+            Some(conn) if !conn.established => conn,
+            _ => {
+                self.reset_connection(ports);
+                return;
+            }
+        };
+        conn.established = true;
+        // Nothing else has been written to the stream yet, so the short reply fits in the socket
+        // buffer.
+        let reply = format!("OK {}\n", ports.host);
+        if let Err(e) = conn.stream.write_all(reply.as_bytes()) {
+            info!("vsock: failed to acknowledge host connection: {}", e);
+            self.reset_connection(ports);
+        }
+    }
+
+    /// Forwards data sent by the guest to the host socket.
+    fn forward_to_host(&mut self, ports: PortPair, data: Vec<u8>) {
+        let conn = match self.connections.get_mut(&ports) {
+            Some(conn)
+                if conn.established && conn.guest_shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND == 0 =>
+            {
+                conn
+            }
+            _ => {
+                self.reset_connection(ports);
+                return;
+            }
+        };
+        if conn.pending_write.len() + data.len() > CONNECTION_BUF_ALLOC as usize {
+            warn!("vsock: guest exceeded its credit on {:?}", ports);
+            self.reset_connection(ports);
+            return;
+        }
+        conn.pending_write.extend_from_slice(&data);
+        self.flush_to_host(ports);
+    }
+
+    /// Writes as much pending guest data as possible to the host socket.
+    fn flush_to_host(&mut self, ports: PortPair) {
+        let conn = match self.connections.get_mut(&ports) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let mut written = 0;
+        let mut failed = false;
+        while written < conn.pending_write.len() {
+            match conn.stream.write(&conn.pending_write[written..]) {
+                Ok(0) => {
+                    failed = true;
+                    break;
+                }
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    info!("vsock: failed to write to host socket: {}", e);
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        if failed {
+            self.reset_connection(ports);
+            return;
+        }
+        conn.pending_write.drain(..written);
+        conn.fwd_cnt = conn.fwd_cnt.wrapping_add(written as u32);
+        if conn.fwd_cnt.wrapping_sub(conn.last_fwd_cnt_sent) >= CONNECTION_BUF_ALLOC / 4 {
+            self.send_to_guest(
+                ports,
+                vsock_op::VIRTIO_VSOCK_OP_CREDIT_UPDATE,
+                0,
+                Vec::new(),
+            );
+        }
+        self.finish_shutdown(ports);
+    }
+
+    /// Applies the shutdown requested by the guest once all of its data reached the host.
+    fn finish_shutdown(&mut self, ports: PortPair) {
+        let conn = match self.connections.get_mut(&ports) {
+            Some(conn) if conn.pending_write.is_empty() => conn,
+            _ => return,
+        };
+        if conn.guest_shutdown == VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND {
+            // The guest closed the socket and waits for the RST that completes the shutdown.
+            self.reset_connection(ports);
+        } else if conn.guest_shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND != 0 && !conn.host_write_shutdown
+        {
+            conn.host_write_shutdown = true;
+            if let Err(e) = conn.stream.shutdown(Shutdown::Write) {
+                info!("vsock: failed to shut down host socket: {}", e);
+                self.reset_connection(ports);
+            }
+        }
+    }
+
+    /// Reads data from the host socket and sends it to the guest, within the guest's credit.
+    fn read_from_host(&mut self, ports: PortPair) {
+        let conn = match self.connections.get_mut(&ports) {
+            Some(conn) => conn,
+            None => return,
+        };
+        if !conn.can_read() {
+            return;
+        }
+        let mut buf = vec![0; min(conn.peer_credit(), MAX_PACKET_PAYLOAD)];
+        match conn.stream.read(&mut buf) {
+            Ok(0) => {
+                conn.host_eof = true;
+                self.send_to_guest(
+                    ports,
+                    vsock_op::VIRTIO_VSOCK_OP_SHUTDOWN,
+                    VIRTIO_VSOCK_SHUTDOWN_SEND,
+                    Vec::new(),
+                );
+            }
+            Ok(n) => {
+                buf.truncate(n);
+                self.send_to_guest(ports, vsock_op::VIRTIO_VSOCK_OP_RW, 0, buf);
+            }
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                info!("vsock: failed to read from host socket: {}", e);
+                self.reset_connection(ports);
+            }
+        }
+    }
+
+    fn handle_connection_event(&mut self, id: u32, readable: bool, writable: bool, hungup: bool) {
+        let ports = match self.connection_ports.get(&id) {
+            Some(p) => *p,
+            None => return,
+        };
+        if writable {
+            self.flush_to_host(ports);
+        }
+        if readable || hungup {
+            self.read_from_host(ports);
+        }
+        if !hungup {
+            return;
+        }
+        let conn = match self.connections.get_mut(&ports) {
+            Some(conn) => conn,
+            None => return,
+        };
+        if !conn.established {
+            // The host process gave up before the guest accepted the connection.
+            self.reset_connection(ports);
+            return;
+        }
+        // A hung up socket stays readable until its data is consumed, so stop waiting on it to
+        // avoid spinning; `update_connections` keeps reading it as the guest grants credit.
+        conn.hungup = true;
+        if let Err(e) = self.wait_ctx.delete(&conn.stream) {
+            error!("vsock: failed to remove connection from WaitContext: {}", e);
+        }
+    }
+
+    /// Adjusts the events each connection waits for based on the guest's credit and the rx
+    /// backlog.
+    fn update_connections(&mut self) {
+        let ports: Vec<PortPair> = self.connections.keys().copied().collect();
+        for ports in ports {
+            let backlog_full = self.rx_backlog.len() >= MAX_RX_BACKLOG;
+            let conn = match self.connections.get_mut(&ports) {
+                Some(conn) => conn,
+                None => continue,
+            };
+            let read = conn.can_read() && !backlog_full;
+            if conn.hungup {
+                if read {
+                    self.read_from_host(ports);
+                }
+                continue;
+            }
+            let events = match (read, !conn.pending_write.is_empty()) {
+                (true, true) => EventType::ReadWrite,
+                (true, false) => EventType::Read,
+                (false, true) => EventType::Write,
+                (false, false) => EventType::None,
+            };
+            if events != conn.events {
+                if let Err(e) =
+                    self.wait_ctx
+                        .modify(&conn.stream, events, Token::Connection { id: conn.id })
+                {
+                    error!("vsock: failed to modify connection events: {}", e);
+                    self.reset_connection(ports);
+                    continue;
+                }
+                conn.events = events;
+            }
+        }
+    }
+
+    /// Accepts connections made by host processes to the main socket.
+    fn accept_host_connections(&mut self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((s, _)) => s,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("vsock: failed to accept host connection: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = stream.set_nonblocking(true) {
+                error!("vsock: failed to set host connection nonblocking: {}", e);
+                continue;
+            }
+            let id = self.alloc_id();
+            if let Err(e) = self.wait_ctx.add(&stream, Token::Pending { id }) {
+                error!("vsock: failed to add host connection to WaitContext: {}", e);
+                continue;
+            }
+            self.pending.insert(
+                id,
+                PendingConnection {
+                    stream,
+                    line: Vec::new(),
+                },
+            );
+        }
+    }
+
+    /// Reads the `CONNECT <port>\n` line of a host-initiated connection and forwards the request
+    /// to the guest once it is complete.
+    fn read_connect_line(&mut self, id: u32) {
+        let pending = match self.pending.get_mut(&id) {
+            Some(p) => p,
+            None => return,
+        };
+        // Read a byte at a time so that no connection data following the line is consumed.
+        let mut complete = false;
+        let mut close = false;
+        let mut byte = [0u8];
+        loop {
+            match pending.stream.read(&mut byte) {
+                Ok(0) => {
+                    close = true;
+                    break;
+                }
+                Ok(_) if byte[0] == b'\n' => {
+                    complete = true;
+                    break;
+                }
+                Ok(_) => {
+                    pending.line.push(byte[0]);
+                    if pending.line.len() >= MAX_CONNECT_LINE {
+                        close = true;
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => {
+                    close = true;
+                    break;
+                }
+            }
+        }
+        if !complete && !close {
+            return;
+        }
+
+        let pending = self.pending.remove(&id).unwrap();
+        if close {
+            let _ = self.wait_ctx.delete(&pending.stream);
+            return;
+        }
+        let guest_port = match std::str::from_utf8(&pending.line)
+            .ok()
+            .and_then(|l| l.trim().strip_prefix("CONNECT "))
+            .and_then(|p| p.trim().parse::<u32>().ok())
+        {
+            Some(p) => p,
+            None => {
+                warn!("vsock: invalid host connection request");
+                let _ = self.wait_ctx.delete(&pending.stream);
+                return;
+            }
+        };
+
+        // Wait for nothing until the guest accepts the connection; a hangup is still reported.
+        if let Err(e) =
+            self.wait_ctx
+                .modify(&pending.stream, EventType::None, Token::Connection { id })
+        {
+            error!("vsock: failed to modify host connection events: {}", e);
+            let _ = self.wait_ctx.delete(&pending.stream);
+            return;
+        }
+        let ports = PortPair {
+            host: self.alloc_host_port(),
+            guest: guest_port,
+        };
+        self.connections
+            .insert(ports, Connection::new(id, pending.stream));
+        self.connection_ports.insert(id, ports);
+        self.send_to_guest(ports, vsock_op::VIRTIO_VSOCK_OP_REQUEST, 0, Vec::new());
+    }
+
+    fn run(
+        &mut self,
+        rx_queue_evt: Event,
+        tx_queue_evt: Event,
+        event_queue_evt: Event,
+        kill_evt: Event,
+    ) {
+        if let Err(e) = self.wait_ctx.add_many(&[
+            (&rx_queue_evt, Token::RxQueue),
+            (&tx_queue_evt, Token::TxQueue),
+            (&event_queue_evt, Token::EventQueue),
+            (&kill_evt, Token::Kill),
+            (&self.listener, Token::Listener),
+        ]) {
+            error!("vsock: failed to add events to WaitContext: {}", e);
+            return;
+        }
+        if let Some(resample_evt) = self.interrupt.get_resample_evt() {
+            if self
+                .wait_ctx
+                .add(resample_evt, Token::InterruptResample)
+                .is_err()
+            {
+                error!("vsock: failed adding resample event to WaitContext.");
+                return;
+            }
+        }
+
+        let mut tx_throttled = false;
+        'wait: loop {
+            let events = match self.wait_ctx.wait() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("vsock: failed polling for events: {}", e);
+                    break;
+                }
+            };
+
+            for event in events.iter() {
+                match event.token {
+                    Token::RxQueue => {
+                        if let Err(e) = rx_queue_evt.wait() {
+                            error!("vsock: failed reading rx queue Event: {}", e);
+                            break 'wait;
+                        }
+                    }
+                    Token::TxQueue => {
+                        if let Err(e) = tx_queue_evt.wait() {
+                            error!("vsock: failed reading tx queue Event: {}", e);
+                            break 'wait;
+                        }
+                        tx_throttled = !self.process_tx_queue();
+                    }
+                    Token::EventQueue => {
+                        // The device never sends transport events, so the buffers are left in
+                        // the queue.
+                        if let Err(e) = event_queue_evt.wait() {
+                            error!("vsock: failed reading event queue Event: {}", e);
+                            break 'wait;
+                        }
+                    }
+                    Token::InterruptResample => {
+                        self.interrupt.interrupt_resample();
+                    }
+                    Token::Kill => break 'wait,
+                    Token::Listener => self.accept_host_connections(),
+                    Token::Pending { id } => self.read_connect_line(id),
+                    Token::Connection { id } => self.handle_connection_event(
+                        id,
+                        event.is_readable,
+                        event.is_writable,
+                        event.is_hungup,
+                    ),
+                }
+            }
+
+            self.process_rx_backlog();
+            if tx_throttled && self.rx_backlog.len() < MAX_RX_BACKLOG {
+                tx_throttled = !self.process_tx_queue();
+                self.process_rx_backlog();
+            }
+            self.update_connections();
+            self.process_rx_backlog();
+        }
+    }
+}
+
+/// Virtio vsock device that forwards guest streams to unix domain sockets on the host.
+pub struct Vsock {
+    guest_cid: u64,
+    uds_path: PathBuf,
+    listener: UnixListener,
+    features: u64,
+    kill_evt: Option<Event>,
+    worker_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Vsock {
+    /// Creates a new vsock device for the guest with `guest_cid`. Host processes connect to the
+    /// guest through the unix socket bound at `uds_path`, and guest connections to host port `P`
+    /// are forwarded to the unix socket at `<uds_path>_<P>`.
+    pub fn new(guest_cid: u64, uds_path: &Path, base_features: u64) -> Result<Vsock> {
+        // Remove a socket left behind by a previous instance, but never any other kind of file.
+        match fs::symlink_metadata(uds_path) {
+            Ok(m) if m.file_type().is_socket() => fs::remove_file(uds_path)
+                .map_err(|e| VsockError::RemoveSocket(uds_path.to_path_buf(), e))?,
+            Ok(_) => return Err(VsockError::SocketPathInUse(uds_path.to_path_buf())),
+            Err(_) => {}
+        }
+        let listener = UnixListener::bind(uds_path)
+            .map_err(|e| VsockError::BindSocket(uds_path.to_path_buf(), e))?;
+        listener
+            .set_nonblocking(true)
+            .map_err(VsockError::SetNonBlocking)?;
+
+        Ok(Vsock {
+            guest_cid,
+            uds_path: uds_path.to_path_buf(),
+            listener,
+            features: base_features,
+            kill_evt: None,
+            worker_thread: None,
+        })
+    }
+
+    fn get_config(&self) -> virtio_vsock_config {
+        virtio_vsock_config {
+            guest_cid: Le64::from(self.guest_cid),
+        }
+    }
+
+    fn start_worker(
+        &mut self,
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        mut queues: Vec<Queue>,
+        mut queue_evts: Vec<Event>,
+    ) -> Result<()> {
+        let (self_kill_evt, kill_evt) = Event::new()
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(VsockError::CreateKillEvent)?;
+        let listener = self
+            .listener
+            .try_clone()
+            .map_err(VsockError::CloneListener)?;
+        let wait_ctx = WaitContext::new().map_err(VsockError::CreateWaitContext)?;
+
+        let rx_queue = queues.remove(0);
+        let tx_queue = queues.remove(0);
+        let rx_queue_evt = queue_evts.remove(0);
+        let tx_queue_evt = queue_evts.remove(0);
+        let event_queue_evt = queue_evts.remove(0);
+
+        let mut worker = Worker {
+            mem,
+            interrupt,
+            rx_queue,
+            tx_queue,
+            guest_cid: self.guest_cid,
+            uds_path: self.uds_path.clone(),
+            listener,
+            wait_ctx,
+            connections: HashMap::new(),
+            connection_ports: HashMap::new(),
+            pending: HashMap::new(),
+            next_id: 0,
+            next_host_port: FIRST_HOST_PORT,
+            rx_backlog: VecDeque::new(),
+        };
+        let worker_thread = thread::Builder::new()
+            .name("v_vsock".to_string())
+            .spawn(move || worker.run(rx_queue_evt, tx_queue_evt, event_queue_evt, kill_evt))
+            .map_err(VsockError::SpawnThread)?;
+
+        self.kill_evt = Some(self_kill_evt);
+        self.worker_thread = Some(worker_thread);
+        Ok(())
+    }
+}
+
+impl Drop for Vsock {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.signal();
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let _ = worker_thread.join();
+        }
+    }
+}
+
+impl VirtioDevice for Vsock {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        vec![self.listener.as_raw_descriptor()]
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Vsock
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.features &= value;
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        copy_config(data, 0, self.get_config().as_slice(), offset);
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        queues: Vec<Queue>,
+        queue_evts: Vec<Event>,
+    ) {
+        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+            error!(
+                "vsock: expected {} queues, got {}",
+                NUM_QUEUES,
+                queues.len()
+            );
+            return;
+        }
+
+        if let Err(e) = self.start_worker(mem, interrupt, queues, queue_evts) {
+            error!("vsock: failed to start worker: {}", e);
+        }
+    }
+}
+
+impl Suspendable for Vsock {}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufRead;
+    use std::io::BufReader;
+
+    use vm_memory::GuestAddress;
+
+    use super::*;
+    use crate::virtio::descriptor_utils::create_descriptor_chain;
+    use crate::virtio::descriptor_utils::DescriptorType;
+    use crate::IrqLevelEvent;
+
+    const GUEST_CID: u64 = 3;
+    const BUFFER_ADDR: u64 = 0x1000;
+
+    fn new_worker(uds_path: &Path) -> Worker {
+        let listener = UnixListener::bind(uds_path).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        Worker {
+            mem: GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap(),
+            interrupt: Interrupt::new(IrqLevelEvent::new().unwrap(), None, 10),
+            rx_queue: Queue::new(QUEUE_SIZE),
+            tx_queue: Queue::new(QUEUE_SIZE),
+            guest_cid: GUEST_CID,
+            uds_path: uds_path.to_path_buf(),
+            listener,
+            wait_ctx: WaitContext::new().unwrap(),
+            connections: HashMap::new(),
+            connection_ports: HashMap::new(),
+            pending: HashMap::new(),
+            next_id: 0,
+            next_host_port: FIRST_HOST_PORT,
+            rx_backlog: VecDeque::new(),
+        }
+    }
+
+    fn send_from_guest(worker: &mut Worker, ports: PortPair, op: u16, data: &[u8]) {
+        send_packet_from_guest(worker, ports, op, data.len() as u32, data);
+    }
+
+    // Sends a packet whose header claims a payload of `len` bytes, followed by `data`.
+    fn send_packet_from_guest(
+        worker: &mut Worker,
+        ports: PortPair,
+        op: u16,
+        len: u32,
+        data: &[u8],
+    ) {
+        let hdr = virtio_vsock_hdr {
+            src_cid: Le64::from(GUEST_CID),
+            dst_cid: Le64::from(HOST_CID),
+            src_port: Le32::from(ports.guest),
+            dst_port: Le32::from(ports.host),
+            len: Le32::from(len),
+            r#type: TYPE_STREAM_SOCKET.into(),
+            op: op.into(),
+            flags: Le32::from(0),
+            buf_alloc: Le32::from(CONNECTION_BUF_ALLOC),
+            fwd_cnt: Le32::from(0),
+        };
+        let mut packet = hdr.as_slice().to_vec();
+        packet.extend_from_slice(data);
+        worker
+            .mem
+            .write_all_at_addr(&packet, GuestAddress(BUFFER_ADDR))
+            .unwrap();
+        let chain = create_descriptor_chain(
+            &worker.mem,
+            GuestAddress(0),
+            GuestAddress(BUFFER_ADDR),
+            vec![(DescriptorType::Readable, packet.len() as u32)],
+            0,
+        )
+        .unwrap();
+        let mut reader = Reader::new(worker.mem.clone(), chain).unwrap();
+        worker.process_tx_packet(&mut reader).unwrap();
+    }
+
+    fn recv_from_device(worker: &mut Worker) -> (PortPair, u16, Vec<u8>) {
+        let (hdr, data) = worker
+            .rx_backlog
+            .pop_front()
+            .expect("no packet for the guest");
+        assert_eq!(hdr.src_cid.to_native(), HOST_CID);
+        assert_eq!(hdr.dst_cid.to_native(), GUEST_CID);
+        let ports = PortPair {
+            host: hdr.src_port.to_native(),
+            guest: hdr.dst_port.to_native(),
+        };
+        (ports, hdr.op.to_native(), data)
+    }
+
+    #[test]
+    fn guest_connects_to_host_port() {
+        let dir = tempfile::tempdir().unwrap();
+        let uds_path = dir.path().join("vsock");
+        let host_listener = UnixListener::bind(dir.path().join("vsock_1234")).unwrap();
+        let mut worker = new_worker(&uds_path);
+        let ports = PortPair {
+            host: 1234,
+            guest: 5000,
+        };
+
+        send_from_guest(&mut worker, ports, vsock_op::VIRTIO_VSOCK_OP_REQUEST, &[]);
+        assert_eq!(
+            recv_from_device(&mut worker),
+            (ports, vsock_op::VIRTIO_VSOCK_OP_RESPONSE, Vec::new())
+        );
+        let (mut host_stream, _) = host_listener.accept().unwrap();
+
+        send_from_guest(&mut worker, ports, vsock_op::VIRTIO_VSOCK_OP_RW, b"ping");
+        let mut buf = [0u8; 4];
+        host_stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        host_stream.write_all(b"pong").unwrap();
+        worker.read_from_host(ports);
+        assert_eq!(
+            recv_from_device(&mut worker),
+            (ports, vsock_op::VIRTIO_VSOCK_OP_RW, b"pong".to_vec())
+        );
+
+        send_from_guest(&mut worker, ports, vsock_op::VIRTIO_VSOCK_OP_RST, &[]);
+        assert!(worker.connections.is_empty());
+        assert_eq!(host_stream.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn guest_packet_with_invalid_length_is_reset() {
+        let dir = tempfile::tempdir().unwrap();
+        let host_listener = UnixListener::bind(dir.path().join("vsock_1234")).unwrap();
+        let mut worker = new_worker(&dir.path().join("vsock"));
+        let ports = PortPair {
+            host: 1234,
+            guest: 5000,
+        };
+
+        send_from_guest(&mut worker, ports, vsock_op::VIRTIO_VSOCK_OP_REQUEST, &[]);
+        recv_from_device(&mut worker);
+        let (mut host_stream, _) = host_listener.accept().unwrap();
+
+        // The payload is shorter than the header claims.
+        send_packet_from_guest(
+            &mut worker,
+            ports,
+            vsock_op::VIRTIO_VSOCK_OP_RW,
+            u32::MAX,
+            b"ping",
+        );
+        assert_eq!(
+            recv_from_device(&mut worker),
+            (ports, vsock_op::VIRTIO_VSOCK_OP_RST, Vec::new())
+        );
+        assert!(worker.connections.is_empty());
+        let mut buf = [0u8; 4];
+        assert_eq!(host_stream.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn guest_connect_without_host_listener_is_reset() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut worker = new_worker(&dir.path().join("vsock"));
+        let ports = PortPair {
+            host: 1234,
+            guest: 5000,
+        };
+
+        send_from_guest(&mut worker, ports, vsock_op::VIRTIO_VSOCK_OP_REQUEST, &[]);
+        assert_eq!(
+            recv_from_device(&mut worker),
+            (ports, vsock_op::VIRTIO_VSOCK_OP_RST, Vec::new())
+        );
+        assert!(worker.connections.is_empty());
+    }
+
+    #[test]
+    fn host_connects_to_guest_port() {
+        let dir = tempfile::tempdir().unwrap();
+        let uds_path = dir.path().join("vsock");
+        let mut worker = new_worker(&uds_path);
+
+        let mut host_stream = UnixStream::connect(&uds_path).unwrap();
+        host_stream.write_all(b"CONNECT 52\n").unwrap();
+        worker.accept_host_connections();
+        worker.read_connect_line(0);
+        let (ports, op, _) = recv_from_device(&mut worker);
+        assert_eq!(op, vsock_op::VIRTIO_VSOCK_OP_REQUEST);
+        assert_eq!(
+            ports,
+            PortPair {
+                host: FIRST_HOST_PORT,
+                guest: 52,
+            }
+        );
+
+        send_from_guest(&mut worker, ports, vsock_op::VIRTIO_VSOCK_OP_RESPONSE, &[]);
+        let mut line = String::new();
+        BufReader::new(&host_stream).read_line(&mut line).unwrap();
+        assert_eq!(line, format!("OK {}\n", FIRST_HOST_PORT));
+    }
+}
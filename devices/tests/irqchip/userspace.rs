@@ -38,6 +38,7 @@ use hypervisor::DebugRegs;
 use hypervisor::DeliveryMode;
 use hypervisor::DestinationMode;
 use hypervisor::Fpu;
+use hypervisor::GuestDebug;
 use hypervisor::HypervHypercall;
 use hypervisor::IoParams;
 use hypervisor::IoapicRedirectionTableEntry;
@@ -58,7 +59,6 @@ use resources::AddressRange;
 use resources::SystemAllocator;
 use resources::SystemAllocatorConfig;
 use sync::Mutex;
-use vm_memory::GuestAddress;
 
 use crate::x86_64::test_get_ioapic;
 use crate::x86_64::test_get_pit;
@@ -766,7 +766,13 @@ impl VcpuX86_64 for FakeVcpu {
     fn get_hyperv_cpuid(&self) -> Result<CpuId> {
         unimplemented!()
     }
-    fn set_guest_debug(&self, _addrs: &[GuestAddress], _enable_singlestep: bool) -> Result<()> {
+    fn set_guest_debug(&self, _debug: &GuestDebug) -> Result<()> {
+        unimplemented!()
+    }
+    fn get_debug_exit_watchpoint(&self, _debug: &GuestDebug) -> Result<Option<usize>> {
+        unimplemented!()
+    }
+    fn inject_breakpoint(&self) -> Result<()> {
         unimplemented!()
     }
     fn get_tsc_offset(&self) -> Result<u64> {
@@ -32,6 +32,7 @@ use thiserror::Error;
 use crate::AsyncDisk;
 use crate::AsyncDiskFileWrapper;
 use crate::DiskGetLen;
+use crate::DiskSnapshots;
 use crate::ToAsyncDisk;
 
 #[sorted]
@@ -335,6 +336,8 @@ impl FileReadWriteAtVolatile for AndroidSparse {
     }
 }
 
+impl DiskSnapshots for AndroidSparse {}
+
 impl ToAsyncDisk for AndroidSparse {
     fn to_async_disk(self: Box<Self>, ex: &Executor) -> crate::Result<Box<dyn AsyncDisk>> {
         Ok(Box::new(AsyncDiskFileWrapper::new(*self, ex)))
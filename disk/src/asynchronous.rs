@@ -21,6 +21,8 @@ use sync::Mutex;
 use crate::AsyncDisk;
 use crate::DiskFile;
 use crate::DiskGetLen;
+use crate::DiskSnapshotInfo;
+use crate::DiskSnapshots;
 use crate::Error;
 use crate::Result;
 
@@ -61,6 +63,24 @@ impl<T: DiskFile + Send> FileAllocate for AsyncDiskFileWrapper<T> {
     }
 }
 
+impl<T: DiskFile + Send> DiskSnapshots for AsyncDiskFileWrapper<T> {
+    fn list_snapshots(&self) -> Result<Vec<DiskSnapshotInfo>> {
+        self.inner.lock().list_snapshots()
+    }
+
+    fn create_snapshot(&mut self, name: &str) -> Result<()> {
+        self.inner.lock().create_snapshot(name)
+    }
+
+    fn apply_snapshot(&mut self, snapshot: &str) -> Result<()> {
+        self.inner.lock().apply_snapshot(snapshot)
+    }
+
+    fn delete_snapshot(&mut self, snapshot: &str) -> Result<()> {
+        self.inner.lock().delete_snapshot(snapshot)
+    }
+}
+
 impl<T: DiskFile + Send> AsRawDescriptors for AsyncDiskFileWrapper<T> {
     fn as_raw_descriptors(&self) -> Vec<RawDescriptor> {
         self.inner.lock().as_raw_descriptors()
@@ -54,6 +54,7 @@ use crate::AsyncDisk;
 use crate::AsyncDiskFileWrapper;
 use crate::DiskFile;
 use crate::DiskGetLen;
+use crate::DiskSnapshots;
 use crate::ImageType;
 use crate::ToAsyncDisk;
 
@@ -440,6 +441,8 @@ impl AsRawDescriptors for CompositeDiskFile {
     }
 }
 
+impl DiskSnapshots for CompositeDiskFile {}
+
 impl ToAsyncDisk for CompositeDiskFile {
     fn to_async_disk(self: Box<Self>, ex: &Executor) -> crate::Result<Box<dyn AsyncDisk>> {
         Ok(Box::new(AsyncDiskFileWrapper::new(*self, ex)))
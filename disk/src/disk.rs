@@ -28,6 +28,8 @@ use cros_async::AllocateMode;
 use cros_async::BackingMemory;
 use cros_async::Executor;
 use cros_async::IoSourceExt;
+use serde::Deserialize;
+use serde::Serialize;
 use thiserror::Error as ThisError;
 
 mod asynchronous;
@@ -113,6 +115,8 @@ pub enum Error {
     SeekingFile(io::Error),
     #[error("failed to set file size: {0}")]
     SettingFileSize(io::Error),
+    #[error("disk image format does not support internal snapshots")]
+    SnapshotsNotSupported,
     #[error("unknown disk type")]
     UnknownType,
     #[error("failed to write from memory: {0}")]
@@ -146,6 +150,47 @@ impl DiskGetLen for File {
     }
 }
 
+/// Describes an internal snapshot stored in a disk image.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskSnapshotInfo {
+    /// ID of the snapshot, unique within the image.
+    pub id: String,
+    /// Name given to the snapshot when it was created.
+    pub name: String,
+    /// Time the snapshot was taken, as seconds and nanoseconds since the Unix epoch.
+    pub date_sec: u32,
+    pub date_nsec: u32,
+    /// Size of the disk when the snapshot was taken, in bytes.
+    pub disk_size: u64,
+}
+
+/// A trait for managing the internal snapshots that some disk image formats, such as qcow2, can
+/// store alongside the disk contents. The default implementations are for formats without them
+/// and return `Error::SnapshotsNotSupported`.
+pub trait DiskSnapshots {
+    /// Lists the internal snapshots stored in the image.
+    fn list_snapshots(&self) -> Result<Vec<DiskSnapshotInfo>> {
+        Err(Error::SnapshotsNotSupported)
+    }
+
+    /// Saves the current contents of the disk as a snapshot called `name`.
+    fn create_snapshot(&mut self, _name: &str) -> Result<()> {
+        Err(Error::SnapshotsNotSupported)
+    }
+
+    /// Reverts the contents of the disk to the snapshot with the ID or name `snapshot`.
+    fn apply_snapshot(&mut self, _snapshot: &str) -> Result<()> {
+        Err(Error::SnapshotsNotSupported)
+    }
+
+    /// Deletes the snapshot with the ID or name `snapshot`.
+    fn delete_snapshot(&mut self, _snapshot: &str) -> Result<()> {
+        Err(Error::SnapshotsNotSupported)
+    }
+}
+
+impl DiskSnapshots for File {}
+
 /// The prerequisites necessary to support a block device.
 #[rustfmt::skip] // rustfmt won't wrap the long list of trait bounds.
 pub trait DiskFile:
@@ -156,6 +201,7 @@ pub trait DiskFile:
     + PunchHole
     + WriteZeroesAt
     + FileAllocate
+    + DiskSnapshots
     + ToAsyncDisk
     + Send
     + AsRawDescriptors
@@ -170,6 +216,7 @@ impl<
             + FileReadWriteAtVolatile
             + WriteZeroesAt
             + FileAllocate
+            + DiskSnapshots
             + ToAsyncDisk
             + Send
             + AsRawDescriptors
@@ -315,7 +362,7 @@ pub fn create_disk_file(
 
 /// An asynchronously accessible disk.
 #[async_trait(?Send)]
-pub trait AsyncDisk: DiskGetLen + FileSetLen + FileAllocate {
+pub trait AsyncDisk: DiskGetLen + FileSetLen + FileAllocate + DiskSnapshots {
     /// Returns the inner file consuming self.
     fn into_inner(self: Box<Self>) -> Box<dyn DiskFile>;
 
@@ -377,6 +424,8 @@ impl FileAllocate for SingleFileDisk {
     }
 }
 
+impl DiskSnapshots for SingleFileDisk {}
+
 #[async_trait(?Send)]
 impl AsyncDisk for SingleFileDisk {
     fn into_inner(self: Box<Self>) -> Box<dyn DiskFile> {
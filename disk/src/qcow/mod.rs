@@ -18,6 +18,8 @@ use std::io::Write;
 use std::mem::size_of;
 use std::path::Path;
 use std::str;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use base::error;
 use base::open_file;
@@ -33,9 +35,10 @@ use base::WriteZeroesAt;
 use cros_async::Executor;
 use data_model::VolatileMemory;
 use data_model::VolatileSlice;
+use flate2::read::DeflateDecoder;
 use libc::EINVAL;
 use libc::ENOSPC;
-use libc::ENOTSUP;
+use libc::EOVERFLOW;
 use remain::sorted;
 use thiserror::Error;
 
@@ -49,6 +52,8 @@ use crate::AsyncDisk;
 use crate::AsyncDiskFileWrapper;
 use crate::DiskFile;
 use crate::DiskGetLen;
+use crate::DiskSnapshotInfo;
+use crate::DiskSnapshots;
 use crate::ToAsyncDisk;
 
 #[sorted]
@@ -60,8 +65,6 @@ pub enum Error {
     BackingFileOpen(Box<crate::Error>),
     #[error("backing file name is too long: {0} bytes over")]
     BackingFileTooLong(usize),
-    #[error("compressed blocks not supported")]
-    CompressedBlocksNotSupported,
     #[error("failed to evict cache: {0}")]
     EvictingCache(io::Error),
     #[error("file larger than max of {}: {0}", MAX_QCOW_FILE_SIZE)]
@@ -90,6 +93,10 @@ pub enum Error {
     InvalidRefcountTableOffset,
     #[error("invalid refcount table size: {0}")]
     InvalidRefcountTableSize(u64),
+    #[error("invalid snapshot name: {0:?}")]
+    InvalidSnapshotName(String),
+    #[error("invalid snapshot table")]
+    InvalidSnapshotTable,
     #[error("no free clusters")]
     NoFreeClusters,
     #[error("no refcount clusters")]
@@ -106,6 +113,8 @@ pub enum Error {
     ReadingRefCountBlock(refcount::Error),
     #[error("failed to read ref counts: {0}")]
     ReadingRefCounts(io::Error),
+    #[error("failed to read snapshot table: {0}")]
+    ReadingSnapshotTable(io::Error),
     #[error("failed to rebuild ref counts: {0}")]
     RebuildingRefCounts(io::Error),
     #[error("refcount table offset past file end")]
@@ -118,14 +127,26 @@ pub enum Error {
     SettingRefcountRefcount(io::Error),
     #[error("size too small for number of clusters")]
     SizeTooSmallForNumberOfClusters,
+    #[error("snapshot {0:?} already exists")]
+    SnapshotExists(String),
+    #[error("snapshot {0:?} not found")]
+    SnapshotNotFound(String),
+    #[error("snapshot was taken of a disk of {0} bytes")]
+    SnapshotSizeMismatch(u64),
     #[error("l1 entry table too large: {0}")]
     TooManyL1Entries(u64),
     #[error("ref count table too large: {0}")]
     TooManyRefcounts(u64),
+    #[error("too many snapshots: {0}")]
+    TooManySnapshots(u32),
+    #[error("unsupported compression type")]
+    UnsupportedCompressionType,
     #[error("unsupported refcount order")]
     UnsupportedRefcountOrder,
     #[error("unsupported version: {0}")]
     UnsupportedVersion(u32),
+    #[error("failed to update snapshots: {0}")]
+    UpdatingSnapshots(io::Error),
     #[error("failed to write header: {0}")]
     WritingHeader(io::Error),
 }
@@ -159,6 +180,21 @@ const L2_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
 const COMPRESSED_FLAG: u64 = 1 << 62;
 const CLUSTER_USED_FLAG: u64 = 1 << 63;
 const COMPATIBLE_FEATURES_LAZY_REFCOUNTS: u64 = 1 << 0;
+// Set when compressed clusters use something other than zlib, which is not supported.
+const INCOMPATIBLE_FEATURES_COMPRESSION_TYPE: u64 = 1 << 3;
+
+// Compressed cluster descriptors count the length of the compressed data in 512 byte sectors.
+const COMPRESSED_SECTOR_SIZE: u64 = 512;
+
+// The snapshot count and table offset are stored together, starting at this offset in the header.
+const SNAPSHOT_HEADER_FIELDS_OFFSET: u64 = 60;
+// Limits on the snapshot table, the same as qemu uses.
+const MAX_SNAPSHOTS: u32 = 65536;
+const MAX_SNAPSHOT_EXTRA_DATA_SIZE: u32 = 1024;
+// Size of the fixed part of a snapshot table entry.
+const SNAPSHOT_ENTRY_HEADER_SIZE: usize = 40;
+// Size of the extra data crosvm understands: the 64 bit VM state size and the disk size.
+const SNAPSHOT_KNOWN_EXTRA_DATA_SIZE: usize = 16;
 
 // The format supports a "header extension area", that crosvm does not use.
 const QCOW_EMPTY_HEADER_EXTENSION_SIZE: u32 = 8;
@@ -385,6 +421,214 @@ impl QcowHeader {
     }
 }
 
+/// An internal snapshot, as stored in the snapshot table of a qcow2 file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct QcowSnapshot {
+    id: String,
+    name: String,
+    // The snapshot's own copy of the L1 table.
+    l1_table_offset: u64,
+    l1_size: u32,
+    // Wall clock time at which the snapshot was taken.
+    date_sec: u32,
+    date_nsec: u32,
+    // Guest clock and VM state size. crosvm only snapshots disks, so it leaves these as zero.
+    vm_clock_nsec: u64,
+    vm_state_size: u64,
+    // Virtual size of the disk when the snapshot was taken.
+    disk_size: u64,
+    // Extra data following the fields crosvm knows about, written back unchanged.
+    extra_data: Vec<u8>,
+}
+
+impl QcowSnapshot {
+    // Reads the snapshot table entry at the current position of `f`. Returns the snapshot and the
+    // size of its entry in bytes. `disk_size` is used for entries that don't record their own.
+    fn read_from(f: &mut File, disk_size: u64) -> Result<(QcowSnapshot, u64)> {
+        fn read_bytes(f: &mut File, len: usize) -> Result<Vec<u8>> {
+            let mut bytes = vec![0u8; len];
+            f.read_exact(&mut bytes)
+                .map_err(Error::ReadingSnapshotTable)?;
+            Ok(bytes)
+        }
+
+        fn read_string(f: &mut File, len: usize) -> Result<String> {
+            String::from_utf8(read_bytes(f, len)?).map_err(|_| Error::InvalidSnapshotTable)
+        }
+
+        let header = read_bytes(f, SNAPSHOT_ENTRY_HEADER_SIZE)?;
+        let be_u16 =
+            |offset: usize| u16::from_be_bytes(header[offset..offset + 2].try_into().unwrap());
+        let be_u32 =
+            |offset: usize| u32::from_be_bytes(header[offset..offset + 4].try_into().unwrap());
+        let be_u64 =
+            |offset: usize| u64::from_be_bytes(header[offset..offset + 8].try_into().unwrap());
+
+        let id_size = be_u16(12) as usize;
+        let name_size = be_u16(14) as usize;
+        let extra_data_size = be_u32(36);
+        if extra_data_size > MAX_SNAPSHOT_EXTRA_DATA_SIZE {
+            return Err(Error::InvalidSnapshotTable);
+        }
+
+        let mut snapshot = QcowSnapshot {
+            id: String::new(),
+            name: String::new(),
+            l1_table_offset: be_u64(0),
+            l1_size: be_u32(8),
+            date_sec: be_u32(16),
+            date_nsec: be_u32(20),
+            vm_clock_nsec: be_u64(24),
+            vm_state_size: u64::from(be_u32(32)),
+            disk_size,
+            extra_data: Vec::new(),
+        };
+
+        // Snapshots written by version 2 implementations may lack the 64 bit VM state size and the
+        // disk size.
+        let mut extra_data = read_bytes(f, extra_data_size as usize)?;
+        if extra_data.len() >= 8 {
+            snapshot.vm_state_size = u64::from_be_bytes(extra_data[0..8].try_into().unwrap());
+        }
+        if extra_data.len() >= SNAPSHOT_KNOWN_EXTRA_DATA_SIZE {
+            snapshot.disk_size = u64::from_be_bytes(extra_data[8..16].try_into().unwrap());
+            snapshot.extra_data = extra_data.split_off(SNAPSHOT_KNOWN_EXTRA_DATA_SIZE);
+        }
+
+        snapshot.id = read_string(f, id_size)?;
+        snapshot.name = read_string(f, name_size)?;
+
+        // Entries are padded to a multiple of 8 bytes.
+        let entry_size =
+            SNAPSHOT_ENTRY_HEADER_SIZE + extra_data_size as usize + id_size + name_size;
+        let padding = (8 - entry_size % 8) % 8;
+        f.seek(SeekFrom::Current(padding as i64))
+            .map_err(Error::ReadingSnapshotTable)?;
+
+        Ok((snapshot, (entry_size + padding) as u64))
+    }
+
+    // Appends the snapshot table entry for this snapshot to `table`.
+    fn write_to(&self, table: &mut Vec<u8>) {
+        let start = table.len();
+        table.extend_from_slice(&self.l1_table_offset.to_be_bytes());
+        table.extend_from_slice(&self.l1_size.to_be_bytes());
+        table.extend_from_slice(&(self.id.len() as u16).to_be_bytes());
+        table.extend_from_slice(&(self.name.len() as u16).to_be_bytes());
+        table.extend_from_slice(&self.date_sec.to_be_bytes());
+        table.extend_from_slice(&self.date_nsec.to_be_bytes());
+        table.extend_from_slice(&self.vm_clock_nsec.to_be_bytes());
+        let vm_state_size_32 = min(self.vm_state_size, u64::from(u32::MAX)) as u32;
+        table.extend_from_slice(&vm_state_size_32.to_be_bytes());
+        let extra_data_size = SNAPSHOT_KNOWN_EXTRA_DATA_SIZE + self.extra_data.len();
+        table.extend_from_slice(&(extra_data_size as u32).to_be_bytes());
+        table.extend_from_slice(&self.vm_state_size.to_be_bytes());
+        table.extend_from_slice(&self.disk_size.to_be_bytes());
+        table.extend_from_slice(&self.extra_data);
+        table.extend_from_slice(self.id.as_bytes());
+        table.extend_from_slice(self.name.as_bytes());
+        let entry_size = (table.len() - start) as u64;
+        table.resize(start + div_round_up_u64(entry_size, 8) as usize * 8, 0);
+    }
+}
+
+// Reads the snapshot table described by `header`. Returns the snapshots and the size of the table
+// in bytes.
+fn read_snapshot_table(f: &mut File, header: &QcowHeader) -> Result<(Vec<QcowSnapshot>, u64)> {
+    if header.nb_snapshots > MAX_SNAPSHOTS {
+        return Err(Error::TooManySnapshots(header.nb_snapshots));
+    }
+    if header.nb_snapshots == 0 {
+        return Ok((Vec::new(), 0));
+    }
+
+    f.seek(SeekFrom::Start(header.snapshots_offset))
+        .map_err(Error::ReadingSnapshotTable)?;
+    let mut snapshots = Vec::with_capacity(header.nb_snapshots as usize);
+    let mut table_size = 0;
+    for _ in 0..header.nb_snapshots {
+        let (snapshot, entry_size) = QcowSnapshot::read_from(f, header.size)?;
+        offset_is_cluster_boundary(snapshot.l1_table_offset, header.cluster_bits)?;
+        if u64::from(snapshot.l1_size) > MAX_RAM_POINTER_TABLE_SIZE {
+            return Err(Error::InvalidL1TableSize(snapshot.l1_size));
+        }
+        snapshots.push(snapshot);
+        table_size += entry_size;
+    }
+    Ok((snapshots, table_size))
+}
+
+// Returns the host offset and the length in bytes of the compressed data described by the
+// compressed L2 table entry `entry`.
+fn compressed_cluster_range(entry: u64, cluster_bits: u32) -> (u64, u64) {
+    let offset_bits = 62 - (cluster_bits - 8);
+    let offset = entry & ((1 << offset_bits) - 1);
+    let additional_sectors = (entry >> offset_bits) & ((1 << (cluster_bits - 8)) - 1);
+    let len = (additional_sectors + 1) * COMPRESSED_SECTOR_SIZE - offset % COMPRESSED_SECTOR_SIZE;
+    (offset, len)
+}
+
+// Returns the addresses of the host clusters holding the compressed data described by `entry`.
+// Each compressed L2 entry holds one reference to every cluster its data touches.
+fn compressed_clusters(entry: u64, cluster_bits: u32) -> impl Iterator<Item = u64> {
+    let cluster_size = 1u64 << cluster_bits;
+    let (offset, len) = compressed_cluster_range(entry, cluster_bits);
+    (offset & !(cluster_size - 1)..offset + len).step_by(cluster_size as usize)
+}
+
+// Writes the L2 table `table` to the cluster at `addr`. Standard entries are flagged as copied,
+// which tells qcow2 implementations that the cluster may be written in place, only while they hold
+// the sole reference to their cluster. Compressed entries are never flagged.
+fn write_l2_table(
+    raw_file: &mut QcowRawFile,
+    refcounts: &mut RefCount,
+    addr: u64,
+    table: &[u64],
+) -> io::Result<()> {
+    let mut entries = Vec::with_capacity(table.len());
+    for &entry in table {
+        let sole_reference = entry != 0
+            && entry & COMPRESSED_FLAG == 0
+            && refcounts
+                .get_cluster_refcount(raw_file, entry)
+                .map_err(|_| io::Error::from_raw_os_error(EINVAL))?
+                == 1;
+        entries.push(if sole_reference {
+            entry | CLUSTER_USED_FLAG
+        } else {
+            entry
+        });
+    }
+    raw_file.write_pointer_table(addr, &entries, 0)
+}
+
+// Where `QcowFile::read_cb` finds the data for a range of the disk.
+enum ReadSource<'a> {
+    // Read from a file, starting at the given offset.
+    File(&'a mut dyn DiskFile, u64),
+    // Copy from part of a decompressed cluster.
+    Buffer(&'a [u8]),
+    // The range is unallocated and reads as zeros.
+    Zeroes,
+}
+
+impl ReadSource<'_> {
+    // Fills `slice` with data from this source.
+    fn read_into(self, slice: VolatileSlice) -> io::Result<()> {
+        match self {
+            ReadSource::File(f, offset) => f.read_exact_at_volatile(slice, offset),
+            ReadSource::Buffer(buf) => {
+                slice.copy_from(buf);
+                Ok(())
+            }
+            ReadSource::Zeroes => {
+                slice.write_bytes(0);
+                Ok(())
+            }
+        }
+    }
+}
+
 fn max_refcount_clusters(refcount_order: u32, cluster_size: u32, num_clusters: u32) -> u64 {
     // Use u64 as the product of the u32 inputs can overflow.
     let refcount_bytes = (0x01 << refcount_order as u64) / 8;
@@ -424,6 +668,11 @@ pub struct QcowFile {
     // removal of references to them have been synced to disk.
     avail_clusters: Vec<u64>,
     backing_file: Option<Box<dyn DiskFile>>,
+    snapshots: Vec<QcowSnapshot>,
+    // Size of the snapshot table in the file, in bytes.
+    snapshot_table_size: u64,
+    // The most recently read compressed cluster, decompressed, along with its L2 entry.
+    decompressed_cluster: Option<(u64, Vec<u8>)>,
 }
 
 impl QcowFile {
@@ -452,6 +701,11 @@ impl QcowFile {
             return Err(Error::FileTooBig(header.size));
         }
 
+        // Compressed clusters are only supported when they use zlib.
+        if header.incompatible_features & INCOMPATIBLE_FEATURES_COMPRESSION_TYPE != 0 {
+            return Err(Error::UnsupportedCompressionType);
+        }
+
         let backing_file = if let Some(backing_file_path) = header.backing_file_path.as_ref() {
             let path = backing_file_path.clone();
             let backing_raw_file = open_file(
@@ -514,10 +768,17 @@ impl QcowFile {
             refcount_rebuild_required = true;
         }
 
+        let (snapshots, snapshot_table_size) = read_snapshot_table(&mut file, &header)?;
+
         let mut raw_file =
             QcowRawFile::from(file, cluster_size).ok_or(Error::InvalidClusterSize)?;
         if refcount_rebuild_required {
-            QcowFile::rebuild_refcounts(&mut raw_file, header.clone())?;
+            QcowFile::rebuild_refcounts(
+                &mut raw_file,
+                header.clone(),
+                &snapshots,
+                snapshot_table_size,
+            )?;
         }
 
         let l2_size = cluster_size / size_of::<u64>() as u64;
@@ -574,6 +835,9 @@ impl QcowFile {
             unref_clusters: Vec::new(),
             avail_clusters: Vec::new(),
             backing_file,
+            snapshots,
+            snapshot_table_size,
+            decompressed_cluster: None,
         };
 
         // Check that the L1 and refcount tables fit in a 64bit address space.
@@ -657,6 +921,174 @@ impl QcowFile {
         self.backing_file = backing;
     }
 
+    // Returns the index of the snapshot with the ID `snapshot`, or failing that, the name.
+    fn find_snapshot(&self, snapshot: &str) -> Result<usize> {
+        self.snapshots
+            .iter()
+            .position(|s| s.id == snapshot)
+            .or_else(|| self.snapshots.iter().position(|s| s.name == snapshot))
+            .ok_or_else(|| Error::SnapshotNotFound(snapshot.to_string()))
+    }
+
+    // Saves the current contents of the disk as an internal snapshot called `name`.
+    fn save_snapshot(&mut self, name: &str) -> Result<()> {
+        if name.is_empty() || name.len() > u16::MAX as usize {
+            return Err(Error::InvalidSnapshotName(name.to_string()));
+        }
+        if self.snapshots.iter().any(|s| s.name == name) {
+            return Err(Error::SnapshotExists(name.to_string()));
+        }
+        if self.snapshots.len() >= MAX_SNAPSHOTS as usize {
+            return Err(Error::TooManySnapshots(self.snapshots.len() as u32 + 1));
+        }
+
+        let id = self
+            .snapshots
+            .iter()
+            .filter_map(|s| s.id.parse::<u64>().ok())
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let l1_table = self.l1_table.get_values().to_vec();
+        let mut snapshot = QcowSnapshot {
+            id: id.to_string(),
+            name: name.to_string(),
+            l1_table_offset: 0,
+            l1_size: l1_table.len() as u32,
+            date_sec: date.as_secs() as u32,
+            date_nsec: date.subsec_nanos(),
+            vm_clock_nsec: 0,
+            vm_state_size: 0,
+            disk_size: self.virtual_size(),
+            extra_data: Vec::new(),
+        };
+
+        let save = || -> std::io::Result<()> {
+            self.fsync()?;
+            // The snapshot gets its own copy of the L1 table and shares everything the table
+            // points to with the active image, until one of them is written to.
+            let l1_clusters = div_round_up_u64(
+                l1_table.len() as u64 * size_of::<u64>() as u64,
+                self.raw_file.cluster_size(),
+            );
+            snapshot.l1_table_offset = self.append_clusters(l1_clusters)?;
+            self.raw_file
+                .write_pointer_table(snapshot.l1_table_offset, &l1_table, 0)?;
+            self.update_l1_refcounts(&l1_table, true)?;
+
+            let mut snapshots = self.snapshots.clone();
+            snapshots.push(snapshot);
+            self.write_snapshot_table(snapshots)
+        };
+        save().map_err(Error::UpdatingSnapshots)
+    }
+
+    // Reverts the contents of the disk to the internal snapshot with the ID or name `snapshot`.
+    fn revert_to_snapshot(&mut self, snapshot: &str) -> Result<()> {
+        let snapshot = self.snapshots[self.find_snapshot(snapshot)?].clone();
+        if snapshot.disk_size != self.virtual_size()
+            || snapshot.l1_size as usize > self.l1_table.len()
+        {
+            return Err(Error::SnapshotSizeMismatch(snapshot.disk_size));
+        }
+
+        let mut revert = || -> std::io::Result<()> {
+            self.fsync()?;
+            let mut l1_table = self.raw_file.read_pointer_table(
+                snapshot.l1_table_offset,
+                snapshot.l1_size.into(),
+                Some(L1_TABLE_OFFSET_MASK),
+            )?;
+            l1_table.resize(self.l1_table.len(), 0);
+
+            // Take the references for the snapshot's tables before the active L1 table points to
+            // them, and only drop those of the replaced tables once it no longer does.
+            self.update_l1_refcounts(&l1_table, true)?;
+            self.fsync()?;
+            self.raw_file
+                .write_pointer_table(self.header.l1_table_offset, &l1_table, 0)?;
+            self.raw_file.file_mut().sync_data()?;
+
+            let old_l1_table = std::mem::replace(&mut self.l1_table, VecCache::from_vec(l1_table));
+            // Every cached L2 table is clean after the sync above.
+            self.l2_cache = CacheMap::new(100);
+            self.update_l1_refcounts(old_l1_table.get_values(), false)?;
+            self.fsync()
+        };
+        revert().map_err(Error::UpdatingSnapshots)
+    }
+
+    // Deletes the internal snapshot with the ID or name `snapshot`, freeing the clusters that only
+    // it was using.
+    fn remove_snapshot(&mut self, snapshot: &str) -> Result<()> {
+        let mut snapshots = self.snapshots.clone();
+        let snapshot = snapshots.remove(self.find_snapshot(snapshot)?);
+
+        let remove = || -> std::io::Result<()> {
+            self.fsync()?;
+            self.write_snapshot_table(snapshots)?;
+
+            let l1_table = self.raw_file.read_pointer_table(
+                snapshot.l1_table_offset,
+                snapshot.l1_size.into(),
+                Some(L1_TABLE_OFFSET_MASK),
+            )?;
+            self.update_l1_refcounts(&l1_table, false)?;
+            let cluster_size = self.raw_file.cluster_size();
+            let l1_clusters = div_round_up_u64(
+                u64::from(snapshot.l1_size) * size_of::<u64>() as u64,
+                cluster_size,
+            );
+            for i in 0..l1_clusters {
+                self.unref_cluster(snapshot.l1_table_offset + i * cluster_size)?;
+            }
+            self.fsync()
+        };
+        remove().map_err(Error::UpdatingSnapshots)
+    }
+
+    // Writes `snapshots` to a newly allocated snapshot table, points the header at it, and then
+    // frees the old table.
+    fn write_snapshot_table(&mut self, snapshots: Vec<QcowSnapshot>) -> std::io::Result<()> {
+        let mut table = Vec::new();
+        for snapshot in &snapshots {
+            snapshot.write_to(&mut table);
+        }
+        let cluster_size = self.raw_file.cluster_size();
+        let table_offset = if table.is_empty() {
+            0
+        } else {
+            let offset =
+                self.append_clusters(div_round_up_u64(table.len() as u64, cluster_size))?;
+            let file = self.raw_file.file_mut();
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&table)?;
+            offset
+        };
+        // The table and its refcounts have to be on disk before the header points to them.
+        self.fsync()?;
+
+        let mut header_fields = Vec::new();
+        header_fields.extend_from_slice(&(snapshots.len() as u32).to_be_bytes());
+        header_fields.extend_from_slice(&table_offset.to_be_bytes());
+        let file = self.raw_file.file_mut();
+        file.seek(SeekFrom::Start(SNAPSHOT_HEADER_FIELDS_OFFSET))?;
+        file.write_all(&header_fields)?;
+        file.sync_data()?;
+
+        let old_table_offset = std::mem::replace(&mut self.header.snapshots_offset, table_offset);
+        let old_table_size = std::mem::replace(&mut self.snapshot_table_size, table.len() as u64);
+        self.header.nb_snapshots = snapshots.len() as u32;
+        self.snapshots = snapshots;
+        for i in 0..div_round_up_u64(old_table_size, cluster_size) {
+            self.unref_cluster(old_table_offset + i * cluster_size)?;
+        }
+        Ok(())
+    }
+
     /// Returns the first cluster in the file with a 0 refcount. Used for testing.
     pub fn first_zero_refcount(&mut self) -> Result<Option<u64>> {
         let file_size = self
@@ -705,7 +1137,12 @@ impl QcowFile {
     }
 
     /// Rebuild the reference count tables.
-    fn rebuild_refcounts(raw_file: &mut QcowRawFile, header: QcowHeader) -> Result<()> {
+    fn rebuild_refcounts(
+        raw_file: &mut QcowRawFile,
+        header: QcowHeader,
+        snapshots: &[QcowSnapshot],
+        snapshot_table_size: u64,
+    ) -> Result<()> {
         fn add_ref(refcounts: &mut [u16], cluster_size: u64, cluster_address: u64) -> Result<()> {
             let idx = (cluster_address / cluster_size) as usize;
             if idx >= refcounts.len() {
@@ -734,21 +1171,52 @@ impl QcowFile {
             Ok(())
         }
 
-        // Traverse the L1 and L2 tables to find all reachable data clusters.
-        fn set_data_refcounts(
+        // Add references to the snapshot table and to the L1 tables of the snapshots.
+        fn set_snapshot_refcounts(
             refcounts: &mut [u16],
             header: QcowHeader,
             cluster_size: u64,
+            snapshots: &[QcowSnapshot],
+            snapshot_table_size: u64,
+        ) -> Result<()> {
+            let table_clusters = div_round_up_u64(snapshot_table_size, cluster_size);
+            for i in 0..table_clusters {
+                add_ref(
+                    refcounts,
+                    cluster_size,
+                    header.snapshots_offset + i * cluster_size,
+                )?;
+            }
+            for snapshot in snapshots {
+                let l1_clusters = div_round_up_u64(
+                    u64::from(snapshot.l1_size) * size_of::<u64>() as u64,
+                    cluster_size,
+                );
+                for i in 0..l1_clusters {
+                    add_ref(
+                        refcounts,
+                        cluster_size,
+                        snapshot.l1_table_offset + i * cluster_size,
+                    )?;
+                }
+            }
+            Ok(())
+        }
+
+        // Traverse the L1 table at `l1_table_offset` and its L2 tables to find all reachable data
+        // clusters.
+        fn set_data_refcounts(
+            refcounts: &mut [u16],
+            l1_table_offset: u64,
+            l1_size: u32,
+            cluster_size: u64,
             raw_file: &mut QcowRawFile,
         ) -> Result<()> {
+            let cluster_bits = cluster_size.trailing_zeros();
             let l1_table = raw_file
-                .read_pointer_table(
-                    header.l1_table_offset,
-                    header.l1_size as u64,
-                    Some(L1_TABLE_OFFSET_MASK),
-                )
+                .read_pointer_table(l1_table_offset, l1_size as u64, Some(L1_TABLE_OFFSET_MASK))
                 .map_err(Error::ReadingPointers)?;
-            for l1_index in 0..header.l1_size as usize {
+            for l1_index in 0..l1_size as usize {
                 let l2_addr_disk = *l1_table.get(l1_index).ok_or(Error::InvalidIndex)?;
                 if l2_addr_disk != 0 {
                     // Add a reference to the L2 table cluster itself.
@@ -759,12 +1227,16 @@ impl QcowFile {
                         .read_pointer_table(
                             l2_addr_disk,
                             cluster_size / size_of::<u64>() as u64,
-                            Some(L2_TABLE_OFFSET_MASK),
+                            None,
                         )
                         .map_err(Error::ReadingPointers)?;
-                    for data_cluster_addr in l2_table {
-                        if data_cluster_addr != 0 {
-                            add_ref(refcounts, cluster_size, data_cluster_addr)?;
+                    for entry in l2_table {
+                        if entry & COMPRESSED_FLAG != 0 {
+                            for cluster_addr in compressed_clusters(entry, cluster_bits) {
+                                add_ref(refcounts, cluster_size, cluster_addr)?;
+                            }
+                        } else if entry & L2_TABLE_OFFSET_MASK != 0 {
+                            add_ref(refcounts, cluster_size, entry & L2_TABLE_OFFSET_MASK)?;
                         }
                     }
                 }
@@ -923,7 +1395,29 @@ impl QcowFile {
         // Find all references clusters and rebuild refcounts.
         set_header_refcount(&mut refcounts, cluster_size)?;
         set_l1_refcounts(&mut refcounts, header.clone(), cluster_size)?;
-        set_data_refcounts(&mut refcounts, header.clone(), cluster_size, raw_file)?;
+        set_data_refcounts(
+            &mut refcounts,
+            header.l1_table_offset,
+            header.l1_size,
+            cluster_size,
+            raw_file,
+        )?;
+        set_snapshot_refcounts(
+            &mut refcounts,
+            header.clone(),
+            cluster_size,
+            snapshots,
+            snapshot_table_size,
+        )?;
+        for snapshot in snapshots {
+            set_data_refcounts(
+                &mut refcounts,
+                snapshot.l1_table_offset,
+                snapshot.l1_size,
+                cluster_size,
+                raw_file,
+            )?;
+        }
         set_refcount_table_refcounts(&mut refcounts, header.clone(), cluster_size)?;
 
         // Allocate clusters to store the new reference count blocks.
@@ -980,9 +1474,20 @@ impl QcowFile {
         (address / self.raw_file.cluster_size()) % self.l2_entries
     }
 
-    // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters have
-    // yet to be allocated, return None.
-    fn file_offset_read(&mut self, address: u64) -> std::io::Result<Option<u64>> {
+    // Inserts `l2_table`, the L2 table for L1 entry `l1_index`, into the cache, writing out any
+    // table it evicts.
+    fn cache_l2_table(&mut self, l1_index: usize, l2_table: VecCache<u64>) -> std::io::Result<()> {
+        let l1_table = &self.l1_table;
+        let raw_file = &mut self.raw_file;
+        let refcounts = &mut self.refcounts;
+        self.l2_cache.insert(l1_index, l2_table, |index, evicted| {
+            write_l2_table(raw_file, refcounts, l1_table[index], evicted.get_values())
+        })
+    }
+
+    // Gets the L2 table entry that maps the given guest address. Returns 0 if the L2 table or the
+    // data cluster has yet to be allocated.
+    fn l2_entry(&mut self, address: u64) -> std::io::Result<u64> {
         if address >= self.virtual_size() as u64 {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
@@ -995,7 +1500,7 @@ impl QcowFile {
 
         if l2_addr_disk == 0 {
             // Reading from an unallocated cluster will return zeros.
-            return Ok(None);
+            return Ok(0);
         }
 
         let l2_index = self.l2_table_index(address) as usize;
@@ -1004,27 +1509,15 @@ impl QcowFile {
             // Not in the cache.
             let table =
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?);
-
-            let l1_table = &self.l1_table;
-            let raw_file = &mut self.raw_file;
-            self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
-            })?;
+            self.cache_l2_table(l1_index, table)?;
         };
 
-        let cluster_addr = self.l2_cache.get(&l1_index).unwrap()[l2_index];
-        if cluster_addr == 0 {
-            return Ok(None);
-        }
-        Ok(Some(cluster_addr + self.raw_file.cluster_offset(address)))
+        Ok(self.l2_cache.get(&l1_index).unwrap()[l2_index])
     }
 
     // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters need
-    // to be allocated, they will be.
+    // to be allocated, they will be. Data clusters that are compressed or shared with a snapshot
+    // are copied first, so that writing to them only changes the active image.
     fn file_offset_write(&mut self, address: u64) -> std::io::Result<u64> {
         if address >= self.virtual_size() as u64 {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
@@ -1052,35 +1545,34 @@ impl QcowFile {
             } else {
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?)
             };
-            let l1_table = &self.l1_table;
-            let raw_file = &mut self.raw_file;
-            self.l2_cache.insert(l1_index, l2_table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
-            })?;
+            self.cache_l2_table(l1_index, l2_table)?;
         }
 
-        let cluster_addr = match self.l2_cache.get(&l1_index).unwrap()[l2_index] {
-            0 => {
-                let initial_data = if let Some(backing) = self.backing_file.as_mut() {
-                    let cluster_size = self.raw_file.cluster_size();
-                    let cluster_begin = address - (address % cluster_size);
-                    let mut cluster_data = vec![0u8; cluster_size as usize];
-                    let volatile_slice = VolatileSlice::new(&mut cluster_data);
-                    backing.read_exact_at_volatile(volatile_slice, cluster_begin)?;
-                    Some(cluster_data)
-                } else {
-                    None
-                };
-                // Need to allocate a data cluster
-                let cluster_addr = self.append_data_cluster(initial_data)?;
-                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
-                cluster_addr
-            }
-            a => a,
+        let entry = self.l2_cache.get(&l1_index).unwrap()[l2_index];
+        let cluster_addr = if entry == 0 {
+            let initial_data = if let Some(backing) = self.backing_file.as_mut() {
+                let cluster_size = self.raw_file.cluster_size();
+                let cluster_begin = address - (address % cluster_size);
+                let mut cluster_data = vec![0u8; cluster_size as usize];
+                let volatile_slice = VolatileSlice::new(&mut cluster_data);
+                backing.read_exact_at_volatile(volatile_slice, cluster_begin)?;
+                Some(cluster_data)
+            } else {
+                None
+            };
+            // Need to allocate a data cluster
+            let cluster_addr = self.append_data_cluster(initial_data)?;
+            self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+            cluster_addr
+        } else if entry & COMPRESSED_FLAG != 0 || self.cluster_refcount(entry)? > 1 {
+            // The cluster can't be written in place, give the active image its own copy.
+            let cluster_data = self.read_cluster_data(entry)?;
+            let cluster_addr = self.append_data_cluster(Some(cluster_data))?;
+            self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+            self.update_data_refcount(entry, false)?;
+            cluster_addr
+        } else {
+            entry
         };
 
         for (addr, count) in set_refcounts {
@@ -1100,14 +1592,18 @@ impl QcowFile {
         set_refcounts: &mut Vec<(u64, u16)>,
     ) -> io::Result<()> {
         if !self.l2_cache.get(&l1_index).unwrap().dirty() {
-            // Free the previously used cluster if one exists. Modified tables are always
-            // witten to new clusters so the L1 table can be committed to disk after they
-            // are and L1 never points at an invalid table.
+            // Drop the reference to the previously used cluster if one exists. Modified tables are
+            // always witten to new clusters so the L1 table can be committed to disk after they
+            // are and L1 never points at an invalid table. The old table is only freed if no
+            // snapshot is still using it.
             // The index must be valid from when it was insterted.
             let addr = self.l1_table[l1_index];
             if addr != 0 {
-                self.unref_clusters.push(addr);
-                set_refcounts.push((addr, 0));
+                let refcount = self.cluster_refcount(addr)?;
+                if refcount <= 1 {
+                    self.unref_clusters.push(addr);
+                }
+                set_refcounts.push((addr, refcount.saturating_sub(1)));
             }
 
             // Allocate a new cluster to store the L2 table and update the L1 table to point
@@ -1158,74 +1654,61 @@ impl QcowFile {
         Ok(new_addr)
     }
 
+    // Allocates `count` contiguous clusters at the end of the file, each with a refcount of one,
+    // and returns the address of the first. Used for tables that may span several clusters.
+    fn append_clusters(&mut self, count: u64) -> std::io::Result<u64> {
+        let max_valid_cluster_offset = self.refcounts.max_valid_cluster_offset();
+        let mut clusters = Vec::new();
+        for _ in 0..count {
+            match self.raw_file.add_cluster_end(max_valid_cluster_offset)? {
+                Some(new_cluster) => clusters.push(new_cluster),
+                None => {
+                    error!("No free clusters in append_clusters()");
+                    return Err(std::io::Error::from_raw_os_error(ENOSPC));
+                }
+            }
+        }
+        for &addr in &clusters {
+            let mut newly_unref = self.set_cluster_refcount(addr, 1)?;
+            self.unref_clusters.append(&mut newly_unref);
+        }
+        clusters
+            .first()
+            .copied()
+            .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))
+    }
+
     // Deallocate the storage for the cluster starting at `address`.
     // Any future reads of this cluster will return all zeroes (or the backing file, if in use).
     fn deallocate_cluster(&mut self, address: u64) -> std::io::Result<()> {
-        if address >= self.virtual_size() as u64 {
-            return Err(std::io::Error::from_raw_os_error(EINVAL));
+        let entry = self.l2_entry(address)?;
+        if entry == 0 {
+            // This cluster is already unallocated; nothing to do.
+            return Ok(());
         }
 
+        // Rewrite the L2 entry to remove the cluster mapping. The L2 table may be shared with a
+        // snapshot, so this goes through the same path as writes.
         let l1_index = self.l1_table_index(address) as usize;
-        let l2_addr_disk = *self
-            .l1_table
-            .get(l1_index)
-            .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?;
         let l2_index = self.l2_table_index(address) as usize;
-
-        if l2_addr_disk == 0 {
-            // The whole L2 table for this address is not allocated yet,
-            // so the cluster must also be unallocated.
-            return Ok(());
+        let mut set_refcounts = Vec::new();
+        self.update_cluster_addr(l1_index, l2_index, 0, &mut set_refcounts)?;
+        for (addr, count) in set_refcounts {
+            let mut newly_unref = self.set_cluster_refcount(addr, count)?;
+            self.unref_clusters.append(&mut newly_unref);
         }
 
-        if !self.l2_cache.contains_key(&l1_index) {
-            // Not in the cache.
-            let table =
-                VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?);
-            let l1_table = &self.l1_table;
-            let raw_file = &mut self.raw_file;
-            self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
-            })?;
-        }
-
-        let cluster_addr = self.l2_cache.get(&l1_index).unwrap()[l2_index];
-        if cluster_addr == 0 {
-            // This cluster is already unallocated; nothing to do.
-            return Ok(());
+        if entry & COMPRESSED_FLAG != 0 {
+            return self.update_data_refcount(entry, false);
         }
 
         // Decrement the refcount.
-        let refcount = self
-            .refcounts
-            .get_cluster_refcount(&mut self.raw_file, cluster_addr)
-            .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
-        if refcount == 0 {
-            return Err(std::io::Error::from_raw_os_error(EINVAL));
-        }
-
-        let new_refcount = refcount - 1;
-        let mut newly_unref = self.set_cluster_refcount(cluster_addr, new_refcount)?;
-        self.unref_clusters.append(&mut newly_unref);
-
-        // Rewrite the L2 entry to remove the cluster mapping.
-        // unwrap is safe as we just checked/inserted this entry.
-        self.l2_cache.get_mut(&l1_index).unwrap()[l2_index] = 0;
-
-        if new_refcount == 0 {
+        if self.unref_cluster(entry)? == 0 {
             let cluster_size = self.raw_file.cluster_size();
             // This cluster is no longer in use; deallocate the storage.
             // The underlying FS may not support FALLOC_FL_PUNCH_HOLE,
             // so don't treat an error as fatal.  Future reads will return zeros anyways.
-            let _ = self
-                .raw_file
-                .file_mut()
-                .punch_hole(cluster_addr, cluster_size);
-            self.unref_clusters.push(cluster_addr);
+            let _ = self.raw_file.file_mut().punch_hole(entry, cluster_size);
         }
         Ok(())
     }
@@ -1246,15 +1729,16 @@ impl QcowFile {
                 self.deallocate_cluster(curr_addr)?;
             } else {
                 // Partial cluster - zero out the relevant bytes.
-                let offset = if self.backing_file.is_some() {
+                let offset = if self.backing_file.is_some() || self.l2_entry(curr_addr)? != 0 {
                     // There is a backing file, so we need to allocate a cluster in order to
                     // zero out the hole-punched bytes such that the backing file contents do not
-                    // show through.
+                    // show through. Allocated clusters may be compressed or shared with a
+                    // snapshot, in which case they are copied before being zeroed.
                     Some(self.file_offset_write(curr_addr)?)
                 } else {
                     // Any space in unallocated clusters can be left alone, since
                     // unallocated clusters already read back as zeroes.
-                    self.file_offset_read(curr_addr)?
+                    None
                 };
                 if let Some(offset) = offset {
                     // Partial cluster - zero it out.
@@ -1269,19 +1753,128 @@ impl QcowFile {
         Ok(())
     }
 
-    // Reads an L2 cluster from the disk, returning an error if the file can't be read or if any
-    // cluster is compressed.
+    // Reads an L2 cluster from the disk, returning an error if the file can't be read. Compressed
+    // cluster descriptors are kept whole, the other entries are reduced to their cluster address.
     fn read_l2_cluster(raw_file: &mut QcowRawFile, cluster_addr: u64) -> std::io::Result<Vec<u64>> {
         let file_values = raw_file.read_pointer_cluster(cluster_addr, None)?;
-        if file_values.iter().any(|entry| entry & COMPRESSED_FLAG != 0) {
-            return Err(std::io::Error::from_raw_os_error(ENOTSUP));
-        }
         Ok(file_values
             .iter()
-            .map(|entry| *entry & L2_TABLE_OFFSET_MASK)
+            .map(|entry| {
+                if entry & COMPRESSED_FLAG != 0 {
+                    *entry & !CLUSTER_USED_FLAG
+                } else {
+                    *entry & L2_TABLE_OFFSET_MASK
+                }
+            })
             .collect())
     }
 
+    // Returns the contents of the compressed cluster described by the L2 entry `entry`.
+    fn decompress_cluster(&mut self, entry: u64) -> std::io::Result<&[u8]> {
+        let cached =
+            matches!(&self.decompressed_cluster, Some((cached_entry, _)) if *cached_entry == entry);
+        if !cached {
+            let (offset, len) = compressed_cluster_range(entry, self.header.cluster_bits);
+            let cluster_size = self.raw_file.cluster_size();
+            let file = self.raw_file.file_mut();
+            // The length is rounded up to whole sectors, so it can run past the end of the file.
+            let file_size = file.metadata()?.len();
+            let mut compressed = vec![0u8; min(len, file_size.saturating_sub(offset)) as usize];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut compressed)?;
+            let mut cluster = vec![0u8; cluster_size as usize];
+            DeflateDecoder::new(&compressed[..]).read_exact(&mut cluster)?;
+            self.decompressed_cluster = Some((entry, cluster));
+        }
+        // 'unwrap' is OK because the cluster was either cached already or just decompressed.
+        Ok(&self.decompressed_cluster.as_ref().unwrap().1)
+    }
+
+    // Reads the guest data held by the data cluster that the L2 entry `entry` points to.
+    fn read_cluster_data(&mut self, entry: u64) -> std::io::Result<Vec<u8>> {
+        if entry & COMPRESSED_FLAG != 0 {
+            return Ok(self.decompress_cluster(entry)?.to_vec());
+        }
+        let mut cluster_data = vec![0u8; self.raw_file.cluster_size() as usize];
+        self.raw_file
+            .file_mut()
+            .read_exact_at_volatile(VolatileSlice::new(&mut cluster_data), entry)?;
+        Ok(cluster_data)
+    }
+
+    // Gets the refcount of the host cluster at `address`.
+    fn cluster_refcount(&mut self, address: u64) -> std::io::Result<u16> {
+        self.refcounts
+            .get_cluster_refcount(&mut self.raw_file, address)
+            .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))
+    }
+
+    // Adds a reference to the host cluster at `address`.
+    fn ref_cluster(&mut self, address: u64) -> std::io::Result<()> {
+        let refcount = self
+            .cluster_refcount(address)?
+            .checked_add(1)
+            .ok_or_else(|| std::io::Error::from_raw_os_error(EOVERFLOW))?;
+        let mut newly_unref = self.set_cluster_refcount(address, refcount)?;
+        self.unref_clusters.append(&mut newly_unref);
+        Ok(())
+    }
+
+    // Drops a reference to the host cluster at `address` and returns its new refcount. Clusters
+    // that are no longer referenced are queued for reuse.
+    fn unref_cluster(&mut self, address: u64) -> std::io::Result<u16> {
+        let refcount = self.cluster_refcount(address)?;
+        if refcount == 0 {
+            return Err(std::io::Error::from_raw_os_error(EINVAL));
+        }
+        let mut newly_unref = self.set_cluster_refcount(address, refcount - 1)?;
+        self.unref_clusters.append(&mut newly_unref);
+        if refcount == 1 {
+            self.unref_clusters.push(address);
+        }
+        Ok(refcount - 1)
+    }
+
+    // Adds (or, if `increment` is false, drops) the references that the L2 entry `entry` holds on
+    // the host clusters storing its data.
+    fn update_data_refcount(&mut self, entry: u64, increment: bool) -> std::io::Result<()> {
+        if entry & COMPRESSED_FLAG == 0 {
+            return if increment {
+                self.ref_cluster(entry)
+            } else {
+                self.unref_cluster(entry).map(|_| ())
+            };
+        }
+        for addr in compressed_clusters(entry, self.header.cluster_bits) {
+            if increment {
+                self.ref_cluster(addr)?;
+            } else {
+                self.unref_cluster(addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Adds (or, if `increment` is false, drops) a reference to every L2 table listed in `l1_table`
+    // and to every data cluster those tables point to. This is how an L1 table takes or gives up
+    // its share of the image. L2 tables gaining a reference are rewritten so that none of their
+    // entries are still flagged as safe to write in place.
+    fn update_l1_refcounts(&mut self, l1_table: &[u64], increment: bool) -> std::io::Result<()> {
+        for &l2_addr in l1_table.iter().filter(|addr| **addr != 0) {
+            let l2_table = Self::read_l2_cluster(&mut self.raw_file, l2_addr)?;
+            for &entry in l2_table.iter().filter(|entry| **entry != 0) {
+                self.update_data_refcount(entry, increment)?;
+            }
+            if increment {
+                self.ref_cluster(l2_addr)?;
+                write_l2_table(&mut self.raw_file, &mut self.refcounts, l2_addr, &l2_table)?;
+            } else {
+                self.unref_cluster(l2_addr)?;
+            }
+        }
+        Ok(())
+    }
+
     // Set the refcount for a cluster with the given address.
     // Returns a list of any refblocks that can be reused, this happens when a refblock is moved,
     // the old location can be reused.
@@ -1345,10 +1938,11 @@ impl QcowFile {
             // The index must be valid from when we insterted it.
             let addr = self.l1_table[*l1_index];
             if addr != 0 {
-                self.raw_file.write_pointer_table(
+                write_l2_table(
+                    &mut self.raw_file,
+                    &mut self.refcounts,
                     addr,
                     l2_table.get_values(),
-                    CLUSTER_USED_FLAG,
                 )?;
             } else {
                 return Err(std::io::Error::from_raw_os_error(EINVAL));
@@ -1380,27 +1974,35 @@ impl QcowFile {
     }
 
     // Reads `count` bytes starting at `address`, calling `cb` repeatedly with the data source,
-    // number of bytes read so far, offset to read from, and number of bytes to read from the file
-    // in that invocation. If None is given to `cb` in place of the backing file, the `cb` should
-    // infer zeros would have been read.
+    // number of bytes read so far, and number of bytes to read from the source in that invocation.
     fn read_cb<F>(&mut self, address: u64, count: usize, mut cb: F) -> std::io::Result<usize>
     where
-        F: FnMut(Option<&mut dyn DiskFile>, usize, u64, usize) -> std::io::Result<()>,
+        F: FnMut(ReadSource, usize, usize) -> std::io::Result<()>,
     {
         let read_count: usize = self.limit_range_file(address, count);
 
         let mut nread: usize = 0;
         while nread < read_count {
             let curr_addr = address + nread as u64;
-            let file_offset = self.file_offset_read(curr_addr)?;
+            let entry = self.l2_entry(curr_addr)?;
             let count = self.limit_range_cluster(curr_addr, read_count - nread);
-
-            if let Some(offset) = file_offset {
-                cb(Some(self.raw_file.file_mut()), nread, offset, count)?;
+            let cluster_offset = self.raw_file.cluster_offset(curr_addr);
+
+            if entry & COMPRESSED_FLAG != 0 {
+                let start = cluster_offset as usize;
+                let cluster = self.decompress_cluster(entry)?;
+                cb(
+                    ReadSource::Buffer(&cluster[start..start + count]),
+                    nread,
+                    count,
+                )?;
+            } else if entry != 0 {
+                let source = ReadSource::File(self.raw_file.file_mut(), entry + cluster_offset);
+                cb(source, nread, count)?;
             } else if let Some(backing) = self.backing_file.as_mut() {
-                cb(Some(backing.as_mut()), nread, curr_addr, count)?;
+                cb(ReadSource::File(backing.as_mut(), curr_addr), nread, count)?;
             } else {
-                cb(None, nread, 0, count)?;
+                cb(ReadSource::Zeroes, nread, count)?;
             }
 
             nread += count;
@@ -1451,20 +2053,10 @@ impl Read for QcowFile {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let len = buf.len();
         let slice = VolatileSlice::new(buf);
-        let read_count = self.read_cb(
-            self.current_offset,
-            len,
-            |file, already_read, offset, count| {
-                let sub_slice = slice.get_slice(already_read, count).unwrap();
-                match file {
-                    Some(f) => f.read_exact_at_volatile(sub_slice, offset),
-                    None => {
-                        sub_slice.write_bytes(0);
-                        Ok(())
-                    }
-                }
-            },
-        )?;
+        let read_count =
+            self.read_cb(self.current_offset, len, |source, already_read, count| {
+                source.read_into(slice.get_slice(already_read, count).unwrap())
+            })?;
         self.current_offset += read_count as u64;
         Ok(read_count)
     }
@@ -1523,15 +2115,8 @@ impl Write for QcowFile {
 
 impl FileReadWriteAtVolatile for QcowFile {
     fn read_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
-        self.read_cb(offset, slice.size(), |file, read, offset, count| {
-            let sub_slice = slice.get_slice(read, count).unwrap();
-            match file {
-                Some(f) => f.read_exact_at_volatile(sub_slice, offset),
-                None => {
-                    sub_slice.write_bytes(0);
-                    Ok(())
-                }
-            }
+        self.read_cb(offset, slice.size(), |source, read, count| {
+            source.read_into(slice.get_slice(read, count).unwrap())
         })
     }
 
@@ -1600,6 +2185,36 @@ impl WriteZeroesAt for QcowFile {
     }
 }
 
+impl DiskSnapshots for QcowFile {
+    fn list_snapshots(&self) -> crate::Result<Vec<DiskSnapshotInfo>> {
+        Ok(self
+            .snapshots
+            .iter()
+            .map(|s| DiskSnapshotInfo {
+                id: s.id.clone(),
+                name: s.name.clone(),
+                date_sec: s.date_sec,
+                date_nsec: s.date_nsec,
+                disk_size: s.disk_size,
+            })
+            .collect())
+    }
+
+    fn create_snapshot(&mut self, name: &str) -> crate::Result<()> {
+        self.save_snapshot(name).map_err(crate::Error::QcowError)
+    }
+
+    fn apply_snapshot(&mut self, snapshot: &str) -> crate::Result<()> {
+        self.revert_to_snapshot(snapshot)
+            .map_err(crate::Error::QcowError)
+    }
+
+    fn delete_snapshot(&mut self, snapshot: &str) -> crate::Result<()> {
+        self.remove_snapshot(snapshot)
+            .map_err(crate::Error::QcowError)
+    }
+}
+
 impl ToAsyncDisk for QcowFile {
     fn to_async_disk(self: Box<Self>, ex: &Executor) -> crate::Result<Box<dyn AsyncDisk>> {
         Ok(Box::new(AsyncDiskFileWrapper::new(*self, ex)))
@@ -1632,6 +2247,8 @@ mod tests {
     use std::io::SeekFrom;
     use std::io::Write;
 
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
     use tempfile::tempfile;
     use tempfile::TempDir;
 
@@ -2419,6 +3036,134 @@ mod tests {
         });
     }
 
+    #[test]
+    fn read_compressed_cluster() {
+        let cluster_size = 1usize << DEFAULT_CLUSTER_BITS;
+        let data: Vec<u8> = (0..cluster_size).map(|i| (i % 251) as u8).collect();
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut file = tempfile().expect("failed to create temp file");
+        let (l2_addr, compressed_addr) = {
+            let mut q = QcowFile::new(file.try_clone().unwrap(), 0x10_0000).unwrap();
+            // Allocate the L2 table, then a cluster to hold the compressed data.
+            write_all_at(&mut q, &vec![0x55u8; cluster_size], 0).expect("Failed to write.");
+            q.fsync().unwrap();
+            (q.l1_table[0], q.append_clusters(1).unwrap())
+        };
+
+        // Map the second guest cluster to the compressed data.
+        let offset_bits = 62 - (DEFAULT_CLUSTER_BITS - 8);
+        let additional_sectors =
+            div_round_up_u64(compressed.len() as u64, COMPRESSED_SECTOR_SIZE) - 1;
+        let entry = COMPRESSED_FLAG | (additional_sectors << offset_bits) | compressed_addr;
+        file.seek(SeekFrom::Start(compressed_addr)).unwrap();
+        file.write_all(&compressed).unwrap();
+        file.seek(SeekFrom::Start(l2_addr + size_of::<u64>() as u64))
+            .unwrap();
+        file.write_all(&entry.to_be_bytes()).unwrap();
+        file.rewind().unwrap();
+
+        let mut q = QcowFile::from(file, MAX_NESTING_DEPTH).expect("Failed to open qcow file.");
+        let mut readback = vec![0u8; cluster_size];
+        read_exact_at(&mut q, &mut readback, cluster_size as u64).expect("Failed to read.");
+        assert_eq!(readback, data);
+
+        // A read spanning the end of the standard cluster and the start of the compressed one.
+        read_exact_at(&mut q, &mut readback[..1024], cluster_size as u64 - 512)
+            .expect("Failed to read.");
+        assert_eq!(readback[..512], [0x55u8; 512]);
+        assert_eq!(readback[512..1024], data[..512]);
+
+        // Writing to the compressed cluster gives it an uncompressed copy.
+        write_all_at(&mut q, &[0xaau8; 512], cluster_size as u64 + 512).expect("Failed to write.");
+        read_exact_at(&mut q, &mut readback, cluster_size as u64).expect("Failed to read.");
+        assert_eq!(readback[..512], data[..512]);
+        assert_eq!(readback[512..1024], [0xaau8; 512]);
+        assert_eq!(readback[1024..], data[1024..]);
+    }
+
+    #[test]
+    fn snapshot_create_apply_delete() {
+        with_default_file(0x100_0000, |mut q| {
+            let mut readback = [0u8; 512];
+            write_all_at(&mut q, &[0x55u8; 512], 0).expect("Failed to write.");
+            q.create_snapshot("base")
+                .expect("Failed to create snapshot.");
+            let snapshots = q.list_snapshots().unwrap();
+            assert_eq!(snapshots.len(), 1);
+            assert_eq!(snapshots[0].id, "1");
+            assert_eq!(snapshots[0].name, "base");
+            assert_eq!(snapshots[0].disk_size, 0x100_0000);
+            q.create_snapshot("base")
+                .expect_err("Created snapshot with a duplicate name.");
+
+            // Writes after the snapshot only change the active image.
+            write_all_at(&mut q, &[0xaau8; 512], 0).expect("Failed to write.");
+            write_all_at(&mut q, &[0xaau8; 512], 0x80_0000).expect("Failed to write.");
+            read_exact_at(&mut q, &mut readback, 0).expect("Failed to read.");
+            assert_eq!(readback, [0xaau8; 512]);
+
+            q.apply_snapshot("base").expect("Failed to apply snapshot.");
+            read_exact_at(&mut q, &mut readback, 0).expect("Failed to read.");
+            assert_eq!(readback, [0x55u8; 512]);
+            read_exact_at(&mut q, &mut readback, 0x80_0000).expect("Failed to read.");
+            assert_eq!(readback, [0u8; 512]);
+
+            // Snapshots can also be referred to by ID.
+            write_all_at(&mut q, &[0xaau8; 512], 0).expect("Failed to write.");
+            q.apply_snapshot("1").expect("Failed to apply snapshot.");
+            read_exact_at(&mut q, &mut readback, 0).expect("Failed to read.");
+            assert_eq!(readback, [0x55u8; 512]);
+
+            q.delete_snapshot("base")
+                .expect("Failed to delete snapshot.");
+            assert!(q.list_snapshots().unwrap().is_empty());
+            q.apply_snapshot("base")
+                .expect_err("Applied a deleted snapshot.");
+            read_exact_at(&mut q, &mut readback, 0).expect("Failed to read.");
+            assert_eq!(readback, [0x55u8; 512]);
+        });
+    }
+
+    #[test]
+    fn snapshot_reopen() {
+        let file = tempfile().expect("failed to create temp file");
+        let mut readback = [0u8; 512];
+        {
+            let mut q = QcowFile::new(file.try_clone().unwrap(), 0x100_0000).unwrap();
+            write_all_at(&mut q, &[0x55u8; 512], 0).expect("Failed to write.");
+            q.create_snapshot("first")
+                .expect("Failed to create snapshot.");
+            write_all_at(&mut q, &[0xaau8; 512], 0).expect("Failed to write.");
+            q.create_snapshot("second")
+                .expect("Failed to create snapshot.");
+            write_all_at(&mut q, &[0x33u8; 512], 0).expect("Failed to write.");
+        }
+
+        let mut q = QcowFile::from(file, MAX_NESTING_DEPTH).expect("Failed to open qcow file.");
+        let snapshots = q.list_snapshots().unwrap();
+        assert_eq!(
+            snapshots
+                .iter()
+                .map(|s| (s.id.as_str(), s.name.as_str()))
+                .collect::<Vec<_>>(),
+            [("1", "first"), ("2", "second")]
+        );
+        read_exact_at(&mut q, &mut readback, 0).expect("Failed to read.");
+        assert_eq!(readback, [0x33u8; 512]);
+
+        q.apply_snapshot("first")
+            .expect("Failed to apply snapshot.");
+        read_exact_at(&mut q, &mut readback, 0).expect("Failed to read.");
+        assert_eq!(readback, [0x55u8; 512]);
+        q.apply_snapshot("second")
+            .expect("Failed to apply snapshot.");
+        read_exact_at(&mut q, &mut readback, 0).expect("Failed to read.");
+        assert_eq!(readback, [0xaau8; 512]);
+    }
+
     #[test]
     fn rebuild_refcounts() {
         with_basic_file(&valid_header(), |mut disk_file: File| {
@@ -2426,7 +3171,7 @@ mod tests {
             let cluster_size = 65536;
             let mut raw_file =
                 QcowRawFile::from(disk_file, cluster_size).expect("Failed to create QcowRawFile.");
-            QcowFile::rebuild_refcounts(&mut raw_file, header)
+            QcowFile::rebuild_refcounts(&mut raw_file, header, &[], 0)
                 .expect("Failed to rebuild recounts.");
         });
     }
@@ -0,0 +1,1077 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Display backend serving the scanout over the remote framebuffer (RFB) protocol used by VNC
+//! clients.
+//!
+//! The server has no authentication, and clients are expected to connect through a unix socket
+//! or a TCP address that is only reachable by trusted users. Only the raw encoding is supported,
+//! which is good enough for local clients. Key events are translated from the keysyms of a US
+//! layout, and the left button of the pointer is reported as touches of the touchscreen.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Duration;
+
+use base::error;
+use base::AsRawDescriptor;
+use base::EventToken;
+use base::RawDescriptor;
+use base::WaitContext;
+use data_model::VolatileSlice;
+use linux_input_sys::virtio_input_event;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::DisplayT;
+use crate::EventDeviceKind;
+use crate::GpuDisplayEvents;
+use crate::GpuDisplayFramebuffer;
+use crate::GpuDisplayResult;
+use crate::GpuDisplaySurface;
+use crate::SurfaceType;
+use crate::SysDisplayT;
+
+const PROTOCOL_VERSION: &[u8; 12] = b"RFB 003.008\n";
+const SECURITY_TYPE_NONE: u8 = 1;
+const DESKTOP_NAME: &str = "crosvm";
+
+// Client to server messages.
+const SET_PIXEL_FORMAT: u8 = 0;
+const SET_ENCODINGS: u8 = 2;
+const FRAMEBUFFER_UPDATE_REQUEST: u8 = 3;
+const KEY_EVENT: u8 = 4;
+const POINTER_EVENT: u8 = 5;
+const CLIENT_CUT_TEXT: u8 = 6;
+
+// Server to client messages.
+const FRAMEBUFFER_UPDATE: u8 = 0;
+
+const ENCODING_RAW: i32 = 0;
+// Pseudo-encoding telling the client that the framebuffer was resized.
+const ENCODING_DESKTOP_SIZE: i32 = -223;
+
+// Largest cut text accepted from a client, which is otherwise ignored.
+const MAX_CUT_TEXT_LENGTH: usize = 1 << 20;
+// Clients that do not accept a framebuffer update in this time are disconnected.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Bytes per pixel of the surfaces, in the XRGB8888 format.
+const BYTES_PER_PIXEL: u32 = 4;
+
+const POINTER_BUTTON_LEFT: u8 = 1;
+
+// Linux key codes of the X keysyms sent by the clients, for a US keyboard layout. Upper case
+// letters are looked up as lower case letters.
+const KEYSYMS: &[(u32, u16)] = &[
+    (0x0020, 57),  // space
+    (0x0021, 2),   // exclam
+    (0x0022, 40),  // quotedbl
+    (0x0023, 4),   // numbersign
+    (0x0024, 5),   // dollar
+    (0x0025, 6),   // percent
+    (0x0026, 8),   // ampersand
+    (0x0027, 40),  // apostrophe
+    (0x0028, 10),  // parenleft
+    (0x0029, 11),  // parenright
+    (0x002a, 9),   // asterisk
+    (0x002b, 13),  // plus
+    (0x002c, 51),  // comma
+    (0x002d, 12),  // minus
+    (0x002e, 52),  // period
+    (0x002f, 53),  // slash
+    (0x0030, 11),  // 0
+    (0x0031, 2),   // 1
+    (0x0032, 3),   // 2
+    (0x0033, 4),   // 3
+    (0x0034, 5),   // 4
+    (0x0035, 6),   // 5
+    (0x0036, 7),   // 6
+    (0x0037, 8),   // 7
+    (0x0038, 9),   // 8
+    (0x0039, 10),  // 9
+    (0x003a, 39),  // colon
+    (0x003b, 39),  // semicolon
+    (0x003c, 51),  // less
+    (0x003d, 13),  // equal
+    (0x003e, 52),  // greater
+    (0x003f, 53),  // question
+    (0x0040, 3),   // at
+    (0x005b, 26),  // bracketleft
+    (0x005c, 43),  // backslash
+    (0x005d, 27),  // bracketright
+    (0x005e, 7),   // asciicircum
+    (0x005f, 12),  // underscore
+    (0x0060, 41),  // grave
+    (0x0061, 30),  // a
+    (0x0062, 48),  // b
+    (0x0063, 46),  // c
+    (0x0064, 32),  // d
+    (0x0065, 18),  // e
+    (0x0066, 33),  // f
+    (0x0067, 34),  // g
+    (0x0068, 35),  // h
+    (0x0069, 23),  // i
+    (0x006a, 36),  // j
+    (0x006b, 37),  // k
+    (0x006c, 38),  // l
+    (0x006d, 50),  // m
+    (0x006e, 49),  // n
+    (0x006f, 24),  // o
+    (0x0070, 25),  // p
+    (0x0071, 16),  // q
+    (0x0072, 19),  // r
+    (0x0073, 31),  // s
+    (0x0074, 20),  // t
+    (0x0075, 22),  // u
+    (0x0076, 47),  // v
+    (0x0077, 17),  // w
+    (0x0078, 45),  // x
+    (0x0079, 21),  // y
+    (0x007a, 44),  // z
+    (0x007b, 26),  // braceleft
+    (0x007c, 43),  // bar
+    (0x007d, 27),  // braceright
+    (0x007e, 41),  // asciitilde
+    (0xfe20, 15),  // ISO_Left_Tab
+    (0xff08, 14),  // BackSpace
+    (0xff09, 15),  // Tab
+    (0xff0d, 28),  // Return
+    (0xff13, 119), // Pause
+    (0xff14, 70),  // Scroll_Lock
+    (0xff1b, 1),   // Escape
+    (0xff50, 102), // Home
+    (0xff51, 105), // Left
+    (0xff52, 103), // Up
+    (0xff53, 106), // Right
+    (0xff54, 108), // Down
+    (0xff55, 104), // Page_Up
+    (0xff56, 109), // Page_Down
+    (0xff57, 107), // End
+    (0xff61, 99),  // Print
+    (0xff63, 110), // Insert
+    (0xff67, 127), // Menu
+    (0xff7f, 69),  // Num_Lock
+    (0xff8d, 96),  // KP_Enter
+    (0xffaa, 55),  // KP_Multiply
+    (0xffab, 78),  // KP_Add
+    (0xffad, 74),  // KP_Subtract
+    (0xffae, 83),  // KP_Decimal
+    (0xffaf, 98),  // KP_Divide
+    (0xffb0, 82),  // KP_0
+    (0xffb1, 79),  // KP_1
+    (0xffb2, 80),  // KP_2
+    (0xffb3, 81),  // KP_3
+    (0xffb4, 75),  // KP_4
+    (0xffb5, 76),  // KP_5
+    (0xffb6, 77),  // KP_6
+    (0xffb7, 71),  // KP_7
+    (0xffb8, 72),  // KP_8
+    (0xffb9, 73),  // KP_9
+    (0xffbe, 59),  // F1
+    (0xffbf, 60),  // F2
+    (0xffc0, 61),  // F3
+    (0xffc1, 62),  // F4
+    (0xffc2, 63),  // F5
+    (0xffc3, 64),  // F6
+    (0xffc4, 65),  // F7
+    (0xffc5, 66),  // F8
+    (0xffc6, 67),  // F9
+    (0xffc7, 68),  // F10
+    (0xffc8, 87),  // F11
+    (0xffc9, 88),  // F12
+    (0xffe1, 42),  // Shift_L
+    (0xffe2, 54),  // Shift_R
+    (0xffe3, 29),  // Control_L
+    (0xffe4, 97),  // Control_R
+    (0xffe5, 58),  // Caps_Lock
+    (0xffe7, 125), // Meta_L
+    (0xffe8, 126), // Meta_R
+    (0xffe9, 56),  // Alt_L
+    (0xffea, 100), // Alt_R
+    (0xffeb, 125), // Super_L
+    (0xffec, 126), // Super_R
+    (0xffff, 111), // Delete
+];
+
+/// Translates an X keysym into a Linux key code.
+fn keysym_to_keycode(keysym: u32) -> Option<u16> {
+    let keysym = match keysym {
+        0x41..=0x5a => keysym + 0x20,
+        _ => keysym,
+    };
+    KEYSYMS
+        .iter()
+        .find(|(k, _)| *k == keysym)
+        .map(|(_, code)| *code)
+}
+
+/// Address of the socket on which the VNC clients connect.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VncAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for VncAddress {
+    type Err = String;
+
+    /// Parses a `host:port` TCP address, or the path of a unix socket otherwise.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("empty VNC address".to_string());
+        }
+        Ok(match s.parse() {
+            Ok(addr) => VncAddress::Tcp(addr),
+            Err(_) => VncAddress::Unix(PathBuf::from(s)),
+        })
+    }
+}
+
+impl fmt::Display for VncAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VncAddress::Tcp(addr) => write!(f, "{}", addr),
+            VncAddress::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// A listening socket for VNC clients, which can be created before the display is opened.
+pub enum VncListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl VncListener {
+    /// Listens for VNC clients on `address`.
+    pub fn bind(address: &VncAddress) -> io::Result<VncListener> {
+        Ok(match address {
+            VncAddress::Tcp(addr) => VncListener::Tcp(TcpListener::bind(addr)?),
+            VncAddress::Unix(path) => VncListener::Unix(UnixListener::bind(path)?),
+        })
+    }
+
+    pub fn try_clone(&self) -> io::Result<VncListener> {
+        Ok(match self {
+            VncListener::Tcp(listener) => VncListener::Tcp(listener.try_clone()?),
+            VncListener::Unix(listener) => VncListener::Unix(listener.try_clone()?),
+        })
+    }
+
+    fn accept(&self) -> io::Result<VncStream> {
+        let stream = match self {
+            VncListener::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                VncStream::Tcp(stream)
+            }
+            VncListener::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                VncStream::Unix(stream)
+            }
+        };
+        match &stream {
+            VncStream::Tcp(stream) => stream.set_write_timeout(Some(WRITE_TIMEOUT))?,
+            VncStream::Unix(stream) => stream.set_write_timeout(Some(WRITE_TIMEOUT))?,
+        }
+        Ok(stream)
+    }
+}
+
+impl AsRawDescriptor for VncListener {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        match self {
+            VncListener::Tcp(listener) => listener.as_raw_fd(),
+            VncListener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+enum VncStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Read for VncStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            VncStream::Tcp(stream) => stream.read(buf),
+            VncStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for VncStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            VncStream::Tcp(stream) => stream.write(buf),
+            VncStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawDescriptor for VncStream {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        match self {
+            VncStream::Tcp(stream) => stream.as_raw_fd(),
+            VncStream::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+/// Format of the pixels sent to a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct PixelFormat {
+    bits_per_pixel: u8,
+    depth: u8,
+    big_endian: bool,
+    true_colour: bool,
+    red_max: u16,
+    green_max: u16,
+    blue_max: u16,
+    red_shift: u8,
+    green_shift: u8,
+    blue_shift: u8,
+}
+
+impl PixelFormat {
+    /// The XRGB8888 format of the surfaces.
+    const XRGB8888: PixelFormat = PixelFormat {
+        bits_per_pixel: 32,
+        depth: 24,
+        big_endian: false,
+        true_colour: true,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+
+    fn from_bytes(b: &[u8]) -> PixelFormat {
+        PixelFormat {
+            bits_per_pixel: b[0],
+            depth: b[1],
+            big_endian: b[2] != 0,
+            true_colour: b[3] != 0,
+            red_max: u16::from_be_bytes([b[4], b[5]]),
+            green_max: u16::from_be_bytes([b[6], b[7]]),
+            blue_max: u16::from_be_bytes([b[8], b[9]]),
+            red_shift: b[10],
+            green_shift: b[11],
+            blue_shift: b[12],
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 16] {
+        let mut b = [0u8; 16];
+        b[0] = self.bits_per_pixel;
+        b[1] = self.depth;
+        b[2] = self.big_endian as u8;
+        b[3] = self.true_colour as u8;
+        b[4..6].copy_from_slice(&self.red_max.to_be_bytes());
+        b[6..8].copy_from_slice(&self.green_max.to_be_bytes());
+        b[8..10].copy_from_slice(&self.blue_max.to_be_bytes());
+        b[10] = self.red_shift;
+        b[11] = self.green_shift;
+        b[12] = self.blue_shift;
+        b
+    }
+
+    fn is_supported(&self) -> bool {
+        self.true_colour
+            && matches!(self.bits_per_pixel, 8 | 16 | 32)
+            && self.red_shift < 32
+            && self.green_shift < 32
+            && self.blue_shift < 32
+    }
+
+    /// Appends the XRGB8888 `pixels` converted to this format to `out`.
+    fn convert(&self, pixels: &[u8], out: &mut Vec<u8>) {
+        if *self == PixelFormat::XRGB8888 {
+            out.extend_from_slice(pixels);
+            return;
+        }
+        let scale = |value: u8, max: u16| u32::from(value) * u32::from(max) / 255;
+        for pixel in pixels.chunks_exact(BYTES_PER_PIXEL as usize) {
+            let value = scale(pixel[2], self.red_max) << self.red_shift
+                | scale(pixel[1], self.green_max) << self.green_shift
+                | scale(pixel[0], self.blue_max) << self.blue_shift;
+            match (self.bits_per_pixel, self.big_endian) {
+                (8, _) => out.push(value as u8),
+                (16, false) => out.extend_from_slice(&(value as u16).to_le_bytes()),
+                (16, true) => out.extend_from_slice(&(value as u16).to_be_bytes()),
+                (_, false) => out.extend_from_slice(&value.to_le_bytes()),
+                (_, true) => out.extend_from_slice(&value.to_be_bytes()),
+            }
+        }
+    }
+}
+
+/// Content of the scanout shown to the clients.
+struct Frame {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Frame {
+    fn stride(&self) -> usize {
+        (self.width * BYTES_PER_PIXEL) as usize
+    }
+}
+
+enum ClientState {
+    /// Waiting for the protocol version of the client.
+    Version,
+    /// Waiting for the security type chosen by the client, with the minor protocol version.
+    Security { minor: u8 },
+    /// Waiting for the client initialization message.
+    Init,
+    /// Exchanging normal protocol messages.
+    Normal,
+}
+
+struct VncClient {
+    stream: VncStream,
+    state: ClientState,
+    input: Vec<u8>,
+    pixel_format: PixelFormat,
+    desktop_size: bool,
+    // Size of the framebuffer known to the client.
+    width: u32,
+    height: u32,
+    update_requested: bool,
+    // Rows of the frame that changed since the last update sent to the client.
+    dirty_rows: Option<(u32, u32)>,
+    buttons: u8,
+}
+
+impl VncClient {
+    fn new(mut stream: VncStream, frame: &Frame) -> io::Result<VncClient> {
+        stream.write_all(PROTOCOL_VERSION)?;
+        Ok(VncClient {
+            stream,
+            state: ClientState::Version,
+            input: Vec::new(),
+            pixel_format: PixelFormat::XRGB8888,
+            desktop_size: false,
+            width: frame.width,
+            height: frame.height,
+            update_requested: false,
+            dirty_rows: None,
+            buttons: 0,
+        })
+    }
+
+    fn mark_dirty(&mut self, first_row: u32, end_row: u32) {
+        self.dirty_rows = Some(match self.dirty_rows {
+            Some((first, end)) => (first.min(first_row), end.max(end_row)),
+            None => (first_row, end_row),
+        });
+    }
+
+    fn server_init(&mut self, frame: &Frame) -> io::Result<()> {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&(frame.width as u16).to_be_bytes());
+        msg.extend_from_slice(&(frame.height as u16).to_be_bytes());
+        msg.extend_from_slice(&PixelFormat::XRGB8888.to_bytes());
+        msg.extend_from_slice(&(DESKTOP_NAME.len() as u32).to_be_bytes());
+        msg.extend_from_slice(DESKTOP_NAME.as_bytes());
+        self.width = frame.width;
+        self.height = frame.height;
+        self.stream.write_all(&msg)
+    }
+
+    /// Reads the available data of the client and handles its messages. The input events are
+    /// appended to `events`. Returns false if the client disconnected.
+    fn receive(
+        &mut self,
+        frame: &Frame,
+        events: &mut VecDeque<GpuDisplayEvents>,
+        tracking_id: &mut i32,
+    ) -> io::Result<bool> {
+        let mut buf = [0u8; 4096];
+        let len = self.stream.read(&mut buf)?;
+        if len == 0 {
+            return Ok(false);
+        }
+        self.input.extend_from_slice(&buf[..len]);
+        while let Some(consumed) = self.handle_message(frame, events, tracking_id)? {
+            self.input.drain(..consumed);
+        }
+        Ok(true)
+    }
+
+    // Handles the next message in the input buffer. Returns the number of bytes consumed, or
+    // `None` if the message is incomplete.
+    fn handle_message(
+        &mut self,
+        frame: &Frame,
+        events: &mut VecDeque<GpuDisplayEvents>,
+        tracking_id: &mut i32,
+    ) -> io::Result<Option<usize>> {
+        let input = &self.input;
+        match self.state {
+            ClientState::Version => {
+                if input.len() < PROTOCOL_VERSION.len() {
+                    return Ok(None);
+                }
+                let minor = match std::str::from_utf8(&input[8..11]) {
+                    Ok(minor) if input.starts_with(b"RFB 003.") => minor.parse::<u8>().ok(),
+                    _ => None,
+                }
+                .ok_or_else(|| invalid_data("invalid protocol version"))?;
+                // Versions 3.3, 3.7 and 3.8 are the only ones defined, clients may use others to
+                // identify themselves.
+                match minor {
+                    minor if minor < 7 => {
+                        self.stream
+                            .write_all(&u32::from(SECURITY_TYPE_NONE).to_be_bytes())?;
+                        self.state = ClientState::Init;
+                    }
+                    minor => {
+                        self.stream.write_all(&[1, SECURITY_TYPE_NONE])?;
+                        self.state = ClientState::Security {
+                            minor: minor.min(8),
+                        };
+                    }
+                }
+                Ok(Some(PROTOCOL_VERSION.len()))
+            }
+            ClientState::Security { minor } => {
+                if input.is_empty() {
+                    return Ok(None);
+                }
+                if input[0] != SECURITY_TYPE_NONE {
+                    return Err(invalid_data("unsupported security type"));
+                }
+                if minor >= 8 {
+                    // Security result: OK.
+                    self.stream.write_all(&0u32.to_be_bytes())?;
+                }
+                self.state = ClientState::Init;
+                Ok(Some(1))
+            }
+            ClientState::Init => {
+                // The shared flag is ignored, all the clients are kept connected.
+                if input.is_empty() {
+                    return Ok(None);
+                }
+                self.server_init(frame)?;
+                self.state = ClientState::Normal;
+                Ok(Some(1))
+            }
+            ClientState::Normal => self.handle_normal_message(frame, events, tracking_id),
+        }
+    }
+
+    fn handle_normal_message(
+        &mut self,
+        frame: &Frame,
+        events: &mut VecDeque<GpuDisplayEvents>,
+        tracking_id: &mut i32,
+    ) -> io::Result<Option<usize>> {
+        let input = &self.input;
+        if input.is_empty() {
+            return Ok(None);
+        }
+        match input[0] {
+            SET_PIXEL_FORMAT => {
+                if input.len() < 20 {
+                    return Ok(None);
+                }
+                let pixel_format = PixelFormat::from_bytes(&input[4..20]);
+                if !pixel_format.is_supported() {
+                    return Err(invalid_data("unsupported pixel format"));
+                }
+                self.pixel_format = pixel_format;
+                Ok(Some(20))
+            }
+            SET_ENCODINGS => {
+                if input.len() < 4 {
+                    return Ok(None);
+                }
+                let count = u16::from_be_bytes([input[2], input[3]]) as usize;
+                let len = 4 + 4 * count;
+                if input.len() < len {
+                    return Ok(None);
+                }
+                self.desktop_size = input[4..len]
+                    .chunks_exact(4)
+                    .any(|e| i32::from_be_bytes([e[0], e[1], e[2], e[3]]) == ENCODING_DESKTOP_SIZE);
+                Ok(Some(len))
+            }
+            FRAMEBUFFER_UPDATE_REQUEST => {
+                if input.len() < 10 {
+                    return Ok(None);
+                }
+                let incremental = input[1] != 0;
+                if !incremental {
+                    self.mark_dirty(0, frame.height);
+                }
+                self.update_requested = true;
+                Ok(Some(10))
+            }
+            KEY_EVENT => {
+                if input.len() < 8 {
+                    return Ok(None);
+                }
+                let pressed = input[1] != 0;
+                let keysym = u32::from_be_bytes([input[4], input[5], input[6], input[7]]);
+                match keysym_to_keycode(keysym) {
+                    Some(keycode) => events.push_back(GpuDisplayEvents {
+                        events: vec![virtio_input_event::key(keycode, pressed)],
+                        device_type: EventDeviceKind::Keyboard,
+                    }),
+                    None => error!("unsupported VNC keysym {:#x}", keysym),
+                }
+                Ok(Some(8))
+            }
+            POINTER_EVENT => {
+                if input.len() < 6 {
+                    return Ok(None);
+                }
+                let buttons = input[1];
+                let x = i32::from(u16::from_be_bytes([input[2], input[3]]));
+                let y = i32::from(u16::from_be_bytes([input[4], input[5]]));
+                let was_pressed = self.buttons & POINTER_BUTTON_LEFT != 0;
+                let pressed = buttons & POINTER_BUTTON_LEFT != 0;
+                self.buttons = buttons;
+                // Only a single touch from the left button is supported, as for the X display.
+                let mut touch_events = vec![virtio_input_event::multitouch_slot(0)];
+                if pressed {
+                    if !was_pressed {
+                        *tracking_id += 1;
+                    }
+                    touch_events.push(virtio_input_event::multitouch_tracking_id(*tracking_id));
+                    touch_events.push(virtio_input_event::multitouch_absolute_x(x));
+                    touch_events.push(virtio_input_event::multitouch_absolute_y(y));
+                } else if was_pressed {
+                    touch_events.push(virtio_input_event::multitouch_tracking_id(-1));
+                } else {
+                    return Ok(Some(6));
+                }
+                events.push_back(GpuDisplayEvents {
+                    events: touch_events,
+                    device_type: EventDeviceKind::Touchscreen,
+                });
+                Ok(Some(6))
+            }
+            CLIENT_CUT_TEXT => {
+                if input.len() < 8 {
+                    return Ok(None);
+                }
+                let text_len = u32::from_be_bytes([input[4], input[5], input[6], input[7]]);
+                if text_len as usize > MAX_CUT_TEXT_LENGTH {
+                    return Err(invalid_data("cut text is too long"));
+                }
+                let len = 8 + text_len as usize;
+                if input.len() < len {
+                    return Ok(None);
+                }
+                Ok(Some(len))
+            }
+            message_type => Err(invalid_data(&format!(
+                "unsupported message type {}",
+                message_type
+            ))),
+        }
+    }
+
+    /// Sends the dirty part of `frame` if the client requested an update.
+    fn send_update(&mut self, frame: &Frame) -> io::Result<()> {
+        if !self.update_requested || !matches!(self.state, ClientState::Normal) {
+            return Ok(());
+        }
+        let resized = self.width != frame.width || self.height != frame.height;
+        let resize = resized && self.desktop_size;
+        let (first_row, end_row) = match self.dirty_rows {
+            Some(rows) if !resize => rows,
+            // The whole framebuffer is sent after a resize.
+            _ if resize => (0, frame.height),
+            _ => return Ok(()),
+        };
+
+        let mut msg = vec![FRAMEBUFFER_UPDATE, 0];
+        let mut rects = 0u16;
+        msg.extend_from_slice(&[0, 0]);
+        if resize {
+            self.width = frame.width;
+            self.height = frame.height;
+            push_rect_header(
+                &mut msg,
+                0,
+                0,
+                frame.width,
+                frame.height,
+                ENCODING_DESKTOP_SIZE,
+            );
+            rects += 1;
+        }
+        // Clients that do not support resizing only get the part of the frame that fits.
+        let width = self.width.min(frame.width);
+        let end_row = end_row.min(self.height).min(frame.height);
+        if first_row < end_row && width > 0 {
+            push_rect_header(
+                &mut msg,
+                0,
+                first_row,
+                width,
+                end_row - first_row,
+                ENCODING_RAW,
+            );
+            let row_len = (width * BYTES_PER_PIXEL) as usize;
+            for row in first_row..end_row {
+                let start = row as usize * frame.stride();
+                self.pixel_format
+                    .convert(&frame.pixels[start..start + row_len], &mut msg);
+            }
+            rects += 1;
+        }
+        if rects == 0 {
+            self.dirty_rows = None;
+            return Ok(());
+        }
+        msg[2..4].copy_from_slice(&rects.to_be_bytes());
+        self.stream.write_all(&msg)?;
+        self.update_requested = false;
+        self.dirty_rows = None;
+        Ok(())
+    }
+}
+
+fn push_rect_header(msg: &mut Vec<u8>, x: u32, y: u32, width: u32, height: u32, encoding: i32) {
+    msg.extend_from_slice(&(x as u16).to_be_bytes());
+    msg.extend_from_slice(&(y as u16).to_be_bytes());
+    msg.extend_from_slice(&(width as u16).to_be_bytes());
+    msg.extend_from_slice(&(height as u16).to_be_bytes());
+    msg.extend_from_slice(&encoding.to_be_bytes());
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[derive(EventToken)]
+enum VncToken {
+    Listener,
+    Client { client_id: u32 },
+}
+
+/// State shared by the display and its surfaces.
+struct VncServer {
+    listener: VncListener,
+    wait_ctx: WaitContext<VncToken>,
+    clients: BTreeMap<u32, VncClient>,
+    next_client_id: u32,
+    frame: Frame,
+    // The surface shown to the clients.
+    scanout_surface_id: Option<u32>,
+    events: VecDeque<GpuDisplayEvents>,
+    tracking_id: i32,
+}
+
+impl VncServer {
+    fn accept_client(&mut self) {
+        let stream = match self.listener.accept() {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("failed to accept VNC client: {}", e);
+                return;
+            }
+        };
+        let client = match VncClient::new(stream, &self.frame) {
+            Ok(client) => client,
+            Err(e) => {
+                error!("failed to initialize VNC client: {}", e);
+                return;
+            }
+        };
+        let client_id = self.next_client_id;
+        if let Err(e) = self
+            .wait_ctx
+            .add(&client.stream, VncToken::Client { client_id })
+        {
+            error!("failed to wait on VNC client: {}", e);
+            return;
+        }
+        self.next_client_id += 1;
+        self.clients.insert(client_id, client);
+    }
+
+    fn remove_client(&mut self, client_id: u32) {
+        if let Some(client) = self.clients.remove(&client_id) {
+            let _ = self.wait_ctx.delete(&client.stream);
+        }
+    }
+
+    /// Accepts the new clients and handles the messages of the connected ones.
+    fn process_clients(&mut self) {
+        let wait_events = match self.wait_ctx.wait_timeout(Duration::ZERO) {
+            Ok(wait_events) => wait_events,
+            Err(e) => {
+                error!("failed to wait on VNC clients: {}", e);
+                return;
+            }
+        };
+        for wait_event in wait_events.iter() {
+            match wait_event.token {
+                VncToken::Listener => self.accept_client(),
+                VncToken::Client { client_id } => {
+                    let client = match self.clients.get_mut(&client_id) {
+                        Some(client) => client,
+                        None => continue,
+                    };
+                    let result = client
+                        .receive(&self.frame, &mut self.events, &mut self.tracking_id)
+                        .and_then(|connected| {
+                            client.send_update(&self.frame)?;
+                            Ok(connected)
+                        });
+                    match result {
+                        Ok(true) => {}
+                        Ok(false) => self.remove_client(client_id),
+                        Err(e) => {
+                            error!("disconnecting VNC client: {}", e);
+                            self.remove_client(client_id);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Shows `pixels` to the clients, as the new content of the scanout.
+    fn update_frame(&mut self, width: u32, height: u32, pixels: &[u8]) {
+        let resized = self.frame.width != width || self.frame.height != height;
+        let stride = (width * BYTES_PER_PIXEL) as usize;
+        let changed_rows = if resized {
+            Some((0, height))
+        } else {
+            let changed = |row: &usize| {
+                let range = row * stride..(row + 1) * stride;
+                self.frame.pixels[range.clone()] != pixels[range]
+            };
+            let first = (0..height as usize).find(changed);
+            first.map(|first| {
+                let last = (first..height as usize).rev().find(changed).unwrap();
+                (first as u32, last as u32 + 1)
+            })
+        };
+        let (first_row, end_row) = match changed_rows {
+            Some(rows) => rows,
+            None => return,
+        };
+        self.frame = Frame {
+            width,
+            height,
+            pixels: pixels.to_vec(),
+        };
+
+        let mut disconnected = Vec::new();
+        for (client_id, client) in self.clients.iter_mut() {
+            client.mark_dirty(first_row, end_row);
+            if let Err(e) = client.send_update(&self.frame) {
+                error!("disconnecting VNC client: {}", e);
+                disconnected.push(*client_id);
+            }
+        }
+        for client_id in disconnected {
+            self.remove_client(client_id);
+        }
+    }
+}
+
+struct VncSurface {
+    surface_id: u32,
+    width: u32,
+    height: u32,
+    buffer: Vec<u8>,
+    server: Rc<RefCell<VncServer>>,
+}
+
+impl GpuDisplaySurface for VncSurface {
+    fn surface_descriptor(&self) -> u64 {
+        self.surface_id as u64
+    }
+
+    fn framebuffer(&mut self) -> Option<GpuDisplayFramebuffer> {
+        let stride = self.width * BYTES_PER_PIXEL;
+        Some(GpuDisplayFramebuffer::new(
+            VolatileSlice::new(self.buffer.as_mut_slice()),
+            stride,
+            BYTES_PER_PIXEL,
+        ))
+    }
+
+    fn flip(&mut self) {
+        let mut server = self.server.borrow_mut();
+        if server.scanout_surface_id == Some(self.surface_id) {
+            server.update_frame(self.width, self.height, &self.buffer);
+        }
+    }
+}
+
+impl Drop for VncSurface {
+    fn drop(&mut self) {
+        let mut server = self.server.borrow_mut();
+        if server.scanout_surface_id == Some(self.surface_id) {
+            server.scanout_surface_id = None;
+        }
+    }
+}
+
+/// A display whose first scanout is served to VNC clients.
+pub struct DisplayVnc {
+    server: Rc<RefCell<VncServer>>,
+    current_event: Option<GpuDisplayEvents>,
+}
+
+impl DisplayVnc {
+    pub fn new(listener: VncListener) -> GpuDisplayResult<DisplayVnc> {
+        let wait_ctx = WaitContext::new()?;
+        wait_ctx.add(&listener, VncToken::Listener)?;
+        let server = VncServer {
+            listener,
+            wait_ctx,
+            clients: BTreeMap::new(),
+            next_client_id: 0,
+            frame: Frame {
+                width: 0,
+                height: 0,
+                pixels: Vec::new(),
+            },
+            scanout_surface_id: None,
+            events: VecDeque::new(),
+            tracking_id: 0,
+        };
+        Ok(DisplayVnc {
+            server: Rc::new(RefCell::new(server)),
+            current_event: None,
+        })
+    }
+}
+
+impl DisplayT for DisplayVnc {
+    fn pending_events(&self) -> bool {
+        !self.server.borrow().events.is_empty()
+    }
+
+    fn flush(&self) {
+        self.server.borrow_mut().process_clients();
+    }
+
+    fn next_event(&mut self) -> GpuDisplayResult<u64> {
+        let mut server = self.server.borrow_mut();
+        self.current_event = server.events.pop_front();
+        // The input events apply to the scanout shown to the clients.
+        Ok(server.scanout_surface_id.unwrap_or(0) as u64)
+    }
+
+    fn handle_next_event(
+        &mut self,
+        _surface: &mut Box<dyn GpuDisplaySurface>,
+    ) -> Option<GpuDisplayEvents> {
+        self.current_event.take()
+    }
+
+    fn create_surface(
+        &mut self,
+        parent_surface_id: Option<u32>,
+        surface_id: u32,
+        width: u32,
+        height: u32,
+        surf_type: SurfaceType,
+    ) -> GpuDisplayResult<Box<dyn GpuDisplaySurface>> {
+        let mut server = self.server.borrow_mut();
+        // Cursor surfaces are drawn by the clients, and only the first scanout is shown.
+        if parent_surface_id.is_none()
+            && surf_type == SurfaceType::Scanout
+            && server.scanout_surface_id.is_none()
+        {
+            server.scanout_surface_id = Some(surface_id);
+        }
+        let len = (width as usize) * (height as usize) * (BYTES_PER_PIXEL as usize);
+        Ok(Box::new(VncSurface {
+            surface_id,
+            width,
+            height,
+            buffer: vec![0; len],
+            server: self.server.clone(),
+        }))
+    }
+}
+
+impl SysDisplayT for DisplayVnc {}
+
+impl AsRawDescriptor for DisplayVnc {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.server.borrow().wait_ctx.as_raw_descriptor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translate_keysyms() {
+        assert_eq!(keysym_to_keycode(0x61), Some(30));
+        assert_eq!(keysym_to_keycode(0x41), Some(30));
+        assert_eq!(keysym_to_keycode(0xff0d), Some(28));
+        assert_eq!(keysym_to_keycode(0x20ac), None);
+    }
+
+    #[test]
+    fn convert_pixels() {
+        let rgb565 = PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            big_endian: false,
+            true_colour: true,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+        };
+        let mut out = Vec::new();
+        // Pure red then pure blue, in XRGB8888.
+        rgb565.convert(&[0, 0, 0xff, 0, 0xff, 0, 0, 0], &mut out);
+        assert_eq!(out, vec![0x00, 0xf8, 0x1f, 0x00]);
+    }
+
+    #[test]
+    fn parse_address() {
+        assert_eq!(
+            "127.0.0.1:5900".parse::<VncAddress>(),
+            Ok(VncAddress::Tcp("127.0.0.1:5900".parse().unwrap()))
+        );
+        assert_eq!(
+            "/run/vnc.sock".parse::<VncAddress>(),
+            Ok(VncAddress::Unix(PathBuf::from("/run/vnc.sock")))
+        );
+    }
+}
@@ -20,6 +20,8 @@ use thiserror::Error;
 
 mod event_device;
 mod gpu_display_stub;
+#[cfg(unix)]
+mod gpu_display_vnc;
 #[cfg(windows)]
 mod gpu_display_win;
 #[cfg(unix)]
@@ -32,6 +34,10 @@ mod sys;
 
 pub use event_device::EventDevice;
 pub use event_device::EventDeviceKind;
+#[cfg(unix)]
+pub use gpu_display_vnc::VncAddress;
+#[cfg(unix)]
+pub use gpu_display_vnc::VncListener;
 #[cfg(windows)]
 pub use gpu_display_win::DisplayProperties as WinDisplayProperties;
 use linux_input_sys::virtio_input_event;
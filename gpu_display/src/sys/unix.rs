@@ -8,6 +8,8 @@ use base::AsRawDescriptor;
 use base::RawDescriptor;
 use base::WaitContext;
 
+use crate::gpu_display_vnc::DisplayVnc;
+use crate::gpu_display_vnc::VncListener;
 use crate::gpu_display_wl::DisplayWl;
 use crate::DisplayEventToken;
 use crate::DisplayT;
@@ -38,6 +40,9 @@ impl GpuDisplayExt for GpuDisplay {
 pub trait UnixGpuDisplayExt {
     /// Opens a fresh connection to the compositor.
     fn open_wayland<P: AsRef<Path>>(wayland_path: Option<P>) -> GpuDisplayResult<GpuDisplay>;
+
+    /// Serves the display to the VNC clients connecting on `listener`.
+    fn open_vnc(listener: VncListener) -> GpuDisplayResult<GpuDisplay>;
 }
 
 impl UnixGpuDisplayExt for GpuDisplay {
@@ -60,6 +65,23 @@ impl UnixGpuDisplayExt for GpuDisplay {
             is_x: false,
         })
     }
+
+    fn open_vnc(listener: VncListener) -> GpuDisplayResult<GpuDisplay> {
+        let display = DisplayVnc::new(listener)?;
+
+        let wait_ctx = WaitContext::new()?;
+        wait_ctx.add(&display, DisplayEventToken::Display)?;
+
+        Ok(GpuDisplay {
+            inner: Box::new(display),
+            next_id: 1,
+            event_devices: Default::default(),
+            surfaces: Default::default(),
+            imports: Default::default(),
+            wait_ctx,
+            is_x: false,
+        })
+    }
 }
 
 impl AsRawDescriptor for GpuDisplay {
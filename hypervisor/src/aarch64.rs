@@ -15,6 +15,8 @@ use gdbstub_arch::aarch64::AArch64 as GdbArch;
 use libc::EINVAL;
 use vm_memory::GuestAddress;
 
+#[cfg(feature = "gdb")]
+use crate::GuestDebug;
 use crate::Hypervisor;
 use crate::IrqRoute;
 use crate::IrqSource;
@@ -102,7 +104,17 @@ pub trait VcpuAArch64: Vcpu {
 
     #[cfg(feature = "gdb")]
     /// Sets up debug registers and configure vcpu for handling guest debug events.
-    fn set_guest_debug(&self, addrs: &[GuestAddress], enable_singlestep: bool) -> Result<()>;
+    fn set_guest_debug(&self, debug: &GuestDebug) -> Result<()>;
+
+    #[cfg(feature = "gdb")]
+    /// Returns the index in `debug.watchpoints` of the watchpoint that caused the last
+    /// `VcpuExit::Debug`, if any. `debug` must be the state last passed to `set_guest_debug`.
+    fn get_debug_exit_watchpoint(&self, debug: &GuestDebug) -> Result<Option<usize>>;
+
+    #[cfg(feature = "gdb")]
+    /// Delivers to the guest the breakpoint exception (BRK) that caused the last
+    /// `VcpuExit::Debug`, for breakpoint instructions placed by the guest itself.
+    fn inject_breakpoint(&self) -> Result<()>;
 
     #[cfg(feature = "gdb")]
     /// Sets the VCPU general registers used by GDB 'G' packets.
@@ -116,6 +128,10 @@ pub trait VcpuAArch64: Vcpu {
     /// Gets the max number of hardware breakpoints.
     fn get_max_hw_bps(&self) -> Result<usize>;
 
+    #[cfg(feature = "gdb")]
+    /// Gets the max number of hardware watchpoints.
+    fn get_max_hw_wps(&self) -> Result<usize>;
+
     #[cfg(feature = "gdb")]
     /// Sets the value of a single register on this VCPU.
     fn set_gdb_register(&self, reg: <GdbArch as Arch>::RegId, data: &[u8]) -> Result<()>;
@@ -0,0 +1,169 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeMap;
+
+use base::Error;
+use base::Result;
+#[cfg(feature = "gdb")]
+use gdbstub::arch::Arch;
+#[cfg(feature = "gdb")]
+use gdbstub_arch::aarch64::AArch64 as GdbArch;
+use libc::ENODEV;
+#[cfg(feature = "gdb")]
+use libc::ENOTSUP;
+use vm_memory::GuestAddress;
+
+use super::FakeVcpu;
+use super::FakeVm;
+#[cfg(feature = "gdb")]
+use crate::GuestDebug;
+use crate::Hypervisor;
+use crate::PsciVersion;
+use crate::VcpuAArch64;
+use crate::VcpuFeature;
+use crate::VcpuRegAArch64;
+use crate::VmAArch64;
+use crate::PSCI_0_2;
+
+/// Number of hardware breakpoints and watchpoints reported by a `FakeVcpu`.
+#[cfg(feature = "gdb")]
+const FAKE_MAX_HW_DEBUG_REGS: usize = 4;
+
+impl VmAArch64 for FakeVm {
+    fn get_hypervisor(&self) -> &dyn Hypervisor {
+        &self.fake
+    }
+
+    fn load_protected_vm_firmware(
+        &mut self,
+        _fw_addr: GuestAddress,
+        _fw_max_size: u64,
+    ) -> Result<()> {
+        Err(Error::new(ENODEV))
+    }
+
+    fn create_vcpu(&self, id: usize) -> Result<Box<dyn VcpuAArch64>> {
+        Ok(Box::new(self.create_fake_vcpu(id)?))
+    }
+}
+
+/// Architectural state of a `FakeVcpu`.
+#[derive(Default)]
+pub(super) struct FakeVcpuArchState {
+    features: Vec<VcpuFeature>,
+    #[cfg(feature = "gdb")]
+    gdb_registers: <GdbArch as Arch>::Registers,
+    #[cfg(feature = "gdb")]
+    guest_debug: GuestDebug,
+    one_regs: BTreeMap<VcpuRegAArch64, u64>,
+    pmu_irq: Option<u64>,
+}
+
+impl FakeVcpu {
+    /// Returns the features the VCPU was initialized with by `init`.
+    pub fn features(&self) -> Vec<VcpuFeature> {
+        self.state.lock().arch.features.clone()
+    }
+
+    /// Returns the PMU interrupt set with `init_pmu`, if any.
+    pub fn pmu_irq(&self) -> Option<u64> {
+        self.state.lock().arch.pmu_irq
+    }
+
+    /// Returns the debugging state set with `set_guest_debug`.
+    #[cfg(feature = "gdb")]
+    pub fn guest_debug(&self) -> GuestDebug {
+        self.state.lock().arch.guest_debug.clone()
+    }
+}
+
+impl VcpuAArch64 for FakeVcpu {
+    fn init(&self, features: &[VcpuFeature]) -> Result<()> {
+        self.state.lock().arch.features = features.to_vec();
+        Ok(())
+    }
+
+    fn init_pmu(&self, irq: u64) -> Result<()> {
+        self.state.lock().arch.pmu_irq = Some(irq);
+        Ok(())
+    }
+
+    fn has_pvtime_support(&self) -> bool {
+        false
+    }
+
+    fn init_pvtime(&self, _pvtime_ipa: u64) -> Result<()> {
+        Err(Error::new(ENODEV))
+    }
+
+    fn set_one_reg(&self, reg_id: VcpuRegAArch64, data: u64) -> Result<()> {
+        self.state.lock().arch.one_regs.insert(reg_id, data);
+        Ok(())
+    }
+
+    /// Registers that were never set read as 0.
+    fn get_one_reg(&self, reg_id: VcpuRegAArch64) -> Result<u64> {
+        Ok(self
+            .state
+            .lock()
+            .arch
+            .one_regs
+            .get(&reg_id)
+            .copied()
+            .unwrap_or(0))
+    }
+
+    fn get_psci_version(&self) -> Result<PsciVersion> {
+        Ok(PSCI_0_2)
+    }
+
+    #[cfg(feature = "gdb")]
+    fn set_guest_debug(&self, debug: &GuestDebug) -> Result<()> {
+        self.state.lock().arch.guest_debug = debug.clone();
+        Ok(())
+    }
+
+    #[cfg(feature = "gdb")]
+    fn get_debug_exit_watchpoint(&self, _debug: &GuestDebug) -> Result<Option<usize>> {
+        Ok(None)
+    }
+
+    #[cfg(feature = "gdb")]
+    fn inject_breakpoint(&self) -> Result<()> {
+        Ok(())
+    }
+
+    #[cfg(feature = "gdb")]
+    fn set_gdb_registers(&self, regs: &<GdbArch as Arch>::Registers) -> Result<()> {
+        self.state.lock().arch.gdb_registers = regs.clone();
+        Ok(())
+    }
+
+    #[cfg(feature = "gdb")]
+    fn get_gdb_registers(&self, regs: &mut <GdbArch as Arch>::Registers) -> Result<()> {
+        *regs = self.state.lock().arch.gdb_registers.clone();
+        Ok(())
+    }
+
+    #[cfg(feature = "gdb")]
+    fn get_max_hw_bps(&self) -> Result<usize> {
+        Ok(FAKE_MAX_HW_DEBUG_REGS)
+    }
+
+    #[cfg(feature = "gdb")]
+    fn get_max_hw_wps(&self) -> Result<usize> {
+        Ok(FAKE_MAX_HW_DEBUG_REGS)
+    }
+
+    #[cfg(feature = "gdb")]
+    fn set_gdb_register(&self, _reg: <GdbArch as Arch>::RegId, _data: &[u8]) -> Result<()> {
+        Err(Error::new(ENOTSUP))
+    }
+
+    #[cfg(feature = "gdb")]
+    fn get_gdb_register(&self, _reg: <GdbArch as Arch>::RegId, _data: &mut [u8]) -> Result<usize> {
+        Err(Error::new(ENOTSUP))
+    }
+}
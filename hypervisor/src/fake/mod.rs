@@ -0,0 +1,928 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A hypervisor implemented purely in userspace, for testing VM setup, irqchips and VCPU run loops
+//! on hosts without hardware virtualization.
+//!
+//! No guest code is ever executed. Instead, the exits returned by `Vcpu::run` are scripted with
+//! `FakeVm::push_vcpu_exit`, and the memory regions, ioevents and irqfds registered with a
+//! `FakeVm` are recorded so that they can be inspected.
+
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+mod aarch64;
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+pub use aarch64::*;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86_64;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::BinaryHeap;
+use std::collections::VecDeque;
+use std::os::raw::c_int;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use base::pagesize;
+use base::AsRawDescriptor;
+use base::Error;
+use base::Event;
+use base::MappedRegion;
+use base::MmapError;
+use base::Protection;
+use base::Result;
+use base::SafeDescriptor;
+use libc::EBUSY;
+use libc::EEXIST;
+use libc::EFAULT;
+use libc::EINTR;
+use libc::EINVAL;
+use libc::EIO;
+use libc::ENODEV;
+use libc::ENOENT;
+use libc::ENOSPC;
+use libc::EOVERFLOW;
+use sync::Mutex;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use x86_64::*;
+
+use crate::ClockState;
+use crate::Datamatch;
+use crate::DeviceKind;
+use crate::HypervHypercall;
+use crate::Hypervisor;
+use crate::HypervisorCap;
+use crate::IoEventAddress;
+use crate::IoOperation;
+use crate::IoParams;
+use crate::MemSlot;
+use crate::Vcpu;
+use crate::VcpuExit;
+use crate::VcpuRunHandle;
+use crate::VcpuRunHandleFingerprint;
+use crate::Vm;
+use crate::VmCap;
+
+/// Size of the guest physical address space reported by `FakeVm::get_guest_phys_addr_bits`.
+const FAKE_GUEST_PHYS_ADDR_BITS: u8 = 40;
+
+/// A fake hypervisor, which only reports the capabilities it was configured with.
+#[derive(Clone)]
+pub struct FakeHypervisor {
+    caps: Vec<HypervisorCap>,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    supported_cpuid: Vec<crate::CpuIdEntry>,
+}
+
+impl FakeHypervisor {
+    /// Constructs a new `FakeHypervisor` supporting user memory and immediate exits.
+    pub fn new() -> FakeHypervisor {
+        FakeHypervisor {
+            caps: vec![HypervisorCap::ImmediateExit, HypervisorCap::UserMemory],
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            supported_cpuid: Vec::new(),
+        }
+    }
+
+    /// Adds `cap` to the capabilities reported by `check_capability`.
+    pub fn add_capability(&mut self, cap: HypervisorCap) {
+        if !self.caps.contains(&cap) {
+            self.caps.push(cap);
+        }
+    }
+}
+
+impl Default for FakeHypervisor {
+    fn default() -> Self {
+        FakeHypervisor::new()
+    }
+}
+
+impl Hypervisor for FakeHypervisor {
+    fn try_clone(&self) -> Result<Self> {
+        Ok(self.clone())
+    }
+
+    fn check_capability(&self, cap: HypervisorCap) -> bool {
+        self.caps.contains(&cap)
+    }
+}
+
+/// A memory region added to a `FakeVm` with `Vm::add_memory_region`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FakeMemoryRegion {
+    pub slot: MemSlot,
+    pub guest_addr: GuestAddress,
+    /// Size of the region, rounded up to a whole number of pages.
+    pub size: u64,
+    pub read_only: bool,
+    pub log_dirty_pages: bool,
+}
+
+/// An exit to be returned by the next call to `Vcpu::run` on a `FakeVcpu`.
+#[derive(Clone, Copy, Debug)]
+pub enum FakeVcpuExit {
+    /// A port IO access, returned as `VcpuExit::Io` and passed to the handler of
+    /// `Vcpu::handle_io`. Writes matching a registered ioevent signal it instead.
+    Io(IoParams),
+    /// An MMIO access, returned as `VcpuExit::Mmio` and passed to the handler of
+    /// `Vcpu::handle_mmio`. Writes matching a registered ioevent signal it instead.
+    Mmio(IoParams),
+    /// Any other exit, returned as is.
+    Exit(VcpuExit),
+}
+
+struct FakeIoEvent {
+    addr: IoEventAddress,
+    datamatch: Datamatch,
+    evt: Event,
+}
+
+impl FakeIoEvent {
+    fn matches(&self, addr: IoEventAddress, data: &[u8]) -> bool {
+        if self.addr != addr {
+            return false;
+        }
+        match self.datamatch {
+            Datamatch::AnyLength => true,
+            Datamatch::U8(v) => <[u8; 1]>::try_from(data)
+                .map_or(false, |d| v.map_or(true, |v| u8::from_le_bytes(d) == v)),
+            Datamatch::U16(v) => <[u8; 2]>::try_from(data)
+                .map_or(false, |d| v.map_or(true, |v| u16::from_le_bytes(d) == v)),
+            Datamatch::U32(v) => <[u8; 4]>::try_from(data)
+                .map_or(false, |d| v.map_or(true, |v| u32::from_le_bytes(d) == v)),
+            Datamatch::U64(v) => <[u8; 8]>::try_from(data)
+                .map_or(false, |d| v.map_or(true, |v| u64::from_le_bytes(d) == v)),
+        }
+    }
+}
+
+struct FakeIrqfd {
+    gsi: u32,
+    evt: Event,
+    resample_evt: Option<Event>,
+}
+
+/// State shared by all the clones of a `FakeVm` and the `FakeVcpu`s created from them.
+#[derive(Default)]
+struct FakeVmState {
+    caps: Vec<VmCap>,
+    guest_mem_dirty_log: bool,
+    identity_map_addr: Option<GuestAddress>,
+    ioevents: Vec<FakeIoEvent>,
+    irqfds: Vec<FakeIrqfd>,
+    mem_regions: BTreeMap<MemSlot, (FakeMemoryRegion, Box<dyn MappedRegion>)>,
+    mem_slot_gaps: BinaryHeap<Reverse<MemSlot>>,
+    pvclock: ClockState,
+    tss_addr: Option<GuestAddress>,
+    vcpu_exits: BTreeMap<usize, VecDeque<FakeVcpuExit>>,
+    vcpu_ids: BTreeSet<usize>,
+}
+
+impl FakeVmState {
+    /// Signals the ioevent matching the write described by `params` at `addr`, if any. Returns
+    /// whether an ioevent was signaled, in which case the write does not exit to userspace.
+    fn signal_ioevent(&self, addr: IoEventAddress, params: &IoParams) -> Result<bool> {
+        let data = match params.operation {
+            IoOperation::Read => return Ok(false),
+            IoOperation::Write { ref data } => data.get(..params.size).unwrap_or(data),
+        };
+        match self
+            .ioevents
+            .iter()
+            .find(|ioevent| ioevent.matches(addr, data))
+        {
+            Some(ioevent) => {
+                ioevent.evt.signal()?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// A fake VM, recording the state set up by its users instead of running a guest.
+pub struct FakeVm {
+    fake: FakeHypervisor,
+    guest_mem: GuestMemory,
+    state: Arc<Mutex<FakeVmState>>,
+}
+
+impl FakeVm {
+    /// Constructs a new `FakeVm` using the given `FakeHypervisor` instance.
+    pub fn new(fake: &FakeHypervisor, guest_mem: GuestMemory) -> Result<FakeVm> {
+        Ok(FakeVm {
+            fake: fake.clone(),
+            guest_mem,
+            state: Arc::new(Mutex::new(Default::default())),
+        })
+    }
+
+    /// Adds `cap` to the capabilities reported by `check_capability`.
+    pub fn add_capability(&self, cap: VmCap) {
+        let mut state = self.state.lock();
+        if !state.caps.contains(&cap) {
+            state.caps.push(cap);
+        }
+    }
+
+    /// Queues `exit` to be returned by `Vcpu::run` on the VCPU with the given `vcpu_id`, after
+    /// the exits queued before it. Once there are no more queued exits, `Vcpu::run` returns
+    /// `VcpuExit::Shutdown`.
+    pub fn push_vcpu_exit(&self, vcpu_id: usize, exit: FakeVcpuExit) {
+        self.state
+            .lock()
+            .vcpu_exits
+            .entry(vcpu_id)
+            .or_default()
+            .push_back(exit);
+    }
+
+    /// Returns the memory regions added with `add_memory_region`, ordered by slot.
+    pub fn memory_regions(&self) -> Vec<FakeMemoryRegion> {
+        self.state
+            .lock()
+            .mem_regions
+            .values()
+            .map(|(region, _)| *region)
+            .collect()
+    }
+
+    /// Returns the addresses and datamatches of the registered ioevents, in registration order.
+    pub fn ioevents(&self) -> Vec<(IoEventAddress, Datamatch)> {
+        self.state
+            .lock()
+            .ioevents
+            .iter()
+            .map(|ioevent| (ioevent.addr, ioevent.datamatch))
+            .collect()
+    }
+
+    /// Returns the GSIs of the registered irqfds, in registration order.
+    pub fn irqfd_gsis(&self) -> Vec<u32> {
+        self.state
+            .lock()
+            .irqfds
+            .iter()
+            .map(|irqfd| irqfd.gsi)
+            .collect()
+    }
+
+    /// Returns whether the irqfd registered for `gsi` has a resample event.
+    pub fn irqfd_has_resample(&self, gsi: u32) -> bool {
+        self.state
+            .lock()
+            .irqfds
+            .iter()
+            .any(|irqfd| irqfd.gsi == gsi && irqfd.resample_evt.is_some())
+    }
+
+    /// Returns the address set with `set_tss_addr`, if any.
+    pub fn tss_addr(&self) -> Option<GuestAddress> {
+        self.state.lock().tss_addr
+    }
+
+    /// Returns the address set with `set_identity_map_addr`, if any.
+    pub fn identity_map_addr(&self) -> Option<GuestAddress> {
+        self.state.lock().identity_map_addr
+    }
+
+    /// Registers an event that would, when signaled, trigger the `gsi` irq, and `resample_evt`
+    /// ( when not None ) would be triggered when the irqchip is resampled.
+    ///
+    /// The fake VM has no in-kernel irqchip, so the events are only recorded.
+    pub fn register_irqfd(
+        &self,
+        gsi: u32,
+        evt: &Event,
+        resample_evt: Option<&Event>,
+    ) -> Result<()> {
+        let mut state = self.state.lock();
+        if state
+            .irqfds
+            .iter()
+            .any(|irqfd| irqfd.gsi == gsi && irqfd.evt == *evt)
+        {
+            return Err(Error::new(EEXIST));
+        }
+        state.irqfds.push(FakeIrqfd {
+            gsi,
+            evt: evt.try_clone()?,
+            resample_evt: resample_evt.map(Event::try_clone).transpose()?,
+        });
+        Ok(())
+    }
+
+    /// Unregisters an event that was previously registered with
+    /// `register_irqfd`.
+    ///
+    /// The `evt` and `gsi` pair must be the same as the ones passed into
+    /// `register_irqfd`.
+    pub fn unregister_irqfd(&self, gsi: u32, evt: &Event) -> Result<()> {
+        let mut state = self.state.lock();
+        let index = state
+            .irqfds
+            .iter()
+            .position(|irqfd| irqfd.gsi == gsi && irqfd.evt == *evt)
+            .ok_or_else(|| Error::new(ENOENT))?;
+        state.irqfds.remove(index);
+        Ok(())
+    }
+
+    fn create_fake_vcpu(&self, id: usize) -> Result<FakeVcpu> {
+        if !self.state.lock().vcpu_ids.insert(id) {
+            return Err(Error::new(EEXIST));
+        }
+        Ok(FakeVcpu {
+            id,
+            vm_state: self.state.clone(),
+            state: Arc::new(Mutex::new(Default::default())),
+            immediate_exit: Arc::new(AtomicBool::new(false)),
+        })
+    }
+}
+
+/// Checks that `dirty_log` can hold the dirty log of `size` bytes of memory and fills it: no guest
+/// code runs, so no page is ever dirtied.
+fn fill_dirty_log(size: usize, dirty_log: &mut [u8]) -> Result<()> {
+    let pgsz = pagesize();
+    if ((size + pgsz - 1) / pgsz + 7) / 8 > dirty_log.len() {
+        return Err(Error::new(EINVAL));
+    }
+    dirty_log.fill(0);
+    Ok(())
+}
+
+impl Vm for FakeVm {
+    fn try_clone(&self) -> Result<Self> {
+        Ok(FakeVm {
+            fake: self.fake.clone(),
+            guest_mem: self.guest_mem.clone(),
+            state: self.state.clone(),
+        })
+    }
+
+    fn check_capability(&self, c: VmCap) -> bool {
+        self.state.lock().caps.contains(&c)
+    }
+
+    fn get_guest_phys_addr_bits(&self) -> u8 {
+        FAKE_GUEST_PHYS_ADDR_BITS
+    }
+
+    fn get_memory(&self) -> &GuestMemory {
+        &self.guest_mem
+    }
+
+    fn add_memory_region(
+        &mut self,
+        guest_addr: GuestAddress,
+        mem: Box<dyn MappedRegion>,
+        read_only: bool,
+        log_dirty_pages: bool,
+    ) -> Result<MemSlot> {
+        let pgsz = pagesize() as u64;
+        // Same as KVM, the size of the slot is the size of the mapping rounded up to a page.
+        let size = (mem.size() as u64 + pgsz - 1) / pgsz * pgsz;
+        let end_addr = guest_addr
+            .checked_add(size)
+            .ok_or_else(|| Error::new(EOVERFLOW))?;
+        if self.guest_mem.range_overlap(guest_addr, end_addr) {
+            return Err(Error::new(ENOSPC));
+        }
+        let mut state = self.state.lock();
+        if state.mem_regions.values().any(|(region, _)| {
+            guest_addr < region.guest_addr.unchecked_add(region.size)
+                && region.guest_addr < end_addr
+        }) {
+            return Err(Error::new(EEXIST));
+        }
+        let slot = match state.mem_slot_gaps.pop() {
+            Some(gap) => gap.0,
+            None => (state.mem_regions.len() + self.guest_mem.num_regions() as usize) as MemSlot,
+        };
+        let region = FakeMemoryRegion {
+            slot,
+            guest_addr,
+            size,
+            read_only,
+            log_dirty_pages,
+        };
+        state.mem_regions.insert(slot, (region, mem));
+        Ok(slot)
+    }
+
+    fn msync_memory_region(&mut self, slot: MemSlot, offset: usize, size: usize) -> Result<()> {
+        let mut state = self.state.lock();
+        let (_, mem) = state
+            .mem_regions
+            .get_mut(&slot)
+            .ok_or_else(|| Error::new(ENOENT))?;
+
+        mem.msync(offset, size).map_err(|err| match err {
+            MmapError::InvalidAddress => Error::new(EFAULT),
+            MmapError::NotPageAligned => Error::new(EINVAL),
+            MmapError::SystemCallFailed(e) => e,
+            _ => Error::new(EIO),
+        })
+    }
+
+    fn remove_memory_region(&mut self, slot: MemSlot) -> Result<Box<dyn MappedRegion>> {
+        let mut state = self.state.lock();
+        let (_, mem) = state
+            .mem_regions
+            .remove(&slot)
+            .ok_or_else(|| Error::new(ENOENT))?;
+        state.mem_slot_gaps.push(Reverse(slot));
+        Ok(mem)
+    }
+
+    fn create_device(&self, _kind: DeviceKind) -> Result<SafeDescriptor> {
+        Err(Error::new(ENODEV))
+    }
+
+    fn get_dirty_log(&self, slot: MemSlot, dirty_log: &mut [u8]) -> Result<()> {
+        let state = self.state.lock();
+        let size = match state.mem_regions.get(&slot) {
+            Some((region, _)) if region.log_dirty_pages => region.size as usize,
+            _ => return Err(Error::new(ENOENT)),
+        };
+        fill_dirty_log(size, dirty_log)
+    }
+
+    fn set_guest_memory_dirty_log(&mut self, enable: bool) -> Result<()> {
+        self.state.lock().guest_mem_dirty_log = enable;
+        Ok(())
+    }
+
+    fn get_guest_memory_dirty_log(
+        &self,
+        guest_addr: GuestAddress,
+        dirty_log: &mut [u8],
+    ) -> Result<()> {
+        if !self.state.lock().guest_mem_dirty_log {
+            return Err(Error::new(ENOENT));
+        }
+        let (_, size) = self
+            .guest_mem
+            .guest_memory_regions()
+            .into_iter()
+            .find(|&(addr, _)| addr == guest_addr)
+            .ok_or_else(|| Error::new(ENOENT))?;
+        fill_dirty_log(size, dirty_log)
+    }
+
+    fn register_ioevent(
+        &mut self,
+        evt: &Event,
+        addr: IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()> {
+        let mut state = self.state.lock();
+        if state
+            .ioevents
+            .iter()
+            .any(|ioevent| ioevent.addr == addr && ioevent.datamatch == datamatch)
+        {
+            return Err(Error::new(EEXIST));
+        }
+        state.ioevents.push(FakeIoEvent {
+            addr,
+            datamatch,
+            evt: evt.try_clone()?,
+        });
+        Ok(())
+    }
+
+    fn unregister_ioevent(
+        &mut self,
+        evt: &Event,
+        addr: IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()> {
+        let mut state = self.state.lock();
+        let index = state
+            .ioevents
+            .iter()
+            .position(|ioevent| {
+                ioevent.addr == addr && ioevent.datamatch == datamatch && ioevent.evt == *evt
+            })
+            .ok_or_else(|| Error::new(ENOENT))?;
+        state.ioevents.remove(index);
+        Ok(())
+    }
+
+    fn handle_io_events(&self, _addr: IoEventAddress, _data: &[u8]) -> Result<()> {
+        // Like KVM, the fake VCPUs signal the ioevents themselves without exiting, so this is a
+        // no-op.
+        Ok(())
+    }
+
+    fn get_pvclock(&self) -> Result<ClockState> {
+        Ok(self.state.lock().pvclock)
+    }
+
+    fn set_pvclock(&self, state: &ClockState) -> Result<()> {
+        self.state.lock().pvclock = *state;
+        Ok(())
+    }
+
+    fn add_fd_mapping(
+        &mut self,
+        slot: u32,
+        offset: usize,
+        size: usize,
+        fd: &dyn AsRawDescriptor,
+        fd_offset: u64,
+        prot: Protection,
+    ) -> Result<()> {
+        let mut state = self.state.lock();
+        let (_, region) = state
+            .mem_regions
+            .get_mut(&slot)
+            .ok_or_else(|| Error::new(EINVAL))?;
+
+        match region.add_fd_mapping(offset, size, fd, fd_offset, prot) {
+            Ok(()) => Ok(()),
+            Err(MmapError::SystemCallFailed(e)) => Err(e),
+            Err(_) => Err(Error::new(EIO)),
+        }
+    }
+
+    fn remove_mapping(&mut self, slot: u32, offset: usize, size: usize) -> Result<()> {
+        let mut state = self.state.lock();
+        let (_, region) = state
+            .mem_regions
+            .get_mut(&slot)
+            .ok_or_else(|| Error::new(EINVAL))?;
+
+        match region.remove_mapping(offset, size) {
+            Ok(()) => Ok(()),
+            Err(MmapError::SystemCallFailed(e)) => Err(e),
+            Err(_) => Err(Error::new(EIO)),
+        }
+    }
+
+    fn handle_inflate(&mut self, _guest_address: GuestAddress, _size: u64) -> Result<()> {
+        // The guest memory is left untouched, since no guest is running to notice the difference.
+        Ok(())
+    }
+
+    fn handle_deflate(&mut self, _guest_address: GuestAddress, _size: u64) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Per-VCPU state shared by all the clones of a `FakeVcpu`.
+#[derive(Default)]
+struct FakeVcpuState {
+    arch: FakeVcpuArchState,
+    last_read_data: Option<[u8; 8]>,
+    pending_io: Option<IoParams>,
+    pending_mmio: Option<IoParams>,
+    run_handle_fingerprint: Option<VcpuRunHandleFingerprint>,
+}
+
+thread_local!(static FAKE_VCPU_THREAD: RefCell<Option<Arc<AtomicBool>>> = RefCell::new(None));
+
+/// A fake VCPU, returning the exits queued with `FakeVm::push_vcpu_exit` when run.
+pub struct FakeVcpu {
+    id: usize,
+    vm_state: Arc<Mutex<FakeVmState>>,
+    state: Arc<Mutex<FakeVcpuState>>,
+    immediate_exit: Arc<AtomicBool>,
+}
+
+impl FakeVcpu {
+    /// Returns the data given by the handler of the last read passed to `handle_io` or
+    /// `handle_mmio`.
+    pub fn last_read_data(&self) -> Option<[u8; 8]> {
+        self.state.lock().last_read_data
+    }
+
+    fn handle_pending(
+        &self,
+        pending: Option<IoParams>,
+        handle_fn: &mut dyn FnMut(IoParams) -> Option<[u8; 8]>,
+    ) -> Result<()> {
+        let params = pending.ok_or_else(|| Error::new(EINVAL))?;
+        let data = handle_fn(params);
+        if let IoOperation::Read = params.operation {
+            self.state.lock().last_read_data = data;
+        }
+        Ok(())
+    }
+}
+
+impl Vcpu for FakeVcpu {
+    fn try_clone(&self) -> Result<Self> {
+        Ok(FakeVcpu {
+            id: self.id,
+            vm_state: self.vm_state.clone(),
+            state: self.state.clone(),
+            immediate_exit: self.immediate_exit.clone(),
+        })
+    }
+
+    fn as_vcpu(&self) -> &dyn Vcpu {
+        self
+    }
+
+    fn take_run_handle(&self, _signal_num: Option<c_int>) -> Result<VcpuRunHandle> {
+        fn vcpu_run_handle_drop() {
+            FAKE_VCPU_THREAD.with(|v| *v.borrow_mut() = None);
+        }
+
+        FAKE_VCPU_THREAD.with(|v| {
+            if v.borrow().is_none() {
+                *v.borrow_mut() = Some(self.immediate_exit.clone());
+                Ok(())
+            } else {
+                Err(Error::new(EBUSY))
+            }
+        })?;
+
+        let vcpu_run_handle = VcpuRunHandle::new(vcpu_run_handle_drop);
+        self.state.lock().run_handle_fingerprint = Some(vcpu_run_handle.fingerprint().clone());
+        Ok(vcpu_run_handle)
+    }
+
+    fn run(&mut self, run_handle: &VcpuRunHandle) -> Result<VcpuExit> {
+        let mut state = self.state.lock();
+        if state.run_handle_fingerprint.as_ref() != Some(run_handle.fingerprint()) {
+            panic!("invalid VcpuRunHandle used to run Vcpu");
+        }
+        state.pending_io = None;
+        state.pending_mmio = None;
+
+        if self.immediate_exit.load(Ordering::Acquire) {
+            return Err(Error::new(EINTR));
+        }
+
+        let mut vm_state = self.vm_state.lock();
+        loop {
+            let exit = match vm_state
+                .vcpu_exits
+                .get_mut(&self.id)
+                .and_then(VecDeque::pop_front)
+            {
+                Some(exit) => exit,
+                None => return Ok(VcpuExit::Shutdown),
+            };
+            match exit {
+                FakeVcpuExit::Io(params) => {
+                    if !vm_state.signal_ioevent(IoEventAddress::Pio(params.address), &params)? {
+                        state.pending_io = Some(params);
+                        return Ok(VcpuExit::Io);
+                    }
+                }
+                FakeVcpuExit::Mmio(params) => {
+                    if !vm_state.signal_ioevent(IoEventAddress::Mmio(params.address), &params)? {
+                        state.pending_mmio = Some(params);
+                        return Ok(VcpuExit::Mmio);
+                    }
+                }
+                FakeVcpuExit::Exit(exit) => return Ok(exit),
+            }
+        }
+    }
+
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn set_immediate_exit(&self, exit: bool) {
+        self.immediate_exit.store(exit, Ordering::Release);
+    }
+
+    fn set_local_immediate_exit(exit: bool) {
+        FAKE_VCPU_THREAD.with(|v| {
+            if let Some(immediate_exit) = &(*v.borrow()) {
+                immediate_exit.store(exit, Ordering::Release);
+            }
+        });
+    }
+
+    fn set_local_immediate_exit_fn(&self) -> extern "C" fn() {
+        extern "C" fn f() {
+            FakeVcpu::set_local_immediate_exit(true);
+        }
+        f
+    }
+
+    fn handle_mmio(&self, handle_fn: &mut dyn FnMut(IoParams) -> Option<[u8; 8]>) -> Result<()> {
+        let pending = self.state.lock().pending_mmio.take();
+        self.handle_pending(pending, handle_fn)
+    }
+
+    fn handle_io(&self, handle_fn: &mut dyn FnMut(IoParams) -> Option<[u8; 8]>) -> Result<()> {
+        let pending = self.state.lock().pending_io.take();
+        self.handle_pending(pending, handle_fn)
+    }
+
+    fn handle_hyperv_hypercall(&self, _func: &mut dyn FnMut(HypervHypercall) -> u64) -> Result<()> {
+        Err(Error::new(EINVAL))
+    }
+
+    fn handle_rdmsr(&self, _data: u64) -> Result<()> {
+        Ok(())
+    }
+
+    fn handle_wrmsr(&self) {}
+
+    fn pvclock_ctrl(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_signal_mask(&self, _signals: &[c_int]) -> Result<()> {
+        Ok(())
+    }
+
+    unsafe fn enable_raw_capability(&self, _cap: u32, _args: &[u64; 4]) -> Result<()> {
+        Err(Error::new(EINVAL))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base::MemoryMappingBuilder;
+
+    use super::*;
+
+    fn new_vm() -> FakeVm {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        FakeVm::new(&FakeHypervisor::new(), mem).unwrap()
+    }
+
+    fn write(address: u64, data: &[u8]) -> IoParams {
+        let mut buf = [0u8; 8];
+        buf[..data.len()].copy_from_slice(data);
+        IoParams {
+            address,
+            size: data.len(),
+            operation: IoOperation::Write { data: buf },
+        }
+    }
+
+    #[test]
+    fn add_memory_region() {
+        let mut vm = new_vm();
+        let mem = Box::new(MemoryMappingBuilder::new(0x1000).build().unwrap());
+        assert_eq!(
+            vm.add_memory_region(GuestAddress(0x1000), mem, false, false)
+                .unwrap_err(),
+            Error::new(ENOSPC)
+        );
+
+        let mem = Box::new(MemoryMappingBuilder::new(0x1000).build().unwrap());
+        let slot = vm
+            .add_memory_region(GuestAddress(0x10000), mem, true, false)
+            .unwrap();
+        // Slot 0 is used by the guest memory.
+        assert_eq!(slot, 1);
+        assert_eq!(
+            vm.memory_regions(),
+            vec![FakeMemoryRegion {
+                slot: 1,
+                guest_addr: GuestAddress(0x10000),
+                size: 0x1000,
+                read_only: true,
+                log_dirty_pages: false,
+            }]
+        );
+
+        let mem = Box::new(MemoryMappingBuilder::new(0x1000).build().unwrap());
+        assert_eq!(
+            vm.add_memory_region(GuestAddress(0x10000), mem, false, false)
+                .unwrap_err(),
+            Error::new(EEXIST)
+        );
+
+        vm.remove_memory_region(slot).unwrap();
+        assert!(vm.memory_regions().is_empty());
+        assert_eq!(
+            vm.remove_memory_region(slot).err(),
+            Some(Error::new(ENOENT))
+        );
+
+        let mem = Box::new(MemoryMappingBuilder::new(0x1000).build().unwrap());
+        assert_eq!(
+            vm.add_memory_region(GuestAddress(0x20000), mem, false, false)
+                .unwrap(),
+            slot
+        );
+    }
+
+    #[test]
+    fn scripted_exits() {
+        let vm = new_vm();
+        let mut vcpu = vm.create_fake_vcpu(0).unwrap();
+        vm.push_vcpu_exit(
+            0,
+            FakeVcpuExit::Mmio(IoParams {
+                address: 0x20000,
+                size: 4,
+                operation: IoOperation::Read,
+            }),
+        );
+        vm.push_vcpu_exit(0, FakeVcpuExit::Io(write(0x3f8, &[0x41])));
+        vm.push_vcpu_exit(0, FakeVcpuExit::Exit(VcpuExit::Hlt));
+
+        let run_handle = vcpu.take_run_handle(None).unwrap();
+        assert!(matches!(vcpu.run(&run_handle), Ok(VcpuExit::Mmio)));
+        // Only the pending exit can be handled.
+        assert!(vcpu.handle_io(&mut |_| None).is_err());
+        vcpu.handle_mmio(&mut |params| {
+            assert_eq!(params.address, 0x20000);
+            assert_eq!(params.size, 4);
+            Some([0x12, 0x34, 0x56, 0x78, 0, 0, 0, 0])
+        })
+        .unwrap();
+        assert_eq!(
+            vcpu.last_read_data(),
+            Some([0x12, 0x34, 0x56, 0x78, 0, 0, 0, 0])
+        );
+
+        assert!(matches!(vcpu.run(&run_handle), Ok(VcpuExit::Io)));
+        let mut written = Vec::new();
+        vcpu.handle_io(&mut |params| {
+            if let IoOperation::Write { data } = params.operation {
+                written.push((params.address, data[0]));
+            }
+            None
+        })
+        .unwrap();
+        assert_eq!(written, vec![(0x3f8, 0x41)]);
+
+        assert!(matches!(vcpu.run(&run_handle), Ok(VcpuExit::Hlt)));
+        assert!(matches!(vcpu.run(&run_handle), Ok(VcpuExit::Shutdown)));
+
+        vcpu.set_immediate_exit(true);
+        vm.push_vcpu_exit(0, FakeVcpuExit::Exit(VcpuExit::Hlt));
+        assert_eq!(vcpu.run(&run_handle).unwrap_err(), Error::new(EINTR));
+        vcpu.set_immediate_exit(false);
+        assert!(matches!(vcpu.run(&run_handle), Ok(VcpuExit::Hlt)));
+    }
+
+    #[test]
+    fn ioevents() {
+        let mut vm = new_vm();
+        let evt = Event::new().unwrap();
+        vm.register_ioevent(&evt, IoEventAddress::Pio(0x1000), Datamatch::U8(Some(0x12)))
+            .unwrap();
+        assert_eq!(
+            vm.register_ioevent(&evt, IoEventAddress::Pio(0x1000), Datamatch::U8(Some(0x12)))
+                .unwrap_err(),
+            Error::new(EEXIST)
+        );
+        assert_eq!(
+            vm.ioevents(),
+            vec![(IoEventAddress::Pio(0x1000), Datamatch::U8(Some(0x12)))]
+        );
+
+        let mut vcpu = vm.create_fake_vcpu(0).unwrap();
+        // A write of the wrong value or size, or to MMIO, exits to userspace.
+        vm.push_vcpu_exit(0, FakeVcpuExit::Io(write(0x1000, &[0x34])));
+        vm.push_vcpu_exit(0, FakeVcpuExit::Io(write(0x1000, &[0x12, 0])));
+        vm.push_vcpu_exit(0, FakeVcpuExit::Mmio(write(0x1000, &[0x12])));
+        // A matching write signals the event without exiting.
+        vm.push_vcpu_exit(0, FakeVcpuExit::Io(write(0x1000, &[0x12])));
+        vm.push_vcpu_exit(0, FakeVcpuExit::Exit(VcpuExit::Hlt));
+
+        let run_handle = vcpu.take_run_handle(None).unwrap();
+        assert!(matches!(vcpu.run(&run_handle), Ok(VcpuExit::Io)));
+        assert!(matches!(vcpu.run(&run_handle), Ok(VcpuExit::Io)));
+        assert!(matches!(vcpu.run(&run_handle), Ok(VcpuExit::Mmio)));
+        assert!(matches!(vcpu.run(&run_handle), Ok(VcpuExit::Hlt)));
+        evt.wait().unwrap();
+
+        vm.unregister_ioevent(&evt, IoEventAddress::Pio(0x1000), Datamatch::U8(Some(0x12)))
+            .unwrap();
+        assert!(vm.ioevents().is_empty());
+    }
+
+    #[test]
+    fn irqfds() {
+        let vm = new_vm();
+        let evt = Event::new().unwrap();
+        let resample_evt = Event::new().unwrap();
+        vm.register_irqfd(4, &evt, Some(&resample_evt)).unwrap();
+        vm.register_irqfd(5, &evt, None).unwrap();
+        assert_eq!(vm.irqfd_gsis(), vec![4, 5]);
+        assert!(vm.irqfd_has_resample(4));
+        assert!(!vm.irqfd_has_resample(5));
+
+        vm.unregister_irqfd(4, &evt).unwrap();
+        assert_eq!(
+            vm.unregister_irqfd(4, &evt).unwrap_err(),
+            Error::new(ENOENT)
+        );
+        assert_eq!(vm.irqfd_gsis(), vec![5]);
+    }
+}
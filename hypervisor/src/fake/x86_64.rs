@@ -0,0 +1,295 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeMap;
+
+use base::Error;
+use base::Result;
+use libc::ENXIO;
+use vm_memory::GuestAddress;
+
+use super::FakeHypervisor;
+use super::FakeVcpu;
+use super::FakeVm;
+use crate::CpuId;
+use crate::CpuIdEntry;
+use crate::DebugRegs;
+use crate::Fpu;
+use crate::GuestDebug;
+use crate::HypervisorX86_64;
+use crate::Register;
+use crate::Regs;
+use crate::Sregs;
+use crate::VcpuX86_64;
+use crate::VmX86_64;
+
+/// Interrupt enable flag in RFLAGS.
+const RFLAGS_IF: u64 = 1 << 9;
+
+impl FakeHypervisor {
+    /// Sets the CPUID entries returned by `get_supported_cpuid`.
+    pub fn set_supported_cpuid(&mut self, cpuid: &CpuId) {
+        self.supported_cpuid = cpuid.cpu_id_entries.clone();
+    }
+}
+
+impl HypervisorX86_64 for FakeHypervisor {
+    fn get_supported_cpuid(&self) -> Result<CpuId> {
+        Ok(CpuId {
+            cpu_id_entries: self.supported_cpuid.clone(),
+        })
+    }
+
+    fn get_emulated_cpuid(&self) -> Result<CpuId> {
+        Ok(CpuId::new(0))
+    }
+
+    fn get_msr_index_list(&self) -> Result<Vec<u32>> {
+        Ok(Vec::new())
+    }
+}
+
+impl VmX86_64 for FakeVm {
+    fn get_hypervisor(&self) -> &dyn HypervisorX86_64 {
+        &self.fake
+    }
+
+    fn create_vcpu(&self, id: usize) -> Result<Box<dyn VcpuX86_64>> {
+        Ok(Box::new(self.create_fake_vcpu(id)?))
+    }
+
+    fn set_tss_addr(&self, addr: GuestAddress) -> Result<()> {
+        self.state.lock().tss_addr = Some(addr);
+        Ok(())
+    }
+
+    fn set_identity_map_addr(&self, addr: GuestAddress) -> Result<()> {
+        self.state.lock().identity_map_addr = Some(addr);
+        Ok(())
+    }
+}
+
+/// Architectural state of a `FakeVcpu`.
+#[derive(Default)]
+pub(super) struct FakeVcpuArchState {
+    cpuid: Vec<CpuIdEntry>,
+    debugregs: DebugRegs,
+    fpu: Fpu,
+    guest_debug: GuestDebug,
+    injected_interrupts: Vec<u32>,
+    injected_nmis: usize,
+    interrupt_window_requested: bool,
+    msrs: BTreeMap<u32, u64>,
+    regs: Regs,
+    sregs: Sregs,
+    tsc_offset: u64,
+    xcrs: BTreeMap<u32, u64>,
+}
+
+impl FakeVcpu {
+    /// Returns the CPUID entries set with `set_cpuid`.
+    pub fn cpuid(&self) -> CpuId {
+        CpuId {
+            cpu_id_entries: self.state.lock().arch.cpuid.clone(),
+        }
+    }
+
+    /// Returns the debugging state set with `set_guest_debug`.
+    pub fn guest_debug(&self) -> GuestDebug {
+        self.state.lock().arch.guest_debug.clone()
+    }
+
+    /// Returns whether an interrupt window exit was requested with
+    /// `set_interrupt_window_requested`.
+    pub fn interrupt_window_requested(&self) -> bool {
+        self.state.lock().arch.interrupt_window_requested
+    }
+
+    /// Returns and clears the vectors injected with `interrupt`, in injection order.
+    pub fn take_injected_interrupts(&self) -> Vec<u32> {
+        std::mem::take(&mut self.state.lock().arch.injected_interrupts)
+    }
+
+    /// Returns and clears the number of NMIs injected with `inject_nmi`.
+    pub fn take_injected_nmis(&self) -> usize {
+        std::mem::take(&mut self.state.lock().arch.injected_nmis)
+    }
+}
+
+impl VcpuX86_64 for FakeVcpu {
+    fn set_interrupt_window_requested(&self, requested: bool) {
+        self.state.lock().arch.interrupt_window_requested = requested;
+    }
+
+    /// The fake VCPU accepts interrupts whenever the interrupt flag is set in its RFLAGS.
+    fn ready_for_interrupt(&self) -> bool {
+        self.state.lock().arch.regs.rflags & RFLAGS_IF != 0
+    }
+
+    fn interrupt(&self, irq: u32) -> Result<()> {
+        self.state.lock().arch.injected_interrupts.push(irq);
+        Ok(())
+    }
+
+    fn inject_nmi(&self) -> Result<()> {
+        self.state.lock().arch.injected_nmis += 1;
+        Ok(())
+    }
+
+    fn get_regs(&self) -> Result<Regs> {
+        Ok(self.state.lock().arch.regs)
+    }
+
+    fn set_regs(&self, regs: &Regs) -> Result<()> {
+        self.state.lock().arch.regs = *regs;
+        Ok(())
+    }
+
+    fn get_sregs(&self) -> Result<Sregs> {
+        Ok(self.state.lock().arch.sregs)
+    }
+
+    fn set_sregs(&self, sregs: &Sregs) -> Result<()> {
+        self.state.lock().arch.sregs = *sregs;
+        Ok(())
+    }
+
+    fn get_fpu(&self) -> Result<Fpu> {
+        Ok(self.state.lock().arch.fpu)
+    }
+
+    fn set_fpu(&self, fpu: &Fpu) -> Result<()> {
+        self.state.lock().arch.fpu = *fpu;
+        Ok(())
+    }
+
+    fn get_debugregs(&self) -> Result<DebugRegs> {
+        Ok(self.state.lock().arch.debugregs)
+    }
+
+    fn set_debugregs(&self, debugregs: &DebugRegs) -> Result<()> {
+        self.state.lock().arch.debugregs = *debugregs;
+        Ok(())
+    }
+
+    fn get_xcrs(&self) -> Result<Vec<Register>> {
+        Ok(self
+            .state
+            .lock()
+            .arch
+            .xcrs
+            .iter()
+            .map(|(&id, &value)| Register { id, value })
+            .collect())
+    }
+
+    fn set_xcrs(&self, xcrs: &[Register]) -> Result<()> {
+        let mut state = self.state.lock();
+        for xcr in xcrs {
+            state.arch.xcrs.insert(xcr.id, xcr.value);
+        }
+        Ok(())
+    }
+
+    /// MSRs that were never set read as 0.
+    fn get_msrs(&self, msrs: &mut Vec<Register>) -> Result<()> {
+        let state = self.state.lock();
+        for msr in msrs.iter_mut() {
+            msr.value = state.arch.msrs.get(&msr.id).copied().unwrap_or(0);
+        }
+        Ok(())
+    }
+
+    fn set_msrs(&self, msrs: &[Register]) -> Result<()> {
+        let mut state = self.state.lock();
+        for msr in msrs {
+            state.arch.msrs.insert(msr.id, msr.value);
+        }
+        Ok(())
+    }
+
+    fn set_cpuid(&self, cpuid: &CpuId) -> Result<()> {
+        self.state.lock().arch.cpuid = cpuid.cpu_id_entries.clone();
+        Ok(())
+    }
+
+    fn handle_cpuid(&mut self, _entry: &CpuIdEntry) -> Result<()> {
+        Err(Error::new(ENXIO))
+    }
+
+    fn get_hyperv_cpuid(&self) -> Result<CpuId> {
+        Ok(CpuId::new(0))
+    }
+
+    fn set_guest_debug(&self, debug: &GuestDebug) -> Result<()> {
+        self.state.lock().arch.guest_debug = debug.clone();
+        Ok(())
+    }
+
+    fn get_debug_exit_watchpoint(&self, _debug: &GuestDebug) -> Result<Option<usize>> {
+        Ok(None)
+    }
+
+    fn inject_breakpoint(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_tsc_offset(&self) -> Result<u64> {
+        Ok(self.state.lock().arch.tsc_offset)
+    }
+
+    fn set_tsc_offset(&self, offset: u64) -> Result<()> {
+        self.state.lock().arch.tsc_offset = offset;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vm_memory::GuestMemory;
+
+    use super::*;
+    use crate::Vcpu;
+
+    #[test]
+    fn vcpu_state() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vm = FakeVm::new(&FakeHypervisor::new(), mem).unwrap();
+        vm.set_tss_addr(GuestAddress(0xfffb_d000)).unwrap();
+        assert_eq!(vm.tss_addr(), Some(GuestAddress(0xfffb_d000)));
+
+        let vcpu = vm.create_vcpu(0).unwrap();
+        assert!(vm.create_vcpu(0).is_err());
+        // Clones share the state of the VCPU.
+        let clone = vcpu
+            .downcast_ref::<FakeVcpu>()
+            .unwrap()
+            .try_clone()
+            .unwrap();
+
+        assert!(!vcpu.ready_for_interrupt());
+        let regs = Regs {
+            rip: 0x1000,
+            rflags: 0x2 | RFLAGS_IF,
+            ..Default::default()
+        };
+        vcpu.set_regs(&regs).unwrap();
+        assert_eq!(clone.get_regs().unwrap().rip, 0x1000);
+        assert!(clone.ready_for_interrupt());
+
+        vcpu.set_msrs(&[Register { id: 0x10, value: 5 }]).unwrap();
+        let mut msrs = vec![
+            Register { id: 0x10, value: 0 },
+            Register { id: 0x11, value: 3 },
+        ];
+        clone.get_msrs(&mut msrs).unwrap();
+        assert_eq!(msrs[0].value, 5);
+        assert_eq!(msrs[1].value, 0);
+
+        vcpu.interrupt(0x30).unwrap();
+        vcpu.interrupt(0x31).unwrap();
+        assert_eq!(clone.take_injected_interrupts(), vec![0x30, 0x31]);
+        assert!(clone.take_injected_interrupts().is_empty());
+    }
+}
@@ -36,6 +36,7 @@ use crate::CpuIdEntry;
 use crate::DebugRegs;
 use crate::DescriptorTable;
 use crate::Fpu;
+use crate::GuestDebug;
 use crate::HypervHypercall;
 use crate::IoOperation;
 use crate::IoParams;
@@ -588,7 +589,17 @@ impl VcpuX86_64 for HaxmVcpu {
         Err(Error::new(libc::ENXIO))
     }
 
-    fn set_guest_debug(&self, _addrs: &[GuestAddress], _enable_singlestep: bool) -> Result<()> {
+    fn set_guest_debug(&self, _debug: &GuestDebug) -> Result<()> {
+        // TODO(b/173807302): Implement this
+        Err(Error::new(ENOENT))
+    }
+
+    fn get_debug_exit_watchpoint(&self, _debug: &GuestDebug) -> Result<Option<usize>> {
+        // TODO(b/173807302): Implement this
+        Err(Error::new(ENOENT))
+    }
+
+    fn inject_breakpoint(&self) -> Result<()> {
         // TODO(b/173807302): Implement this
         Err(Error::new(ENOENT))
     }
@@ -300,6 +300,20 @@ impl Vm for HaxmVm {
         Err(Error::new(libc::ENXIO))
     }
 
+    fn set_guest_memory_dirty_log(&mut self, _enable: bool) -> Result<()> {
+        // Haxm does not support VmCap::DirtyLog
+        Err(Error::new(libc::ENXIO))
+    }
+
+    fn get_guest_memory_dirty_log(
+        &self,
+        _guest_addr: GuestAddress,
+        _dirty_log: &mut [u8],
+    ) -> Result<()> {
+        // Haxm does not support VmCap::DirtyLog
+        Err(Error::new(libc::ENXIO))
+    }
+
     fn register_ioevent(
         &mut self,
         evt: &Event,
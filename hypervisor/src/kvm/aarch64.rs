@@ -38,6 +38,8 @@ use super::KvmVcpu;
 use super::KvmVm;
 use crate::ClockState;
 use crate::DeviceKind;
+#[cfg(feature = "gdb")]
+use crate::GuestDebug;
 use crate::Hypervisor;
 use crate::IrqSourceChip;
 use crate::ProtectionType;
@@ -48,6 +50,8 @@ use crate::VcpuFeature;
 use crate::VcpuRegAArch64;
 use crate::VmAArch64;
 use crate::VmCap;
+#[cfg(feature = "gdb")]
+use crate::WatchpointKind;
 use crate::PSCI_0_2;
 
 impl Kvm {
@@ -689,18 +693,44 @@ impl VcpuAArch64 for KvmVcpu {
     }
 
     #[cfg(feature = "gdb")]
-    fn set_guest_debug(&self, addrs: &[GuestAddress], enable_singlestep: bool) -> Result<()> {
+    fn get_max_hw_wps(&self) -> Result<usize> {
+        // Safe because the kernel will only return the result of the ioctl.
+        let max_hw_wps = unsafe {
+            ioctl_with_val(
+                &self.vm,
+                KVM_CHECK_EXTENSION(),
+                KVM_CAP_GUEST_DEBUG_HW_WPS.into(),
+            )
+        };
+
+        if max_hw_wps < 0 {
+            errno_result()
+        } else {
+            Ok(max_hw_wps.try_into().expect("can't represent u64 as usize"))
+        }
+    }
+
+    #[cfg(feature = "gdb")]
+    fn set_guest_debug(&self, debug: &GuestDebug) -> Result<()> {
         let mut dbg: kvm_guest_debug = Default::default();
 
         dbg.control = KVM_GUESTDBG_ENABLE;
-        if enable_singlestep {
+        if debug.single_step {
             dbg.control |= KVM_GUESTDBG_SINGLESTEP;
         }
-        if !addrs.is_empty() {
+        if debug.sw_breakpoints {
+            dbg.control |= KVM_GUESTDBG_USE_SW_BP;
+        }
+        if !debug.hw_breakpoints.is_empty() || !debug.watchpoints.is_empty() {
             dbg.control |= KVM_GUESTDBG_USE_HW;
         }
+        if debug.hw_breakpoints.len() > dbg.arch.dbg_bvr.len()
+            || debug.watchpoints.len() > dbg.arch.dbg_wvr.len()
+        {
+            return Err(Error::new(EINVAL));
+        }
 
-        for (i, guest_addr) in addrs.iter().enumerate() {
+        for (i, guest_addr) in debug.hw_breakpoints.iter().enumerate() {
             // From the ARMv8 Architecture Reference Manual (DDI0487H.a) D31.3.{2,3}:
             // When DBGBCR<n>_EL1.BT == 0b000x:
             //      DBGBVR<n>_EL1, Bits [1:0]: Reserved, RES0
@@ -722,6 +752,34 @@ impl VcpuAArch64 for KvmVcpu {
             dbg.arch.dbg_bcr[i] = 0b1111_11_1;
         }
 
+        for (i, watchpoint) in debug.watchpoints.iter().enumerate() {
+            // The watched bytes must fit in the doubleword selected by DBGWVR<n>_EL1.
+            let offset = watchpoint.addr.0 & 0b111;
+            if watchpoint.len == 0 || offset + watchpoint.len > 8 {
+                return Err(Error::new(EINVAL));
+            }
+            let sign_ext = 15;
+            // DBGWVR<n>_EL1, Bits [2:0]: Reserved, RES0
+            //      DBGWVR<n>_EL1.RESS[14:0], bits [63:49]: Reserved, Sign extended
+            dbg.arch.dbg_wvr[i] =
+                ((((watchpoint.addr.0 & !0b111) << sign_ext) as i64) >> sign_ext) as u64;
+            // DBGWCR<n>_EL1.BAS, bits [12:5]: Byte address select
+            //      One bit per watched byte of the doubleword.
+            let bas = ((1u64 << watchpoint.len) - 1) << offset;
+            // DBGWCR<n>_EL1.LSC, bits [4:3]: Load/store control
+            //      0b01: Loads, 0b10: Stores, 0b11: Loads and stores.
+            let lsc = match watchpoint.kind {
+                WatchpointKind::Read => 0b01,
+                WatchpointKind::Write => 0b10,
+                WatchpointKind::ReadWrite => 0b11,
+            };
+            // DBGWCR<n>_EL1.PAC, bits [2:1]: Privilege of access control
+            //      0b11: EL1 & EL0
+            // DBGWCR<n>_EL1.E, bit [0]: Enable watchpoint
+            //      0b1: Enabled
+            dbg.arch.dbg_wcr[i] = (bas << 5) | (lsc << 3) | 0b11_1;
+        }
+
         // Safe because the kernel won't read past the end of the kvm_guest_debug struct.
         let ret = unsafe { ioctl_with_ref(self, KVM_SET_GUEST_DEBUG(), &dbg) };
         if ret == 0 {
@@ -731,6 +789,79 @@ impl VcpuAArch64 for KvmVcpu {
         }
     }
 
+    #[cfg(feature = "gdb")]
+    fn get_debug_exit_watchpoint(&self, debug: &GuestDebug) -> Result<Option<usize>> {
+        // Exception classes of watchpoint exceptions from a lower or the same exception level.
+        const ESR_ELX_EC_WATCHPT_LOW: u32 = 0x34;
+        const ESR_ELX_EC_WATCHPT_CUR: u32 = 0x35;
+
+        // Safe because we know we mapped enough memory to hold the kvm_run struct because the
+        // kernel told us how large it was.
+        let run = unsafe { &*(self.run_mmap.as_ptr() as *const kvm_run) };
+        if run.exit_reason != KVM_EXIT_DEBUG {
+            return Err(Error::new(EINVAL));
+        }
+        // Safe because the exit_reason (which comes from the kernel) told us which
+        // union field to use.
+        let arch = unsafe { run.__bindgen_anon_1.debug.arch };
+        let ec = arch.hsr >> 26;
+        if ec != ESR_ELX_EC_WATCHPT_LOW && ec != ESR_ELX_EC_WATCHPT_CUR {
+            return Ok(None);
+        }
+        // FAR holds an address accessed by the instruction, within the watched doubleword.
+        Ok(debug
+            .watchpoints
+            .iter()
+            .position(|w| w.addr.0 & !0b111 == arch.far & !0b111))
+    }
+
+    #[cfg(feature = "gdb")]
+    fn inject_breakpoint(&self) -> Result<()> {
+        // System register encodings (Op0, Op1, CRn, CRm, Op2) of ESR_EL1 and VBAR_EL1.
+        const ESR_EL1: u16 = 3 << 14 | 5 << 7 | 2 << 3;
+        const VBAR_EL1: u16 = 3 << 14 | 12 << 7;
+        const PSR_MODE32_BIT: u64 = 0x10;
+        const PSR_MODE_MASK: u64 = 0xf;
+        const PSR_MODE_EL0T: u64 = 0x0;
+        const PSR_MODE_EL1T: u64 = 0x4;
+        const PSR_MODE_EL1H: u64 = 0x5;
+        // D, A, I and F are masked on exception entry.
+        const PSR_DAIF: u64 = 0xf << 6;
+
+        // Safe because we know we mapped enough memory to hold the kvm_run struct because the
+        // kernel told us how large it was.
+        let run = unsafe { &*(self.run_mmap.as_ptr() as *const kvm_run) };
+        if run.exit_reason != KVM_EXIT_DEBUG {
+            return Err(Error::new(EINVAL));
+        }
+        // Safe because the exit_reason (which comes from the kernel) told us which
+        // union field to use.
+        let esr = unsafe { run.__bindgen_anon_1.debug.arch.hsr };
+
+        // KVM can't inject synchronous exceptions, so take the exception to EL1 the way the CPU
+        // would have without the debugger: the BRK instruction is the preferred return address.
+        let pc = self.get_one_kvm_reg_u64(KvmVcpuRegister::Pc)?;
+        let pstate = self.get_one_kvm_reg_u64(KvmVcpuRegister::Pstate)?;
+        let vbar = self.get_one_kvm_reg_u64(KvmVcpuRegister::System(VBAR_EL1))?;
+        // Offset of the synchronous exception vector for the exception level and stack pointer
+        // that the vCPU was using.
+        let vector = if pstate & PSR_MODE32_BIT != 0 {
+            0x600
+        } else {
+            match pstate & PSR_MODE_MASK {
+                PSR_MODE_EL1T => 0x000,
+                PSR_MODE_EL1H => 0x200,
+                PSR_MODE_EL0T => 0x400,
+                _ => return Err(Error::new(EINVAL)),
+            }
+        };
+        self.set_one_kvm_reg_u64(KvmVcpuRegister::ElrEl1, pc)?;
+        self.set_one_kvm_reg_u64(KvmVcpuRegister::Spsr(KVM_SPSR_EL1 as u8), pstate)?;
+        self.set_one_kvm_reg_u64(KvmVcpuRegister::System(ESR_EL1), esr.into())?;
+        self.set_one_kvm_reg_u64(KvmVcpuRegister::Pstate, PSR_MODE_EL1H | PSR_DAIF)?;
+        self.set_one_kvm_reg_u64(KvmVcpuRegister::Pc, vbar + vector)
+    }
+
     #[cfg(feature = "gdb")]
     fn set_gdb_registers(&self, regs: &<GdbArch as Arch>::Registers) -> Result<()> {
         assert!(
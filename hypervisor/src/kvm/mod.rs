@@ -204,6 +204,8 @@ pub struct KvmVm {
     mem_regions: Arc<Mutex<BTreeMap<MemSlot, Box<dyn MappedRegion>>>>,
     /// A min heap of MemSlot numbers that were used and then removed and can now be re-used
     mem_slot_gaps: Arc<Mutex<BinaryHeap<Reverse<MemSlot>>>>,
+    /// The slots of the regions of `guest_mem`, by guest address.
+    guest_mem_slots: Arc<BTreeMap<GuestAddress, MemSlot>>,
 }
 
 impl KvmVm {
@@ -223,7 +225,9 @@ impl KvmVm {
         }
         // Safe because we verify that ret is valid and we own the fd.
         let vm_descriptor = unsafe { SafeDescriptor::from_raw_descriptor(ret) };
+        let mut guest_mem_slots = BTreeMap::new();
         guest_mem.with_regions(|index, guest_addr, size, host_addr, _, _| {
+            guest_mem_slots.insert(guest_addr, index as MemSlot);
             unsafe {
                 // Safe because the guest regions are guaranteed not to overlap.
                 set_user_memory_region(
@@ -244,11 +248,34 @@ impl KvmVm {
             guest_mem,
             mem_regions: Arc::new(Mutex::new(BTreeMap::new())),
             mem_slot_gaps: Arc::new(Mutex::new(BinaryHeap::new())),
+            guest_mem_slots: Arc::new(guest_mem_slots),
         };
         vm.init_arch(&cfg)?;
         Ok(vm)
     }
 
+    /// Gets the dirty log of `slot`, which maps `size` bytes.
+    fn get_slot_dirty_log(&self, slot: MemSlot, size: usize, dirty_log: &mut [u8]) -> Result<()> {
+        // Ensures that there are as many bytes in dirty_log as there are pages in the mmap.
+        if dirty_log_bitmap_size(size) > dirty_log.len() {
+            return Err(Error::new(EINVAL));
+        }
+
+        let mut dirty_log_kvm = kvm_dirty_log {
+            slot,
+            ..Default::default()
+        };
+        dirty_log_kvm.__bindgen_anon_1.dirty_bitmap = dirty_log.as_ptr() as *mut c_void;
+        // Safe because the `dirty_bitmap` pointer assigned above is guaranteed to be valid (because
+        // it's from a slice) and we checked that it will be large enough to hold the entire log.
+        let ret = unsafe { ioctl_with_ref(self, KVM_GET_DIRTY_LOG(), &dirty_log_kvm) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            errno_result()
+        }
+    }
+
     pub fn create_kvm_vcpu(&self, id: usize) -> Result<KvmVcpu> {
         let run_mmap_size = self.kvm.get_vcpu_mmap_size()?;
 
@@ -481,6 +508,7 @@ impl Vm for KvmVm {
             guest_mem: self.guest_mem.clone(),
             mem_regions: self.mem_regions.clone(),
             mem_slot_gaps: self.mem_slot_gaps.clone(),
+            guest_mem_slots: self.guest_mem_slots.clone(),
         })
     }
 
@@ -611,24 +639,44 @@ impl Vm for KvmVm {
     fn get_dirty_log(&self, slot: MemSlot, dirty_log: &mut [u8]) -> Result<()> {
         let regions = self.mem_regions.lock();
         let mmap = regions.get(&slot).ok_or_else(|| Error::new(ENOENT))?;
-        // Ensures that there are as many bytes in dirty_log as there are pages in the mmap.
-        if dirty_log_bitmap_size(mmap.size()) > dirty_log.len() {
-            return Err(Error::new(EINVAL));
-        }
+        self.get_slot_dirty_log(slot, mmap.size(), dirty_log)
+    }
 
-        let mut dirty_log_kvm = kvm_dirty_log {
-            slot,
-            ..Default::default()
-        };
-        dirty_log_kvm.__bindgen_anon_1.dirty_bitmap = dirty_log.as_ptr() as *mut c_void;
-        // Safe because the `dirty_bitmap` pointer assigned above is guaranteed to be valid (because
-        // it's from a slice) and we checked that it will be large enough to hold the entire log.
-        let ret = unsafe { ioctl_with_ref(self, KVM_GET_DIRTY_LOG(), &dirty_log_kvm) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            errno_result()
-        }
+    fn set_guest_memory_dirty_log(&mut self, enable: bool) -> Result<()> {
+        self.guest_mem
+            .with_regions(|_, guest_addr, size, host_addr, _, _| {
+                unsafe {
+                    // Safe because the guest regions are guaranteed not to overlap and this only
+                    // changes the flags of the slots set up in `new`.
+                    set_user_memory_region(
+                        &self.vm,
+                        self.guest_mem_slots[&guest_addr],
+                        false,
+                        enable,
+                        guest_addr.offset(),
+                        size as u64,
+                        host_addr as *mut u8,
+                    )
+                }
+            })
+    }
+
+    fn get_guest_memory_dirty_log(
+        &self,
+        guest_addr: GuestAddress,
+        dirty_log: &mut [u8],
+    ) -> Result<()> {
+        let slot = *self
+            .guest_mem_slots
+            .get(&guest_addr)
+            .ok_or_else(|| Error::new(ENOENT))?;
+        let (_, size) = self
+            .guest_mem
+            .guest_memory_regions()
+            .into_iter()
+            .find(|&(addr, _)| addr == guest_addr)
+            .ok_or_else(|| Error::new(ENOENT))?;
+        self.get_slot_dirty_log(slot, size, dirty_log)
     }
 
     fn register_ioevent(
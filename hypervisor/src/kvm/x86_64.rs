@@ -37,6 +37,7 @@ use crate::DebugRegs;
 use crate::DescriptorTable;
 use crate::DeviceKind;
 use crate::Fpu;
+use crate::GuestDebug;
 use crate::HypervisorX86_64;
 use crate::IoapicRedirectionTableEntry;
 use crate::IoapicState;
@@ -55,6 +56,7 @@ use crate::VcpuExit;
 use crate::VcpuX86_64;
 use crate::VmCap;
 use crate::VmX86_64;
+use crate::WatchpointKind;
 use crate::MAX_IOAPIC_PINS;
 use crate::NUM_IOAPIC_PINS;
 
@@ -779,34 +781,65 @@ impl VcpuX86_64 for KvmVcpu {
         get_cpuid_with_initial_capacity(self, KVM_GET_SUPPORTED_HV_CPUID(), KVM_MAX_ENTRIES)
     }
 
-    fn set_guest_debug(&self, addrs: &[GuestAddress], enable_singlestep: bool) -> Result<()> {
+    fn set_guest_debug(&self, debug: &GuestDebug) -> Result<()> {
         use kvm_sys::*;
         let mut dbg: kvm_guest_debug = Default::default();
 
-        if addrs.len() > 4 {
+        let slots = debug.hw_breakpoints.len() + debug.watchpoints.len();
+        if slots > 4 {
             error!(
-                "Support 4 breakpoints at most but {} addresses are passed",
-                addrs.len()
+                "Support 4 breakpoints and watchpoints at most but {} are passed",
+                slots
             );
             return Err(base::Error::new(libc::EINVAL));
         }
 
         dbg.control = KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_HW_BP;
-        if enable_singlestep {
+        if debug.single_step {
             dbg.control |= KVM_GUESTDBG_SINGLESTEP;
         }
+        if debug.sw_breakpoints {
+            dbg.control |= KVM_GUESTDBG_USE_SW_BP;
+        }
 
         // Set bits 9 and 10.
         // bit 9: GE (global exact breakpoint enable) flag.
         // bit 10: always 1.
         dbg.arch.debugreg[7] = 0x0600;
 
-        for (i, addr) in addrs.iter().enumerate() {
+        for (i, addr) in debug.hw_breakpoints.iter().enumerate() {
             dbg.arch.debugreg[i] = addr.0;
             // Set global breakpoint enable flag
             dbg.arch.debugreg[7] |= 2 << (i * 2);
         }
 
+        // Watchpoints use the debug registers left after the breakpoints.
+        for (i, watchpoint) in debug.watchpoints.iter().enumerate() {
+            let i = debug.hw_breakpoints.len() + i;
+            // R/W bits: 0b01 breaks on data writes, 0b11 on data reads or writes.
+            let rw = match watchpoint.kind {
+                WatchpointKind::Write => 0b01,
+                WatchpointKind::Read | WatchpointKind::ReadWrite => 0b11,
+            };
+            // LEN bits: 0b00 for 1 byte, 0b01 for 2, 0b11 for 4 and 0b10 for 8.
+            let len = match watchpoint.len {
+                1 => 0b00,
+                2 => 0b01,
+                4 => 0b11,
+                8 => 0b10,
+                _ => {
+                    error!("Unsupported watchpoint length {}", watchpoint.len);
+                    return Err(base::Error::new(libc::EINVAL));
+                }
+            };
+            if watchpoint.addr.0 % watchpoint.len != 0 {
+                error!("Unaligned watchpoint address {}", watchpoint.addr);
+                return Err(base::Error::new(libc::EINVAL));
+            }
+            dbg.arch.debugreg[i] = watchpoint.addr.0;
+            dbg.arch.debugreg[7] |= (2 << (i * 2)) | (rw << (16 + i * 4)) | (len << (18 + i * 4));
+        }
+
         let ret = unsafe {
             // Here we trust the kernel not to read past the end of the kvm_guest_debug struct.
             ioctl_with_ref(self, KVM_SET_GUEST_DEBUG(), &dbg)
@@ -818,6 +851,47 @@ impl VcpuX86_64 for KvmVcpu {
         }
     }
 
+    fn get_debug_exit_watchpoint(&self, debug: &GuestDebug) -> Result<Option<usize>> {
+        // Safe because we know we mapped enough memory to hold the kvm_run struct because the
+        // kernel told us how large it was.
+        let run = unsafe { &*(self.run_mmap.as_ptr() as *const kvm_run) };
+        if run.exit_reason != KVM_EXIT_DEBUG {
+            return Err(base::Error::new(libc::EINVAL));
+        }
+        // Safe because the exit_reason (which comes from the kernel) told us which
+        // union field to use.
+        let dr6 = unsafe { run.__bindgen_anon_1.debug.arch.dr6 };
+        // Bits 0-3 of DR6 tell which debug registers triggered the exit.
+        let first = debug.hw_breakpoints.len();
+        Ok((0..debug.watchpoints.len()).find(|i| dr6 & (1 << (first + i)) != 0))
+    }
+
+    fn inject_breakpoint(&self) -> Result<()> {
+        const BP_VECTOR: u8 = 3;
+
+        let mut events: kvm_vcpu_events = Default::default();
+        // Safe because we know that our file is a VCPU fd, we know the kernel will only write the
+        // correct amount of memory to our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_mut_ref(self, KVM_GET_VCPU_EVENTS(), &mut events) };
+        if ret != 0 {
+            return errno_result();
+        }
+        // KVM remembers the length of the INT3 instruction that exited, so the guest handler
+        // returns after it.
+        events.exception.injected = 1;
+        events.exception.nr = BP_VECTOR;
+        events.exception.has_error_code = 0;
+        events.exception.error_code = 0;
+        // Safe because we know that our file is a VCPU fd, we know the kernel will only read the
+        // correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_VCPU_EVENTS(), &events) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            errno_result()
+        }
+    }
+
     /// KVM does not support the VcpuExit::Cpuid exit type.
     fn handle_cpuid(&mut self, _entry: &CpuIdEntry) -> Result<()> {
         Err(Error::new(ENXIO))
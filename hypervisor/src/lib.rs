@@ -7,6 +7,8 @@
 pub mod aarch64;
 pub mod caps;
 
+#[cfg(feature = "fake")]
+pub mod fake;
 #[cfg(all(windows, feature = "haxm"))]
 pub mod haxm;
 #[cfg(unix)]
@@ -109,6 +111,21 @@ pub trait Vm: Send {
     /// be 2 bytes or greater.
     fn get_dirty_log(&self, slot: MemSlot, dirty_log: &mut [u8]) -> Result<()>;
 
+    /// Enables or disables dirty page logging for the guest memory returned by `get_memory`.  Only
+    /// works on VMs that support `VmCap::DirtyLog`.
+    fn set_guest_memory_dirty_log(&mut self, enable: bool) -> Result<()>;
+
+    /// Gets the bitmap of dirty pages since the last call for the region of the guest memory
+    /// returned by `get_memory` that starts at `guest_addr`. Dirty page logging must have been
+    /// enabled with `set_guest_memory_dirty_log`.
+    ///
+    /// The size of `dirty_log` must be at least as many bits as there are pages in the region.
+    fn get_guest_memory_dirty_log(
+        &self,
+        guest_addr: GuestAddress,
+        dirty_log: &mut [u8],
+    ) -> Result<()>;
+
     /// Registers an event to be signaled whenever a certain address is written to.
     ///
     /// The `datamatch` parameter can be used to limit signaling `evt` to only the cases where the
@@ -380,7 +397,7 @@ pub enum IoEventAddress {
 }
 
 /// Used in `Vm::register_ioevent` to indicate a size and optionally value to match.
-#[derive(PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Datamatch {
     AnyLength,
     U8(Option<u8>),
@@ -515,7 +532,7 @@ pub struct IrqRoute {
 }
 
 /// The state of the paravirtual clock.
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
 pub struct ClockState {
     /// Current pv clock timestamp, as seen by the guest
     pub clock: u64,
@@ -525,7 +542,7 @@ pub struct ClockState {
 
 /// The MPState represents the state of a processor.
 #[repr(C)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MPState {
     /// the vcpu is currently running (x86/x86_64,arm/arm64)
     Runnable,
@@ -542,6 +559,36 @@ pub enum MPState {
     Stopped,
 }
 
+/// The kind of memory access that triggers a `Watchpoint`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchpointKind {
+    Write,
+    Read,
+    ReadWrite,
+}
+
+/// A hardware watchpoint on `len` bytes of guest virtual memory starting at `addr`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: GuestAddress,
+    pub len: u64,
+    pub kind: WatchpointKind,
+}
+
+/// The guest debugging state of a VCPU, as set by `set_guest_debug`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GuestDebug {
+    /// Guest virtual addresses of the hardware breakpoints.
+    pub hw_breakpoints: Vec<GuestAddress>,
+    /// Hardware watchpoints, sharing the debug registers with `hw_breakpoints` when the
+    /// architecture does not have dedicated ones.
+    pub watchpoints: Vec<Watchpoint>,
+    /// Exit to the VMM instead of raising an exception in the guest on software breakpoints.
+    pub sw_breakpoints: bool,
+    /// Exit to the VMM after every guest instruction.
+    pub single_step: bool,
+}
+
 /// Whether the VM should be run in protected mode or not.
 #[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub enum ProtectionType {
@@ -28,6 +28,7 @@ use crate::CpuId;
 use crate::CpuIdEntry;
 use crate::DebugRegs;
 use crate::Fpu;
+use crate::GuestDebug;
 use crate::HypervHypercall;
 use crate::IoOperation;
 use crate::IoParams;
@@ -1245,7 +1246,17 @@ impl VcpuX86_64 for WhpxVcpu {
     }
 
     /// Sets up debug registers and configure vcpu for handling guest debug events.
-    fn set_guest_debug(&self, _addrs: &[GuestAddress], _enable_singlestep: bool) -> Result<()> {
+    fn set_guest_debug(&self, _debug: &GuestDebug) -> Result<()> {
+        // TODO(b/173807302): Implement this
+        Err(Error::new(ENOENT))
+    }
+
+    fn get_debug_exit_watchpoint(&self, _debug: &GuestDebug) -> Result<Option<usize>> {
+        // TODO(b/173807302): Implement this
+        Err(Error::new(ENOENT))
+    }
+
+    fn inject_breakpoint(&self) -> Result<()> {
         // TODO(b/173807302): Implement this
         Err(Error::new(ENOENT))
     }
@@ -528,6 +528,20 @@ impl Vm for WhpxVm {
         }
     }
 
+    fn set_guest_memory_dirty_log(&mut self, _enable: bool) -> Result<()> {
+        // Guest memory is mapped without dirty page tracking.
+        Err(Error::new(ENOTSUP))
+    }
+
+    fn get_guest_memory_dirty_log(
+        &self,
+        _guest_addr: GuestAddress,
+        _dirty_log: &mut [u8],
+    ) -> Result<()> {
+        // Guest memory is mapped without dirty page tracking.
+        Err(Error::new(ENOTSUP))
+    }
+
     fn register_ioevent(
         &mut self,
         evt: &Event,
@@ -16,6 +16,7 @@ use serde::Deserialize;
 use serde::Serialize;
 use vm_memory::GuestAddress;
 
+use crate::GuestDebug;
 use crate::Hypervisor;
 use crate::IrqRoute;
 use crate::IrqSource;
@@ -109,7 +110,15 @@ pub trait VcpuX86_64: Vcpu {
     fn get_hyperv_cpuid(&self) -> Result<CpuId>;
 
     /// Sets up debug registers and configure vcpu for handling guest debug events.
-    fn set_guest_debug(&self, addrs: &[GuestAddress], enable_singlestep: bool) -> Result<()>;
+    fn set_guest_debug(&self, debug: &GuestDebug) -> Result<()>;
+
+    /// Returns the index in `debug.watchpoints` of the watchpoint that caused the last
+    /// `VcpuExit::Debug`, if any. `debug` must be the state last passed to `set_guest_debug`.
+    fn get_debug_exit_watchpoint(&self, debug: &GuestDebug) -> Result<Option<usize>>;
+
+    /// Delivers to the guest the breakpoint exception (#BP) that caused the last
+    /// `VcpuExit::Debug`, for breakpoint instructions placed by the guest itself.
+    fn inject_breakpoint(&self) -> Result<()>;
 
     /// This function should be called after `Vcpu::run` returns `VcpuExit::Cpuid`, and `entry`
     /// should represent the result of emulating the CPUID instruction. The `handle_cpuid` function
@@ -121,6 +130,37 @@ pub trait VcpuX86_64: Vcpu {
 
     /// Set the guest->host TSC offset
     fn set_tsc_offset(&self, offset: u64) -> Result<()>;
+
+    /// Captures the architectural state of this VCPU. `msr_indices` lists the MSRs to be saved;
+    /// MSRs that the hypervisor refuses to read are skipped.
+    fn snapshot(&self, msr_indices: &[u32]) -> Result<VcpuSnapshot> {
+        let mut msrs = Vec::with_capacity(msr_indices.len());
+        // KVM_GET_MSRS stops at the first MSR it fails to read, so query them one at a time.
+        for &id in msr_indices {
+            let mut msr = vec![Register { id, value: 0 }];
+            if self.get_msrs(&mut msr).is_ok() {
+                msrs.extend(msr);
+            }
+        }
+        Ok(VcpuSnapshot {
+            regs: self.get_regs()?,
+            sregs: self.get_sregs()?,
+            fpu: self.get_fpu()?,
+            debug_regs: self.get_debugregs()?,
+            xcrs: self.get_xcrs()?,
+            msrs,
+        })
+    }
+
+    /// Restores state previously captured by `snapshot`.
+    fn restore(&self, snapshot: &VcpuSnapshot) -> Result<()> {
+        self.set_sregs(&snapshot.sregs)?;
+        self.set_regs(&snapshot.regs)?;
+        self.set_fpu(&snapshot.fpu)?;
+        self.set_debugregs(&snapshot.debug_regs)?;
+        self.set_xcrs(&snapshot.xcrs)?;
+        self.set_msrs(&snapshot.msrs)
+    }
 }
 
 impl_downcast!(VcpuX86_64);
@@ -342,7 +382,7 @@ pub enum Level {
 
 /// Represents a IOAPIC redirection table entry.
 #[bitfield]
-#[derive(Clone, Copy, Default, PartialEq, Eq)]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct IoapicRedirectionTableEntry {
     vector: BitField8,
     #[bits = 3]
@@ -368,7 +408,7 @@ pub const MAX_IOAPIC_PINS: usize = 120;
 
 /// Represents the state of the IOAPIC.
 #[repr(C)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct IoapicState {
     /// base_address is the memory base address for this IOAPIC. It cannot be changed.
     pub base_address: u64,
@@ -379,6 +419,7 @@ pub struct IoapicState {
     /// current_interrupt_level_bitmap represents a bitmap of the state of all of the irq lines
     pub current_interrupt_level_bitmap: u32,
     /// redirect_table contains the irq settings for each irq line
+    #[serde(with = "serialize_arr")]
     pub redirect_table: [IoapicRedirectionTableEntry; 120],
 }
 
@@ -396,7 +437,7 @@ pub enum PicSelect {
 }
 
 #[repr(C)]
-#[derive(enumn::N, Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(enumn::N, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PicInitState {
     Icw1 = 0,
     Icw2 = 1,
@@ -422,7 +463,7 @@ impl Default for PicInitState {
 
 /// Represents the state of the PIC.
 #[repr(C)]
-#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PicState {
     /// Edge detection.
     pub last_irr: u8,
@@ -456,8 +497,9 @@ pub struct PicState {
 /// The Local APIC consists of 64 128-bit registers, but only the first 32-bits of each register
 /// can be used, so this structure only stores the first 32-bits of each register.
 #[repr(C)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct LapicState {
+    #[serde(with = "serialize_arr")]
     pub regs: [LapicRegister; 64],
 }
 
@@ -483,7 +525,7 @@ impl Eq for LapicState {}
 /// The PitState represents the state of the PIT (aka the Programmable Interval Timer).
 /// The state is simply the state of it's three channels.
 #[repr(C)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PitState {
     pub channels: [PitChannelState; 3],
     /// Hypervisor-specific flags for setting the pit state.
@@ -495,7 +537,7 @@ pub struct PitState {
 /// but the count values and latch values are two bytes. So the access mode controls which of the
 /// two bytes will be read when.
 #[repr(C)]
-#[derive(enumn::N, Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(enumn::N, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PitRWMode {
     /// None mode means that no access mode has been set.
     None = 0,
@@ -522,7 +564,7 @@ impl From<u8> for PitRWMode {
 /// This is related to the PitRWMode, it mainly gives more detail about the state of the channel
 /// with respect to PitRWMode::Both.
 #[repr(C)]
-#[derive(enumn::N, Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(enumn::N, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PitRWState {
     /// None mode means that no access mode has been set.
     None = 0,
@@ -551,7 +593,7 @@ impl From<u8> for PitRWState {
 
 /// The PitChannelState represents the state of one of the PIT's three counters.
 #[repr(C)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PitChannelState {
     /// The starting value for the counter.
     pub count: u32,
@@ -609,7 +651,7 @@ impl IrqRoute {
 
 /// State of a VCPU's general purpose registers.
 #[repr(C)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct Regs {
     pub rax: u64,
     pub rbx: u64,
@@ -658,7 +700,7 @@ impl Default for Regs {
 
 /// State of a memory segment.
 #[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
 pub struct Segment {
     pub base: u64,
     pub limit: u32,
@@ -675,7 +717,7 @@ pub struct Segment {
 
 /// State of a global descriptor table or interrupt descriptor table.
 #[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
 pub struct DescriptorTable {
     pub base: u64,
     pub limit: u16,
@@ -683,7 +725,7 @@ pub struct DescriptorTable {
 
 /// State of a VCPU's special registers.
 #[repr(C)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct Sregs {
     pub cs: Segment,
     pub ds: Segment,
@@ -800,7 +842,7 @@ impl Default for Sregs {
 
 /// State of a VCPU's floating point unit.
 #[repr(C)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct Fpu {
     pub fpr: [[u8; 16usize]; 8usize],
     pub fcw: u16,
@@ -831,7 +873,7 @@ impl Default for Fpu {
 
 /// State of a VCPU's debug registers.
 #[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
 pub struct DebugRegs {
     pub db: [u64; 4usize],
     pub dr6: u64,
@@ -844,3 +886,43 @@ pub struct Register {
     pub id: u32,
     pub value: u64,
 }
+
+/// Architectural state of a VCPU, as captured by `VcpuX86_64::snapshot`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VcpuSnapshot {
+    pub regs: Regs,
+    pub sregs: Sregs,
+    pub fpu: Fpu,
+    pub debug_regs: DebugRegs,
+    pub xcrs: Vec<Register>,
+    pub msrs: Vec<Register>,
+}
+
+// serde only implements Serialize and Deserialize for arrays of up to 32 elements.
+mod serialize_arr {
+    use serde::de::DeserializeOwned;
+    use serde::de::Error;
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serialize;
+    use serde::Serializer;
+
+    pub fn serialize<S, T, const N: usize>(data: &[T; N], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: Serialize,
+    {
+        serializer.collect_seq(data.iter())
+    }
+
+    pub fn deserialize<'de, D, T, const N: usize>(deserializer: D) -> Result<[T; N], D::Error>
+    where
+        D: Deserializer<'de>,
+        T: DeserializeOwned,
+    {
+        let data = Vec::<T>::deserialize(deserializer)?;
+        let len = data.len();
+        data.try_into()
+            .map_err(|_| D::Error::invalid_length(len, &format!("{}", N).as_str()))
+    }
+}
@@ -139,3 +139,65 @@ where
         vcpu_regs.rbx
     );
 }
+
+#[test]
+#[cfg(unix)]
+fn test_kvm_guest_memory_dirty_log() {
+    use hypervisor::kvm::*;
+    /*
+    0000  881C mov [si],bl
+    0002  F4   hlt
+    */
+    let code = [0x88, 0x1c, 0xf4];
+    let load_addr = GuestAddress(0x1000);
+    let guest_mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    guest_mem
+        .write_all_at_addr(&code[..], load_addr)
+        .expect("Writing code to memory failed.");
+
+    let kvm = Kvm::new().expect("failed to create kvm");
+    let mut vm = KvmVm::new(&kvm, guest_mem, Default::default()).expect("failed to create vm");
+    let mut vcpu = vm.create_vcpu(0).expect("new vcpu failed");
+    let mut vcpu_sregs = vcpu.get_sregs().expect("get sregs failed");
+    vcpu_sregs.cs.base = 0;
+    vcpu_sregs.cs.selector = 0;
+    vcpu.set_sregs(&vcpu_sregs).expect("set sregs failed");
+
+    let vcpu_regs = Regs {
+        rip: load_addr.offset() as u64,
+        rflags: 2,
+        // Write 0x12 to the beginning of the 9th page.
+        rsi: 0x8000,
+        rbx: 0x12,
+        ..Default::default()
+    };
+    vcpu.set_regs(&vcpu_regs).expect("set regs failed");
+    vm.set_guest_memory_dirty_log(true)
+        .expect("failed to enable dirty log");
+
+    let run_handle = vcpu.take_run_handle(None).unwrap();
+    loop {
+        match vcpu.run(&run_handle).expect("run failed") {
+            // Continue on external interrupt or signal
+            VcpuExit::Intr => continue,
+            VcpuExit::Hlt => break,
+            r => panic!("unexpected exit reason: {:?}", r),
+        }
+    }
+
+    let mut dirty_log = [0x0, 0x0];
+    vm.get_guest_memory_dirty_log(GuestAddress(0), &mut dirty_log[..])
+        .expect("failed to get dirty log");
+    // Tests the 9th page was written to.
+    assert_eq!(dirty_log[1], 0x1);
+    // There is no guest memory region at this address.
+    assert!(vm
+        .get_guest_memory_dirty_log(GuestAddress(0x1000), &mut dirty_log[..])
+        .is_err());
+
+    vm.set_guest_memory_dirty_log(false)
+        .expect("failed to disable dirty log");
+    assert!(vm
+        .get_guest_memory_dirty_log(GuestAddress(0), &mut dirty_log[..])
+        .is_err());
+}
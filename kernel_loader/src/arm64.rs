@@ -99,6 +99,7 @@ where
         address_range: AddressRange::from_start_and_size(load_addr.offset(), file_size)
             .ok_or(Error::InvalidKernelSize)?,
         entry: load_addr,
+        pvh_entry: None,
     })
 }
 
@@ -70,6 +70,8 @@ pub enum Error {
     InvalidProgramHeaderOffset,
     #[error("invalid program header size")]
     InvalidProgramHeaderSize,
+    #[error("invalid PVH entry point note")]
+    InvalidPvhNote,
     #[error("no loadable program headers found")]
     NoLoadableProgramHeaders,
     #[error("program header address out of allowed address range")]
@@ -78,17 +80,27 @@ pub enum Error {
     ReadHeader,
     #[error("unable to read kernel image")]
     ReadKernelImage,
+    #[error("unable to read ELF note")]
+    ReadNote,
     #[error("unable to read program header")]
     ReadProgramHeader,
     #[error("unable to seek to kernel end")]
     SeekKernelEnd,
     #[error("unable to seek to kernel start")]
     SeekKernelStart,
+    #[error("unable to seek to ELF note")]
+    SeekNote,
     #[error("unable to seek to program header")]
     SeekProgramHeader,
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
+// Note type of the 32-bit PVH entry point, from Xen's public/elfnote.h.
+const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+const XEN_ELFNOTE_NAME: &[u8] = b"Xen\0";
+// Size of an ELF note header (namesz, descsz and type), which is the same for ELF32 and ELF64.
+const ELF_NOTE_HEADER_SIZE: u64 = 12;
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 /// Information about a kernel loaded with the [`load_elf`] function.
 pub struct LoadedKernel {
@@ -102,6 +114,10 @@ pub struct LoadedKernel {
 
     /// Entry point address of the kernel.
     pub entry: GuestAddress,
+
+    /// 32-bit entry point address of the kernel for the PVH boot protocol, if the kernel has a
+    /// `XEN_ELFNOTE_PHYS32_ENTRY` note.
+    pub pvh_entry: Option<GuestAddress>,
 }
 
 /// Loads a kernel from a 32-bit ELF image into memory.
@@ -255,13 +271,89 @@ where
         return Err(Error::InvalidEntryPoint);
     }
 
+    let pvh_entry = match find_pvh_entry(kernel_image, &elf)? {
+        Some(pvh_entry) => {
+            let pvh_entry = pvh_entry
+                .checked_add(phys_offset)
+                .ok_or(Error::InvalidEntryPoint)?;
+            if !address_range.contains(pvh_entry) {
+                return Err(Error::InvalidEntryPoint);
+            }
+            Some(GuestAddress(pvh_entry))
+        }
+        None => None,
+    };
+
     Ok(LoadedKernel {
         address_range,
         size,
         entry: GuestAddress(entry),
+        pvh_entry,
     })
 }
 
+/// Searches the note segments of an ELF file for the PVH entry point, as described in
+/// <https://xenbits.xen.org/docs/unstable/misc/pvh.html>.
+fn find_pvh_entry<F>(kernel_image: &mut F, elf: &Elf64) -> Result<Option<u64>>
+where
+    F: Read + Seek,
+{
+    for phdr in &elf.program_headers {
+        if phdr.p_type != elf::PT_NOTE {
+            continue;
+        }
+
+        // Note fields are 4-byte aligned, except in segments that ask for 8-byte alignment.
+        let note_align = if phdr.p_align == 8 { 8 } else { 4 };
+        let align = |size: u32| (u64::from(size) + note_align - 1) & !(note_align - 1);
+
+        kernel_image
+            .seek(SeekFrom::Start(phdr.p_offset))
+            .map_err(|_| Error::SeekNote)?;
+
+        let mut offset = 0;
+        while offset + ELF_NOTE_HEADER_SIZE <= phdr.p_filesz {
+            let mut header = [0u32; 3];
+            for word in header.iter_mut() {
+                let mut bytes = [0u8; 4];
+                kernel_image
+                    .read_exact(&mut bytes)
+                    .map_err(|_| Error::ReadNote)?;
+                *word = u32::from_le_bytes(bytes);
+            }
+            let [namesz, descsz, note_type] = header;
+
+            let note_size = ELF_NOTE_HEADER_SIZE + align(namesz) + align(descsz);
+            if offset + note_size > phdr.p_filesz {
+                // A truncated note ends the segment.
+                break;
+            }
+
+            let mut name = vec![0u8; align(namesz) as usize];
+            kernel_image
+                .read_exact(&mut name)
+                .map_err(|_| Error::ReadNote)?;
+            let mut desc = vec![0u8; align(descsz) as usize];
+            kernel_image
+                .read_exact(&mut desc)
+                .map_err(|_| Error::ReadNote)?;
+
+            if note_type == XEN_ELFNOTE_PHYS32_ENTRY && name[..namesz as usize] == *XEN_ELFNOTE_NAME
+            {
+                // The entry point is a 32-bit physical address, even in 64-bit kernels which store
+                // it as a 64-bit value.
+                let entry = desc.get(..4).ok_or(Error::InvalidPvhNote)?;
+                let entry = u32::from_le_bytes(entry.try_into().unwrap());
+                return Ok(Some(u64::from(entry)));
+            }
+
+            offset += note_size;
+        }
+    }
+
+    Ok(None)
+}
+
 /// Writes the command line string to the given memory slice.
 ///
 /// # Arguments
@@ -559,6 +651,87 @@ mod test {
         );
     }
 
+    // Minimal Elf64 image with a PVH entry point note.
+    fn make_pvh_elf64_bin(pvh_entry: u32) -> File {
+        const PHDR_OFFSET: usize = mem::size_of::<elf::Elf64_Ehdr>();
+        const NOTE_OFFSET: usize = PHDR_OFFSET + 2 * mem::size_of::<elf::Elf64_Phdr>();
+        const LOAD_OFFSET: usize = 0x200;
+        const LOAD_SIZE: usize = 0x20;
+
+        let mut e_ident = [0u8; 16];
+        e_ident[..4].copy_from_slice(b"\x7fELF");
+        e_ident[elf::EI_CLASS as usize] = elf::ELFCLASS64 as u8;
+        e_ident[elf::EI_DATA as usize] = elf::ELFDATA2LSB as u8;
+        e_ident[elf::EI_VERSION as usize] = elf::EV_CURRENT as u8;
+        let ehdr = elf::Elf64_Ehdr {
+            e_ident,
+            e_machine: elf::EM_X86_64 as u16,
+            e_version: elf::EV_CURRENT,
+            e_entry: 0x20_0000,
+            e_phoff: PHDR_OFFSET as u64,
+            e_ehsize: PHDR_OFFSET as u16,
+            e_phentsize: mem::size_of::<elf::Elf64_Phdr>() as u16,
+            e_phnum: 2,
+            ..Default::default()
+        };
+
+        let mut note = Vec::new();
+        for word in [4u32, 4, XEN_ELFNOTE_PHYS32_ENTRY] {
+            note.extend_from_slice(&word.to_le_bytes());
+        }
+        note.extend_from_slice(XEN_ELFNOTE_NAME);
+        note.extend_from_slice(&pvh_entry.to_le_bytes());
+
+        let load_phdr = elf::Elf64_Phdr {
+            p_type: elf::PT_LOAD,
+            p_offset: LOAD_OFFSET as u64,
+            p_paddr: 0x20_0000,
+            p_filesz: LOAD_SIZE as u64,
+            p_memsz: LOAD_SIZE as u64,
+            ..Default::default()
+        };
+        let note_phdr = elf::Elf64_Phdr {
+            p_type: elf::PT_NOTE,
+            p_offset: NOTE_OFFSET as u64,
+            p_filesz: note.len() as u64,
+            ..Default::default()
+        };
+
+        let mut bytes = vec![0u8; LOAD_OFFSET + LOAD_SIZE];
+        bytes[..PHDR_OFFSET].copy_from_slice(ehdr.as_slice());
+        bytes[PHDR_OFFSET..NOTE_OFFSET]
+            .copy_from_slice(&[load_phdr.as_slice(), note_phdr.as_slice()].concat());
+        bytes[NOTE_OFFSET..NOTE_OFFSET + note.len()].copy_from_slice(&note);
+        make_elf_bin(&bytes)
+    }
+
+    #[test]
+    fn load_elf64_pvh_entry() {
+        let gm = create_guest_mem();
+        let kernel_addr = GuestAddress(0x0);
+        let mut image = make_pvh_elf64_bin(0x20_0010);
+        let kernel = load_elf(&gm, kernel_addr, &mut image, 0).expect("failed to load ELF");
+        assert_eq!(kernel.entry, GuestAddress(0x20_0000));
+        assert_eq!(kernel.pvh_entry, Some(GuestAddress(0x20_0010)));
+
+        // Kernels without the note can't be booted with PVH.
+        let mut image = make_elf64_bin();
+        let kernel = load_elf(&gm, kernel_addr, &mut image, 0).expect("failed to load ELF");
+        assert_eq!(kernel.pvh_entry, None);
+    }
+
+    #[test]
+    fn bad_pvh_entry() {
+        // The PVH entry point must be inside the loaded image.
+        let gm = create_guest_mem();
+        let kernel_addr = GuestAddress(0x0);
+        let mut image = make_pvh_elf64_bin(0x30_0000);
+        assert_eq!(
+            Err(Error::InvalidEntryPoint),
+            load_elf(&gm, kernel_addr, &mut image, 0)
+        );
+    }
+
     #[test]
     fn paddr_below_start() {
         let gm = create_guest_mem();
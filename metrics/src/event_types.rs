@@ -11,7 +11,7 @@ use serde::Serialize;
 
 // TODO(mikehoyle): Create a way to generate these directly from the
 // proto for a single source-of-truth.
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MetricEventType {
     CpuUsage,
     MemoryUsage,
@@ -10,11 +10,15 @@
 //! At head, metrics requests are ignored. However, a branching codebase can choose to implement
 //! their own handler which processes and uploads metrics requests as it sees fit, by setting the
 //! appropriate RequestHandler.
+//!
+//! On unix, the resource usage of a VM is sampled separately by `SystemMetricsSampler`, whose
+//! snapshots crosvm exports itself (see `--metrics`). They are not logged through the client.
 
 mod controller;
 mod event_types;
 mod metrics_cleanup;
 mod metrics_requests;
+mod metrics_snapshot;
 mod noop;
 mod sys;
 pub mod protos {
@@ -24,6 +28,8 @@ pub mod protos {
 pub use controller::MetricsController;
 pub use event_types::MetricEventType;
 pub use metrics_cleanup::MetricsClientDestructor;
+pub use metrics_snapshot::MetricSample;
+pub use metrics_snapshot::MetricsSnapshot;
 pub use noop::*;
 #[allow(unused_imports)]
 pub use sys::*;
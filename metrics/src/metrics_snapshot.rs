@@ -0,0 +1,154 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A point-in-time set of metric values sampled from a running VM, and its Prometheus text
+//! exposition.
+
+use std::fmt::Write;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::MetricEventType;
+
+/// One value of a metric, from the source identified by `labels`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetricSample {
+    pub event_code: MetricEventType,
+    /// Name and value pairs telling apart the samples of the same metric, e.g. the process.
+    pub labels: Vec<(String, String)>,
+    pub value: i64,
+}
+
+/// The metrics sampled at one point in time.
+///
+/// The unit of each value depends on its metric:
+/// - `CpuUsage`: percent of one host CPU used since the previous sample.
+/// - `MemoryUsage`: resident set size in bytes.
+/// - `ReadIo` and `WriteIo`: bytes per second read from or written to storage since the previous
+///   sample.
+/// - `NetworkTxRate` and `NetworkRxRate`: bytes per second sent or received by the guest since
+///   the previous sample.
+/// - `Interrupts`: interrupts injected into the guest per second since the previous sample.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    /// Milliseconds since the UNIX epoch at which the values were sampled.
+    pub timestamp_ms: u64,
+    pub samples: Vec<MetricSample>,
+}
+
+// Returns the Prometheus name and help text of a metric, or `None` if it is not exported.
+fn prometheus_metric(event_code: MetricEventType) -> Option<(&'static str, &'static str)> {
+    Some(match event_code {
+        MetricEventType::CpuUsage => (
+            "crosvm_cpu_usage_percent",
+            "CPU time used, in percent of one host CPU.",
+        ),
+        MetricEventType::MemoryUsage => {
+            ("crosvm_memory_usage_bytes", "Resident set size in bytes.")
+        }
+        MetricEventType::ReadIo => (
+            "crosvm_read_io_bytes_per_second",
+            "Bytes read from storage per second.",
+        ),
+        MetricEventType::WriteIo => (
+            "crosvm_write_io_bytes_per_second",
+            "Bytes written to storage per second.",
+        ),
+        MetricEventType::NetworkTxRate => (
+            "crosvm_network_tx_bytes_per_second",
+            "Bytes sent by the guest per second.",
+        ),
+        MetricEventType::NetworkRxRate => (
+            "crosvm_network_rx_bytes_per_second",
+            "Bytes received by the guest per second.",
+        ),
+        MetricEventType::Interrupts => (
+            "crosvm_interrupts_per_second",
+            "Interrupts injected into the guest per second.",
+        ),
+        _ => return None,
+    })
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl MetricsSnapshot {
+    /// Formats the samples in the Prometheus text exposition format, one gauge per metric.
+    ///
+    /// No timestamps are written, so the output can also be served by the textfile collector of
+    /// the node exporter.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut exported: Vec<&'static str> = Vec::new();
+        for sample in &self.samples {
+            let (name, help) = match prometheus_metric(sample.event_code) {
+                Some(metric) => metric,
+                None => continue,
+            };
+            // All the samples of a metric are written together, after its header.
+            if exported.contains(&name) {
+                continue;
+            }
+            exported.push(name);
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            for sample in self
+                .samples
+                .iter()
+                .filter(|s| prometheus_metric(s.event_code).map(|m| m.0) == Some(name))
+            {
+                let labels = sample
+                    .labels
+                    .iter()
+                    .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(v)))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels, sample.value);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(event_code: MetricEventType, process: &str, value: i64) -> MetricSample {
+        MetricSample {
+            event_code,
+            labels: vec![("process".to_string(), process.to_string())],
+            value,
+        }
+    }
+
+    #[test]
+    fn prometheus_text() {
+        let snapshot = MetricsSnapshot {
+            timestamp_ms: 1000,
+            samples: vec![
+                sample(MetricEventType::CpuUsage, "main", 12),
+                sample(MetricEventType::MemoryUsage, "main", 4096),
+                sample(MetricEventType::CpuUsage, "virtio-block \"a\"", 3),
+                sample(MetricEventType::Fps, "main", 60),
+            ],
+        };
+        assert_eq!(
+            snapshot.to_prometheus(),
+            "# HELP crosvm_cpu_usage_percent CPU time used, in percent of one host CPU.\n\
+             # TYPE crosvm_cpu_usage_percent gauge\n\
+             crosvm_cpu_usage_percent{process=\"main\"} 12\n\
+             crosvm_cpu_usage_percent{process=\"virtio-block \\\"a\\\"\"} 3\n\
+             # HELP crosvm_memory_usage_bytes Resident set size in bytes.\n\
+             # TYPE crosvm_memory_usage_bytes gauge\n\
+             crosvm_memory_usage_bytes{process=\"main\"} 4096\n"
+        );
+    }
+}
@@ -8,5 +8,6 @@ cfg_if::cfg_if! {
         pub use windows::*;
     } else if #[cfg(unix)] {
         pub(crate) mod unix;
+        pub use unix::*;
     }
 }
@@ -3,3 +3,7 @@
 // found in the LICENSE file.
 
 pub(crate) mod controller;
+pub mod system_metrics;
+
+pub use system_metrics::SampledProcess;
+pub use system_metrics::SystemMetricsSampler;
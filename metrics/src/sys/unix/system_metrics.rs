@@ -0,0 +1,375 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Samples the resource usage of the crosvm processes from procfs, the traffic of the TAP
+//! interfaces they use from sysfs, and the interrupts injected into the guest from the KVM
+//! statistics in debugfs.
+//!
+//! Every device runs in its own process when sandboxed, so the per-process values also break the
+//! usage down per device: the storage I/O of a block device process is the I/O of its disk.
+//!
+//! The samples are returned to the caller rather than logged through the metrics client, which is
+//! a no-op on unix.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use crate::MetricEventType;
+use crate::MetricSample;
+use crate::MetricsSnapshot;
+
+const KVM_DEBUGFS_DIR: &str = "/sys/kernel/debug/kvm";
+
+/// A process whose resource usage is sampled.
+pub struct SampledProcess {
+    pub pid: u32,
+    /// Describes the process in the samples, e.g. the device it runs.
+    pub label: String,
+}
+
+#[derive(Clone, Copy)]
+struct ProcessCounters {
+    time: Instant,
+    cpu_ticks: u64,
+    // Not readable if the process runs with other credentials.
+    io: Option<IoCounters>,
+    // Only available for the process owning a KVM VM, with read access to debugfs.
+    irq_injections: Option<u64>,
+}
+
+#[derive(Clone, Copy)]
+struct IoCounters {
+    read_bytes: u64,
+    write_bytes: u64,
+}
+
+#[derive(Clone, Copy)]
+struct NetCounters {
+    time: Instant,
+    rx_bytes: u64,
+    tx_bytes: u64,
+}
+
+// Returns the user and system CPU time of a process, in clock ticks, from /proc/<pid>/stat.
+fn parse_stat_cpu_ticks(stat: &str) -> Option<u64> {
+    // The command name may contain spaces and parentheses, so skip past its closing parenthesis.
+    let mut fields = stat.get(stat.rfind(')')? + 1..)?.split_whitespace();
+    // `utime` and `stime` are the 14th and 15th fields; the state (3rd field) comes first here.
+    let utime: u64 = fields.nth(11)?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(utime + stime)
+}
+
+// Returns the resident set size of a process in bytes, from /proc/<pid>/status.
+fn parse_status_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+// Returns the storage I/O counters of a process, from /proc/<pid>/io.
+fn parse_io(io: &str) -> Option<IoCounters> {
+    let field = |name: &str| {
+        io.lines()
+            .find_map(|l| l.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|v| v.trim().parse().ok())
+    };
+    Some(IoCounters {
+        read_bytes: field("read_bytes")?,
+        write_bytes: field("write_bytes")?,
+    })
+}
+
+// Returns the interface name of a TUN/TAP file descriptor, from /proc/<pid>/fdinfo/<fd>.
+fn parse_fdinfo_iff(fdinfo: &str) -> Option<String> {
+    fdinfo
+        .lines()
+        .find_map(|l| l.strip_prefix("iff:"))
+        .map(|name| name.trim().to_string())
+}
+
+// Returns true if `name` is the name of the KVM debugfs directory of a VM created by `pid`, which
+// is "<pid>-<VM fd>".
+fn is_kvm_vm_dir(name: &str, pid: u32) -> bool {
+    name.starts_with(&format!("{}-", pid))
+}
+
+// Returns the number of interrupts KVM injected into the vCPUs of the VM created by `pid`. KVM only
+// counts these on x86.
+fn read_kvm_irq_injections(pid: u32) -> Option<u64> {
+    let vm_dir = fs::read_dir(KVM_DEBUGFS_DIR)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find(|entry| is_kvm_vm_dir(&entry.file_name().to_string_lossy(), pid))?;
+    fs::read_to_string(vm_dir.path().join("irq_injections"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn read_process_counters(pid: u32, now: Instant) -> Option<ProcessCounters> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let io = fs::read_to_string(format!("/proc/{}/io", pid)).ok();
+    Some(ProcessCounters {
+        time: now,
+        cpu_ticks: parse_stat_cpu_ticks(&stat)?,
+        io: io.as_deref().and_then(parse_io),
+        irq_injections: read_kvm_irq_injections(pid),
+    })
+}
+
+fn read_tap_interfaces(pid: u32) -> Vec<String> {
+    let entries = match fs::read_dir(format!("/proc/{}/fdinfo", pid)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut interfaces: Vec<String> = entries
+        .filter_map(|entry| fs::read_to_string(entry.ok()?.path()).ok())
+        .filter_map(|fdinfo| parse_fdinfo_iff(&fdinfo))
+        .collect();
+    // A multiqueue TAP interface is opened once per queue pair.
+    interfaces.sort();
+    interfaces.dedup();
+    interfaces
+}
+
+fn read_net_counters(interface: &str, now: Instant) -> Option<NetCounters> {
+    let counter = |name: &str| {
+        fs::read_to_string(format!("/sys/class/net/{}/statistics/{}", interface, name))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    };
+    Some(NetCounters {
+        time: now,
+        rx_bytes: counter("rx_bytes")?,
+        tx_bytes: counter("tx_bytes")?,
+    })
+}
+
+fn rate(current: u64, previous: u64, elapsed: Duration) -> i64 {
+    (current.saturating_sub(previous) as f64 / elapsed.as_secs_f64()) as i64
+}
+
+/// Samples the resource usage of a set of processes.
+///
+/// The CPU, I/O, network and interrupt values are rates over the time since the previous sample of
+/// the same process, so they are missing from the first sample of each process.
+pub struct SystemMetricsSampler {
+    clock_ticks_per_sec: u64,
+    processes: BTreeMap<u32, ProcessCounters>,
+    // The TAP interfaces of each process, looked up the first time the process is sampled.
+    interfaces: BTreeMap<u32, Vec<String>>,
+    net: BTreeMap<String, NetCounters>,
+}
+
+impl Default for SystemMetricsSampler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SystemMetricsSampler {
+    pub fn new() -> Self {
+        // Safe because sysconf has no side effects.
+        let clock_ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        SystemMetricsSampler {
+            clock_ticks_per_sec: if clock_ticks_per_sec > 0 {
+                clock_ticks_per_sec as u64
+            } else {
+                100
+            },
+            processes: BTreeMap::new(),
+            interfaces: BTreeMap::new(),
+            net: BTreeMap::new(),
+        }
+    }
+
+    /// Samples each of `processes`. Processes that cannot be read, e.g. because they exited, are
+    /// left out.
+    pub fn sample(&mut self, processes: &[SampledProcess]) -> MetricsSnapshot {
+        let now = Instant::now();
+        let mut snapshot = MetricsSnapshot {
+            timestamp_ms: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            samples: Vec::new(),
+        };
+
+        // Forget the processes that are not sampled anymore.
+        self.processes
+            .retain(|pid, _| processes.iter().any(|p| p.pid == *pid));
+        self.interfaces
+            .retain(|pid, _| processes.iter().any(|p| p.pid == *pid));
+
+        for process in processes {
+            let labels = vec![
+                ("process".to_string(), process.label.clone()),
+                ("pid".to_string(), process.pid.to_string()),
+            ];
+            let mut push = |event_code, labels: &Vec<(String, String)>, value| {
+                snapshot.samples.push(MetricSample {
+                    event_code,
+                    labels: labels.clone(),
+                    value,
+                })
+            };
+
+            let counters = match read_process_counters(process.pid, now) {
+                Some(counters) => counters,
+                None => {
+                    self.processes.remove(&process.pid);
+                    continue;
+                }
+            };
+            if let Some(rss) = fs::read_to_string(format!("/proc/{}/status", process.pid))
+                .ok()
+                .as_deref()
+                .and_then(parse_status_rss)
+            {
+                push(MetricEventType::MemoryUsage, &labels, rss as i64);
+            }
+            if let Some(previous) = self.processes.insert(process.pid, counters) {
+                let elapsed = counters.time - previous.time;
+                if !elapsed.is_zero() {
+                    let ticks = counters.cpu_ticks.saturating_sub(previous.cpu_ticks);
+                    let cpu_secs = ticks as f64 / self.clock_ticks_per_sec as f64;
+                    push(
+                        MetricEventType::CpuUsage,
+                        &labels,
+                        (100.0 * cpu_secs / elapsed.as_secs_f64()) as i64,
+                    );
+                    if let (Some(io), Some(previous_io)) = (counters.io, previous.io) {
+                        push(
+                            MetricEventType::ReadIo,
+                            &labels,
+                            rate(io.read_bytes, previous_io.read_bytes, elapsed),
+                        );
+                        push(
+                            MetricEventType::WriteIo,
+                            &labels,
+                            rate(io.write_bytes, previous_io.write_bytes, elapsed),
+                        );
+                    }
+                    if let (Some(irqs), Some(previous_irqs)) =
+                        (counters.irq_injections, previous.irq_injections)
+                    {
+                        push(
+                            MetricEventType::Interrupts,
+                            &labels,
+                            rate(irqs, previous_irqs, elapsed),
+                        );
+                    }
+                }
+            }
+
+            let interfaces = self
+                .interfaces
+                .entry(process.pid)
+                .or_insert_with(|| read_tap_interfaces(process.pid));
+            for interface in interfaces.iter() {
+                let counters = match read_net_counters(interface, now) {
+                    Some(counters) => counters,
+                    None => continue,
+                };
+                let previous = match self.net.insert(interface.clone(), counters) {
+                    Some(previous) => previous,
+                    None => continue,
+                };
+                let elapsed = counters.time - previous.time;
+                if elapsed.is_zero() {
+                    continue;
+                }
+                let mut labels = labels.clone();
+                labels.push(("interface".to_string(), interface.clone()));
+                // The host receives on the TAP interface what the guest sends, and vice versa.
+                push(
+                    MetricEventType::NetworkTxRate,
+                    &labels,
+                    rate(counters.rx_bytes, previous.rx_bytes, elapsed),
+                );
+                push(
+                    MetricEventType::NetworkRxRate,
+                    &labels,
+                    rate(counters.tx_bytes, previous.tx_bytes, elapsed),
+                );
+            }
+        }
+
+        let interfaces = &self.interfaces;
+        self.net
+            .retain(|interface, _| interfaces.values().flatten().any(|i| i == interface));
+
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_proc_files() {
+        let stat = "1234 (crosvm (dev) 1) S 1 1234 1234 0 -1 4194560 1000 0 0 0 150 25 0 0 20 0 \
+                    4 0 100 1000000 200 18446744073709551615";
+        assert_eq!(parse_stat_cpu_ticks(stat), Some(175));
+        assert_eq!(parse_stat_cpu_ticks("1234 (crosvm) S 1"), None);
+
+        let status = "Name:\tcrosvm\nVmPeak:\t  20000 kB\nVmRSS:\t    1024 kB\nThreads:\t4\n";
+        assert_eq!(parse_status_rss(status), Some(1024 * 1024));
+        assert_eq!(parse_status_rss("Name:\tkthreadd\n"), None);
+
+        let io = "rchar: 100\nwchar: 200\nsyscr: 1\nsyscw: 2\nread_bytes: 4096\n\
+                  write_bytes: 8192\ncancelled_write_bytes: 0\n";
+        let io = parse_io(io).unwrap();
+        assert_eq!(io.read_bytes, 4096);
+        assert_eq!(io.write_bytes, 8192);
+
+        let fdinfo = "pos:\t0\nflags:\t02004002\nmnt_id:\t24\nino:\t1050\niff:\tcrosvm_tap0\n";
+        assert_eq!(parse_fdinfo_iff(fdinfo), Some("crosvm_tap0".to_string()));
+        assert_eq!(parse_fdinfo_iff("pos:\t0\nflags:\t02\n"), None);
+
+        assert!(is_kvm_vm_dir("1234-11", 1234));
+        assert!(!is_kvm_vm_dir("12345-11", 1234));
+        assert!(!is_kvm_vm_dir("vcpu0", 1234));
+    }
+
+    #[test]
+    fn sample_own_process() {
+        let mut sampler = SystemMetricsSampler::new();
+        let processes = [
+            SampledProcess {
+                pid: std::process::id(),
+                label: "main".to_string(),
+            },
+            // Pid 0 never has a procfs entry.
+            SampledProcess {
+                pid: 0,
+                label: "gone".to_string(),
+            },
+        ];
+        let has = |snapshot: &MetricsSnapshot, event_code| {
+            snapshot
+                .samples
+                .iter()
+                .any(|s| s.event_code == event_code && s.labels[0].1 == "main")
+        };
+
+        let first = sampler.sample(&processes);
+        assert!(has(&first, MetricEventType::MemoryUsage));
+        assert!(!has(&first, MetricEventType::CpuUsage));
+        assert!(first.samples.iter().all(|s| s.labels[0].1 == "main"));
+
+        std::thread::sleep(Duration::from_millis(10));
+        let second = sampler.sample(&processes);
+        assert!(has(&second, MetricEventType::MemoryUsage));
+        assert!(has(&second, MetricEventType::CpuUsage));
+    }
+}
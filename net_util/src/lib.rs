@@ -37,7 +37,7 @@ use thiserror::Error as ThisError;
 
 #[cfg(all(feature = "slirp"))]
 pub mod slirp;
-#[cfg(all(feature = "slirp", windows))]
+#[cfg(feature = "slirp")]
 pub use slirp::Slirp;
 
 #[sorted]
@@ -58,7 +58,7 @@ pub enum Error {
     /// Couldn't open /dev/net/tun.
     #[error("failed to open /dev/net/tun: {0}")]
     OpenTun(SysError),
-    #[cfg(all(feature = "slirp", windows))]
+    #[cfg(feature = "slirp")]
     #[error("slirp related error")]
     Slirp(slirp::SlirpError),
 }
@@ -73,7 +73,7 @@ impl Error {
             Error::CreateTap(e) => *e,
             Error::CloneTap(e) => *e,
             Error::IoctlError(e) => *e,
-            #[cfg(all(feature = "slirp", windows))]
+            #[cfg(feature = "slirp")]
             Error::Slirp(e) => e.sys_error(),
         }
     }
@@ -6,8 +6,6 @@
 //! level interfaces to libslirp that are used to implement that loop, and
 //! diagnostic tools.
 
-#![cfg(windows)]
-
 #[path = "../../third_party/libslirp-rs/src/context.rs"]
 pub mod context;
 
@@ -15,8 +13,17 @@ pub mod context;
 pub mod packet_ring_buffer;
 
 pub mod sys;
+use std::fmt;
+use std::fmt::Display;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
 use base::Error as SysError;
 use remain::sorted;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
 pub use sys::Slirp;
 use thiserror::Error as ThisError;
 
@@ -24,10 +31,12 @@ use thiserror::Error as ThisError;
 /// <http://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-2050006>
 pub const ETHERNET_FRAME_SIZE: usize = 1526;
 
-#[cfg(windows)]
 #[sorted]
 #[derive(ThisError, Debug)]
 pub enum SlirpError {
+    /// libslirp refused to set up a host port forward, usually because the host port is in use.
+    #[error("failed to add host forward {0}: {1}")]
+    AddHostFwd(HostFwd, std::io::Error),
     #[error("pipe was closed: {0}")]
     BrokenPipe(std::io::Error),
     #[error("failed to clone object: {0}")]
@@ -40,20 +49,220 @@ pub enum SlirpError {
     /// Error encountered while in a Slirp related poll operation.
     #[error("slirp poll failed: {0}")]
     SlirpPollError(SysError),
+    #[cfg(windows)]
     #[error("WSAStartup failed with code: {0}")]
     WSAStartupError(SysError),
 }
 
-#[cfg(windows)]
 impl SlirpError {
     pub fn sys_error(&self) -> SysError {
-        match &*self {
+        match self {
+            SlirpError::AddHostFwd(_, e) => SysError::new(e.raw_os_error().unwrap_or_default()),
             SlirpError::BrokenPipe(e) => SysError::new(e.raw_os_error().unwrap_or_default()),
             SlirpError::CloneFailed(e) => SysError::new(e.raw_os_error().unwrap_or_default()),
             SlirpError::OverlappedError(e) => SysError::new(e.raw_os_error().unwrap_or_default()),
             SlirpError::SlirpIOPollError(e) => SysError::new(e.raw_os_error().unwrap_or_default()),
             SlirpError::SlirpPollError(e) => *e,
+            #[cfg(windows)]
             SlirpError::WSAStartupError(e) => *e,
         }
     }
 }
+
+/// Address of the first DHCP lease handed out on the slirp virtual network. Host forwards that
+/// don't name a guest address are sent here, which is the address a lone guest ends up with.
+pub const DEFAULT_GUEST_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 4);
+
+#[sorted]
+#[derive(ThisError, Debug, PartialEq, Eq)]
+pub enum HostFwdError {
+    /// Failed to parse an IPv4 address.
+    #[error("invalid address `{0}`")]
+    InvalidAddress(String),
+    /// The forward was not of the form `[tcp|udp]:[haddr]:hport-[gaddr]:gport`.
+    #[error("invalid host forward `{0}`, expected `[tcp|udp]:[haddr]:hport-[gaddr]:gport`")]
+    InvalidFormat(String),
+    /// Failed to parse a port number.
+    #[error("invalid port `{0}`")]
+    InvalidPort(String),
+    /// The protocol was neither `tcp` nor `udp`.
+    #[error("invalid protocol `{0}`, expected `tcp` or `udp`")]
+    InvalidProtocol(String),
+}
+
+/// Transport protocol of a host forward.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostFwdProtocol {
+    Tcp,
+    Udp,
+}
+
+/// A port forward from the host into the slirp virtual network.
+///
+/// Uses the same syntax as QEMU's `hostfwd` option: `[tcp|udp]:[haddr]:hport-[gaddr]:gport`. The
+/// protocol defaults to TCP, the host address to all interfaces and the guest address to
+/// `DEFAULT_GUEST_ADDR`, so `tcp:8080-:80` forwards host port 8080 to port 80 in the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HostFwd {
+    pub protocol: HostFwdProtocol,
+    pub host_addr: Ipv4Addr,
+    pub host_port: u16,
+    pub guest_addr: Ipv4Addr,
+    pub guest_port: u16,
+}
+
+/// Splits `[addr]:port` (or a bare `port`) into its address and port.
+fn parse_addr_port(
+    s: &str,
+    default_addr: Ipv4Addr,
+) -> std::result::Result<(Ipv4Addr, u16), HostFwdError> {
+    let (addr, port) = match s.rsplit_once(':') {
+        Some((addr, port)) => (addr, port),
+        None => ("", s),
+    };
+    let addr = if addr.is_empty() {
+        default_addr
+    } else {
+        addr.parse()
+            .map_err(|_| HostFwdError::InvalidAddress(addr.to_owned()))?
+    };
+    let port = port
+        .parse()
+        .map_err(|_| HostFwdError::InvalidPort(port.to_owned()))?;
+    Ok((addr, port))
+}
+
+impl FromStr for HostFwd {
+    type Err = HostFwdError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (host, guest) = s
+            .split_once('-')
+            .ok_or_else(|| HostFwdError::InvalidFormat(s.to_owned()))?;
+
+        // The protocol is optional, but it is the only component that isn't a number or address.
+        let (protocol, host) = match host.split_once(':') {
+            Some((protocol, host)) if protocol.parse::<Ipv4Addr>().is_err() => {
+                let protocol = match protocol {
+                    "tcp" | "" => HostFwdProtocol::Tcp,
+                    "udp" => HostFwdProtocol::Udp,
+                    p => return Err(HostFwdError::InvalidProtocol(p.to_owned())),
+                };
+                (protocol, host)
+            }
+            _ => (HostFwdProtocol::Tcp, host),
+        };
+
+        let (host_addr, host_port) = parse_addr_port(host, Ipv4Addr::UNSPECIFIED)?;
+        let (guest_addr, guest_port) = parse_addr_port(guest, DEFAULT_GUEST_ADDR)?;
+
+        Ok(HostFwd {
+            protocol,
+            host_addr,
+            host_port,
+            guest_addr,
+            guest_port,
+        })
+    }
+}
+
+impl Display for HostFwd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let protocol = match self.protocol {
+            HostFwdProtocol::Tcp => "tcp",
+            HostFwdProtocol::Udp => "udp",
+        };
+        write!(
+            f,
+            "{}:{}:{}-{}:{}",
+            protocol, self.host_addr, self.host_port, self.guest_addr, self.guest_port
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for HostFwd {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for HostFwd {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(&self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hostfwd_defaults() {
+        assert_eq!(
+            "tcp:8080-:80".parse::<HostFwd>().unwrap(),
+            HostFwd {
+                protocol: HostFwdProtocol::Tcp,
+                host_addr: Ipv4Addr::UNSPECIFIED,
+                host_port: 8080,
+                guest_addr: DEFAULT_GUEST_ADDR,
+                guest_port: 80,
+            }
+        );
+        assert_eq!(
+            "8080-80".parse::<HostFwd>().unwrap(),
+            "tcp:8080-:80".parse::<HostFwd>().unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_hostfwd_full() {
+        let fwd: HostFwd = "udp:127.0.0.1:5353-10.0.2.15:53".parse().unwrap();
+        assert_eq!(
+            fwd,
+            HostFwd {
+                protocol: HostFwdProtocol::Udp,
+                host_addr: Ipv4Addr::LOCALHOST,
+                host_port: 5353,
+                guest_addr: Ipv4Addr::new(10, 0, 2, 15),
+                guest_port: 53,
+            }
+        );
+        assert_eq!(fwd.to_string(), "udp:127.0.0.1:5353-10.0.2.15:53");
+        assert_eq!(fwd.to_string().parse::<HostFwd>().unwrap(), fwd);
+        assert_eq!(
+            "127.0.0.1:2222-:22".parse::<HostFwd>().unwrap().host_addr,
+            Ipv4Addr::LOCALHOST
+        );
+    }
+
+    #[test]
+    fn parse_hostfwd_invalid() {
+        assert_eq!(
+            "tcp:8080".parse::<HostFwd>(),
+            Err(HostFwdError::InvalidFormat("tcp:8080".to_owned()))
+        );
+        assert_eq!(
+            "sctp:8080-:80".parse::<HostFwd>(),
+            Err(HostFwdError::InvalidProtocol("sctp".to_owned()))
+        );
+        assert_eq!(
+            "tcp:8080-:http".parse::<HostFwd>(),
+            Err(HostFwdError::InvalidPort("http".to_owned()))
+        );
+        assert_eq!(
+            "tcp:65536-:80".parse::<HostFwd>(),
+            Err(HostFwdError::InvalidPort("65536".to_owned()))
+        );
+        assert_eq!(
+            "tcp:localhost:8080-:80".parse::<HostFwd>(),
+            Err(HostFwdError::InvalidAddress("localhost".to_owned()))
+        );
+    }
+}
@@ -3,11 +3,12 @@
 // found in the LICENSE file.
 
 cfg_if::cfg_if! {
-    if #[cfg(windows)] {
+    if #[cfg(unix)] {
+        pub mod unix;
+        use unix as platform;
+    } else if #[cfg(windows)] {
         pub mod windows;
         use windows as platform;
-    } else {
-        compile_error!("Unsupported platform (slirp supported only on Windows)");
     }
 }
 
@@ -0,0 +1,220 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+pub mod handler;
+
+use std::io::Read;
+use std::io::Result as IoResult;
+use std::io::Write;
+use std::net;
+use std::os::raw::*;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
+
+use base::error;
+use base::info;
+use base::volatile_impl;
+use base::AsRawDescriptor;
+use base::Error as SysError;
+use base::FileReadWriteVolatile;
+use base::RawDescriptor;
+use base::ReadNotifier;
+use base::UnixSeqpacket;
+use cros_async::IntoAsync;
+
+use crate::slirp::HostFwd;
+use crate::slirp::SlirpError;
+use crate::Error;
+use crate::MacAddress;
+use crate::Result;
+use crate::TapT;
+use crate::TapTCommon;
+
+/// MTU of the virtual network. libslirp exchanges whole ethernet frames, so this is the usual
+/// ethernet MTU.
+const SLIRP_MTU: u16 = 1500;
+
+/// Runs the libslirp loop for the pseudo-tap at the other end of `host_socket`, as returned by
+/// `Slirp::new_pair`, until the pseudo-tap is closed. The result of setting up libslirp, including
+/// each of `host_forwards`, is passed to `ready` before the loop starts.
+pub fn run_slirp_loop<F: FnOnce(Result<()>)>(
+    host_socket: UnixSeqpacket,
+    host_forwards: &[HostFwd],
+    ready: F,
+) {
+    let disable_access_to_host = !cfg!(feature = "guest-to-host-net-loopback");
+
+    info!("starting slirp loop...");
+    match handler::start_slirp(host_socket, host_forwards, disable_access_to_host, ready) {
+        Err(Error::Slirp(SlirpError::BrokenPipe(e))) => {
+            info!("exited slirp listening loop: {}", e)
+        }
+        Err(e) => error!("error while running slirp listening loop: {}", e),
+        Ok(()) => {}
+    }
+}
+
+/// Handle for a pseudo-tap interface backed by libslirp.
+///
+/// Frames are exchanged with the libslirp loop over a `SOCK_SEQPACKET` socket pair. The loop is
+/// started separately with `run_slirp_loop`, usually in its own sandboxed process, and exits
+/// once every handle to the guest end of the pair has been closed.
+pub struct Slirp {
+    guest_socket: UnixSeqpacket,
+}
+
+impl Slirp {
+    /// Returns a pseudo-tap along with the other end of its socket pair, which must be handed to
+    /// `run_slirp_loop`.
+    pub fn new_pair() -> Result<(Slirp, UnixSeqpacket)> {
+        let (host_socket, guest_socket) = UnixSeqpacket::pair()
+            .map_err(SysError::from)
+            .map_err(Error::CreateSocket)?;
+        for socket in [&host_socket, &guest_socket] {
+            socket
+                .set_nonblocking(true)
+                .map_err(SysError::from)
+                .map_err(Error::CreateSocket)?;
+        }
+        Ok((Slirp { guest_socket }, host_socket))
+    }
+}
+
+impl TapT for Slirp {}
+
+impl TapTCommon for Slirp {
+    fn new_with_name(_name: &[u8], _vnet_hdr: bool, _multi_vq: bool) -> Result<Self> {
+        unimplemented!("not implemented for Slirp");
+    }
+
+    fn new(_vnet_hdr: bool, _multi_vq: bool) -> Result<Slirp> {
+        unimplemented!("not implemented for Slirp");
+    }
+
+    fn into_mq_taps(self, vq_pairs: u16) -> Result<Vec<Self>> {
+        if vq_pairs != 1 {
+            unimplemented!("libslirp is single threaded; only one vq pair is supported.");
+        }
+
+        Ok(vec![self])
+    }
+
+    fn ip_addr(&self) -> Result<net::Ipv4Addr> {
+        // Only used by the plugin system.
+        unimplemented!("need to fetch the client's IP address from Slirp");
+    }
+
+    fn set_ip_addr(&self, _ip_addr: net::Ipv4Addr) -> Result<()> {
+        // Only used by the plugin system.
+        unimplemented!("need to fetch the client's IP address from Slirp");
+    }
+
+    fn netmask(&self) -> Result<net::Ipv4Addr> {
+        // Only used by the plugin system.
+        unimplemented!("need to fetch the client's IP address from Slirp");
+    }
+
+    fn set_netmask(&self, _netmask: net::Ipv4Addr) -> Result<()> {
+        // Only used by the plugin system.
+        unimplemented!("need to fetch the client's IP address from Slirp");
+    }
+
+    fn mtu(&self) -> Result<u16> {
+        Ok(SLIRP_MTU)
+    }
+
+    fn set_mtu(&self, _mtu: u16) -> Result<()> {
+        unimplemented!("Set MTU unsupported by Slirp");
+    }
+
+    fn mac_address(&self) -> Result<MacAddress> {
+        // Only used by the plugin system.
+        unimplemented!("need to fetch the client's IP address from Slirp");
+    }
+
+    fn set_mac_address(&self, _mac_addr: MacAddress) -> Result<()> {
+        // Only used by the plugin system.
+        unimplemented!("need to fetch the client's IP address from Slirp");
+    }
+
+    fn set_offload(&self, flags: c_uint) -> Result<()> {
+        // Slirp does not support offload.
+        if flags != 0 {
+            return Err(Error::IoctlError(SysError::new(libc::EINVAL)));
+        }
+        Ok(())
+    }
+
+    fn enable(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_vnet_hdr_size(&self, size: c_int) -> Result<()> {
+        // The slirp loop always adds and strips a 12 byte virtio_net_hdr_mrg_rxbuf.
+        if size != 12 {
+            return Err(Error::IoctlError(SysError::new(libc::EINVAL)));
+        }
+        Ok(())
+    }
+
+    fn get_ifreq(&self) -> net_sys::ifreq {
+        // Used only by accessors on this struct, which are unimplemented for Slirp.
+        unimplemented!("not used by Slirp");
+    }
+
+    fn if_flags(&self) -> u32 {
+        // Frames carry a vnet header and no packet information, like a single queue tap.
+        net_sys::IFF_TAP | net_sys::IFF_NO_PI | net_sys::IFF_VNET_HDR
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        Ok(Slirp {
+            guest_socket: self
+                .guest_socket
+                .try_clone()
+                .map_err(|e| Error::Slirp(SlirpError::CloneFailed(e)))?,
+        })
+    }
+
+    unsafe fn from_raw_descriptor(_descriptor: RawDescriptor) -> Result<Self> {
+        unimplemented!("not used by Slirp");
+    }
+}
+
+impl Read for Slirp {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.guest_socket.recv(buf)
+    }
+}
+
+impl Write for Slirp {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.guest_socket.send(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for Slirp {
+    fn as_raw_fd(&self) -> RawFd {
+        self.guest_socket.as_raw_descriptor()
+    }
+}
+
+impl AsRawDescriptor for Slirp {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.guest_socket.as_raw_descriptor()
+    }
+}
+
+impl ReadNotifier for Slirp {
+    fn get_read_notifier(&self) -> &dyn AsRawDescriptor {
+        self
+    }
+}
+
+impl IntoAsync for Slirp {}
+volatile_impl!(Slirp);
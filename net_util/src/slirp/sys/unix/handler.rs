@@ -0,0 +1,425 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::time::Duration;
+use std::time::Instant;
+
+use base::warn;
+use base::AsRawDescriptor;
+use base::RawDescriptor;
+use base::Timer;
+use base::UnixSeqpacket;
+use data_model::DataInit;
+use smallvec::SmallVec;
+use virtio_sys::virtio_net::virtio_net_hdr;
+use virtio_sys::virtio_net::virtio_net_hdr_mrg_rxbuf;
+
+use crate::slirp::context::CallbackHandler;
+use crate::slirp::context::Context;
+use crate::slirp::context::PollEvents;
+use crate::slirp::HostFwd;
+use crate::slirp::HostFwdProtocol;
+use crate::slirp::SlirpError;
+use crate::slirp::DEFAULT_GUEST_ADDR;
+use crate::slirp::ETHERNET_FRAME_SIZE;
+use crate::Error;
+use crate::Result;
+
+const VETH_HEADER_LENGTH: usize = 12;
+
+/// A libslirp timer callback, along with a clone of the Timer that triggers it. The clone lets the
+/// slirp loop drain the timer when it fires.
+struct TimerCallback {
+    timer: Timer,
+    callback: Box<dyn FnMut()>,
+}
+
+struct Handler {
+    start: Instant,
+    socket: UnixSeqpacket,
+    buf: [u8; ETHERNET_FRAME_SIZE],
+    // Stores the timer callbacks. Note that Timer ownership is held by libslirp, and
+    // created/released via `timer_new` and `timer_free`.
+    timer_callbacks: HashMap<RawDescriptor, TimerCallback>,
+}
+
+impl CallbackHandler for Handler {
+    type Timer = base::Timer;
+
+    fn clock_get_ns(&mut self) -> i64 {
+        const NANOS_PER_SEC: u64 = 1_000_000_000;
+        let running_duration = self.start.elapsed();
+        (running_duration.as_secs() * NANOS_PER_SEC + running_duration.subsec_nanos() as u64) as i64
+    }
+
+    /// Sends a packet to the guest.
+    fn send_packet(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let vnet_hdr = virtio_net_hdr_mrg_rxbuf {
+            hdr: virtio_net_hdr {
+                flags: 0,
+                gso_size: 0,
+                hdr_len: 0,
+                csum_start: 0,
+                csum_offset: 0,
+                gso_type: virtio_sys::virtio_net::VIRTIO_NET_HDR_GSO_NONE as u8,
+            },
+            num_buffers: 1,
+        };
+        let send_buf = [vnet_hdr.as_slice(), buf].concat();
+        self.socket.send(&send_buf)
+    }
+
+    // Not required per https://github.com/rootless-containers/slirp4netns/blob/7f6a4a654a84d4356c881a10417bab77fd5be325/slirp4netns.c
+    fn register_poll_fd(&mut self, _fd: i32) {}
+    fn unregister_poll_fd(&mut self, _fd: i32) {}
+
+    fn guest_error(&mut self, msg: &str) {
+        warn!("guest error: {}", msg);
+    }
+
+    // Not required per https://github.com/rootless-containers/slirp4netns/blob/7f6a4a654a84d4356c881a10417bab77fd5be325/slirp4netns.c
+    fn notify(&mut self) {}
+
+    fn timer_new(&mut self, callback: Box<dyn FnMut()>) -> Box<Self::Timer> {
+        let timer = Timer::new().expect("failed to create network timer");
+        self.timer_callbacks.insert(
+            timer.as_raw_descriptor(),
+            TimerCallback {
+                timer: timer.try_clone().expect("failed to clone network timer"),
+                callback,
+            },
+        );
+        Box::new(timer)
+    }
+
+    fn timer_mod(&mut self, timer: &mut Self::Timer, expire_time: i64) {
+        // expire_time is a clock_get_ns relative deadline in milliseconds, which may already have
+        // passed. A zero duration would disarm the timer, so fire as soon as possible instead.
+        let timer_duration = Duration::from_millis(expire_time.max(0) as u64)
+            .saturating_sub(Duration::from_nanos(self.clock_get_ns() as u64))
+            .max(Duration::from_nanos(1));
+
+        timer
+            .reset(timer_duration, None)
+            .expect("failed to modify network timer");
+    }
+
+    fn timer_free(&mut self, timer: Box<Self::Timer>) {
+        self.timer_callbacks.remove(&timer.as_raw_descriptor());
+        // The actual Timer is freed implicitly by the Box drop.
+    }
+
+    fn get_timers<'a>(&'a self) -> Box<dyn Iterator<Item = &RawDescriptor> + 'a> {
+        Box::new(self.timer_callbacks.keys())
+    }
+
+    fn execute_timer(&mut self, timer: RawDescriptor) {
+        let timer_callback = self
+            .timer_callbacks
+            .get_mut(&timer)
+            .expect("tried to run timer that has no callback");
+        if let Err(e) = timer_callback.timer.mark_waited() {
+            warn!("failed to clear network timer: {}", e);
+        }
+        (timer_callback.callback)()
+    }
+
+    fn begin_read_from_guest(&mut self) -> io::Result<()> {
+        // The socket is polled by the slirp loop, so there is nothing to start here.
+        Ok(())
+    }
+
+    fn end_read_from_guest(&mut self) -> io::Result<&[u8]> {
+        match self.socket.recv(&mut self.buf) {
+            Ok(0) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "guest's virtio-net frontend closed the connection",
+            )),
+            // Skip over the veth header (12 bytes, created by the frontend per the virtio spec).
+            Ok(len) if len >= VETH_HEADER_LENGTH => Ok(&self.buf[VETH_HEADER_LENGTH..len]),
+            Ok(len) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Too few bytes ({}) read from the guest's virtio-net frontend.",
+                    len
+                ),
+            )),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn slirp_events_to_poll_events(events: PollEvents) -> libc::c_short {
+    let mut poll_events = 0;
+    if events.has_in() {
+        poll_events |= libc::POLLIN;
+    }
+    if events.has_out() {
+        poll_events |= libc::POLLOUT;
+    }
+    if events.has_pri() {
+        poll_events |= libc::POLLPRI;
+    }
+    // POLLERR and POLLHUP are always reported by poll(2), so they need not be requested.
+    poll_events
+}
+
+fn poll_events_to_slirp_events(events: libc::c_short) -> PollEvents {
+    let mut slirp_events = PollEvents::empty();
+    if events & libc::POLLIN != 0 {
+        slirp_events |= PollEvents::poll_in();
+    }
+    if events & libc::POLLOUT != 0 {
+        slirp_events |= PollEvents::poll_out();
+    }
+    if events & libc::POLLPRI != 0 {
+        slirp_events |= PollEvents::poll_pri();
+    }
+    if events & libc::POLLERR != 0 {
+        slirp_events |= PollEvents::poll_err();
+    }
+    if events & libc::POLLHUP != 0 {
+        slirp_events |= PollEvents::poll_hup();
+    }
+    slirp_events
+}
+
+fn poll(poll_fds: &mut [libc::pollfd], timeout_ms: u32) -> io::Result<usize> {
+    // libslirp uses u32::MAX to mean that it has no deadline of its own.
+    let timeout = i32::try_from(timeout_ms).unwrap_or(-1);
+    // Safe because poll_fds is a valid slice of pollfd structs, and we check the return value.
+    let ret = unsafe {
+        libc::poll(
+            poll_fds.as_mut_ptr(),
+            poll_fds.len() as libc::nfds_t,
+            timeout,
+        )
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Starts libslirp's main loop attached to host_socket. Packets are exchanged between host_socket
+/// and the host's network stack.
+///
+/// host_socket must be non blocking. The result of setting up libslirp (including the host
+/// forwards) is passed to `ready` before the loop starts. The loop runs until the other end of
+/// host_socket is closed, which is reported as `SlirpError::BrokenPipe`.
+pub fn start_slirp<F: FnOnce(Result<()>)>(
+    host_socket: UnixSeqpacket,
+    host_forwards: &[HostFwd],
+    disable_access_to_host: bool,
+    ready: F,
+) -> Result<()> {
+    let (mut context, host_socket_fd) =
+        match create_slirp_context(host_socket, host_forwards, disable_access_to_host) {
+            Ok(v) => {
+                ready(Ok(()));
+                v
+            }
+            Err(e) => {
+                ready(Err(e));
+                return Ok(());
+            }
+        };
+
+    loop {
+        // Request the FDs that we should poll from Slirp. See the Windows implementation for a
+        // description of the data flow between pollfds_fill, poll and pollfds_poll.
+        let mut poll_fds = Vec::new();
+        // We'd like to sleep as long as possible (assuming no actionable notifications arrive).
+        let mut timeout_ms: u32 = u32::MAX;
+        context.pollfds_fill(&mut timeout_ms, |fd: i32, events: PollEvents| {
+            poll_fds.push(libc::pollfd {
+                fd,
+                events: slirp_events_to_poll_events(events),
+                revents: 0,
+            });
+            (poll_fds.len() - 1) as i32
+        });
+        let slirp_fd_count = poll_fds.len();
+
+        poll_fds.push(libc::pollfd {
+            fd: host_socket_fd,
+            events: libc::POLLIN,
+            revents: 0,
+        });
+
+        // There are relatively few concurrent timers used by libslirp, so we set the small vector
+        // size low.
+        let timers = context
+            .get_timers()
+            .copied()
+            .collect::<SmallVec<[RawDescriptor; 8]>>();
+        poll_fds.extend(timers.iter().map(|&fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        }));
+
+        let error = match poll(&mut poll_fds, timeout_ms) {
+            Ok(_) => false,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::Slirp(SlirpError::SlirpIOPollError(e))),
+        };
+
+        if poll_fds[slirp_fd_count].revents != 0 {
+            // Collect input from the guest & inject into Slirp. It seems that this input step
+            // should be between pollfds_fill & pollfds_poll.
+            context.handle_guest_input()?;
+        }
+
+        for poll_fd in &poll_fds[slirp_fd_count + 1..] {
+            if poll_fd.revents & libc::POLLIN != 0 {
+                context.execute_timer(poll_fd.fd);
+            }
+        }
+
+        // It's possible no socket notified and we got here from a timeout. This is fine, because
+        // libslirp wants to be woken up if timeout has expired (even if no sockets are ready).
+        context.pollfds_poll(error, |fd_index: i32| {
+            poll_events_to_slirp_events(poll_fds[fd_index as usize].revents)
+        })
+    }
+}
+
+fn create_slirp_context(
+    host_socket: UnixSeqpacket,
+    host_forwards: &[HostFwd],
+    disable_access_to_host: bool,
+) -> Result<(Box<Context<Handler>>, RawDescriptor)> {
+    let host_socket_fd = host_socket.as_raw_descriptor();
+    let handler = Handler {
+        start: Instant::now(),
+        socket: host_socket,
+        buf: [0; ETHERNET_FRAME_SIZE],
+        timer_callbacks: HashMap::new(),
+    };
+
+    // Address & mask of the virtual network.
+    let v4_network_addr = Ipv4Addr::new(10, 0, 2, 0);
+    let v4_network_mask = Ipv4Addr::new(255, 255, 255, 0);
+
+    // Address of the host machine on the virtual network (if the feature is enabled).
+    let host_v4_addr = Ipv4Addr::new(10, 0, 2, 2);
+
+    // Address of the libslirp provided DNS proxy (packets to this address are intercepted by
+    // libslirp & routed to the first nameserver configured on the machine's NICs by libslirp).
+    let dns_addr = Ipv4Addr::new(10, 0, 2, 3);
+
+    // DHCP range should start *after* the statically assigned addresses.
+    let dhcp_start_addr = DEFAULT_GUEST_ADDR;
+
+    // IPv6 network address. This is the same ULA network as the Windows implementation uses.
+    let v6_network_addr = Ipv6Addr::new(0xfd13, 0x6246, 0x3218, 0x0001, 0, 0, 0, 0);
+
+    let v6_host_addr = Ipv6Addr::new(0xfd13, 0x6246, 0x3218, 0x0001, 0, 0, 0, 2);
+    let v6_dns_addr = Ipv6Addr::new(0xfd13, 0x6246, 0x3218, 0x0001, 0, 0, 0, 3);
+    let mut context = Context::new(
+        disable_access_to_host,
+        /* IPv4 enabled */
+        true,
+        v4_network_addr,
+        v4_network_mask,
+        host_v4_addr,
+        /* IPv6 enabled */ true,
+        v6_network_addr,
+        /* virtual_network_v6_prefix_len */ 64,
+        /* host_v6_address */ v6_host_addr,
+        /* host_hostname */ None,
+        dhcp_start_addr,
+        dns_addr,
+        /* dns_server_v6_addr */ v6_dns_addr,
+        /* virtual_network_dns_search_domains */ Vec::new(),
+        /* dns_server_domain_name */ None,
+        handler,
+    )?;
+
+    for host_forward in host_forwards {
+        context
+            .add_hostfwd(
+                host_forward.protocol == HostFwdProtocol::Udp,
+                host_forward.host_addr,
+                host_forward.host_port,
+                host_forward.guest_addr,
+                host_forward.guest_port,
+            )
+            .map_err(|e| Error::Slirp(SlirpError::AddHostFwd(*host_forward, e)))?;
+    }
+
+    Ok((context, host_socket_fd))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn poll_events_round_trip() {
+        let events = PollEvents::poll_in() | PollEvents::poll_out();
+        assert_eq!(
+            poll_events_to_slirp_events(slirp_events_to_poll_events(events)),
+            events
+        );
+        assert!(poll_events_to_slirp_events(libc::POLLHUP).has_hup());
+    }
+
+    #[test]
+    fn slirp_exits_when_guest_disconnects() {
+        let (host_socket, guest_socket) = UnixSeqpacket::pair().unwrap();
+        host_socket.set_nonblocking(true).unwrap();
+        let (ready_send, ready_recv) = channel();
+
+        // Bind an ephemeral port on loopback for the forward so the test can connect to it.
+        let host_forward = HostFwd {
+            protocol: HostFwdProtocol::Tcp,
+            host_addr: Ipv4Addr::LOCALHOST,
+            host_port: 0,
+            guest_addr: DEFAULT_GUEST_ADDR,
+            guest_port: 80,
+        };
+        let slirp_thread = thread::spawn(move || {
+            start_slirp(host_socket, &[host_forward], true, |res| {
+                let _ = ready_send.send(res);
+            })
+        });
+        ready_recv.recv().unwrap().unwrap();
+
+        drop(guest_socket);
+        match slirp_thread.join().unwrap() {
+            Err(Error::Slirp(SlirpError::BrokenPipe(_))) => {}
+            r => panic!("unexpected slirp loop result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn hostfwd_port_in_use() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let (host_socket, _guest_socket) = UnixSeqpacket::pair().unwrap();
+        let host_forward = HostFwd {
+            protocol: HostFwdProtocol::Tcp,
+            host_addr: Ipv4Addr::LOCALHOST,
+            host_port: port,
+            guest_addr: DEFAULT_GUEST_ADDR,
+            guest_port: 80,
+        };
+        assert!(matches!(
+            create_slirp_context(host_socket, &[host_forward], true),
+            Err(Error::Slirp(SlirpError::AddHostFwd(..)))
+        ));
+    }
+}
@@ -10,9 +10,11 @@ cfg_if::cfg_if! {
         use devices::virtio::vhost::user::device::parse_wayland_sock;
 
         use super::sys::config::{
-            VfioCommand, parse_vfio, parse_vfio_platform,
+            MetricsOption, VfioCommand, VirtioMemOption, parse_vfio, parse_vfio_platform,
         };
         use super::config::SharedDir;
+        #[cfg(feature = "swtpm")]
+        use super::sys::config::SwtpmOption;
     } else if #[cfg(windows)] {
         use crate::crosvm::sys::config::IrqChipKind;
 
@@ -30,6 +32,7 @@ use std::sync::atomic::Ordering;
 use arch::CpuSet;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use arch::MsrConfig;
+use arch::NumaNode;
 use arch::Pstore;
 use arch::VcpuAffinity;
 use argh::FromArgs;
@@ -47,8 +50,11 @@ use devices::virtio::GpuDisplayParameters;
 use devices::virtio::GpuParameters;
 #[cfg(unix)]
 use devices::virtio::NetParameters;
+#[cfg(all(unix, feature = "gpu"))]
+use devices::virtio::VncAddress;
 #[cfg(feature = "audio")]
 use devices::Ac97Parameters;
+use devices::FwCfgParameters;
 use devices::PflashParameters;
 use devices::SerialHardware;
 use devices::SerialParameters;
@@ -60,6 +66,9 @@ use resources::AddressRange;
 use serde::Deserialize;
 #[cfg(feature = "gpu")]
 use serde_keyvalue::FromKeyValues;
+#[cfg(unix)]
+use vm_control::DiskThrottleConfig;
+use vm_control::MigrationUri;
 
 #[cfg(feature = "gpu")]
 use super::gpu_config::fixup_gpu_display_options;
@@ -75,6 +84,7 @@ use crate::crosvm::config::parse_cpu_affinity;
 use crate::crosvm::config::parse_cpu_capacity;
 #[cfg(feature = "direct")]
 use crate::crosvm::config::parse_direct_io_options;
+use crate::crosvm::config::parse_fw_cfg_parameters;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use crate::crosvm::config::parse_memory_region;
 use crate::crosvm::config::parse_mmio_address_range;
@@ -104,6 +114,10 @@ use crate::crosvm::config::TouchDeviceOption;
 use crate::crosvm::config::VhostUserFsOption;
 use crate::crosvm::config::VhostUserOption;
 use crate::crosvm::config::VvuOption;
+#[cfg(unix)]
+use crate::crosvm::config::DEFAULT_TOUCH_DEVICE_HEIGHT;
+#[cfg(unix)]
+use crate::crosvm::config::DEFAULT_TOUCH_DEVICE_WIDTH;
 
 #[derive(FromArgs)]
 /// crosvm
@@ -239,6 +253,11 @@ pub struct CreateQcow2Command {
 #[argh(subcommand)]
 pub enum DiskSubcommand {
     Resize(ResizeDiskSubcommand),
+    CreateSnapshot(CreateSnapshotDiskSubcommand),
+    ListSnapshots(ListSnapshotsDiskSubcommand),
+    ApplySnapshot(ApplySnapshotDiskSubcommand),
+    DeleteSnapshot(DeleteSnapshotDiskSubcommand),
+    SetThrottle(SetThrottleDiskSubcommand),
 }
 
 #[derive(FromArgs)]
@@ -256,6 +275,79 @@ pub struct ResizeDiskSubcommand {
     pub socket_path: String,
 }
 
+#[derive(FromArgs)]
+/// save the current contents of a qcow2 disk as an internal snapshot
+#[argh(subcommand, name = "create-snapshot")]
+pub struct CreateSnapshotDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "NAME")]
+    /// snapshot name
+    pub name: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// list the internal snapshots of a qcow2 disk
+#[argh(subcommand, name = "list-snapshots")]
+pub struct ListSnapshotsDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// revert a qcow2 disk to an internal snapshot. The guest should not be using the disk, as its
+/// contents change underneath it.
+#[argh(subcommand, name = "apply-snapshot")]
+pub struct ApplySnapshotDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "SNAPSHOT")]
+    /// snapshot ID or name
+    pub snapshot: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// delete an internal snapshot of a qcow2 disk
+#[argh(subcommand, name = "delete-snapshot")]
+pub struct DeleteSnapshotDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "SNAPSHOT")]
+    /// snapshot ID or name
+    pub snapshot: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// replace the I/O limits of a disk. Limits left out of LIMITS are removed.
+#[argh(subcommand, name = "set-throttle")]
+pub struct SetThrottleDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "LIMITS")]
+    /// comma-separated limits, e.g. read-iops=1000,write-bps=10485760
+    pub limits: DiskThrottleConfig,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "disk")]
 /// Manage attached virtual disk devices
@@ -524,6 +616,9 @@ pub struct GpuRemoveDisplaysCommand {
 #[argh(subcommand)]
 pub enum UsbSubCommand {
     Attach(UsbAttachCommand),
+    #[cfg(unix)]
+    AttachHid(UsbAttachHidCommand),
+    AttachStorage(UsbAttachStorageCommand),
     Detach(UsbDetachCommand),
     List(UsbListCommand),
 }
@@ -546,6 +641,63 @@ pub struct UsbAttachCommand {
     pub socket_path: String,
 }
 
+#[derive(FromArgs)]
+/// Attach an emulated usb mass storage device backed by a disk image
+#[argh(subcommand, name = "attach-storage")]
+pub struct UsbAttachStorageCommand {
+    #[argh(switch)]
+    /// expose the disk image as read-only
+    pub ro: bool,
+    #[argh(positional, arg_name = "IMAGE")]
+    /// disk image path
+    pub image_path: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+/// Type of an emulated usb HID device.
+#[cfg(unix)]
+pub enum UsbHidType {
+    Keyboard,
+    Tablet,
+}
+
+#[cfg(unix)]
+impl FromStr for UsbHidType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keyboard" => Ok(UsbHidType::Keyboard),
+            "tablet" => Ok(UsbHidType::Tablet),
+            _ => Err(format!("unknown HID device type: {}", s)),
+        }
+    }
+}
+
+#[cfg(unix)]
+#[derive(FromArgs)]
+/// Attach an emulated usb keyboard or tablet reporting the input events of a socket
+#[argh(subcommand, name = "attach-hid")]
+pub struct UsbAttachHidCommand {
+    #[argh(positional, arg_name = "TYPE")]
+    /// device type: keyboard or tablet
+    pub device_type: UsbHidType,
+    #[argh(positional, arg_name = "SOURCE")]
+    /// path to the socket sending the input events
+    pub source_path: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+    #[argh(option, default = "DEFAULT_TOUCH_DEVICE_WIDTH")]
+    /// width of the tablet coordinates
+    pub width: u32,
+    #[argh(option, default = "DEFAULT_TOUCH_DEVICE_HEIGHT")]
+    /// height of the tablet coordinates
+    pub height: u32,
+}
+
 #[derive(FromArgs)]
 /// Detach usb device
 #[argh(subcommand, name = "detach")]
@@ -614,7 +766,7 @@ pub struct SnapshotCommand {
 /// Take a snapshot of the VM
 pub struct SnapshotTakeCommand {
     #[argh(positional, arg_name = "snapshot_path")]
-    /// VM Image path
+    /// directory to write the snapshot to
     pub snapshot_path: PathBuf,
     #[argh(positional, arg_name = "VM_SOCKET")]
     /// VM Socket path
@@ -640,7 +792,7 @@ pub struct RestoreCommand {
 /// Restore VM
 pub struct RestoreApplyCommand {
     #[argh(positional, arg_name = "restore_path")]
-    /// VM Restore image path
+    /// snapshot directory to restore the VM from
     pub restore_path: PathBuf,
     #[argh(positional, arg_name = "VM_SOCKET")]
     /// VM Socket path
@@ -1105,6 +1257,22 @@ pub struct RunCommand {
     /// doesn't require one.
     pub force_calibrated_tsc_leaf: bool,
 
+    #[argh(
+        option,
+        arg_name = "name=NAME,(path=PATH|string=STRING)",
+        from_str_fn(parse_fw_cfg_parameters)
+    )]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = append)]
+    /// comma-separated key-value pair for a file exposed to the
+    ///     firmware and guest through the fw_cfg device. Can be given
+    ///     more than once.
+    ///     Possible key values:
+    ///     name=NAME - name of the file, such as opt/org.example/foo
+    ///     path=PATH - host file whose content is exposed
+    ///     string=STRING - string exposed as the content of the file
+    pub fw_cfg: Vec<FwCfgParameters>,
+
     #[cfg(feature = "gdb")]
     #[argh(option, arg_name = "PORT")]
     #[merge(strategy = overwrite_option)]
@@ -1229,6 +1397,15 @@ pub struct RunCommand {
     #[merge(strategy = overwrite_option)]
     pub hypervisor: Option<HypervisorKind>,
 
+    #[cfg(unix)]
+    #[argh(option, arg_name = "URI")]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_option)]
+    /// wait for a VM sent with `crosvm migrate send` on URI,
+    /// either `unix:PATH` or `tcp:HOST:PORT`, and run it
+    /// instead of booting.
+    pub incoming: Option<MigrationUri>,
+
     #[argh(option, arg_name = "N")]
     #[serde(skip)] // TODO(b/255223604)
     #[merge(strategy = overwrite_option)]
@@ -1247,6 +1424,14 @@ pub struct RunCommand {
     /// type of interrupt controller emulation.  \"split\" is only available for x86 KVM.
     pub irqchip: Option<IrqChipKind>,
 
+    #[cfg(unix)]
+    #[argh(switch)]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_false)]
+    /// put the emulated virtio PCI devices behind the virtio-iommu, so that the guest controls
+    /// which memory they can access. Requires a virtio-iommu driver in the guest.
+    pub isolate_virtio_devices: bool,
+
     #[argh(switch)]
     #[serde(skip)] // TODO(b/255223604)
     #[merge(strategy = overwrite_false)]
@@ -1313,6 +1498,20 @@ pub struct RunCommand {
     ///     size=NUM - amount of guest memory in MiB. (default: 256)
     pub mem: Option<MemOptions>,
 
+    #[cfg(unix)]
+    #[argh(option, arg_name = "[path=PATH][,interval=SECS]")]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_option)]
+    /// periodically sample the CPU, memory, storage and network
+    /// usage of the crosvm processes. The latest sample is
+    /// returned by `crosvm metrics`.
+    /// Possible key values:
+    ///     path=PATH - write each sample to PATH in the
+    ///        Prometheus text format.
+    ///     interval=SECS - seconds between two samples.
+    ///        (default: 10)
+    pub metrics: Option<MetricsOption>,
+
     #[argh(option, from_str_fn(parse_mmio_address_range))]
     #[serde(skip)] // TODO(b/255223604)
     #[merge(strategy = overwrite_option)]
@@ -1341,7 +1540,7 @@ pub struct RunCommand {
     #[cfg(unix)]
     #[argh(
         option,
-        arg_name = "tap_name=TAP_NAME|tap_fd=TAP_FD|host_ip=IP,netmask=NETMASK,mac=MAC_ADDRESS"
+        arg_name = "tap_name=TAP_NAME|tap_fd=TAP_FD|host_ip=IP,netmask=NETMASK,mac=MAC_ADDRESS|slirp[,hostfwd=FWD]"
     )]
     #[serde(default)]
     #[merge(strategy = append)]
@@ -1357,8 +1556,14 @@ pub struct RunCommand {
     ///         host tap interface.
     ///     netmask=STRING - Netmask for VM subnet.
     ///     mac=STRING - MAC address for VM.
-    /// Either one tap_name, one tap_fd or a triplet of host_ip,
-    /// netmask and mac must be specified.
+    /// OR
+    ///     slirp - Use libslirp user-mode networking, which needs
+    ///         no TAP interface (requires the `slirp` feature).
+    ///     hostfwd=[tcp|udp]:[haddr]:hport-[gaddr]:gport - Forward
+    ///         a host port into the guest, e.g. tcp:8080-:80. Use
+    ///         [FWD1,FWD2] to forward several ports.
+    /// Either one tap_name, one tap_fd, slirp or a triplet of
+    /// host_ip, netmask and mac must be specified.
     pub net: Vec<NetParameters>,
 
     #[cfg(unix)]
@@ -1413,6 +1618,23 @@ pub struct RunCommand {
     /// don't use usb devices in the guest
     pub no_usb: bool,
 
+    #[argh(option, arg_name = "node=ID,cpus=[CPUS],mem=SIZE")]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = append)]
+    /// comma separated key=value pairs describing a guest NUMA
+    /// node. Can be given more than once.
+    /// Possible key values:
+    ///     node=NUM - ID of the node. IDs must be contiguous
+    ///        from 0.
+    ///     cpus=[CPUS] - vCPUs of the node, e.g. [0-3].
+    ///     mem=NUM - amount of guest memory of the node in MiB.
+    ///     host-node=NUM - host NUMA node to allocate the memory
+    ///        of the node from (optional).
+    ///     distances=[DIST,...] - distance from this node to
+    ///        every node, 10 for itself. (default: 20 to the
+    ///        other nodes)
+    pub numa: Vec<NumaNode>,
+
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     #[argh(option, arg_name = "OEM_STRING")]
     #[serde(skip)] // TODO(b/255223604)
@@ -1602,10 +1824,19 @@ pub struct RunCommand {
     /// enable virtio-pvclock.
     pub pvclock: bool,
 
+    #[cfg(unix)]
+    #[argh(option, arg_name = "PATH")]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_option)]
+    /// path to put a control socket speaking JSON with the
+    /// conventions of the QEMU Machine Protocol (QMP)
+    pub qmp_socket: Option<PathBuf>,
+
     #[argh(option, long = "restore", arg_name = "PATH")]
     #[serde(skip)] // TODO(b/255223604)
     #[merge(strategy = overwrite_option)]
-    /// path of the snapshot that is used to restore the VM on startup.
+    /// path of the snapshot directory that is used to restore the VM
+    /// on startup.
     pub restore: Option<PathBuf>,
 
     #[argh(option, arg_name = "PATH[,key=value[,key=value[,...]]]", short = 'r')]
@@ -1673,6 +1904,25 @@ pub struct RunCommand {
     /// routines to perform full guest suspension/resumption
     pub s2idle: bool,
 
+    #[cfg(unix)]
+    #[argh(option, arg_name = "PATH[,key=value[,key=value[,...]]]")]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = append)]
+    /// add a disk image as a LUN of the virtio-scsi controller.
+    /// The LUNs are numbered in the order of the options.
+    /// Valid keys:
+    ///     path=PATH - Path to the disk image. Can be specified
+    ///         without the key as the first argument.
+    ///     ro=BOOL - Whether the disk should be read-only.
+    ///         (default: false)
+    ///     sparse=BOOL - Indicates whether the disk should support
+    ///         UNMAP. (default: true)
+    ///     block-size=BYTES - Set the reported block size of the
+    ///         disk. (default: 512)
+    ///     direct=BOOL - Use O_DIRECT mode to bypass page cache.
+    ///         (default: false)
+    pub scsi_disk: Vec<DiskOption>,
+
     #[cfg(unix)]
     #[argh(switch)]
     #[serde(skip)] // TODO(b/255223604)
@@ -1689,7 +1939,7 @@ pub struct RunCommand {
 
     #[argh(
         option,
-        arg_name = "type=TYPE,[hardware=HW,num=NUM,path=PATH,input=PATH,console,earlycon,stdin]",
+        arg_name = "type=TYPE,[hardware=HW,num=NUM,path=PATH,input=PATH,console,earlycon,stdin,name=NAME]",
         from_str_fn(parse_serial_options)
     )]
     #[serde(default)]
@@ -1719,6 +1969,9 @@ pub struct RunCommand {
     ///     stdin - Direct standard input to this serial device.
     ///        Can only be given once. Will default to first serial
     ///        port if not provided.
+    ///     name=NAME - Expose this virtio-console device as the
+    ///        port NAME of a multiport console, shared by all the
+    ///        named virtio-console devices.
     pub serial: Vec<SerialParameters>,
 
     #[cfg(feature = "kiwi")]
@@ -1867,6 +2120,18 @@ pub struct RunCommand {
     /// path to a socket from where to read switch input events and write status updates to
     pub switches: Vec<PathBuf>,
 
+    #[cfg(all(unix, feature = "swtpm"))]
+    #[argh(option, arg_name = "path=PATH[,version=tpm12|tpm2]")]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_option)]
+    /// add a virtio-tpm device backed by the swtpm process
+    ///     whose control channel is at PATH.
+    /// Possible key values:
+    ///     path=PATH - control channel socket of swtpm.
+    ///     version=(tpm12|tpm2) - version of the TPM emulated
+    ///        by swtpm. (default: tpm2)
+    pub swtpm: Option<SwtpmOption>,
+
     #[argh(option, arg_name = "TAG")]
     #[serde(skip)] // TODO(b/255223604)
     #[merge(strategy = overwrite_option)]
@@ -1894,6 +2159,13 @@ pub struct RunCommand {
     /// comma-separated names of the task profiles to apply to all threads in crosvm including the vCPU threads
     pub task_profiles: Vec<String>,
 
+    #[cfg(all(unix, feature = "trace_json"))]
+    #[argh(option, arg_name = "PATH")]
+    #[merge(strategy = overwrite_option)]
+    /// path of a file to write trace events to, in the Chrome
+    ///     trace-event JSON format
+    pub trace_output: Option<PathBuf>,
+
     #[argh(option, arg_name = "PATH:WIDTH:HEIGHT")]
     #[serde(skip)] // TODO(b/255223604)
     #[merge(strategy = append)]
@@ -2066,6 +2338,20 @@ pub struct RunCommand {
     /// Possible backend values: libvda
     pub video_encoder: Vec<VideoDeviceConfig>,
 
+    #[cfg(unix)]
+    #[argh(option, arg_name = "size=MiB[,requested-size=MiB]")]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_option)]
+    /// add a virtio-mem device with a hotpluggable memory region.
+    /// The amount of memory the guest plugs is changed with
+    /// `crosvm mem resize`.
+    /// Possible key values:
+    ///     size=MiB - size of the hotpluggable region, a multiple
+    ///        of 128.
+    ///     requested-size=MiB - amount of memory the guest
+    ///        plugs at boot. (default: 0)
+    pub virtio_mem: Option<VirtioMemOption>,
+
     #[cfg(feature = "audio")]
     #[argh(
         option,
@@ -2079,8 +2365,13 @@ pub struct RunCommand {
     /// Possible key values:
     ///     capture=(false,true) - Disable/enable audio capture.
     ///         Default is false.
-    ///     backend=(null,[cras]) - Which backend to use for
-    ///         virtio-snd.
+    ///     backend=(null,file,[alsa],[cras]) - Which backend to
+    ///         use for virtio-snd.
+    ///     wav_dir=PATH - Directory of the WAV files of the file
+    ///         backend. PCM stream N is written to or read from
+    ///         streamN.wav.
+    ///     alsa_device=NAME - PCM device of the alsa backend.
+    ///         Default is default.
     ///     client_type=(crosvm,arcvm,borealis) - Set specific
     ///         client type for cras backend. Default is crosvm.
     ///     socket_type=(legacy,unified) Set specific socket type
@@ -2094,6 +2385,28 @@ pub struct RunCommand {
     ///         per device.
     pub virtio_snd: Vec<SndParameters>,
 
+    #[cfg(all(unix, feature = "gpu"))]
+    #[argh(option, arg_name = "ADDRESS")]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_option)]
+    /// serve the GPU display to VNC clients on ADDRESS, which is
+    ///     either a TCP address such as 127.0.0.1:5900 or the path of a
+    ///     unix socket. There is no authentication, so only bind it to
+    ///     addresses reachable by trusted users.
+    pub vnc_display: Option<VncAddress>,
+
+    #[cfg(unix)]
+    #[argh(option, arg_name = "SOCKET_PATH")]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_option)]
+    /// use a userspace vsock device that forwards guest
+    /// connections to unix sockets instead of vhost-vsock.
+    /// Guest connections to host port P are forwarded to
+    /// SOCKET_PATH_P, and host processes connect to the guest
+    /// by writing "CONNECT <port>\n" to SOCKET_PATH. Requires
+    /// --cid
+    pub vsock_uds: Option<PathBuf>,
+
     #[cfg(all(feature = "vtpm", target_arch = "x86_64"))]
     #[argh(switch)]
     #[serde(skip)] // TODO(b/255223604)
@@ -2223,6 +2536,8 @@ impl TryFrom<RunCommand> for super::config::Config {
 
         cfg.no_smt = cmd.no_smt;
 
+        cfg.numa_nodes = cmd.numa;
+
         if let Some(rt_cpus) = cmd.rt_cpus {
             cfg.rt_cpus = rt_cpus;
         }
@@ -2305,6 +2620,16 @@ impl TryFrom<RunCommand> for super::config::Config {
                 }
             }
 
+            if let Some(name) = &serial_params.name {
+                if cfg
+                    .serial_parameters
+                    .values()
+                    .any(|sp| sp.name.as_ref() == Some(name))
+                {
+                    return Err(format!("serial device name {} already used", name));
+                }
+            }
+
             if serial_params.stdin {
                 if let Some(previous_stdin) = cfg.serial_parameters.values().find(|sp| sp.stdin) {
                     return Err(format!(
@@ -2425,11 +2750,24 @@ impl TryFrom<RunCommand> for super::config::Config {
             cfg.x_display = cmd.x_display;
         }
 
+        #[cfg(all(unix, feature = "gpu"))]
+        {
+            cfg.vnc_display = cmd.vnc_display;
+        }
+
         cfg.display_window_keyboard = cmd.display_window_keyboard;
         cfg.display_window_mouse = cmd.display_window_mouse;
 
         cfg.swap_dir = cmd.swap_dir;
         cfg.restore_path = cmd.restore;
+        #[cfg(unix)]
+        {
+            cfg.incoming = cmd.incoming;
+            cfg.metrics = cmd.metrics;
+            cfg.qmp_socket_path = cmd.qmp_socket;
+            cfg.virtio_mem = cmd.virtio_mem;
+            cfg.scsi_disks = cmd.scsi_disk;
+        }
 
         if let Some(mut socket_path) = cmd.socket {
             if socket_path.is_dir() {
@@ -2503,6 +2841,11 @@ impl TryFrom<RunCommand> for super::config::Config {
             cfg.vtpm_proxy = cmd.vtpm_proxy;
         }
 
+        #[cfg(all(unix, feature = "swtpm"))]
+        {
+            cfg.swtpm = cmd.swtpm;
+        }
+
         cfg.virtio_single_touch = cmd.single_touch;
         cfg.virtio_multi_touch = cmd.multi_touch;
         cfg.virtio_trackpad = cmd.trackpad;
@@ -2519,14 +2862,20 @@ impl TryFrom<RunCommand> for super::config::Config {
         cfg.initrd_path = cmd.initrd;
 
         if let Some(p) = cmd.bios {
-            if cfg.executable_path.is_some() {
-                return Err(format!(
-                    "A VM executable was already specified: {:?}",
-                    cfg.executable_path
-                ));
+            match cfg.executable_path.take() {
+                // The firmware loads the kernel from the fw_cfg device.
+                Some(Executable::Kernel(kernel)) => cfg.fw_cfg_kernel_path = Some(kernel),
+                Some(executable) => {
+                    return Err(format!(
+                        "A VM executable was already specified: {:?}",
+                        executable
+                    ));
+                }
+                None => {}
             }
             cfg.executable_path = Some(Executable::Bios(p));
         }
+        cfg.fw_cfg_parameters = cmd.fw_cfg;
         cfg.pflash_parameters = cmd.pflash;
 
         #[cfg(feature = "video-decoder")]
@@ -2589,6 +2938,8 @@ impl TryFrom<RunCommand> for super::config::Config {
                 cfg.vhost_vsock_device = Some(PathBuf::from(format!("/proc/self/fd/{}", fd)));
             }
 
+            cfg.vsock_uds = cmd.vsock_uds;
+
             cfg.shared_dirs = cmd.shared_dir;
 
             cfg.net = cmd.net;
@@ -2728,6 +3079,11 @@ impl TryFrom<RunCommand> for super::config::Config {
 
         cfg.dmi_path = cmd.dmi;
 
+        #[cfg(unix)]
+        {
+            cfg.isolate_virtio_devices = cmd.isolate_virtio_devices;
+        }
+
         cfg.itmt = cmd.itmt;
 
         #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -2762,6 +3118,11 @@ impl TryFrom<RunCommand> for super::config::Config {
             cfg.task_profiles = cmd.task_profiles;
         }
 
+        #[cfg(all(unix, feature = "trace_json"))]
+        {
+            cfg.trace_output = cmd.trace_output;
+        }
+
         #[cfg(unix)]
         {
             cfg.vfio.extend(cmd.vfio);
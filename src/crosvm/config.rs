@@ -18,6 +18,7 @@ use arch::MsrFilter;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use arch::MsrRWType;
 use arch::MsrValueFrom;
+use arch::NumaNode;
 use arch::Pstore;
 use arch::VcpuAffinity;
 use base::debug;
@@ -34,6 +35,8 @@ use devices::virtio::block::block::DiskOption;
 use devices::virtio::device_constants::video::VideoDeviceConfig;
 #[cfg(feature = "gpu")]
 use devices::virtio::gpu::GpuParameters;
+#[cfg(all(unix, feature = "gpu"))]
+use devices::virtio::gpu::VncAddress;
 #[cfg(feature = "audio")]
 use devices::virtio::snd::parameters::Parameters as SndParameters;
 #[cfg(all(windows, feature = "gpu"))]
@@ -47,6 +50,7 @@ use devices::Ac97Backend;
 use devices::Ac97Parameters;
 #[cfg(feature = "direct")]
 use devices::BusRange;
+use devices::FwCfgParameters;
 use devices::PciAddress;
 use devices::PflashParameters;
 use devices::StubPciParameters;
@@ -57,6 +61,8 @@ use serde::Serialize;
 use serde_keyvalue::FromKeyValues;
 use uuid::Uuid;
 use vm_control::BatteryType;
+#[cfg(unix)]
+use vm_control::MigrationUri;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use x86_64::set_enable_pnp_data_msr_config;
 
@@ -661,6 +667,15 @@ pub fn validate_serial_parameters(params: &SerialParameters) -> Result<(), Strin
         ));
     }
 
+    if params.name.is_some() {
+        if params.hardware != SerialHardware::VirtioConsole {
+            return Err("Only virtio-console serial devices can be named".to_string());
+        }
+        if params.console {
+            return Err("Named virtio-console serial devices cannot be the console".to_string());
+        }
+    }
+
     Ok(())
 }
 
@@ -984,6 +999,19 @@ pub fn executable_is_plugin(executable: &Option<Executable>) -> bool {
     matches!(executable, Some(Executable::Plugin(_)))
 }
 
+pub fn parse_fw_cfg_parameters(s: &str) -> Result<FwCfgParameters, String> {
+    let fw_cfg_parameters: FwCfgParameters = from_key_values(s)?;
+
+    if fw_cfg_parameters.name.is_empty() {
+        return Err("fw_cfg name must not be empty".to_string());
+    }
+    if fw_cfg_parameters.path.is_some() == fw_cfg_parameters.string.is_some() {
+        return Err("exactly one of fw_cfg path or string must be given".to_string());
+    }
+
+    Ok(fw_cfg_parameters)
+}
+
 pub fn parse_pflash_parameters(s: &str) -> Result<PflashParameters, String> {
     let pflash_parameters: PflashParameters = from_key_values(s)?;
 
@@ -1078,6 +1106,8 @@ pub struct Config {
     pub file_backed_mappings: Vec<FileBackedMappingParameters>,
     pub force_calibrated_tsc_leaf: bool,
     pub force_s2idle: bool,
+    pub fw_cfg_kernel_path: Option<PathBuf>,
+    pub fw_cfg_parameters: Vec<FwCfgParameters>,
     #[cfg(feature = "gdb")]
     pub gdb: Option<u32>,
     #[cfg(all(windows, feature = "gpu"))]
@@ -1094,10 +1124,14 @@ pub struct Config {
     pub host_ip: Option<net::Ipv4Addr>,
     pub hugepages: bool,
     pub hypervisor: Option<HypervisorKind>,
+    #[cfg(unix)]
+    pub incoming: Option<MigrationUri>,
     pub init_memory: Option<u64>,
     pub initrd_path: Option<PathBuf>,
     #[cfg(windows)]
     pub irq_chip: Option<IrqChipKind>,
+    #[cfg(unix)]
+    pub isolate_virtio_devices: bool,
     pub itmt: bool,
     pub jail_config: Option<JailConfig>,
     #[cfg(windows)]
@@ -1113,6 +1147,8 @@ pub struct Config {
     pub mac_address: Option<net_util::MacAddress>,
     pub memory: Option<u64>,
     pub memory_file: Option<PathBuf>,
+    #[cfg(unix)]
+    pub metrics: Option<super::sys::config::MetricsOption>,
     pub mmio_address_ranges: Vec<AddressRange>,
     #[cfg(target_arch = "aarch64")]
     pub mte: bool,
@@ -1124,6 +1160,7 @@ pub struct Config {
     pub no_i8042: bool,
     pub no_rtc: bool,
     pub no_smt: bool,
+    pub numa_nodes: Vec<NumaNode>,
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub oem_strings: Vec<String>,
     pub params: Vec<String>,
@@ -1157,9 +1194,13 @@ pub struct Config {
     pub pvclock: bool,
     /// Must be `Some` iff `protection_type == ProtectionType::UnprotectedWithFirmware`.
     pub pvm_fw: Option<PathBuf>,
+    #[cfg(unix)]
+    pub qmp_socket_path: Option<PathBuf>,
     pub restore_path: Option<PathBuf>,
     pub rng: bool,
     pub rt_cpus: CpuSet,
+    #[cfg(unix)]
+    pub scsi_disks: Vec<DiskOption>,
     #[serde(with = "serde_serial_params")]
     pub serial_parameters: BTreeMap<(SerialHardware, u8), SerialParameters>,
     #[cfg(feature = "kiwi")]
@@ -1179,6 +1220,8 @@ pub struct Config {
     pub stub_pci_devices: Vec<StubPciParameters>,
     pub swap_dir: Option<PathBuf>,
     pub swiotlb: Option<u64>,
+    #[cfg(all(unix, feature = "swtpm"))]
+    pub swtpm: Option<super::sys::config::SwtpmOption>,
     #[cfg(windows)]
     pub syslog_tag: Option<String>,
     #[cfg(unix)]
@@ -1186,6 +1229,8 @@ pub struct Config {
     pub tap_name: Vec<String>,
     #[cfg(target_os = "android")]
     pub task_profiles: Vec<String>,
+    #[cfg(all(unix, feature = "trace_json"))]
+    pub trace_output: Option<PathBuf>,
     pub usb: bool,
     pub userspace_msr: BTreeMap<u32, MsrConfig>,
     pub vcpu_affinity: Option<VcpuAffinity>,
@@ -1216,6 +1261,8 @@ pub struct Config {
     pub video_enc: Vec<VideoDeviceConfig>,
     pub virtio_input_evdevs: Vec<PathBuf>,
     pub virtio_keyboard: Vec<PathBuf>,
+    #[cfg(unix)]
+    pub virtio_mem: Option<super::sys::config::VirtioMemOption>,
     pub virtio_mice: Vec<PathBuf>,
     pub virtio_multi_touch: Vec<TouchDeviceOption>,
     pub virtio_single_touch: Vec<TouchDeviceOption>,
@@ -1228,6 +1275,10 @@ pub struct Config {
     pub vm_evt_rdtube: Option<RecvTube>,
     #[cfg(windows)]
     pub vm_evt_wrtube: Option<SendTube>,
+    #[cfg(all(unix, feature = "gpu"))]
+    pub vnc_display: Option<VncAddress>,
+    #[cfg(unix)]
+    pub vsock_uds: Option<PathBuf>,
     #[cfg(all(feature = "vtpm", target_arch = "x86_64"))]
     pub vtpm_proxy: bool,
     pub vvu_proxy: Vec<VvuOption>,
@@ -1289,6 +1340,8 @@ impl Default for Config {
             file_backed_mappings: Vec::new(),
             force_calibrated_tsc_leaf: false,
             force_s2idle: false,
+            fw_cfg_kernel_path: None,
+            fw_cfg_parameters: Vec::new(),
             #[cfg(feature = "gdb")]
             gdb: None,
             #[cfg(all(windows, feature = "gpu"))]
@@ -1309,10 +1362,14 @@ impl Default for Config {
             product_channel: None,
             hugepages: false,
             hypervisor: None,
+            #[cfg(unix)]
+            incoming: None,
             init_memory: None,
             initrd_path: None,
             #[cfg(windows)]
             irq_chip: None,
+            #[cfg(unix)]
+            isolate_virtio_devices: false,
             itmt: false,
             jail_config: if !cfg!(feature = "default-no-sandbox") {
                 Some(Default::default())
@@ -1332,6 +1389,8 @@ impl Default for Config {
             mac_address: None,
             memory: None,
             memory_file: None,
+            #[cfg(unix)]
+            metrics: None,
             mmio_address_ranges: Vec::new(),
             #[cfg(target_arch = "aarch64")]
             mte: false,
@@ -1343,6 +1402,7 @@ impl Default for Config {
             no_i8042: false,
             no_rtc: false,
             no_smt: false,
+            numa_nodes: Vec::new(),
             #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
             oem_strings: Vec::new(),
             params: Vec::new(),
@@ -1371,9 +1431,13 @@ impl Default for Config {
             #[cfg(windows)]
             pvclock: false,
             pvm_fw: None,
+            #[cfg(unix)]
+            qmp_socket_path: None,
             restore_path: None,
             rng: true,
             rt_cpus: Default::default(),
+            #[cfg(unix)]
+            scsi_disks: Vec::new(),
             serial_parameters: BTreeMap::new(),
             #[cfg(feature = "kiwi")]
             service_pipe_name: None,
@@ -1391,6 +1455,8 @@ impl Default for Config {
             strict_balloon: false,
             stub_pci_devices: Vec::new(),
             swiotlb: None,
+            #[cfg(all(unix, feature = "swtpm"))]
+            swtpm: None,
             #[cfg(windows)]
             syslog_tag: None,
             #[cfg(unix)]
@@ -1398,6 +1464,8 @@ impl Default for Config {
             tap_name: Vec::new(),
             #[cfg(target_os = "android")]
             task_profiles: Vec::new(),
+            #[cfg(all(unix, feature = "trace_json"))]
+            trace_output: None,
             usb: true,
             userspace_msr: BTreeMap::new(),
             vcpu_affinity: None,
@@ -1428,6 +1496,8 @@ impl Default for Config {
             video_enc: Vec::new(),
             virtio_input_evdevs: Vec::new(),
             virtio_keyboard: Vec::new(),
+            #[cfg(unix)]
+            virtio_mem: None,
             virtio_mice: Vec::new(),
             virtio_multi_touch: Vec::new(),
             virtio_single_touch: Vec::new(),
@@ -1439,9 +1509,13 @@ impl Default for Config {
             vm_evt_rdtube: None,
             #[cfg(windows)]
             vm_evt_wrtube: None,
+            #[cfg(all(unix, feature = "gpu"))]
+            vnc_display: None,
             #[cfg(all(feature = "vtpm", target_arch = "x86_64"))]
             vtpm_proxy: false,
             vvu_proxy: Vec::new(),
+            #[cfg(unix)]
+            vsock_uds: None,
             wayland_socket_paths: BTreeMap::new(),
             x_display: None,
         }
@@ -1461,9 +1535,13 @@ pub fn validate_config(cfg: &mut Config) -> std::result::Result<(), String> {
     {
         crate::crosvm::gpu_config::validate_gpu_config(cfg)?;
     }
-    #[cfg(feature = "gdb")]
-    if cfg.gdb.is_some() && cfg.vcpu_count.unwrap_or(1) != 1 {
-        return Err("`gdb` requires the number of vCPU to be 1".to_string());
+    #[cfg(unix)]
+    if cfg.incoming.is_some() && cfg.restore_path.is_some() {
+        return Err("`incoming` and `restore` are mutually exclusive".to_string());
+    }
+    #[cfg(unix)]
+    if cfg.vsock_uds.is_some() && cfg.cid.is_none() {
+        return Err("`vsock-uds` requires `cid`".to_string());
     }
     if cfg.host_cpu_topology {
         if cfg.no_smt {
@@ -1595,6 +1673,10 @@ pub fn validate_config(cfg: &mut Config) -> std::result::Result<(), String> {
         validate_file_backed_mapping(mapping)?;
     }
 
+    if !cfg.numa_nodes.is_empty() {
+        validate_numa_nodes(cfg)?;
+    }
+
     // Validate platform specific things
     super::sys::config::validate_config(cfg)
 }
@@ -1617,6 +1699,81 @@ fn validate_file_backed_mapping(mapping: &mut FileBackedMappingParameters) -> Re
     Ok(())
 }
 
+fn validate_numa_nodes(cfg: &mut Config) -> Result<(), String> {
+    cfg.numa_nodes.sort_by_key(|node| node.node);
+    let num_nodes = cfg.numa_nodes.len();
+
+    for (index, node) in cfg.numa_nodes.iter().enumerate() {
+        if node.node as usize != index {
+            return Err(format!(
+                "--numa node IDs must be unique and contiguous from 0, found node {}",
+                node.node
+            ));
+        }
+        if !node.distances.is_empty() {
+            if node.distances.len() != num_nodes {
+                return Err(format!(
+                    "--numa node {} must give one distance for each of the {} nodes",
+                    node.node, num_nodes
+                ));
+            }
+            for (to, &distance) in node.distances.iter().enumerate() {
+                if (to == index) != (distance == arch::numa::LOCAL_DISTANCE)
+                    || distance < arch::numa::LOCAL_DISTANCE
+                {
+                    return Err(format!(
+                        "--numa node {} has invalid distance {} to node {}",
+                        node.node, distance, to
+                    ));
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        if node.host_node.is_some() {
+            return Err("--numa host-node is not supported on this platform".to_string());
+        }
+    }
+
+    let numa_memory: u64 = cfg.numa_nodes.iter().map(|node| node.mem).sum();
+    match cfg.memory {
+        None => cfg.memory = Some(numa_memory),
+        Some(memory) if memory != numa_memory => {
+            return Err(format!(
+                "--numa nodes have {} MiB of memory in total but the VM has {} MiB",
+                numa_memory, memory
+            ));
+        }
+        Some(_) => {}
+    }
+
+    let vcpu_count = cfg.vcpu_count.unwrap_or(1);
+    let mut vcpu_nodes = vec![None; vcpu_count];
+    for node in cfg.numa_nodes.iter() {
+        for &cpu in node.cpus.iter() {
+            match vcpu_nodes.get_mut(cpu) {
+                None => {
+                    return Err(format!(
+                        "--numa node {} has vCPU {} but the VM only has {} vCPUs",
+                        node.node, cpu, vcpu_count
+                    ));
+                }
+                Some(Some(other)) => {
+                    return Err(format!(
+                        "vCPU {} belongs to both --numa nodes {} and {}",
+                        cpu, other, node.node
+                    ));
+                }
+                Some(vcpu_node) => *vcpu_node = Some(node.node),
+            }
+        }
+    }
+    if let Some(cpu) = vcpu_nodes.iter().position(Option::is_none) {
+        return Err(format!("vCPU {} does not belong to any --numa node", cpu));
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use argh::FromArgs;
@@ -1855,6 +2012,18 @@ mod tests {
             .expect("parse should have succeded");
     }
 
+    #[test]
+    fn parse_serial_virtio_console_named() {
+        let parsed = parse_serial_options(
+            "type=file,path=/tmp/qga,hardware=virtio-console,num=2,name=org.qemu.guest_agent.0",
+        )
+        .expect("parse should have succeded");
+        assert_eq!(parsed.name.as_deref(), Some("org.qemu.guest_agent.0"));
+        parse_serial_options("type=syslog,name=port").expect_err("parse should have failed");
+        parse_serial_options("type=syslog,hardware=virtio-console,console,name=port")
+            .expect_err("parse should have failed");
+    }
+
     #[test]
     fn parse_serial_valid_no_num() {
         parse_serial_options("type=syslog").expect("parse should have succeded");
@@ -1915,6 +2084,129 @@ mod tests {
         .is_err())
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn vsock_uds_requires_cid() {
+        assert!(TryInto::<Config>::try_into(
+            crate::crosvm::cmdline::RunCommand::from_args(
+                &[],
+                &["--vsock-uds", "/run/vsock.sock", "/dev/null"]
+            )
+            .unwrap()
+        )
+        .is_err());
+
+        let cfg: Config = crate::crosvm::cmdline::RunCommand::from_args(
+            &[],
+            &["--cid", "3", "--vsock-uds", "/run/vsock.sock", "/dev/null"],
+        )
+        .unwrap()
+        .try_into()
+        .unwrap();
+        assert_eq!(cfg.vsock_uds, Some(PathBuf::from("/run/vsock.sock")));
+    }
+
+    #[test]
+    fn parse_fw_cfg() {
+        let cfg: Config = crate::crosvm::cmdline::RunCommand::from_args(
+            &[],
+            &[
+                "--fw-cfg",
+                "name=opt/org.example/foo,string=bar",
+                "--fw-cfg",
+                "name=opt/org.example/baz,path=/dev/null",
+                "--bios",
+                "/path/to/bios",
+                "/path/to/kernel",
+            ],
+        )
+        .unwrap()
+        .try_into()
+        .unwrap();
+        assert_eq!(
+            cfg.fw_cfg_parameters,
+            vec![
+                FwCfgParameters {
+                    name: "opt/org.example/foo".to_string(),
+                    path: None,
+                    string: Some("bar".to_string()),
+                },
+                FwCfgParameters {
+                    name: "opt/org.example/baz".to_string(),
+                    path: Some(PathBuf::from("/dev/null")),
+                    string: None,
+                },
+            ]
+        );
+        assert_eq!(
+            cfg.fw_cfg_kernel_path,
+            Some(PathBuf::from("/path/to/kernel"))
+        );
+
+        assert!(parse_fw_cfg_parameters("name=opt/foo").is_err());
+        assert!(parse_fw_cfg_parameters("name=opt/foo,string=a,path=/dev/null").is_err());
+        assert!(parse_fw_cfg_parameters("string=a").is_err());
+    }
+
+    #[test]
+    fn parse_numa_nodes() {
+        let cfg: Config = crate::crosvm::cmdline::RunCommand::from_args(
+            &[],
+            &[
+                "--cpus",
+                "4",
+                "--numa",
+                "node=1,cpus=[2-3],mem=1024,distances=[20,10]",
+                "--numa",
+                "node=0,cpus=[0,1],mem=512",
+                "/dev/null",
+            ],
+        )
+        .unwrap()
+        .try_into()
+        .unwrap();
+        assert_eq!(cfg.memory, Some(1536));
+        assert_eq!(cfg.numa_nodes.len(), 2);
+        assert_eq!(cfg.numa_nodes[0].node, 0);
+        assert_eq!(cfg.numa_nodes[1].cpus, CpuSet::new([2, 3]));
+    }
+
+    #[test]
+    fn parse_numa_nodes_invalid() {
+        let try_numa = |args: &[&str]| -> Result<Config, String> {
+            let mut args = args.to_vec();
+            args.push("/dev/null");
+            crate::crosvm::cmdline::RunCommand::from_args(&[], &args)
+                .unwrap()
+                .try_into()
+        };
+
+        // Memory of the nodes does not match the VM memory.
+        assert!(try_numa(&["--mem", "1024", "--numa", "node=0,cpus=[0],mem=512"]).is_err());
+        // Node IDs are not contiguous.
+        assert!(try_numa(&[
+            "--cpus",
+            "2",
+            "--numa",
+            "node=0,cpus=[0],mem=512",
+            "--numa",
+            "node=2,cpus=[1],mem=512",
+        ])
+        .is_err());
+        // vCPU 1 belongs to no node.
+        assert!(try_numa(&["--cpus", "2", "--numa", "node=0,cpus=[0],mem=512"]).is_err());
+        // vCPU 0 belongs to two nodes.
+        assert!(try_numa(&[
+            "--numa",
+            "node=0,cpus=[0],mem=512",
+            "--numa",
+            "node=1,cpus=[0],mem=512",
+        ])
+        .is_err());
+        // Distance to itself is not 10.
+        assert!(try_numa(&["--numa", "node=0,cpus=[0],mem=512,distances=[20]"]).is_err());
+    }
+
     #[test]
     fn parse_plugin_mount_invalid() {
         "".parse::<BindMount>().expect_err("parse should fail");
@@ -2,6 +2,8 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
 use std::net::TcpListener;
 use std::sync::mpsc;
 use std::time::Duration;
@@ -12,24 +14,30 @@ use base::Tube;
 use base::TubeError;
 use gdbstub::arch::Arch;
 use gdbstub::common::Signal;
+use gdbstub::common::Tid;
 use gdbstub::conn::Connection;
 use gdbstub::conn::ConnectionExt;
 use gdbstub::stub::run_blocking;
 use gdbstub::stub::run_blocking::BlockingEventLoop;
-use gdbstub::stub::SingleThreadStopReason;
+use gdbstub::stub::MultiThreadStopReason;
+use gdbstub::target::ext::base::multithread::MultiThreadBase;
+use gdbstub::target::ext::base::multithread::MultiThreadResume;
+use gdbstub::target::ext::base::multithread::MultiThreadResumeOps;
+use gdbstub::target::ext::base::multithread::MultiThreadSingleStep;
+use gdbstub::target::ext::base::multithread::MultiThreadSingleStepOps;
 use gdbstub::target::ext::base::single_register_access::SingleRegisterAccess;
 #[cfg(target_arch = "aarch64")]
 use gdbstub::target::ext::base::single_register_access::SingleRegisterAccessOps;
-use gdbstub::target::ext::base::singlethread::SingleThreadBase;
-use gdbstub::target::ext::base::singlethread::SingleThreadResume;
-use gdbstub::target::ext::base::singlethread::SingleThreadResumeOps;
-use gdbstub::target::ext::base::singlethread::SingleThreadSingleStep;
-use gdbstub::target::ext::base::singlethread::SingleThreadSingleStepOps;
 use gdbstub::target::ext::base::BaseOps;
 use gdbstub::target::ext::breakpoints::Breakpoints;
 use gdbstub::target::ext::breakpoints::BreakpointsOps;
 use gdbstub::target::ext::breakpoints::HwBreakpoint;
 use gdbstub::target::ext::breakpoints::HwBreakpointOps;
+use gdbstub::target::ext::breakpoints::HwWatchpoint;
+use gdbstub::target::ext::breakpoints::HwWatchpointOps;
+use gdbstub::target::ext::breakpoints::SwBreakpoint;
+use gdbstub::target::ext::breakpoints::SwBreakpointOps;
+use gdbstub::target::ext::breakpoints::WatchKind;
 use gdbstub::target::Target;
 use gdbstub::target::TargetError::NonFatal;
 use gdbstub::target::TargetResult;
@@ -37,6 +45,9 @@ use gdbstub::target::TargetResult;
 use gdbstub_arch::aarch64::AArch64 as GdbArch;
 #[cfg(target_arch = "x86_64")]
 use gdbstub_arch::x86::X86_64_SSE as GdbArch;
+use hypervisor::GuestDebug;
+use hypervisor::Watchpoint;
+use hypervisor::WatchpointKind;
 use remain::sorted;
 use sync::Mutex;
 use thiserror::Error as ThisError;
@@ -46,6 +57,7 @@ use vm_control::VcpuDebugStatus;
 use vm_control::VcpuDebugStatusMessage;
 use vm_control::VmRequest;
 use vm_control::VmResponse;
+use vm_control::VmRunMode;
 use vm_memory::GuestAddress;
 
 pub fn gdb_thread(mut gdbstub: GdbStub, port: u32) {
@@ -81,6 +93,14 @@ pub fn gdb_thread(mut gdbstub: GdbStub, port: u32) {
     }
 
     // Resume the VM when GDB session is disconnected.
+    for cpu in 0..gdbstub.vcpu_com.len() {
+        if let Err(e) = gdbstub.hold_vcpu(cpu, false) {
+            error!(
+                "Failed to release vCPU {} after GDB disconnected: {}",
+                cpu, e
+            );
+        }
+    }
     if let Err(e) = gdbstub.vm_request(VmRequest::Resume) {
         error!("Failed to resume the VM after GDB disconnected: {}", e);
     }
@@ -89,6 +109,9 @@ pub fn gdb_thread(mut gdbstub: GdbStub, port: u32) {
 #[sorted]
 #[derive(ThisError, Debug)]
 enum Error {
+    /// Got an unexpected vCPU response.
+    #[error("Got an unexpected vCPU response: {0:?}")]
+    UnexpectedVcpuResponse(VcpuDebugStatus),
     /// Got an unexpected VM response.
     #[error("Got an unexpected VM response: {0}")]
     UnexpectedVmResponse(VmResponse),
@@ -107,14 +130,70 @@ enum Error {
 }
 type GdbResult<T> = std::result::Result<T, Error>;
 
+/// Instruction written to guest memory for software breakpoints (INT3).
+#[cfg(target_arch = "x86_64")]
+const SW_BREAKPOINT_INSN: [u8; 1] = [0xcc];
+/// Instruction written to guest memory for software breakpoints (BRK #0).
+#[cfg(target_arch = "aarch64")]
+const SW_BREAKPOINT_INSN: [u8; 4] = 0xd420_0000u32.to_le_bytes();
+
+/// What a vCPU does on the next resume.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ResumeAction {
+    Continue,
+    Step,
+}
+
+// GDB thread IDs start at 1, so vCPU `n` is exposed as thread `n + 1`.
+fn cpu_to_tid(cpu: usize) -> Tid {
+    Tid::new(cpu + 1).expect("vCPU index overflow")
+}
+
+fn tid_to_cpu(tid: Tid) -> usize {
+    tid.get() - 1
+}
+
+#[cfg(target_arch = "x86_64")]
+fn program_counter(regs: &<GdbArch as Arch>::Registers) -> u64 {
+    regs.rip
+}
+
+#[cfg(target_arch = "aarch64")]
+fn program_counter(regs: &<GdbArch as Arch>::Registers) -> u64 {
+    regs.pc
+}
+
+fn to_watchpoint_kind(kind: WatchKind) -> WatchpointKind {
+    match kind {
+        WatchKind::Write => WatchpointKind::Write,
+        WatchKind::Read => WatchpointKind::Read,
+        WatchKind::ReadWrite => WatchpointKind::ReadWrite,
+    }
+}
+
+fn to_watch_kind(kind: WatchpointKind) -> WatchKind {
+    match kind {
+        WatchpointKind::Write => WatchKind::Write,
+        WatchpointKind::Read => WatchKind::Read,
+        WatchpointKind::ReadWrite => WatchKind::ReadWrite,
+    }
+}
+
 pub struct GdbStub {
     vm_tube: Mutex<Tube>,
     vcpu_com: Vec<mpsc::Sender<VcpuControl>>,
     from_vcpu: mpsc::Receiver<VcpuDebugStatusMessage>,
+    // Stop events of vCPUs that stopped along with the last reported one, reported one at a time
+    // on the following resumes.
+    pending_stops: Mutex<VecDeque<VcpuDebugStatusMessage>>,
 
-    single_step: bool,
+    resume_actions: BTreeMap<usize, ResumeAction>,
     max_hw_breakpoints: Option<usize>,
     hw_breakpoints: Vec<GuestAddress>,
+    max_hw_watchpoints: Option<usize>,
+    watchpoints: Vec<Watchpoint>,
+    // Original guest memory contents at the address of each software breakpoint.
+    sw_breakpoints: BTreeMap<u64, Vec<u8>>,
 }
 
 impl GdbStub {
@@ -127,19 +206,34 @@ impl GdbStub {
             vm_tube: Mutex::new(vm_tube),
             vcpu_com,
             from_vcpu,
-            single_step: false,
+            pending_stops: Mutex::new(VecDeque::new()),
+            resume_actions: BTreeMap::new(),
             max_hw_breakpoints: None,
             hw_breakpoints: Default::default(),
+            max_hw_watchpoints: None,
+            watchpoints: Default::default(),
+            sw_breakpoints: BTreeMap::new(),
         }
     }
 
-    fn vcpu_request(&self, request: VcpuControl) -> GdbResult<VcpuDebugStatus> {
-        // We use the only one vCPU when GDB is enabled.
-        self.vcpu_com[0].send(request).map_err(Error::VcpuRequest)?;
+    fn vcpu_request(&self, cpu: usize, request: VcpuControl) -> GdbResult<VcpuDebugStatus> {
+        self.vcpu_com[cpu]
+            .send(request)
+            .map_err(Error::VcpuRequest)?;
 
-        match self.from_vcpu.recv_timeout(Duration::from_millis(500)) {
-            Ok(msg) => Ok(msg.msg),
-            Err(e) => Err(Error::VcpuResponse(e)),
+        loop {
+            let msg = self
+                .from_vcpu
+                .recv_timeout(Duration::from_millis(500))
+                .map_err(Error::VcpuResponse)?;
+            match msg.msg {
+                // Another vCPU stopped while the target was being stopped.
+                VcpuDebugStatus::HitBreakPoint | VcpuDebugStatus::HitWatchPoint(_) => {
+                    self.pending_stops.lock().push_back(msg)
+                }
+                status if msg.cpu == cpu => return Ok(status),
+                status => error!("Unexpected response from vCPU {}: {:?}", msg.cpu, status),
+            }
         }
     }
 
@@ -154,7 +248,7 @@ impl GdbStub {
     }
 
     fn max_hw_breakpoints_request(&self) -> TargetResult<usize, Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::GetHwBreakPointCount)) {
+        match self.vcpu_request(0, VcpuControl::Debug(VcpuDebug::GetHwBreakPointCount)) {
             Ok(VcpuDebugStatus::HwBreakPointCount(n)) => Ok(n),
             Ok(s) => {
                 error!("Unexpected vCPU response for GetHwBreakPointCount: {:?}", s);
@@ -166,6 +260,183 @@ impl GdbStub {
             }
         }
     }
+
+    fn max_hw_watchpoints_request(&self) -> TargetResult<usize, Self> {
+        match self.vcpu_request(0, VcpuControl::Debug(VcpuDebug::GetHwWatchPointCount)) {
+            Ok(VcpuDebugStatus::HwWatchPointCount(n)) => Ok(n),
+            Ok(s) => {
+                error!("Unexpected vCPU response for GetHwWatchPointCount: {:?}", s);
+                Err(NonFatal)
+            }
+            Err(e) => {
+                error!("Failed to request GetHwWatchPointCount: {}", e);
+                Err(NonFatal)
+            }
+        }
+    }
+
+    fn set_guest_debug(&self, cpu: usize, single_step: bool) -> GdbResult<()> {
+        let debug = GuestDebug {
+            hw_breakpoints: self.hw_breakpoints.clone(),
+            watchpoints: self.watchpoints.clone(),
+            sw_breakpoints: !self.sw_breakpoints.is_empty(),
+            single_step,
+        };
+        match self.vcpu_request(cpu, VcpuControl::Debug(VcpuDebug::SetGuestDebug(debug)))? {
+            VcpuDebugStatus::CommandComplete => Ok(()),
+            s => Err(Error::UnexpectedVcpuResponse(s)),
+        }
+    }
+
+    /// Applies the current breakpoints and watchpoints to all the vCPUs.
+    fn update_guest_debug(&self) -> TargetResult<(), Self> {
+        for cpu in 0..self.vcpu_com.len() {
+            if let Err(e) = self.set_guest_debug(cpu, false) {
+                error!("Failed to request SetGuestDebug: {}", e);
+                return Err(NonFatal);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets whether `cpu` stays stopped when the VM resumes.
+    fn hold_vcpu(&self, cpu: usize, hold: bool) -> GdbResult<()> {
+        match self.vcpu_request(cpu, VcpuControl::Debug(VcpuDebug::Hold(hold)))? {
+            VcpuDebugStatus::CommandComplete => Ok(()),
+            s => Err(Error::UnexpectedVcpuResponse(s)),
+        }
+    }
+
+    fn read_pc(&self, cpu: usize) -> GdbResult<u64> {
+        match self.vcpu_request(cpu, VcpuControl::Debug(VcpuDebug::ReadRegs))? {
+            VcpuDebugStatus::RegValues(regs) => Ok(program_counter(&regs)),
+            s => Err(Error::UnexpectedVcpuResponse(s)),
+        }
+    }
+
+    /// Returns whether `cpu` stopped at `pc` on a breakpoint instruction placed by the guest
+    /// itself, which KVM traps along with those of GDB while software breakpoints are set.
+    fn is_guest_breakpoint(&self, cpu: usize, pc: u64) -> GdbResult<bool> {
+        if self.sw_breakpoints.contains_key(&pc) || self.hw_breakpoints.contains(&GuestAddress(pc))
+        {
+            return Ok(false);
+        }
+        Ok(self.read_memory(cpu, pc, SW_BREAKPOINT_INSN.len())? == SW_BREAKPOINT_INSN)
+    }
+
+    /// Passes the breakpoint exception that stopped `cpu` on to the guest, which handles it
+    /// when the vCPU runs again.
+    fn inject_breakpoint(&self, cpu: usize) -> GdbResult<()> {
+        match self.vcpu_request(cpu, VcpuControl::Debug(VcpuDebug::InjectBreakpoint))? {
+            VcpuDebugStatus::CommandComplete => Ok(()),
+            s => Err(Error::UnexpectedVcpuResponse(s)),
+        }
+    }
+
+    fn read_memory(&self, cpu: usize, addr: u64, len: usize) -> GdbResult<Vec<u8>> {
+        match self.vcpu_request(
+            cpu,
+            VcpuControl::Debug(VcpuDebug::ReadMem(GuestAddress(addr), len)),
+        )? {
+            VcpuDebugStatus::MemoryRegion(r) => Ok(r),
+            s => Err(Error::UnexpectedVcpuResponse(s)),
+        }
+    }
+
+    fn write_memory(&self, cpu: usize, addr: u64, data: &[u8]) -> GdbResult<()> {
+        match self.vcpu_request(
+            cpu,
+            VcpuControl::Debug(VcpuDebug::WriteMem(GuestAddress(addr), data.to_owned())),
+        )? {
+            VcpuDebugStatus::CommandComplete => Ok(()),
+            s => Err(Error::UnexpectedVcpuResponse(s)),
+        }
+    }
+
+    /// Stops the whole target after `cpu` reported `status` and tells GDB why it stopped.
+    ///
+    /// Returns `None` and lets `cpu` run again if it only hit a breakpoint instruction of the
+    /// guest, whose exception is passed on to the guest.
+    fn stop_reason(
+        &mut self,
+        cpu: usize,
+        status: VcpuDebugStatus,
+    ) -> GdbResult<Option<MultiThreadStopReason<<GdbArch as Arch>::Usize>>> {
+        let tid = cpu_to_tid(cpu);
+        let action = self.resume_actions.get(&cpu).copied();
+
+        let mut sw_break = false;
+        if matches!(status, VcpuDebugStatus::HitBreakPoint)
+            && action != Some(ResumeAction::Step)
+            && !self.sw_breakpoints.is_empty()
+        {
+            let pc = self.read_pc(cpu)?;
+            if self.is_guest_breakpoint(cpu, pc)? {
+                self.inject_breakpoint(cpu)?;
+                self.vcpu_com[cpu]
+                    .send(VcpuControl::RunState(VmRunMode::Running))
+                    .map_err(Error::VcpuRequest)?;
+                return Ok(None);
+            }
+            sw_break = self.sw_breakpoints.contains_key(&pc);
+        }
+
+        // GDB expects all the threads to be stopped when one of them stops.
+        self.vm_request(VmRequest::Suspend)?;
+        self.resume_actions.clear();
+
+        if let VcpuDebugStatus::HitWatchPoint(index) = status {
+            if let Some(watchpoint) = self.watchpoints.get(index) {
+                return Ok(Some(MultiThreadStopReason::Watch {
+                    tid,
+                    kind: to_watch_kind(watchpoint.kind),
+                    addr: watchpoint.addr.0,
+                }));
+            }
+        }
+        if action == Some(ResumeAction::Step) {
+            return Ok(Some(MultiThreadStopReason::SignalWithThread {
+                tid,
+                signal: Signal::SIGTRAP,
+            }));
+        }
+        if sw_break {
+            return Ok(Some(MultiThreadStopReason::SwBreak(tid)));
+        }
+        Ok(Some(MultiThreadStopReason::HwBreak(tid)))
+    }
+
+    /// Returns the stop reason of the first pending stop event that is still relevant, skipping
+    /// those whose breakpoint or watchpoint was removed since and passing those of breakpoint
+    /// instructions of the guest on to it.
+    fn pending_stop_reason(
+        &mut self,
+    ) -> GdbResult<Option<MultiThreadStopReason<<GdbArch as Arch>::Usize>>> {
+        loop {
+            // The lock must be released before sending vCPU requests, which may queue more events.
+            let msg = match self.pending_stops.lock().pop_front() {
+                Some(msg) => msg,
+                None => return Ok(None),
+            };
+            let relevant = match msg.msg {
+                VcpuDebugStatus::HitWatchPoint(index) => index < self.watchpoints.len(),
+                _ => {
+                    let pc = self.read_pc(msg.cpu)?;
+                    if self.is_guest_breakpoint(msg.cpu, pc)? {
+                        // The target is stopped, so the guest handles it when the vCPU resumes.
+                        self.inject_breakpoint(msg.cpu)?;
+                        continue;
+                    }
+                    self.sw_breakpoints.contains_key(&pc)
+                        || self.hw_breakpoints.contains(&GuestAddress(pc))
+                }
+            };
+            if relevant {
+                return self.stop_reason(msg.cpu, msg.msg);
+            }
+            info!("Dropping a stale event of vCPU {}: {:?}", msg.cpu, msg.msg);
+        }
+    }
 }
 
 impl Target for GdbStub {
@@ -173,33 +444,22 @@ impl Target for GdbStub {
     type Error = &'static str;
 
     fn base_ops(&mut self) -> BaseOps<Self::Arch, Self::Error> {
-        BaseOps::SingleThread(self)
+        BaseOps::MultiThread(self)
     }
 
-    // TODO(keiichiw): sw_breakpoint, hw_watchpoint, extended_mode, monitor_cmd, section_offsets
+    // TODO(keiichiw): extended_mode, monitor_cmd, section_offsets
     fn support_breakpoints(&mut self) -> Option<BreakpointsOps<Self>> {
         Some(self)
     }
-
-    // TODO(crbug.com/1141812): Remove this override once proper software breakpoint
-    // support has been added.
-    //
-    // Overriding this method to return `true` allows GDB to implement software
-    // breakpoints by writing breakpoint instructions directly into the target's
-    // instruction stream. This will be redundant once proper software breakpoints
-    // have been implemented. See the trait method's docs for more information:
-    // https://docs.rs/gdbstub/0.6.1/gdbstub/target/trait.Target.html#method.guard_rail_implicit_sw_breakpoints
-    fn guard_rail_implicit_sw_breakpoints(&self) -> bool {
-        true
-    }
 }
 
-impl SingleThreadBase for GdbStub {
+impl MultiThreadBase for GdbStub {
     fn read_registers(
         &mut self,
         regs: &mut <Self::Arch as Arch>::Registers,
+        tid: Tid,
     ) -> TargetResult<(), Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::ReadRegs)) {
+        match self.vcpu_request(tid_to_cpu(tid), VcpuControl::Debug(VcpuDebug::ReadRegs)) {
             Ok(VcpuDebugStatus::RegValues(r)) => {
                 *regs = r;
                 Ok(())
@@ -218,10 +478,12 @@ impl SingleThreadBase for GdbStub {
     fn write_registers(
         &mut self,
         regs: &<Self::Arch as Arch>::Registers,
+        tid: Tid,
     ) -> TargetResult<(), Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::WriteRegs(Box::new(
-            regs.clone(),
-        )))) {
+        match self.vcpu_request(
+            tid_to_cpu(tid),
+            VcpuControl::Debug(VcpuDebug::WriteRegs(Box::new(regs.clone()))),
+        ) {
             Ok(VcpuDebugStatus::CommandComplete) => Ok(()),
             Ok(s) => {
                 error!("Unexpected vCPU response for WriteRegs: {:?}", s);
@@ -238,21 +500,15 @@ impl SingleThreadBase for GdbStub {
         &mut self,
         start_addr: <Self::Arch as Arch>::Usize,
         data: &mut [u8],
+        tid: Tid,
     ) -> TargetResult<(), Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::ReadMem(
-            GuestAddress(start_addr),
-            data.len(),
-        ))) {
-            Ok(VcpuDebugStatus::MemoryRegion(r)) => {
+        match self.read_memory(tid_to_cpu(tid), start_addr, data.len()) {
+            Ok(r) => {
                 for (dst, v) in data.iter_mut().zip(r.iter()) {
                     *dst = *v;
                 }
                 Ok(())
             }
-            Ok(s) => {
-                error!("Unexpected vCPU response for ReadMem: {:?}", s);
-                Err(NonFatal)
-            }
             Err(e) => {
                 error!("Failed to request ReadMem: {}", e);
                 Err(NonFatal)
@@ -264,81 +520,188 @@ impl SingleThreadBase for GdbStub {
         &mut self,
         start_addr: <Self::Arch as Arch>::Usize,
         data: &[u8],
+        tid: Tid,
     ) -> TargetResult<(), Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::WriteMem(
-            GuestAddress(start_addr),
-            data.to_owned(),
-        ))) {
-            Ok(VcpuDebugStatus::CommandComplete) => Ok(()),
-            Ok(s) => {
-                error!("Unexpected vCPU response for WriteMem: {:?}", s);
-                Err(NonFatal)
-            }
-            Err(e) => {
+        self.write_memory(tid_to_cpu(tid), start_addr, data)
+            .map_err(|e| {
                 error!("Failed to request WriteMem: {}", e);
-                Err(NonFatal)
-            }
+                NonFatal
+            })
+    }
+
+    fn list_active_threads(
+        &mut self,
+        thread_is_active: &mut dyn FnMut(Tid),
+    ) -> Result<(), Self::Error> {
+        for cpu in 0..self.vcpu_com.len() {
+            thread_is_active(cpu_to_tid(cpu));
         }
+        Ok(())
     }
 
     #[inline(always)]
-    fn support_resume(&mut self) -> Option<SingleThreadResumeOps<Self>> {
+    fn support_resume(&mut self) -> Option<MultiThreadResumeOps<Self>> {
         Some(self)
     }
 
     #[cfg(target_arch = "aarch64")]
     #[inline(always)]
-    fn support_single_register_access(&mut self) -> Option<SingleRegisterAccessOps<(), Self>> {
+    fn support_single_register_access(&mut self) -> Option<SingleRegisterAccessOps<Tid, Self>> {
         Some(self)
     }
 }
 
-impl SingleThreadResume for GdbStub {
-    fn resume(&mut self, _signal: Option<Signal>) -> Result<(), Self::Error> {
+impl MultiThreadResume for GdbStub {
+    fn resume(&mut self) -> Result<(), Self::Error> {
         // TODO: Handle any incoming signal.
 
+        // Keep the stop events of vCPUs that stopped along with the last reported one. The target
+        // stays stopped until they have all been reported by `wait_for_stop_reason`.
+        let mut pending_stops = self.pending_stops.lock();
+        for msg in self.from_vcpu.try_iter() {
+            match msg.msg {
+                VcpuDebugStatus::HitBreakPoint | VcpuDebugStatus::HitWatchPoint(_) => {
+                    pending_stops.push_back(msg)
+                }
+                status => error!("Unexpected VcpuDebugStatus: {:?}", status),
+            }
+        }
+        if !pending_stops.is_empty() {
+            return Ok(());
+        }
+        drop(pending_stops);
+
+        for (&cpu, &action) in &self.resume_actions {
+            self.set_guest_debug(cpu, action == ResumeAction::Step)
+                .map_err(|e| {
+                    error!("Failed to request SetGuestDebug: {}", e);
+                    "Failed to request SetGuestDebug"
+                })?;
+        }
+
+        // Threads without a resume action stay stopped.
+        for cpu in 0..self.vcpu_com.len() {
+            self.hold_vcpu(cpu, !self.resume_actions.contains_key(&cpu))
+                .map_err(|e| {
+                    error!("Failed to request Hold: {}", e);
+                    "Failed to request Hold"
+                })?;
+        }
+
+        // The main loop resumes the vCPUs and updates the run state of the VM.
         self.vm_request(VmRequest::Resume).map_err(|e| {
             error!("Failed to resume the target: {}", e);
             "Failed to resume the target"
         })
     }
 
+    fn clear_resume_actions(&mut self) -> Result<(), Self::Error> {
+        self.resume_actions.clear();
+        Ok(())
+    }
+
+    fn set_resume_action_continue(
+        &mut self,
+        tid: Tid,
+        _signal: Option<Signal>,
+    ) -> Result<(), Self::Error> {
+        self.resume_actions
+            .insert(tid_to_cpu(tid), ResumeAction::Continue);
+        Ok(())
+    }
+
     #[inline(always)]
-    fn support_single_step(&mut self) -> Option<SingleThreadSingleStepOps<'_, Self>> {
+    fn support_single_step(&mut self) -> Option<MultiThreadSingleStepOps<'_, Self>> {
         Some(self)
     }
 }
 
-impl SingleThreadSingleStep for GdbStub {
-    fn step(&mut self, _signal: Option<Signal>) -> Result<(), Self::Error> {
-        // TODO: Handle any incoming signal.
+impl MultiThreadSingleStep for GdbStub {
+    fn set_resume_action_step(
+        &mut self,
+        tid: Tid,
+        _signal: Option<Signal>,
+    ) -> Result<(), Self::Error> {
+        self.resume_actions
+            .insert(tid_to_cpu(tid), ResumeAction::Step);
+        Ok(())
+    }
+}
 
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::EnableSinglestep)) {
-            Ok(VcpuDebugStatus::CommandComplete) => {
-                self.single_step = true;
-            }
-            Ok(s) => {
-                error!("Unexpected vCPU response for EnableSinglestep: {:?}", s);
-                return Err("Unexpected vCPU response for EnableSinglestep");
-            }
+impl Breakpoints for GdbStub {
+    fn support_sw_breakpoint(&mut self) -> Option<SwBreakpointOps<Self>> {
+        Some(self)
+    }
+
+    fn support_hw_breakpoint(&mut self) -> Option<HwBreakpointOps<Self>> {
+        Some(self)
+    }
+
+    fn support_hw_watchpoint(&mut self) -> Option<HwWatchpointOps<Self>> {
+        Some(self)
+    }
+}
+
+impl SwBreakpoint for GdbStub {
+    /// Add a new software breakpoint by replacing the instruction at `addr` with a breakpoint
+    /// instruction.
+    /// Return `Ok(false)` if the operation could not be completed.
+    fn add_sw_breakpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        _kind: <Self::Arch as Arch>::BreakpointKind,
+    ) -> TargetResult<bool, Self> {
+        if self.sw_breakpoints.contains_key(&addr) {
+            return Ok(true);
+        }
+        let original = match self.read_memory(0, addr, SW_BREAKPOINT_INSN.len()) {
+            Ok(r) => r,
             Err(e) => {
-                error!("Failed to request EnableSinglestep: {}", e);
-                return Err("Failed to request EnableSinglestep");
+                error!("Failed to read the instruction at {:#x}: {}", addr, e);
+                return Ok(false);
             }
         };
+        self.sw_breakpoints.insert(addr, original);
 
-        self.vm_request(VmRequest::Resume).map_err(|e| {
-            error!("Failed to resume the target: {}", e);
-            "Failed to resume the target"
-        })?;
+        // Make the vCPUs exit on breakpoint instructions before planting the first one.
+        if self.sw_breakpoints.len() == 1 {
+            if let Err(e) = self.update_guest_debug() {
+                self.sw_breakpoints.remove(&addr);
+                return Err(e);
+            }
+        }
 
-        Ok(())
+        match self.write_memory(0, addr, &SW_BREAKPOINT_INSN) {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                error!("Failed to write a breakpoint at {:#x}: {}", addr, e);
+                self.sw_breakpoints.remove(&addr);
+                Ok(false)
+            }
+        }
     }
-}
 
-impl Breakpoints for GdbStub {
-    fn support_hw_breakpoint(&mut self) -> Option<HwBreakpointOps<Self>> {
-        Some(self)
+    /// Remove an existing software breakpoint by restoring the original instruction.
+    /// Return `Ok(false)` if the operation could not be completed.
+    fn remove_sw_breakpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        _kind: <Self::Arch as Arch>::BreakpointKind,
+    ) -> TargetResult<bool, Self> {
+        let original = match self.sw_breakpoints.get(&addr) {
+            Some(original) => original,
+            None => return Ok(false),
+        };
+        if let Err(e) = self.write_memory(0, addr, original) {
+            error!("Failed to restore the instruction at {:#x}: {}", addr, e);
+            return Ok(false);
+        }
+        self.sw_breakpoints.remove(&addr);
+
+        if self.sw_breakpoints.is_empty() {
+            self.update_guest_debug()?;
+        }
+        Ok(true)
     }
 }
 
@@ -362,19 +725,11 @@ impl HwBreakpoint for GdbStub {
         }
         self.hw_breakpoints.push(GuestAddress(addr));
 
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::SetHwBreakPoint(
-            self.hw_breakpoints.clone(),
-        ))) {
-            Ok(VcpuDebugStatus::CommandComplete) => Ok(true),
-            Ok(s) => {
-                error!("Unexpected vCPU response for SetHwBreakPoint: {:?}", s);
-                Err(NonFatal)
-            }
-            Err(e) => {
-                error!("Failed to request SetHwBreakPoint: {}", e);
-                Err(NonFatal)
-            }
+        if let Err(e) = self.update_guest_debug() {
+            self.hw_breakpoints.pop();
+            return Err(e);
         }
+        Ok(true)
     }
 
     /// Remove an existing hardware breakpoint.
@@ -386,30 +741,80 @@ impl HwBreakpoint for GdbStub {
     ) -> TargetResult<bool, Self> {
         self.hw_breakpoints.retain(|&b| b.0 != addr);
 
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::SetHwBreakPoint(
-            self.hw_breakpoints.clone(),
-        ))) {
-            Ok(VcpuDebugStatus::CommandComplete) => Ok(true),
-            Ok(s) => {
-                error!("Unexpected vCPU response for SetHwBreakPoint: {:?}", s);
-                Err(NonFatal)
-            }
-            Err(e) => {
-                error!("Failed to request SetHwBreakPoint: {}", e);
-                Err(NonFatal)
+        self.update_guest_debug()?;
+        Ok(true)
+    }
+}
+
+impl HwWatchpoint for GdbStub {
+    /// Add a new hardware watchpoint.
+    /// Return `Ok(false)` if the operation could not be completed.
+    fn add_hw_watchpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        len: <Self::Arch as Arch>::Usize,
+        kind: WatchKind,
+    ) -> TargetResult<bool, Self> {
+        let max_count = *(match &mut self.max_hw_watchpoints {
+            None => self
+                .max_hw_watchpoints
+                .insert(self.max_hw_watchpoints_request()?),
+            Some(c) => c,
+        });
+        if self.watchpoints.len() >= max_count {
+            error!("Not allowed to set more than {} HW watchpoints", max_count);
+            return Err(NonFatal);
+        }
+        self.watchpoints.push(Watchpoint {
+            addr: GuestAddress(addr),
+            len,
+            kind: to_watchpoint_kind(kind),
+        });
+
+        // The hypervisor rejects watchpoints it cannot express, e.g. with an unaligned address.
+        if let Err(e) = self.update_guest_debug() {
+            self.watchpoints.pop();
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Remove an existing hardware watchpoint.
+    /// Return `Ok(false)` if the operation could not be completed.
+    fn remove_hw_watchpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        len: <Self::Arch as Arch>::Usize,
+        kind: WatchKind,
+    ) -> TargetResult<bool, Self> {
+        let watchpoint = Watchpoint {
+            addr: GuestAddress(addr),
+            len,
+            kind: to_watchpoint_kind(kind),
+        };
+        match self.watchpoints.iter().position(|w| *w == watchpoint) {
+            Some(index) => {
+                self.watchpoints.remove(index);
             }
+            None => return Ok(false),
         }
+
+        self.update_guest_debug()?;
+        Ok(true)
     }
 }
 
-impl SingleRegisterAccess<()> for GdbStub {
+impl SingleRegisterAccess<Tid> for GdbStub {
     fn read_register(
         &mut self,
-        _tid: (),
+        tid: Tid,
         reg_id: <Self::Arch as Arch>::RegId,
         buf: &mut [u8],
     ) -> TargetResult<usize, Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::ReadReg(reg_id))) {
+        match self.vcpu_request(
+            tid_to_cpu(tid),
+            VcpuControl::Debug(VcpuDebug::ReadReg(reg_id)),
+        ) {
             Ok(VcpuDebugStatus::RegValue(r)) => {
                 if buf.len() != r.len() {
                     error!(
@@ -437,14 +842,14 @@ impl SingleRegisterAccess<()> for GdbStub {
 
     fn write_register(
         &mut self,
-        _tid: (),
+        tid: Tid,
         reg_id: <Self::Arch as Arch>::RegId,
         val: &[u8],
     ) -> TargetResult<(), Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::WriteReg(
-            reg_id,
-            val.to_owned(),
-        ))) {
+        match self.vcpu_request(
+            tid_to_cpu(tid),
+            VcpuControl::Debug(VcpuDebug::WriteReg(reg_id, val.to_owned())),
+        ) {
             Ok(VcpuDebugStatus::CommandComplete) => Ok(()),
             Ok(s) => {
                 error!("Unexpected vCPU response for WriteReg: {:?}", s);
@@ -463,7 +868,7 @@ struct GdbStubEventLoop;
 impl BlockingEventLoop for GdbStubEventLoop {
     type Target = GdbStub;
     type Connection = Box<dyn ConnectionExt<Error = std::io::Error>>;
-    type StopReason = SingleThreadStopReason<<GdbArch as Arch>::Usize>;
+    type StopReason = MultiThreadStopReason<<GdbArch as Arch>::Usize>;
 
     fn wait_for_stop_reason(
         target: &mut Self::Target,
@@ -475,6 +880,14 @@ impl BlockingEventLoop for GdbStubEventLoop {
             <Self::Connection as Connection>::Error,
         >,
     > {
+        let reason = target.pending_stop_reason().map_err(|e| {
+            error!("Failed to stop the target: {}", e);
+            run_blocking::WaitForStopReasonError::Target("Failed to stop the target")
+        })?;
+        if let Some(reason) = reason {
+            return Ok(run_blocking::Event::TargetStopped(reason));
+        }
+
         loop {
             // TODO(keiichiw): handle error?
             if let Ok(msg) = target
@@ -482,16 +895,16 @@ impl BlockingEventLoop for GdbStubEventLoop {
                 .recv_timeout(std::time::Duration::from_millis(100))
             {
                 match msg.msg {
-                    VcpuDebugStatus::HitBreakPoint => {
-                        if target.single_step {
-                            target.single_step = false;
-                            return Ok(run_blocking::Event::TargetStopped(
-                                SingleThreadStopReason::DoneStep,
-                            ));
-                        } else {
-                            return Ok(run_blocking::Event::TargetStopped(
-                                SingleThreadStopReason::HwBreak(()),
-                            ));
+                    status @ (VcpuDebugStatus::HitBreakPoint
+                    | VcpuDebugStatus::HitWatchPoint(_)) => {
+                        let reason = target.stop_reason(msg.cpu, status).map_err(|e| {
+                            error!("Failed to stop the target: {}", e);
+                            run_blocking::WaitForStopReasonError::Target(
+                                "Failed to stop the target",
+                            )
+                        })?;
+                        if let Some(reason) = reason {
+                            return Ok(run_blocking::Event::TargetStopped(reason));
                         }
                     }
                     status => {
@@ -522,7 +935,74 @@ impl BlockingEventLoop for GdbStubEventLoop {
             error!("Failed to suspend the target: {}", e);
             "Failed to suspend the target"
         })?;
+        target.resume_actions.clear();
+
+        Ok(Some(MultiThreadStopReason::Signal(Signal::SIGINT)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_program_counter(regs: &mut <GdbArch as Arch>::Registers, pc: u64) {
+        regs.rip = pc;
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn set_program_counter(regs: &mut <GdbArch as Arch>::Registers, pc: u64) {
+        regs.pc = pc;
+    }
+
+    #[test]
+    fn guest_breakpoint_is_injected() {
+        const GDB_BREAKPOINT: u64 = 0x1000;
+        const GUEST_BREAKPOINT: u64 = 0x2000;
+
+        let (vm_tube, _main_tube) = Tube::pair().unwrap();
+        let (to_vcpu, from_gdb) = mpsc::channel();
+        let (to_gdb, from_vcpu) = mpsc::channel();
+        let mut gdbstub = GdbStub::new(vm_tube, vec![to_vcpu], from_vcpu);
+        gdbstub
+            .sw_breakpoints
+            .insert(GDB_BREAKPOINT, SW_BREAKPOINT_INSN.to_vec());
+        gdbstub.resume_actions.insert(0, ResumeAction::Continue);
+
+        // vCPU 0 trapped on a breakpoint instruction that the guest placed itself.
+        let vcpu = std::thread::spawn(move || {
+            let mut injected = false;
+            loop {
+                let msg = match from_gdb.recv().unwrap() {
+                    VcpuControl::Debug(VcpuDebug::ReadRegs) => {
+                        let mut regs = Default::default();
+                        set_program_counter(&mut regs, GUEST_BREAKPOINT);
+                        VcpuDebugStatus::RegValues(regs)
+                    }
+                    VcpuControl::Debug(VcpuDebug::ReadMem(GuestAddress(GUEST_BREAKPOINT), len)) => {
+                        VcpuDebugStatus::MemoryRegion(SW_BREAKPOINT_INSN[..len].to_vec())
+                    }
+                    VcpuControl::Debug(VcpuDebug::InjectBreakpoint) => {
+                        injected = true;
+                        VcpuDebugStatus::CommandComplete
+                    }
+                    VcpuControl::RunState(VmRunMode::Running) => return injected,
+                    _ => panic!("unexpected vCPU request"),
+                };
+                to_gdb.send(VcpuDebugStatusMessage { cpu: 0, msg }).unwrap();
+            }
+        });
 
-        Ok(Some(SingleThreadStopReason::Signal(Signal::SIGINT)))
+        // The exception goes to the guest and the target keeps running: stopping it would send a
+        // request to the main loop, which isn't there to reply.
+        let reason = gdbstub
+            .stop_reason(0, VcpuDebugStatus::HitBreakPoint)
+            .unwrap();
+        assert!(reason.is_none());
+        assert!(vcpu.join().unwrap(), "the breakpoint wasn't injected");
+        assert_eq!(
+            gdbstub.resume_actions.get(&0),
+            Some(&ResumeAction::Continue)
+        );
     }
 }
@@ -10,7 +10,11 @@ mod device_helpers;
 #[cfg(feature = "gpu")]
 pub(crate) mod gpu;
 pub(crate) mod jail_helpers;
+mod migration;
+mod qmp;
+mod snapshot;
 mod vcpu;
+mod vm_metrics;
 
 use std::cmp::max;
 use std::cmp::Reverse;
@@ -32,7 +36,7 @@ use std::process;
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Barrier;
-#[cfg(feature = "balloon")]
+use std::thread::JoinHandle;
 use std::time::Duration;
 
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
@@ -141,6 +145,7 @@ use hypervisor::VmCap;
 use hypervisor::VmX86_64 as VmArch;
 use jail_helpers::*;
 use libc;
+use metrics::SystemMetricsSampler;
 use minijail::Minijail;
 use resources::AddressRange;
 use resources::Alloc;
@@ -152,6 +157,9 @@ use rutabaga_gfx::RutabagaGralloc;
 use swap::SwapController;
 use sync::Condvar;
 use sync::Mutex;
+use vm_control::qmp::QmpCommand;
+use vm_control::qmp::QmpEvent;
+use vm_control::qmp::ShutdownReason;
 use vm_control::*;
 use vm_memory::GuestAddress;
 use vm_memory::GuestMemory;
@@ -176,6 +184,7 @@ use crate::crosvm::gdb::gdb_thread;
 use crate::crosvm::gdb::GdbStub;
 use crate::crosvm::sys::cmdline::DevicesCommand;
 use crate::crosvm::sys::config::VfioType;
+use crate::crosvm::sys::unix::qmp::QmpServer;
 
 fn create_virtio_devices(
     cfg: &Config,
@@ -187,6 +196,7 @@ fn create_virtio_devices(
     #[cfg(feature = "balloon")] init_balloon_size: u64,
     disk_device_tubes: &mut Vec<Tube>,
     pmem_device_tubes: &mut Vec<Tube>,
+    virtio_mem_device_tube: Option<Tube>,
     fs_device_tubes: &mut Vec<Tube>,
     #[cfg(feature = "gpu")] gpu_control_tube: Tube,
     #[cfg(all(feature = "gpu", feature = "virgl_renderer_next"))] render_server_fd: Option<
@@ -333,12 +343,25 @@ fn create_virtio_devices(
     for (_, param) in cfg
         .serial_parameters
         .iter()
-        .filter(|(_k, v)| v.hardware == SerialHardware::VirtioConsole)
+        .filter(|(_k, v)| v.hardware == SerialHardware::VirtioConsole && v.name.is_none())
     {
         let dev = param.create_virtio_device_and_jail(cfg.protection_type, &cfg.jail_config)?;
         devs.push(dev);
     }
 
+    // Named virtio-console serial devices are the ports of a single multiport console.
+    let console_ports: Vec<_> = cfg
+        .serial_parameters
+        .values()
+        .filter(|v| v.hardware == SerialHardware::VirtioConsole && v.name.is_some())
+        .collect();
+    if !console_ports.is_empty() {
+        devs.push(
+            MultiportConsoleConfig::new(console_ports)
+                .create_virtio_device_and_jail(cfg.protection_type, &cfg.jail_config)?,
+        );
+    }
+
     for disk in &cfg.disks {
         let disk_config = DiskConfig::new(disk, Some(disk_device_tubes.remove(0)));
         devs.push(
@@ -370,6 +393,25 @@ fn create_virtio_devices(
         )?);
     }
 
+    if let Some(virtio_mem_device_tube) = virtio_mem_device_tube {
+        let virtio_mem = cfg.virtio_mem.as_ref().unwrap();
+        devs.push(create_virtio_mem_device(
+            cfg.protection_type,
+            &cfg.jail_config,
+            vm,
+            virtio_mem.requested_size * 1024 * 1024,
+            virtio_mem_device_tube,
+        )?);
+    }
+
+    if !cfg.scsi_disks.is_empty() {
+        devs.push(create_scsi_device(
+            cfg.protection_type,
+            &cfg.jail_config,
+            &cfg.scsi_disks,
+        )?);
+    }
+
     if cfg.rng {
         devs.push(create_rng_device(cfg.protection_type, &cfg.jail_config)?);
     }
@@ -394,6 +436,15 @@ fn create_virtio_devices(
         }
     }
 
+    #[cfg(feature = "swtpm")]
+    if let Some(swtpm) = &cfg.swtpm {
+        devs.push(create_swtpm_device(
+            cfg.protection_type,
+            &cfg.jail_config,
+            swtpm,
+        )?);
+    }
+
     for (idx, single_touch_spec) in cfg.virtio_single_touch.iter().enumerate() {
         devs.push(create_single_touch_device(
             cfg.protection_type,
@@ -521,6 +572,14 @@ fn create_virtio_devices(
                     *mac,
                 )?);
             }
+            #[cfg(feature = "slirp")]
+            NetParametersMode::Slirp { hostfwd, .. } => {
+                devs.push(create_slirp_net_device(
+                    cfg.protection_type,
+                    &cfg.jail_config,
+                    hostfwd,
+                )?);
+            }
         }
     }
 
@@ -626,15 +685,24 @@ fn create_virtio_devices(
     }
 
     if let Some(cid) = cfg.cid {
-        let vhost_config = VhostVsockConfig {
-            device: cfg.vhost_vsock_device.clone(),
-            cid,
-        };
-        devs.push(create_vhost_vsock_device(
-            cfg.protection_type,
-            &cfg.jail_config,
-            &vhost_config,
-        )?);
+        if let Some(uds_path) = &cfg.vsock_uds {
+            devs.push(create_vsock_device(
+                cfg.protection_type,
+                &cfg.jail_config,
+                cid,
+                uds_path,
+            )?);
+        } else {
+            let vhost_config = VhostVsockConfig {
+                device: cfg.vhost_vsock_device.clone(),
+                cid,
+            };
+            devs.push(create_vhost_vsock_device(
+                cfg.protection_type,
+                &cfg.jail_config,
+                &vhost_config,
+            )?);
+        }
     }
 
     for vhost_user_fs in &cfg.vhost_user_fs {
@@ -719,6 +787,7 @@ fn create_devices(
     #[cfg(feature = "balloon")] init_balloon_size: u64,
     disk_device_tubes: &mut Vec<Tube>,
     pmem_device_tubes: &mut Vec<Tube>,
+    virtio_mem_device_tube: Option<Tube>,
     fs_device_tubes: &mut Vec<Tube>,
     #[cfg(feature = "usb")] usb_provider: HostBackendDeviceProvider,
     #[cfg(feature = "gpu")] gpu_control_tube: Tube,
@@ -870,6 +939,7 @@ fn create_devices(
         init_balloon_size,
         disk_device_tubes,
         pmem_device_tubes,
+        virtio_mem_device_tube,
         fs_device_tubes,
         #[cfg(feature = "gpu")]
         gpu_control_tube,
@@ -898,7 +968,7 @@ fn create_devices(
                     None
                 };
 
-                let dev = VirtioPciDevice::new(
+                let mut dev = VirtioPciDevice::new(
                     vm.get_memory().clone(),
                     stub.dev,
                     msi_device_tube,
@@ -906,6 +976,9 @@ fn create_devices(
                     shared_memory_tube,
                 )
                 .context("failed to create virtio pci dev")?;
+                if cfg.isolate_virtio_devices {
+                    dev.set_access_platform();
+                }
 
                 devices.push((Box::new(dev) as Box<dyn BusDeviceObj>, stub.jail));
             }
@@ -1165,6 +1238,16 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
         _ => panic!("Did not receive a bios or kernel, should be impossible."),
     };
 
+    let fw_cfg_kernel_image = if let Some(kernel_path) = &cfg.fw_cfg_kernel_path {
+        Some(
+            open_file(kernel_path, OpenOptions::new().read(true)).with_context(|| {
+                format!("failed to open kernel image {}", kernel_path.display())
+            })?,
+        )
+    } else {
+        None
+    };
+
     let swiotlb = if let Some(size) = cfg.swiotlb {
         Some(
             size.checked_mul(1024 * 1024)
@@ -1210,6 +1293,7 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
         #[cfg(feature = "direct")]
         direct_fixed_evts: cfg.direct_fixed_evts.clone(),
         no_smt: cfg.no_smt,
+        numa_nodes: cfg.numa_nodes.clone(),
         hugepages: cfg.hugepages,
         hv_cfg: hypervisor::Config {
             #[cfg(target_arch = "aarch64")]
@@ -1229,6 +1313,8 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
         pflash_block_size,
         pflash_image,
         initrd_image,
+        fw_cfg_kernel_image,
+        fw_cfg_parameters: cfg.fw_cfg_parameters.clone(),
         extra_kernel_params: cfg.params.clone(),
         acpi_sdts: cfg
             .acpi_tables
@@ -1310,6 +1396,22 @@ fn punch_holes_in_guest_mem_layout_for_mappings(
         .collect()
 }
 
+// Returns the guest memory range of the virtio-mem device, which is part of guest memory but placed
+// above the RAM, the file-backed mappings and the 32-bit MMIO hole.
+fn virtio_mem_range(
+    guest_mem_layout: &[(GuestAddress, u64)],
+    file_backed_mappings: &[FileBackedMappingParameters],
+    size: u64,
+) -> (GuestAddress, u64) {
+    let end = guest_mem_layout
+        .iter()
+        .map(|(addr, size)| addr.offset() + size)
+        .chain(file_backed_mappings.iter().map(|m| m.address + m.size))
+        .fold(1 << 32, u64::max);
+    let align = virtio::VIRTIO_MEM_REGION_ALIGN;
+    (GuestAddress((end + align - 1) / align * align), size)
+}
+
 fn run_kvm(
     cfg: Config,
     components: VmComponents,
@@ -1405,6 +1507,13 @@ fn get_default_hypervisor() -> Result<HypervisorKind> {
 }
 
 pub fn run_config(cfg: Config) -> Result<ExitState> {
+    // Device processes are forked later on and inherit the trace output.
+    #[cfg(feature = "trace_json")]
+    if let Some(trace_output) = &cfg.trace_output {
+        cros_tracing::init_with_output(trace_output)
+            .with_context(|| format!("failed to open trace output {}", trace_output.display()))?;
+    }
+
     if let Some(async_executor) = cfg.async_executor {
         Executor::set_default_executor_kind(async_executor)
             .context("Failed to set the default async executor")?;
@@ -1418,7 +1527,30 @@ pub fn run_config(cfg: Config) -> Result<ExitState> {
     let guest_mem_layout =
         punch_holes_in_guest_mem_layout_for_mappings(guest_mem_layout, &cfg.file_backed_mappings);
 
-    let guest_mem = GuestMemory::new(&guest_mem_layout).context("failed to create guest memory")?;
+    let guest_mem = match &cfg.virtio_mem {
+        Some(virtio_mem) => GuestMemory::new_with_hotplug(
+            &guest_mem_layout,
+            virtio_mem_range(
+                &guest_mem_layout,
+                &cfg.file_backed_mappings,
+                virtio_mem.size * 1024 * 1024,
+            ),
+        ),
+        None => GuestMemory::new(&guest_mem_layout),
+    }
+    .context("failed to create guest memory")?;
+
+    // Bind the NUMA nodes to their host nodes before anything faults the guest memory in.
+    for range in
+        Arch::numa_memory_layout(&components).context("failed to create NUMA memory layout")?
+    {
+        if let Some(host_node) = components.numa_nodes[range.node as usize].host_node {
+            guest_mem
+                .bind_range_to_host_node(range.start, range.size, host_node)
+                .with_context(|| format!("failed to bind guest NUMA node {}", range.node))?;
+        }
+    }
+
     let mut mem_policy = MemoryPolicy::empty();
     if components.hugepages {
         mem_policy |= MemoryPolicy::USE_HUGEPAGES;
@@ -1492,6 +1624,10 @@ where
         )),
         None => None,
     };
+    let qmp_server = match &cfg.qmp_socket_path {
+        Some(path) => Some(QmpServer::bind(path)?),
+        None => None,
+    };
 
     let mut control_tubes = Vec::new();
 
@@ -1549,6 +1685,13 @@ where
         control_tubes.push(TaggedControlTube::VmMsync(pmem_host_tube));
     }
 
+    let (virtio_mem_host_tube, virtio_mem_device_tube) = if cfg.virtio_mem.is_some() {
+        let (host, device) = Tube::pair().context("failed to create tube")?;
+        (Some(host), Some(device))
+    } else {
+        (None, None)
+    };
+
     if let Some(ioapic_host_tube) = ioapic_host_tube {
         control_tubes.push(TaggedControlTube::VmIrq(ioapic_host_tube));
     }
@@ -1735,6 +1878,7 @@ where
         init_balloon_size,
         &mut disk_device_tubes,
         &mut pmem_device_tubes,
+        virtio_mem_device_tube,
         &mut fs_device_tubes,
         #[cfg(feature = "usb")]
         usb_provider,
@@ -1910,9 +2054,11 @@ where
         sys_allocator,
         cfg,
         control_server_socket,
+        qmp_server,
         control_tubes,
         #[cfg(feature = "balloon")]
         balloon_host_tube,
+        virtio_mem_host_tube,
         &disk_host_tubes,
         #[cfg(feature = "gpu")]
         gpu_control_host_tube,
@@ -2324,13 +2470,75 @@ fn handle_hotplug_command<V: VmArch, Vcpu: VcpuArch>(
     }
 }
 
+fn handle_virtio_mem_command(
+    cfg: &Config,
+    virtio_mem_host_tube: Option<&Tube>,
+    command: &VirtioMemControlCommand,
+) -> VmResponse {
+    let (tube, virtio_mem) = match (virtio_mem_host_tube, &cfg.virtio_mem) {
+        (Some(tube), Some(virtio_mem)) => (tube, virtio_mem),
+        _ => return VmResponse::Err(base::Error::new(libc::ENODEV)),
+    };
+
+    let VirtioMemControlCommand::Resize { requested_size } = *command;
+    if requested_size > virtio_mem.size * 1024 * 1024
+        || requested_size % virtio::VIRTIO_MEM_BLOCK_SIZE != 0
+    {
+        return VmResponse::Err(base::Error::new(libc::EINVAL));
+    }
+
+    // The guest plugs or unplugs blocks asynchronously, so don't wait for it.
+    match tube.send(command) {
+        Ok(()) => VmResponse::Ok,
+        Err(e) => {
+            error!("failed to send virtio-mem command: {}", e);
+            VmResponse::Err(base::Error::new(libc::EIO))
+        }
+    }
+}
+
+// Switches the VM to `run_mode` as requested through the control socket or QMP: notifies the QMP
+// clients and the devices, and kicks the vCPUs if `kick_vcpus` is true. Returns true if the VM
+// should exit.
+fn change_run_mode<V: VmArch, Vcpu: VcpuArch>(
+    linux: &RunnableLinuxVm<V, Vcpu>,
+    vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<VcpuControl>)],
+    qmp_server: Option<&mut QmpServer>,
+    vm_run_mode: &mut VmRunMode,
+    run_mode: VmRunMode,
+    kick_vcpus: bool,
+) -> bool {
+    if let Some(qmp_server) = qmp_server {
+        qmp_server.send_event(&QmpEvent::from_run_mode(&run_mode));
+    }
+    *vm_run_mode = run_mode.clone();
+    if run_mode == VmRunMode::Exiting {
+        return true;
+    }
+    if run_mode == VmRunMode::Running {
+        for dev in &linux.resume_notify_devices {
+            dev.lock().resume_imminent();
+        }
+    }
+    if kick_vcpus {
+        vcpu::kick_all_vcpus(
+            vcpu_handles,
+            linux.irq_chip.as_irq_chip(),
+            VcpuControl::RunState(run_mode),
+        );
+    }
+    false
+}
+
 fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     mut linux: RunnableLinuxVm<V, Vcpu>,
     mut sys_allocator: SystemAllocator,
     cfg: Config,
     control_server_socket: Option<UnlinkUnixSeqpacketListener>,
+    mut qmp_server: Option<QmpServer>,
     mut control_tubes: Vec<TaggedControlTube>,
     #[cfg(feature = "balloon")] balloon_host_tube: Option<Tube>,
+    virtio_mem_host_tube: Option<Tube>,
     disk_host_tubes: &[Tube],
     #[cfg(feature = "gpu")] gpu_control_tube: Tube,
     #[cfg(feature = "usb")] usb_control_tube: Tube,
@@ -2354,6 +2562,9 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
         VmControlServer,
         VmControl { index: usize },
         DelayedIrqFd,
+        Metrics,
+        QmpServer,
+        QmpClient { id: usize },
     }
 
     let mut iommu_client = iommu_host_tube
@@ -2381,6 +2592,11 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
             .add(socket.as_ref(), Token::VmControl { index })
             .context("failed to add descriptor to wait context")?;
     }
+    if let Some(qmp_server) = &qmp_server {
+        wait_ctx
+            .add(qmp_server.listener(), Token::QmpServer)
+            .context("failed to add descriptor to wait context")?;
+    }
 
     let events = linux
         .irq_chip
@@ -2399,6 +2615,23 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
             .context("failed to add descriptor to wait context")?;
     }
 
+    let mut metrics_sampler = SystemMetricsSampler::new();
+    let mut latest_metrics = None;
+    let mut metrics_timer = match &cfg.metrics {
+        Some(metrics) => {
+            let mut timer = Timer::new().context("failed to create metrics timer")?;
+            let interval = Duration::from_secs(metrics.interval);
+            timer
+                .reset(interval, Some(interval))
+                .context("failed to arm metrics timer")?;
+            wait_ctx
+                .add(&timer, Token::Metrics)
+                .context("failed to add descriptor to wait context")?;
+            Some(timer)
+        }
+        None => None,
+    };
+
     if cfg.jail_config.is_some() {
         // Before starting VCPUs, in case we started with some capabilities, drop them all.
         drop_capabilities().context("failed to drop process capabilities")?;
@@ -2414,7 +2647,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     };
 
     let (device_ctrl_tube, device_ctrl_resp) = Tube::pair().context("failed to create tube")?;
-    // Create devices thread, and restore if a restore file exists.
+    // Create devices thread.
     linux.devices_thread = match create_devices_worker_thread(
         linux.io_bus.clone(),
         linux.mmio_bus.clone(),
@@ -2425,13 +2658,6 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
             return Err(anyhow!("Failed to start devices thread: {}", e));
         }
     };
-    if let Some(path) = cfg.restore_path.clone() {
-        if let Err(e) =
-            device_ctrl_tube.send(&DeviceControlCommand::RestoreDevices { restore_path: path })
-        {
-            error!("fail to send command to devices control socket: {}", e);
-        };
-    }
 
     let mut vcpu_handles = Vec::with_capacity(linux.vcpu_count);
     let vcpu_thread_barrier = Arc::new(Barrier::new(linux.vcpu_count + 1));
@@ -2537,6 +2763,13 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
             },
             cfg.userspace_msr.clone(),
             guest_suspended_cvar.clone(),
+            // When restoring a snapshot or receiving a migration, the VCPUs must not run before
+            // their state is loaded.
+            if cfg.restore_path.is_some() || cfg.incoming.is_some() {
+                VmRunMode::Suspending
+            } else {
+                VmRunMode::Running
+            },
         )?;
         vcpu_handles.push((handle, to_vcpu_channel));
     }
@@ -2561,8 +2794,29 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
 
     vcpu_thread_barrier.wait();
 
+    if let Some(path) = &cfg.restore_path {
+        snapshot::restore_snapshot(&mut linux, &vcpu_handles, &device_ctrl_tube, path)
+            .with_context(|| format!("failed to restore snapshot {}", path.display()))?;
+        vcpu::kick_all_vcpus(
+            &vcpu_handles,
+            linux.irq_chip.as_irq_chip(),
+            VcpuControl::RunState(VmRunMode::Running),
+        );
+    }
+
+    if let Some(uri) = &cfg.incoming {
+        migration::receive_migration(&mut linux, &vcpu_handles, &device_ctrl_tube, uri)
+            .with_context(|| format!("failed to receive migration on {}", uri))?;
+        vcpu::kick_all_vcpus(
+            &vcpu_handles,
+            linux.irq_chip.as_irq_chip(),
+            VcpuControl::RunState(VmRunMode::Running),
+        );
+    }
+
     let mut exit_state = ExitState::Stop;
     let mut pvpanic_code = PvPanicCode::Unknown;
+    let mut vm_run_mode = VmRunMode::Running;
     #[cfg(feature = "balloon")]
     let mut balloon_stats_id: u64 = 0;
 
@@ -2582,34 +2836,52 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
             match event.token {
                 Token::VmEvent => {
                     let mut break_to_wait: bool = true;
+                    let mut qmp_event = None;
                     match vm_evt_rdtube.recv::<VmEventType>() {
                         Ok(vm_event) => match vm_event {
                             VmEventType::Exit => {
                                 info!("vcpu requested shutdown");
                                 exit_state = ExitState::Stop;
+                                let reason = if pvpanic_code == PvPanicCode::Panicked {
+                                    ShutdownReason::GuestPanic
+                                } else {
+                                    ShutdownReason::GuestShutdown
+                                };
+                                qmp_event = Some(QmpEvent::Shutdown { reason });
                             }
                             VmEventType::Reset => {
                                 info!("vcpu requested reset");
                                 exit_state = ExitState::Reset;
+                                qmp_event = Some(QmpEvent::Reset);
                             }
                             VmEventType::Crash => {
                                 info!("vcpu crashed");
                                 exit_state = ExitState::Crash;
+                                qmp_event = Some(QmpEvent::Shutdown {
+                                    reason: ShutdownReason::HostError,
+                                });
                             }
                             VmEventType::Panic(panic_code) => {
                                 pvpanic_code = PvPanicCode::from_u8(panic_code);
                                 info!("Guest reported panic [Code: {}]", pvpanic_code);
                                 break_to_wait = false;
+                                if pvpanic_code == PvPanicCode::Panicked {
+                                    qmp_event = Some(QmpEvent::GuestPanicked);
+                                }
                             }
                             VmEventType::WatchdogReset => {
                                 info!("vcpu stall detected");
                                 exit_state = ExitState::WatchdogReset;
+                                qmp_event = Some(QmpEvent::Watchdog);
                             }
                         },
                         Err(e) => {
                             warn!("failed to recv VmEvent: {}", e);
                         }
                     }
+                    if let (Some(qmp_server), Some(event)) = (&mut qmp_server, &qmp_event) {
+                        qmp_server.send_event(event);
+                    }
                     if break_to_wait {
                         if pvpanic_code == PvPanicCode::Panicked {
                             exit_state = ExitState::GuestPanic;
@@ -2668,6 +2940,23 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                         warn!("can't deliver delayed irqs: {}", e);
                     }
                 }
+                Token::Metrics => {
+                    if let Some(timer) = &mut metrics_timer {
+                        if let Err(e) = timer.mark_waited() {
+                            warn!("failed to wait on metrics timer: {}", e);
+                        }
+                    }
+                    let snapshot = vm_metrics::sample_metrics(
+                        &mut metrics_sampler,
+                        &linux.pid_debug_label_map,
+                    );
+                    if let Some(path) = cfg.metrics.as_ref().and_then(|m| m.path.as_ref()) {
+                        if let Err(e) = vm_metrics::write_metrics_file(path, &snapshot) {
+                            warn!("failed to write metrics to {}: {}", path.display(), e);
+                        }
+                    }
+                    latest_metrics = Some(snapshot);
+                }
                 Token::VmControlServer => {
                     if let Some(socket_server) = &control_server_socket {
                         match socket_server.accept() {
@@ -2688,6 +2977,80 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                         }
                     }
                 }
+                Token::QmpServer => {
+                    if let Some(qmp_server) = &mut qmp_server {
+                        match qmp_server.accept() {
+                            Ok((id, stream)) => wait_ctx
+                                .add(stream, Token::QmpClient { id })
+                                .context("failed to add descriptor to wait context")?,
+                            Err(e) => error!("{:#}", e),
+                        }
+                    }
+                }
+                Token::QmpClient { id } => {
+                    if let Some(qmp_server) = &mut qmp_server {
+                        let commands = match qmp_server.read(id) {
+                            Some(commands) => commands,
+                            None => {
+                                if let Some(stream) = qmp_server.remove(id) {
+                                    wait_ctx
+                                        .delete(&stream)
+                                        .context("failed to remove descriptor from wait context")?;
+                                }
+                                continue;
+                            }
+                        };
+                        let memory_size = linux.vm.get_memory().memory_size();
+                        for (command, message_id) in commands {
+                            let mut run_mode_opt = None;
+                            let result = match command.vm_request(memory_size) {
+                                Some(request) => {
+                                    let response = request.execute(
+                                        &mut run_mode_opt,
+                                        #[cfg(feature = "balloon")]
+                                        balloon_host_tube.as_ref(),
+                                        #[cfg(feature = "balloon")]
+                                        &mut balloon_stats_id,
+                                        disk_host_tubes,
+                                        &mut linux.pm,
+                                        #[cfg(feature = "gpu")]
+                                        &gpu_control_tube,
+                                        #[cfg(feature = "usb")]
+                                        Some(&usb_control_tube),
+                                        #[cfg(not(feature = "usb"))]
+                                        None,
+                                        &mut linux.bat_control,
+                                        &vcpu_handles,
+                                        cfg.force_s2idle,
+                                        #[cfg(feature = "swap")]
+                                        swap_controller.as_ref(),
+                                        &device_ctrl_tube,
+                                    );
+                                    command.result(response, memory_size)
+                                }
+                                None => Ok(vm_control::qmp::query_status(&vm_run_mode)),
+                            };
+                            if result.is_ok() && command == QmpCommand::SystemPowerdown {
+                                qmp_server.send_event(&QmpEvent::Powerdown);
+                            }
+                            qmp_server.reply(id, message_id, result);
+
+                            if let Some(run_mode) = run_mode_opt {
+                                info!("QMP socket changed run mode to {}", run_mode);
+                                if change_run_mode(
+                                    &linux,
+                                    &vcpu_handles,
+                                    Some(&mut *qmp_server),
+                                    &mut vm_run_mode,
+                                    run_mode,
+                                    true,
+                                ) {
+                                    break 'wait;
+                                }
+                            }
+                        }
+                    }
+                }
                 Token::VmControl { index } => {
                     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
                     let mut add_tubes = Vec::new();
@@ -2726,6 +3089,117 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                                                 VmResponse::Ok
                                             }
                                         }
+                                        VmRequest::Snapshot(SnapshotCommand::Take {
+                                            ref snapshot_path,
+                                        }) => {
+                                            vcpu::kick_all_vcpus(
+                                                &vcpu_handles,
+                                                linux.irq_chip.as_irq_chip(),
+                                                VcpuControl::RunState(VmRunMode::Suspending),
+                                            );
+                                            let res = snapshot::take_snapshot(
+                                                &linux,
+                                                &vcpu_handles,
+                                                &device_ctrl_tube,
+                                                snapshot_path,
+                                            );
+                                            vcpu::kick_all_vcpus(
+                                                &vcpu_handles,
+                                                linux.irq_chip.as_irq_chip(),
+                                                VcpuControl::RunState(VmRunMode::Running),
+                                            );
+                                            match res {
+                                                Ok(()) => VmResponse::SnapshotResponse(
+                                                    SnapshotControlResult::Ok,
+                                                ),
+                                                Err(e) => {
+                                                    error!("failed to take snapshot: {:#}", e);
+                                                    VmResponse::SnapshotResponse(
+                                                        SnapshotControlResult::Failed(format!(
+                                                            "{:#}",
+                                                            e
+                                                        )),
+                                                    )
+                                                }
+                                            }
+                                        }
+                                        VmRequest::Restore(RestoreCommand::Apply {
+                                            ref restore_path,
+                                        }) => {
+                                            vcpu::kick_all_vcpus(
+                                                &vcpu_handles,
+                                                linux.irq_chip.as_irq_chip(),
+                                                VcpuControl::RunState(VmRunMode::Suspending),
+                                            );
+                                            let res = snapshot::restore_snapshot(
+                                                &mut linux,
+                                                &vcpu_handles,
+                                                &device_ctrl_tube,
+                                                restore_path,
+                                            );
+                                            vcpu::kick_all_vcpus(
+                                                &vcpu_handles,
+                                                linux.irq_chip.as_irq_chip(),
+                                                VcpuControl::RunState(VmRunMode::Running),
+                                            );
+                                            match res {
+                                                Ok(()) => VmResponse::RestoreResponse(
+                                                    RestoreControlResult::Ok,
+                                                ),
+                                                Err(e) => {
+                                                    error!("failed to restore snapshot: {:#}", e);
+                                                    VmResponse::RestoreResponse(
+                                                        RestoreControlResult::Failed(format!(
+                                                            "{:#}",
+                                                            e
+                                                        )),
+                                                    )
+                                                }
+                                            }
+                                        }
+                                        VmRequest::GetMetrics => {
+                                            // Without periodic sampling, sample on demand.
+                                            VmResponse::Metrics(match &latest_metrics {
+                                                Some(snapshot) => snapshot.clone(),
+                                                None => vm_metrics::sample_metrics(
+                                                    &mut metrics_sampler,
+                                                    &linux.pid_debug_label_map,
+                                                ),
+                                            })
+                                        }
+                                        VmRequest::VirtioMemCommand(ref command) => {
+                                            handle_virtio_mem_command(
+                                                &cfg,
+                                                virtio_mem_host_tube.as_ref(),
+                                                command,
+                                            )
+                                        }
+                                        VmRequest::Migrate(MigrateCommand::Send { ref uri }) => {
+                                            match migration::send_migration(
+                                                &mut linux,
+                                                &vcpu_handles,
+                                                &device_ctrl_tube,
+                                                uri,
+                                            ) {
+                                                Ok(()) => {
+                                                    info!("VM migrated to {}", uri);
+                                                    // The destination runs the VM from now on.
+                                                    run_mode_opt = Some(VmRunMode::Exiting);
+                                                    VmResponse::MigrateResponse(
+                                                        MigrateControlResult::Ok,
+                                                    )
+                                                }
+                                                Err(e) => {
+                                                    error!("failed to migrate VM: {:#}", e);
+                                                    VmResponse::MigrateResponse(
+                                                        MigrateControlResult::Failed(format!(
+                                                            "{:#}",
+                                                            e
+                                                        )),
+                                                    )
+                                                }
+                                            }
+                                        }
                                         _ => {
                                             let response = request.execute(
                                                 &mut run_mode_opt,
@@ -2795,27 +3269,17 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
 
                                     if let Some(run_mode) = run_mode_opt {
                                         info!("control socket changed run mode to {}", run_mode);
-                                        match run_mode {
-                                            VmRunMode::Exiting => {
-                                                break 'wait;
-                                            }
-                                            other => {
-                                                if other == VmRunMode::Running {
-                                                    for dev in &linux.resume_notify_devices {
-                                                        dev.lock().resume_imminent();
-                                                    }
-                                                }
-                                                // If suspend requested skip that step since it
-                                                // will be performed by s2idle_wait thread when
-                                                // needed.
-                                                if !suspend_requested {
-                                                    vcpu::kick_all_vcpus(
-                                                        &vcpu_handles,
-                                                        linux.irq_chip.as_irq_chip(),
-                                                        VcpuControl::RunState(other),
-                                                    );
-                                                }
-                                            }
+                                        // If suspend requested skip kicking the vCPUs since it
+                                        // will be performed by s2idle_wait thread when needed.
+                                        if change_run_mode(
+                                            &linux,
+                                            &vcpu_handles,
+                                            qmp_server.as_mut(),
+                                            &mut vm_run_mode,
+                                            run_mode,
+                                            !suspend_requested,
+                                        ) {
+                                            break 'wait;
                                         }
                                     }
                                 }
@@ -2973,8 +3437,16 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
         // tube should fail. On such failure, we get Disconnected error and index gets added to
         // vm_control_indices_to_remove by the time we reach here.
         for event in events.iter().filter(|e| e.is_hungup && !e.is_readable) {
-            if let Token::VmControl { index } = event.token {
-                vm_control_indices_to_remove.push(index);
+            match event.token {
+                Token::VmControl { index } => vm_control_indices_to_remove.push(index),
+                Token::QmpClient { id } => {
+                    if let Some(stream) = qmp_server.as_mut().and_then(|s| s.remove(id)) {
+                        wait_ctx
+                            .delete(&stream)
+                            .context("failed to remove descriptor from wait context")?;
+                    }
+                }
+                _ => {}
             }
         }
 
@@ -3059,6 +3531,11 @@ fn jail_and_start_vu_device<T: VirtioDeviceBuilder>(
     let mut keep_rds = Vec::new();
 
     base::syslog::push_descriptors(&mut keep_rds);
+    cros_tracing::push_descriptors(&mut keep_rds);
+    let trace_process = cros_tracing::ForkedProcess::new(name);
+    if let Some(trace_process) = &trace_process {
+        trace_process.push_descriptors(&mut keep_rds);
+    }
 
     // Create the device in the parent process, so the child does not need any privileges necessary
     // to do it (only runtime capabilities are required).
@@ -3110,6 +3587,10 @@ fn jail_and_start_vu_device<T: VirtioDeviceBuilder>(
             // Preserve TZ for `chrono::Local` (b/257987535).
             std::env::set_var("TZ", tz);
 
+            if let Some(trace_process) = trace_process {
+                trace_process.child();
+            }
+
             // Run the device loop and terminate the child process once it exits.
             let res = match listener.run_device(device) {
                 Ok(()) => 0,
@@ -3126,6 +3607,10 @@ fn jail_and_start_vu_device<T: VirtioDeviceBuilder>(
             // will keep living there. We just retain `parent_resources` for things we are supposed
             // to clean up ourselves.
 
+            if let Some(trace_process) = trace_process {
+                trace_process.parent(pid);
+            }
+
             info!("process for device {} (PID {}) started", &name, pid);
             Ok((pid, parent_resources))
         }
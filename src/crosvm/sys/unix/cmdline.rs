@@ -10,6 +10,7 @@ use devices::virtio::block::block::DiskOption;
 use devices::virtio::vhost::user::device;
 use devices::virtio::vhost::user::VhostUserParams;
 use devices::SerialParameters;
+use vm_control::MigrationUri;
 
 use crate::crosvm::config::from_key_values;
 use crate::crosvm::config::validate_serial_parameters;
@@ -108,10 +109,77 @@ pub struct DevicesCommand {
     pub control_socket: Option<PathBuf>,
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand, name = "send")]
+/// Send the VM to a crosvm instance started with `--incoming`
+pub struct MigrateSendCommand {
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+    #[argh(positional, arg_name = "URI")]
+    /// where the destination listens, `unix:PATH` or `tcp:HOST:PORT`
+    pub uri: MigrationUri,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum MigrateSubcommand {
+    Send(MigrateSendCommand),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "migrate")]
+/// Live-migrate the VM to another crosvm instance
+pub struct MigrateCommand {
+    #[argh(subcommand)]
+    pub command: MigrateSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "resize")]
+/// Change the amount of memory the guest plugs from the virtio-mem region
+pub struct MemResizeCommand {
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+    #[argh(positional, arg_name = "SIZE")]
+    /// amount of memory in MiB, a multiple of 2
+    pub size: u64,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum MemSubcommand {
+    Resize(MemResizeCommand),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "mem")]
+/// Control the virtio-mem device
+pub struct MemCommand {
+    #[argh(subcommand)]
+    pub command: MemSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "metrics")]
+/// Print the resource usage of the VM in the Prometheus text format
+pub struct MetricsCommand {
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand)]
 /// Unix Commands
 pub enum Commands {
     #[cfg(unix)]
     Devices(DevicesCommand),
+    #[cfg(unix)]
+    Mem(MemCommand),
+    #[cfg(unix)]
+    Metrics(MetricsCommand),
+    #[cfg(unix)]
+    Migrate(MigrateCommand),
 }
@@ -6,11 +6,15 @@ use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::str::FromStr;
 
+use devices::virtio::scsi::MAX_LUNS;
 use devices::IommuDevType;
 use devices::PciAddress;
 use devices::SerialParameters;
+#[cfg(feature = "swtpm")]
+use devices::TpmVersion;
 use serde::Deserialize;
 use serde::Serialize;
+use serde_keyvalue::FromKeyValues;
 
 use crate::crosvm::config::invalid_value_err;
 use crate::crosvm::config::Config;
@@ -68,6 +72,54 @@ pub fn validate_config(cfg: &mut Config) -> std::result::Result<(), String> {
         }
     }
 
+    if let Some(metrics) = &cfg.metrics {
+        if metrics.interval == 0 {
+            return Err("`metrics` interval must be at least 1 second".to_string());
+        }
+    }
+
+    if cfg.scsi_disks.len() > MAX_LUNS {
+        return Err(format!("at most {} `scsi-disk` can be given", MAX_LUNS));
+    }
+    if cfg.scsi_disks.iter().any(|d| d.root) {
+        return Err("`root` is not supported for `scsi-disk`".to_string());
+    }
+
+    #[cfg(feature = "audio")]
+    for snd in &mut cfg.virtio_snds {
+        use devices::virtio::snd::parameters::StreamSourceBackend;
+        use devices::virtio::snd::sys::StreamSourceBackend as SysStreamSourceBackend;
+
+        if snd.backend != StreamSourceBackend::Sys(SysStreamSourceBackend::FILE) {
+            continue;
+        }
+        // The directory is bind mounted at the same path in the jail of the device.
+        let wav_dir = snd
+            .wav_dir
+            .as_ref()
+            .ok_or("`virtio-snd` file backend needs `wav_dir`")?;
+        snd.wav_dir = Some(
+            std::fs::canonicalize(wav_dir)
+                .map_err(|e| format!("invalid `virtio-snd` wav_dir {:?}: {}", wav_dir, e))?,
+        );
+    }
+
+    if let Some(virtio_mem) = &cfg.virtio_mem {
+        if virtio_mem.size == 0 || virtio_mem.size % 128 != 0 {
+            return Err("`virtio-mem` size must be a non-zero multiple of 128 MiB".to_string());
+        }
+        if virtio_mem.requested_size > virtio_mem.size || virtio_mem.requested_size % 2 != 0 {
+            return Err(
+                "`virtio-mem` requested size must be a multiple of 2 MiB not larger than the size"
+                    .to_string(),
+            );
+        }
+        // The host can't take back the memory of unplugged blocks from a protected guest.
+        if cfg.protection_type.isolates_memory() {
+            return Err("`virtio-mem` is not supported with protected VMs".to_string());
+        }
+    }
+
     Ok(())
 }
 
@@ -91,6 +143,45 @@ impl FromStr for VfioType {
     }
 }
 
+fn default_metrics_interval() -> u64 {
+    10
+}
+
+/// Periodic sampling of the resource usage of the VM.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, FromKeyValues)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct MetricsOption {
+    /// File the samples are written to in the Prometheus text format, replaced after each sample.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Seconds between two samples.
+    #[serde(default = "default_metrics_interval")]
+    pub interval: u64,
+}
+
+/// Hotpluggable memory region of the virtio-mem device.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, FromKeyValues)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct VirtioMemOption {
+    /// Size of the region in MiB.
+    pub size: u64,
+    /// Memory in MiB the guest is asked to plug at boot.
+    #[serde(default)]
+    pub requested_size: u64,
+}
+
+/// swtpm process backing the virtio-tpm device.
+#[cfg(feature = "swtpm")]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, FromKeyValues)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SwtpmOption {
+    /// Control channel socket of swtpm.
+    pub path: PathBuf,
+    /// Version of the TPM emulated by swtpm.
+    #[serde(default)]
+    pub version: TpmVersion,
+}
+
 #[derive(Serialize, Deserialize)]
 /// VFIO device structure for creating a new instance based on command line options.
 pub struct VfioCommand {
@@ -444,6 +535,100 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_metrics() {
+        let config: Config = crate::crosvm::cmdline::RunCommand::from_args(
+            &[],
+            &["--metrics", "path=/run/crosvm.prom", "/dev/null"],
+        )
+        .unwrap()
+        .try_into()
+        .unwrap();
+        assert_eq!(
+            config.metrics,
+            Some(MetricsOption {
+                path: Some(PathBuf::from("/run/crosvm.prom")),
+                interval: 10,
+            })
+        );
+
+        let metrics: MetricsOption = from_key_values("interval=1").unwrap();
+        assert_eq!(
+            metrics,
+            MetricsOption {
+                path: None,
+                interval: 1,
+            }
+        );
+        assert!(from_key_values::<MetricsOption>("period=1").is_err());
+
+        let config: Result<Config, _> = crate::crosvm::cmdline::RunCommand::from_args(
+            &[],
+            &["--metrics", "interval=0", "/dev/null"],
+        )
+        .unwrap()
+        .try_into();
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn parse_virtio_mem() {
+        let config: Config = crate::crosvm::cmdline::RunCommand::from_args(
+            &[],
+            &["--virtio-mem", "size=1024,requested-size=512", "/dev/null"],
+        )
+        .unwrap()
+        .try_into()
+        .unwrap();
+        assert_eq!(
+            config.virtio_mem,
+            Some(VirtioMemOption {
+                size: 1024,
+                requested_size: 512,
+            })
+        );
+
+        let virtio_mem: VirtioMemOption = from_key_values("size=128").unwrap();
+        assert_eq!(virtio_mem.requested_size, 0);
+
+        for args in [
+            "size=100",
+            "size=128,requested-size=256",
+            "size=128,requested-size=3",
+        ] {
+            let config: Result<Config, _> = crate::crosvm::cmdline::RunCommand::from_args(
+                &[],
+                &["--virtio-mem", args, "/dev/null"],
+            )
+            .unwrap()
+            .try_into();
+            assert!(config.is_err(), "{} should be rejected", args);
+        }
+    }
+
+    #[cfg(feature = "swtpm")]
+    #[test]
+    fn parse_swtpm() {
+        let config: Config = crate::crosvm::cmdline::RunCommand::from_args(
+            &[],
+            &["--swtpm", "path=/run/swtpm.sock", "/dev/null"],
+        )
+        .unwrap()
+        .try_into()
+        .unwrap();
+        assert_eq!(
+            config.swtpm,
+            Some(SwtpmOption {
+                path: PathBuf::from("/run/swtpm.sock"),
+                version: TpmVersion::Tpm2,
+            })
+        );
+
+        let swtpm: SwtpmOption = from_key_values("path=/run/swtpm.sock,version=tpm12").unwrap();
+        assert_eq!(swtpm.version, TpmVersion::Tpm12);
+        assert!(from_key_values::<SwtpmOption>("path=/run/swtpm.sock,version=1.2").is_err());
+    }
+
     #[test]
     fn virtio_switches() {
         let mut config: Config = crate::crosvm::cmdline::RunCommand::from_args(
@@ -28,6 +28,8 @@ use devices::vfio::VfioCommonTrait;
 use devices::virtio;
 use devices::virtio::block::block::DiskOption;
 use devices::virtio::console::asynchronous::AsyncConsole;
+use devices::virtio::console::multiport::ConsolePort;
+use devices::virtio::console::multiport::MultiportConsole;
 #[cfg(any(feature = "video-decoder", feature = "video-encoder"))]
 use devices::virtio::device_constants::video::VideoBackendType;
 use devices::virtio::device_constants::video::VideoDeviceType;
@@ -51,6 +53,8 @@ use devices::PciAddress;
 use devices::PciDevice;
 #[cfg(feature = "tpm")]
 use devices::SoftwareTpm;
+#[cfg(feature = "swtpm")]
+use devices::Swtpm;
 use devices::VfioDevice;
 use devices::VfioPciDevice;
 use devices::VfioPlatformDevice;
@@ -59,6 +63,8 @@ use devices::VtpmProxy;
 use hypervisor::ProtectionType;
 use hypervisor::Vm;
 use minijail::Minijail;
+#[cfg(feature = "slirp")]
+use net_util::slirp::HostFwd;
 use net_util::sys::unix::Tap;
 use net_util::MacAddress;
 use resources::Alloc;
@@ -73,6 +79,8 @@ use crate::crosvm::config::TouchDeviceOption;
 use crate::crosvm::config::VhostUserFsOption;
 use crate::crosvm::config::VhostUserOption;
 use crate::crosvm::config::VvuOption;
+#[cfg(feature = "swtpm")]
+use crate::crosvm::sys::config::SwtpmOption;
 
 pub enum TaggedControlTube {
     Fs(Tube),
@@ -253,6 +261,7 @@ impl<'a> VirtioDeviceBuilder for DiskConfig<'a> {
                 None,
                 self.disk.async_executor,
                 None,
+                self.disk.throttle,
             )
             .context("failed to create block device")?,
         ))
@@ -277,6 +286,7 @@ impl<'a> VirtioDeviceBuilder for DiskConfig<'a> {
                 None,
                 disk.async_executor,
                 None,
+                disk.throttle,
             )
             .context("failed to create block device")?,
         );
@@ -439,35 +449,80 @@ pub fn create_rng_device(
     })
 }
 
+pub fn create_scsi_device(
+    protection_type: ProtectionType,
+    jail_config: &Option<JailConfig>,
+    disks: &[DiskOption],
+) -> DeviceResult {
+    let mut luns = Vec::with_capacity(disks.len());
+    for disk in disks {
+        let disk_image = disk.open()?;
+        let lun = virtio::scsi::LogicalUnit::new(
+            disk_image,
+            disk.read_only,
+            disk.sparse,
+            disk.block_size,
+        )
+        .with_context(|| format!("failed to add SCSI disk {}", disk.path.display()))?;
+        luns.push(lun);
+    }
+
+    let dev = virtio::scsi::Scsi::new(virtio::base_features(protection_type), luns);
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(jail_config, "scsi_device")?,
+    })
+}
+
 #[cfg(feature = "audio")]
 pub fn create_virtio_snd_device(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
     snd_params: SndParameters,
 ) -> DeviceResult {
-    let backend = snd_params.backend;
-    let dev = virtio::snd::common_backend::VirtioSnd::new(
-        virtio::base_features(protection_type),
-        snd_params,
-    )
-    .context("failed to create cras sound device")?;
-
     use virtio::snd::parameters::StreamSourceBackend as Backend;
+    use virtio::snd::sys::StreamSourceBackend as SysBackend;
 
+    let backend = snd_params.backend;
+    // Host paths the backend needs in its jail, along with whether they are writable.
+    let mut bind_mounts: Vec<(PathBuf, bool)> = Vec::new();
     let policy = match backend {
         Backend::NULL => "snd_null_device",
+        #[cfg(feature = "audio_alsa")]
+        Backend::Sys(SysBackend::ALSA) => {
+            // Only the kernel devices, `hw:` and `plughw:`, can be opened in the jail. Plugin
+            // libraries and sound server sockets are not mounted.
+            bind_mounts.push((PathBuf::from("/dev/snd"), true));
+            for path in ["/usr/share/alsa", "/etc/asound.conf"] {
+                if Path::new(path).exists() {
+                    bind_mounts.push((PathBuf::from(path), false));
+                }
+            }
+            "snd_alsa_device"
+        }
         #[cfg(feature = "audio_cras")]
-        Backend::Sys(virtio::snd::sys::StreamSourceBackend::CRAS) => "snd_cras_device",
-        #[cfg(not(feature = "audio_cras"))]
-        _ => unreachable!(),
+        Backend::Sys(SysBackend::CRAS) => {
+            bind_mounts.push((PathBuf::from("/run/cras"), true));
+            "snd_cras_device"
+        }
+        Backend::Sys(SysBackend::FILE) => {
+            bind_mounts.extend(snd_params.wav_dir.clone().map(|dir| (dir, true)));
+            "snd_file_device"
+        }
     };
 
+    let dev = virtio::snd::common_backend::VirtioSnd::new(
+        virtio::base_features(protection_type),
+        snd_params,
+    )
+    .context("failed to create sound device")?;
+
     let jail = match simple_jail(jail_config, policy)? {
         Some(mut jail) => {
-            // Create a tmpfs in the device's root directory for cras_snd_device.
-            // The size is 20*1024, or 20 KB.
-            #[cfg(feature = "audio_cras")]
-            if backend == Backend::Sys(virtio::snd::sys::StreamSourceBackend::CRAS) {
+            if !bind_mounts.is_empty() {
+                // Create a tmpfs in the device's root directory to bind mount the host paths into.
+                // The size is 20*1024, or 20 KB.
                 jail.mount_with_data(
                     Path::new("none"),
                     Path::new("/"),
@@ -475,9 +530,9 @@ pub fn create_virtio_snd_device(
                     (libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC) as usize,
                     "size=20480",
                 )?;
-
-                let run_cras_path = Path::new("/run/cras");
-                jail.mount_bind(run_cras_path, run_cras_path, true)?;
+                for (path, writable) in &bind_mounts {
+                    jail.mount_bind(path, path, *writable)?;
+                }
             }
 
             add_current_user_to_jail(&mut jail)?;
@@ -582,6 +637,22 @@ pub fn create_vtpm_proxy_device(
     })
 }
 
+#[cfg(feature = "swtpm")]
+pub fn create_swtpm_device(
+    protection_type: ProtectionType,
+    jail_config: &Option<JailConfig>,
+    swtpm: &SwtpmOption,
+) -> DeviceResult {
+    // The sockets connected to swtpm are kept by the device, so the jail needs no access to it.
+    let backend = Swtpm::new(&swtpm.path, swtpm.version).context("failed to create Swtpm")?;
+    let dev = virtio::Tpm::new(Box::new(backend), virtio::base_features(protection_type));
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(jail_config, "swtpm_device")?,
+    })
+}
+
 pub fn create_single_touch_device(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
@@ -888,6 +959,92 @@ pub fn create_tap_net_device_from_name(
     )
 }
 
+/// Returns a network device backed by libslirp user-mode networking, which needs no TAP interface
+/// or privileges. Each of `host_forwards` forwards a host port into the guest.
+///
+/// The libslirp loop runs in its own process, which is jailed like device processes except that it
+/// keeps access to the host's network. The device process only exchanges frames with it.
+#[cfg(feature = "slirp")]
+pub fn create_slirp_net_device(
+    protection_type: ProtectionType,
+    jail_config: &Option<JailConfig>,
+    host_forwards: &[HostFwd],
+) -> DeviceResult {
+    let (slirp, host_socket) =
+        net_util::Slirp::new_pair().context("failed to create slirp socket pair")?;
+    let jail = match network_jail(jail_config, "slirp")? {
+        Some(mut jail) => {
+            // Create a tmpfs in the jail's root directory so that the host's DNS configuration can
+            // be bind mounted into it. libslirp forwards the guest's DNS queries to the
+            // nameservers listed there.
+            jail.mount_with_data(
+                Path::new("none"),
+                Path::new("/"),
+                "tmpfs",
+                (libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC) as usize,
+                "size=1048576",
+            )?;
+            jail_mount_bind_if_exists(&mut jail, &["/etc/resolv.conf"])?;
+            jail
+        }
+        None => Minijail::new().context("failed to create slirp jail")?,
+    };
+    start_slirp_process(jail, host_socket, host_forwards)?;
+
+    let dev =
+        virtio::Net::<net_util::Slirp>::new_slirp(virtio::base_features(protection_type), slirp)
+            .context("failed to create slirp network device")?;
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(jail_config, "net_device")?,
+    })
+}
+
+/// Forks a process running the libslirp loop for `host_socket` in `jail`. Returns once libslirp is
+/// set up, so that a host port that is already in use is reported here.
+#[cfg(feature = "slirp")]
+fn start_slirp_process(
+    jail: Minijail,
+    host_socket: UnixSeqpacket,
+    host_forwards: &[HostFwd],
+) -> Result<()> {
+    let (ready_tube, child_ready_tube) = Tube::pair().context("failed to create slirp tube")?;
+    let mut keep_rds = vec![
+        host_socket.as_raw_descriptor(),
+        child_ready_tube.as_raw_descriptor(),
+    ];
+    syslog::push_descriptors(&mut keep_rds);
+    // Deduplicate the FDs since minijail expects this.
+    keep_rds.sort_unstable();
+    keep_rds.dedup();
+
+    // Forking here is safe as long as the program is still single threaded.
+    // We own the jail object and nobody else will try to reuse it.
+    match unsafe { jail.fork(Some(&keep_rds)) }.context("failed to fork slirp process")? {
+        0 => {
+            net_util::slirp::sys::unix::run_slirp_loop(host_socket, host_forwards, |res| {
+                let res = res.map_err(|e| e.to_string());
+                if let Err(e) = child_ready_tube.send(&res) {
+                    error!("failed to report the slirp loop status: {}", e);
+                }
+            });
+            // exit() is trivially safe.
+            // ! Never returns
+            unsafe { libc::exit(0) };
+        }
+        _ => {
+            drop(host_socket);
+            drop(child_ready_tube);
+            // The child process exits without an answer if it fails to start.
+            ready_tube
+                .recv::<std::result::Result<(), String>>()
+                .context("slirp process failed to start")?
+                .map_err(|e| anyhow!("failed to set up slirp: {}", e))
+        }
+    }
+}
+
 pub fn create_vhost_user_net_device(
     protection_type: ProtectionType,
     opt: &VhostUserOption,
@@ -1113,6 +1270,39 @@ pub fn create_vhost_vsock_device(
     })
 }
 
+pub fn create_vsock_device(
+    protection_type: ProtectionType,
+    jail_config: &Option<JailConfig>,
+    cid: u64,
+    uds_path: &Path,
+) -> DeviceResult {
+    let uds_dir = uds_path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .ok_or_else(|| anyhow!("vsock socket path has no parent directory"))?;
+
+    let features = virtio::base_features(protection_type);
+    let dev = virtio::Vsock::new(cid, uds_path, features)
+        .context("failed to set up virtual socket device")?;
+
+    let jail = match simple_jail(jail_config, "vsock_device")? {
+        Some(mut jail) => {
+            // Bind mount the socket directory into the jail so that guest connections can reach
+            // the per-port sockets created by host services after the device started.
+            jail.mount_bind(uds_dir, uds_dir, true)?;
+            add_current_user_to_jail(&mut jail)?;
+
+            Some(jail)
+        }
+        None => None,
+    };
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail,
+    })
+}
+
 pub fn create_fs_device(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
@@ -1135,6 +1325,7 @@ pub fn create_fs_device(
             uid_map: Some(uid_map),
             gid_map: Some(gid_map),
             log_failures: jail_config.seccomp_log_failures,
+            namespace_net: true,
             seccomp_policy_path: policy_path.as_deref(),
             seccomp_policy_name: "fs_device",
             // We want bind mounts from the parent namespaces to propagate into the fs device's
@@ -1179,6 +1370,7 @@ pub fn create_9p_device(
             uid_map: Some(uid_map),
             gid_map: Some(gid_map),
             log_failures: jail_config.seccomp_log_failures,
+            namespace_net: true,
             seccomp_policy_path: policy_path.as_deref(),
             seccomp_policy_name: "9p_device",
             // We want bind mounts from the parent namespaces to propagate into the 9p server's
@@ -1318,6 +1510,46 @@ pub fn create_pmem_device(
     })
 }
 
+pub fn create_virtio_mem_device(
+    protection_type: ProtectionType,
+    jail_config: &Option<JailConfig>,
+    vm: &impl Vm,
+    requested_size: u64,
+    virtio_mem_device_tube: Tube,
+) -> DeviceResult {
+    // The region is the hotplug range of guest memory, so that it is mapped into the VM and
+    // accessible to the other devices, snapshots and live migration like the rest of guest memory.
+    let mem = vm.get_memory();
+    let (region_address, size) = mem
+        .hotplug_range()
+        .context("guest memory has no range for virtio-mem")?;
+    let map_size = usize::try_from(size).context("virtio-mem region too big")?;
+    let device_mapping = MemoryMappingBuilder::new(map_size)
+        .from_descriptor(
+            mem.shm_region(region_address)
+                .context("failed to find virtio-mem memory")?,
+        )
+        .offset(
+            mem.offset_from_base(region_address)
+                .context("failed to find virtio-mem memory")?,
+        )
+        .build()
+        .context("failed to map virtio-mem memory")?;
+
+    let dev = virtio::VirtioMem::new(
+        virtio::base_features(protection_type),
+        region_address,
+        device_mapping,
+        requested_size,
+        virtio_mem_device_tube,
+    );
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev) as Box<dyn VirtioDevice>,
+        jail: simple_jail(jail_config, "virtio_mem_device")?,
+    })
+}
+
 pub fn create_iommu_device(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
@@ -1390,28 +1622,86 @@ impl VirtioDeviceBuilder for SerialParameters {
         jail_config: &Option<JailConfig>,
         jail_type: VirtioDeviceType,
     ) -> anyhow::Result<Option<Minijail>> {
-        let jail = match simple_jail(jail_config, &jail_type.seccomp_policy_file("serial"))? {
-            Some(mut jail) => {
-                // Create a tmpfs in the device's root directory so that we can bind mount the
-                // log socket directory into it.
-                // The size=67108864 is size=64*1024*1024 or size=64MB.
-                jail.mount_with_data(
-                    Path::new("none"),
-                    Path::new("/"),
-                    "tmpfs",
-                    (libc::MS_NODEV | libc::MS_NOEXEC | libc::MS_NOSUID) as usize,
-                    "size=67108864",
-                )?;
-                add_current_user_to_jail(&mut jail)?;
-                add_bind_mounts(self, &mut jail)
+        create_serial_jail(jail_config, jail_type, &[self])
+    }
+}
+
+/// A one-shot configuration structure for implementing `VirtioDeviceBuilder` on a multiport
+/// console, which exposes the named virtio-console serial devices as ports of a single device.
+pub struct MultiportConsoleConfig<'a> {
+    /// Parameters of the ports, in order of port ID.
+    ports: Vec<&'a SerialParameters>,
+}
+
+impl<'a> MultiportConsoleConfig<'a> {
+    pub fn new(ports: Vec<&'a SerialParameters>) -> Self {
+        Self { ports }
+    }
+}
+
+impl<'a> VirtioDeviceBuilder for MultiportConsoleConfig<'a> {
+    const NAME: &'static str = "serial";
+
+    fn create_virtio_device(
+        &self,
+        protection_type: ProtectionType,
+    ) -> anyhow::Result<Box<dyn VirtioDevice>> {
+        let mut keep_rds = Vec::new();
+        let evt = Event::new().context("failed to create event")?;
+
+        let mut ports = Vec::new();
+        for param in &self.ports {
+            let name = param.name.clone().unwrap_or_default();
+            let port = param
+                .create_serial_device::<ConsolePort>(protection_type, &evt, &mut keep_rds)
+                .with_context(|| format!("failed to create console port {}", name))?;
+            ports.push((name, port));
+        }
+
+        Ok(Box::new(MultiportConsole::new(
+            protection_type,
+            ports,
+            keep_rds,
+        )))
+    }
+
+    fn create_jail(
+        &self,
+        jail_config: &Option<JailConfig>,
+        jail_type: VirtioDeviceType,
+    ) -> anyhow::Result<Option<Minijail>> {
+        create_serial_jail(jail_config, jail_type, &self.ports)
+    }
+}
+
+fn create_serial_jail(
+    jail_config: &Option<JailConfig>,
+    jail_type: VirtioDeviceType,
+    params: &[&SerialParameters],
+) -> anyhow::Result<Option<Minijail>> {
+    let jail = match simple_jail(jail_config, &jail_type.seccomp_policy_file("serial"))? {
+        Some(mut jail) => {
+            // Create a tmpfs in the device's root directory so that we can bind mount the
+            // log socket directory into it.
+            // The size=67108864 is size=64*1024*1024 or size=64MB.
+            jail.mount_with_data(
+                Path::new("none"),
+                Path::new("/"),
+                "tmpfs",
+                (libc::MS_NODEV | libc::MS_NOEXEC | libc::MS_NOSUID) as usize,
+                "size=67108864",
+            )?;
+            add_current_user_to_jail(&mut jail)?;
+            for param in params {
+                add_bind_mounts(param, &mut jail)
                     .context("failed to add bind mounts for console device")?;
-                Some(jail)
             }
-            None => None,
-        };
+            Some(jail)
+        }
+        None => None,
+    };
 
-        Ok(jail)
-    }
+    Ok(jail)
 }
 
 #[cfg(feature = "audio")]
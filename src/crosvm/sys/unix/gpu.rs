@@ -100,6 +100,14 @@ pub fn create_gpu_device(
         );
     }
 
+    if let Some(address) = &cfg.vnc_display {
+        // The listener is bound before jailing the device, which has no access to the network or
+        // the file system of the host.
+        let listener = virtio::VncListener::bind(address)
+            .with_context(|| format!("failed to listen for VNC clients on {}", address))?;
+        display_backends.insert(0, virtio::DisplayBackend::Vnc(Arc::new(listener)));
+    }
+
     let dev = virtio::Gpu::new(
         exit_evt_wrtube
             .try_clone()
@@ -23,6 +23,7 @@ pub static EMBEDDED_BPFS: Lazy<std::collections::HashMap<&str, Vec<u8>>> =
 pub(super) struct SandboxConfig<'a> {
     pub(super) limit_caps: bool,
     pub(super) log_failures: bool,
+    pub(super) namespace_net: bool,
     pub(super) seccomp_policy_path: Option<&'a Path>,
     pub(super) seccomp_policy_name: &'a str,
     pub(super) uid_map: Option<&'a str>,
@@ -64,8 +65,10 @@ pub(super) fn create_base_minijail(
         // Run in a new mount namespace.
         j.namespace_vfs();
 
-        // Run in an empty network namespace.
-        j.namespace_net();
+        if config.namespace_net {
+            // Run in an empty network namespace.
+            j.namespace_net();
+        }
 
         // Don't allow the device to gain new privileges.
         j.no_new_privs();
@@ -149,6 +152,15 @@ pub(super) fn simple_jail_ext(
     jail_config: &Option<JailConfig>,
     policy: &str,
     r_limit: Option<u64>,
+) -> Result<Option<Minijail>> {
+    jail_ext(jail_config, policy, r_limit, true)
+}
+
+fn jail_ext(
+    jail_config: &Option<JailConfig>,
+    policy: &str,
+    r_limit: Option<u64>,
+    namespace_net: bool,
 ) -> Result<Option<Minijail>> {
     if let Some(jail_config) = jail_config {
         // A directory for a jailed device's pivot root.
@@ -165,6 +177,7 @@ pub(super) fn simple_jail_ext(
         let config = SandboxConfig {
             limit_caps: true,
             log_failures: jail_config.seccomp_log_failures,
+            namespace_net,
             seccomp_policy_path: policy_path.as_deref(),
             seccomp_policy_name: policy,
             uid_map: None,
@@ -188,6 +201,16 @@ pub(super) fn simple_jail(
     simple_jail_ext(jail_config, policy, None)
 }
 
+/// Returns a jail like `simple_jail` in which the host's network remains reachable, for processes
+/// that forward guest traffic to it.
+#[cfg(feature = "slirp")]
+pub(super) fn network_jail(
+    jail_config: &Option<JailConfig>,
+    policy: &str,
+) -> Result<Option<Minijail>> {
+    jail_ext(jail_config, policy, None, false)
+}
+
 pub(super) fn gpu_jail(jail_config: &Option<JailConfig>, policy: &str) -> Result<Option<Minijail>> {
     match simple_jail_ext(jail_config, policy, Some(32768))? {
         Some(mut jail) => {
@@ -0,0 +1,491 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Live migration of a VM to another crosvm process.
+//!
+//! The destination is started with the same configuration as the source plus `--incoming URI`
+//! and waits for a connection before running any VCPU. The source streams guest memory while its
+//! VCPUs and devices keep running: a first pass copies every page, then each round resends the
+//! pages written since the previous one. Once little enough memory is dirty, or after
+//! `MAX_PRECOPY_ROUNDS`, the source suspends its VCPUs and puts its devices to sleep, sends the
+//! last dirty pages along with the VCPU, interrupt controller and device state, and waits for the
+//! destination to confirm that it has taken over.
+//!
+//! Pages written by the VCPUs are reported by the hypervisor's dirty log. Devices write guest
+//! memory from the host, which the hypervisor doesn't see, so their writes are tracked by the
+//! device dirty log of `GuestMemory` instead, which `Writer` and the `GuestMemory` write helpers
+//! fill in. Devices that write guest memory by other means, such as vhost or VFIO devices, don't
+//! support snapshotting and thus can't be migrated either.
+//!
+//! The stream starts with `MAGIC` and the JSON guest memory layout, followed by any number of
+//! `MSG_PAGES` messages and a single `MSG_STATE` message. The destination answers with a JSON
+//! `Result<(), String>`. Integers are little endian and blobs are prefixed with their length.
+
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+
+use anyhow::Context;
+use anyhow::Result;
+use arch::RunnableLinuxVm;
+use base::info;
+use base::Tube;
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+use hypervisor::VcpuAArch64 as VcpuArch;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use hypervisor::VcpuX86_64 as VcpuArch;
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+use hypervisor::VmAArch64 as VmArch;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use hypervisor::VmX86_64 as VmArch;
+use vm_control::MigrationUri;
+use vm_control::VcpuControl;
+
+#[cfg(target_arch = "x86_64")]
+pub use self::x86_64::*;
+
+/// Connection to the other end of a migration.
+enum MigrationStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl MigrationStream {
+    /// Connects to a destination listening on `uri`.
+    fn connect(uri: &MigrationUri) -> Result<MigrationStream> {
+        Ok(match uri {
+            MigrationUri::Unix(path) => MigrationStream::Unix(
+                UnixStream::connect(path)
+                    .with_context(|| format!("failed to connect to {}", uri))?,
+            ),
+            MigrationUri::Tcp(addr) => MigrationStream::Tcp(
+                TcpStream::connect(addr.as_str())
+                    .with_context(|| format!("failed to connect to {}", uri))?,
+            ),
+        })
+    }
+
+    /// Waits for a source to connect on `uri`.
+    fn accept(uri: &MigrationUri) -> Result<MigrationStream> {
+        Ok(match uri {
+            MigrationUri::Unix(path) => {
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("failed to listen on {}", uri))?;
+                info!("waiting for an incoming migration on {}", uri);
+                let res = listener.accept();
+                // Only a single connection is accepted, the socket is not needed anymore.
+                let _ = std::fs::remove_file(path);
+                let (stream, _) = res.with_context(|| format!("failed to accept on {}", uri))?;
+                MigrationStream::Unix(stream)
+            }
+            MigrationUri::Tcp(addr) => {
+                let listener = TcpListener::bind(addr.as_str())
+                    .with_context(|| format!("failed to listen on {}", uri))?;
+                info!("waiting for an incoming migration on {}", uri);
+                let (stream, _) = listener
+                    .accept()
+                    .with_context(|| format!("failed to accept on {}", uri))?;
+                MigrationStream::Tcp(stream)
+            }
+        })
+    }
+}
+
+impl Read for MigrationStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            MigrationStream::Unix(s) => s.read(buf),
+            MigrationStream::Tcp(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for MigrationStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            MigrationStream::Unix(s) => s.write(buf),
+            MigrationStream::Tcp(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            MigrationStream::Unix(s) => s.flush(),
+            MigrationStream::Tcp(s) => s.flush(),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn send_migration<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
+    _linux: &mut RunnableLinuxVm<V, Vcpu>,
+    _vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<VcpuControl>)],
+    _device_ctrl_tube: &Tube,
+    _uri: &MigrationUri,
+) -> Result<()> {
+    anyhow::bail!("migration is not supported on this architecture")
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn receive_migration<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
+    _linux: &mut RunnableLinuxVm<V, Vcpu>,
+    _vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<VcpuControl>)],
+    _device_ctrl_tube: &Tube,
+    _uri: &MigrationUri,
+) -> Result<()> {
+    anyhow::bail!("migration is not supported on this architecture")
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86_64 {
+    use std::cmp::min;
+    use std::io::BufReader;
+    use std::io::BufWriter;
+
+    use anyhow::anyhow;
+    use anyhow::bail;
+    use base::pagesize;
+    use base::warn;
+    use hypervisor::Vm;
+    use vm_control::VmRunMode;
+    use vm_memory::GuestAddress;
+    use vm_memory::GuestMemory;
+    use vm_memory::MemoryRegionSnapshot;
+
+    use super::super::snapshot::capture_vm_state;
+    use super::super::snapshot::restore_devices;
+    use super::super::snapshot::restore_vm_state;
+    use super::super::snapshot::snapshot_devices;
+    use super::super::snapshot::snapshot_vcpus;
+    use super::super::snapshot::wake_devices;
+    use super::super::snapshot::VmSnapshot;
+    use super::super::vcpu::kick_all_vcpus;
+    use super::*;
+
+    /// Identifies the stream and the version of its format.
+    const MAGIC: &[u8; 8] = b"crosvmM1";
+    /// Tag of a message carrying guest memory.
+    const MSG_PAGES: u8 = 1;
+    /// Tag of the message carrying the VM and device state, which ends the stream.
+    const MSG_STATE: u8 = 2;
+
+    /// Pre-copy stops once less than this many bytes of guest memory are dirty.
+    const DIRTY_MEMORY_THRESHOLD: u64 = 1 << 20;
+    /// Maximum number of rounds of dirty pages sent while the VCPUs are running.
+    const MAX_PRECOPY_ROUNDS: usize = 30;
+    /// Largest amount of guest memory sent in a single message.
+    const MAX_CHUNK_SIZE: u64 = 1 << 20;
+    /// Largest blob accepted from the stream.
+    const MAX_BLOB_SIZE: u64 = 1 << 30;
+
+    /// Sends the VM to the destination listening on `uri`.
+    ///
+    /// On success, the VCPUs are left suspended and the devices asleep, and the VM is expected to
+    /// exit. On failure, the VM is resumed.
+    pub fn send_migration<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
+        linux: &mut RunnableLinuxVm<V, Vcpu>,
+        vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<VcpuControl>)],
+        device_ctrl_tube: &Tube,
+        uri: &MigrationUri,
+    ) -> Result<()> {
+        let stream = MigrationStream::connect(uri)?;
+
+        // Set once the devices are put to sleep, after which they must be woken up unless the
+        // destination takes over.
+        let mut devices_asleep = false;
+        let mem = linux.vm.get_memory();
+        mem.set_device_dirty_log(true);
+        let res = linux
+            .vm
+            .set_guest_memory_dirty_log(true)
+            .context("failed to enable dirty page logging")
+            .and_then(|()| {
+                send_vm(
+                    linux,
+                    vcpu_handles,
+                    device_ctrl_tube,
+                    stream,
+                    &mut devices_asleep,
+                )
+            });
+        if let Err(e) = linux.vm.set_guest_memory_dirty_log(false) {
+            warn!("failed to disable dirty page logging: {}", e);
+        }
+        mem.set_device_dirty_log(false);
+        if res.is_err() {
+            kick_all_vcpus(
+                vcpu_handles,
+                linux.irq_chip.as_irq_chip(),
+                VcpuControl::RunState(VmRunMode::Running),
+            );
+            if devices_asleep {
+                wake_devices(device_ctrl_tube)?;
+            }
+        }
+        res
+    }
+
+    fn send_vm<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
+        linux: &RunnableLinuxVm<V, Vcpu>,
+        vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<VcpuControl>)],
+        device_ctrl_tube: &Tube,
+        stream: MigrationStream,
+        devices_asleep: &mut bool,
+    ) -> Result<()> {
+        let mem = linux.vm.get_memory();
+        let regions = mem.guest_memory_regions();
+        let mut writer = BufWriter::new(stream);
+
+        writer.write_all(MAGIC).context("failed to send header")?;
+        write_blob(&mut writer, &serde_json::to_vec(&memory_layout(mem))?)
+            .context("failed to send header")?;
+
+        for &(guest_base, size) in &regions {
+            send_pages(&mut writer, mem, guest_base, size as u64)?;
+        }
+        let mut dirty = collect_dirty_pages(&linux.vm, mem, &regions)?;
+        let mut round = 0;
+        while dirty_size(&dirty) > DIRTY_MEMORY_THRESHOLD && round < MAX_PRECOPY_ROUNDS {
+            for &(addr, len) in &dirty {
+                send_pages(&mut writer, mem, addr, len)?;
+            }
+            dirty = collect_dirty_pages(&linux.vm, mem, &regions)?;
+            round += 1;
+        }
+        info!(
+            "pre-copy done after {} rounds, {} bytes left to send",
+            round,
+            dirty_size(&dirty)
+        );
+
+        kick_all_vcpus(
+            vcpu_handles,
+            linux.irq_chip.as_irq_chip(),
+            VcpuControl::RunState(VmRunMode::Suspending),
+        );
+        // The VCPUs answer once they have stopped.
+        let vcpus = snapshot_vcpus(vcpu_handles)?;
+
+        // Snapshotting puts the devices to sleep, after which guest memory does not change.
+        let devices_dir = tempfile::tempdir().context("failed to create a temporary directory")?;
+        let devices_path = devices_dir.path().join("devices.json");
+        *devices_asleep = true;
+        snapshot_devices(device_ctrl_tube, &devices_path)?;
+        let devices = std::fs::read(&devices_path).context("failed to read device state")?;
+
+        dirty.extend(collect_dirty_pages(&linux.vm, mem, &regions)?);
+        for &(addr, len) in &dirty {
+            send_pages(&mut writer, mem, addr, len)?;
+        }
+        let state = serde_json::to_vec(&capture_vm_state(linux, vcpus, memory_layout(mem))?)
+            .context("failed to serialize VM state")?;
+
+        writer
+            .write_all(&[MSG_STATE])
+            .and_then(|()| write_blob(&mut writer, &state))
+            .and_then(|()| write_blob(&mut writer, &devices))
+            .and_then(|()| writer.flush())
+            .context("failed to send VM state")?;
+
+        let mut stream = writer
+            .into_inner()
+            .map_err(|e| anyhow!("failed to send VM state: {}", e))?;
+        let reply: std::result::Result<(), String> =
+            serde_json::from_slice(&read_blob(&mut stream).context("destination did not answer")?)
+                .context("invalid answer from the destination")?;
+        reply.map_err(|e| anyhow!("destination failed to load the VM: {}", e))
+    }
+
+    /// Receives a VM from a source connecting to `uri` and loads it. The VCPUs must be suspended.
+    pub fn receive_migration<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
+        linux: &mut RunnableLinuxVm<V, Vcpu>,
+        vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<VcpuControl>)],
+        device_ctrl_tube: &Tube,
+        uri: &MigrationUri,
+    ) -> Result<()> {
+        let mut stream = MigrationStream::accept(uri)?;
+
+        let res = receive_vm(linux, vcpu_handles, device_ctrl_tube, &mut stream);
+        // Let the source know whether it can exit or has to resume the VM.
+        let reply = match &res {
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("{:#}", e)),
+        };
+        write_blob(&mut stream, &serde_json::to_vec(&reply)?)
+            .context("failed to answer the source")?;
+        res
+    }
+
+    fn receive_vm<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
+        linux: &mut RunnableLinuxVm<V, Vcpu>,
+        vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<VcpuControl>)],
+        device_ctrl_tube: &Tube,
+        stream: &mut MigrationStream,
+    ) -> Result<()> {
+        let mem = linux.vm.get_memory();
+        let mut reader = BufReader::new(stream);
+
+        let mut magic = [0u8; MAGIC.len()];
+        reader
+            .read_exact(&mut magic)
+            .context("failed to receive header")?;
+        if &magic != MAGIC {
+            bail!("not a crosvm migration stream");
+        }
+        let layout: Vec<MemoryRegionSnapshot> =
+            serde_json::from_slice(&read_blob(&mut reader).context("failed to receive header")?)
+                .context("invalid guest memory layout")?;
+        if layout != memory_layout(mem) {
+            bail!("guest memory layout differs from the source's");
+        }
+
+        let mut buf = Vec::new();
+        loop {
+            let mut tag = [0u8];
+            reader
+                .read_exact(&mut tag)
+                .context("failed to receive message")?;
+            match tag[0] {
+                MSG_PAGES => {
+                    let addr = GuestAddress(read_u64(&mut reader)?);
+                    let len = read_u64(&mut reader)?;
+                    if len > MAX_CHUNK_SIZE || !mem.is_valid_range(addr, len) {
+                        bail!("invalid guest memory range {:#x}+{:#x}", addr.offset(), len);
+                    }
+                    buf.resize(len as usize, 0);
+                    reader
+                        .read_exact(&mut buf)
+                        .context("failed to receive guest memory")?;
+                    mem.write_all_at_addr(&buf, addr)
+                        .context("failed to write guest memory")?;
+                }
+                MSG_STATE => break,
+                t => bail!("unexpected message type {}", t),
+            }
+        }
+
+        let state: VmSnapshot =
+            serde_json::from_slice(&read_blob(&mut reader).context("failed to receive VM state")?)
+                .context("invalid VM state")?;
+        let devices = read_blob(&mut reader).context("failed to receive device state")?;
+        let devices_dir = tempfile::tempdir().context("failed to create a temporary directory")?;
+        let devices_path = devices_dir.path().join("devices.json");
+        std::fs::write(&devices_path, devices).context("failed to write device state")?;
+
+        // Devices are put to sleep here and must be woken up on every path below.
+        restore_devices(device_ctrl_tube, &devices_path)?;
+        let res = restore_vm_state(linux, vcpu_handles, state);
+        wake_devices(device_ctrl_tube)?;
+        res
+    }
+
+    fn memory_layout(mem: &GuestMemory) -> Vec<MemoryRegionSnapshot> {
+        mem.guest_memory_regions()
+            .into_iter()
+            .map(|(guest_base, size)| MemoryRegionSnapshot {
+                guest_base,
+                size: size as u64,
+            })
+            .collect()
+    }
+
+    /// Returns the ranges of guest memory written to by the VCPUs or the devices since the last
+    /// call, coalescing adjacent pages. `regions` must be the regions of `mem`, the VM's guest
+    /// memory.
+    fn collect_dirty_pages(
+        vm: &impl Vm,
+        mem: &GuestMemory,
+        regions: &[(GuestAddress, usize)],
+    ) -> Result<Vec<(GuestAddress, u64)>> {
+        let page_size = pagesize() as u64;
+        let mut dirty = Vec::new();
+        for &(guest_base, size) in regions {
+            let size = size as u64;
+            let num_pages = (size + page_size - 1) / page_size;
+            let mut bitmap = vec![0u8; ((num_pages + 7) / 8) as usize];
+            vm.get_guest_memory_dirty_log(guest_base, &mut bitmap)
+                .with_context(|| {
+                    format!(
+                        "failed to get dirty log of region at {:#x}",
+                        guest_base.offset()
+                    )
+                })?;
+
+            let is_dirty = |page: u64| bitmap[(page / 8) as usize] & (1 << (page % 8)) != 0;
+            let mut page = 0;
+            while page < num_pages {
+                if !is_dirty(page) {
+                    page += 1;
+                    continue;
+                }
+                let first = page;
+                while page < num_pages && is_dirty(page) {
+                    page += 1;
+                }
+                let offset = first * page_size;
+                let len = min((page - first) * page_size, size - offset);
+                dirty.push((guest_base.unchecked_add(offset), len));
+            }
+        }
+        // Pages written by both are sent twice, which is harmless and rare enough not to bother
+        // merging the two lists.
+        dirty.extend(mem.take_device_dirty_pages());
+        Ok(dirty)
+    }
+
+    fn dirty_size(dirty: &[(GuestAddress, u64)]) -> u64 {
+        dirty.iter().map(|&(_, len)| len).sum()
+    }
+
+    /// Sends `len` bytes of guest memory starting at `addr`, which must lie in a single region.
+    fn send_pages<W: Write>(
+        writer: &mut W,
+        mem: &GuestMemory,
+        addr: GuestAddress,
+        len: u64,
+    ) -> Result<()> {
+        let mut buf = vec![0u8; min(len, MAX_CHUNK_SIZE) as usize];
+        let mut offset = 0;
+        while offset < len {
+            let chunk = &mut buf[..min(len - offset, MAX_CHUNK_SIZE) as usize];
+            let chunk_addr = addr.unchecked_add(offset);
+            mem.read_exact_at_addr(chunk, chunk_addr)
+                .context("failed to read guest memory")?;
+            writer
+                .write_all(&[MSG_PAGES])
+                .and_then(|()| writer.write_all(&chunk_addr.offset().to_le_bytes()))
+                .and_then(|()| writer.write_all(&(chunk.len() as u64).to_le_bytes()))
+                .and_then(|()| writer.write_all(chunk))
+                .context("failed to send guest memory")?;
+            offset += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn write_blob<W: Write>(writer: &mut W, blob: &[u8]) -> std::io::Result<()> {
+        writer.write_all(&(blob.len() as u64).to_le_bytes())?;
+        writer.write_all(blob)
+    }
+
+    fn read_blob<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+        let len = read_u64(reader)?;
+        if len > MAX_BLOB_SIZE {
+            bail!("message of {} bytes is too large", len);
+        }
+        let mut blob = vec![0u8; len as usize];
+        reader.read_exact(&mut blob)?;
+        Ok(blob)
+    }
+
+    fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+        let mut bytes = [0u8; 8];
+        reader.read_exact(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
@@ -0,0 +1,165 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! QMP control socket, which serves the protocol of `vm_control::qmp` on a Unix stream socket.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use base::error;
+use base::UnlinkUnixListener;
+use serde_json::Value;
+use vm_control::qmp::reply_message;
+use vm_control::qmp::QmpCommand;
+use vm_control::qmp::QmpError;
+use vm_control::qmp::QmpEvent;
+use vm_control::qmp::QmpInput;
+use vm_control::qmp::QmpSession;
+
+/// Maximum length of a message from a client, to bound the memory used by a misbehaving one.
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// Time a client has to take a message before it is disconnected, so that a client that stops
+/// reading can't stall the main loop that sends the messages.
+const WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+
+struct QmpClient {
+    stream: UnixStream,
+    session: QmpSession,
+    /// Bytes received after the last complete line.
+    buf: Vec<u8>,
+    /// Set once a write failed, after which nothing more is sent.
+    disconnected: bool,
+}
+
+impl QmpClient {
+    fn send(&mut self, message: &str) {
+        if self.disconnected {
+            return;
+        }
+        if let Err(e) = self
+            .stream
+            .write_all(message.as_bytes())
+            .and_then(|_| self.stream.write_all(b"\r\n"))
+        {
+            error!("failed to write to QMP client, disconnecting it: {}", e);
+            self.disconnected = true;
+            // The socket then reads as closed, which gets the client removed by the main loop.
+            let _ = self.stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Listener of the QMP socket and its connected clients, identified by the ID returned by
+/// `accept`.
+pub struct QmpServer {
+    listener: UnlinkUnixListener,
+    clients: BTreeMap<usize, QmpClient>,
+    next_id: usize,
+}
+
+impl QmpServer {
+    /// Creates the socket at `path`.
+    pub fn bind(path: &Path) -> Result<QmpServer> {
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("failed to create QMP socket {}", path.display()))?;
+        Ok(QmpServer {
+            listener: UnlinkUnixListener(listener),
+            clients: BTreeMap::new(),
+            next_id: 0,
+        })
+    }
+
+    /// Returns the listening socket, which is readable when a client connects.
+    pub fn listener(&self) -> &UnixListener {
+        &self.listener
+    }
+
+    /// Accepts and greets a new client. Returns its ID along with its socket, which is readable
+    /// when `read` should be called.
+    pub fn accept(&mut self) -> Result<(usize, &UnixStream)> {
+        let (stream, _) = self
+            .listener
+            .accept()
+            .context("failed to accept QMP client")?;
+        stream
+            .set_write_timeout(Some(WRITE_TIMEOUT))
+            .context("failed to set QMP client write timeout")?;
+        let mut client = QmpClient {
+            stream,
+            session: QmpSession::new(),
+            buf: Vec::new(),
+            disconnected: false,
+        };
+        client.send(&QmpSession::greeting());
+        let id = self.next_id;
+        self.next_id += 1;
+        let client = self.clients.entry(id).or_insert(client);
+        Ok((id, &client.stream))
+    }
+
+    /// Reads the messages available from the client `id`, answers those that do not need the VM
+    /// and returns the commands to execute along with their message ID.
+    ///
+    /// Returns `None` once the client disconnected, in which case it should be removed.
+    pub fn read(&mut self, id: usize) -> Option<Vec<(QmpCommand, Option<Value>)>> {
+        let client = self.clients.get_mut(&id)?;
+        let mut data = [0u8; 4096];
+        let len = match client.stream.read(&mut data) {
+            Ok(0) => return None,
+            Ok(len) => len,
+            Err(e) => {
+                error!("failed to read from QMP client: {}", e);
+                return None;
+            }
+        };
+        client.buf.extend_from_slice(&data[..len]);
+
+        let mut commands = Vec::new();
+        while let Some(end) = client.buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = client.buf.drain(..=end).collect();
+            match client.session.handle_line(&String::from_utf8_lossy(&line)) {
+                QmpInput::None => {}
+                QmpInput::Reply(message) => client.send(&message),
+                QmpInput::Execute { command, id } => commands.push((command, id)),
+            }
+        }
+        if client.buf.len() > MAX_MESSAGE_LEN {
+            error!("QMP message too long, disconnecting client");
+            return None;
+        }
+        Some(commands)
+    }
+
+    /// Sends the result of a command returned by `read` to the client `id`.
+    pub fn reply(&mut self, id: usize, message_id: Option<Value>, result: Result<Value, QmpError>) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.send(&reply_message(message_id, result));
+        }
+    }
+
+    /// Sends `event` to every client that completed capabilities negotiation.
+    pub fn send_event(&mut self, event: &QmpEvent) {
+        let message = event.to_message();
+        for client in self.clients.values_mut() {
+            if client.session.negotiated() {
+                client.send(&message);
+            }
+        }
+    }
+
+    /// Removes the client `id`, returning its socket so that it can be removed from a
+    /// `WaitContext` before being closed.
+    pub fn remove(&mut self, id: usize) -> Option<UnixStream> {
+        self.clients.remove(&id).map(|client| client.stream)
+    }
+}
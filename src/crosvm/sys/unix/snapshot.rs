@@ -0,0 +1,308 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Saving and loading the complete state of a VM.
+//!
+//! A snapshot is a directory holding three files:
+//!   * `vm.json`: VCPU, interrupt controller and clock state, plus the guest memory layout.
+//!   * `mem.bin`: the raw contents of guest memory, region by region.
+//!   * `devices.json`: the state of the devices on the IO and MMIO buses.
+//!
+//! The functions here expect the caller to have suspended every VCPU beforehand and to resume
+//! them afterwards.
+
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+
+use anyhow::Result;
+use arch::RunnableLinuxVm;
+use base::Tube;
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+use hypervisor::VcpuAArch64 as VcpuArch;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use hypervisor::VcpuX86_64 as VcpuArch;
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+use hypervisor::VmAArch64 as VmArch;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use hypervisor::VmX86_64 as VmArch;
+use vm_control::VcpuControl;
+
+#[cfg(target_arch = "x86_64")]
+pub use self::x86_64::*;
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn take_snapshot<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
+    _linux: &RunnableLinuxVm<V, Vcpu>,
+    _vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<VcpuControl>)],
+    _device_ctrl_tube: &Tube,
+    _snapshot_path: &Path,
+) -> Result<()> {
+    anyhow::bail!("snapshots are not supported on this architecture")
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn restore_snapshot<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
+    _linux: &mut RunnableLinuxVm<V, Vcpu>,
+    _vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<VcpuControl>)],
+    _device_ctrl_tube: &Tube,
+    _restore_path: &Path,
+) -> Result<()> {
+    anyhow::bail!("snapshots are not supported on this architecture")
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86_64 {
+    use std::fs::File;
+    use std::fs::OpenOptions;
+    use std::io::BufReader;
+    use std::io::BufWriter;
+    use std::io::Write;
+
+    use anyhow::anyhow;
+    use anyhow::bail;
+    use anyhow::Context;
+    use devices::IrqChipSnapshot;
+    use hypervisor::ClockState;
+    use hypervisor::VcpuSnapshot;
+    use hypervisor::VmCap;
+    use serde::Deserialize;
+    use serde::Serialize;
+    use vm_control::DeviceControlCommand;
+    use vm_control::RestoreControlResult;
+    use vm_control::SnapshotControlResult;
+    use vm_control::VcpuRestoreRequest;
+    use vm_memory::MemoryRegionSnapshot;
+
+    use super::*;
+
+    const VM_STATE_FILE: &str = "vm.json";
+    const MEMORY_FILE: &str = "mem.bin";
+    const DEVICES_FILE: &str = "devices.json";
+
+    /// Contents of `vm.json`.
+    #[derive(Serialize, Deserialize)]
+    pub struct VmSnapshot {
+        vcpus: Vec<VcpuSnapshot>,
+        irq_chip: IrqChipSnapshot,
+        pvclock: Option<ClockState>,
+        memory: Vec<MemoryRegionSnapshot>,
+    }
+
+    /// Writes the state of the VM into the directory `snapshot_path`, creating it if needed.
+    pub fn take_snapshot<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
+        linux: &RunnableLinuxVm<V, Vcpu>,
+        vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<VcpuControl>)],
+        device_ctrl_tube: &Tube,
+        snapshot_path: &Path,
+    ) -> Result<()> {
+        std::fs::create_dir_all(snapshot_path).with_context(|| {
+            format!(
+                "failed to create snapshot directory {}",
+                snapshot_path.display()
+            )
+        })?;
+
+        let vcpus = snapshot_vcpus(vcpu_handles)?;
+
+        // Devices are put to sleep here and must be woken up on every path below.
+        snapshot_devices(device_ctrl_tube, &snapshot_path.join(DEVICES_FILE))?;
+
+        let res = snapshot_vm_state(linux, vcpus, snapshot_path);
+        wake_devices(device_ctrl_tube)?;
+        res
+    }
+
+    fn snapshot_vm_state<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
+        linux: &RunnableLinuxVm<V, Vcpu>,
+        vcpus: Vec<VcpuSnapshot>,
+        snapshot_path: &Path,
+    ) -> Result<()> {
+        let mem_path = snapshot_path.join(MEMORY_FILE);
+        let mut mem_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&mem_path)
+            .with_context(|| format!("failed to create {}", mem_path.display()))?;
+        let memory = linux
+            .vm
+            .get_memory()
+            .snapshot(&mut mem_file)
+            .context("failed to snapshot guest memory")?;
+        let snapshot = capture_vm_state(linux, vcpus, memory)?;
+
+        let state_path = snapshot_path.join(VM_STATE_FILE);
+        let state_file = File::create(&state_path)
+            .with_context(|| format!("failed to create {}", state_path.display()))?;
+        let mut writer = BufWriter::new(state_file);
+        serde_json::to_writer(&mut writer, &snapshot)
+            .with_context(|| format!("failed to write {}", state_path.display()))?;
+        writer
+            .flush()
+            .with_context(|| format!("failed to write {}", state_path.display()))
+    }
+
+    /// Loads the state of the VM from the snapshot directory `restore_path`.
+    pub fn restore_snapshot<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
+        linux: &mut RunnableLinuxVm<V, Vcpu>,
+        vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<VcpuControl>)],
+        device_ctrl_tube: &Tube,
+        restore_path: &Path,
+    ) -> Result<()> {
+        let state_path = restore_path.join(VM_STATE_FILE);
+        let state_file = File::open(&state_path)
+            .with_context(|| format!("failed to open {}", state_path.display()))?;
+        let snapshot: VmSnapshot = serde_json::from_reader(BufReader::new(state_file))
+            .with_context(|| format!("failed to parse {}", state_path.display()))?;
+
+        // Devices are put to sleep here and must be woken up on every path below.
+        restore_devices(device_ctrl_tube, &restore_path.join(DEVICES_FILE))?;
+
+        let res = restore_memory(linux, &snapshot, restore_path)
+            .and_then(|()| restore_vm_state(linux, vcpu_handles, snapshot));
+        wake_devices(device_ctrl_tube)?;
+        res
+    }
+
+    fn restore_memory<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
+        linux: &RunnableLinuxVm<V, Vcpu>,
+        snapshot: &VmSnapshot,
+        restore_path: &Path,
+    ) -> Result<()> {
+        let mem_path = restore_path.join(MEMORY_FILE);
+        let mut mem_file = File::open(&mem_path)
+            .with_context(|| format!("failed to open {}", mem_path.display()))?;
+        linux
+            .vm
+            .get_memory()
+            .restore(&snapshot.memory, &mut mem_file)
+            .context("failed to restore guest memory")
+    }
+
+    /// Collects the state of every VCPU. The VCPUs must be suspended.
+    pub fn snapshot_vcpus(
+        vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<VcpuControl>)],
+    ) -> Result<Vec<VcpuSnapshot>> {
+        let mut vcpus = Vec::with_capacity(vcpu_handles.len());
+        for (cpu_id, (_, channel)) in vcpu_handles.iter().enumerate() {
+            let (send, recv) = mpsc::channel();
+            channel
+                .send(VcpuControl::Snapshot(send))
+                .with_context(|| format!("failed to send snapshot request to vcpu {}", cpu_id))?;
+            let vcpu = recv
+                .recv()
+                .with_context(|| format!("vcpu {} did not answer snapshot request", cpu_id))?
+                .with_context(|| format!("failed to snapshot vcpu {}", cpu_id))?;
+            vcpus.push(vcpu);
+        }
+        Ok(vcpus)
+    }
+
+    /// Builds a `VmSnapshot` out of `vcpus`, the guest memory `layout` and the current state of
+    /// the interrupt controller and clock.
+    pub fn capture_vm_state<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
+        linux: &RunnableLinuxVm<V, Vcpu>,
+        vcpus: Vec<VcpuSnapshot>,
+        memory: Vec<MemoryRegionSnapshot>,
+    ) -> Result<VmSnapshot> {
+        let irq_chip = linux
+            .irq_chip
+            .snapshot(linux.vcpu_count)
+            .context("failed to snapshot irqchip")?;
+        let pvclock = if linux.vm.check_capability(VmCap::PvClock) {
+            Some(linux.vm.get_pvclock().context("failed to get pvclock")?)
+        } else {
+            None
+        };
+        Ok(VmSnapshot {
+            vcpus,
+            irq_chip,
+            pvclock,
+            memory,
+        })
+    }
+
+    /// Loads everything but guest memory from `snapshot`. The VCPUs must be suspended.
+    pub fn restore_vm_state<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
+        linux: &mut RunnableLinuxVm<V, Vcpu>,
+        vcpu_handles: &[(JoinHandle<()>, mpsc::Sender<VcpuControl>)],
+        snapshot: VmSnapshot,
+    ) -> Result<()> {
+        if snapshot.vcpus.len() != vcpu_handles.len() {
+            bail!(
+                "snapshot has {} vcpus but the VM has {}",
+                snapshot.vcpus.len(),
+                vcpu_handles.len()
+            );
+        }
+        for (cpu_id, ((_, channel), vcpu)) in vcpu_handles
+            .iter()
+            .zip(snapshot.vcpus.into_iter())
+            .enumerate()
+        {
+            let (send, recv) = mpsc::channel();
+            channel
+                .send(VcpuControl::Restore(VcpuRestoreRequest {
+                    result_sender: send,
+                    snapshot: Box::new(vcpu),
+                }))
+                .with_context(|| format!("failed to send restore request to vcpu {}", cpu_id))?;
+            recv.recv()
+                .with_context(|| format!("vcpu {} did not answer restore request", cpu_id))?
+                .with_context(|| format!("failed to restore vcpu {}", cpu_id))?;
+        }
+
+        linux
+            .irq_chip
+            .restore(&snapshot.irq_chip)
+            .context("failed to restore irqchip")?;
+        if let Some(pvclock) = snapshot.pvclock {
+            linux
+                .vm
+                .set_pvclock(&pvclock)
+                .context("failed to set pvclock")?;
+        }
+        Ok(())
+    }
+
+    /// Puts the devices to sleep and writes their state to `path`.
+    pub fn snapshot_devices(device_ctrl_tube: &Tube, path: &Path) -> Result<()> {
+        device_ctrl_tube
+            .send(&DeviceControlCommand::SnapshotDevices {
+                snapshot_path: path.to_path_buf(),
+            })
+            .context("failed to send command to devices control socket")?;
+        match device_ctrl_tube
+            .recv()
+            .context("failed to receive from devices control socket")?
+        {
+            SnapshotControlResult::Ok => Ok(()),
+            SnapshotControlResult::Failed(e) => bail!("failed to snapshot devices: {}", e),
+            SnapshotControlResult::Shutdown => bail!("devices requested shutdown"),
+        }
+    }
+
+    /// Puts the devices to sleep and loads their state from `path`.
+    pub fn restore_devices(device_ctrl_tube: &Tube, path: &Path) -> Result<()> {
+        device_ctrl_tube
+            .send(&DeviceControlCommand::RestoreDevices {
+                restore_path: path.to_path_buf(),
+            })
+            .context("failed to send command to devices control socket")?;
+        match device_ctrl_tube
+            .recv()
+            .context("failed to receive from devices control socket")?
+        {
+            RestoreControlResult::Ok => Ok(()),
+            RestoreControlResult::Failed(e) => bail!("failed to restore devices: {}", e),
+        }
+    }
+
+    pub fn wake_devices(device_ctrl_tube: &Tube) -> Result<()> {
+        device_ctrl_tube
+            .send(&DeviceControlCommand::WakeDevices)
+            .map_err(|e| anyhow!("failed to wake devices: {}", e))
+    }
+}
@@ -23,6 +23,7 @@ use arch::CpuSet;
 use arch::LinuxArch;
 use arch::MsrConfig;
 use base::*;
+use cros_tracing::trace_event;
 use devices::Bus;
 use devices::IrqChip;
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
@@ -34,6 +35,8 @@ use devices::VcpuRunState;
 use hypervisor::CpuConfigAArch64 as CpuConfigArch;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use hypervisor::CpuConfigX86_64 as CpuConfigArch;
+#[cfg(all(any(target_arch = "x86_64", target_arch = "aarch64"), feature = "gdb"))]
+use hypervisor::GuestDebug;
 use hypervisor::IoOperation;
 use hypervisor::IoParams;
 use hypervisor::Vcpu;
@@ -221,6 +224,8 @@ fn handle_debug_msg<V>(
     cpu_id: usize,
     vcpu: &V,
     guest_mem: &GuestMemory,
+    guest_debug: &mut GuestDebug,
+    held: &mut bool,
     d: VcpuDebug,
     reply_tube: &mpsc::Sender<VcpuDebugStatusMessage>,
 ) -> Result<()>
@@ -294,16 +299,6 @@ where
                 })
                 .context("failed to send a debug status to GDB thread")
         }
-        VcpuDebug::EnableSinglestep => {
-            <Arch as arch::GdbOps<V>>::enable_singlestep(vcpu as &V)
-                .context("failed to handle a gdb EnableSingleStep command")?;
-            reply_tube
-                .send(VcpuDebugStatusMessage {
-                    cpu: cpu_id as usize,
-                    msg: VcpuDebugStatus::CommandComplete,
-                })
-                .context("failed to send a debug status to GDB thread")
-        }
         VcpuDebug::GetHwBreakPointCount => {
             let msg = VcpuDebugStatusMessage {
                 cpu: cpu_id as usize,
@@ -316,9 +311,41 @@ where
                 .send(msg)
                 .context("failed to send a debug status to GDB thread")
         }
-        VcpuDebug::SetHwBreakPoint(addrs) => {
-            <Arch as arch::GdbOps<V>>::set_hw_breakpoints(vcpu as &V, &addrs)
-                .context("failed to handle a gdb SetHwBreakPoint command")?;
+        VcpuDebug::GetHwWatchPointCount => {
+            let msg = VcpuDebugStatusMessage {
+                cpu: cpu_id as usize,
+                msg: VcpuDebugStatus::HwWatchPointCount(
+                    <Arch as arch::GdbOps<V>>::get_max_hw_watchpoints(vcpu as &V)
+                        .context("failed to get max number of HW watchpoints")?,
+                ),
+            };
+            reply_tube
+                .send(msg)
+                .context("failed to send a debug status to GDB thread")
+        }
+        VcpuDebug::SetGuestDebug(debug) => {
+            <Arch as arch::GdbOps<V>>::set_guest_debug(vcpu as &V, &debug)
+                .context("failed to handle a gdb SetGuestDebug command")?;
+            *guest_debug = debug;
+            reply_tube
+                .send(VcpuDebugStatusMessage {
+                    cpu: cpu_id as usize,
+                    msg: VcpuDebugStatus::CommandComplete,
+                })
+                .context("failed to send a debug status to GDB thread")
+        }
+        VcpuDebug::InjectBreakpoint => {
+            <Arch as arch::GdbOps<V>>::inject_breakpoint(vcpu as &V)
+                .context("failed to handle a gdb InjectBreakpoint command")?;
+            reply_tube
+                .send(VcpuDebugStatusMessage {
+                    cpu: cpu_id as usize,
+                    msg: VcpuDebugStatus::CommandComplete,
+                })
+                .context("failed to send a debug status to GDB thread")
+        }
+        VcpuDebug::Hold(hold) => {
+            *held = hold;
             reply_tube
                 .send(VcpuDebugStatusMessage {
                     cpu: cpu_id as usize,
@@ -329,6 +356,34 @@ where
     }
 }
 
+/// Re-enters the VCPU with immediate exit set so the hypervisor completes any MMIO or port I/O
+/// exit still pending from the last run. The register state is only consistent after that.
+#[cfg(target_arch = "x86_64")]
+fn complete_pending_exit<V>(
+    vcpu: &mut V,
+    vcpu_run_handle: &VcpuRunHandle,
+    use_hypervisor_signals: bool,
+) -> base::Result<()>
+where
+    V: VcpuArch + 'static,
+{
+    if use_hypervisor_signals {
+        // Without immediate exit support there is no way to leave KVM_RUN before the guest runs.
+        return Err(base::Error::new(libc::ENOTSUP));
+    }
+    vcpu.set_immediate_exit(true);
+    let res = vcpu.run(vcpu_run_handle);
+    vcpu.set_immediate_exit(false);
+    match res {
+        Err(e) if e.errno() == libc::EINTR => Ok(()),
+        Err(e) => Err(e),
+        Ok(exit) => {
+            error!("unexpected vcpu exit while flushing state: {:?}", exit);
+            Err(base::Error::new(libc::EIO))
+        }
+    }
+}
+
 #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
 fn handle_s2idle_request(
     _privileged_vm: bool,
@@ -392,11 +447,17 @@ fn vcpu_loop<V>(
     guest_mem: GuestMemory,
     msr_handlers: MsrHandlers,
     guest_suspended_cvar: Arc<(Mutex<bool>, Condvar)>,
+    #[cfg(target_arch = "x86_64")] msr_indices: Vec<u32>,
 ) -> ExitState
 where
     V: VcpuArch + 'static,
 {
     let mut interrupted_by_signal = false;
+    #[cfg(all(any(target_arch = "x86_64", target_arch = "aarch64"), feature = "gdb"))]
+    let mut guest_debug = GuestDebug::default();
+    // Set by GDB for the vCPUs that must not run when it resumes the VM.
+    #[cfg(all(any(target_arch = "x86_64", target_arch = "aarch64"), feature = "gdb"))]
+    let mut held_by_gdb = false;
 
     loop {
         // Start by checking for messages to process and the run state of the CPU.
@@ -437,6 +498,13 @@ where
                         VcpuControl::RunState(new_mode) => {
                             run_mode = new_mode;
                             match run_mode {
+                                #[cfg(all(
+                                    any(target_arch = "x86_64", target_arch = "aarch64"),
+                                    feature = "gdb"
+                                ))]
+                                VmRunMode::Running if held_by_gdb => {
+                                    run_mode = VmRunMode::Breakpoint;
+                                }
                                 VmRunMode::Running => break 'state_loop,
                                 VmRunMode::Suspending => {
                                     // On KVM implementations that use a paravirtualized
@@ -464,7 +532,15 @@ where
                         ))]
                         VcpuControl::Debug(d) => match &to_gdb_tube {
                             Some(ref ch) => {
-                                if let Err(e) = handle_debug_msg(cpu_id, &vcpu, &guest_mem, d, ch) {
+                                if let Err(e) = handle_debug_msg(
+                                    cpu_id,
+                                    &vcpu,
+                                    &guest_mem,
+                                    &mut guest_debug,
+                                    &mut held_by_gdb,
+                                    d,
+                                    ch,
+                                ) {
                                     error!("Failed to handle gdb message: {}", e);
                                 }
                             }
@@ -485,6 +561,30 @@ where
                                 }
                             }
                         }
+                        #[cfg(target_arch = "x86_64")]
+                        VcpuControl::Snapshot(response_chan) => {
+                            let res = complete_pending_exit(
+                                &mut vcpu,
+                                &vcpu_run_handle,
+                                use_hypervisor_signals,
+                            )
+                            .and_then(|_| vcpu.snapshot(&msr_indices));
+                            if let Err(e) = response_chan.send(res) {
+                                error!("Failed to send snapshot of vcpu {}: {}", cpu_id, e);
+                            }
+                        }
+                        #[cfg(target_arch = "x86_64")]
+                        VcpuControl::Restore(req) => {
+                            let res = complete_pending_exit(
+                                &mut vcpu,
+                                &vcpu_run_handle,
+                                use_hypervisor_signals,
+                            )
+                            .and_then(|_| vcpu.restore(&req.snapshot));
+                            if let Err(e) = req.result_sender.send(res) {
+                                error!("Failed to send restore result of vcpu {}: {}", cpu_id, e);
+                            }
+                        }
                     }
                 }
             }
@@ -508,13 +608,19 @@ where
         }
 
         if !interrupted_by_signal {
-            match vcpu.run(&vcpu_run_handle) {
+            let exit = {
+                let _trace_event = trace_event!(crosvm, "vcpu::run");
+                vcpu.run(&vcpu_run_handle)
+            };
+            match exit {
                 Ok(VcpuExit::Io) => {
+                    let _trace_event = trace_event!(crosvm, "VcpuExit::Io");
                     if let Err(e) = vcpu.handle_io(&mut bus_io_handler(&io_bus)) {
                         error!("failed to handle io: {}", e)
                     }
                 }
                 Ok(VcpuExit::Mmio) => {
+                    let _trace_event = trace_event!(crosvm, "VcpuExit::Mmio");
                     if let Err(e) = vcpu.handle_mmio(&mut bus_io_handler(&mmio_bus)) {
                         error!("failed to handle mmio: {}", e);
                     }
@@ -564,9 +670,23 @@ where
                 #[rustfmt::skip] Ok(VcpuExit::Debug { .. }) => {
                     #[cfg(all(any(target_arch = "x86_64", target_arch = "aarch64"), feature = "gdb"))]
                     {
+                        let status = match <Arch as arch::GdbOps<V>>::get_watchpoint_hit(
+                            &vcpu,
+                            &guest_debug,
+                        ) {
+                            Ok(Some(index)) => VcpuDebugStatus::HitWatchPoint(index),
+                            Ok(None) => VcpuDebugStatus::HitBreakPoint,
+                            Err(e) => {
+                                error!(
+                                    "failed to get the watchpoint hit by vcpu {}: {}",
+                                    cpu_id, e
+                                );
+                                VcpuDebugStatus::HitBreakPoint
+                            }
+                        };
                         let msg = VcpuDebugStatusMessage {
                             cpu: cpu_id as usize,
-                            msg: VcpuDebugStatus::HitBreakPoint,
+                            msg: status,
                         };
                         if let Some(ref ch) = to_gdb_tube {
                             if let Err(e) = ch.send(msg) {
@@ -636,6 +756,7 @@ pub fn run_vcpu<V>(
     vcpu_cgroup_tasks_file: Option<File>,
     userspace_msr: BTreeMap<u32, MsrConfig>,
     guest_suspended_cvar: Arc<(Mutex<bool>, Condvar)>,
+    initial_run_mode: VmRunMode,
 ) -> Result<JoinHandle<()>>
 where
     V: VcpuArch + 'static,
@@ -660,6 +781,16 @@ where
                 #[cfg(all(any(target_arch = "x86_64", target_arch = "aarch64"), feature = "gdb"))]
                 let guest_mem = vm.get_memory().clone();
 
+                // The MSRs to save in a snapshot.
+                #[cfg(target_arch = "x86_64")]
+                let msr_indices = match vm.get_hypervisor().get_msr_index_list() {
+                    Ok(indices) => indices,
+                    Err(e) => {
+                        warn!("failed to get the MSR index list: {}", e);
+                        Vec::new()
+                    }
+                };
+
                 let runnable_vcpu = runnable_vcpu(
                     cpu_id,
                     vcpu_id,
@@ -696,7 +827,7 @@ where
                 };
 
                 #[allow(unused_mut)]
-                let mut run_mode = VmRunMode::Running;
+                let mut run_mode = initial_run_mode;
                 #[cfg(all(any(target_arch = "x86_64", target_arch = "aarch64"), feature = "gdb"))]
                 if to_gdb_tube.is_some() {
                     // Wait until a GDB client attaches
@@ -732,6 +863,8 @@ where
                     guest_mem,
                     msr_handlers,
                     guest_suspended_cvar,
+                    #[cfg(target_arch = "x86_64")]
+                    msr_indices,
                 )
             };
 
@@ -0,0 +1,45 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Sampling of the resource usage of a running VM, for `--metrics` and `crosvm metrics`.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+
+use metrics::MetricsSnapshot;
+use metrics::SampledProcess;
+use metrics::SystemMetricsSampler;
+
+/// Samples the main process and every device process in `pid_debug_label_map`.
+pub fn sample_metrics(
+    sampler: &mut SystemMetricsSampler,
+    pid_debug_label_map: &BTreeMap<u32, String>,
+) -> MetricsSnapshot {
+    let mut processes = vec![SampledProcess {
+        pid: process::id(),
+        label: "main".to_string(),
+    }];
+    processes.extend(
+        pid_debug_label_map
+            .iter()
+            .map(|(pid, label)| SampledProcess {
+                pid: *pid,
+                label: label.clone(),
+            }),
+    );
+    sampler.sample(&processes)
+}
+
+/// Writes `snapshot` to `path` in the Prometheus text format.
+///
+/// The file is replaced by renaming a new one over it, so readers never see a partial sample.
+pub fn write_metrics_file(path: &Path, snapshot: &MetricsSnapshot) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, snapshot.to_prometheus())?;
+    fs::rename(&tmp_path, path)
+}
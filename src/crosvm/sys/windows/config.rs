@@ -38,6 +38,9 @@ pub fn check_serial_params(
             return Err(format!("parameter not supported: stdin"));
         }
     }
+    if serial_params.name.is_some() {
+        return Err("multiport virtio-console devices are not supported".to_string());
+    }
     Ok(())
 }
 
@@ -58,6 +58,9 @@ use vm_control::client::do_gpu_display_remove;
 use vm_control::client::do_modify_battery;
 use vm_control::client::do_swap_status;
 use vm_control::client::do_usb_attach;
+#[cfg(unix)]
+use vm_control::client::do_usb_attach_hid;
+use vm_control::client::do_usb_attach_storage;
 use vm_control::client::do_usb_detach;
 use vm_control::client::do_usb_list;
 use vm_control::client::handle_request;
@@ -74,6 +77,8 @@ use vm_control::RestoreCommand;
 use vm_control::SnapshotCommand;
 use vm_control::SwapCommand;
 use vm_control::UsbControlResult;
+#[cfg(unix)]
+use vm_control::UsbHidDevice;
 use vm_control::VmRequest;
 #[cfg(feature = "balloon")]
 use vm_control::VmResponse;
@@ -454,6 +459,61 @@ fn disk_cmd(cmd: cmdline::DiskCommand) -> std::result::Result<(), ()> {
             };
             vms_request(&request, cmd.socket_path)
         }
+        cmdline::DiskSubcommand::CreateSnapshot(cmd) => {
+            let request = VmRequest::DiskCommand {
+                disk_index: cmd.disk_index,
+                command: DiskControlCommand::CreateSnapshot { name: cmd.name },
+            };
+            vms_request(&request, cmd.socket_path)
+        }
+        cmdline::DiskSubcommand::ListSnapshots(cmd) => {
+            let request = VmRequest::DiskCommand {
+                disk_index: cmd.disk_index,
+                command: DiskControlCommand::ListSnapshots,
+            };
+            let response = handle_request(&request, cmd.socket_path)?;
+            match response {
+                VmResponse::DiskSnapshots(snapshots) => {
+                    match serde_json::to_string_pretty(&snapshots) {
+                        Ok(snapshots_json) => println!("{}", snapshots_json),
+                        Err(e) => {
+                            error!("Failed to serialize into JSON: {}", e);
+                            return Err(());
+                        }
+                    }
+                    Ok(())
+                }
+                r => {
+                    error!("unexpected response: {}", r);
+                    Err(())
+                }
+            }
+        }
+        cmdline::DiskSubcommand::ApplySnapshot(cmd) => {
+            let request = VmRequest::DiskCommand {
+                disk_index: cmd.disk_index,
+                command: DiskControlCommand::ApplySnapshot {
+                    snapshot: cmd.snapshot,
+                },
+            };
+            vms_request(&request, cmd.socket_path)
+        }
+        cmdline::DiskSubcommand::DeleteSnapshot(cmd) => {
+            let request = VmRequest::DiskCommand {
+                disk_index: cmd.disk_index,
+                command: DiskControlCommand::DeleteSnapshot {
+                    snapshot: cmd.snapshot,
+                },
+            };
+            vms_request(&request, cmd.socket_path)
+        }
+        cmdline::DiskSubcommand::SetThrottle(cmd) => {
+            let request = VmRequest::DiskCommand {
+                disk_index: cmd.disk_index,
+                command: DiskControlCommand::SetThrottle { config: cmd.limits },
+            };
+            vms_request(&request, cmd.socket_path)
+        }
     }
 }
 
@@ -501,6 +561,26 @@ fn usb_attach(cmd: UsbAttachCommand) -> ModifyUsbResult<UsbControlResult> {
     do_usb_attach(cmd.socket_path, dev_path)
 }
 
+fn usb_attach_storage(cmd: cmdline::UsbAttachStorageCommand) -> ModifyUsbResult<UsbControlResult> {
+    let image_path = Path::new(&cmd.image_path);
+
+    do_usb_attach_storage(cmd.socket_path, image_path, cmd.ro)
+}
+
+#[cfg(unix)]
+fn usb_attach_hid(cmd: cmdline::UsbAttachHidCommand) -> ModifyUsbResult<UsbControlResult> {
+    let device = match cmd.device_type {
+        cmdline::UsbHidType::Keyboard => UsbHidDevice::Keyboard,
+        cmdline::UsbHidType::Tablet => UsbHidDevice::Tablet {
+            width: cmd.width,
+            height: cmd.height,
+        },
+    };
+    let source_path = Path::new(&cmd.source_path);
+
+    do_usb_attach_hid(cmd.socket_path, device, source_path)
+}
+
 fn usb_detach(cmd: cmdline::UsbDetachCommand) -> ModifyUsbResult<UsbControlResult> {
     do_usb_detach(cmd.socket_path, cmd.port)
 }
@@ -512,6 +592,9 @@ fn usb_list(cmd: cmdline::UsbListCommand) -> ModifyUsbResult<UsbControlResult> {
 fn modify_usb(cmd: cmdline::UsbCommand) -> std::result::Result<(), ()> {
     let result = match cmd.command {
         cmdline::UsbSubCommand::Attach(cmd) => usb_attach(cmd),
+        #[cfg(unix)]
+        cmdline::UsbSubCommand::AttachHid(cmd) => usb_attach_hid(cmd),
+        cmdline::UsbSubCommand::AttachStorage(cmd) => usb_attach_storage(cmd),
         cmdline::UsbSubCommand::Detach(cmd) => usb_detach(cmd),
         cmdline::UsbSubCommand::List(cmd) => usb_list(cmd),
     };
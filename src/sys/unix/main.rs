@@ -18,9 +18,17 @@ use devices::virtio::vhost::user::device::run_fs_device;
 use devices::virtio::vhost::user::device::run_snd_device;
 use devices::virtio::vhost::user::device::run_vsock_device;
 use devices::virtio::vhost::user::device::run_wl_device;
+use vm_control::client::handle_request;
+use vm_control::MigrateCommand;
+use vm_control::MigrateControlResult;
+use vm_control::VirtioMemControlCommand;
+use vm_control::VmRequest;
+use vm_control::VmResponse;
 
 use crate::crosvm::sys::cmdline::Commands;
 use crate::crosvm::sys::cmdline::DeviceSubcommand;
+use crate::crosvm::sys::cmdline::MemSubcommand;
+use crate::crosvm::sys::cmdline::MigrateSubcommand;
 use crate::crosvm::sys::unix::start_devices;
 use crate::CommandStatus;
 use crate::Config;
@@ -85,6 +93,39 @@ pub fn get_library_watcher() -> std::io::Result<()> {
 pub(crate) fn run_command(command: Commands) -> anyhow::Result<()> {
     match command {
         Commands::Devices(cmd) => start_devices(cmd).context("start_devices subcommand failed"),
+        Commands::Mem(cmd) => match cmd.command {
+            MemSubcommand::Resize(cmd) => {
+                let request = VmRequest::VirtioMemCommand(VirtioMemControlCommand::Resize {
+                    requested_size: cmd.size * 1024 * 1024,
+                });
+                match handle_request(&request, &cmd.socket_path) {
+                    Ok(VmResponse::Ok) => Ok(()),
+                    Ok(r) => Err(anyhow!("unexpected response: {}", r)),
+                    Err(()) => Err(anyhow!("mem resize subcommand failed")),
+                }
+            }
+        },
+        Commands::Metrics(cmd) => match handle_request(&VmRequest::GetMetrics, &cmd.socket_path) {
+            Ok(VmResponse::Metrics(snapshot)) => {
+                print!("{}", snapshot.to_prometheus());
+                Ok(())
+            }
+            Ok(r) => Err(anyhow!("unexpected response: {}", r)),
+            Err(()) => Err(anyhow!("metrics subcommand failed")),
+        },
+        Commands::Migrate(cmd) => match cmd.command {
+            MigrateSubcommand::Send(cmd) => {
+                let request = VmRequest::Migrate(MigrateCommand::Send { uri: cmd.uri });
+                match handle_request(&request, &cmd.socket_path) {
+                    Ok(VmResponse::MigrateResponse(MigrateControlResult::Ok)) => Ok(()),
+                    Ok(VmResponse::MigrateResponse(MigrateControlResult::Failed(e))) => {
+                        Err(anyhow!("migration failed: {}", e))
+                    }
+                    Ok(r) => Err(anyhow!("unexpected response: {}", r)),
+                    Err(()) => Err(anyhow!("migrate send subcommand failed")),
+                }
+            }
+        },
     }
 }
 
@@ -273,6 +273,7 @@ fn create_block_device(cfg: &Config, disk: &DiskOption, disk_device_tube: Tube)
         None,
         None,
         None,
+        disk.throttle,
     )
     .exit_context(Exit::BlockDeviceNew, "failed to create block device")?;
 
@@ -915,6 +916,9 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
         {
             error!("fail to send command to devices control socket: {}", e);
         };
+        if let Err(e) = device_ctrl_tube.send(&DeviceControlCommand::WakeDevices) {
+            error!("fail to send command to devices control socket: {}", e);
+        };
     }
 
     let vcpus: Vec<Option<_>> = match guest_os.vcpus.take() {
@@ -1592,6 +1596,16 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
         _ => panic!("Did not receive a bios or kernel, should be impossible."),
     };
 
+    let fw_cfg_kernel_image = if let Some(kernel_path) = &cfg.fw_cfg_kernel_path {
+        Some(
+            open_file(kernel_path, OpenOptions::new().read(true)).with_context(|| {
+                format!("failed to open kernel image {}", kernel_path.display())
+            })?,
+        )
+    } else {
+        None
+    };
+
     let swiotlb = if let Some(size) = cfg.swiotlb {
         Some(
             size.checked_mul(1024 * 1024)
@@ -1633,6 +1647,7 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
         cpu_clusters: cfg.cpu_clusters.clone(),
         cpu_capacity: cfg.cpu_capacity.clone(),
         no_smt: cfg.no_smt,
+        numa_nodes: cfg.numa_nodes.clone(),
         hugepages: cfg.hugepages,
         hv_cfg: hypervisor::Config {
             protection_type: cfg.protection_type,
@@ -1651,6 +1666,8 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
         pflash_block_size,
         pflash_image,
         initrd_image,
+        fw_cfg_kernel_image,
+        fw_cfg_parameters: cfg.fw_cfg_parameters.clone(),
         extra_kernel_params: cfg.params.clone(),
         acpi_sdts: cfg
             .acpi_tables
@@ -384,7 +384,7 @@ extern "C" fn timer_mod_handler<H: CallbackHandler>(
         (*(opaque as *mut Context<H>))
             .callback_handler
             .timer_mod(&mut timer, expire_time);
-        Box::into_raw(timer);
+        let _ = Box::into_raw(timer);
     }
 }
 
@@ -574,6 +574,35 @@ impl<H: CallbackHandler> Context<H> {
         Ok(())
     }
 
+    /// Forwards connections made to `host_addr:host_port` on the host to `guest_addr:guest_port`
+    /// on the virtual network.
+    pub fn add_hostfwd(
+        &mut self,
+        is_udp: bool,
+        host_addr: Ipv4Addr,
+        host_port: u16,
+        guest_addr: Ipv4Addr,
+        guest_port: u16,
+    ) -> io::Result<()> {
+        // Safe because self.slirp is guaranteed to be valid, and all other parameters are passed
+        // by value. libslirp leaves errno set by the failing socket call on error.
+        let ret = unsafe {
+            slirp_add_hostfwd(
+                self.slirp,
+                is_udp as c_int,
+                host_addr.into(),
+                host_port as c_int,
+                guest_addr.into(),
+                guest_port as c_int,
+            )
+        };
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn connection_info(&mut self) -> &str {
         str::from_utf8(unsafe { CStr::from_ptr(slirp_connection_info(self.slirp)) }.to_bytes())
             .unwrap_or("")
@@ -2,11 +2,18 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::fs::File;
 use std::fs::OpenOptions;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
 use std::path::Path;
 use std::path::PathBuf;
 
 use base::open_file;
+#[cfg(unix)]
+use base::AsRawDescriptor;
+#[cfg(unix)]
+use base::SafeDescriptor;
 use remain::sorted;
 use thiserror::Error;
 
@@ -25,6 +32,8 @@ enum ModifyBatError {
 #[sorted]
 #[derive(Error, Debug)]
 pub enum ModifyUsbError {
+    #[error("failed to connect to {0}: {1}")]
+    FailedToConnect(PathBuf, std::io::Error),
     #[error("failed to open device {0}: {1}")]
     FailedToOpenDevice(PathBuf, base::Error),
     #[error("socket failed")]
@@ -76,6 +85,45 @@ pub fn do_usb_detach<T: AsRef<Path> + std::fmt::Debug>(
     }
 }
 
+pub fn do_usb_attach_storage<T: AsRef<Path> + std::fmt::Debug>(
+    socket_path: T,
+    image_path: &Path,
+    read_only: bool,
+) -> ModifyUsbResult<UsbControlResult> {
+    let file = open_file(image_path, OpenOptions::new().read(true).write(!read_only))
+        .map_err(|e| ModifyUsbError::FailedToOpenDevice(image_path.into(), e))?;
+
+    let request = VmRequest::UsbCommand(UsbControlCommand::AttachMassStorage { file, read_only });
+    let response =
+        handle_request(&request, socket_path).map_err(|_| ModifyUsbError::SocketFailed)?;
+    match response {
+        VmResponse::UsbResponse(usb_resp) => Ok(usb_resp),
+        r => Err(ModifyUsbError::UnexpectedResponse(r)),
+    }
+}
+
+/// Attaches a HID device reporting the input events sent to the unix socket at `source_path`.
+#[cfg(unix)]
+pub fn do_usb_attach_hid<T: AsRef<Path> + std::fmt::Debug>(
+    socket_path: T,
+    device: UsbHidDevice,
+    source_path: &Path,
+) -> ModifyUsbResult<UsbControlResult> {
+    let stream = UnixStream::connect(source_path)
+        .map_err(|e| ModifyUsbError::FailedToConnect(source_path.into(), e))?;
+    let source = SafeDescriptor::try_from(&stream as &dyn AsRawDescriptor)
+        .map(File::from)
+        .map_err(|e| ModifyUsbError::FailedToConnect(source_path.into(), e))?;
+
+    let request = VmRequest::UsbCommand(UsbControlCommand::AttachHidDevice { device, source });
+    let response =
+        handle_request(&request, socket_path).map_err(|_| ModifyUsbError::SocketFailed)?;
+    match response {
+        VmResponse::UsbResponse(usb_resp) => Ok(usb_resp),
+        r => Err(ModifyUsbError::UnexpectedResponse(r)),
+    }
+}
+
 pub fn do_usb_list<T: AsRef<Path> + std::fmt::Debug>(
     socket_path: T,
 ) -> ModifyUsbResult<UsbControlResult> {
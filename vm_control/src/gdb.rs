@@ -7,6 +7,7 @@ use gdbstub::arch::Arch;
 use gdbstub_arch::aarch64::AArch64 as GdbArch;
 #[cfg(target_arch = "x86_64")]
 use gdbstub_arch::x86::X86_64_SSE as GdbArch;
+use hypervisor::GuestDebug;
 use vm_memory::GuestAddress;
 
 /// Messages that can be sent to a vCPU to set/get its state from the debugger.
@@ -18,9 +19,13 @@ pub enum VcpuDebug {
     WriteRegs(Box<<GdbArch as Arch>::Registers>),
     WriteReg(<GdbArch as Arch>::RegId, Vec<u8>),
     WriteMem(GuestAddress, Vec<u8>),
-    EnableSinglestep,
     GetHwBreakPointCount,
-    SetHwBreakPoint(Vec<GuestAddress>),
+    GetHwWatchPointCount,
+    SetGuestDebug(GuestDebug),
+    /// Passes the breakpoint exception that stopped the vCPU on to the guest.
+    InjectBreakpoint,
+    /// Whether the vCPU stays stopped when the VM resumes.
+    Hold(bool),
 }
 
 /// Messages that can be sent from a vCPU to update the state to the debugger.
@@ -32,7 +37,10 @@ pub enum VcpuDebugStatus {
     MemoryRegion(Vec<u8>),
     CommandComplete,
     HwBreakPointCount(usize),
+    HwWatchPointCount(usize),
     HitBreakPoint,
+    /// The vCPU stopped on the watchpoint at this index in the last `SetGuestDebug`.
+    HitWatchPoint(usize),
 }
 
 /// Pair of a vCPU ID and messages that can be sent from the vCPU to update the state to the
@@ -22,6 +22,7 @@ use base::MemoryMappingBuilderWindows;
 
 pub mod client;
 pub mod display;
+pub mod qmp;
 pub mod sys;
 
 use std::collections::BTreeSet;
@@ -55,17 +56,21 @@ use base::Result;
 use base::SafeDescriptor;
 use base::SharedMemory;
 use base::Tube;
+use disk::DiskSnapshotInfo;
 use hypervisor::Datamatch;
 use hypervisor::IoEventAddress;
 use hypervisor::IrqRoute;
 use hypervisor::IrqSource;
 pub use hypervisor::MemSlot;
+#[cfg(target_arch = "x86_64")]
+use hypervisor::VcpuSnapshot;
 use hypervisor::Vm;
 use libc::EINVAL;
 use libc::EIO;
 use libc::ENODEV;
 use libc::ENOTSUP;
 use libc::ERANGE;
+use metrics::MetricsSnapshot;
 use remain::sorted;
 use resources::Alloc;
 use resources::SystemAllocator;
@@ -75,6 +80,7 @@ use rutabaga_gfx::RutabagaHandle;
 use rutabaga_gfx::VulkanInfo;
 use serde::Deserialize;
 use serde::Serialize;
+use serde_keyvalue::FromKeyValues;
 use sync::Mutex;
 use sys::kill_handle;
 #[cfg(unix)]
@@ -111,6 +117,20 @@ pub enum VcpuControl {
     Debug(VcpuDebug),
     RunState(VmRunMode),
     MakeRT,
+    /// Captures the VCPU's architectural state and sends it back over the channel.
+    #[cfg(target_arch = "x86_64")]
+    Snapshot(mpsc::Sender<Result<VcpuSnapshot>>),
+    /// Loads previously captured architectural state into the VCPU.
+    #[cfg(target_arch = "x86_64")]
+    Restore(VcpuRestoreRequest),
+}
+
+/// Payload of `VcpuControl::Restore`.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Debug)]
+pub struct VcpuRestoreRequest {
+    pub result_sender: mpsc::Sender<Result<()>>,
+    pub snapshot: Box<VcpuSnapshot>,
 }
 
 /// Mode of execution for the VM.
@@ -183,10 +203,54 @@ pub enum BalloonControlResult {
     },
 }
 
+/// Limits on the I/O that a disk accepts from the guest, enforced with token buckets.
+///
+/// Rates are per second, and a rate of 0 leaves that kind of I/O unlimited. Each burst is the
+/// amount of I/O that may be issued at once after a quiet period, and defaults to one second's
+/// worth of the matching rate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromKeyValues)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DiskThrottleConfig {
+    /// Read requests per second.
+    #[serde(default)]
+    pub read_iops: u64,
+    /// Write, discard and write zeroes requests per second.
+    #[serde(default)]
+    pub write_iops: u64,
+    /// Bytes read per second.
+    #[serde(default)]
+    pub read_bps: u64,
+    /// Bytes written per second.
+    #[serde(default)]
+    pub write_bps: u64,
+    /// Burst size for `read_iops`.
+    #[serde(default)]
+    pub read_iops_burst: u64,
+    /// Burst size for `write_iops`.
+    #[serde(default)]
+    pub write_iops_burst: u64,
+    /// Burst size for `read_bps`.
+    #[serde(default)]
+    pub read_bps_burst: u64,
+    /// Burst size for `write_bps`.
+    #[serde(default)]
+    pub write_bps_burst: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum DiskControlCommand {
     /// Resize a disk to `new_size` in bytes.
     Resize { new_size: u64 },
+    /// Save the current contents of a disk as an internal snapshot called `name`.
+    CreateSnapshot { name: String },
+    /// List the internal snapshots of a disk.
+    ListSnapshots,
+    /// Revert a disk to the internal snapshot with the ID or name `snapshot`.
+    ApplySnapshot { snapshot: String },
+    /// Delete the internal snapshot with the ID or name `snapshot`.
+    DeleteSnapshot { snapshot: String },
+    /// Replace the I/O limits of a disk.
+    SetThrottle { config: DiskThrottleConfig },
 }
 
 impl Display for DiskControlCommand {
@@ -195,6 +259,11 @@ impl Display for DiskControlCommand {
 
         match self {
             Resize { new_size } => write!(f, "disk_resize {}", new_size),
+            CreateSnapshot { name } => write!(f, "disk_create_snapshot {}", name),
+            ListSnapshots => write!(f, "disk_list_snapshots"),
+            ApplySnapshot { snapshot } => write!(f, "disk_apply_snapshot {}", snapshot),
+            DeleteSnapshot { snapshot } => write!(f, "disk_delete_snapshot {}", snapshot),
+            SetThrottle { config } => write!(f, "disk_set_throttle {:?}", config),
         }
     }
 }
@@ -203,6 +272,8 @@ impl Display for DiskControlCommand {
 pub enum DiskControlResult {
     Ok,
     Err(SysError),
+    /// The internal snapshots of the disk, in response to `DiskControlCommand::ListSnapshots`.
+    Snapshots(Vec<DiskSnapshotInfo>),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -217,6 +288,29 @@ pub enum UsbControlCommand {
     ListDevice {
         ports: [u8; USB_CONTROL_MAX_PORTS],
     },
+    /// Attach an emulated mass storage device exposing the disk image `file`.
+    AttachMassStorage {
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+        read_only: bool,
+    },
+    /// Attach an emulated HID device reporting the input events read from `source`.
+    AttachHidDevice {
+        device: UsbHidDevice,
+        #[serde(with = "with_as_descriptor")]
+        source: File,
+    },
+}
+
+/// Type of an emulated USB HID device.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbHidDevice {
+    Keyboard,
+    /// A tablet whose absolute coordinates range from 0 to `width` - 1 and `height` - 1.
+    Tablet {
+        width: u32,
+        height: u32,
+    },
 }
 
 #[derive(Serialize, Deserialize, Copy, Clone, Debug, Default)]
@@ -296,11 +390,74 @@ pub enum RestoreControlResult {
     Failed(String),
 }
 
+/// Address that a migration is sent to or received on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum MigrationUri {
+    /// `unix:PATH`: a unix stream socket at `PATH`.
+    Unix(PathBuf),
+    /// `tcp:HOST:PORT`: a TCP socket.
+    Tcp(String),
+}
+
+impl FromStr for MigrationUri {
+    type Err = String;
+
+    fn from_str(s: &str) -> StdResult<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("unix", path)) if !path.is_empty() => Ok(MigrationUri::Unix(PathBuf::from(path))),
+            Some(("tcp", addr)) if addr.contains(':') => Ok(MigrationUri::Tcp(addr.to_owned())),
+            _ => Err(format!(
+                "invalid migration uri `{}`: expected `unix:PATH` or `tcp:HOST:PORT`",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for MigrationUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationUri::Unix(path) => write!(f, "unix:{}", path.display()),
+            MigrationUri::Tcp(addr) => write!(f, "tcp:{}", addr),
+        }
+    }
+}
+
+/// Commands for live migration
+#[derive(Serialize, Deserialize, Debug)]
+pub enum MigrateCommand {
+    /// Send the VM to a crosvm instance started with `--incoming uri`. The VM exits once the
+    /// destination has taken over.
+    Send { uri: MigrationUri },
+}
+
+/// Response for [MigrateCommand]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum MigrateControlResult {
+    /// The VM is now running on the destination.
+    Ok,
+    /// The migration fails and the VM keeps running here.
+    Failed(String),
+}
+
+/// Commands for the virtio-mem device.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum VirtioMemControlCommand {
+    /// Ask the guest to plug or unplug memory until `requested_size` bytes of the virtio-mem
+    /// region are plugged.
+    Resize { requested_size: u64 },
+}
+
 /// Commands for actions on devices and the devices control thread.
+///
+/// `SnapshotDevices` and `RestoreDevices` leave the devices asleep on success so the caller can
+/// save or load the rest of the VM state while no device touches guest memory; `WakeDevices`
+/// resumes them.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum DeviceControlCommand {
     SnapshotDevices { snapshot_path: PathBuf },
     RestoreDevices { restore_path: PathBuf },
+    WakeDevices,
 }
 
 /// Source of a `VmMemoryRequest::RegisterMemory` mapping.
@@ -1008,6 +1165,12 @@ pub enum VmRequest {
     Snapshot(SnapshotCommand),
     /// Command to Restore devices
     Restore(RestoreCommand),
+    /// Command to migrate the VM to another crosvm instance
+    Migrate(MigrateCommand),
+    /// Get the latest sample of the resource usage of the VM.
+    GetMetrics,
+    /// Command for the virtio-mem device.
+    VirtioMemCommand(VirtioMemControlCommand),
 }
 
 pub fn handle_disk_command(command: &DiskControlCommand, disk_host_tube: &Tube) -> VmResponse {
@@ -1021,6 +1184,7 @@ pub fn handle_disk_command(command: &DiskControlCommand, disk_host_tube: &Tube)
     match disk_host_tube.recv() {
         Ok(DiskControlResult::Ok) => VmResponse::Ok,
         Ok(DiskControlResult::Err(e)) => VmResponse::Err(e),
+        Ok(DiskControlResult::Snapshots(snapshots)) => VmResponse::DiskSnapshots(snapshots),
         Err(e) => {
             error!("disk socket recv failed: {}", e);
             VmResponse::Err(SysError::new(EINVAL))
@@ -1028,6 +1192,13 @@ pub fn handle_disk_command(command: &DiskControlCommand, disk_host_tube: &Tube)
     }
 }
 
+/// Resumes devices left asleep by a successful `SnapshotDevices` or `RestoreDevices`.
+fn wake_devices(device_control_tube: &Tube) {
+    if let Err(e) = device_control_tube.send(&DeviceControlCommand::WakeDevices) {
+        error!("fail to send command to devices control socket: {}", e);
+    }
+}
+
 /// WARNING: descriptor must be a mapping handle on Windows.
 fn map_descriptor(
     descriptor: &dyn AsRawDescriptor,
@@ -1328,7 +1499,12 @@ impl VmRequest {
                 };
 
                 match device_control_tube.recv() {
-                    Ok(response) => VmResponse::SnapshotResponse(response),
+                    Ok(response) => {
+                        if let SnapshotControlResult::Ok = response {
+                            wake_devices(device_control_tube);
+                        }
+                        VmResponse::SnapshotResponse(response)
+                    }
                     Err(e) => {
                         error!("fail to recv command from device control socket: {}", e);
                         VmResponse::Err(SysError::new(EIO))
@@ -1345,13 +1521,30 @@ impl VmRequest {
                 };
 
                 match device_control_tube.recv() {
-                    Ok(response) => VmResponse::RestoreResponse(response),
+                    Ok(response) => {
+                        if let RestoreControlResult::Ok = response {
+                            wake_devices(device_control_tube);
+                        }
+                        VmResponse::RestoreResponse(response)
+                    }
                     Err(e) => {
                         error!("fail to recv command from device control socket: {}", e);
                         VmResponse::Err(SysError::new(EIO))
                     }
                 }
             }
+            VmRequest::Migrate(_) => {
+                error!("{:#?} not supported", *self);
+                VmResponse::Err(SysError::new(ENOTSUP))
+            }
+            VmRequest::GetMetrics => {
+                error!("{:#?} not supported", *self);
+                VmResponse::Err(SysError::new(ENOTSUP))
+            }
+            VmRequest::VirtioMemCommand(_) => {
+                error!("{:#?} not supported", *self);
+                VmResponse::Err(SysError::new(ENOTSUP))
+            }
         }
     }
 }
@@ -1386,6 +1579,12 @@ pub enum VmResponse {
     SnapshotResponse(SnapshotControlResult),
     /// Results of restore commands.
     RestoreResponse(RestoreControlResult),
+    /// Results of migrate commands.
+    MigrateResponse(MigrateControlResult),
+    /// Internal snapshots of a disk.
+    DiskSnapshots(Vec<DiskSnapshotInfo>),
+    /// Results of the get metrics command.
+    Metrics(MetricsSnapshot),
 }
 
 impl Display for VmResponse {
@@ -1426,6 +1625,16 @@ impl Display for VmResponse {
             }
             SnapshotResponse(result) => write!(f, "snapshot control request result {:?}", result),
             RestoreResponse(result) => write!(f, "restore control request result {:?}", result),
+            MigrateResponse(result) => write!(f, "migrate control request result {:?}", result),
+            DiskSnapshots(snapshots) => {
+                write!(
+                    f,
+                    "{}",
+                    serde_json::to_string_pretty(&snapshots)
+                        .unwrap_or_else(|_| "invalid_response".to_string()),
+                )
+            }
+            Metrics(snapshot) => write!(f, "{}", snapshot.to_prometheus()),
         }
     }
 }
@@ -1498,6 +1707,28 @@ mod tests {
         recv_event.signal().unwrap();
         e1.wait().unwrap();
     }
+
+    #[test]
+    fn parse_migration_uri() {
+        assert_eq!(
+            "unix:/run/vm.sock".parse(),
+            Ok(MigrationUri::Unix(PathBuf::from("/run/vm.sock")))
+        );
+        assert_eq!(
+            "tcp:127.0.0.1:4444".parse(),
+            Ok(MigrationUri::Tcp("127.0.0.1:4444".to_owned()))
+        );
+        assert_eq!(
+            "tcp:[::1]:4444"
+                .parse::<MigrationUri>()
+                .unwrap()
+                .to_string(),
+            "tcp:[::1]:4444"
+        );
+        assert!("unix:".parse::<MigrationUri>().is_err());
+        assert!("tcp:localhost".parse::<MigrationUri>().is_err());
+        assert!("/run/vm.sock".parse::<MigrationUri>().is_err());
+    }
 }
 
 #[sorted]
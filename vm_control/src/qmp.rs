@@ -0,0 +1,568 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! JSON control protocol following the conventions of the QEMU Machine Protocol (QMP).
+//!
+//! The server greets each client, which must then negotiate capabilities with `qmp_capabilities`
+//! before sending commands of the form `{"execute": NAME, "arguments": {...}, "id": ID}`. Each
+//! command is answered with `{"return": VALUE, "id": ID}` or
+//! `{"error": {"class": CLASS, "desc": TEXT}, "id": ID}`, and negotiated clients also receive
+//! asynchronous events such as `STOP` or `GUEST_PANICKED`. Every message takes exactly one line.
+//!
+//! This module only implements the protocol: `QmpSession` parses the messages of one client and
+//! `QmpCommand` maps the supported commands to a `VmRequest` and its `VmResponse` back to a result.
+
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+use serde_json::Value;
+
+use crate::BalloonControlCommand;
+use crate::DiskControlCommand;
+use crate::VmRequest;
+use crate::VmResponse;
+use crate::VmRunMode;
+
+/// Names of the commands accepted after capabilities negotiation, as listed by `query-commands`.
+pub const QMP_COMMANDS: &[&str] = &[
+    "balloon",
+    "block_resize",
+    "cont",
+    "qmp_capabilities",
+    "query-balloon",
+    "query-commands",
+    "query-status",
+    "quit",
+    "stop",
+    "system_powerdown",
+];
+
+/// Category of a QMP error, which clients can match on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum QmpErrorClass {
+    CommandNotFound,
+    GenericError,
+}
+
+/// Error result of a QMP command.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct QmpError {
+    pub class: QmpErrorClass,
+    pub desc: String,
+}
+
+impl QmpError {
+    fn generic(desc: impl Into<String>) -> QmpError {
+        QmpError {
+            class: QmpErrorClass::GenericError,
+            desc: desc.into(),
+        }
+    }
+
+    fn command_not_found(desc: impl Into<String>) -> QmpError {
+        QmpError {
+            class: QmpErrorClass::CommandNotFound,
+            desc: desc.into(),
+        }
+    }
+}
+
+/// A QMP command that needs the VM to be answered.
+#[derive(Debug, PartialEq, Eq)]
+pub enum QmpCommand {
+    /// Inflates or deflates the balloon so that the guest is left with `value` bytes of memory.
+    Balloon { value: u64 },
+    /// Resizes the disk at index `device` to `size` bytes.
+    BlockResize { device: usize, size: u64 },
+    /// Resumes the VCPUs of a stopped VM.
+    Cont,
+    /// Returns the memory size left to the guest by the balloon.
+    QueryBalloon,
+    /// Returns the run state of the VM, answered with `query_status`.
+    QueryStatus,
+    /// Exits crosvm.
+    Quit,
+    /// Pauses the VCPUs.
+    Stop,
+    /// Presses the power button of the guest.
+    SystemPowerdown,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BalloonArguments {
+    value: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BlockResizeArguments {
+    device: String,
+    size: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CapabilitiesArguments {
+    #[serde(default)]
+    enable: Vec<String>,
+}
+
+fn parse_arguments<'a, T: Deserialize<'a>>(
+    name: &str,
+    arguments: &'a Value,
+) -> Result<T, QmpError> {
+    T::deserialize(arguments)
+        .map_err(|e| QmpError::generic(format!("invalid arguments for {}: {}", name, e)))
+}
+
+impl QmpCommand {
+    fn parse(name: &str, arguments: &Value) -> Result<QmpCommand, QmpError> {
+        let no_arguments = || match arguments.as_object() {
+            Some(arguments) if arguments.is_empty() => Ok(()),
+            _ => Err(QmpError::generic(format!("{} takes no arguments", name))),
+        };
+        Ok(match name {
+            "balloon" => {
+                let args: BalloonArguments = parse_arguments(name, arguments)?;
+                if args.value == 0 {
+                    return Err(QmpError::generic(
+                        "parameter 'value' expects a size greater than 0",
+                    ));
+                }
+                QmpCommand::Balloon { value: args.value }
+            }
+            "block_resize" => {
+                let args: BlockResizeArguments = parse_arguments(name, arguments)?;
+                let device = args.device.parse().map_err(|_| {
+                    QmpError::generic(format!("device '{}' is not a disk index", args.device))
+                })?;
+                QmpCommand::BlockResize {
+                    device,
+                    size: args.size,
+                }
+            }
+            "cont" => no_arguments().map(|_| QmpCommand::Cont)?,
+            "query-balloon" => no_arguments().map(|_| QmpCommand::QueryBalloon)?,
+            "query-status" => no_arguments().map(|_| QmpCommand::QueryStatus)?,
+            "quit" => no_arguments().map(|_| QmpCommand::Quit)?,
+            "stop" => no_arguments().map(|_| QmpCommand::Stop)?,
+            "system_powerdown" => no_arguments().map(|_| QmpCommand::SystemPowerdown)?,
+            _ => {
+                return Err(QmpError::command_not_found(format!(
+                    "the command {} has not been found",
+                    name
+                )))
+            }
+        })
+    }
+
+    /// Returns the request that executes this command on a VM with `memory_size` bytes of memory,
+    /// or `None` for `QueryStatus`.
+    pub fn vm_request(&self, memory_size: u64) -> Option<VmRequest> {
+        Some(match *self {
+            QmpCommand::Balloon { value } => {
+                VmRequest::BalloonCommand(BalloonControlCommand::Adjust {
+                    num_bytes: memory_size.saturating_sub(value),
+                })
+            }
+            QmpCommand::BlockResize { device, size } => VmRequest::DiskCommand {
+                disk_index: device,
+                command: DiskControlCommand::Resize { new_size: size },
+            },
+            QmpCommand::Cont => VmRequest::Resume,
+            QmpCommand::QueryBalloon => VmRequest::BalloonCommand(BalloonControlCommand::Stats),
+            QmpCommand::QueryStatus => return None,
+            QmpCommand::Quit => VmRequest::Exit,
+            QmpCommand::Stop => VmRequest::Suspend,
+            QmpCommand::SystemPowerdown => VmRequest::Powerbtn,
+        })
+    }
+
+    /// Converts the response to the request returned by `vm_request` to the result of this
+    /// command.
+    pub fn result(&self, response: VmResponse, memory_size: u64) -> Result<Value, QmpError> {
+        match (self, response) {
+            (_, VmResponse::Err(e)) => Err(QmpError::generic(e.to_string())),
+            (QmpCommand::QueryBalloon, VmResponse::BalloonStats { balloon_actual, .. }) => {
+                Ok(json!({ "actual": memory_size.saturating_sub(balloon_actual) }))
+            }
+            (QmpCommand::QueryBalloon, _) => Err(QmpError::generic("unexpected response")),
+            (_, VmResponse::Ok) => Ok(json!({})),
+            (_, response) => Err(QmpError::generic(format!(
+                "unexpected response: {}",
+                response
+            ))),
+        }
+    }
+}
+
+/// Returns the result of `query-status` for a VM in `run_mode`.
+pub fn query_status(run_mode: &VmRunMode) -> Value {
+    let status = match run_mode {
+        VmRunMode::Running => "running",
+        VmRunMode::Suspending => "paused",
+        VmRunMode::Exiting => "shutdown",
+        VmRunMode::Breakpoint => "debug",
+    };
+    json!({
+        "running": *run_mode == VmRunMode::Running,
+        "singlestep": false,
+        "status": status,
+    })
+}
+
+/// Asynchronous notification sent to every negotiated client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QmpEvent {
+    /// The guest reported a panic and keeps running.
+    GuestPanicked,
+    /// The power button of the guest was pressed.
+    Powerdown,
+    /// The guest reset itself.
+    Reset,
+    /// The VCPUs were resumed.
+    Resume,
+    /// crosvm is exiting.
+    Shutdown { reason: ShutdownReason },
+    /// The VCPUs were paused.
+    Stop,
+    /// The watchdog detected a stall of the guest and resets it.
+    Watchdog,
+}
+
+/// Cause of a `QmpEvent::Shutdown`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownReason {
+    /// The guest shut down after reporting a panic.
+    GuestPanic,
+    /// The guest shut down.
+    GuestShutdown,
+    /// A VCPU failed.
+    HostError,
+    /// A control socket requested the exit.
+    HostQuit,
+}
+
+impl ShutdownReason {
+    fn by_guest(&self) -> bool {
+        matches!(
+            self,
+            ShutdownReason::GuestPanic | ShutdownReason::GuestShutdown
+        )
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ShutdownReason::GuestPanic => "guest-panic",
+            ShutdownReason::GuestShutdown => "guest-shutdown",
+            ShutdownReason::HostError => "host-error",
+            ShutdownReason::HostQuit => "host-qmp-quit",
+        }
+    }
+}
+
+impl QmpEvent {
+    /// Returns the event notifying a change of the run mode to `run_mode`.
+    pub fn from_run_mode(run_mode: &VmRunMode) -> QmpEvent {
+        match run_mode {
+            VmRunMode::Running => QmpEvent::Resume,
+            VmRunMode::Suspending | VmRunMode::Breakpoint => QmpEvent::Stop,
+            VmRunMode::Exiting => QmpEvent::Shutdown {
+                reason: ShutdownReason::HostQuit,
+            },
+        }
+    }
+
+    /// Returns the message of this event, timestamped with the current time.
+    pub fn to_message(&self) -> String {
+        let (event, data) = match self {
+            QmpEvent::GuestPanicked => ("GUEST_PANICKED", Some(json!({ "action": "run" }))),
+            QmpEvent::Powerdown => ("POWERDOWN", None),
+            QmpEvent::Reset => (
+                "RESET",
+                Some(json!({ "guest": true, "reason": "guest-reset" })),
+            ),
+            QmpEvent::Resume => ("RESUME", None),
+            QmpEvent::Shutdown { reason } => (
+                "SHUTDOWN",
+                Some(json!({ "guest": reason.by_guest(), "reason": reason.name() })),
+            ),
+            QmpEvent::Stop => ("STOP", None),
+            QmpEvent::Watchdog => ("WATCHDOG", Some(json!({ "action": "reset" }))),
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut message = json!({
+            "event": event,
+            "timestamp": {
+                "seconds": now.as_secs(),
+                "microseconds": now.subsec_micros(),
+            },
+        });
+        if let Some(data) = data {
+            message["data"] = data;
+        }
+        message.to_string()
+    }
+}
+
+/// Returns the message answering the command with `id` with `result`.
+pub fn reply_message(id: Option<Value>, result: Result<Value, QmpError>) -> String {
+    let mut message = match result {
+        Ok(value) => json!({ "return": value }),
+        Err(e) => json!({ "error": e }),
+    };
+    if let Some(id) = id {
+        message["id"] = id;
+    }
+    message.to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QmpMessage {
+    execute: String,
+    #[serde(default)]
+    arguments: Option<Value>,
+    #[serde(default)]
+    id: Option<Value>,
+}
+
+/// What to do with a line received from a client.
+#[derive(Debug, PartialEq)]
+pub enum QmpInput {
+    /// Nothing, the line was empty.
+    None,
+    /// Send this message back to the client.
+    Reply(String),
+    /// Execute `command` and send the result back to the client, with `id`.
+    Execute {
+        command: QmpCommand,
+        id: Option<Value>,
+    },
+}
+
+/// Protocol state of one QMP client.
+#[derive(Default)]
+pub struct QmpSession {
+    negotiated: bool,
+}
+
+impl QmpSession {
+    pub fn new() -> QmpSession {
+        Default::default()
+    }
+
+    /// Returns the first message sent to a client.
+    pub fn greeting() -> String {
+        json!({
+            "QMP": {
+                "version": {
+                    "qemu": { "major": 0, "minor": 0, "micro": 0 },
+                    "package": format!("crosvm {}", env!("CARGO_PKG_VERSION")),
+                },
+                "capabilities": [],
+            }
+        })
+        .to_string()
+    }
+
+    /// Returns whether the client completed capabilities negotiation and receives events.
+    pub fn negotiated(&self) -> bool {
+        self.negotiated
+    }
+
+    /// Handles a line received from the client.
+    pub fn handle_line(&mut self, line: &str) -> QmpInput {
+        if line.trim().is_empty() {
+            return QmpInput::None;
+        }
+        let message: QmpMessage = match serde_json::from_str(line) {
+            Ok(message) => message,
+            Err(e) => {
+                return QmpInput::Reply(reply_message(
+                    None,
+                    Err(QmpError::generic(format!("invalid message: {}", e))),
+                ))
+            }
+        };
+        let arguments = message.arguments.unwrap_or_else(|| json!({}));
+        let result = match message.execute.as_str() {
+            "qmp_capabilities" if self.negotiated => Err(QmpError::command_not_found(
+                "capabilities negotiation is already complete, command ignored",
+            )),
+            "qmp_capabilities" => {
+                parse_arguments::<CapabilitiesArguments>(&message.execute, &arguments).and_then(
+                    |args| match args.enable.first() {
+                        Some(capability) => Err(QmpError::generic(format!(
+                            "capability '{}' is not supported",
+                            capability
+                        ))),
+                        None => {
+                            self.negotiated = true;
+                            Ok(json!({}))
+                        }
+                    },
+                )
+            }
+            _ if !self.negotiated => Err(QmpError::command_not_found(
+                "expecting capabilities negotiation with 'qmp_capabilities'",
+            )),
+            "query-commands" => Ok(Value::Array(
+                QMP_COMMANDS
+                    .iter()
+                    .map(|name| json!({ "name": name }))
+                    .collect(),
+            )),
+            name => match QmpCommand::parse(name, &arguments) {
+                Ok(command) => {
+                    return QmpInput::Execute {
+                        command,
+                        id: message.id,
+                    }
+                }
+                Err(e) => Err(e),
+            },
+        };
+        QmpInput::Reply(reply_message(message.id, result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base::Error as SysError;
+
+    use super::*;
+
+    fn reply(input: QmpInput) -> Value {
+        match input {
+            QmpInput::Reply(message) => serde_json::from_str(&message).unwrap(),
+            input => panic!("unexpected input {:?}", input),
+        }
+    }
+
+    #[test]
+    fn negotiation() {
+        let mut session = QmpSession::new();
+        let greeting: Value = serde_json::from_str(&QmpSession::greeting()).unwrap();
+        assert_eq!(greeting["QMP"]["capabilities"], json!([]));
+
+        let error = reply(session.handle_line(r#"{"execute": "stop", "id": 1}"#));
+        assert_eq!(error["error"]["class"], "CommandNotFound");
+        assert_eq!(error["id"], 1);
+        assert!(!session.negotiated());
+
+        let error =
+            reply(session.handle_line(
+                r#"{"execute": "qmp_capabilities", "arguments": {"enable": ["oob"]}}"#,
+            ));
+        assert_eq!(error["error"]["class"], "GenericError");
+        assert!(!session.negotiated());
+
+        let ok = reply(session.handle_line(r#"{"execute": "qmp_capabilities", "id": "a"}"#));
+        assert_eq!(ok, json!({"return": {}, "id": "a"}));
+        assert!(session.negotiated());
+
+        let error = reply(session.handle_line(r#"{"execute": "qmp_capabilities"}"#));
+        assert_eq!(error["error"]["class"], "CommandNotFound");
+    }
+
+    #[test]
+    fn commands() {
+        let mut session = QmpSession::new();
+        session.handle_line(r#"{"execute": "qmp_capabilities"}"#);
+
+        assert_eq!(session.handle_line(""), QmpInput::None);
+        assert_eq!(
+            session.handle_line(r#"{"execute": "stop", "id": 2}"#),
+            QmpInput::Execute {
+                command: QmpCommand::Stop,
+                id: Some(json!(2)),
+            }
+        );
+        assert_eq!(
+            session.handle_line(
+                r#"{"execute": "block_resize", "arguments": {"device": "1", "size": 4096}}"#
+            ),
+            QmpInput::Execute {
+                command: QmpCommand::BlockResize {
+                    device: 1,
+                    size: 4096
+                },
+                id: None,
+            }
+        );
+
+        let error = reply(session.handle_line(
+            r#"{"execute": "block_resize", "arguments": {"device": "vda", "size": 4096}}"#,
+        ));
+        assert_eq!(error["error"]["class"], "GenericError");
+        let error = reply(session.handle_line(r#"{"execute": "cont", "arguments": {"a": 1}}"#));
+        assert_eq!(error["error"]["class"], "GenericError");
+        let error = reply(session.handle_line(r#"{"execute": "system_reset"}"#));
+        assert_eq!(error["error"]["class"], "CommandNotFound");
+        let error = reply(session.handle_line("{"));
+        assert_eq!(error["error"]["class"], "GenericError");
+
+        let commands = reply(session.handle_line(r#"{"execute": "query-commands"}"#));
+        assert_eq!(
+            commands["return"].as_array().unwrap().len(),
+            QMP_COMMANDS.len()
+        );
+    }
+
+    #[test]
+    fn balloon() {
+        let memory_size = 1 << 30;
+        match (QmpCommand::Balloon { value: 1 << 28 }).vm_request(memory_size) {
+            Some(VmRequest::BalloonCommand(BalloonControlCommand::Adjust { num_bytes })) => {
+                assert_eq!(num_bytes, 3 << 28)
+            }
+            request => panic!("unexpected request {:?}", request),
+        }
+
+        let result = QmpCommand::QueryBalloon.result(
+            VmResponse::BalloonStats {
+                stats: Default::default(),
+                balloon_actual: 1 << 28,
+            },
+            memory_size,
+        );
+        assert_eq!(result, Ok(json!({ "actual": 3 << 28 })));
+
+        let result = (QmpCommand::Balloon { value: 1 })
+            .result(VmResponse::Err(SysError::new(libc::ENOTSUP)), memory_size);
+        assert_eq!(result.unwrap_err().class, QmpErrorClass::GenericError);
+    }
+
+    #[test]
+    fn events() {
+        let event: Value = serde_json::from_str(&QmpEvent::GuestPanicked.to_message()).unwrap();
+        assert_eq!(event["event"], "GUEST_PANICKED");
+        assert_eq!(event["data"]["action"], "run");
+        assert!(event["timestamp"]["seconds"].as_u64().is_some());
+
+        let event: Value = serde_json::from_str(&QmpEvent::Stop.to_message()).unwrap();
+        assert_eq!(event["event"], "STOP");
+        assert!(event.get("data").is_none());
+
+        let event: Value =
+            serde_json::from_str(&QmpEvent::from_run_mode(&VmRunMode::Exiting).to_message())
+                .unwrap();
+        assert_eq!(
+            event["data"],
+            json!({"guest": false, "reason": "host-qmp-quit"})
+        );
+
+        assert_eq!(
+            query_status(&VmRunMode::Suspending),
+            json!({"running": false, "singlestep": false, "status": "paused"})
+        );
+    }
+}
@@ -13,6 +13,8 @@ use std::marker::Send;
 use std::marker::Sync;
 use std::mem::size_of;
 use std::result;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use base::pagesize;
@@ -30,6 +32,8 @@ use cros_async::BackingMemory;
 use data_model::volatile_memory::*;
 use data_model::DataInit;
 use remain::sorted;
+use serde::Deserialize;
+use serde::Serialize;
 use thiserror::Error;
 
 use crate::guest_address::GuestAddress;
@@ -40,6 +44,8 @@ pub use sys::MemoryPolicy;
 #[sorted]
 #[derive(Error, Debug)]
 pub enum Error {
+    #[error("failed to bind guest memory to host NUMA node {0}: {1}")]
+    BindHostNode(u32, #[source] SysError),
     #[error("invalid guest address {0}")]
     InvalidGuestAddress(GuestAddress),
     #[error("invalid offset {0}")]
@@ -64,6 +70,8 @@ pub enum Error {
     ShortRead { expected: usize, completed: usize },
     #[error("incomplete write of {completed} instead of {expected} bytes")]
     ShortWrite { expected: usize, completed: usize },
+    #[error("snapshot memory layout does not match guest memory")]
+    SnapshotLayoutMismatch,
     #[error("DescriptorChain split is out of bounds: {0}")]
     SplitOutOfBounds(usize),
     #[error("{0}")]
@@ -166,11 +174,125 @@ impl MemoryRegion {
     }
 }
 
+/// Describes a guest memory region whose contents are stored in a snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryRegionSnapshot {
+    pub guest_base: GuestAddress,
+    pub size: u64,
+}
+
+/// Bitmap of the guest pages written by devices through `GuestMemory`, used by live migration to
+/// find the pages that the hypervisor's dirty log doesn't see.
+///
+/// The bitmap lives in shared memory so that writes made by sandboxed device processes, which
+/// inherit the mapping when they are forked, are visible to the main process. The first word is
+/// the enable flag, followed by one bit per page for each region in order.
+#[derive(Debug)]
+struct DeviceDirtyLog {
+    mapping: MemoryMapping,
+    // Guest base address, size and index of the first bitmap word of each region.
+    regions: Vec<(GuestAddress, u64, usize)>,
+    words: usize,
+}
+
+impl DeviceDirtyLog {
+    const PAGE_SHIFT: u64 = 12;
+
+    fn new(regions: &[MemoryRegion]) -> Result<DeviceDirtyLog> {
+        let mut words = 1;
+        let regions = regions
+            .iter()
+            .map(|region| {
+                let size = region.mapping.size() as u64;
+                let first_word = words;
+                words += ((size >> Self::PAGE_SHIFT) as usize + 63) / 64;
+                (region.guest_base, size, first_word)
+            })
+            .collect();
+        let size = words * size_of::<u64>();
+        let shm = SharedMemory::new("crosvm_device_dirty_log", size as u64)
+            .map_err(Error::MemoryCreationFailed)?;
+        let mapping = MemoryMappingBuilder::new(size)
+            .from_shared_memory(&shm)
+            .build()
+            .map_err(Error::MemoryMappingFailed)?;
+        Ok(DeviceDirtyLog {
+            mapping,
+            regions,
+            words,
+        })
+    }
+
+    fn words(&self) -> &[AtomicU64] {
+        // Safe because the mapping is page aligned, holds `self.words` u64s and lives as long as
+        // `self`. All accesses to it go through atomics.
+        unsafe { std::slice::from_raw_parts(self.mapping.as_ptr() as *const AtomicU64, self.words) }
+    }
+
+    fn set_enabled(&self, enable: bool) {
+        let words = self.words();
+        if enable {
+            for word in &words[1..] {
+                word.store(0, Ordering::Relaxed);
+            }
+        }
+        words[0].store(enable as u64, Ordering::SeqCst);
+    }
+
+    fn mark_dirty(&self, guest_addr: GuestAddress, len: u64) {
+        let words = self.words();
+        if len == 0 || words[0].load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let region = self
+            .regions
+            .iter()
+            .find(|(base, size, _)| guest_addr >= *base && guest_addr.offset_from(*base) < *size);
+        if let Some(&(base, size, first_word)) = region {
+            let start = guest_addr.offset_from(base);
+            let end = start.saturating_add(len).min(size);
+            for page in (start >> Self::PAGE_SHIFT)..((end - 1) >> Self::PAGE_SHIFT) + 1 {
+                words[first_word + page as usize / 64].fetch_or(1 << (page % 64), Ordering::AcqRel);
+            }
+        }
+    }
+
+    fn take_dirty_pages(&self) -> Vec<(GuestAddress, u64)> {
+        let words = self.words();
+        let page_size = 1 << Self::PAGE_SHIFT;
+        let mut ranges: Vec<(GuestAddress, u64)> = Vec::new();
+        for &(base, size, first_word) in &self.regions {
+            let pages = size >> Self::PAGE_SHIFT;
+            for (i, word) in words[first_word..first_word + ((pages as usize + 63) / 64)]
+                .iter()
+                .enumerate()
+            {
+                let mut bits = word.swap(0, Ordering::AcqRel);
+                while bits != 0 {
+                    let page = i as u64 * 64 + bits.trailing_zeros() as u64;
+                    bits &= bits - 1;
+                    let addr = base.unchecked_add(page << Self::PAGE_SHIFT);
+                    match ranges.last_mut() {
+                        Some((start, len)) if start.unchecked_add(*len) == addr => {
+                            *len += page_size
+                        }
+                        _ => ranges.push((addr, page_size)),
+                    }
+                }
+            }
+        }
+        ranges
+    }
+}
+
 /// Tracks memory regions and where they are mapped in the guest, along with shm
 /// descriptors of the underlying memory regions.
 #[derive(Clone, Debug)]
 pub struct GuestMemory {
     regions: Arc<[MemoryRegion]>,
+    device_dirty_log: Arc<DeviceDirtyLog>,
+    // Region whose memory is plugged into the guest at runtime rather than reported as RAM at boot.
+    hotplug_range: Option<(GuestAddress, u64)>,
 }
 
 impl AsRawDescriptors for GuestMemory {
@@ -248,10 +370,27 @@ impl GuestMemory {
         }
 
         Ok(GuestMemory {
+            device_dirty_log: Arc::new(DeviceDirtyLog::new(&regions)?),
             regions: Arc::from(regions),
+            hotplug_range: None,
         })
     }
 
+    /// Creates guest memory like `new`, followed by `hotplug_range`, a region whose memory is plugged
+    /// into the guest at runtime, e.g. by virtio-mem. The region is backed and accessible like the
+    /// others, but `ram_end_addr` excludes it so that it isn't reported to the guest as RAM at boot.
+    /// It must be above all the `ranges`.
+    pub fn new_with_hotplug(
+        ranges: &[(GuestAddress, u64)],
+        hotplug_range: (GuestAddress, u64),
+    ) -> Result<GuestMemory> {
+        let mut all_ranges = ranges.to_vec();
+        all_ranges.push(hotplug_range);
+        let mut mem = GuestMemory::new(&all_ranges)?;
+        mem.hotplug_range = Some(hotplug_range);
+        Ok(mem)
+    }
+
     /// Creates a `GuestMemory` from a collection of MemoryRegions.
     pub fn from_regions(mut regions: Vec<MemoryRegion>) -> Result<Self> {
         // Sort the regions and ensure non overlap.
@@ -276,7 +415,9 @@ impl GuestMemory {
         }
 
         Ok(GuestMemory {
+            device_dirty_log: Arc::new(DeviceDirtyLog::new(&regions)?),
             regions: Arc::from(regions),
+            hotplug_range: None,
         })
     }
 
@@ -301,6 +442,20 @@ impl GuestMemory {
             .map_or(GuestAddress(0), MemoryRegion::end)
     }
 
+    /// Returns the end address of the memory present at boot, which excludes the hotplug range.
+    pub fn ram_end_addr(&self) -> GuestAddress {
+        self.regions
+            .iter()
+            .filter(|region| Some(region.guest_base) != self.hotplug_range.map(|(base, _)| base))
+            .max_by_key(|region| region.start())
+            .map_or(GuestAddress(0), MemoryRegion::end)
+    }
+
+    /// Returns the guest address and size of the hotplug range given to `new_with_hotplug`.
+    pub fn hotplug_range(&self) -> Option<(GuestAddress, u64)> {
+        self.hotplug_range
+    }
+
     /// Returns the guest addresses and sizes of the memory regions.
     pub fn guest_memory_regions(&self) -> Vec<(GuestAddress, usize)> {
         self.regions
@@ -309,6 +464,52 @@ impl GuestMemory {
             .collect()
     }
 
+    /// Writes the contents of every memory region to `dst`, in guest address order, and returns
+    /// the layout needed to restore them with `restore`.
+    pub fn snapshot<F: Write + AsRawDescriptor>(
+        &self,
+        dst: &mut F,
+    ) -> Result<Vec<MemoryRegionSnapshot>> {
+        let mut layout = Vec::with_capacity(self.regions.len());
+        for region in self.sorted_regions() {
+            let size = region.mapping.size();
+            self.write_from_memory(region.guest_base, dst, size)?;
+            layout.push(MemoryRegionSnapshot {
+                guest_base: region.guest_base,
+                size: size as u64,
+            });
+        }
+        Ok(layout)
+    }
+
+    /// Reads the contents of every memory region from `src`, which must have been produced by
+    /// `snapshot` on guest memory with the same `layout`.
+    pub fn restore<F: Read + AsRawDescriptor>(
+        &self,
+        layout: &[MemoryRegionSnapshot],
+        src: &mut F,
+    ) -> Result<()> {
+        let regions = self.sorted_regions();
+        if regions.len() != layout.len()
+            || regions
+                .iter()
+                .zip(layout)
+                .any(|(r, l)| r.guest_base != l.guest_base || r.mapping.size() as u64 != l.size)
+        {
+            return Err(Error::SnapshotLayoutMismatch);
+        }
+        for region in regions {
+            self.read_to_memory(region.guest_base, src, region.mapping.size())?;
+        }
+        Ok(())
+    }
+
+    fn sorted_regions(&self) -> Vec<&MemoryRegion> {
+        let mut regions: Vec<&MemoryRegion> = self.regions.iter().collect();
+        regions.sort_by_key(|region| region.guest_base);
+        regions
+    }
+
     /// Returns the total size of memory in bytes.
     pub fn memory_size(&self) -> u64 {
         self.regions
@@ -414,9 +615,11 @@ impl GuestMemory {
     /// ```
     pub fn write_at_addr(&self, buf: &[u8], guest_addr: GuestAddress) -> Result<usize> {
         let (mapping, offset, _) = self.find_region(guest_addr)?;
-        mapping
+        let written = mapping
             .write_slice(buf, offset)
-            .map_err(|e| Error::MemoryAccess(guest_addr, e))
+            .map_err(|e| Error::MemoryAccess(guest_addr, e))?;
+        self.mark_dirty(guest_addr, written as u64);
+        Ok(written)
     }
 
     /// Writes the entire contents of a slice to guest memory at the specified
@@ -556,7 +759,9 @@ impl GuestMemory {
         let (mapping, offset, _) = self.find_region(guest_addr)?;
         mapping
             .write_obj(val, offset)
-            .map_err(|e| Error::MemoryAccess(guest_addr, e))
+            .map_err(|e| Error::MemoryAccess(guest_addr, e))?;
+        self.mark_dirty(guest_addr, size_of::<T>() as u64);
+        Ok(())
     }
 
     /// Returns a `VolatileSlice` of `len` bytes starting at `addr`. Returns an error if the slice
@@ -652,7 +857,9 @@ impl GuestMemory {
         let (mapping, offset, _) = self.find_region(guest_addr)?;
         mapping
             .read_to_memory(offset, src, count)
-            .map_err(|e| Error::MemoryAccess(guest_addr, e))
+            .map_err(|e| Error::MemoryAccess(guest_addr, e))?;
+        self.mark_dirty(guest_addr, count as u64);
+        Ok(())
     }
 
     /// Writes data from memory to a file descriptor.
@@ -838,6 +1045,30 @@ impl GuestMemory {
             .ok_or(Error::InvalidGuestAddress(guest_addr))
             .map(|region| region.obj_offset + guest_addr.offset_from(region.start()))
     }
+
+    /// Enables or disables tracking of the pages written by devices. Enabling the log clears any
+    /// pages that were previously marked dirty.
+    ///
+    /// The log is shared with every process holding a clone of this `GuestMemory`, including
+    /// sandboxed device processes.
+    pub fn set_device_dirty_log(&self, enable: bool) {
+        self.device_dirty_log.set_enabled(enable);
+    }
+
+    /// Marks the pages in `len` bytes starting at `guest_addr` as written by a device. This is a
+    /// no-op unless the device dirty log is enabled.
+    ///
+    /// The write helpers of `GuestMemory` call this themselves; it only needs to be called by code
+    /// that writes guest memory through a slice or a host address.
+    pub fn mark_dirty(&self, guest_addr: GuestAddress, len: u64) {
+        self.device_dirty_log.mark_dirty(guest_addr, len);
+    }
+
+    /// Returns the page aligned ranges of guest memory marked dirty since the last call, as
+    /// (address, length) tuples, and clears them.
+    pub fn take_device_dirty_pages(&self) -> Vec<(GuestAddress, u64)> {
+        self.device_dirty_log.take_dirty_pages()
+    }
 }
 
 // It is safe to implement BackingMemory because GuestMemory can be mutated any time already.
@@ -858,6 +1089,38 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn device_dirty_log() {
+        let gm = GuestMemory::new(&[
+            (GuestAddress(0x0), 0x10000),
+            (GuestAddress(0x20000), 0x4000),
+        ])
+        .unwrap();
+
+        // Writes aren't tracked until the log is enabled.
+        gm.write_obj_at_addr(1u64, GuestAddress(0x0)).unwrap();
+        gm.set_device_dirty_log(true);
+        assert!(gm.take_device_dirty_pages().is_empty());
+
+        gm.write_all_at_addr(&[1u8; 0x1001], GuestAddress(0x1fff))
+            .unwrap();
+        gm.write_obj_at_addr(1u32, GuestAddress(0x4000)).unwrap();
+        gm.mark_dirty(GuestAddress(0x23000), 0x2000);
+        assert_eq!(
+            gm.take_device_dirty_pages(),
+            vec![
+                (GuestAddress(0x1000), 0x2000),
+                (GuestAddress(0x4000), 0x1000),
+                (GuestAddress(0x23000), 0x1000),
+            ]
+        );
+        assert!(gm.take_device_dirty_pages().is_empty());
+
+        gm.set_device_dirty_log(false);
+        gm.write_obj_at_addr(1u64, GuestAddress(0x0)).unwrap();
+        assert!(gm.take_device_dirty_pages().is_empty());
+    }
+
     #[test]
     fn test_alignment() {
         let start_addr1 = GuestAddress(0x0);
@@ -881,6 +1144,34 @@ mod tests {
         assert!(!gm.is_valid_range(GuestAddress(0x5000), 0x10000));
     }
 
+    #[test]
+    fn hotplug_range() {
+        let ram = [
+            (GuestAddress(0x0), 0x10000),
+            (GuestAddress(0x20000), 0x10000),
+        ];
+        let hotplug = (GuestAddress(0x40000), 0x20000);
+        let gm = GuestMemory::new_with_hotplug(&ram, hotplug).unwrap();
+
+        assert_eq!(gm.hotplug_range(), Some(hotplug));
+        assert_eq!(gm.ram_end_addr(), GuestAddress(0x30000));
+        assert_eq!(gm.end_addr(), GuestAddress(0x60000));
+        assert_eq!(gm.num_regions(), 3);
+        gm.write_obj_at_addr(0x1234u32, GuestAddress(0x50000))
+            .unwrap();
+        assert_eq!(
+            gm.read_obj_from_addr::<u32>(GuestAddress(0x50000)).unwrap(),
+            0x1234
+        );
+
+        // The hotplug range must be above the RAM.
+        assert!(GuestMemory::new_with_hotplug(&ram, (GuestAddress(0x10000), 0x10000)).is_err());
+        assert_eq!(
+            GuestMemory::new(&ram).unwrap().ram_end_addr(),
+            GuestAddress(0x30000)
+        );
+    }
+
     #[test]
     fn overlap_memory() {
         let start_addr1 = GuestAddress(0x0);
@@ -991,6 +1282,53 @@ mod tests {
         assert_eq!(mem_size, size_region1 + size_region2);
     }
 
+    #[test]
+    fn snapshot_restore() {
+        use std::io::Seek;
+        use std::io::SeekFrom;
+
+        let ranges = [
+            (GuestAddress(0x0), 0x10000),
+            (GuestAddress(0x20000), 0x10000),
+        ];
+        let gm = GuestMemory::new(&ranges).unwrap();
+        gm.write_obj_at_addr(0xaa55aa55u32, GuestAddress(0x500))
+            .unwrap();
+        gm.write_obj_at_addr(0x55aa55aau32, GuestAddress(0x2fff0))
+            .unwrap();
+
+        let mut file = tempfile::tempfile().unwrap();
+        let layout = gm.snapshot(&mut file).unwrap();
+        assert_eq!(
+            layout,
+            vec![
+                MemoryRegionSnapshot {
+                    guest_base: GuestAddress(0x0),
+                    size: 0x10000
+                },
+                MemoryRegionSnapshot {
+                    guest_base: GuestAddress(0x20000),
+                    size: 0x10000
+                },
+            ]
+        );
+
+        let restored = GuestMemory::new(&ranges).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        restored.restore(&layout, &mut file).unwrap();
+        let val1: u32 = restored.read_obj_from_addr(GuestAddress(0x500)).unwrap();
+        let val2: u32 = restored.read_obj_from_addr(GuestAddress(0x2fff0)).unwrap();
+        assert_eq!(val1, 0xaa55aa55);
+        assert_eq!(val2, 0x55aa55aa);
+
+        let other = GuestMemory::new(&[(GuestAddress(0x0), 0x20000)]).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        assert!(matches!(
+            other.restore(&layout, &mut file),
+            Err(Error::SnapshotLayoutMismatch)
+        ));
+    }
+
     // Get the base address of the mapping for a GuestAddress.
     fn get_mapping(mem: &GuestMemory, addr: GuestAddress) -> Result<*const u8> {
         Ok(mem.find_region(addr)?.0.as_ptr() as *const u8)
@@ -2,6 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use base::MappedRegion;
 use base::MemfdSeals;
 use base::MemoryMappingUnix;
 use base::SharedMemory;
@@ -44,6 +45,56 @@ impl GuestMemory {
             .map_err(|e| Error::MemoryAccess(addr, e))
     }
 
+    /// Binds the host memory backing the guest range to the host NUMA node `host_node`.
+    ///
+    /// Pages of the range that were already allocated on other nodes are moved to `host_node`.
+    pub fn bind_range_to_host_node(
+        &self,
+        addr: GuestAddress,
+        count: u64,
+        host_node: u32,
+    ) -> Result<()> {
+        const MPOL_BIND: libc::c_ulong = 2;
+        const MPOL_MF_STRICT: libc::c_ulong = 1 << 0;
+        const MPOL_MF_MOVE: libc::c_ulong = 1 << 1;
+        const BITS_PER_MASK_WORD: u32 = libc::c_ulong::BITS;
+
+        let word = (host_node / BITS_PER_MASK_WORD) as usize;
+        let mut nodemask = vec![0 as libc::c_ulong; word + 1];
+        nodemask[word] |= 1 << (host_node % BITS_PER_MASK_WORD);
+        // The kernel ignores the last bit of the mask, so count it in.
+        let maxnode = nodemask.len() as libc::c_ulong * BITS_PER_MASK_WORD as libc::c_ulong + 1;
+
+        let end = addr.unchecked_add(count);
+        // The range may span several regions, e.g. around file-backed mappings.
+        for region in self.regions.iter() {
+            let start = region.start().max(addr);
+            let range_end = region.end().min(end);
+            if start >= range_end {
+                continue;
+            }
+            let len = range_end.offset_from(start);
+            let offset = start.offset_from(region.start()) as usize;
+            // Safe because the range is within a mapping owned by `self` and mbind does not
+            // touch memory outside of `nodemask`.
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_mbind,
+                    region.mapping.as_ptr().add(offset),
+                    len as libc::c_ulong,
+                    MPOL_BIND,
+                    nodemask.as_ptr(),
+                    maxnode,
+                    MPOL_MF_STRICT | MPOL_MF_MOVE,
+                )
+            };
+            if ret < 0 {
+                return Err(Error::BindHostNode(host_node, base::Error::last()));
+            }
+        }
+        Ok(())
+    }
+
     /// Handles guest memory policy hints/advices.
     pub fn set_memory_policy(&self, mem_policy: MemoryPolicy) {
         if mem_policy.is_empty() {
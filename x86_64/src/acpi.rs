@@ -12,7 +12,10 @@ use acpi_tables::aml;
 use acpi_tables::facs::FACS;
 use acpi_tables::rsdp::RSDP;
 use acpi_tables::sdt::SDT;
+use arch::numa::numa_distance;
+use arch::numa::NumaMemoryRange;
 use arch::CpuSet;
+use arch::NumaNode;
 use arch::VcpuAffinity;
 use base::error;
 use base::warn;
@@ -101,6 +104,55 @@ struct Localx2Apic {
 // Safe as LocalAPIC structure only contains raw data
 unsafe impl DataInit for Localx2Apic {}
 
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct SratLocalApicAffinity {
+    _type: u8,
+    _length: u8,
+    _proximity_domain_lo: u8,
+    _apic_id: u8,
+    _flags: u32,
+    _local_sapic_eid: u8,
+    _proximity_domain_hi: [u8; 3],
+    _clock_domain: u32,
+}
+
+// Safe as SratLocalApicAffinity structure only contains raw data
+unsafe impl DataInit for SratLocalApicAffinity {}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct SratMemoryAffinity {
+    _type: u8,
+    _length: u8,
+    _proximity_domain: u32,
+    _reserved1: u16,
+    _base_address: u64,
+    _range_length: u64,
+    _reserved2: u32,
+    _flags: u32,
+    _reserved3: u64,
+}
+
+// Safe as SratMemoryAffinity structure only contains raw data
+unsafe impl DataInit for SratMemoryAffinity {}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct SratLocalx2ApicAffinity {
+    _type: u8,
+    _length: u8,
+    _reserved1: u16,
+    _proximity_domain: u32,
+    _x2apic_id: u32,
+    _flags: u32,
+    _clock_domain: u32,
+    _reserved2: u32,
+}
+
+// Safe as SratLocalx2ApicAffinity structure only contains raw data
+unsafe impl DataInit for SratLocalx2ApicAffinity {}
+
 // Space ID for GenericAddress
 const ADR_SPACE_SYSTEM_IO: u8 = 1;
 
@@ -185,6 +237,22 @@ const MCFG_FIELD_BASE_ADDRESS: usize = 44;
 const MCFG_FIELD_START_BUS_NUMBER: usize = 54;
 const MCFG_FIELD_END_BUS_NUMBER: usize = 55;
 
+// SRAT
+const SRAT_LEN: u32 = 48;
+const SRAT_REVISION: u8 = 3;
+const SRAT_FIELD_TABLE_REVISION: usize = 36;
+// SRAT types
+const SRAT_TYPE_LOCAL_APIC_AFFINITY: u8 = 0;
+const SRAT_TYPE_MEMORY_AFFINITY: u8 = 1;
+const SRAT_TYPE_LOCAL_X2APIC_AFFINITY: u8 = 2;
+// SRAT flags
+const SRAT_ENABLED: u32 = 1;
+
+// SLIT
+const SLIT_LEN: u32 = 44;
+const SLIT_REVISION: u8 = 1;
+const SLIT_FIELD_LOCALITY_COUNT: usize = 36;
+
 const SSDT_REVISION: u8 = 2;
 pub fn create_customize_ssdt(
     pci_root: Arc<Mutex<PciRoot>>,
@@ -421,6 +489,91 @@ fn next_offset(offset: GuestAddress, len: u64) -> Option<GuestAddress> {
     }
 }
 
+fn create_srat_table(
+    numa_nodes: &[NumaNode],
+    numa_ranges: &[NumaMemoryRange],
+    apic_ids: &[usize],
+) -> SDT {
+    let mut srat = SDT::new(
+        *b"SRAT",
+        SRAT_LEN,
+        SRAT_REVISION,
+        *b"CROSVM",
+        *b"CROSVMDT",
+        OEM_REVISION,
+    );
+    // Reserved field which must be 1 for backward compatibility.
+    srat.write(SRAT_FIELD_TABLE_REVISION, 1_u32);
+
+    for node in numa_nodes {
+        for &cpu in node.cpus.iter() {
+            let apic_id = match apic_ids.get(cpu) {
+                Some(&apic_id) => apic_id as u32,
+                None => continue,
+            };
+            if apic_id < MADT_MIN_LOCAL_APIC_ID {
+                srat.append(SratLocalApicAffinity {
+                    _type: SRAT_TYPE_LOCAL_APIC_AFFINITY,
+                    _length: std::mem::size_of::<SratLocalApicAffinity>() as u8,
+                    _proximity_domain_lo: node.node as u8,
+                    _apic_id: apic_id as u8,
+                    _flags: SRAT_ENABLED,
+                    _proximity_domain_hi: [
+                        (node.node >> 8) as u8,
+                        (node.node >> 16) as u8,
+                        (node.node >> 24) as u8,
+                    ],
+                    ..Default::default()
+                });
+            } else {
+                srat.append(SratLocalx2ApicAffinity {
+                    _type: SRAT_TYPE_LOCAL_X2APIC_AFFINITY,
+                    _length: std::mem::size_of::<SratLocalx2ApicAffinity>() as u8,
+                    _proximity_domain: node.node,
+                    _x2apic_id: apic_id,
+                    _flags: SRAT_ENABLED,
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    for range in numa_ranges {
+        srat.append(SratMemoryAffinity {
+            _type: SRAT_TYPE_MEMORY_AFFINITY,
+            _length: std::mem::size_of::<SratMemoryAffinity>() as u8,
+            _proximity_domain: range.node,
+            _base_address: range.start.offset(),
+            _range_length: range.size,
+            _flags: SRAT_ENABLED,
+            ..Default::default()
+        });
+    }
+
+    srat
+}
+
+fn create_slit_table(numa_nodes: &[NumaNode]) -> SDT {
+    let mut slit = SDT::new(
+        *b"SLIT",
+        SLIT_LEN,
+        SLIT_REVISION,
+        *b"CROSVM",
+        *b"CROSVMDT",
+        OEM_REVISION,
+    );
+    slit.write(SLIT_FIELD_LOCALITY_COUNT, numa_nodes.len() as u64);
+
+    for from in 0..numa_nodes.len() {
+        let distances: Vec<u8> = (0..numa_nodes.len())
+            .map(|to| numa_distance(numa_nodes, from, to))
+            .collect();
+        slit.append_slice(&distances);
+    }
+
+    slit
+}
+
 fn sync_acpi_id_from_cpuid(
     madt: &mut SDT,
     cpus: BTreeMap<usize, CpuSet>,
@@ -531,6 +684,8 @@ fn sync_acpi_id_from_cpuid(
 ///               interrupt pin assignment).
 /// * `pcie_cfg_mmio` - Base address for the pcie enhanced configuration access mechanism
 /// *  `max_bus` - Max bus number in MCFG table
+/// * `numa_nodes` - Guest NUMA nodes described in the SRAT and SLIT, if any.
+/// * `numa_ranges` - Guest memory ranges belonging to each of `numa_nodes`.
 ///
 
 pub fn create_acpi_tables(
@@ -546,6 +701,8 @@ pub fn create_acpi_tables(
     pcie_cfg_mmio: u64,
     max_bus: u8,
     force_s2idle: bool,
+    numa_nodes: &[NumaNode],
+    numa_ranges: &[NumaMemoryRange],
 ) -> Option<GuestAddress> {
     // RSDP is at the HI RSDP WINDOW
     let rsdp_offset = GuestAddress(super::ACPI_HI_RSDP_WINDOW_BASE);
@@ -716,6 +873,20 @@ pub fn create_acpi_tables(
     tables.push(offset.0);
     offset = next_offset(offset, madt.len() as u64)?;
 
+    if !numa_nodes.is_empty() {
+        // SRAT
+        let srat = create_srat_table(numa_nodes, numa_ranges, apic_ids);
+        guest_mem.write_at_addr(srat.as_slice(), offset).ok()?;
+        tables.push(offset.0);
+        offset = next_offset(offset, srat.len() as u64)?;
+
+        // SLIT
+        let slit = create_slit_table(numa_nodes);
+        guest_mem.write_at_addr(slit.as_slice(), offset).ok()?;
+        tables.push(offset.0);
+        offset = next_offset(offset, slit.len() as u64)?;
+    }
+
     // XSDT
     let mut xsdt = SDT::new(
         *b"XSDT",
@@ -47,6 +47,7 @@ pub mod cpuid;
 mod gdt;
 pub mod interrupts;
 pub mod mptable;
+mod pvh;
 pub mod regs;
 pub mod smbios;
 
@@ -55,6 +56,7 @@ use std::ffi::CStr;
 use std::ffi::CString;
 use std::fs::File;
 use std::io;
+use std::io::Read;
 use std::io::Seek;
 use std::mem;
 use std::sync::mpsc;
@@ -64,6 +66,7 @@ use acpi_tables::aml;
 use acpi_tables::aml::Aml;
 use acpi_tables::sdt::SDT;
 use arch::get_serial_cmdline;
+use arch::numa::NumaMemoryRange;
 use arch::GetSerialCmdlineError;
 use arch::MsrAction;
 use arch::MsrConfig;
@@ -86,6 +89,8 @@ use devices::BusDevice;
 use devices::BusDeviceObj;
 use devices::BusResumeDevice;
 use devices::Debugcon;
+use devices::FwCfg;
+use devices::FwCfgInterface;
 use devices::IrqChip;
 use devices::IrqChipX86_64;
 use devices::IrqEventSource;
@@ -117,6 +122,8 @@ use hypervisor::x86_64::Regs;
 #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
 use hypervisor::x86_64::Sregs;
 use hypervisor::CpuConfigX86_64;
+#[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+use hypervisor::GuestDebug;
 use hypervisor::HypervisorX86_64;
 use hypervisor::ProtectionType;
 use hypervisor::VcpuInitX86_64;
@@ -176,6 +183,8 @@ pub enum Error {
     CreateEvent(base::Error),
     #[error("failed to create fdt: {0}")]
     CreateFdt(cros_fdt::Error),
+    #[error("failed to add fw_cfg file: {0}")]
+    CreateFwCfgFile(devices::FwCfgError),
     #[error("failed to create IOAPIC device: {0}")]
     CreateIoapicDevice(base::Error),
     #[error("failed to create a PCI root hub: {0}")]
@@ -200,12 +209,14 @@ pub enum Error {
     #[cfg(feature = "direct")]
     #[error("failed to enable ACPI event forwarding: {0}")]
     EnableAcpiEvent(devices::DirectIrqError),
-    #[error("failed to enable singlestep execution: {0}")]
-    EnableSinglestep(base::Error),
     #[error("failed to enable split irqchip: {0}")]
     EnableSplitIrqchip(base::Error),
     #[error("failed to get serial cmdline: {0}")]
     GetSerialCmdline(GetSerialCmdlineError),
+    #[error("failed to get the hit watchpoint: {0}")]
+    GetWatchpointHit(base::Error),
+    #[error("failed to inject a breakpoint exception: {0}")]
+    InjectBreakpoint(base::Error),
     #[error("failed to insert device onto bus: {0}")]
     InsertBus(devices::BusError),
     #[error("the kernel extends past the end of RAM")]
@@ -218,6 +229,8 @@ pub enum Error {
     LoadBzImage(bzimage::Error),
     #[error("error loading command line: {0}")]
     LoadCmdline(kernel_loader::Error),
+    #[error("error loading fw_cfg file {0}: {1}")]
+    LoadFwCfgFile(String, io::Error),
     #[error("error loading initrd: {0}")]
     LoadInitrd(arch::LoadImageError),
     #[error("error loading Kernel: {0}")]
@@ -226,6 +239,8 @@ pub enum Error {
     LoadPflash(io::Error),
     #[error("error translating address: Page not present")]
     PageNotPresent,
+    #[error("error writing the PVH start info to guest memory")]
+    PvhStartInfoSetup,
     #[error("error reading guest memory {0}")]
     ReadingGuestMemory(vm_memory::GuestMemoryError),
     #[error("single register read not supported on x86_64")]
@@ -238,8 +253,8 @@ pub enum Error {
     RegisterVsock(arch::DeviceRegistrationError),
     #[error("error reserved pcie config mmio")]
     ReservePcieCfgMmio(resources::Error),
-    #[error("failed to set a hardware breakpoint: {0}")]
-    SetHwBreakpoint(base::Error),
+    #[error("failed to set up guest debugging: {0}")]
+    SetGuestDebug(base::Error),
     #[error("failed to set interrupts: {0}")]
     SetLint(interrupts::Error),
     #[error("failed to set tss addr: {0}")]
@@ -284,6 +299,15 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 pub struct X8664arch;
 
+/// Protocol used to enter a directly booted kernel.
+enum BootProtocol {
+    /// The Linux/x86 64-bit boot protocol, with the given boot parameters in the zero page.
+    Linux(boot_params),
+    /// The PVH boot protocol, which enters the kernel in 32-bit protected mode.
+    Pvh,
+}
+
+#[derive(Clone, Copy)]
 enum E820Type {
     Ram = 0x01,
     Reserved = 0x2,
@@ -299,6 +323,10 @@ const FIRST_ADDR_PAST_32BITS: u64 = 1 << 32;
 const HIGH_MMIO_MAX_END: u64 = (1u64 << 46) - 1;
 pub const KERNEL_64BIT_ENTRY_OFFSET: u64 = 0x200;
 pub const ZERO_PAGE_OFFSET: u64 = 0x7000;
+// The PVH start info, module list and memory map take the place of the zero page.
+const PVH_INFO_OFFSET: u64 = ZERO_PAGE_OFFSET;
+const PVH_MODLIST_OFFSET: u64 = PVH_INFO_OFFSET + 0x40;
+const PVH_MEMMAP_OFFSET: u64 = PVH_INFO_OFFSET + 0x80;
 const TSS_ADDR: u64 = 0xfffb_d000;
 
 pub const KERNEL_START_OFFSET: u64 = 0x20_0000;
@@ -411,7 +439,6 @@ fn configure_system(
     initrd: Option<(GuestAddress, usize)>,
     mut params: boot_params,
 ) -> Result<()> {
-    const EBDA_START: u64 = 0x0009_fc00;
     const KERNEL_BOOT_FLAG_MAGIC: u16 = 0xaa55;
     const KERNEL_HDR_MAGIC: u32 = 0x5372_6448;
     const KERNEL_LOADER_OTHER: u8 = 0xff;
@@ -431,18 +458,88 @@ fn configure_system(
         params.hdr.ramdisk_size = initrd_size as u32;
     }
 
-    add_e820_entry(
-        &mut params,
+    for (range, mem_type) in e820_map(guest_mem, kernel_addr) {
+        add_e820_entry(&mut params, range, mem_type)?;
+    }
+
+    let zero_page_addr = GuestAddress(ZERO_PAGE_OFFSET);
+    if !guest_mem.is_valid_range(zero_page_addr, mem::size_of::<boot_params>() as u64) {
+        return Err(Error::ZeroPagePastRamEnd);
+    }
+
+    guest_mem
+        .write_obj_at_addr(params, zero_page_addr)
+        .map_err(|_| Error::ZeroPageSetup)?;
+
+    Ok(())
+}
+
+fn configure_pvh(
+    guest_mem: &GuestMemory,
+    kernel_addr: GuestAddress,
+    cmdline_addr: GuestAddress,
+    initrd: Option<(GuestAddress, usize)>,
+    rsdp_addr: Option<GuestAddress>,
+) -> Result<()> {
+    let mut start_info = pvh::HvmStartInfo {
+        magic: pvh::XEN_HVM_START_MAGIC_VALUE,
+        version: pvh::XEN_HVM_START_INFO_VERSION,
+        cmdline_paddr: cmdline_addr.offset(),
+        rsdp_paddr: rsdp_addr.map_or(0, |addr| addr.offset()),
+        memmap_paddr: PVH_MEMMAP_OFFSET,
+        ..Default::default()
+    };
+
+    if let Some((initrd_addr, initrd_size)) = initrd {
+        let module = pvh::HvmModlistEntry {
+            paddr: initrd_addr.offset(),
+            size: initrd_size as u64,
+            ..Default::default()
+        };
+        guest_mem
+            .write_obj_at_addr(module, GuestAddress(PVH_MODLIST_OFFSET))
+            .map_err(|_| Error::PvhStartInfoSetup)?;
+        start_info.nr_modules = 1;
+        start_info.modlist_paddr = PVH_MODLIST_OFFSET;
+    }
+
+    let mut memmap_addr = GuestAddress(PVH_MEMMAP_OFFSET);
+    for (range, mem_type) in e820_map(guest_mem, kernel_addr) {
+        let entry = pvh::HvmMemmapTableEntry {
+            addr: range.start,
+            size: range.len().ok_or(Error::E820Configuration)?,
+            type_: mem_type as u32,
+            ..Default::default()
+        };
+        guest_mem
+            .write_obj_at_addr(entry, memmap_addr)
+            .map_err(|_| Error::PvhStartInfoSetup)?;
+        memmap_addr = memmap_addr.unchecked_add(mem::size_of_val(&entry) as u64);
+        start_info.memmap_entries += 1;
+    }
+
+    guest_mem
+        .write_obj_at_addr(start_info, GuestAddress(PVH_INFO_OFFSET))
+        .map_err(|_| Error::PvhStartInfoSetup)?;
+
+    Ok(())
+}
+
+/// Returns the memory map of the guest, with RAM starting again at `kernel_addr` above the EBDA.
+fn e820_map(guest_mem: &GuestMemory, kernel_addr: GuestAddress) -> Vec<(AddressRange, E820Type)> {
+    const EBDA_START: u64 = 0x0009_fc00;
+
+    let mut map = vec![(
         AddressRange {
             start: START_OF_RAM_32BITS,
             end: EBDA_START - 1,
         },
         E820Type::Ram,
-    )?;
+    )];
 
-    // GuestMemory::end_addr() returns the first address past the end, so subtract 1 to get the
-    // inclusive end.
-    let guest_mem_end = guest_mem.end_addr().offset() - 1;
+    // GuestMemory::ram_end_addr() returns the first address past the end, so subtract 1 to get the
+    // inclusive end. The hotplug range, if any, is left for its device to report.
+    let guest_mem_end = guest_mem.ram_end_addr().offset() - 1;
     let ram_below_4g = AddressRange {
         start: kernel_addr.offset(),
         end: guest_mem_end.min(read_pci_mmio_before_32bit().start - 1),
@@ -451,30 +548,19 @@ fn configure_system(
         start: FIRST_ADDR_PAST_32BITS,
         end: guest_mem_end,
     };
-    add_e820_entry(&mut params, ram_below_4g, E820Type::Ram)?;
+    map.push((ram_below_4g, E820Type::Ram));
     if !ram_above_4g.is_empty() {
-        add_e820_entry(&mut params, ram_above_4g, E820Type::Ram)?
+        map.push((ram_above_4g, E820Type::Ram));
     }
 
     let pcie_cfg_mmio_range = read_pcie_cfg_mmio();
-    add_e820_entry(&mut params, pcie_cfg_mmio_range, E820Type::Reserved)?;
-
-    add_e820_entry(
-        &mut params,
+    map.push((pcie_cfg_mmio_range, E820Type::Reserved));
+    map.push((
         X8664arch::get_pcie_vcfg_mmio_range(guest_mem, &pcie_cfg_mmio_range),
         E820Type::Reserved,
-    )?;
-
-    let zero_page_addr = GuestAddress(ZERO_PAGE_OFFSET);
-    if !guest_mem.is_valid_range(zero_page_addr, mem::size_of::<boot_params>() as u64) {
-        return Err(Error::ZeroPagePastRamEnd);
-    }
+    ));
 
-    guest_mem
-        .write_obj_at_addr(params, zero_page_addr)
-        .map_err(|_| Error::ZeroPageSetup)?;
-
-    Ok(())
+    map
 }
 
 /// Add an e820 region to the e820 map.
@@ -541,7 +627,26 @@ impl arch::LinuxArch for X8664arch {
             VmImage::Kernel(_) => None,
         };
 
-        Ok(arch_memory_regions(components.memory_size, bios_size))
+        let regions = arch_memory_regions(components.memory_size, bios_size);
+        let numa_ranges = Self::numa_memory_layout(components)?;
+        if numa_ranges.is_empty() {
+            Ok(regions)
+        } else {
+            Ok(arch::numa::split_memory_regions(&regions, &numa_ranges))
+        }
+    }
+
+    fn numa_memory_layout(
+        components: &VmComponents,
+    ) -> std::result::Result<Vec<NumaMemoryRange>, Self::Error> {
+        if components.numa_nodes.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        init_low_memory_layout(components.pcie_ecam, components.pci_low_start);
+        // Only RAM is assigned to the nodes, not the BIOS region.
+        let ram = arch_memory_regions(components.memory_size, None);
+        Ok(arch::numa::numa_memory_ranges(&ram, &components.numa_nodes))
     }
 
     fn get_system_allocator_config<V: Vm>(vm: &V) -> SystemAllocatorConfig {
@@ -581,6 +686,7 @@ impl arch::LinuxArch for X8664arch {
         }
 
         let mem = vm.get_memory().clone();
+        let numa_ranges = Self::numa_memory_layout(&components)?;
 
         let vcpu_count = components.vcpu_count;
 
@@ -757,6 +863,31 @@ impl arch::LinuxArch for X8664arch {
             acpi_dev_resource.sdts.push(sdt);
         }
 
+        let fw_cfg = if components.fw_cfg_kernel_image.is_some()
+            || !components.fw_cfg_parameters.is_empty()
+        {
+            let kernel_image = components.fw_cfg_kernel_image.take();
+            // The initrd is only loaded by the firmware along with a kernel.
+            let initrd_image = if kernel_image.is_some() {
+                components.initrd_image.take()
+            } else {
+                None
+            };
+            let fw_cfg = Self::setup_fw_cfg_device(
+                &io_bus,
+                &mem,
+                vcpu_count,
+                components.memory_size,
+                &components.fw_cfg_parameters,
+                kernel_image,
+                initrd_image,
+            )?;
+            fw_cfg.lock().to_aml_bytes(&mut acpi_dev_resource.amls);
+            Some(fw_cfg)
+        } else {
+            None
+        };
+
         irq_chip
             .finalize_devices(system_allocator, &io_bus, &mmio_bus)
             .map_err(Error::RegisterIrqfd)?;
@@ -784,7 +915,7 @@ impl arch::LinuxArch for X8664arch {
         };
 
         // TODO (tjeznach) Write RSDP to bootconfig before writing to memory
-        acpi::create_acpi_tables(
+        let rsdp_addr = acpi::create_acpi_tables(
             &mem,
             vcpu_count as u8,
             sci_irq,
@@ -797,6 +928,8 @@ impl arch::LinuxArch for X8664arch {
             pcie_cfg_mmio_range.start,
             max_bus,
             components.force_s2idle,
+            &components.numa_nodes,
+            &numa_ranges,
         )
         .ok_or(Error::CreateAcpi)?;
 
@@ -825,6 +958,9 @@ impl arch::LinuxArch for X8664arch {
         let mut msrs;
         match components.vm_image {
             VmImage::Bios(ref mut bios) => {
+                if let Some(fw_cfg) = &fw_cfg {
+                    fw_cfg.lock().set_cmdline(cmdline.as_str());
+                }
                 // Allow a bios to hardcode CMDLINE_OFFSET and read the kernel command line from it.
                 kernel_loader::load_cmdline(
                     &mem,
@@ -837,31 +973,59 @@ impl arch::LinuxArch for X8664arch {
                 // The default values for `Regs` and `Sregs` already set up the reset vector.
             }
             VmImage::Kernel(ref mut kernel_image) => {
-                let (params, kernel_end, kernel_entry) = Self::load_kernel(&mem, kernel_image)?;
-
-                Self::setup_system_memory(
-                    &mem,
-                    &CString::new(cmdline).unwrap(),
-                    components.initrd_image,
-                    components.android_fstab,
-                    kernel_end,
-                    params,
-                )?;
-
-                // Configure the bootstrap VCPU for the Linux/x86 64-bit boot protocol.
-                // <https://www.kernel.org/doc/html/latest/x86/boot.html>
-                vcpu_init[0].regs.rip = kernel_entry.offset();
-                vcpu_init[0].regs.rsp = BOOT_STACK_POINTER;
-                vcpu_init[0].regs.rsi = ZERO_PAGE_OFFSET;
-
-                msrs = regs::long_mode_msrs();
-                msrs.append(&mut regs::mtrr_msrs(&vm, pci_start));
-
-                // Set up long mode and enable paging.
-                regs::configure_segments_and_sregs(&mem, &mut vcpu_init[0].sregs)
-                    .map_err(Error::ConfigureSegments)?;
-                regs::setup_page_tables(&mem, &mut vcpu_init[0].sregs)
-                    .map_err(Error::SetupPageTables)?;
+                let (protocol, kernel_end, kernel_entry) = Self::load_kernel(&mem, kernel_image)?;
+
+                match protocol {
+                    BootProtocol::Linux(params) => {
+                        Self::setup_system_memory(
+                            &mem,
+                            &CString::new(cmdline).unwrap(),
+                            components.initrd_image,
+                            components.android_fstab,
+                            kernel_end,
+                            params,
+                        )?;
+
+                        // Configure the bootstrap VCPU for the Linux/x86 64-bit boot protocol.
+                        // <https://www.kernel.org/doc/html/latest/x86/boot.html>
+                        vcpu_init[0].regs.rip = kernel_entry.offset();
+                        vcpu_init[0].regs.rsp = BOOT_STACK_POINTER;
+                        vcpu_init[0].regs.rsi = ZERO_PAGE_OFFSET;
+
+                        msrs = regs::long_mode_msrs();
+                        msrs.append(&mut regs::mtrr_msrs(&vm, pci_start));
+
+                        // Set up long mode and enable paging.
+                        regs::configure_segments_and_sregs(&mem, &mut vcpu_init[0].sregs)
+                            .map_err(Error::ConfigureSegments)?;
+                        regs::setup_page_tables(&mem, &mut vcpu_init[0].sregs)
+                            .map_err(Error::SetupPageTables)?;
+                    }
+                    BootProtocol::Pvh => {
+                        if components.android_fstab.is_some() {
+                            warn!("android fstab is not passed to kernels booted with PVH");
+                        }
+                        Self::setup_pvh_memory(
+                            &mem,
+                            &CString::new(cmdline).unwrap(),
+                            components.initrd_image,
+                            kernel_end,
+                            Some(rsdp_addr),
+                        )?;
+
+                        // Configure the bootstrap VCPU for the PVH boot protocol.
+                        // <https://xenbits.xen.org/docs/unstable/misc/pvh.html>
+                        vcpu_init[0].regs.rip = kernel_entry.offset();
+                        vcpu_init[0].regs.rbx = PVH_INFO_OFFSET;
+
+                        msrs = regs::default_msrs();
+                        msrs.append(&mut regs::mtrr_msrs(&vm, pci_start));
+
+                        // Set up 32-bit protected mode with paging disabled.
+                        regs::configure_segments_and_sregs_pvh(&mem, &mut vcpu_init[0].sregs)
+                            .map_err(Error::ConfigureSegments)?;
+                    }
+                }
             }
         }
 
@@ -1154,18 +1318,26 @@ impl<T: VcpuX86_64> arch::GdbOps<T> for X8664arch {
         Ok(())
     }
 
-    fn enable_singlestep(vcpu: &T) -> Result<()> {
-        vcpu.set_guest_debug(&[], true /* enable_singlestep */)
-            .map_err(Error::EnableSinglestep)
+    fn get_max_hw_breakpoints(_vcpu: &T) -> Result<usize> {
+        Ok(4usize)
     }
 
-    fn get_max_hw_breakpoints(_vcpu: &T) -> Result<usize> {
+    // Breakpoints and watchpoints share the same four debug registers.
+    fn get_max_hw_watchpoints(_vcpu: &T) -> Result<usize> {
         Ok(4usize)
     }
 
-    fn set_hw_breakpoints(vcpu: &T, breakpoints: &[GuestAddress]) -> Result<()> {
-        vcpu.set_guest_debug(breakpoints, false /* enable_singlestep */)
-            .map_err(Error::SetHwBreakpoint)
+    fn set_guest_debug(vcpu: &T, debug: &GuestDebug) -> Result<()> {
+        vcpu.set_guest_debug(debug).map_err(Error::SetGuestDebug)
+    }
+
+    fn get_watchpoint_hit(vcpu: &T, debug: &GuestDebug) -> Result<Option<usize>> {
+        vcpu.get_debug_exit_watchpoint(debug)
+            .map_err(Error::GetWatchpointHit)
+    }
+
+    fn inject_breakpoint(vcpu: &T) -> Result<()> {
+        vcpu.inject_breakpoint().map_err(Error::InjectBreakpoint)
     }
 }
 
@@ -1398,6 +1570,84 @@ impl X8664arch {
         Ok(())
     }
 
+    /// Sets up the fw_cfg device, which exposes the files given by the user as well as the kernel
+    /// and initrd loaded by the firmware if any.
+    ///
+    /// # Arguments
+    ///
+    /// * - `io_bus` the I/O bus to add the device to
+    /// * - `mem` the guest memory accessed by the DMA interface of the device
+    /// * - `vcpu_count` the number of vCPUs of the guest
+    /// * - `memory_size` the size of the guest memory, in bytes
+    /// * - `fw_cfg_parameters` the files exposed by the device
+    /// * - `kernel_image` the bzImage loaded by the firmware
+    /// * - `initrd_image` the initrd loaded by the firmware with the kernel
+    fn setup_fw_cfg_device(
+        io_bus: &devices::Bus,
+        mem: &GuestMemory,
+        vcpu_count: usize,
+        memory_size: u64,
+        fw_cfg_parameters: &[devices::FwCfgParameters],
+        kernel_image: Option<File>,
+        initrd_image: Option<File>,
+    ) -> Result<Arc<Mutex<FwCfg>>> {
+        let mut fw_cfg = FwCfg::new(
+            mem.clone(),
+            FwCfgInterface::Io {
+                base: devices::FW_CFG_IO_BASE as u16,
+            },
+        );
+        fw_cfg.set_ram_size(memory_size);
+        fw_cfg.set_cpu_count(vcpu_count as u16);
+
+        for param in fw_cfg_parameters {
+            let data = param
+                .data()
+                .map_err(|e| Error::LoadFwCfgFile(param.name.clone(), e))?;
+            fw_cfg
+                .add_file(&param.name, data)
+                .map_err(Error::CreateFwCfgFile)?;
+        }
+
+        if let Some(mut kernel_image) = kernel_image {
+            let mut kernel = Vec::new();
+            kernel_image
+                .read_to_end(&mut kernel)
+                .map_err(|e| Error::LoadFwCfgFile("kernel".to_string(), e))?;
+            // The firmware expects the real-mode setup code of the bzImage separately from the
+            // protected-mode kernel, as described in Documentation/x86/boot.rst.
+            let setup_size = if kernel.get(0x202..0x206) == Some(&b"HdrS"[..]) {
+                // A setup_sects of 0 means 4 sectors, which follow the boot sector.
+                match kernel[0x1f1] {
+                    0 => 5 * 512,
+                    setup_sects => (usize::from(setup_sects) + 1) * 512,
+                }
+            } else {
+                0
+            };
+            let kernel_data = kernel.split_off(setup_size.min(kernel.len()));
+            fw_cfg.set_kernel(kernel, kernel_data);
+
+            if let Some(mut initrd_image) = initrd_image {
+                let mut initrd = Vec::new();
+                initrd_image
+                    .read_to_end(&mut initrd)
+                    .map_err(|e| Error::LoadFwCfgFile("initrd".to_string(), e))?;
+                fw_cfg.set_initrd(initrd);
+            }
+        }
+
+        let fw_cfg = Arc::new(Mutex::new(fw_cfg));
+        io_bus
+            .insert(
+                fw_cfg.clone(),
+                devices::FW_CFG_IO_BASE,
+                devices::FW_CFG_IO_SIZE,
+            )
+            .map_err(Error::InsertBus)?;
+        Ok(fw_cfg)
+    }
+
     /// Loads the kernel from an open file.
     ///
     /// # Arguments
@@ -1407,22 +1657,23 @@ impl X8664arch {
     ///
     /// # Returns
     ///
-    /// On success, returns the Linux x86_64 boot protocol parameters, the first address past the
+    /// On success, returns the boot protocol to enter the kernel with, the first address past the
     /// end of the kernel, and the entry point (initial `RIP` value).
     fn load_kernel(
         mem: &GuestMemory,
         kernel_image: &mut File,
-    ) -> Result<(boot_params, u64, GuestAddress)> {
+    ) -> Result<(BootProtocol, u64, GuestAddress)> {
         let kernel_start = GuestAddress(KERNEL_START_OFFSET);
-        match kernel_loader::load_elf64(mem, kernel_start, kernel_image, 0) {
-            Ok(loaded_kernel) => {
-                // ELF kernels don't contain a `boot_params` structure, so synthesize a default one.
-                let boot_params = Default::default();
-                Ok((
-                    boot_params,
-                    loaded_kernel.address_range.end,
-                    loaded_kernel.entry,
-                ))
+        let loaded_kernel = match kernel_loader::load_elf64(mem, kernel_start, kernel_image, 0) {
+            Ok(loaded_kernel) => loaded_kernel,
+            Err(kernel_loader::Error::InvalidElfClass) => {
+                // 32-bit kernels can only be entered through PVH.
+                let loaded_kernel = kernel_loader::load_elf32(mem, kernel_start, kernel_image, 0)
+                    .map_err(Error::LoadKernel)?;
+                if loaded_kernel.pvh_entry.is_none() {
+                    return Err(Error::LoadKernel(kernel_loader::Error::InvalidElfClass));
+                }
+                loaded_kernel
             }
             Err(kernel_loader::Error::InvalidMagicNumber) => {
                 // The image failed to parse as ELF, so try to load it as a bzImage.
@@ -1432,9 +1683,25 @@ impl X8664arch {
                 let bzimage_entry = mem
                     .checked_offset(kernel_start, KERNEL_64BIT_ENTRY_OFFSET)
                     .ok_or(Error::KernelOffsetPastEnd)?;
-                Ok((boot_params, bzimage_end, bzimage_entry))
+                return Ok((BootProtocol::Linux(boot_params), bzimage_end, bzimage_entry));
             }
-            Err(e) => Err(Error::LoadKernel(e)),
+            Err(e) => return Err(Error::LoadKernel(e)),
+        };
+
+        match loaded_kernel.pvh_entry {
+            // PVH is preferred as it skips the kernel's own setup code and also works for kernels
+            // other than Linux.
+            Some(pvh_entry) => Ok((
+                BootProtocol::Pvh,
+                loaded_kernel.address_range.end,
+                pvh_entry,
+            )),
+            // ELF kernels don't contain a `boot_params` structure, so synthesize a default one.
+            None => Ok((
+                BootProtocol::Linux(Default::default()),
+                loaded_kernel.address_range.end,
+                loaded_kernel.entry,
+            )),
         }
     }
 
@@ -1485,7 +1752,7 @@ impl X8664arch {
                     initrd_addr_max = 0x37FFFFFF;
                 }
 
-                let mem_max = mem.end_addr().offset() - 1;
+                let mem_max = mem.ram_end_addr().offset() - 1;
                 if initrd_addr_max > mem_max {
                     initrd_addr_max = mem_max;
                 }
@@ -1515,6 +1782,54 @@ impl X8664arch {
         Ok(())
     }
 
+    /// Loads the command line and initrd for a kernel entered through the PVH boot protocol, and
+    /// writes the start info describing them.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - The memory to be used by the guest.
+    /// * `cmdline` - the kernel commandline
+    /// * `initrd_file` - an initial ramdisk image
+    /// * `kernel_end` - the first address past the end of the kernel
+    /// * `rsdp_addr` - the address of the ACPI RSDP, if any
+    pub fn setup_pvh_memory(
+        mem: &GuestMemory,
+        cmdline: &CStr,
+        initrd_file: Option<File>,
+        kernel_end: u64,
+        rsdp_addr: Option<GuestAddress>,
+    ) -> Result<()> {
+        kernel_loader::load_cmdline(mem, GuestAddress(CMDLINE_OFFSET), cmdline)
+            .map_err(Error::LoadCmdline)?;
+
+        let initrd = match initrd_file {
+            Some(mut initrd_file) => {
+                // Keep the initrd below the 32-bit PCI hole so that it is reachable before the
+                // kernel enables paging.
+                let initrd_addr_max =
+                    (mem.ram_end_addr().offset() - 1).min(read_pci_mmio_before_32bit().start - 1);
+                let (initrd_start, initrd_size) = arch::load_image_high(
+                    mem,
+                    &mut initrd_file,
+                    GuestAddress(kernel_end),
+                    GuestAddress(initrd_addr_max),
+                    base::pagesize() as u64,
+                )
+                .map_err(Error::LoadInitrd)?;
+                Some((initrd_start, initrd_size))
+            }
+            None => None,
+        };
+
+        configure_pvh(
+            mem,
+            GuestAddress(KERNEL_START_OFFSET),
+            GuestAddress(CMDLINE_OFFSET),
+            initrd,
+            rsdp_addr,
+        )
+    }
+
     fn get_pcie_vcfg_mmio_range(mem: &GuestMemory, pcie_cfg_mmio: &AddressRange) -> AddressRange {
         // Put PCIe VCFG region at a 2MB boundary after physical memory or 4gb, whichever is greater.
         let ram_end_round_2mb = (mem.end_addr().offset() + 2 * MB - 1) / (2 * MB) * (2 * MB);
@@ -0,0 +1,59 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+// Structures of the PVH boot protocol as described in
+// https://xenbits.xen.org/docs/unstable/misc/pvh.html and xen/include/public/arch-x86/hvm/start_info.h
+
+use data_model::DataInit;
+
+/// Magic value of `HvmStartInfo`, "xEn3" with the 0x80 bit of the "E" set.
+pub const XEN_HVM_START_MAGIC_VALUE: u32 = 0x336e_c578;
+
+/// Version of `HvmStartInfo` that includes the memory map.
+pub const XEN_HVM_START_INFO_VERSION: u32 = 1;
+
+/// Start of day information passed to the kernel in `EBX`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HvmStartInfo {
+    pub magic: u32,
+    pub version: u32,
+    pub flags: u32,
+    pub nr_modules: u32,
+    pub modlist_paddr: u64,
+    pub cmdline_paddr: u64,
+    pub rsdp_paddr: u64,
+    pub memmap_paddr: u64,
+    pub memmap_entries: u32,
+    pub reserved: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for HvmStartInfo {}
+
+/// A module, such as an initrd, loaded alongside the kernel.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HvmModlistEntry {
+    pub paddr: u64,
+    pub size: u64,
+    pub cmdline_paddr: u64,
+    pub reserved: u64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for HvmModlistEntry {}
+
+/// An entry of the guest memory map, using the e820 memory types.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HvmMemmapTableEntry {
+    pub addr: u64,
+    pub size: u64,
+    pub type_: u32,
+    pub reserved: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for HvmMemmapTableEntry {}
@@ -264,15 +264,43 @@ pub fn configure_segments_and_sregs(mem: &GuestMemory, sregs: &mut Sregs) -> Res
         gdt::gdt_entry(0xc093, 0, 0xfffff), // DATA
         gdt::gdt_entry(0x808b, 0, 0xfffff), // TSS
     ];
+    configure_segments(mem, sregs, &gdt_table)?;
 
+    /* 64-bit protected mode */
+    sregs.cr0 |= X86_CR0_PE;
+    sregs.efer |= EFER_LME;
+
+    Ok(())
+}
+
+/// Configures the GDT, IDT, and segment registers for 32-bit protected mode without paging, as
+/// required by the PVH boot protocol.
+pub fn configure_segments_and_sregs_pvh(mem: &GuestMemory, sregs: &mut Sregs) -> Result<()> {
+    let gdt_table: [u64; BOOT_GDT_MAX as usize] = [
+        gdt::gdt_entry(0, 0, 0),            // NULL
+        gdt::gdt_entry(0xc09b, 0, 0xfffff), // CODE
+        gdt::gdt_entry(0xc093, 0, 0xfffff), // DATA
+        gdt::gdt_entry(0x008b, 0, 0x67),    // TSS
+    ];
+    configure_segments(mem, sregs, &gdt_table)?;
+
+    /* 32-bit protected mode */
+    sregs.cr0 |= X86_CR0_PE;
+    sregs.cr4 = 0;
+
+    Ok(())
+}
+
+/// Writes the boot GDT and IDT and loads the code, data and task segments from the GDT.
+fn configure_segments(mem: &GuestMemory, sregs: &mut Sregs, gdt_table: &[u64]) -> Result<()> {
     let code_seg = gdt::segment_from_gdt(gdt_table[1], 1);
     let data_seg = gdt::segment_from_gdt(gdt_table[2], 2);
     let tss_seg = gdt::segment_from_gdt(gdt_table[3], 3);
 
     // Write segments
-    write_gdt_table(&gdt_table[..], mem)?;
+    write_gdt_table(gdt_table, mem)?;
     sregs.gdt.base = BOOT_GDT_OFFSET as u64;
-    sregs.gdt.limit = mem::size_of_val(&gdt_table) as u16 - 1;
+    sregs.gdt.limit = mem::size_of_val(gdt_table) as u16 - 1;
 
     write_idt_value(0, mem)?;
     sregs.idt.base = BOOT_IDT_OFFSET as u64;
@@ -286,10 +314,6 @@ pub fn configure_segments_and_sregs(mem: &GuestMemory, sregs: &mut Sregs) -> Res
     sregs.ss = data_seg;
     sregs.tr = tss_seg;
 
-    /* 64-bit protected mode */
-    sregs.cr0 |= X86_CR0_PE;
-    sregs.efer |= EFER_LME;
-
     Ok(())
 }
 
@@ -362,6 +386,27 @@ mod tests {
         assert_eq!(EFER_LME, sregs.efer);
     }
 
+    #[test]
+    fn segments_and_sregs_pvh() {
+        let mut sregs = Default::default();
+        let gm = create_guest_mem();
+        configure_segments_and_sregs_pvh(&gm, &mut sregs).unwrap();
+
+        assert_eq!(0x0, read_u64(&gm, BOOT_GDT_OFFSET));
+        assert_eq!(0xcf9b000000ffff, read_u64(&gm, BOOT_GDT_OFFSET + 8));
+        assert_eq!(0xcf93000000ffff, read_u64(&gm, BOOT_GDT_OFFSET + 16));
+        assert_eq!(0x8b0000000067, read_u64(&gm, BOOT_GDT_OFFSET + 24));
+
+        assert_eq!(1, sregs.cs.db);
+        assert_eq!(0, sregs.cs.l);
+        assert_eq!(0x10, sregs.ss.selector);
+        assert_eq!(0x67, sregs.tr.limit);
+        assert_eq!(X86_CR0_PE, sregs.cr0 & X86_CR0_PE);
+        assert_eq!(0, sregs.cr0 & X86_CR0_PG);
+        assert_eq!(0, sregs.cr4);
+        assert_eq!(0, sregs.efer);
+    }
+
     #[test]
     fn page_tables() {
         let mut sregs = Default::default();
@@ -0,0 +1,242 @@
+// Copyright 2022 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+// Builds a VM with the fake hypervisor backend, which runs without KVM, and drives the device
+// buses with scripted VCPU exits.
+#![cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use arch::set_default_serial_parameters;
+use arch::LinuxArch;
+use arch::VmComponents;
+use arch::VmImage;
+use base::Tube;
+use devices::IrqChip;
+use devices::UserspaceIrqChip;
+use devices::IOAPIC_BASE_ADDRESS;
+use hypervisor::fake::FakeHypervisor;
+use hypervisor::fake::FakeVcpu;
+use hypervisor::fake::FakeVcpuExit;
+use hypervisor::fake::FakeVm;
+use hypervisor::CpuConfigX86_64;
+use hypervisor::IoOperation;
+use hypervisor::IoParams;
+use hypervisor::Vcpu;
+use hypervisor::VcpuExit;
+use hypervisor::VcpuX86_64;
+use hypervisor::VmX86_64;
+use resources::SystemAllocator;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+use x86_64::X8664arch;
+
+fn io_write(address: u64, data: &[u8]) -> IoParams {
+    let mut buf = [0u8; 8];
+    buf[..data.len()].copy_from_slice(data);
+    IoParams {
+        address,
+        size: data.len(),
+        operation: IoOperation::Write { data: buf },
+    }
+}
+
+fn io_read(address: u64, size: usize) -> IoParams {
+    IoParams {
+        address,
+        size,
+        operation: IoOperation::Read,
+    }
+}
+
+#[test]
+fn build_vm_with_fake_hypervisor() {
+    // A BIOS image filled with HLT instructions, which is never executed.
+    let mut bios = tempfile::tempfile().unwrap();
+    bios.write_all(&[0xf4; 0x1000]).unwrap();
+
+    let components = VmComponents {
+        acpi_sdts: Vec::new(),
+        android_fstab: None,
+        cpu_capacity: BTreeMap::new(),
+        cpu_clusters: Vec::new(),
+        delay_rt: false,
+        #[cfg(feature = "direct")]
+        direct_fixed_evts: Vec::new(),
+        #[cfg(feature = "direct")]
+        direct_gpe: Vec::new(),
+        dmi_path: None,
+        extra_kernel_params: Vec::new(),
+        force_s2idle: false,
+        fw_cfg_kernel_image: None,
+        fw_cfg_parameters: Vec::new(),
+        #[cfg(feature = "gdb")]
+        gdb: None,
+        host_cpu_topology: false,
+        hugepages: false,
+        hv_cfg: Default::default(),
+        initrd_image: None,
+        itmt: false,
+        memory_size: 256 * 1024 * 1024,
+        no_i8042: false,
+        no_rtc: false,
+        no_smt: false,
+        numa_nodes: Vec::new(),
+        oem_strings: Vec::new(),
+        pci_low_start: None,
+        pcie_ecam: None,
+        pflash_block_size: 0,
+        pflash_image: None,
+        pstore: None,
+        pvm_fw: None,
+        rt_cpus: Default::default(),
+        swiotlb: None,
+        vcpu_affinity: None,
+        vcpu_count: 1,
+        vm_image: VmImage::Bios(bios),
+    };
+
+    let guest_mem = GuestMemory::new(&X8664arch::guest_memory_layout(&components).unwrap())
+        .expect("failed to create guest memory");
+    let hypervisor = FakeHypervisor::new();
+    let vm = FakeVm::new(&hypervisor, guest_mem.clone()).expect("failed to create fake vm");
+    let mut system_allocator =
+        SystemAllocator::new(X8664arch::get_system_allocator_config(&vm), None, &[])
+            .expect("failed to create system allocator");
+    let (_irq_host_tube, irq_device_tube) = Tube::pair().unwrap();
+    let mut irq_chip = UserspaceIrqChip::<FakeVcpu>::new(1, irq_device_tube, None)
+        .expect("failed to create irq chip");
+    let (vm_evt_wrtube, _vm_evt_rdtube) = Tube::directional_pair().unwrap();
+    let mut serial_parameters = BTreeMap::new();
+    set_default_serial_parameters(&mut serial_parameters, true);
+
+    let mut linux = X8664arch::build_vm::<FakeVm, FakeVcpu>(
+        components,
+        &vm_evt_wrtube,
+        &mut system_allocator,
+        &serial_parameters,
+        None,
+        (None, None),
+        vm,
+        None,
+        Vec::new(),
+        &mut irq_chip,
+        &mut Vec::new(),
+        None,
+        None,
+    )
+    .expect("failed to build vm");
+
+    assert!(linux.has_bios);
+    assert!(linux.vm.tss_addr().is_some());
+    // The BIOS is loaded right below 4 GiB.
+    assert_eq!(
+        guest_mem
+            .read_obj_from_addr::<u8>(GuestAddress(0x1_0000_0000 - 0x1000))
+            .unwrap(),
+        0xf4
+    );
+
+    let mut vcpu = *linux
+        .vm
+        .create_vcpu(0)
+        .expect("failed to create vcpu")
+        .downcast::<FakeVcpu>()
+        .map_err(|_| ())
+        .expect("failed to downcast vcpu");
+    linux
+        .irq_chip
+        .add_vcpu(0, &vcpu)
+        .expect("failed to add vcpu to irqchip");
+    X8664arch::configure_vcpu(
+        &linux.vm,
+        linux.vm.get_hypervisor(),
+        linux.irq_chip.as_mut(),
+        &mut vcpu,
+        linux.vcpu_init[0].clone(),
+        0,
+        1,
+        linux.has_bios,
+        Some(CpuConfigX86_64::new(
+            false, false, false, false, false, false,
+        )),
+    )
+    .expect("failed to configure vcpu");
+    // Booting a BIOS starts at the reset vector.
+    assert_eq!(vcpu.get_regs().unwrap().rip, 0xfff0);
+
+    // Read the vendor and device IDs of the PCI host bridge.
+    linux.vm.push_vcpu_exit(
+        0,
+        FakeVcpuExit::Io(io_write(0xcf8, &0x8000_0000u32.to_le_bytes())),
+    );
+    linux
+        .vm
+        .push_vcpu_exit(0, FakeVcpuExit::Io(io_read(0xcfc, 4)));
+    // Read the version register of the IOAPIC.
+    linux.vm.push_vcpu_exit(
+        0,
+        FakeVcpuExit::Mmio(io_write(IOAPIC_BASE_ADDRESS, &[0x01])),
+    );
+    linux.vm.push_vcpu_exit(
+        0,
+        FakeVcpuExit::Mmio(io_read(IOAPIC_BASE_ADDRESS + 0x10, 4)),
+    );
+    linux
+        .vm
+        .push_vcpu_exit(0, FakeVcpuExit::Exit(VcpuExit::Hlt));
+
+    let mut reads = Vec::new();
+    let run_handle = vcpu.take_run_handle(None).unwrap();
+    loop {
+        match vcpu.run(&run_handle).expect("run failed") {
+            VcpuExit::Io => vcpu
+                .handle_io(&mut |IoParams {
+                                     address,
+                                     size,
+                                     operation,
+                                 }| match operation {
+                    IoOperation::Read => {
+                        let mut data = [0u8; 8];
+                        assert!(linux.io_bus.read(address, &mut data[..size]));
+                        reads.push(data);
+                        Some(data)
+                    }
+                    IoOperation::Write { data } => {
+                        assert!(linux.io_bus.write(address, &data[..size]));
+                        None
+                    }
+                })
+                .expect("handle_io failed"),
+            VcpuExit::Mmio => vcpu
+                .handle_mmio(&mut |IoParams {
+                                       address,
+                                       size,
+                                       operation,
+                                   }| match operation {
+                    IoOperation::Read => {
+                        let mut data = [0u8; 8];
+                        assert!(linux.mmio_bus.read(address, &mut data[..size]));
+                        reads.push(data);
+                        Some(data)
+                    }
+                    IoOperation::Write { data } => {
+                        assert!(linux.mmio_bus.write(address, &data[..size]));
+                        None
+                    }
+                })
+                .expect("handle_mmio failed"),
+            VcpuExit::Hlt => break,
+            r => panic!("unexpected exit {:?}", r),
+        }
+    }
+
+    assert_eq!(reads.len(), 2);
+    // Intel 82441 host bridge.
+    assert_eq!(&reads[0][..4], &[0x86, 0x80, 0x37, 0x12]);
+    // IOAPIC version 0x20.
+    assert_eq!(reads[1][0], 0x20);
+    assert_eq!(vcpu.last_read_data(), Some(reads[1]));
+}
@@ -243,6 +243,8 @@ where
         read_pcie_cfg_mmio().start,
         max_bus,
         false,
+        &[],
+        &[],
     );
 
     let guest_mem2 = guest_mem.clone();